@@ -2,6 +2,7 @@
 // https://github.com/automerge/automerge-perf/
 // mod testdata;
 mod utils;
+mod merge;
 
 use criterion::{black_box, Criterion, BenchmarkId, Throughput};
 use crdt_testdata::{load_testing_data, TestData};
@@ -131,6 +132,17 @@ fn encoding_nodecc_benchmarks(c: &mut Criterion) {
             });
         });
 
+        // Reading the operations is part of encoding, so this puts a bound on how much faster
+        // encoding could get by changing how operations are stored. (iter() also copies out the
+        // content, so it's an overestimate of what the encoder itself spends reading ops.)
+        group.bench_function(BenchmarkId::new("iter", name), |b| {
+            b.iter(|| {
+                for op in oplog.iter() {
+                    black_box(op);
+                }
+            });
+        });
+
         group.bench_function(BenchmarkId::new("merge", name), |b| {
             b.iter(|| {
                 let branch = oplog.checkout_tip();
@@ -142,6 +154,223 @@ fn encoding_nodecc_benchmarks(c: &mut Criterion) {
     }
 }
 
+/// "Time until the document shows up" - decoding a file and checking out its content.
+fn cold_start_benchmarks(c: &mut Criterion) {
+    let mut files = vec![];
+    for name in COMPLEX_DATASETS {
+        let bytes = std::fs::read(format!("benchmark_data/{name}.dt")).unwrap();
+        files.push((*name, ListOpLog::load_from(&bytes).unwrap()));
+    }
+    let test_data = testing_data("automerge-paper");
+    let mut doc = ListCRDT::new();
+    apply_edits_direct(&mut doc, &test_data.txns);
+    files.push(("automerge-paper", doc.oplog));
+
+    for (name, oplog) in files {
+        let mut group = c.benchmark_group("cold_start");
+        group.throughput(Throughput::Elements(oplog.len() as _));
+
+        let bytes = oplog.encode(ENCODE_FULL);
+        let bytes_with_content = oplog.encode(EncodeOptions {
+            experimentally_store_end_branch_content: true,
+            ..ENCODE_FULL
+        });
+
+        group.bench_function(BenchmarkId::new("load_then_checkout", name), |b| {
+            b.iter(|| {
+                let oplog = ListOpLog::load_from(&bytes).unwrap();
+                let content = oplog.checkout_tip().content().to_string();
+                black_box((oplog, content));
+            });
+        });
+
+        group.bench_function(BenchmarkId::new("decode_and_checkout", name), |b| {
+            b.iter(|| {
+                let result = ListOpLog::decode_and_checkout(&bytes).unwrap();
+                black_box(result);
+            });
+        });
+
+        // Only automerge-paper has linear history. The others fall back to decode_and_checkout.
+        group.bench_function(BenchmarkId::new("decode_document", name), |b| {
+            b.iter(|| {
+                let content = decode_document(&bytes).unwrap();
+                black_box(content);
+            });
+        });
+
+        group.bench_function(BenchmarkId::new("decode_and_checkout_stored_content", name), |b| {
+            b.iter(|| {
+                let result = ListOpLog::decode_and_checkout_trusted(&bytes_with_content).unwrap();
+                black_box(result);
+            });
+        });
+
+        group.finish();
+    }
+}
+
+/// Compare files written with and without LZ4 content compression. Criterion doesn't track sizes,
+/// so those are just printed.
+fn compression_benchmarks(c: &mut Criterion) {
+    let test_data = testing_data("automerge-paper");
+    let mut doc = ListCRDT::new();
+    apply_edits_direct(&mut doc, &test_data.txns);
+    let oplog = doc.oplog;
+
+    let mut group = c.benchmark_group("compression");
+    group.throughput(Throughput::Elements(oplog.len() as _));
+
+    for (label, compress_content) in [("uncompressed", false), ("lz4", true)] {
+        let opts = EncodeOptions {
+            store_deleted_content: true,
+            compress_content,
+            ..ENCODE_FULL
+        };
+        let bytes = oplog.encode(opts.clone());
+        println!("automerge-paper {label}: {} bytes", bytes.len());
+
+        group.bench_function(BenchmarkId::new("encode", label), |b| {
+            b.iter(|| {
+                let bytes = oplog.encode(opts.clone());
+                black_box(bytes);
+            });
+        });
+
+        group.bench_function(BenchmarkId::new("decode", label), |b| {
+            b.iter(|| {
+                let oplog = ListOpLog::load_from(&bytes).unwrap();
+                black_box(oplog);
+            });
+        });
+    }
+
+    group.finish();
+}
+
+/// A document where lots of concurrent branches are merged by a single change. Criterion doesn't
+/// track sizes, so the cost of storing the merge is just printed.
+fn wide_merge_benchmarks(c: &mut Criterion) {
+    const WIDTH: usize = 500;
+    let mut oplog = ListOpLog::new();
+    let mut heads = vec![];
+    for i in 0..WIDTH {
+        let agent = oplog.get_or_create_agent_id(&format!("agent {i}"));
+        let v = oplog.add_insert_at(agent, &[], 0, "hi ");
+        heads.push(oplog.add_delete_at(agent, &[v], 0..1));
+    }
+    let merger = oplog.get_or_create_agent_id("merger");
+    let before_merge = oplog.clone();
+    let tip = oplog.local_version();
+    let merge = oplog.add_insert_at(merger, tip.as_ref(), 0, "merged");
+
+    let bytes = oplog.encode(ENCODE_FULL);
+    let merge_bytes = bytes.len() - before_merge.encode(ENCODE_FULL).len();
+    println!("{WIDTH}-way merge: {} bytes ({merge_bytes} bytes for the merge)", bytes.len());
+
+    let mut group = c.benchmark_group("wide_merge");
+    group.throughput(Throughput::Elements(WIDTH as _));
+
+    group.bench_function(BenchmarkId::new("add_merge", WIDTH), |b| {
+        b.iter(|| {
+            let mut oplog = before_merge.clone();
+            black_box(oplog.add_insert_at(merger, tip.as_ref(), 0, "merged"));
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("encode", WIDTH), |b| {
+        b.iter(|| {
+            black_box(oplog.encode(ENCODE_FULL));
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("decode", WIDTH), |b| {
+        b.iter(|| {
+            black_box(ListOpLog::load_from(&bytes).unwrap());
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("find_dominators", WIDTH), |b| {
+        let mut versions = heads.clone();
+        versions.push(merge);
+        b.iter(|| {
+            black_box(oplog.find_dominators(&versions));
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("common_ancestor", WIDTH), |b| {
+        b.iter(|| {
+            black_box(oplog.common_ancestor(&[merge], &heads[..WIDTH / 2]));
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("checkout_tip", WIDTH), |b| {
+        b.iter(|| {
+            black_box(oplog.checkout_tip());
+        });
+    });
+
+    group.finish();
+}
+
+/// Syncing lots of tiny patches, one per keystroke.
+fn patch_batch_benchmarks(c: &mut Criterion) {
+    const PATCHES: usize = 2000;
+    let mut oplog = ListOpLog::new();
+    let agent = oplog.get_or_create_agent_id("seph");
+    let mut patches = vec![];
+    for i in 0..PATCHES {
+        let before = oplog.local_version();
+        oplog.add_insert(agent, i, "x");
+        patches.push(oplog.encode_from(ENCODE_PATCH, before.as_ref()));
+    }
+    let reversed: Vec<&[u8]> = patches.iter().rev().map(|p| &p[..]).collect();
+
+    let mut group = c.benchmark_group("patch_batch");
+    group.throughput(Throughput::Elements(PATCHES as _));
+
+    group.bench_function(BenchmarkId::new("merge_data_loop", PATCHES), |b| {
+        b.iter(|| {
+            let mut dest = ListOpLog::new();
+            for p in &patches { dest.merge_data(p).unwrap(); }
+            black_box(dest);
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("merge_data_many", PATCHES), |b| {
+        b.iter(|| {
+            let mut dest = ListOpLog::new();
+            dest.merge_data_many(patches.iter().map(|p| &p[..])).unwrap();
+            black_box(dest);
+        });
+    });
+
+    group.bench_function(BenchmarkId::new("merge_data_many_reversed", PATCHES), |b| {
+        b.iter(|| {
+            let mut dest = ListOpLog::new();
+            dest.merge_data_many(reversed.iter().copied()).unwrap();
+            black_box(dest);
+        });
+    });
+
+    group.finish();
+}
+
+/// The merge scenarios in merge.rs. These are also run by the perf smoke test.
+fn merge_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merge");
+    for (name, build) in merge::SCENARIOS {
+        let scenario = build();
+        group.throughput(Throughput::Elements(scenario.oplog.len() as _));
+        group.bench_function(BenchmarkId::new("merge", name), |b| {
+            b.iter(|| {
+                black_box(scenario.run());
+            });
+        });
+    }
+    group.finish();
+}
+
 // criterion_group!(benches,
 //     local_benchmarks,
 //     encoding_nodecc_benchmarks,
@@ -157,5 +386,10 @@ fn main() {
 
     local_benchmarks(&mut c);
     encoding_nodecc_benchmarks(&mut c);
+    cold_start_benchmarks(&mut c);
+    compression_benchmarks(&mut c);
+    wide_merge_benchmarks(&mut c);
+    patch_batch_benchmarks(&mut c);
+    merge_benchmarks(&mut c);
     c.final_summary();
 }
\ No newline at end of file
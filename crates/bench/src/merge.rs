@@ -0,0 +1,156 @@
+//! Named merge scenarios, shared by the criterion benchmarks and the perf smoke test.
+//!
+//! Every scenario is built from a fixed seed, so the same operations are merged on every machine.
+//! The smoke test (`cargo test -p bench --release -- --ignored perf`) runs each scenario once and
+//! checks it finishes within the ceiling recorded in `perf_ceilings.toml`. The ceilings are
+//! deliberately generous - they're there to catch large regressions, not to measure anything. If
+//! a change really does make a scenario slower, update the ceiling in the same commit.
+
+use rand::prelude::*;
+use diamond_types::AgentId;
+use diamond_types::list::{make_random_change, ListBranch, ListOpLog};
+use diamond_types::LV;
+
+/// A branch at some version, and the version to merge into it.
+pub struct MergeScenario {
+    pub oplog: ListOpLog,
+    pub start: ListBranch,
+    pub merge_frontier: Vec<LV>,
+}
+
+impl MergeScenario {
+    fn new(oplog: ListOpLog, from: &[LV]) -> Self {
+        Self {
+            start: oplog.checkout(from),
+            merge_frontier: oplog.local_version_ref().to_vec(),
+            oplog,
+        }
+    }
+
+    /// Merge into a copy of the start branch. This is the part which is measured.
+    pub fn run(&self) -> ListBranch {
+        let mut branch = self.start.clone();
+        branch.merge(&self.oplog, &self.merge_frontier);
+        branch
+    }
+}
+
+/// Builds a scenario.
+pub type ScenarioFn = fn() -> MergeScenario;
+
+/// All the scenarios, by name. The names are used in the benchmark IDs and in perf_ceilings.toml,
+/// so they shouldn't be changed.
+pub const SCENARIOS: &[(&str, ScenarioFn)] = &[
+    ("linear_catchup", linear_catchup),
+    ("concurrent_50_50", concurrent_50_50),
+    ("many_small_branches", many_small_branches),
+    ("node_nodecc_replay", node_nodecc_replay),
+];
+
+/// Make n random edits on a branch checked out at `from`, using the same edit generator as the
+/// fuzzers. If `from` isn't the oplog's tip, the edits are concurrent with everything after it.
+/// Returns the resulting version.
+fn random_edits(oplog: &mut ListOpLog, from: &[LV], agent: AgentId, n: usize, rng: &mut SmallRng) -> Vec<LV> {
+    let mut branch = oplog.checkout(from);
+    for _ in 0..n {
+        let v = make_random_change(oplog, &branch, None, agent, rng);
+        branch.merge(oplog, &[v]);
+    }
+    branch.local_version_ref().to_vec()
+}
+
+/// A peer which has been offline catches up on 20k operations of linear history.
+fn linear_catchup() -> MergeScenario {
+    let mut rng = SmallRng::seed_from_u64(1);
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let base = random_edits(&mut oplog, &[], seph, 1000, &mut rng);
+    random_edits(&mut oplog, &base, seph, 20_000, &mut rng);
+    MergeScenario::new(oplog, &base)
+}
+
+/// Two peers make 5000 edits each from the same base. One of them merges in the other's changes.
+fn concurrent_50_50() -> MergeScenario {
+    let mut rng = SmallRng::seed_from_u64(2);
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let base = random_edits(&mut oplog, &[], seph, 1000, &mut rng);
+    let a = random_edits(&mut oplog, &base, seph, 5000, &mut rng);
+    random_edits(&mut oplog, &base, mike, 5000, &mut rng);
+    MergeScenario::new(oplog, &a)
+}
+
+/// 500 peers each make a few edits from the same base, and they're all merged together.
+fn many_small_branches() -> MergeScenario {
+    let mut rng = SmallRng::seed_from_u64(3);
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let base = random_edits(&mut oplog, &[], seph, 1000, &mut rng);
+    for i in 0..500 {
+        let agent = oplog.get_or_create_agent_id(&format!("agent {i}"));
+        random_edits(&mut oplog, &base, agent, 5, &mut rng);
+    }
+    MergeScenario::new(oplog, &base)
+}
+
+/// Check out the full history of node.cc (from git).
+fn node_nodecc_replay() -> MergeScenario {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../benchmark_data/node_nodecc.dt");
+    let oplog = ListOpLog::load_from(&std::fs::read(path).unwrap()).unwrap();
+    MergeScenario::new(oplog, &[])
+}
+
+/// The ceilings in perf_ceilings.toml, by scenario name.
+#[cfg(test)]
+fn ceilings() -> Vec<(String, std::time::Duration)> {
+    let table: toml::Table = include_str!("../perf_ceilings.toml").parse().unwrap();
+    let ceilings = table["ceiling_ms"].as_table().expect("perf_ceilings.toml is missing [ceiling_ms]");
+    ceilings.iter()
+        .map(|(name, ms)| {
+            let ms = ms.as_integer().expect("Ceilings should be whole milliseconds");
+            (name.clone(), std::time::Duration::from_millis(ms as u64))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+    use super::*;
+
+    #[test]
+    fn every_scenario_has_a_ceiling() {
+        let mut names: Vec<String> = ceilings().into_iter().map(|(name, _)| name).collect();
+        let mut expected: Vec<&str> = SCENARIOS.iter().map(|(name, _)| *name).collect();
+        names.sort();
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    #[ignore]
+    fn perf_ceilings() {
+        if cfg!(debug_assertions) {
+            panic!("The perf ceilings are for release builds. Run with --release");
+        }
+
+        let ceilings = ceilings();
+        let mut failed = vec![];
+        for (name, build) in SCENARIOS {
+            let scenario = build();
+            let ceiling = ceilings.iter().find(|(n, _)| n == name).unwrap().1;
+
+            let start = Instant::now();
+            let branch = scenario.run();
+            let elapsed = start.elapsed();
+
+            // Make sure the merge actually happened.
+            assert_eq!(branch.content().to_string(), scenario.oplog.checkout_tip().content().to_string());
+            println!("{name}: {elapsed:?} (ceiling {ceiling:?})");
+            if elapsed > ceiling { failed.push(name); }
+        }
+
+        assert!(failed.is_empty(), "Scenarios over their ceiling: {failed:?}");
+    }
+}
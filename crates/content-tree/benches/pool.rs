@@ -0,0 +1,58 @@
+// Node allocation benchmarks. Building a big tree allocates lots of nodes, and deleting from it
+// frees them again. Dropping the tree releases whatever is left.
+
+use criterion::{criterion_group, criterion_main, black_box, BatchSize, Criterion, Throughput};
+use rand::prelude::*;
+use content_tree::{ContentMetrics, ContentTreeRaw};
+use content_tree::testrange::TestRange;
+
+type Tree = ContentTreeRaw<TestRange, ContentMetrics>;
+
+const ENTRIES: usize = 100_000;
+
+/// Insert lots of small entries at random positions. The IDs are spaced out so none of the
+/// entries merge together.
+fn build_tree() -> std::pin::Pin<Box<Tree>> {
+    let mut rng = SmallRng::seed_from_u64(2238);
+    let mut tree = Tree::new();
+    for i in 0..ENTRIES {
+        let pos = rng.gen_range(0..=tree.content_len());
+        tree.insert_at_content(pos, TestRange { id: (i * 10) as u32, len: 2, is_activated: true });
+    }
+    tree
+}
+
+fn pool_benchmarks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("content-tree");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+
+    group.bench_function("build", |b| {
+        b.iter_batched(|| (), |_| build_tree(), BatchSize::PerIteration)
+    });
+
+    group.bench_function("drop", |b| {
+        b.iter_batched(build_tree, drop, BatchSize::PerIteration)
+    });
+
+    // Deleting most of the tree and building it back up reuses freed nodes.
+    group.bench_function("delete_and_rebuild", |b| {
+        b.iter_batched(build_tree, |mut tree| {
+            let mut rng = SmallRng::seed_from_u64(1);
+            while tree.content_len() > ENTRIES / 10 {
+                let pos = rng.gen_range(0..tree.content_len() - 100);
+                tree.delete_at_content(pos, 100);
+            }
+            for i in 0..ENTRIES {
+                let pos = rng.gen_range(0..=tree.content_len());
+                tree.insert_at_content(pos, TestRange { id: (i * 10 + 5_000_000) as u32, len: 2, is_activated: true });
+            }
+            black_box(tree.content_len());
+            tree
+        }, BatchSize::PerIteration)
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, pool_benchmarks);
+criterion_main!(benches);
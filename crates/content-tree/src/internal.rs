@@ -2,7 +2,7 @@ use super::*;
 use std::mem::{self, MaybeUninit};
 
 impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> NodeInternal<E, I, IE, LE> {
-    pub fn new_with_parent(parent: ParentPtr<E, I, IE, LE>) -> Pin<Box<Self>> {
+    pub fn new_with_parent(parent: ParentPtr<E, I, IE, LE>) -> Self {
         // From the example in the docs:
         // https://doc.rust-lang.org/std/mem/union.MaybeUninit.html#initializing-an-array-element-by-element
         let mut children: [MaybeUninit<Option<Node<E, I, IE, LE>>>; IE] = unsafe {
@@ -11,7 +11,7 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
         for elem in &mut children[..] {
             elem.write(None);
         }
-        Box::pin(Self {
+        Self {
             parent,
             // data: [(I::IndexOffset::default(), None); NUM_NODE_CHILDREN],
             metrics: [I::Value::default(); IE],
@@ -20,7 +20,7 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
                 mem::transmute_copy::<_, [Option<Node<E, I, IE, LE>>; IE]>(&children)
             },
             _pin: PhantomPinned,
-        })
+        }
     }
 
     /// Finds the child at some given offset. Returns the remaining offset within the found child.
@@ -11,6 +11,7 @@ use std::pin::Pin;
 use std::ptr::NonNull;
 
 pub use metrics::*;
+use pool::{NodeBox, NodePool};
 pub use root::DeleteResult;
 
 // Types re-exported from rle for convenience
@@ -27,6 +28,7 @@ mod safe_cursor;
 pub mod testrange;
 mod iter;
 mod debug;
+mod pool;
 
 // pub(crate) use cursor::Cursor;
 
@@ -72,6 +74,10 @@ pub struct ContentTreeRaw<E: ContentTraits, I: TreeMetrics<E>, const INT_ENTRIES
     // TODO: Currently unused.
     // last_cursor: Cell<Option<(usize, Cursor<E, I, IE, LE>)>>,
 
+    // All the nodes in the tree are allocated from these pools.
+    leaf_pool: NodePool<NodeLeaf<E, I, INT_ENTRIES, LEAF_ENTRIES>>,
+    internal_pool: NodePool<NodeInternal<E, I, INT_ENTRIES, LEAF_ENTRIES>>,
+
     _pin: marker::PhantomPinned,
 }
 
@@ -129,8 +135,8 @@ pub struct NodeLeaf<E: ContentTraits, I: TreeMetrics<E>, const INT_ENTRIES: usiz
 
 #[derive(Debug)]
 pub(crate) enum Node<E: ContentTraits, I: TreeMetrics<E>, const IE: usize = DEFAULT_IE, const LE: usize = DEFAULT_LE> {
-    Internal(Pin<Box<NodeInternal<E, I, IE, LE>>>),
-    Leaf(Pin<Box<NodeLeaf<E, I, IE, LE>>>),
+    Internal(NodeBox<NodeInternal<E, I, IE, LE>>),
+    Leaf(NodeBox<NodeLeaf<E, I, IE, LE>>),
 }
 
 // I hate that I need this, but its used all over the place when traversing the tree.
@@ -273,12 +279,8 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
     /// Unsafe: The resulting NodePtr is mutable and doesn't have an associated lifetime.
     unsafe fn as_ptr(&self) -> NodePtr<E, I, IE, LE> {
         match self {
-            Node::Internal(n) => {
-                NodePtr::Internal(ref_to_nonnull(n.as_ref().get_ref()))
-            },
-            Node::Leaf(n) => {
-                NodePtr::Leaf(ref_to_nonnull(n.as_ref().get_ref()))
-            },
+            Node::Internal(n) => NodePtr::Internal(n.as_ptr()),
+            Node::Leaf(n) => NodePtr::Leaf(n.as_ptr()),
         }
     }
 
@@ -325,6 +327,17 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Pare
             ParentPtr::Internal(_) => { false }
         }
     }
+
+    /// Walk up the tree to find the tree's root object, which owns the node pools.
+    unsafe fn tree(self) -> NonNull<ContentTreeRaw<E, I, IE, LE>> {
+        let mut parent = self;
+        loop {
+            match parent {
+                ParentPtr::Root(r) => { return r; }
+                ParentPtr::Internal(n) => { parent = n.as_ref().parent; }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -973,7 +973,7 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
 
             // eprintln!("split_at idx {} stolen_length {:?} self {:?}", idx, stolen_length, &self);
 
-            let mut new_node_boxed = Box::pin(new_node);
+            let mut new_node_boxed = self.parent.tree().as_mut().leaf_pool.alloc(new_node);
 
             // This is the pointer to the new item we'll end up returning.
             let new_leaf_ptr = NonNull::new_unchecked(new_node_boxed.as_mut().get_unchecked_mut());
@@ -1011,6 +1011,20 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
     }
 }
 
+impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> ContentTreeRaw<E, I, IE, LE> {
+    /// Return a node which has been removed from the tree to the node pools. Removed internal
+    /// nodes are always empty.
+    unsafe fn free_node(&mut self, node: Node<E, I, IE, LE>) {
+        match node {
+            Node::Leaf(leaf) => self.leaf_pool.free(leaf),
+            Node::Internal(internal) => {
+                debug_assert!(internal.children[0].is_none());
+                self.internal_pool.free(internal)
+            }
+        }
+    }
+}
+
 impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> NodeInternal<E, I, IE, LE> {
     unsafe fn slice_out(&mut self, child: NodePtr<E, I, IE, LE>) -> Node<E, I, IE, LE> {
         if self.children[1].is_none() {
@@ -1037,8 +1051,9 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
                 self.metrics.copy_within(idx + 1..num_children, idx);
             }
 
-            // This pointer has been moved. We need to set its entry to None without dropping it.
-            std::mem::forget(self.children[num_children - 1].take());
+            // This pointer has been moved. Clear out the stale copy. (Nodes are owned by the
+            // tree's node pool, so this doesn't free anything.)
+            self.children[num_children - 1] = None;
 
             removed
         }
@@ -1063,18 +1078,22 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Node
                     // with a fresh node, which would be simpler, but doing that would mess up the
                     // cursor (which we don't have access to here). And it would require an
                     // additional allocation - though this is rare anyway.
-                    let mut root = root.as_mut();
+                    let root = root.as_mut();
                     spare_leaf.set_parent(root.to_parent_ptr());
                     // spare_leaf.unwrap_leaf_mut().get_unchecked_mut().num_entries = 0;
                     spare_leaf.unwrap_leaf_mut().get_unchecked_mut().clear_all();
-                    root.root = spare_leaf;
+                    let old_root = mem::replace(&mut root.root, spare_leaf);
+                    root.free_node(old_root);
                 }
                 ParentPtr::Internal(mut parent) => {
                     // Remove recursively.
-                    parent.as_mut().slice_out(NodePtr::Internal(self_ptr));
+                    let removed = parent.as_mut().slice_out(NodePtr::Internal(self_ptr));
+                    ParentPtr::Internal(parent).tree().as_mut().free_node(removed);
                     Self::ripple_delete(parent, spare_leaf);
                 }
             }
+        } else {
+            self_ref.parent.tree().as_mut().free_node(spare_leaf);
         }
     }
 }
@@ -1121,7 +1140,7 @@ unsafe fn insert_after<E: ContentTraits, I: TreeMetrics<E>, const INT_ENTRIES: u
             ParentPtr::Root(mut r) => {
                 // This is the simpler case. The new root will be a new
                 // internal node containing old_node and inserted_node.
-                let new_root = Node::Internal(NodeInternal::new_with_parent(ParentPtr::Root(r)));
+                let new_root = Node::Internal(r.as_mut().internal_pool.alloc(NodeInternal::new_with_parent(ParentPtr::Root(r))));
                 let mut old_root = mem::replace(&mut r.as_mut().root, new_root);
 
                 // *inserted_node.get_parent_mut() = parent_ptr;
@@ -1154,7 +1173,7 @@ unsafe fn insert_after<E: ContentTraits, I: TreeMetrics<E>, const INT_ENTRIES: u
                 debug_assert!(left_sibling.count_children() == INT_ENTRIES);
 
                 // let mut right_sibling = NodeInternal::new_with_parent(parent);
-                let mut right_sibling_box = Node::Internal(NodeInternal::new_with_parent(parent));
+                let mut right_sibling_box = Node::Internal(parent.tree().as_mut().internal_pool.alloc(NodeInternal::new_with_parent(parent)));
                 let mut right_sibling = right_sibling_box.unwrap_internal_mut();
                 let old_idx = left_sibling.find_child(insert_after).unwrap();
 
@@ -1255,6 +1274,30 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn removed_nodes_are_reused() {
+        let mut tree = ContentTreeRaw::<TestRange, RawPositionMetricsU32, DEFAULT_IE, DEFAULT_LE>::new();
+        let fill = |tree: &mut Pin<Box<ContentTreeRaw<TestRange, RawPositionMetricsU32, DEFAULT_IE, DEFAULT_LE>>>| {
+            for i in 0..200 {
+                // Gaps in the IDs stop the entries from being merged together.
+                tree.push(TestRange { id: i * 10, len: 5, is_activated: true });
+            }
+            tree.check();
+        };
+
+        fill(&mut tree);
+        assert_eq!(tree.count_entries(), 200);
+        let memory = tree.count_total_memory();
+
+        tree.delete_at_offset(0, 1000);
+        tree.check();
+        assert_eq!(tree.count_entries(), 0);
+        assert_eq!(tree.count_nodes(), (0, 1));
+
+        fill(&mut tree);
+        assert_eq!(tree.count_total_memory(), memory);
+    }
+
     #[test]
     fn delete_collapses() {
         let mut tree = ContentTreeRaw::<TestRange, ContentMetrics, DEFAULT_IE, DEFAULT_LE>::new();
@@ -0,0 +1,144 @@
+//! Nodes in a content tree are allocated out of a per-tree pool rather than individually boxed.
+//!
+//! The pool hands out memory from a list of slabs. Slabs are never resized, so nodes never move
+//! once they've been allocated (which the parent pointers & cursors depend on). Nodes which are
+//! removed from the tree go on a free list and get reused by later splits.
+//!
+//! Nodes only contain Copy data and pointers to other nodes, so nothing in the pool needs to be
+//! dropped. When the tree is dropped the slabs are freed wholesale, without walking the tree.
+
+use std::fmt::{Debug, Formatter};
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::ptr::NonNull;
+
+const FIRST_SLAB_SIZE: usize = 4;
+const MAX_SLAB_SIZE: usize = 1024;
+
+/// A handle to a node owned by a [`NodePool`]. This is used in place of `Pin<Box<T>>`, and has the
+/// same API for getting at the node. Dropping a NodeBox does nothing - nodes must be explicitly
+/// returned to the pool with [`NodePool::free`].
+pub(crate) struct NodeBox<T>(NonNull<T>);
+
+impl<T> NodeBox<T> {
+    pub(crate) fn as_ref(&self) -> Pin<&T> {
+        unsafe { Pin::new_unchecked(self.0.as_ref()) }
+    }
+
+    pub(crate) fn as_mut(&mut self) -> Pin<&mut T> {
+        unsafe { Pin::new_unchecked(&mut *self.0.as_ptr()) }
+    }
+
+    pub(crate) fn as_ptr(&self) -> NonNull<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for NodeBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl<T: Debug> Debug for NodeBox<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+
+pub(crate) struct NodePool<T> {
+    /// Each slab is a leaked Box<[MaybeUninit<T>]>. The slabs are only accessed through raw
+    /// pointers, so handing out pointers into a slab never invalidates the ones handed out before.
+    slabs: Vec<NonNull<[MaybeUninit<T>]>>,
+    /// Number of items used in the last slab.
+    used: usize,
+    free: Vec<NonNull<T>>,
+}
+
+impl<T> NodePool<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            slabs: Vec::new(),
+            used: 0,
+            free: Vec::new(),
+        }
+    }
+
+    pub(crate) fn alloc(&mut self, val: T) -> NodeBox<T> {
+        let ptr = if let Some(ptr) = self.free.pop() {
+            ptr
+        } else {
+            if !matches!(self.slabs.last(), Some(s) if self.used < s.len()) {
+                // Each slab is twice the size of the last, up to MAX_SLAB_SIZE.
+                let size = self.slabs.last()
+                    .map_or(FIRST_SLAB_SIZE, |s| (s.len() * 2).min(MAX_SLAB_SIZE));
+                let slab: Box<[MaybeUninit<T>]> = (0..size).map(|_| MaybeUninit::uninit()).collect();
+                self.slabs.push(NonNull::from(Box::leak(slab)));
+                self.used = 0;
+            }
+
+            let slab = *self.slabs.last().unwrap();
+            let ptr = unsafe { NonNull::new_unchecked(slab.as_ptr().cast::<T>().add(self.used)) };
+            self.used += 1;
+            ptr
+        };
+
+        unsafe { ptr.as_ptr().write(val); }
+        NodeBox(ptr)
+    }
+
+    /// Return a node to the pool. The node must have been allocated by this pool, and must not be
+    /// referenced by anything in the tree.
+    pub(crate) unsafe fn free(&mut self, node: NodeBox<T>) {
+        self.free.push(node.0);
+    }
+
+    /// Number of bytes allocated by the pool, including free space.
+    pub(crate) fn allocated_bytes(&self) -> usize {
+        self.slabs.iter().map(|s| s.len()).sum::<usize>() * std::mem::size_of::<T>()
+    }
+}
+
+impl<T> Drop for NodePool<T> {
+    fn drop(&mut self) {
+        for slab in self.slabs.drain(..) {
+            // Safe because the slabs came from Box::leak. The items are MaybeUninit, so this
+            // doesn't drop any of the nodes. (And nodes don't need dropping anyway.)
+            drop(unsafe { Box::from_raw(slab.as_ptr()) });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nodes_are_reused_and_never_move() {
+        let mut pool = NodePool::new();
+        let nodes: Vec<NodeBox<usize>> = (0..100).map(|i| pool.alloc(i)).collect();
+        let ptrs: Vec<NonNull<usize>> = nodes.iter().map(|n| n.as_ptr()).collect();
+
+        for (i, n) in nodes.iter().enumerate() {
+            assert_eq!(**n, i);
+        }
+
+        let mut nodes = nodes.into_iter();
+        let third = nodes.nth(3).unwrap();
+        let third_ptr = third.as_ptr();
+        unsafe { pool.free(third); }
+
+        let reused = pool.alloc(1000);
+        assert_eq!(reused.as_ptr(), third_ptr);
+        assert_eq!(*reused, 1000);
+
+        for (i, p) in ptrs.iter().enumerate() {
+            if i != 3 {
+                assert_eq!(unsafe { *p.as_ref() }, i);
+            }
+        }
+    }
+}
@@ -11,10 +11,15 @@ pub type DeleteResult<E> = SmallVec<[E; 8]>;
 
 impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> ContentTreeRaw<E, I, IE, LE> {
     pub fn new() -> Pin<Box<Self>> {
+        let mut leaf_pool = NodePool::new();
+        let root = Node::Leaf(leaf_pool.alloc(unsafe { NodeLeaf::new(None) }));
+
         let mut tree = Box::pin(Self {
             count: I::Value::default(),
-            root: unsafe { Node::Leaf(Box::pin(NodeLeaf::new(None))) },
+            root,
             // last_cursor: Cell::new(None),
+            leaf_pool,
+            internal_pool: NodePool::new(),
             _pin: marker::PhantomPinned,
         });
 
@@ -393,26 +398,12 @@ impl<E: ContentTraits, I: TreeMetrics<E>, const IE: usize, const LE: usize> Cont
         num
     }
 
-    fn count_memory_internal(node: &Node<E, I, IE, LE>, size: &mut usize) {
-        match node {
-            Node::Internal(n) => {
-                *size += size_of::<NodeInternal<E, I, IE, LE>>();
-
-                for e in n.children[..].iter().flatten() {
-                    Self::count_memory_internal(e, size);
-                }
-            }
-            Node::Leaf(_) => {
-                *size += std::mem::size_of::<NodeLeaf<E, I, IE, LE>>();
-            }
-        }
-    }
-
+    /// Returns the memory used by the tree, including free space in the node pools.
     #[allow(unused)]
     pub fn count_total_memory(&self) -> usize {
-        let mut size = size_of::<ContentTreeRaw<E, I, IE, LE>>();
-        Self::count_memory_internal(&self.root, &mut size);
-        size
+        size_of::<ContentTreeRaw<E, I, IE, LE>>()
+            + self.leaf_pool.allocated_bytes()
+            + self.internal_pool.allocated_bytes()
     }
 }
 
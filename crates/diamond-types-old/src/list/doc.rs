@@ -41,7 +41,7 @@ impl ClientData {
     }
 }
 
-pub(super) fn notify_for(index: &mut SpaceIndex) -> impl FnMut(YjsSpan, NonNull<NodeLeaf<YjsSpan, DocRangeIndex, DOC_IE, DOC_LE>>) + '_ {
+pub(super) fn notify_for<'a>(index: &'a mut SpaceIndex, listener: &'a mut Option<IndexListener>, regions: &'a mut Regions, client_with_time: &'a RleVec<KVPair<CRDTSpan>>) -> impl FnMut(YjsSpan, NonNull<NodeLeaf<YjsSpan, DocRangeIndex, DOC_IE, DOC_LE>>) + 'a {
     move |entry: YjsSpan, leaf| {
         let mut len = entry.len() as u32;
         let mut order = entry.time;
@@ -60,6 +60,24 @@ pub(super) fn notify_for(index: &mut SpaceIndex) -> impl FnMut(YjsSpan, NonNull<
         // index.replace_range(entry.order as usize, MarkerEntry {
         //     ptr: Some(leaf), len: entry.len() as u32
         // });
+
+        if let Some(listener) = listener {
+            // The entry might span several agents' runs. Each event only names one.
+            let mut time = entry.time;
+            let end = entry.time + entry.len() as u32;
+            while time < end {
+                let (KVPair(_, span), offset) = client_with_time.find_with_offset(time).unwrap();
+                let len = (span.len - offset).min(end - time);
+                listener.emit(IndexEvent {
+                    loc: span.at_offset(offset as usize),
+                    time,
+                    len,
+                    deleted: entry.is_deactivated(),
+                    region: regions.id_for(leaf),
+                });
+                time += len;
+            }
+        }
     }
 }
 
@@ -83,9 +101,39 @@ impl ListCRDT {
             text_content: Some(JumpRopeBuf::new()),
             // text_content: None,
             deleted_content: None,
+
+            index_listener: None,
+            regions: Regions::default(),
         }
     }
 
+    /// Register a callback to be told whenever items are created in, or move between regions of
+    /// the document's internal range tree. Embedders can use this to maintain their own index over
+    /// the document's items (keyed by CRDT location) without touching the tree's node pointers.
+    ///
+    /// The callback is run in the middle of applying changes, so it should just record the event.
+    /// Use [`ListCRDT::lookup_position`] afterwards to find where an item is in the document.
+    ///
+    /// Only one listener can be registered at a time. Setting a new listener replaces the old one.
+    pub fn set_index_listener(&mut self, listener: Box<dyn FnMut(IndexEvent)>) {
+        self.index_listener = Some(IndexListener::Callback(listener));
+    }
+
+    pub fn clear_index_listener(&mut self) {
+        self.index_listener = None;
+    }
+
+    /// Returns the current position in the document of the item at the specified CRDT location,
+    /// or None if the item has been deleted.
+    ///
+    /// Panics if the item isn't known by the document.
+    pub fn lookup_position(&self, loc: CRDTId) -> Option<usize> {
+        let time = self.client_data[loc.agent as usize].seq_to_order(loc.seq);
+        let cursor = self.get_cursor_before(time);
+        if cursor.get_raw_entry().is_deactivated() { None }
+        else { Some(cursor.count_content_pos()) }
+    }
+
     pub fn has_content(&self) -> bool {
         self.text_content.is_some()
     }
@@ -174,6 +222,12 @@ impl ListCRDT {
         // self.index.entry_at(order as usize).unwrap_ptr()
     }
 
+    /// The region holding the item at the specified time, if an index event has named it.
+    #[cfg(test)]
+    pub(super) fn region_at(&self, time: Time) -> Option<RegionId> {
+        self.regions.ids.get(&self.marker_at(time)).copied()
+    }
+
     pub(crate) fn get_unsafe_cursor_before(&self, time: Time) -> UnsafeCursor<YjsSpan, DocRangeIndex, DOC_IE, DOC_LE> {
         if time == ROOT_TIME {
             // Or maybe we should just abort?
@@ -379,7 +433,7 @@ impl ListCRDT {
         }
 
         // Now insert here.
-        unsafe { ContentTreeRaw::unsafe_insert_notify(&mut cursor, item, notify_for(&mut self.index)); }
+        unsafe { ContentTreeRaw::unsafe_insert_notify(&mut cursor, item, notify_for(&mut self.index, &mut self.index_listener, &mut self.regions, &self.client_with_time)); }
         // cursor
     }
 
@@ -478,6 +532,32 @@ impl ListCRDT {
         assert_eq!(will_merge, did_merge);
     }
 
+    /// The range tree only calls notify when items (might) move to a different leaf. Deleting
+    /// items in place doesn't, so the index listener is told about deletes separately.
+    fn notify_listener_deleted(&mut self, target: Time, len: u32) {
+        if let Some(listener) = self.index_listener.as_mut() {
+            let end = target + len;
+            let mut time = target;
+            while time < end {
+                let (KVPair(_, span), offset) = self.client_with_time.find_with_offset(time).unwrap();
+                let cursor = self.index.cursor_at_offset_pos(time as usize, false);
+                let marker = cursor.get_raw_entry();
+                let len = (span.len - offset)
+                    .min(marker.len - cursor.offset as u32)
+                    .min(end - time);
+
+                listener.emit(IndexEvent {
+                    loc: span.at_offset(offset as usize),
+                    time,
+                    len,
+                    deleted: true,
+                    region: self.regions.id_for(marker.unwrap_ptr()),
+                });
+                time += len;
+            }
+        }
+    }
+
     pub(super) fn internal_mark_deleted(&mut self, id: Time, target: Time, max_len: u32, update_content: bool) -> Time {
         // TODO: Make this use mut_cursor instead. The problem is notify_for mutably borrows
         // self.index, and the cursor is borrowing self (rather than self.range_tree).
@@ -489,7 +569,7 @@ impl ListCRDT {
         let target = unsafe { cursor.unsafe_get_item().unwrap() };
 
         let (deleted_here, succeeded) = unsafe {
-            ContentTreeRaw::unsafe_remote_deactivate_notify(cursor, max_len as _, notify_for(&mut self.index))
+            ContentTreeRaw::unsafe_remote_deactivate_notify(cursor, max_len as _, notify_for(&mut self.index, &mut self.index_listener, &mut self.regions, &self.client_with_time))
         };
         let deleted_here = deleted_here as u32;
 
@@ -501,11 +581,14 @@ impl ListCRDT {
         if !succeeded {
             // This span was already deleted by a different peer. Mark duplicate delete.
             self.double_deletes.increment_delete_range(target, deleted_here);
-        } else if let (Some(text), true) = (&mut self.text_content, update_content) {
-            // The call to remote_deactivate will have modified the cursor, but the content position
-            // will have stayed the same.
-            let pos = unsafe { cursor.unsafe_count_content_pos() as usize };
-            text.remove(pos..pos + deleted_here as usize);
+        } else {
+            if let (Some(text), true) = (&mut self.text_content, update_content) {
+                // The call to remote_deactivate will have modified the cursor, but the content
+                // position will have stayed the same.
+                let pos = unsafe { cursor.unsafe_count_content_pos() };
+                text.remove(pos..pos + deleted_here as usize);
+            }
+            self.notify_listener_deleted(target, deleted_here);
         }
 
         deleted_here
@@ -701,7 +784,7 @@ impl ListCRDT {
                 }
 
                 Del => {
-                    let deleted_items = self.range_tree.local_deactivate_at_content_notify(pos as usize, len, notify_for(&mut self.index));
+                    let deleted_items = self.range_tree.local_deactivate_at_content_notify(pos, len, notify_for(&mut self.index, &mut self.index_listener, &mut self.regions, &self.client_with_time));
 
                     // dbg!(&deleted_items);
                     let mut deleted_length = 0; // To check.
@@ -710,6 +793,7 @@ impl ListCRDT {
                             start: item.time,
                             len: item.len as u32
                         }));
+                        self.notify_listener_deleted(item.time, item.len as u32);
                         deleted_length += item.len as usize;
                         next_time += item.len as u32;
                     }
@@ -1061,4 +1145,69 @@ mod tests {
             assert_eq!(text, "ccaabb");
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn index_listener_tracks_items() {
+        use std::cell::RefCell;
+        use std::collections::HashMap;
+        use std::rc::Rc;
+        use rand::prelude::*;
+        use crate::test_helpers::make_random_change;
+
+        // Shadow index of (agent, seq) -> (region, deleted), built only from events.
+        let shadow = Rc::new(RefCell::new(HashMap::new()));
+
+        let mut doc = ListCRDT::new();
+        let listener_shadow = shadow.clone();
+        doc.set_index_listener(Box::new(move |event: IndexEvent| {
+            let mut shadow = listener_shadow.borrow_mut();
+            for i in 0..event.len {
+                shadow.insert((event.loc.agent, event.loc.seq + i), (event.region, event.deleted));
+            }
+        }));
+
+        let mut other = ListCRDT::new();
+        let agent_a = doc.get_or_create_agent_id("a");
+        let agent_b = other.get_or_create_agent_id("b");
+
+        let mut rng = SmallRng::seed_from_u64(321);
+        for i in 0..400 {
+            if rng.gen_bool(0.7) {
+                make_random_change(&mut doc, None, agent_a, &mut rng);
+            } else {
+                make_random_change(&mut other, None, agent_b, &mut rng);
+            }
+            if i % 10 == 0 {
+                other.replicate_into(&mut doc);
+                doc.replicate_into(&mut other);
+            }
+
+            let shadow = shadow.borrow();
+            let mut positions = vec![];
+            for (&(agent, seq), &(region, deleted)) in shadow.iter() {
+                let time = doc.client_data[agent as usize].seq_to_order(seq);
+                assert_eq!(Some(region), doc.region_at(time));
+
+                let pos = doc.lookup_position(CRDTId { agent, seq });
+                assert_eq!(deleted, pos.is_none());
+                positions.extend(pos);
+            }
+
+            // Every live item in the document has been reported.
+            positions.sort_unstable();
+            assert_eq!(positions, (0..doc.len()).collect::<Vec<_>>());
+        }
+
+        // Make sure the document grew big enough that leaves were split.
+        let regions: std::collections::HashSet<_> = shadow.borrow().values().map(|(r, _)| *r).collect();
+        assert!(regions.len() > 1);
+        // Leaves are numbered as they're created, and they all still hold some items.
+        assert_eq!(regions, (0..regions.len()).map(RegionId).collect());
+
+        // Once the listener is removed, we stop hearing about new items.
+        doc.clear_index_listener();
+        let known = shadow.borrow().len();
+        doc.local_insert(agent_a, 0, "x");
+        assert_eq!(shadow.borrow().len(), known);
+    }
+}
@@ -1,11 +1,13 @@
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::ptr::NonNull;
 use jumprope::{JumpRope, JumpRopeBuf};
 
 use smallvec::SmallVec;
 use smartstring::alias::String as SmartString;
 
 use content_tree::*;
-use diamond_core_old::AgentId;
+use diamond_core_old::{AgentId, CRDTId};
 pub use ot::traversal::TraversalComponent;
 pub use positional::{PositionalComponent, PositionalOp, InsDelTag};
 
@@ -33,6 +35,9 @@ mod branch;
 pub mod time;
 pub mod positional;
 mod merge_positional;
+mod subscription;
+
+pub use subscription::{OverflowPolicy, SubscriptionOptions, SubscriptionOverflow, SubscriptionStats};
 
 #[cfg(test)]
 mod positional_fuzzer;
@@ -135,4 +140,78 @@ pub struct ListCRDT {
     text_content: Option<JumpRopeBuf>,
     /// This is a big ol' string containing everything that's been deleted (self.deletes) in order.
     deleted_content: Option<String>,
+
+    /// Optional embedder callback (or queue), told whenever items move around in the range tree.
+    /// See [`ListCRDT::set_index_listener`] and [`ListCRDT::subscribe_index_events`].
+    index_listener: Option<IndexListener>,
+    /// The region IDs handed out to range tree leaves in index events.
+    regions: Regions,
+}
+
+/// Identifies the range tree leaf which currently holds some items. The value itself is
+/// meaningless - its only useful for comparing against other RegionIds. Items stay in the same
+/// region until an [`IndexEvent`] says they've moved.
+///
+/// Region IDs are counted up from 0 as the document names new leaves, so they're never reused
+/// within a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RegionId(usize);
+
+type LeafPtr = NonNull<NodeLeaf<YjsSpan, DocRangeIndex, DOC_IE, DOC_LE>>;
+
+/// Assigns each range tree leaf a [`RegionId`] the first time an [`IndexEvent`] names it. Items
+/// are only ever marked as deleted, never removed, so the range tree doesn't free leaves while the
+/// document exists. Each leaf keeps its address (and its ID) for the life of the document.
+#[derive(Debug, Default)]
+struct Regions {
+    ids: HashMap<LeafPtr, RegionId>,
+    next: usize,
+}
+
+impl Regions {
+    fn id_for(&mut self, leaf: LeafPtr) -> RegionId {
+        *self.ids.entry(leaf).or_insert_with(|| {
+            self.next += 1;
+            RegionId(self.next - 1)
+        })
+    }
+}
+
+/// Emitted by the document when a run of items is created in, or moved to, a region of the range
+/// tree. This happens when items are inserted, when they're deleted (which marks them in place,
+/// but can split the entry containing them) and when a full leaf is split in two.
+///
+/// The run is always contiguous in both local time and in the inserting agent's sequence numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEvent {
+    /// The CRDT location of the first item in the run.
+    pub loc: CRDTId,
+    /// The local time of the first item in the run.
+    pub time: Time,
+    pub len: u32,
+    /// Whether the items in the run have been deleted.
+    pub deleted: bool,
+    /// Where the items are now.
+    pub region: RegionId,
+}
+
+enum IndexListener {
+    Callback(Box<dyn FnMut(IndexEvent)>),
+    /// Events are buffered until they're polled. See [`ListCRDT::subscribe_index_events`].
+    Queue(subscription::IndexQueue),
+}
+
+impl IndexListener {
+    fn emit(&mut self, event: IndexEvent) {
+        match self {
+            IndexListener::Callback(listener) => listener(event),
+            IndexListener::Queue(queue) => queue.push(event),
+        }
+    }
+}
+
+impl std::fmt::Debug for IndexListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IndexListener")
+    }
 }
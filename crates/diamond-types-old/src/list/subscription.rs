@@ -0,0 +1,341 @@
+//! Buffered delivery of [`IndexEvent`]s, for embedders which can't keep up with a synchronous
+//! callback. Events are queued up (to a limit) and collected with
+//! [`ListCRDT::poll_index_events`].
+
+use std::collections::{BTreeMap, VecDeque};
+use crate::list::{IndexEvent, IndexListener, ListCRDT, Time};
+
+/// What to do with a new event when a subscription already has `max_pending` events waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Merge the waiting events into fewer, larger events. An index only needs the latest region
+    /// of each item, so newer events replace the parts of older events they overlap, and events
+    /// for adjacent items which ended up in the same place are joined together. The merged events
+    /// cover exactly the same items (in time order).
+    ///
+    /// This never loses information. If merging doesn't help, the queue grows past `max_pending`.
+    Coalesce,
+    /// Throw away the oldest waiting event.
+    DropOldest,
+    /// Throw away the new event, and return an error from the next call to
+    /// [`ListCRDT::poll_index_events`].
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionOptions {
+    pub max_pending: usize,
+    pub on_overflow: OverflowPolicy,
+}
+
+/// Counters for a subscription, from [`ListCRDT::index_subscription_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubscriptionStats {
+    /// The number of events the document emitted.
+    pub events_emitted: usize,
+    /// The number of events which were merged into other events by [`OverflowPolicy::Coalesce`].
+    /// Splitting an older event around a newer one takes one back, so this is the number of events
+    /// saved overall.
+    pub events_coalesced: usize,
+    /// The number of events which were thrown away.
+    pub events_dropped: usize,
+}
+
+/// Returned by [`ListCRDT::poll_index_events`] when events were dropped using
+/// [`OverflowPolicy::Error`]. Any index built from earlier events is now out of date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionOverflow {
+    /// The number of events dropped since the last poll.
+    pub dropped: usize,
+}
+
+#[derive(Debug)]
+pub(super) struct IndexQueue {
+    opts: SubscriptionOptions,
+    pending: VecDeque<IndexEvent>,
+    /// Once a subscription using [`OverflowPolicy::Coalesce`] fills up, its events are moved in
+    /// here and merged as they arrive.
+    coalesced: Option<BTreeMap<Time, IndexEvent>>,
+    stats: SubscriptionStats,
+    /// Events dropped with OverflowPolicy::Error which haven't been reported yet.
+    unreported_drops: usize,
+}
+
+impl IndexQueue {
+    fn new(opts: SubscriptionOptions) -> Self {
+        Self {
+            opts,
+            pending: VecDeque::new(),
+            coalesced: None,
+            stats: SubscriptionStats::default(),
+            unreported_drops: 0,
+        }
+    }
+
+    pub(super) fn push(&mut self, event: IndexEvent) {
+        self.stats.events_emitted += 1;
+
+        if let Some(by_time) = self.coalesced.as_mut() {
+            // A new event in the middle of an old one splits it, so merging can make the queue
+            // longer.
+            let before = by_time.len() + 1;
+            insert_coalesced(by_time, event);
+            self.stats.events_coalesced = (self.stats.events_coalesced + before).saturating_sub(by_time.len());
+            return;
+        }
+
+        if self.pending.len() < self.opts.max_pending {
+            self.pending.push_back(event);
+            return;
+        }
+
+        match self.opts.on_overflow {
+            OverflowPolicy::Coalesce => {
+                let before = self.pending.len() + 1;
+                let mut by_time = BTreeMap::new();
+                for e in self.pending.drain(..).chain(std::iter::once(event)) {
+                    insert_coalesced(&mut by_time, e);
+                }
+                self.stats.events_coalesced = (self.stats.events_coalesced + before).saturating_sub(by_time.len());
+                self.coalesced = Some(by_time);
+            }
+            OverflowPolicy::DropOldest => {
+                self.stats.events_dropped += 1;
+                if self.pending.pop_front().is_some() {
+                    self.pending.push_back(event);
+                }
+            }
+            OverflowPolicy::Error => {
+                self.stats.events_dropped += 1;
+                self.unreported_drops += 1;
+            }
+        }
+    }
+
+    fn poll(&mut self) -> Result<Vec<IndexEvent>, SubscriptionOverflow> {
+        if self.unreported_drops > 0 {
+            let dropped = std::mem::take(&mut self.unreported_drops);
+            return Err(SubscriptionOverflow { dropped });
+        }
+        match self.coalesced.take() {
+            Some(by_time) => Ok(by_time.into_values().collect()),
+            None => Ok(self.pending.drain(..).collect()),
+        }
+    }
+}
+
+/// Returns the part of the event from `time` onwards.
+fn event_from(event: IndexEvent, time: Time) -> IndexEvent {
+    let offset = time - event.time;
+    let mut result = event;
+    result.loc.seq += offset;
+    result.time = time;
+    result.len -= offset;
+    result
+}
+
+/// Can `b` be joined onto the end of `a`?
+fn can_join(a: &IndexEvent, b: &IndexEvent) -> bool {
+    a.time + a.len == b.time
+        && a.loc.agent == b.loc.agent
+        && a.loc.seq + a.len == b.loc.seq
+        && a.deleted == b.deleted
+        && a.region == b.region
+}
+
+/// Add a new event to a set of non-overlapping events keyed by time, which leaves an index in the
+/// same state as delivering them in order. The new event replaces the parts of older events it
+/// overlaps, and is joined onto its neighbours where it can be.
+fn insert_coalesced(by_time: &mut BTreeMap<Time, IndexEvent>, mut event: IndexEvent) {
+    let end = event.time + event.len;
+    let overlapping: Vec<Time> = by_time.range(..end).rev()
+        .take_while(|(_, e)| e.time + e.len > event.time)
+        .map(|(t, _)| *t)
+        .collect();
+
+    for t in overlapping {
+        let old = by_time.remove(&t).unwrap();
+        if old.time < event.time {
+            let mut before = old;
+            before.len = event.time - old.time;
+            by_time.insert(before.time, before);
+        }
+        if old.time + old.len > end {
+            by_time.insert(end, event_from(old, end));
+        }
+    }
+
+    // Everything else was already joined up as much as it can be, so only the new event's
+    // neighbours need checking.
+    if let Some(prev) = by_time.range_mut(..event.time).next_back().map(|(_, e)| e) {
+        if can_join(prev, &event) {
+            prev.len += event.len;
+            event = *prev;
+            by_time.remove(&event.time);
+        }
+    }
+    if let Some(next) = by_time.get(&end) {
+        if can_join(&event, next) {
+            event.len += next.len;
+            by_time.remove(&end);
+        }
+    }
+    by_time.insert(event.time, event);
+}
+
+impl ListCRDT {
+    /// Queue up index events instead of running a callback for each one. The events can be
+    /// collected later with [`poll_index_events`](ListCRDT::poll_index_events), so a slow consumer
+    /// doesn't hold up editing. At most `opts.max_pending` events are kept waiting (except when
+    /// coalescing), and `opts.on_overflow` says what happens to the rest.
+    ///
+    /// This replaces any listener set with [`set_index_listener`](ListCRDT::set_index_listener).
+    pub fn subscribe_index_events(&mut self, opts: SubscriptionOptions) {
+        self.index_listener = Some(IndexListener::Queue(IndexQueue::new(opts)));
+    }
+
+    /// Take all the index events which are waiting. Returns an empty list if there's no
+    /// subscription.
+    ///
+    /// If events were dropped using [`OverflowPolicy::Error`], this returns an error once. The
+    /// waiting events are returned by the next call.
+    pub fn poll_index_events(&mut self) -> Result<Vec<IndexEvent>, SubscriptionOverflow> {
+        match self.index_listener.as_mut() {
+            Some(IndexListener::Queue(queue)) => queue.poll(),
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Counters for the subscription made with
+    /// [`subscribe_index_events`](ListCRDT::subscribe_index_events), if there is one.
+    pub fn index_subscription_stats(&self) -> Option<SubscriptionStats> {
+        match self.index_listener.as_ref() {
+            Some(IndexListener::Queue(queue)) => Some(queue.stats),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use rand::prelude::*;
+    use diamond_core_old::CRDTId;
+    use crate::list::{IndexEvent, ListCRDT, RegionId};
+    use crate::test_helpers::make_random_change;
+    use super::*;
+
+    /// Make the same series of random edits to a document, calling poll every few steps.
+    fn run_edits<F: FnMut(&mut ListCRDT)>(doc: &mut ListCRDT, mut poll: F) {
+        let mut other = ListCRDT::new();
+        let agent_a = doc.get_or_create_agent_id("a");
+        let agent_b = other.get_or_create_agent_id("b");
+
+        let mut rng = SmallRng::seed_from_u64(123);
+        for i in 0..300 {
+            if rng.gen_bool(0.7) {
+                make_random_change(doc, None, agent_a, &mut rng);
+            } else {
+                make_random_change(&mut other, None, agent_b, &mut rng);
+            }
+            if i % 10 == 0 {
+                other.replicate_into(doc);
+                doc.replicate_into(&mut other);
+            }
+            if i % 50 == 49 { poll(doc); }
+        }
+        poll(doc);
+    }
+
+    /// The set of local times named by a list of events.
+    fn covered(events: &[IndexEvent]) -> Vec<Time> {
+        let mut times: Vec<Time> = events.iter().flat_map(|e| e.time..e.time + e.len).collect();
+        times.sort_unstable();
+        times.dedup();
+        times
+    }
+
+    fn all_events() -> Vec<IndexEvent> {
+        let events = Rc::new(RefCell::new(vec![]));
+        let mut doc = ListCRDT::new();
+        let e = events.clone();
+        doc.set_index_listener(Box::new(move |event| e.borrow_mut().push(event)));
+        run_edits(&mut doc, |_| {});
+        events.take()
+    }
+
+    #[test]
+    fn coalescing_keeps_coverage() {
+        let expected = all_events();
+
+        let mut doc = ListCRDT::new();
+        doc.subscribe_index_events(SubscriptionOptions { max_pending: 8, on_overflow: OverflowPolicy::Coalesce });
+        let mut delivered = vec![];
+        let mut shadow = HashMap::new();
+        run_edits(&mut doc, |doc| {
+            let events = doc.poll_index_events().unwrap();
+            for e in events.iter() {
+                for i in 0..e.len {
+                    shadow.insert((e.loc.agent, e.loc.seq + i), (e.region, e.deleted));
+                }
+            }
+            delivered.extend(events);
+        });
+
+        assert_eq!(covered(&delivered), covered(&expected));
+
+        let stats = doc.index_subscription_stats().unwrap();
+        assert_eq!(stats.events_emitted, expected.len());
+        assert_eq!(stats.events_dropped, 0);
+        assert!(stats.events_coalesced > 0);
+        assert_eq!(stats.events_emitted, delivered.len() + stats.events_coalesced);
+
+        // The coalesced events still leave the index in the right state.
+        for (&(agent, seq), &(region, deleted)) in shadow.iter() {
+            let time = doc.client_data[agent as usize].seq_to_order(seq);
+            assert_eq!(Some(region), doc.region_at(time));
+            assert_eq!(deleted, doc.lookup_position(CRDTId { agent, seq }).is_none());
+        }
+    }
+
+    #[test]
+    fn drop_oldest_counts_drops() {
+        let expected = all_events();
+
+        let mut doc = ListCRDT::new();
+        doc.subscribe_index_events(SubscriptionOptions { max_pending: 3, on_overflow: OverflowPolicy::DropOldest });
+        let mut delivered = vec![];
+        run_edits(&mut doc, |doc| delivered.extend(doc.poll_index_events().unwrap()));
+
+        let stats = doc.index_subscription_stats().unwrap();
+        assert_eq!(stats.events_emitted, expected.len());
+        assert_eq!(stats.events_coalesced, 0);
+        assert_eq!(stats.events_dropped, expected.len() - delivered.len());
+
+        // The newest events are the ones kept.
+        let last = |events: &[IndexEvent]| events.last().map(|e| (e.loc, e.time, e.len, e.deleted));
+        assert_eq!(last(&delivered), last(&expected));
+    }
+
+    #[test]
+    fn error_reports_drops_on_next_poll() {
+        let mut doc = ListCRDT::new();
+        assert_eq!(doc.poll_index_events(), Ok(vec![]));
+        assert_eq!(doc.index_subscription_stats(), None);
+
+        doc.subscribe_index_events(SubscriptionOptions { max_pending: 2, on_overflow: OverflowPolicy::Error });
+        let agent = doc.get_or_create_agent_id("a");
+        for _ in 0..5 { doc.local_insert(agent, 0, "x"); }
+        let emitted = doc.index_subscription_stats().unwrap().events_emitted;
+        assert!(emitted >= 5);
+
+        assert_eq!(doc.poll_index_events(), Err(SubscriptionOverflow { dropped: emitted - 2 }));
+        assert_eq!(doc.poll_index_events().unwrap().len(), 2);
+        assert_eq!(doc.poll_index_events(), Ok(vec![]));
+
+        let stats = doc.index_subscription_stats().unwrap();
+        assert_eq!(stats, SubscriptionStats { events_emitted: emitted, events_coalesced: 0, events_dropped: emitted - 2 });
+    }
+}
@@ -0,0 +1,67 @@
+//! Formatting for `dt doctor`. The actual analysis lives in diamond_types::list::compat.
+
+use diamond_types::list::compat::{CompatReport, Recommendation, Squashed};
+
+fn fmt_ranges<T: std::fmt::Debug>(ranges: &[T]) -> String {
+    ranges.iter()
+        .map(|r| format!("{:?}", r))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn print_report(a_name: &str, b_name: &str, report: &CompatReport) {
+    println!("Comparing '{a_name}' (A) and '{b_name}' (B)\n");
+
+    for (name, err) in [("A", &report.a_load_error), ("B", &report.b_load_error)] {
+        if let Some(err) = err {
+            println!("{name} cannot be loaded on its own: {err}");
+        }
+    }
+
+    if report.doc_id_mismatch {
+        println!("The files have different document IDs.");
+    }
+
+    match report.squashed {
+        Some(Squashed::SnapshotA) => println!("A is a snapshot. It has the document's content, but none of its history."),
+        Some(Squashed::SnapshotB) => println!("B is a snapshot. It has the document's content, but none of its history."),
+        Some(Squashed::SameContent) => println!("The files have the same content, but no operations in common."),
+        None => {}
+    }
+
+    if report.shared_agents.is_empty() {
+        println!("The files have no agents in common.");
+    } else {
+        println!("Shared agents:");
+        for agent in report.shared_agents.iter() {
+            println!("  {}: A has {}, B has {}", agent.name,
+                     fmt_ranges(&agent.a_seqs), fmt_ranges(&agent.b_seqs));
+            if !agent.overlap.is_empty() {
+                println!("    overlapping seqs {}", fmt_ranges(&agent.overlap));
+            }
+        }
+    }
+
+    if !report.conflicts.is_empty() {
+        println!("\nThese (agent, seq) IDs name different operations in each file:");
+        for c in report.conflicts.iter() {
+            println!("  {} {:?}", c.name, c.seqs);
+        }
+    }
+
+    println!();
+    for (desc, result) in [("A into B", &report.merge_a_into_b), ("B into A", &report.merge_b_into_a)] {
+        match result {
+            Ok(()) => println!("Merging {desc}: ok"),
+            Err(e) => println!("Merging {desc}: failed ({e})"),
+        }
+    }
+
+    println!("\n{}", match report.recommendation {
+        Recommendation::Mergeable => "Recommendation: These files can be merged.",
+        Recommendation::NeedsIntermediatePatches => "Recommendation: Neither file contains the base version the other needs. Merge in the intermediate changes first.",
+        Recommendation::IrreconcilableFork => "Recommendation: These files are irreconcilable forks and cannot be safely merged.",
+        Recommendation::Squashed => "Recommendation: One file is a copy of the document without the other's history. Use the file with the history instead of merging them.",
+        Recommendation::Unreadable => "Recommendation: At least one file could not be read.",
+    });
+}
@@ -7,16 +7,18 @@
 /// 1. The exported data is missing user agents. (Or should be missing user agents)
 /// 2. The exported data is missing `fwd: bool` for operations.
 ///
-/// Write a second export script which outputs the data to some dt-json style format (making this a
-/// non-issue). Or just add these fields in and demand people ignore them.
-
+/// [`export_oplog`] writes the whole oplog in a dt-json style format instead, for when the result
+/// needs to be turned back into an identical DT document.
 use std::collections::HashMap;
-use serde::Serialize;
+use anyhow::bail;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
-use diamond_types::list::ListOpLog;
+use diamond_types::list::{ListBranch, ListOpLog};
 use diamond_types::list::operation::{ListOpKind, TextOperation};
 use smartstring::alias::{String as SmartString};
-use diamond_types::HasLength;
+use diamond_types::{HasLength, LV};
+use diamond_types::causalgraph::agent_assignment::remote_ids::{RemoteVersion, RemoteVersionOwned};
 
 // Note this discards the fwd/backwards direction of the changes. This shouldn't matter in
 // practice given the whole operation is unitary.
@@ -100,6 +102,207 @@ pub fn export_to_json(oplog: &ListOpLog) -> ExportedEditHistory {
         txns,
     }
 }
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// An editing trace, for other CRDT libraries. See [`export_to_json`].
+    Trace,
+    /// The whole oplog. See [`export_oplog`].
+    Json,
+}
+
+/// A whole oplog as JSON. Unlike [`ExportedEditHistory`], this has everything needed to rebuild
+/// an equivalent oplog with [`import_oplog`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedOplog {
+    /// Every agent in the oplog, in order. Agents which didn't make any changes are kept.
+    agents: Vec<SmartString>,
+    entries: Vec<ExportedOplogEntry>,
+}
+
+/// A run of operations made by one agent, with consecutive seq numbers. Each operation after the
+/// first has the previous operation as its parent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedOplogEntry {
+    agent: SmartString,
+    /// The seq number of the first operation.
+    seq: usize,
+    /// The parents of the first operation, as (agent, seq) pairs. Parents always come earlier in
+    /// the entry list.
+    parents: Vec<RemoteVersionOwned>,
+    ops: Vec<TextOperation>,
+}
+
+pub fn export_oplog(oplog: &ListOpLog) -> ExportedOplog {
+    let aa = &oplog.cg.agent_assignment;
+
+    ExportedOplog {
+        agents: (0..aa.num_agents()).map(|agent| aa.get_agent_name(agent as _).into()).collect(),
+        entries: oplog.iter_full_2().into_iter().map(|entry| ExportedOplogEntry {
+            agent: aa.get_agent_name(entry.agent_id).into(),
+            seq: aa.local_to_agent_version(entry.span.start).1,
+            parents: entry.parents.iter().map(|p| aa.local_to_remote_version(*p).to_owned()).collect(),
+            ops: entry.ops.into_vec(),
+        }).collect(),
+    }
+}
+
+/// Rebuild an oplog from JSON written by [`export_oplog`].
+///
+/// Reusing an (agent, seq) pair for two different operations would corrupt the oplog (see the
+/// warning on `dt set --agent`), so entries which do that are rejected. So are operations which
+/// fall outside the document they're applied to. Checking a merge means merging a document to
+/// the merge's parents, so this is slow on histories with lots of merges.
+pub fn import_oplog(data: &ExportedOplog) -> Result<ListOpLog, anyhow::Error> {
+    let mut oplog = ListOpLog::new();
+    for name in &data.agents {
+        oplog.get_or_create_agent_id(name);
+    }
+
+    // Operations are checked against the length of the document at their entry's parents. Merging
+    // to each entry's parents is slow on branchy histories, so the document length after each
+    // entry is remembered. An entry which follows on from another one starts from that length.
+    // Other entries (merges, or ones which branch off partway through an entry) move the branch.
+    let mut len_after: HashMap<LV, usize> = HashMap::new();
+    let mut branch = ListBranch::new();
+
+    for (i, entry) in data.entries.iter().enumerate() {
+        if entry.ops.iter().any(|op| op.start() > op.end()) {
+            bail!("Entry {i} has an operation which ends before it starts");
+        }
+        let Some(len) = entry.ops.iter().try_fold(0usize, |sum, op| sum.checked_add(op.len())) else {
+            bail!("Entry {i} is too long");
+        };
+        if len == 0 { bail!("Entry {i} has no operations"); }
+        for op in &entry.ops {
+            if op.kind == ListOpKind::Ins && op.content.as_ref().map(|c| c.chars().count()) != Some(op.len()) {
+                bail!("Entry {i} has an insert whose content doesn't match its length");
+            }
+        }
+
+        let parents = oplog.cg.agent_assignment.try_remote_to_local_frontier(
+            entry.parents.iter().map(|RemoteVersionOwned(name, seq)| RemoteVersion(name, *seq))
+        ).map_err(|e| anyhow::anyhow!("Entry {i} has an unknown parent: {e:?}"))?;
+
+        let mut doc_len = match parents.as_ref() {
+            [] => 0,
+            [p] if len_after.contains_key(p) => len_after[p],
+            _ => {
+                branch.advance_to(&oplog, parents.as_ref());
+                branch.len()
+            }
+        };
+        for op in &entry.ops {
+            // Inserted content is checked against the length above, so inserts only need a valid
+            // start position.
+            let past_end = match op.kind {
+                ListOpKind::Ins => op.start() > doc_len,
+                ListOpKind::Del => op.end() > doc_len,
+            };
+            if past_end {
+                bail!("Entry {i} has an operation at {}..{} outside the document (length {doc_len})", op.start(), op.end());
+            }
+            match op.kind {
+                ListOpKind::Ins => doc_len += op.len(),
+                ListOpKind::Del => doc_len -= op.len(),
+            }
+        }
+
+        let Some(end_seq) = entry.seq.checked_add(len) else {
+            bail!("Entry {i} has seq numbers past the end of the range");
+        };
+        let agent = oplog.get_or_create_agent_id(&entry.agent);
+        for seq in entry.seq..end_seq {
+            if oplog.cg.agent_assignment.try_remote_to_local_version(RemoteVersion(&entry.agent, seq)).is_ok() {
+                bail!("Entry {i} reuses ({}, {seq}), which is already assigned to another operation", entry.agent);
+            }
+        }
+
+        let last = oplog.add_remote_operations_at(agent, entry.seq, parents.as_ref(), &entry.ops);
+        len_after.insert(last, doc_len);
+    }
+
+    Ok(oplog)
+}
+
+#[cfg(test)]
+mod test {
+    use diamond_types::list::ListOpLog;
+    use super::{export_oplog, ExportedOplog, import_oplog};
+
+    fn round_trip(oplog: &ListOpLog) -> Result<ListOpLog, anyhow::Error> {
+        let json = serde_json::to_string(&export_oplog(oplog)).unwrap();
+        import_oplog(&serde_json::from_str(&json).unwrap())
+    }
+
+    #[test]
+    fn oplog_json_round_trips() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.get_or_create_agent_id("unused");
+        let a = oplog.add_insert(seph, 0, "hi there");
+        let b = oplog.add_insert_at(mike, &[a], 2, " you");
+        oplog.add_delete_at(seph, &[a], 0..3);
+        // Backwards typing makes reversed inserts.
+        for c in ["c", "b", "a"] {
+            oplog.add_insert_at(mike, &[b], 0, c);
+        }
+        oplog.add_insert(seph, 0, "🙂");
+
+        assert_eq!(round_trip(&oplog).unwrap(), oplog);
+
+        // Importing checks every merge against the document at its parents, which is slow. The
+        // start of the git history still has plenty of branches and merges.
+        let bytes = std::fs::read("../../benchmark_data/git-makefile.dt").unwrap();
+        let oplog = ListOpLog::load_from(&bytes).unwrap().truncate_to(&[50_000]);
+        assert_eq!(round_trip(&oplog).unwrap(), oplog);
+    }
+
+    #[test]
+    fn reused_seqs_are_rejected() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "abc");
+        // Concurrent with the first insert, so it's a separate entry.
+        oplog.add_insert_at(seph, &[], 0, "xyz");
+
+        let mut data: ExportedOplog = serde_json::from_str(&serde_json::to_string(&export_oplog(&oplog)).unwrap()).unwrap();
+        data.entries[1].seq = 1;
+        let err = import_oplog(&data).unwrap_err();
+        assert!(err.to_string().contains("reuses (seph, 1)"), "{err}");
+    }
+
+    #[test]
+    fn ops_outside_the_document_are_rejected() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let a = oplog.add_insert(seph, 0, "abc");
+        oplog.add_delete_at(mike, &[a], 1..3);
+        // Concurrent with the delete, so it's applied to "abc" rather than "a".
+        oplog.add_insert_at(seph, &[a], 3, "d");
+        let data: ExportedOplog = serde_json::from_str(&serde_json::to_string(&export_oplog(&oplog)).unwrap()).unwrap();
+        assert_eq!(import_oplog(&data).unwrap(), oplog);
+
+        let mut bad = data.clone();
+        bad.entries[1].ops[0].loc.span = (1..4).into();
+        let err = import_oplog(&bad).unwrap_err();
+        assert!(err.to_string().contains("outside the document"), "{err}");
+
+        let mut bad = data.clone();
+        bad.entries[0].ops[0].loc.span = (1..4).into();
+        let err = import_oplog(&bad).unwrap_err();
+        assert!(err.to_string().contains("outside the document"), "{err}");
+
+        let mut bad = data.clone();
+        bad.entries[1].seq = usize::MAX - 1;
+        let err = import_oplog(&bad).unwrap_err();
+        assert!(err.to_string().contains("past the end"), "{err}");
+    }
+}
+
 // pub fn export_to_json(oplog: &ListOpLog) -> Vec<ExportEntry> {
 //     let mut result = vec![];
 //
@@ -4,10 +4,10 @@
 // #![allow(unused_imports)]
 
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::OpenOptions;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use anyhow::Context;
+use anyhow::{bail, Context};
 use git2::{BranchType, Commit, Oid, Repository};
 use git2::ObjectType::Blob;
 use similar::{ChangeTag, TextDiff};
@@ -15,8 +15,13 @@ use similar::utils::TextDiffRemapper;
 use smallvec::{SmallVec, smallvec};
 use indicatif::ProgressBar;
 use std::io::{BufWriter, Write};
+use serde::{Deserialize, Serialize};
 
+use diamond_types::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
 use diamond_types::list::*;
+use diamond_types::list::encoding::{ENCODE_FULL, EncodeOptions};
+use crate::version::{resolve_version, Version};
+use crate::write_atomic;
 
 /// In the git repository for linux, there are commits (maybe just one commit?) with the same commit
 /// named twice in the parents list. Its this commit: 13e652800d1644dfedcd0d59ac95ef0beb7f3165
@@ -59,7 +64,101 @@ impl<'commit> UniqParentIds<'commit> {
     }
 }
 
-pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>) -> anyhow::Result<ListOpLog> {
+/// How many commits [`convert_resume`] processes between checkpoints by default.
+pub const DEFAULT_CHECKPOINT_EVERY: usize = 1000;
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"DTGITCP1";
+
+/// The converter's progress, saved in a checkpoint file next to the oplog.
+///
+/// This doesn't store the parents and children of each commit. They're recomputed by scanning
+/// the repository again when resuming, which is quick compared to converting the commits.
+#[derive(Debug, Serialize, Deserialize)]
+struct ConversionState {
+    /// The file being converted and the commit the conversion ends at. A checkpoint is only
+    /// resumed if these match.
+    file: String,
+    head: String,
+
+    /// Commits which are ready to be processed (all their parents are done), in stack order.
+    fwd_frontier: Vec<String>,
+
+    /// The branch for each processed commit which still has unprocessed children.
+    pending: Vec<PendingBranch>,
+
+    commits_processed: usize,
+    git_bytes_read: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingBranch {
+    commit: String,
+
+    /// The branch is checked out again from the oplog at this version. (Local versions aren't
+    /// stable across encoding, so the remote version is stored instead.)
+    version: Vec<RemoteVersionOwned>,
+
+    /// The git object ID of the file in this commit.
+    file_oid: String,
+    remaining_children: usize,
+}
+
+/// Checkpoints are stored as the magic bytes, the length of the encoded oplog (u64 LE), the
+/// encoded oplog and then the rest of the state as JSON.
+fn write_checkpoint(path: &Path, oplog: &ListOpLog, state: &ConversionState) -> anyhow::Result<()> {
+    // Deleted content is kept so the resumed oplog is identical to an uninterrupted conversion.
+    let oplog_data = oplog.encode(EncodeOptions {
+        store_deleted_content: true,
+        ..ENCODE_FULL
+    });
+
+    let mut data = Vec::with_capacity(oplog_data.len() + 1024);
+    data.extend_from_slice(CHECKPOINT_MAGIC);
+    data.extend_from_slice(&(oplog_data.len() as u64).to_le_bytes());
+    data.extend_from_slice(&oplog_data);
+    serde_json::to_writer(&mut data, state)?;
+
+    write_atomic(path, &data)?;
+    Ok(())
+}
+
+fn read_checkpoint(path: &Path) -> anyhow::Result<(ListOpLog, ConversionState)> {
+    let data = std::fs::read(path)?;
+    if data.len() < 16 || &data[..8] != CHECKPOINT_MAGIC {
+        bail!("{} is not a git-import checkpoint", path.display());
+    }
+
+    let len = u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize;
+    let (oplog_data, state_data) = data[16..].split_at_checked(len)
+        .context("Checkpoint file is truncated")?;
+
+    let oplog = ListOpLog::load_from(oplog_data)?;
+    let state = serde_json::from_slice(state_data)?;
+    Ok((oplog, state))
+}
+
+pub fn extract_from_git(input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>) -> anyhow::Result<ListOpLog> {
+    let oplog = convert(input_path, branch, quiet, map_out, None, &mut |_| false)?;
+    Ok(oplog.expect("Conversion can't be interrupted"))
+}
+
+/// Like [`extract_from_git`], but the conversion's progress is saved to `checkpoint_path` every
+/// `checkpoint_every` commits. If the checkpoint file already exists, the conversion picks up
+/// where it left off. The result is the same as an uninterrupted conversion.
+///
+/// When resuming, the commit map (if any) is appended to. It may list some commits twice.
+pub fn convert_resume(input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>, checkpoint_path: &Path, checkpoint_every: usize) -> anyhow::Result<ListOpLog> {
+    assert!(checkpoint_every > 0);
+    let oplog = convert(input_path, branch, quiet, map_out, Some((checkpoint_path, checkpoint_every)), &mut |_| false)?;
+    Ok(oplog.expect("Conversion can't be interrupted"))
+}
+
+/// Convert the file's history. After each commit, `interrupt` is called with the number of commits
+/// processed so far. If it returns true, the conversion stops (as if the process was killed) and
+/// None is returned.
+fn convert(mut input_path: PathBuf, branch: Option<String>, quiet: bool, map_out: Option<PathBuf>,
+           checkpoint: Option<(&Path, usize)>, interrupt: &mut dyn FnMut(usize) -> bool
+) -> anyhow::Result<Option<ListOpLog>> {
     // let mut args: Args = argh::from_env();
 
     if input_path.is_relative() {
@@ -102,6 +201,7 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
     // let mut commit_info = HashMap::<Oid, (SmallVec<[Oid; 3]>, SmallVec<[Oid; 3]>)>::new();
 
     let c = head.peel_to_commit().unwrap();
+    let head_id = c.id();
     scan_frontier.push(c.id());
     // Mark the final change as having no children.
     commit_children.insert(c.id(), smallvec![]);
@@ -142,10 +242,43 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
     let mut branch_at_oid = HashMap::<Oid, (ListBranch, Oid, usize)>::new();
     // let mut branch_at_oid = HashMap::<Oid, ListBranch>::new();
 
-    // Unwrap is lazy here, but kinda fine.
-    let mut map_file = map_out.map(|map_path| BufWriter::new(File::create(map_path).unwrap()));
-
     let mut git_bytes_read = 0;
+    let mut commits_processed = 0;
+
+    let file_name = path.to_string_lossy().into_owned();
+    let resume = match checkpoint {
+        Some((checkpoint_path, _)) if checkpoint_path.exists() => Some(read_checkpoint(checkpoint_path)?),
+        _ => None,
+    };
+    let resuming = resume.is_some();
+
+    if let Some((checkpoint_oplog, state)) = resume {
+        if state.file != file_name || state.head != head_id.to_string() {
+            bail!("The checkpoint was saved while converting {} at commit {}", state.file, state.head);
+        }
+        if !quiet { println!("Resuming after {} commits", state.commits_processed); }
+
+        fwd_frontier = state.fwd_frontier.iter()
+            .map(|id| Oid::from_str(id))
+            .collect::<Result<_, _>>()?;
+
+        for p in state.pending {
+            let version = resolve_version(&checkpoint_oplog, Some(&Version(p.version.into())))?;
+            // Branches are behind the oplog's tip when they have unprocessed children.
+            let branch = checkpoint_oplog.checkout(version.as_ref()).fork_editable();
+            branch_at_oid.insert(Oid::from_str(&p.commit)?, (branch, Oid::from_str(&p.file_oid)?, p.remaining_children));
+        }
+
+        oplog = checkpoint_oplog;
+        commits_processed = state.commits_processed;
+        git_bytes_read = state.git_bytes_read;
+    }
+
+    // Unwrap is lazy here, but kinda fine.
+    let mut map_file = map_out.map(|map_path| BufWriter::new(
+        OpenOptions::new().create(true).write(true).append(resuming).truncate(!resuming)
+            .open(map_path).unwrap()
+    ));
 
     let take = |branch_at_oid: &mut HashMap::<Oid, (ListBranch, Oid, usize)>, p_id: Oid| -> (ListBranch, Oid) {
         let (branch_here, oid, num_children) = branch_at_oid.get_mut(&p_id)
@@ -180,7 +313,7 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
             // for p_id in commit.parent_ids() {
             //     let (branch_here, num_children) = branch_at_oid.get_mut(&p_id).unwrap();
             //
-            //     frontier.extend_from_slice(branch_here.local_frontier_ref());
+            //     frontier.extend_from_slice(branch_here.local_version_ref());
             //
             //     debug_assert!(*num_children > 0);
             //     if *num_children == 1 {
@@ -195,7 +328,7 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
             //
             // // The frontier might contain repeated elements. Simplify!
             // frontier.sort_unstable();
-            // let merge_frontier = oplog.cg.graph.find_dominators(&frontier);
+            // let merge_frontier = oplog.find_dominators(&frontier);
             //
             // let mut branch = branch.unwrap_or_else(|| {
             //     // We might not have found any branch with no parents.
@@ -215,14 +348,14 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
 
             for p in iter {
                 let (child_branch, child_oid) = take(branch_at_oid, p);
-                let child_frontier = child_branch.local_frontier_ref();
+                let child_frontier = child_branch.local_version_ref();
 
                 // This is a microoptimization. It makes some traces a little faster.
-                if oplog.cg.graph.frontier_contains_frontier(child_frontier, branch.local_frontier_ref()) {
+                if oplog.cg.graph.frontier_contains_frontier(child_frontier, branch.local_version_ref()) {
                     // Well, just use the child branch then.
                     branch = child_branch;
                     oid = Some(child_oid);
-                } else if !oplog.cg.graph.frontier_contains_frontier(branch.local_frontier_ref(), child_frontier) {
+                } else if !oplog.cg.graph.frontier_contains_frontier(branch.local_version_ref(), child_frontier) {
                     // They're concurrent.
                     branch.merge(&oplog, child_frontier);
                     oid = None;
@@ -240,6 +373,7 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
     } else {
         ProgressBar::new(commit_parents.len() as _)
     };
+    bar.set_position(commits_processed as _);
 
     // let mut i = 0;
     while let Some(commit_id) = fwd_frontier.pop() {
@@ -296,6 +430,7 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
                     // dbg!(changes);
                     // for change in diff.iter
 
+                    let first_new = oplog.len();
                     let mut pos = 0;
                     for (tag, str) in diff.ops().iter()
                         .flat_map(move |x| remapper.iter_slices(x)) {
@@ -321,8 +456,11 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
                     assert_eq!(branch.content(), &new);
                     // println!("branch '{}' -> '{}'", old, branch.content);
 
+                    // Keep the time the commit was authored, in seconds since the unix epoch.
+                    oplog.set_timestamp((first_new..oplog.len()).into(), sig.when().seconds());
+
                     if let Some(map_file) = map_file.as_mut() {
-                        let frontier = branch.local_frontier_ref();
+                        let frontier = branch.local_version_ref();
                         let rv = oplog.cg.agent_assignment.local_to_remote_frontier(frontier);
                         writeln!(map_file, "{},{}",
                             commit.id(),
@@ -349,6 +487,37 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
                 }
             }
         }
+
+        commits_processed += 1;
+        if let Some((checkpoint_path, checkpoint_every)) = checkpoint {
+            if commits_processed % checkpoint_every == 0 {
+                let mut pending: Vec<PendingBranch> = branch_at_oid.iter()
+                    .map(|(commit, (branch, file_oid, remaining_children))| PendingBranch {
+                        commit: commit.to_string(),
+                        version: oplog.cg.agent_assignment.local_to_remote_frontier_owned(branch.local_version_ref()).into_vec(),
+                        file_oid: file_oid.to_string(),
+                        remaining_children: *remaining_children,
+                    })
+                    .collect();
+                pending.sort_unstable_by(|a, b| a.commit.cmp(&b.commit));
+
+                if let Some(map_file) = map_file.as_mut() {
+                    map_file.flush()?;
+                }
+                write_checkpoint(checkpoint_path, &oplog, &ConversionState {
+                    file: file_name.clone(),
+                    head: head_id.to_string(),
+                    fwd_frontier: fwd_frontier.iter().map(|id| id.to_string()).collect(),
+                    pending,
+                    commits_processed,
+                    git_bytes_read,
+                })?;
+            }
+        }
+
+        if interrupt(commits_processed) {
+            return Ok(None);
+        }
     }
     bar.finish();
 
@@ -357,7 +526,7 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
     // dbg!(&oplog);
     // let branch = ListBranch::new_at_tip(&oplog);
     // println!("{}: '{}'", file, branch.content);
-    // println!("Branch at {:?}", branch.local_frontier_ref());
+    // println!("Branch at {:?}", branch.local_version_ref());
 
     // dbg!(&oplog.history.entries.len());
     // println!("Number of entries in history: {}", &oplog.history.num_entries());
@@ -380,5 +549,74 @@ pub fn extract_from_git(mut input_path: PathBuf, branch: Option<String>, quiet:
         println!("Read {} bytes of content from git commits", git_bytes_read);
     }
 
-    Ok(oplog)
+    Ok(Some(oplog))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use git2::{Oid, Repository, Signature, Time};
+    use super::{convert, convert_resume, extract_from_git};
+
+    fn commit(repo: &Repository, content: &str, parents: &[Oid], author: &str) -> Oid {
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut tree = repo.treebuilder(None).unwrap();
+        tree.insert("doc.txt", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(tree.write().unwrap()).unwrap();
+
+        let sig = Signature::new(author, "test@example.com", &Time::new(0, 0)).unwrap();
+        let parents: Vec<_> = parents.iter().map(|p| repo.find_commit(*p).unwrap()).collect();
+        let parents: Vec<_> = parents.iter().collect();
+        repo.commit(None, &sig, &sig, content, &tree, &parents).unwrap()
+    }
+
+    /// Make a repository where doc.txt is edited on a few branches which are merged together.
+    fn make_repo(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dt-git-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+
+        let c0 = commit(&repo, "hello\n", &[], "seph");
+        let c1 = commit(&repo, "hello, world\n", &[c0], "seph");
+        let c2 = commit(&repo, "oh hello\n", &[c0], "mike");
+        let c3 = commit(&repo, "oh hello world\n", &[c1, c2], "seph");
+        let c4 = commit(&repo, "oh hello world!\n", &[c3], "mike");
+        let c5 = commit(&repo, "oh hello world\nbye\n", &[c3], "seph");
+        let c6 = commit(&repo, "oh hello world!\nbye\n", &[c4, c5], "seph");
+        let c7 = commit(&repo, "Oh hello world!\nbye\n", &[c6], "mike");
+        repo.reference("refs/heads/master", c7, true, "test").unwrap();
+
+        std::fs::write(dir.join("doc.txt"), "Oh hello world!\nbye\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn resume_matches_uninterrupted_conversion() {
+        let dir = make_repo("resume");
+        let doc = dir.join("doc.txt");
+        let checkpoint = dir.join("convert.checkpoint");
+
+        let expected = extract_from_git(doc.clone(), None, true, None).unwrap();
+        assert_eq!(expected.checkout_tip().content(), "Oh hello world!\nbye\n");
+
+        // Crash after 5 commits. The last checkpoint was after commit 4 (the first merge, which
+        // deletes the comma), with the branches at c4 and c5 still pending.
+        let result = convert(doc.clone(), None, true, None, Some((&checkpoint, 2)), &mut |n| n == 5).unwrap();
+        assert!(result.is_none());
+        assert!(checkpoint.exists());
+
+        let mut first_commit = None;
+        let resumed = convert(doc.clone(), None, true, None, Some((&checkpoint, 2)), &mut |n| {
+            first_commit.get_or_insert(n);
+            false
+        }).unwrap().unwrap();
+        assert_eq!(first_commit, Some(5));
+        assert_eq!(resumed, expected);
+
+        // Resuming from the checkpoint saved at the end of the conversion has nothing left to do.
+        let finished = convert_resume(doc, None, true, None, &checkpoint, 2).unwrap();
+        assert_eq!(finished, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
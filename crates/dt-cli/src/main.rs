@@ -1,24 +1,32 @@
 mod export;
 mod dot;
 mod git;
+mod doctor;
+mod version;
 
 use std::ffi::OsString;
 use std::fs;
 use std::fs::File;
 use std::io::{BufWriter, ErrorKind, Read, Write};
-use std::path::PathBuf;
-use std::str::FromStr;
-use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use similar::{ChangeTag, TextDiff};
 use similar::utils::TextDiffRemapper;
-use diamond_types::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use diamond_types::{AgentId, DTRange, Frontier, HasLength, ParseError};
+use diamond_types::causalgraph::agent_assignment::remote_ids::RemoteVersionSpan;
 use diamond_types::list::{ListBranch, ListOpLog};
-use diamond_types::list::encoding::{ENCODE_FULL, EncodeOptions};
+use diamond_types::list::operation::ListOpKind;
+use diamond_types::list::encoding::{capabilities, CapabilitySet, chunk_sizes, ENCODE_FULL, EncodeMode, EncodeOptions};
+use diamond_types::list::compat::{analyze, seq_conflicts};
 use crate::dot::{generate_svg_with_dot};
-use crate::export::export_to_json;
-use crate::git::extract_from_git;
+use crate::doctor::print_report;
+use crate::export::{export_oplog, export_to_json, ExportFormat, import_oplog};
+use crate::git::{convert_resume, DEFAULT_CHECKPOINT_EVERY, extract_from_git};
+use crate::version::{parse_version, resolve_version, Version};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -33,11 +41,11 @@ enum Commands {
     Create {
         filename: OsString,
 
-        /// Initialize the DT file with contents from here.
+        /// Initialize the DT file with contents from here. Use `-` to read from stdin.
         ///
         /// Equivalent to calling create followed by set.
         #[arg(short)]
-        input: Option<String>,
+        input: Option<OsString>,
 
         /// Agent name for edits. If not specified, a random name is chosen.
         ///
@@ -64,11 +72,50 @@ enum Commands {
         ///
         /// If not specified, the version defaults to the latest version, printing the result of
         /// merging all changes.
-        #[arg(short, long)]
+        #[arg(short, long, value_parser = parse_version)]
         version: Option<Version>,
+
+        /// Append a provenance trailer to the output, which names the agent and remote version of
+        /// the insert behind each run of characters. The trailer is a line containing
+        /// `--- provenance ---`, followed by the report as JSON.
+        #[arg(long)]
+        provenance: bool,
+
+        /// Write the provenance report to the named JSON file instead of appending it to the
+        /// content.
+        #[arg(long, value_name = "filename")]
+        sidecar: Option<OsString>,
+    },
+
+    /// Write a new diamond types file containing only the history up to a version. This is handy
+    /// for bisecting by hand.
+    ///
+    /// The new file's version is the requested version, and `dt cat` on it prints the same content
+    /// as `dt cat -v <version>` on the original file.
+    Checkout {
+        /// Diamond types file to read
+        #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
+        oplog: ListOpLog,
+
+        /// Only keep the operations in this version (and the operations before it)
+        #[arg(short, long, value_parser = parse_version)]
+        version: Version,
+
+        /// Save the truncated file here
+        #[arg(short, long)]
+        output: OsString,
+
+        /// Force overwrite the output file if it already exists.
+        #[arg(short, long)]
+        force: bool,
+
+        /// Suppress output to stdout
+        #[arg(short, long)]
+        quiet: bool,
     },
 
-    /// Print the operations contained within a diamond types file
+    /// Print the operations contained within a diamond types file. Deletes include the text they
+    /// removed, if the file stores deleted content (see `dt repack`).
     Log {
         /// Diamond types file to read
         #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
@@ -94,18 +141,36 @@ enum Commands {
         oplog: ListOpLog,
     },
 
+    /// Summarize a DT file: its agents, history and how its encoded size breaks down by chunk.
+    /// Also prints how many characters, words and lines were inserted and deleted.
+    ///
+    /// This does not check out the document, so it stays fast on huge files.
+    Stats {
+        /// Diamond types file to read
+        #[arg(value_name = "filename")]
+        dt_filename: OsString,
+
+        /// Only print how many characters, words and lines were inserted and deleted between these
+        /// two versions.
+        ///
+        /// Word and line counts are unknown if the file does not store the inserted or deleted
+        /// content in that range.
+        #[arg(long, num_args = 2, value_names = ["FROM", "TO"], value_parser = parse_version)]
+        between: Option<Vec<Version>>,
+    },
+
     /// Set the contents of a DT file by applying a diff
     Set {
         /// Diamond types file to modify
         dt_filename: OsString,
 
-        /// The file containing the new content
+        /// The file containing the new content. Use `-` to read from stdin.
         target_content_file: OsString,
 
         /// Set the new content with this version as the named parent.
         ///
         /// If not specified, the version defaults to the latest version (including all changes)
-        #[arg(short, long)]
+        #[arg(short, long, value_parser = parse_version)]
         version: Option<Version>,
 
         /// Suppress output to stdout
@@ -120,6 +185,31 @@ enum Commands {
         agent: Option<String>,
     },
 
+    /// Merge the operations from two diamond types files together.
+    ///
+    /// Operations which appear in both files are only stored once. The merge fails if an
+    /// (agent, seq) ID names different operations in each file.
+    Merge {
+        /// First file
+        a: OsString,
+
+        /// Second file, merged into the first
+        b: OsString,
+
+        /// Save the merged result to this file. If not specified, the first file will be
+        /// overwritten.
+        #[arg(short, long)]
+        output: Option<OsString>,
+
+        /// Force overwrite the output file if it already exists.
+        #[arg(short, long)]
+        force: bool,
+
+        /// Suppress output to stdout
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
     /// Re-save a diamond types file with different options. This method can:
     ///
     /// - Compress / uncompress the file's contents
@@ -143,7 +233,7 @@ enum Commands {
         uncompressed: bool,
 
         /// Trim the file to only contain changes from the specified point in time onwards.
-        #[arg(short, long)]
+        #[arg(short, long, value_parser = parse_version)]
         version: Option<Version>,
 
         /// Save a patch. Patch files do not contain the base snapshot state. They must be merged
@@ -164,6 +254,80 @@ enum Commands {
         #[arg(long)]
         no_deleted_content: bool,
 
+        /// Store repeated sections of content (eg the same block pasted many times) as references
+        /// to the first copy. Files saved with this flag can't be read by older versions of
+        /// diamond types.
+        #[arg(long)]
+        dedup: bool,
+
+        /// Suppress all output to stdout
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// Rewrite a diamond types file as a single linear history which produces the same content.
+    ///
+    /// All of the file's history and attribution is discarded. The new file contains one edit by
+    /// a single agent, which inserts the document's current content (or a diff from --base).
+    Squash {
+        /// File to squash
+        dt_filename: OsString,
+
+        /// Save the squashed file here. If not specified, the original file will be overwritten,
+        /// but only with -f.
+        #[arg(short, long)]
+        output: Option<OsString>,
+
+        /// Force overwrite the output file if it already exists.
+        #[arg(short, long)]
+        force: bool,
+
+        /// Start the new history from the content at this version. The new file inserts that
+        /// content, then applies a minimal diff to reach the current content.
+        #[arg(short, long, value_parser = parse_version)]
+        base: Option<Version>,
+
+        /// Agent name for the new edits. If not specified, a random name is chosen.
+        #[arg(short, long)]
+        agent: Option<String>,
+
+        /// Suppress all output to stdout
+        #[arg(short, long)]
+        quiet: bool,
+    },
+
+    /// List the text deleted from a DT file, with who deleted it. Deleted text is only listed if
+    /// the file stores it (see `dt repack`).
+    ShowDeleted {
+        /// Diamond types file to read
+        #[arg(value_name = "filename", value_parser = parse_dt_oplog)]
+        oplog: ListOpLog,
+
+        /// Only list text deleted by this agent
+        #[arg(short, long)]
+        agent: Option<String>,
+
+        /// Only list text deleted after this version
+        #[arg(short, long, value_parser = parse_version)]
+        since: Option<Version>,
+    },
+
+    /// Find text anywhere in a DT file's history (including deleted text) and erase it.
+    ///
+    /// By default this only lists the matches. Pass --apply to rewrite the file with the matching
+    /// text replaced by unknown content. Checkouts show U+FFFD characters in its place.
+    Redact {
+        /// File to redact
+        dt_filename: OsString,
+
+        /// Text to search for. This is matched literally.
+        #[arg(short, long = "match", value_name = "text")]
+        pattern: String,
+
+        /// Rewrite the file. Without this, nothing is changed.
+        #[arg(long)]
+        apply: bool,
+
         /// Suppress all output to stdout
         #[arg(short, long)]
         quiet: bool,
@@ -182,6 +346,26 @@ enum Commands {
         /// Use pretty JSON output
         #[arg(short, long)]
         pretty: bool,
+
+        /// The kind of JSON to write. `trace` is an editing trace for other CRDT libraries, and
+        /// `json` is the whole oplog (agents, seq numbers, parents and operations), which can be
+        /// turned back into a diamond types file with `dt import`.
+        #[arg(long, value_enum, default_value_t = ExportFormat::Trace)]
+        format: ExportFormat,
+    },
+
+    /// Create a diamond types file from an oplog exported with `dt export --format json`.
+    Import {
+        /// The JSON file to read. Use - to read from stdin.
+        json_filename: OsString,
+
+        /// The diamond types file to write
+        #[arg(short, long)]
+        output: OsString,
+
+        /// Overwrite the output file if it already exists
+        #[arg(short, long)]
+        force: bool,
     },
 
     /// Generate a diagram of the causal graph contained in a diamond types' file.
@@ -210,6 +394,54 @@ enum Commands {
         dot_path: Option<OsString>,
     },
 
+    /// Explain whether (and why not) two diamond types files can be merged together.
+    ///
+    /// This inspects both files and attempts a dry-run merge in each direction. Neither file is
+    /// modified.
+    Doctor {
+        /// First file
+        a: OsString,
+
+        /// Second file
+        b: OsString,
+    },
+
+    /// Find the smallest version of a diamond types file which still reproduces a problem.
+    ///
+    /// The command is run on truncated copies of the file, with the path of the copy appended to
+    /// its arguments. Like `git bisect run`, the command should fail (exit with a non-zero status)
+    /// when the problem is present. For example:
+    ///
+    /// `dt bisect crash.dt -o minimal.dt -- ./check.sh`
+    Bisect {
+        /// File to minimize
+        dt_filename: OsString,
+
+        /// Save the smallest file which reproduces the problem here.
+        #[arg(short, long)]
+        output: Option<OsString>,
+
+        /// Force overwrite the output file if it already exists.
+        #[arg(short, long)]
+        force: bool,
+
+        /// Suppress output to stdout
+        #[arg(short, long)]
+        quiet: bool,
+
+        /// The command to run on each version of the file
+        #[arg(last = true, required = true)]
+        command: Vec<OsString>,
+    },
+
+    /// Print a shell completion script for dt to stdout.
+    ///
+    /// For example, `dt completions bash > /etc/bash_completion.d/dt`.
+    Completions {
+        /// The shell to generate completions for
+        shell: Shell,
+    },
+
     /// Import & convert the editing history for a file from git to diamond types.
     GitImport {
         /// Path to the file being read. Must be inside a git repository.
@@ -230,35 +462,51 @@ enum Commands {
         /// Output an extra file containing mapping from git commits <-> DT versions.
         #[arg(short, long)]
         map_out: Option<PathBuf>,
+
+        /// Periodically save the conversion's progress to this file. If the file already exists,
+        /// the conversion resumes from it. It's removed once the output is written.
+        #[arg(long)]
+        checkpoint: Option<PathBuf>,
+
+        /// Save a checkpoint after this many commits.
+        #[arg(long, default_value_t = DEFAULT_CHECKPOINT_EVERY as u64, value_parser = clap::value_parser!(u64).range(1..))]
+        checkpoint_every: u64,
     }
 }
 
-#[derive(Clone, Debug)]
-struct Version(Box<[RemoteVersionOwned]>);
-
-impl FromStr for Version {
-    type Err = serde_json::Error;
+/// Read a text file, or stdin if the filename is `-`.
+fn read_text_input(filename: &OsString) -> anyhow::Result<String> {
+    let (bytes, name) = if filename == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        (bytes, "stdin".into())
+    } else {
+        (fs::read(filename)?, filename.to_string_lossy())
+    };
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Version(serde_json::from_str(s)?))
-    }
+    String::from_utf8(bytes).map_err(|e| {
+        anyhow!("Could not read {name}: it is not valid UTF-8 text (invalid byte at offset {})", e.utf8_error().valid_up_to())
+    })
 }
 
 fn parse_dt_oplog(filename: &str) -> Result<ListOpLog, anyhow::Error> {
     let data = fs::read(filename)?;
-    let oplog = ListOpLog::load_from(&data)?;
+    let oplog = ListOpLog::load_from(&data)
+        .map_err(|e| anyhow!(describe_parse_error(e)))?;
     Ok(oplog)
 }
 
-// fn checkout_version_or_tip(oplog: OpLog, version: Option<&[RemoteVersionOwned]>) -> Branch {
-fn checkout_version_or_tip(oplog: &ListOpLog, version: Option<Box<[RemoteVersionOwned]>>) -> ListBranch {
-    let v = if let Some(version) = version {
-        oplog.cg.agent_assignment.try_remote_to_local_frontier(version.iter()).unwrap()
-    } else {
-        oplog.local_frontier()
-    };
-
-    oplog.checkout(v.as_ref())
+/// Describe where a file failed to parse. Eg, "parse error at byte 0x1a2f in chunk Patches >
+/// OpParents: Invalid length".
+fn describe_parse_error(e: ParseError) -> String {
+    let mut msg = "parse error".to_string();
+    if let Some(pos) = e.pos {
+        msg += &format!(" at byte {pos:#x}");
+    }
+    if !e.chunk.is_empty() {
+        msg += &format!(" in chunk {}", e.chunk);
+    }
+    format!("{msg}: {}", e.kind)
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -268,7 +516,7 @@ fn main() -> Result<(), anyhow::Error> {
             let mut oplog = ListOpLog::new();
 
             if let Some(content_file) = content_file {
-                let content = fs::read_to_string(content_file)?;
+                let content = read_text_input(&content_file)?;
                 let agent_name = agent.unwrap_or_else(random_agent_name);
                 let agent = oplog.get_or_create_agent_id(&agent_name);
                 oplog.add_insert(agent, 0, &content);
@@ -279,14 +527,26 @@ fn main() -> Result<(), anyhow::Error> {
             maybe_overwrite(&filename, &data, force)?;
         }
 
-        Commands::Cat { oplog, output, version } => {
+        Commands::Cat { oplog, output, version, provenance, sidecar } => {
             // let data = fs::read(filename)?;
             // Using custom oplog / branch here to support custom versions
             // let oplog = OpLog::load_from(&data).unwrap();
 
             // let branch = checkout_version_or_tip(oplog, version.map(|v| &v));
-            let branch = checkout_version_or_tip(&oplog, version.map(|v| v.0));
-            let content = branch.content();
+            let branch = oplog.checkout(resolve_version(&oplog, version.as_ref())?.as_ref());
+            let mut content = branch.content().to_string();
+
+            if provenance || sidecar.is_some() {
+                let report = serde_json::to_string_pretty(&branch.attribution_report(&oplog))?;
+                if let Some(sidecar) = sidecar {
+                    fs::write(sidecar, report + "\n")?;
+                } else {
+                    if !content.is_empty() && !content.ends_with('\n') { content.push('\n'); }
+                    content.push_str("--- provenance ---\n");
+                    content.push_str(&report);
+                    content.push('\n');
+                }
+            }
 
             // There's probably some fancy way to switch and share code here - either write to a
             // File or stdout. But eh.
@@ -298,6 +558,20 @@ fn main() -> Result<(), anyhow::Error> {
             }
         }
 
+        Commands::Checkout { oplog, version, output, force, quiet } => {
+            let version = resolve_version(&oplog, Some(&version))?;
+            let new_data = oplog.encode_between(save_opts(&oplog), &[], version.as_ref());
+            maybe_overwrite(&output, &new_data, force)?;
+
+            if !quiet {
+                let num_ops = oplog.cg.graph.diff(&[], version.as_ref()).1.iter()
+                    .map(|span| span.len())
+                    .sum::<usize>();
+                println!("Operations: {num_ops} of {}", oplog.len());
+                println!("Written {} bytes to {}", new_data.len(), output.to_str().unwrap_or("(invalid)"));
+            }
+        }
+
         Commands::Log { oplog, transformed, json, history: history_mode } => {
             if history_mode {
                 for hist in oplog.iter_history() {
@@ -308,6 +582,7 @@ fn main() -> Result<(), anyhow::Error> {
                         println!("{:?}", hist);
                     }
                 }
+                print_checkpoints(&oplog, json);
             } else if transformed {
                     for (_, op) in oplog.iter_xf_operations() {
                         if let Some(op) = op {
@@ -329,26 +604,74 @@ fn main() -> Result<(), anyhow::Error> {
                         println!("{:?}", op);
                     }
                 }
+                print_checkpoints(&oplog, json);
             }
         }
 
         Commands::Version { oplog } => {
-            let version = serde_json::to_string(&oplog.remote_frontier()).unwrap();
+            let version = serde_json::to_string(&oplog.remote_version()).unwrap();
             println!("{version}");
         }
 
-        Commands::Set { dt_filename, target_content_file, version, quiet, agent } => {
+        Commands::Stats { dt_filename, between } => {
             let data = fs::read(&dt_filename)?;
+            let oplog = ListOpLog::load_from(&data)
+                .map_err(|e| anyhow!(describe_parse_error(e)))?;
 
-            let new = if target_content_file == "-" {
-                let mut s = String::new();
-                std::io::stdin().read_to_string(&mut s)?;
-                s
+            let (from, to) = if let Some(between) = &between {
+                (resolve_version(&oplog, Some(&between[0]))?, resolve_version(&oplog, Some(&between[1]))?)
             } else {
-                fs::read_to_string(target_content_file)?
+                (Frontier::root(), oplog.local_version())
             };
 
+            if between.is_none() {
+                let summary = oplog.summary();
+                println!("Agents: {}", summary.agents.len());
+                for agent in &summary.agents {
+                    println!("  {}: next seq {}", agent.name, agent.next_seq);
+                }
+                println!("Operations: {}", summary.num_operations);
+                println!("History entries: {}", summary.num_history_entries);
+                println!("Branches: {}", summary.num_branches);
+                println!("Merges: {}", summary.num_merges);
+            }
+
+            let stats = oplog.edit_stats_between(from.as_ref(), to.as_ref());
+            let fmt = |ins: Option<usize>, del: Option<usize>| match (ins, del) {
+                (Some(ins), Some(del)) => format!("+{ins} -{del}"),
+                (Some(ins), None) => format!("+{ins} -(unknown)"),
+                (None, Some(del)) => format!("+(unknown) -{del}"),
+                (None, None) => "(unknown)".to_string(),
+            };
+            println!("Characters: +{} -{}", stats.chars_inserted, stats.chars_deleted);
+            println!("Words: {}", fmt(stats.words_inserted, stats.words_deleted));
+            println!("Lines: {}", fmt(stats.lines_inserted, stats.lines_deleted));
+
+            if between.is_none() {
+                println!("File size: {} bytes", data.len());
+                for chunk in chunk_sizes(&data).map_err(|e| anyhow!(describe_parse_error(e)))? {
+                    println!("{:indent$}{}: {} bytes", "", chunk.name, chunk.len, indent = 2 + chunk.depth * 2);
+                }
+
+                let (required, optional) = capabilities(&data).map_err(|e| anyhow!(describe_parse_error(e)))?;
+                let list = |set: CapabilitySet| {
+                    let names: Vec<String> = set.known().map(|c| format!("{c:?}"))
+                        .chain(set.unknown().map(|bit| format!("unknown ({bit})")))
+                        .collect();
+                    if names.is_empty() { "(none)".to_string() } else { names.join(", ") }
+                };
+                println!("Required capabilities: {}", list(required));
+                println!("Optional capabilities: {}", list(optional));
+            }
+        }
+
+        Commands::Set { dt_filename, target_content_file, version, quiet, agent } => {
+            let data = fs::read(&dt_filename)?;
+
+            let new = read_text_input(&target_content_file)?;
+
             let mut oplog = ListOpLog::load_from(&data)?;
+            let from_version = resolve_version(&oplog, version.as_ref())?;
 
             if !quiet {
                 let v_json = if let Some(v) = version.as_ref() {
@@ -356,60 +679,69 @@ fn main() -> Result<(), anyhow::Error> {
                     serde_json::to_string(&v.0)
                 } else {
                     // println!("Editing from tip version {:?}", oplog.remote_version());
-                    serde_json::to_string(&oplog.remote_frontier())
+                    serde_json::to_string(&oplog.remote_version())
                 }.unwrap();
                 println!("Editing from version {v_json}");
             }
 
-            let mut branch = checkout_version_or_tip(&oplog, version.map(|v| v.0));
-
-            let old = branch.content().to_string();
-            let diff = TextDiff::from_chars(&old, &new);
-            let remapper = TextDiffRemapper::from_text_diff(&diff, &old, &new);
+            // Editing from an old version is the point of --version. The new changes are concurrent
+            // with everything after it.
+            let mut branch = oplog.checkout(from_version.as_ref()).fork_editable();
 
             let agent_name = agent.unwrap_or_else(random_agent_name);
             let agent_id = oplog.get_or_create_agent_id(&agent_name);
-
-            let mut pos = 0;
-            for (tag, str) in diff.ops().iter()
-                .flat_map(move |x| remapper.iter_slices(x)) {
-
-                let len = str.chars().count();
-                match tag {
-                    ChangeTag::Equal => pos += len,
-                    ChangeTag::Delete => {
-                        // dbg!(("delete", pos .. pos+len));
-                        branch.delete(&mut oplog, agent_id, pos .. pos+len);
-                    }
-                    ChangeTag::Insert => {
-                        // dbg!(("insert", pos, str));
-                        branch.insert(&mut oplog, agent_id, pos, str);
-                        pos += len;
-                    }
-                }
-            }
+            apply_diff(&mut oplog, &mut branch, agent_id, &new);
 
             if !quiet {
                 println!("Resulting branch version after changes {}",
-                         serde_json::to_string(&branch.remote_frontier(&oplog)).unwrap());
+                         serde_json::to_string(&branch.remote_version(&oplog)).unwrap());
                 println!("Resulting file version after changes {}",
-                         serde_json::to_string(&oplog.remote_frontier()).unwrap());
+                         serde_json::to_string(&oplog.remote_version()).unwrap());
             }
 
-            // TODO: Do that atomic rename nonsense instead of just overwriting.
-            let out_data = oplog.encode(EncodeOptions::default());
-            fs::write(&dt_filename, out_data)?;
+            let out_data = oplog.encode(save_opts(&oplog));
+            write_atomic(Path::new(&dt_filename), &out_data)?;
         }
 
-        Commands::Repack { dt_filename, output, force, uncompressed, version, patch, no_inserted_content, no_deleted_content, quiet } => {
+        Commands::Merge { a, b, output, force, quiet } => {
+            let a_data = fs::read(&a)?;
+            let b_data = fs::read(&b)?;
+            let mut oplog = ListOpLog::load_from(&a_data)?;
+            let other = ListOpLog::load_from(&b_data)?;
+
+            let conflicts = seq_conflicts(&oplog, &other);
+            if !conflicts.is_empty() {
+                let ids = conflicts.iter()
+                    .map(|c| format!("{} {}..{}", c.name, c.seqs.start, c.seqs.end))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                bail!("Cannot merge: these (agent, seq) IDs name different operations in each file: {ids}");
+            }
+
+            oplog.decode_and_add(&b_data)?;
+            let new_data = oplog.encode(save_opts(&oplog));
+
+            if let Some(output) = output.as_ref() {
+                maybe_overwrite(output, &new_data, force)?;
+            } else {
+                fs::write(&a, &new_data)?;
+            }
+
+            if !quiet {
+                println!("Resulting file version after merging {}",
+                         serde_json::to_string(&oplog.remote_version()).unwrap());
+            }
+        }
+
+        Commands::Repack { dt_filename, output, force, uncompressed, version, patch, no_inserted_content, no_deleted_content, dedup, quiet } => {
             let data = fs::read(&dt_filename)?;
             let oplog = ListOpLog::load_from(&data)?;
 
-            let from_version = match &version {
-                Some(v) => v.0.as_ref(),
-                None => &[],
+            let from_version = if version.is_some() {
+                resolve_version(&oplog, version.as_ref())?
+            } else {
+                Frontier::root()
             };
-            let from_version = oplog.cg.agent_assignment.remote_to_local_frontier(from_version.iter());
 
             let new_data = oplog.encode_from(EncodeOptions {
                 user_data: None,
@@ -418,6 +750,12 @@ fn main() -> Result<(), anyhow::Error> {
                 store_inserted_content: !no_inserted_content,
                 store_deleted_content: !no_deleted_content,
                 compress_content: !uncompressed,
+                dedup_content: dedup,
+                redact_content_for: &[],
+                keep_deleted_content_after: None,
+                chunk_checksums: false,
+                canonical: false,
+                mode: EncodeMode::Full,
                 verbose: false
             }, from_version.as_ref());
 
@@ -443,36 +781,146 @@ fn main() -> Result<(), anyhow::Error> {
             }
         }
 
-        Commands::Export { dt_filename, mut output, pretty } => {
+        Commands::Squash { dt_filename, output, force, base, agent, quiet } => {
             let data = fs::read(&dt_filename)?;
             let oplog = ListOpLog::load_from(&data)?;
 
-            let result = export_to_json(&oplog);
+            if output.is_none() && !force {
+                eprintln!("Squashing discards the file's history. Pass -o to write to a new file, or -f to overwrite it");
+                std::process::exit(1);
+            }
+
+            let tip_content = oplog.checkout_tip().content().to_string();
+            let base_content = match base.as_ref() {
+                Some(_) => {
+                    let v = resolve_version(&oplog, base.as_ref())?;
+                    Some(oplog.checkout(v.as_ref()).content().to_string())
+                }
+                None => None,
+            };
+
+            let mut new_oplog = ListOpLog::new();
+            let agent_name = agent.unwrap_or_else(random_agent_name);
+            let agent_id = new_oplog.get_or_create_agent_id(&agent_name);
+            let mut branch = ListBranch::new();
+            branch.insert(&mut new_oplog, agent_id, 0, base_content.as_deref().unwrap_or(&tip_content));
+            if base_content.is_some() {
+                apply_diff(&mut new_oplog, &mut branch, agent_id, &tip_content);
+            }
 
-            // This repetition is gross, but I'm not sure a better way to do it given the type of
-            // stdout and File are different. Halp!
+            eprintln!("Warning: the squashed file does not contain the original history or attribution");
+
+            let new_data = new_oplog.encode(ENCODE_FULL);
+            if let Some(output) = output.as_ref() {
+                maybe_overwrite(output, &new_data, force)?;
+            } else {
+                write_atomic(Path::new(&dt_filename), &new_data)?;
+            }
+
+            if !quiet {
+                println!("Squashed {} operations into {}", oplog.len(), new_oplog.len());
+                println!("Written {} bytes to {}", new_data.len(), output.unwrap_or(dt_filename)
+                    .to_str()
+                    .unwrap_or("(invalid)"));
+            }
+        }
+
+        Commands::ShowDeleted { oplog, agent, since } => {
+            let since = match since.as_ref() {
+                Some(_) => Some(resolve_version(&oplog, since.as_ref())?),
+                None => None,
+            };
+
+            let mut stored = false;
+            for entry in oplog.iter_full_2() {
+                let mut lv = entry.span.start;
+                for op in entry.ops.iter() {
+                    let span: DTRange = (lv..lv + op.len()).into();
+                    lv = span.end;
+                    if op.kind != ListOpKind::Del { continue; }
+                    let Some(content) = op.content.as_ref() else { continue; };
+                    stored = true;
+
+                    if agent.as_ref().is_some_and(|a| a != oplog.get_agent_name(entry.agent_id)) { continue; }
+                    if since.as_ref().is_some_and(|v| oplog.version_contains_time(v.as_ref(), span.start)) { continue; }
+
+                    // Backspaced text is stored in the order it was deleted.
+                    let text: String = if op.loc.fwd { content.to_string() } else {
+                        content.chars().rev().collect()
+                    };
+                    println!("{} {:?}", describe_span(&oplog, span), text);
+                }
+            }
+
+            if !stored {
+                eprintln!("This file does not store deleted content");
+            }
+        }
+
+        Commands::Redact { dt_filename, pattern, apply, quiet } => {
+            let data = fs::read(&dt_filename)?;
+            let mut oplog = ListOpLog::load_from(&data)?;
+
+            let matches = oplog.find_content_matches(&pattern);
+            if !quiet {
+                for m in matches.iter() {
+                    let kind = match m.kind {
+                        ListOpKind::Ins => "inserted",
+                        ListOpKind::Del => "deleted",
+                    };
+                    println!("{kind} by {}", describe_span(&oplog, m.span));
+                }
+                println!("{} matches", matches.len());
+            }
+
+            if apply && !matches.is_empty() {
+                // Keep the deleted content if the file had it. Only the redacted text is removed.
+                let opts = save_opts(&oplog);
+
+                let spans: Vec<DTRange> = matches.iter().map(|m| m.span).collect();
+                oplog.redact_content(&spans);
+
+                let new_data = oplog.encode(opts);
+                write_atomic(Path::new(&dt_filename), &new_data)?;
+
+                if !quiet {
+                    println!("Written {} bytes to {}", new_data.len(), dt_filename
+                        .to_str()
+                        .unwrap_or("(invalid)"));
+                }
+            } else if !matches.is_empty() && !quiet {
+                println!("Nothing was changed. Run again with --apply to redact the matches");
+            }
+        }
+
+        Commands::Export { dt_filename, mut output, pretty, format } => {
+            let data = fs::read(&dt_filename)?;
+            let oplog = ListOpLog::load_from(&data)?;
 
             // Bit gross. Handle -o- even though its unnecessary.
             if let Some(path) = &output {
                 if path == "-" { output = None; }
             }
 
-            if let Some(path) = output {
-                let writer = BufWriter::new(File::create(path)?);
-                if pretty {
-                    serde_json::to_writer_pretty(writer, &result)?;
-                } else {
-                    serde_json::to_writer(writer, &result)?;
-                }
+            let writer: Box<dyn Write> = if let Some(path) = output {
+                Box::new(BufWriter::new(File::create(path)?))
             } else {
-                let writer = BufWriter::new(std::io::stdout());
-                if pretty {
-                    serde_json::to_writer_pretty(writer, &result)?;
-                } else {
-                    serde_json::to_writer(writer, &result)?;
-                }
+                Box::new(BufWriter::new(std::io::stdout()))
+            };
+
+            match (format, pretty) {
+                (ExportFormat::Trace, false) => serde_json::to_writer(writer, &export_to_json(&oplog))?,
+                (ExportFormat::Trace, true) => serde_json::to_writer_pretty(writer, &export_to_json(&oplog))?,
+                (ExportFormat::Json, false) => serde_json::to_writer(writer, &export_oplog(&oplog))?,
+                (ExportFormat::Json, true) => serde_json::to_writer_pretty(writer, &export_oplog(&oplog))?,
             }
-            // serde_json::to_writer()
+        }
+
+        Commands::Import { json_filename, output, force } => {
+            let json = read_text_input(&json_filename)?;
+
+            let oplog = import_oplog(&serde_json::from_str(&json)?)?;
+            maybe_overwrite(&output, &oplog.encode(save_opts(&oplog)), force)?;
         }
 
         Commands::Dot { dt_filename, no_render, output, dot_path } => {
@@ -505,8 +953,69 @@ fn main() -> Result<(), anyhow::Error> {
             }
         }
 
-        Commands::GitImport { path, branch, quiet, out, map_out } => {
-            let oplog = extract_from_git(path.clone(), branch, quiet, map_out)?;
+        Commands::Doctor { a, b } => {
+            let a_data = fs::read(&a)?;
+            let b_data = fs::read(&b)?;
+            let report = analyze(&a_data, &b_data);
+            print_report(&a.to_string_lossy(), &b.to_string_lossy(), &report);
+        }
+
+        Commands::Bisect { dt_filename, output, force, quiet, command } => {
+            let data = fs::read(&dt_filename)?;
+            let oplog = ListOpLog::load_from(&data)?;
+
+            let mut tmp = dt_filename.clone();
+            tmp.push(format!(".bisect-{}.dt", random_agent_name()));
+
+            // If the command can't be run at all, give up rather than treating it as a pass.
+            let mut run_error = None;
+            let result = oplog.bisect(|candidate| {
+                if run_error.is_some() { return false; }
+
+                let status = fs::write(&tmp, candidate.encode(ENCODE_FULL)).and_then(|_| {
+                    std::process::Command::new(&command[0])
+                        .args(&command[1..])
+                        .arg(&tmp)
+                        .status()
+                });
+                match status {
+                    Ok(status) => !status.success(),
+                    Err(e) => {
+                        run_error = Some(e);
+                        false
+                    }
+                }
+            });
+            let _ = fs::remove_file(&tmp);
+
+            if let Some(e) = run_error {
+                bail!("Could not run {}: {e}", command[0].to_string_lossy());
+            }
+            let Some(version) = result else {
+                bail!("The command succeeds on the whole file. Nothing to bisect");
+            };
+
+            let minimal = oplog.truncate_to(version.as_ref());
+            if let Some(output) = output.as_ref() {
+                maybe_overwrite(output, &minimal.encode(ENCODE_FULL), force)?;
+            }
+
+            if !quiet {
+                println!("Smallest failing version: {}",
+                         serde_json::to_string(&minimal.remote_version()).unwrap());
+                println!("Operations: {} of {}", minimal.len(), oplog.len());
+            }
+        }
+
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "dt", &mut std::io::stdout());
+        }
+
+        Commands::GitImport { path, branch, quiet, out, map_out, checkpoint, checkpoint_every } => {
+            let oplog = match checkpoint.as_ref() {
+                Some(checkpoint) => convert_resume(path.clone(), branch, quiet, map_out, checkpoint, checkpoint_every as usize)?,
+                None => extract_from_git(path.clone(), branch, quiet, map_out)?,
+            };
 
             let out_filename = out.unwrap_or_else(|| {
                 let stem = path.file_stem().expect("Invalid path");
@@ -515,10 +1024,18 @@ fn main() -> Result<(), anyhow::Error> {
                 path
             });
 
-            let data = oplog.encode(ENCODE_FULL);
-            fs::write(&out_filename, &data).unwrap();
+            // Histories imported from git can be large, so stream the encoded file out.
+            let mut file = BufWriter::new(File::create(&out_filename)?);
+            oplog.encode_to(ENCODE_FULL, &mut file)?;
+            drop(file);
             if !quiet {
-                println!("{} bytes written to {}", data.len(), out_filename.display());
+                let len = fs::metadata(&out_filename)?.len();
+                println!("{} bytes written to {}", len, out_filename.display());
+            }
+
+            // Short conversions might finish before saving a checkpoint.
+            if let Some(checkpoint) = checkpoint.filter(|c| c.exists()) {
+                fs::remove_file(checkpoint)?;
             }
         }
     }
@@ -539,6 +1056,60 @@ fn get_filename_from(dt_filename: &PathBuf, output: Option<OsString>, extension:
     }
 }
 
+/// The options for saving a file after changing it. The deleted content is kept if the oplog has
+/// any, so editing a file (or merging into it) doesn't throw it away.
+fn save_opts(oplog: &ListOpLog) -> EncodeOptions<'static> {
+    let store_deleted_content = oplog.iter()
+        .any(|op| op.kind == ListOpKind::Del && op.content.is_some());
+    EncodeOptions { store_deleted_content, ..ENCODE_FULL }
+}
+
+/// Edit the branch's content to match `new`, using a minimal character diff.
+fn apply_diff(oplog: &mut ListOpLog, branch: &mut ListBranch, agent_id: AgentId, new: &str) {
+    let old = branch.content().to_string();
+    let diff = TextDiff::from_chars(old.as_str(), new);
+    let remapper = TextDiffRemapper::from_text_diff(&diff, &old, new);
+
+    let mut pos = 0;
+    for (tag, str) in diff.ops().iter()
+        .flat_map(move |x| remapper.iter_slices(x)) {
+
+        let len = str.chars().count();
+        match tag {
+            ChangeTag::Equal => pos += len,
+            ChangeTag::Delete => {
+                // dbg!(("delete", pos .. pos+len));
+                branch.delete(oplog, agent_id, pos .. pos+len);
+            }
+            ChangeTag::Insert => {
+                // dbg!(("insert", pos, str));
+                branch.insert(oplog, agent_id, pos, str);
+                pos += len;
+            }
+        }
+    }
+}
+
+/// Describe who made a span of operations. Eg, "seph 3..9".
+fn describe_span(oplog: &ListOpLog, span: DTRange) -> String {
+    oplog.iter_remote_mappings_range(span)
+        .map(|RemoteVersionSpan(agent, seq)| format!("{agent} {}..{}", seq.start, seq.end))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Print the checkpoints in the oplog, with their messages. Used by `dt log`.
+fn print_checkpoints(oplog: &ListOpLog, json: bool) {
+    for checkpoint in oplog.iter_checkpoints() {
+        if json {
+            let s = serde_json::to_string(&checkpoint).unwrap();
+            println!("{s}");
+        } else {
+            println!("{:?}", checkpoint);
+        }
+    }
+}
+
 fn maybe_overwrite(output: &OsString, new_data: &Vec<u8>, force: bool) -> Result<(), anyhow::Error> {
     let file_result = fs::OpenOptions::new()
         .create_new(!force)
@@ -558,6 +1129,30 @@ fn maybe_overwrite(output: &OsString, new_data: &Vec<u8>, force: bool) -> Result
     Ok(())
 }
 
+/// Write a file by writing the data to a temporary file next to it, then renaming it into place.
+/// If we're killed (or the machine loses power) partway through, the file has either its old
+/// contents or its new contents.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    // The data has to be on disk before the rename is, or a crash can leave an empty file behind.
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+
+    // Sync the directory too, so the rename itself is durable.
+    #[cfg(unix)] {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
 fn random_agent_name() -> String {
     rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -0,0 +1,54 @@
+//! Parsing and checking the `--version` argument accepted by a few commands.
+
+use anyhow::bail;
+use diamond_types::causalgraph::agent_assignment::remote_ids::{RemoteVersion, RemoteVersionOwned, VersionConversionError};
+use diamond_types::Frontier;
+use diamond_types::list::ListOpLog;
+
+const VERSION_HELP: &str = "Versions are written as a JSON list of [agent, seq] pairs. For example:
+  --version '[[\"seph\", 10]]'
+  --version '[[\"seph\", 10], [\"mike\", 3]]'
+  --version '[]'    (The start of history)
+
+Use `dt version <file>` to print the current version of a file.";
+
+#[derive(Clone, Debug)]
+pub struct Version(pub Box<[RemoteVersionOwned]>);
+
+/// Used as the clap value parser for versions. Unlike serde's errors, the error message here
+/// explains what a version is supposed to look like.
+pub fn parse_version(s: &str) -> Result<Version, String> {
+    let versions: Box<[RemoteVersionOwned]> = serde_json::from_str(s)
+        .map_err(|e| format!("{e}\n\n{VERSION_HELP}"))?;
+
+    if let Some(rv) = versions.iter().find(|rv| rv.0.is_empty()) {
+        return Err(format!("Agent names cannot be empty (in [\"\", {}])\n\n{VERSION_HELP}", rv.1));
+    }
+
+    Ok(Version(versions))
+}
+
+/// Convert the requested version into a local frontier, or the oplog's current version if no
+/// version was requested. This fails (naming the first unknown ID) if the file doesn't contain
+/// the requested version.
+pub fn resolve_version(oplog: &ListOpLog, version: Option<&Version>) -> Result<Frontier, anyhow::Error> {
+    let Some(version) = version else {
+        return Ok(oplog.local_version());
+    };
+
+    let mut result = Vec::with_capacity(version.0.len());
+    for rv in version.0.iter() {
+        match oplog.cg.agent_assignment.try_remote_to_local_version(RemoteVersion::from(rv)) {
+            Ok(lv) => result.push(lv),
+            Err(e) => {
+                let reason = match e {
+                    VersionConversionError::UnknownAgent => "the file has no changes from that agent",
+                    VersionConversionError::SeqInFuture => "the seq is past that agent's last change",
+                };
+                bail!("Version [\"{}\", {}] is not contained in the file: {reason}", rv.0, rv.1)
+            }
+        }
+    }
+
+    Ok(Frontier::from_unsorted(&result))
+}
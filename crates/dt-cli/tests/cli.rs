@@ -0,0 +1,323 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn dt(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dt"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Run dt with `input` piped to stdin.
+fn dt_with_stdin(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dt"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Create a DT file containing "hi there\n", written by agent "seph".
+fn make_dt_file(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dt-cli-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let content = dir.join(format!("{name}.txt"));
+    std::fs::write(&content, "hi there\n").unwrap();
+    let dt_file = dir.join(format!("{name}.dt"));
+
+    let output = dt(&["create", dt_file.to_str().unwrap(), "-i", content.to_str().unwrap(), "-a", "seph", "-f"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    dt_file
+}
+
+#[test]
+fn completions_generate() {
+    for shell in ["bash", "zsh", "fish"] {
+        let output = dt(&["completions", shell]);
+        assert!(output.status.success(), "{shell}: {}", stderr(&output));
+        let script = String::from_utf8(output.stdout).unwrap();
+        assert!(script.contains("git-import"), "{shell} completions missing subcommands");
+    }
+}
+
+#[test]
+fn invalid_version_json_shows_example() {
+    let file = make_dt_file("invalid_json");
+    let output = dt(&["cat", file.to_str().unwrap(), "--version", "seph:3"]);
+    assert!(!output.status.success());
+
+    let err = stderr(&output);
+    assert!(err.contains("JSON list of [agent, seq] pairs"), "{err}");
+    assert!(err.contains(r#"--version '[["seph", 10]]'"#), "{err}");
+}
+
+#[test]
+fn invalid_version_structure_is_rejected() {
+    let file = make_dt_file("invalid_structure");
+
+    for version in [r#"[{"agent": "seph", "seq": 1}]"#, r#"[["seph"]]"#, r#"[["seph", -1]]"#, r#"[["", 1]]"#] {
+        let output = dt(&["cat", file.to_str().unwrap(), "--version", version]);
+        assert!(!output.status.success(), "{version} was accepted");
+        let err = stderr(&output);
+        assert!(err.contains("JSON list of [agent, seq] pairs"), "{version}: {err}");
+    }
+}
+
+#[test]
+fn unknown_versions_are_reported() {
+    let file = make_dt_file("unknown_version");
+    let file = file.to_str().unwrap();
+
+    let output = dt(&["cat", file, "--version", r#"[["seph", 2], ["mike", 0]]"#]);
+    assert!(!output.status.success());
+    let err = stderr(&output);
+    assert!(err.contains(r#"Version ["mike", 0] is not contained in the file"#), "{err}");
+
+    let output = dt(&["cat", file, "--version", r#"[["seph", 100]]"#]);
+    assert!(!output.status.success());
+    let err = stderr(&output);
+    assert!(err.contains(r#"Version ["seph", 100] is not contained in the file"#), "{err}");
+
+    // Set should fail before touching the file.
+    let before = std::fs::read(file).unwrap();
+    let output = dt(&["set", file, "-", "--version", r#"[["mike", 0]]"#, "-q"]);
+    assert!(!output.status.success());
+    assert_eq!(std::fs::read(file).unwrap(), before);
+}
+
+#[test]
+fn known_versions_are_accepted() {
+    let file = make_dt_file("known_version");
+    let file = file.to_str().unwrap();
+
+    let output = dt(&["cat", file, "--version", r#"[["seph", 1]]"#]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hi");
+
+    let output = dt(&["cat", file, "--version", "[]"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+}
+
+#[test]
+fn create_and_set_read_stdin() {
+    let file = make_dt_file("stdin");
+    let file = file.to_str().unwrap();
+
+    let output = dt_with_stdin(&["create", file, "-i", "-", "-f"], b"piped\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(dt(&["cat", file]).stdout).unwrap(), "piped\n");
+
+    let output = dt_with_stdin(&["set", file, "-", "-q"], b"piped again\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(dt(&["cat", file]).stdout).unwrap(), "piped again\n");
+
+    let output = dt_with_stdin(&["set", file, "-"], b"bad \xff utf8");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Could not read stdin: it is not valid UTF-8 text (invalid byte at offset 4)"),
+        "{}", stderr(&output));
+    assert_eq!(String::from_utf8(dt(&["cat", file]).stdout).unwrap(), "piped again\n");
+}
+
+#[test]
+fn stats_summarizes_file() {
+    let file = make_dt_file("stats_summary");
+    let file = file.to_str().unwrap();
+
+    let output = dt(&["stats", file]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Agents: 1\n  seph: next seq 9\nOperations: 9\nHistory entries: 1\n\
+        Branches: 1\nMerges: 0\nCharacters: +9 -0\nWords: +2 -0\nLines: +1 -0\nFile size: "), "{stdout}");
+    assert!(stdout.contains("\n  Patches: "), "{stdout}");
+    assert!(stdout.contains("\n    AgentNames: 5 bytes\n"), "{stdout}");
+}
+
+#[test]
+fn stats_between_versions() {
+    let file = make_dt_file("stats");
+    let file = file.to_str().unwrap();
+
+    let output = dt(&["stats", file, "--between", r#"[["seph", 1]]"#, r#"[["seph", 8]]"#]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(),
+               "Characters: +7 -0\nWords: +1 -0\nLines: +1 -0\n");
+
+    let output = dt(&["stats", file, "--between", r#"[["mike", 0]]"#, "[]"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains(r#"Version ["mike", 0] is not contained in the file"#));
+}
+
+#[test]
+fn bisect_finds_smallest_failing_version() {
+    let file = make_dt_file("bisect");
+    let file = file.to_str().unwrap();
+    let output_file = format!("{file}.min.dt");
+
+    // Fails (reproduces) once the document contains "the".
+    let check = r#"! "$0" cat "$1" | grep -q the"#;
+    let output = dt(&["bisect", file, "-o", &output_file, "-f", "--",
+        "sh", "-c", check, env!("CARGO_BIN_EXE_dt")]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(),
+               "Smallest failing version: [[\"seph\",5]]\nOperations: 6 of 9\n");
+
+    let output = dt(&["cat", &output_file]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hi the");
+
+    // The command passes on the whole file, so there's nothing to find.
+    let output = dt(&["bisect", file, "--", "true"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("Nothing to bisect"), "{}", stderr(&output));
+}
+
+#[test]
+fn export_then_import_round_trips() {
+    let file = make_dt_file("export_import");
+    let json = file.with_extension("json");
+    let imported = file.with_extension("imported.dt");
+
+    let output = dt(&["export", file.to_str().unwrap(), "--format", "json", "-o", json.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = dt(&["import", json.to_str().unwrap(), "-o", imported.to_str().unwrap(), "-f"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = dt(&["cat", imported.to_str().unwrap()]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hi there\n");
+    let original = diamond_types::list::ListOpLog::load_from(&std::fs::read(&file).unwrap()).unwrap();
+    let result = diamond_types::list::ListOpLog::load_from(&std::fs::read(&imported).unwrap()).unwrap();
+    assert_eq!(result, original);
+}
+
+#[test]
+fn squash_keeps_tip_content() {
+    let file = make_dt_file("squash");
+    let squashed = file.with_extension("squashed.dt");
+    let (file, squashed) = (file.to_str().unwrap(), squashed.to_str().unwrap());
+    let output = dt_with_stdin(&["set", file, "-", "-q", "-a", "mike"], b"hi everyone\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // Squashing is lossy, so it won't overwrite the input without -f.
+    let output = dt(&["squash", file]);
+    assert!(!output.status.success());
+
+    let output = dt(&["squash", file, "-o", squashed, "-f", "-a", "sam"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("does not contain the original history or attribution"));
+    assert_eq!(String::from_utf8(dt(&["cat", squashed]).stdout).unwrap(), "hi everyone\n");
+    let oplog = diamond_types::list::ListOpLog::load_from(&std::fs::read(squashed).unwrap()).unwrap();
+    assert_eq!(oplog.len(), "hi everyone\n".len());
+
+    // With --base, the new file inserts the base content and diffs from there.
+    let output = dt(&["squash", file, "-o", squashed, "-f", "-a", "sam", "--base", r#"[["seph", 8]]"#]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(dt(&["cat", squashed]).stdout).unwrap(), "hi everyone\n");
+    let oplog = diamond_types::list::ListOpLog::load_from(&std::fs::read(squashed).unwrap()).unwrap();
+    assert_eq!(oplog.checkout(&[8]).content().to_string(), "hi there\n");
+    assert!(oplog.len() < 9 + 12);
+}
+
+#[test]
+fn redact_erases_text_from_history() {
+    use diamond_types::list::ListCRDT;
+    use diamond_types::list::encoding::{ENCODE_FULL, EncodeOptions};
+
+    let file = make_dt_file("redact");
+    let file = file.to_str().unwrap();
+    // Files only list deleted text if they store it.
+    assert!(stderr(&dt(&["show-deleted", file])).contains("does not store deleted content"));
+
+    let mut doc = ListCRDT::new();
+    let seph = doc.get_or_create_agent_id("seph");
+    let mike = doc.get_or_create_agent_id("mike");
+    doc.insert(seph, 0, "hi there\n");
+    doc.delete(mike, 3..8);
+    doc.insert(mike, 3, "secret");
+    std::fs::write(file, doc.oplog.encode(EncodeOptions {
+        store_deleted_content: true,
+        ..ENCODE_FULL
+    })).unwrap();
+
+    let shown = String::from_utf8(dt(&["show-deleted", file]).stdout).unwrap();
+    assert!(shown.contains("\"there\""), "{shown}");
+    assert!(shown.starts_with("mike "), "{shown}");
+    let shown = String::from_utf8(dt(&["show-deleted", file, "--agent", "seph"]).stdout).unwrap();
+    assert_eq!(shown, "");
+
+    // Without --apply, nothing is changed.
+    let before = std::fs::read(file).unwrap();
+    let output = dt(&["redact", file, "--match", "secret"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8(output.stdout).unwrap().contains("inserted by mike"));
+    assert_eq!(std::fs::read(file).unwrap(), before);
+
+    for pattern in ["secret", "there"] {
+        let output = dt(&["redact", file, "--match", pattern, "--apply", "-q"]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    let data = std::fs::read(file).unwrap();
+    assert!(!data.windows(6).any(|w| w == b"secret"));
+    let oplog = diamond_types::list::ListOpLog::load_from(&data).unwrap();
+    assert!(oplog.find_content_matches("secret").is_empty());
+    assert!(oplog.find_content_matches("there").is_empty());
+
+    assert_eq!(String::from_utf8(dt(&["cat", file]).stdout).unwrap(), "hi \u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\n");
+    let shown = String::from_utf8(dt(&["show-deleted", file]).stdout).unwrap();
+    assert!(!shown.contains("there"), "{shown}");
+}
+
+#[test]
+fn log_shows_deleted_content() {
+    let file = make_dt_file("log_deleted");
+    let file = file.to_str().unwrap();
+    let output = dt_with_stdin(&["set", file, "-", "-q", "-a", "mike"], b"hi\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = dt(&["log", file, "--json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(),
+        "{\"kind\":\"Ins\",\"start\":0,\"end\":9,\"fwd\":true,\"content\":\"hi there\\n\"}\n\
+        {\"kind\":\"Del\",\"start\":2,\"end\":8,\"fwd\":true,\"content\":\" there\"}\n");
+
+    // Merging keeps it too.
+    let other = make_dt_file("log_deleted_other");
+    let output = dt(&["merge", other.to_str().unwrap(), file, "-q"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = dt(&["log", other.to_str().unwrap(), "--json"]);
+    assert!(String::from_utf8(output.stdout).unwrap().contains("\"content\":\" there\""));
+}
+
+#[test]
+fn checkout_truncates_history() {
+    let file = make_dt_file("checkout");
+    let truncated = file.with_extension("truncated.dt");
+    let (file, truncated) = (file.to_str().unwrap(), truncated.to_str().unwrap());
+    let output = dt_with_stdin(&["set", file, "-", "-q", "-a", "mike"], b"hi everyone\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let version = r#"[["seph", 5]]"#;
+    let output = dt(&["checkout", file, "-v", version, "-o", truncated, "-f"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with("Operations: 6 of "));
+
+    let expected = dt(&["cat", file, "-v", version]).stdout;
+    assert_eq!(String::from_utf8(expected).unwrap(), "hi the");
+    assert_eq!(String::from_utf8(dt(&["cat", truncated]).stdout).unwrap(), "hi the");
+    let version_output = dt(&["version", truncated]);
+    assert_eq!(String::from_utf8(version_output.stdout).unwrap().trim(), r#"[["seph",5]]"#);
+
+    // The output isn't overwritten without -f.
+    let output = dt(&["checkout", file, "-v", version, "-o", truncated]);
+    assert!(!output.status.success());
+}
@@ -1,12 +1,15 @@
 mod utils;
+mod utf16;
 
+use js_sys::JsString;
 use wasm_bindgen::prelude::*;
 // use serde_wasm_bindgen::Serializer;
 // use serde::{Serialize};
 use diamond_types::{AgentId, LV};
 use diamond_types::list::{ListBranch as DTBranch, ListCRDT, ListOpLog as DTOpLog};
-use diamond_types::list::encoding::{ENCODE_FULL, ENCODE_PATCH};
+use diamond_types::list::encoding::{DecodeDriver, DecodeOptions, DecodeStatus as DTDecodeStatus, ENCODE_FULL, ENCODE_PATCH};
 use diamond_types::list::operation::TextOperation;
+use crate::utf16::js_to_string;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -69,7 +72,7 @@ pub fn local_to_remote_version(oplog: &DTOpLog, local_version: &[LV]) -> WasmRes
 }
 
 pub fn oplog_version_to_remote_version(oplog: &DTOpLog) -> WasmResult {
-    let version = oplog.local_frontier_ref();
+    let version = oplog.local_version_ref();
     let frontier = oplog.cg.agent_assignment.local_to_remote_frontier(version);
     serde_wasm_bindgen::to_value(&frontier)
 }
@@ -100,7 +103,7 @@ pub fn decode_and_add(oplog: &mut DTOpLog, bytes: &[u8]) -> WasmResult {
 }
 
 pub fn xf_since(oplog: &DTOpLog, version: &[LV]) -> WasmResult {
-    let xf = oplog.iter_xf_operations_from(version, &oplog.local_frontier_ref())
+    let xf = oplog.iter_xf_operations_from(version, oplog.local_version_ref())
         .filter_map(|(_v, op)| op)
         .collect::<Vec<_>>();
 
@@ -112,6 +115,19 @@ pub fn merge_versions(oplog: &DTOpLog, a: &[LV], b: &[LV]) -> Box<[LV]> {
     result.as_ref().into()
 }
 
+// Content never contains unpaired surrogates (see the utf16 module), so these conversions are exact
+// for any position between two characters.
+fn wchars_to_chars(branch: &DTBranch, pos_wchars: usize) -> Result<usize, JsError> {
+    branch.wchar_pos_to_chars(pos_wchars).map_err(|e| JsError::new(&format!("Invalid position {pos_wchars}: {e}")))
+}
+
+fn chars_to_wchars(branch: &DTBranch, pos_chars: usize) -> Result<usize, JsError> {
+    if pos_chars > branch.len() {
+        return Err(JsError::new(&format!("Invalid position {pos_chars}: past the end of the document")));
+    }
+    Ok(branch.content().borrow().chars_to_wchars(pos_chars))
+}
+
 fn unwrap_agentid(agent_id: Option<AgentId>) -> AgentId {
     agent_id.expect_throw("Agent missing. Set agent before modifying oplog.")
 }
@@ -129,7 +145,7 @@ impl Branch {
     #[wasm_bindgen]
     pub fn all(oplog: &OpLog) -> Self {
         let mut result = Self::new();
-        result.0.merge(&oplog.inner, &oplog.inner.local_frontier_ref());
+        result.0.merge(&oplog.inner, oplog.inner.local_version_ref());
         result
     }
 
@@ -144,23 +160,32 @@ impl Branch {
         if let Some(branch) = branch {
             self.0.merge(&ops.inner, &branch);
         } else {
-            self.0.merge(&ops.inner, &ops.inner.local_frontier_ref());
+            self.0.merge(&ops.inner, ops.inner.local_version_ref());
         }
     }
 
     #[wasm_bindgen(js_name = getLocalVersion)]
-    pub fn get_local_frontier(&self) -> Box<[LV]> {
-        self.0.local_frontier_ref().into()
+    pub fn get_local_version(&self) -> Box<[LV]> {
+        self.0.local_version_ref().into()
     }
 
+    #[wasm_bindgen(js_name = getRemoteVersion)]
+    pub fn get_remote_version(&self, oplog: &OpLog) -> WasmResult {
+        local_to_remote_version(&oplog.inner, self.0.local_version_ref())
+    }
+
+    /// Convert a UTF-16 position in the branch's content into a character position. Throws if
+    /// the position is past the end or between the two halves of a surrogate pair.
     #[wasm_bindgen(js_name = wCharsToChars)]
-    pub fn wchars_to_chars(&self, pos_wchars: usize) -> usize {
-        self.0.content().borrow().wchars_to_chars(pos_wchars)
+    pub fn wchars_to_chars(&self, pos_wchars: usize) -> Result<usize, JsError> {
+        wchars_to_chars(&self.0, pos_wchars)
     }
 
+    /// Convert a character position in the branch's content into a UTF-16 position. Throws if the
+    /// position is past the end.
     #[wasm_bindgen(js_name = charsToWchars)]
-    pub fn chars_to_wchars(&self, pos_chars: usize) -> usize {
-        self.0.content().borrow().chars_to_wchars(pos_chars)
+    pub fn chars_to_wchars(&self, pos_chars: usize) -> Result<usize, JsError> {
+        chars_to_wchars(&self.0, pos_chars)
     }
 }
 
@@ -196,8 +221,11 @@ impl OpLog {
         }
     }
 
+    /// Insert content at a character position. Throws if the content contains unpaired
+    /// surrogates.
     #[wasm_bindgen(js_name = ins)]
-    pub fn add_insert(&mut self, pos: usize, content: &str, parents_in: Option<Box<[usize]>>) -> usize {
+    pub fn add_insert(&mut self, pos: usize, content: &JsString, parents_in: Option<Box<[usize]>>) -> Result<usize, JsError> {
+        let content = js_to_string(content)?;
         // let parents = parents_in.map_or_else(|| self.inner.local_version(), |p| {
         //     js_to_internal_version(&p)
         // });
@@ -205,17 +233,17 @@ impl OpLog {
         let parents = parents_in.unwrap_or_else(|| {
             // Its gross here - I'm converting the frontier into a smallvec then immediately
             // converting it to a slice again :p
-            self.inner.local_frontier_ref().into()
+            self.inner.local_version_ref().into()
         });
         // Safe because we're just adding [ROOT] if its set.
-        self.inner.add_insert_at(unwrap_agentid(self.agent_id), &parents, pos, content)
+        Ok(self.inner.add_insert_at(unwrap_agentid(self.agent_id), &parents, pos, &content))
     }
 
     #[wasm_bindgen(js_name = del)]
     pub fn add_delete(&mut self, pos: usize, len: usize, parents_in: Option<Box<[usize]>>) -> usize {
         let parents = parents_in.unwrap_or_else(|| {
             // And here :p
-            self.inner.local_frontier_ref().into()
+            self.inner.local_version_ref().into()
         });
         self.inner.add_delete_at(unwrap_agentid(self.agent_id), &parents, pos..pos + len)
     }
@@ -260,8 +288,8 @@ impl OpLog {
     }
 
     #[wasm_bindgen(js_name = getLocalVersion)]
-    pub fn get_local_frontier(&self) -> Box<[LV]> {
-        self.inner.local_frontier_ref().into()
+    pub fn get_local_version(&self) -> Box<[LV]> {
+        self.inner.local_version_ref().into()
     }
 
     // #[wasm_bindgen]
@@ -328,6 +356,94 @@ impl OpLog {
     // pub fn merge_versions(&self, a: &[usize], b: &[usize]) ->
 }
 
+/// The result of calling [`Decoder::work`].
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeStatus {
+    /// More bytes need to be pushed before decoding can continue.
+    NeedsData,
+    /// There's more work to do. Call work() again (eg, in the next animation frame).
+    InProgress,
+    /// Every operation has been decoded. Call finish() to get the oplog.
+    Done,
+}
+
+impl From<DTDecodeStatus> for DecodeStatus {
+    fn from(status: DTDecodeStatus) -> Self {
+        match status {
+            DTDecodeStatus::NeedsData => DecodeStatus::NeedsData,
+            DTDecodeStatus::InProgress => DecodeStatus::InProgress,
+            DTDecodeStatus::Done => DecodeStatus::Done,
+        }
+    }
+}
+
+/// Decodes an oplog a few operations at a time, so loading a large document doesn't freeze the
+/// page. Push bytes as they arrive, and call work() from setTimeout or requestAnimationFrame until
+/// it returns Done:
+///
+/// ```javascript
+/// const decoder = new Decoder()
+/// decoder.push(bytes)
+/// while (decoder.work(10000) !== DecodeStatus.Done) {
+///   await new Promise(resolve => setTimeout(resolve, 0))
+/// }
+/// const oplog = decoder.finish("seph")
+/// ```
+#[wasm_bindgen]
+pub struct Decoder(DecodeDriver);
+
+#[wasm_bindgen]
+impl Decoder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        utils::set_panic_hook();
+        Self(DecodeDriver::new(DecodeOptions::default()))
+    }
+
+    /// Add the next chunk of the encoded oplog.
+    #[wasm_bindgen]
+    pub fn push(&mut self, bytes: &[u8]) -> WasmResult<()> {
+        self.0.push(bytes).map_err(|e| {
+            let js: JsValue = format!("Error decoding {:?}", e).into();
+            js.into()
+        })
+    }
+
+    /// Decode at most `budget` operations.
+    #[wasm_bindgen]
+    pub fn work(&mut self, budget: usize) -> WasmResult<DecodeStatus> {
+        match self.0.work(budget) {
+            Ok(status) => Ok(status.into()),
+            Err(e) => {
+                let js: JsValue = format!("Error decoding {:?}", e).into();
+                Err(js.into())
+            }
+        }
+    }
+
+    /// Finish decoding any remaining operations, and return the oplog.
+    #[wasm_bindgen]
+    pub fn finish(self, agent_name: Option<String>) -> WasmResult<OpLog> {
+        match self.0.finish() {
+            Ok(mut inner) => {
+                let agent_id = agent_name.map(|name| {
+                    inner.get_or_create_agent_id(name.as_str())
+                });
+                Ok(OpLog { inner, agent_id })
+            }
+            Err(e) => {
+                let js: JsValue = format!("Error decoding {:?}", e).into();
+                Err(js.into())
+            }
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self { Self::new() }
+}
+
 #[wasm_bindgen]
 pub struct Doc {
     inner: ListCRDT,
@@ -357,10 +473,14 @@ impl Doc {
         Doc { inner, agent_id }
     }
 
+    /// Insert content at a character position. Throws if the content contains unpaired
+    /// surrogates.
     #[wasm_bindgen]
-    pub fn ins(&mut self, pos: usize, content: &str) {
+    pub fn ins(&mut self, pos: usize, content: &JsString) -> Result<(), JsError> {
         // let id = self.0.get_or_create_agent_id("seph");
-        self.inner.insert(unwrap_agentid(self.agent_id), pos, content);
+        let content = js_to_string(content)?;
+        self.inner.insert(unwrap_agentid(self.agent_id), pos, &content);
+        Ok(())
     }
 
     #[wasm_bindgen]
@@ -400,6 +520,8 @@ impl Doc {
 
     // TODO: Do better error handling here.
     // pub fn from_bytes(bytes: &[u8], agent_name: Option<String>) -> WasmResult<Doc> {
+    /// Open a document. The document's content is always checked out from the operations, even if
+    /// the file stores it.
     #[wasm_bindgen(js_name = fromBytes)]
     pub fn from_bytes(bytes: &[u8], agent_name: Option<String>) -> Self {
         utils::set_panic_hook();
@@ -447,8 +569,8 @@ impl Doc {
     }
 
     #[wasm_bindgen(js_name = getLocalVersion)]
-    pub fn get_local_frontier(&self) -> Box<[LV]> {
-        self.inner.branch.local_frontier_ref().into()
+    pub fn get_local_version(&self) -> Box<[LV]> {
+        self.inner.local_version_ref().into()
     }
 
     #[wasm_bindgen(js_name = localToRemoteVersion)]
@@ -458,7 +580,7 @@ impl Doc {
 
     #[wasm_bindgen(js_name = getRemoteVersion)]
     pub fn get_remote_version(&self) -> WasmResult {
-        local_to_remote_version(&self.inner.oplog, &self.inner.branch.local_frontier_ref())
+        local_to_remote_version(&self.inner.oplog, self.inner.local_version_ref())
     }
 
     #[wasm_bindgen(js_name = xfSince)]
@@ -476,14 +598,18 @@ impl Doc {
         merge_versions(&self.inner.oplog, a, b)
     }
 
+    /// Convert a UTF-16 position in the document into a character position. Throws if the
+    /// position is past the end or between the two halves of a surrogate pair.
     #[wasm_bindgen(js_name = wCharsToChars)]
-    pub fn wchars_to_chars(&self, pos_wchars: usize) -> usize {
-        self.inner.branch.content().borrow().wchars_to_chars(pos_wchars)
+    pub fn wchars_to_chars(&self, pos_wchars: usize) -> Result<usize, JsError> {
+        wchars_to_chars(&self.inner.branch, pos_wchars)
     }
 
+    /// Convert a character position in the document into a UTF-16 position. Throws if the
+    /// position is past the end.
     #[wasm_bindgen(js_name = charsToWchars)]
-    pub fn chars_to_wchars(&self, pos_chars: usize) -> usize {
-        self.inner.branch.content().borrow().chars_to_wchars(pos_chars)
+    pub fn chars_to_wchars(&self, pos_chars: usize) -> Result<usize, JsError> {
+        chars_to_wchars(&self.inner.branch, pos_chars)
     }
 
     // #[wasm_bindgen]
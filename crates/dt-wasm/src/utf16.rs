@@ -0,0 +1,78 @@
+//! Checking the strings handed to us by Javascript.
+//!
+//! Javascript strings are sequences of UTF-16 code units, and they can contain unpaired
+//! surrogates. Rust strings can't. If wasm-bindgen converted them for us, each unpaired surrogate
+//! would silently become U+FFFD, and the document's length (and every position after it) would no
+//! longer match the string the Javascript side is holding. So strings containing unpaired
+//! surrogates are rejected instead, with the index of the offending code unit.
+//!
+//! Every string which makes it into a document is valid unicode, so its UTF-16 length is exactly
+//! the length Javascript sees, and positions between characters convert exactly both ways.
+
+use wasm_bindgen::JsError;
+use js_sys::JsString;
+
+/// A string contained an unpaired surrogate at this UTF-16 index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct UnpairedSurrogate {
+    pub index: usize,
+    pub unit: u16,
+}
+
+/// Decode UTF-16 code units into a string, or find the first unpaired surrogate.
+pub(crate) fn string_from_utf16<I: IntoIterator<Item = u16>>(units: I) -> Result<String, UnpairedSurrogate> {
+    let mut result = String::new();
+    let mut index = 0;
+    for c in char::decode_utf16(units) {
+        match c {
+            Ok(c) => {
+                result.push(c);
+                index += c.len_utf16();
+            }
+            Err(e) => return Err(UnpairedSurrogate { index, unit: e.unpaired_surrogate() }),
+        }
+    }
+    Ok(result)
+}
+
+/// Convert a Javascript string into a Rust string, throwing if it contains unpaired surrogates.
+pub(crate) fn js_to_string(s: &JsString) -> Result<String, JsError> {
+    if s.is_valid_utf16() {
+        // Valid strings convert without any replacement characters.
+        return Ok(s.as_string().unwrap());
+    }
+
+    match string_from_utf16(s.iter()) {
+        Ok(s) => Ok(s),
+        Err(UnpairedSurrogate { index, unit }) => Err(JsError::new(&format!(
+            "String contains an unpaired surrogate (0x{unit:X}) at UTF-16 index {index}. Only valid unicode text can be inserted"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid_utf16_decodes() {
+        let s = "a😀bツ";
+        assert_eq!(string_from_utf16(s.encode_utf16()), Ok(s.to_string()));
+        assert_eq!(string_from_utf16([]), Ok(String::new()));
+    }
+
+    #[test]
+    fn lone_surrogates_are_found() {
+        // "a😀" followed by a lone high surrogate, then "b".
+        let mut units: Vec<u16> = "a😀".encode_utf16().collect();
+        units.extend([0xD83D, 'b' as u16]);
+        assert_eq!(string_from_utf16(units), Err(UnpairedSurrogate { index: 3, unit: 0xD83D }));
+
+        // A low surrogate on its own, at the start and at the end.
+        assert_eq!(string_from_utf16([0xDE00, 'x' as u16]), Err(UnpairedSurrogate { index: 0, unit: 0xDE00 }));
+        assert_eq!(string_from_utf16(['x' as u16, 0xD800]), Err(UnpairedSurrogate { index: 1, unit: 0xD800 }));
+
+        // Two high surrogates in a row. The first is unpaired, even though the second is fine.
+        assert_eq!(string_from_utf16([0xD83D, 0xD83D, 0xDE00]), Err(UnpairedSurrogate { index: 0, unit: 0xD83D }));
+    }
+}
@@ -0,0 +1,200 @@
+//! Checks that diamond-types still builds for wasm32-unknown-unknown, and that the resulting
+//! module stays inside its size budget.
+//!
+//! This needs the wasm32-unknown-unknown target installed, so its ignored by default. Run it with:
+//!
+//!     cargo wasm-size
+//!
+//! The budget (in bytes) can be overridden with DT_WASM_SIZE_BUDGET. Custom sections (debug names
+//! and wasm-bindgen's metadata) are stripped before the module is measured, since wasm-bindgen
+//! removes them too. If wasm-opt is on the path, the module is also optimized with it, like
+//! wasm-pack does.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::path::PathBuf;
+use std::process::Command;
+
+const TARGET: &str = "wasm32-unknown-unknown";
+/// The module was 653,007 bytes (stripped, without wasm-opt) when this was last set.
+const DEFAULT_BUDGET: usize = 700 * 1024;
+
+fn target_dir() -> PathBuf {
+    match std::env::var_os("CARGO_TARGET_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../target"),
+    }
+}
+
+fn read_leb(data: &[u8], pos: &mut usize) -> usize {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        result |= ((b & 0x7f) as usize) << shift;
+        if b & 0x80 == 0 { return result; }
+        shift += 7;
+    }
+}
+
+fn read_name<'a>(data: &'a [u8], pos: &mut usize) -> &'a str {
+    let len = read_leb(data, pos);
+    let s = std::str::from_utf8(&data[*pos..*pos + len]).unwrap_or("<invalid utf8>");
+    *pos += len;
+    s
+}
+
+/// Split a module into its sections, as (id, whole section including its header).
+fn sections(wasm: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut result = vec![];
+    let mut pos = 8; // Magic number and version.
+    while pos < wasm.len() {
+        let start = pos;
+        pos += 1;
+        let len = read_leb(wasm, &mut pos);
+        pos += len;
+        result.push((wasm[start], &wasm[start..pos]));
+    }
+    result
+}
+
+/// A copy of the module without its custom sections.
+fn strip_custom_sections(wasm: &[u8]) -> Vec<u8> {
+    let mut result = wasm[..8].to_vec();
+    for (id, section) in sections(wasm) {
+        if id != 0 { result.extend_from_slice(section); }
+    }
+    result
+}
+
+/// Demangle a legacy Rust symbol name (`_ZN...E`), dropping its hash. Other names are returned
+/// unchanged.
+fn demangle(name: &str) -> String {
+    let Some(mut rest) = name.strip_prefix("_ZN") else { return name.to_string(); };
+    let mut parts = vec![];
+    while let Some(len_end) = rest.find(|c: char| !c.is_ascii_digit()).filter(|&i| i > 0) {
+        let len: usize = rest[..len_end].parse().unwrap();
+        let Some(part) = rest.get(len_end..len_end + len) else { return name.to_string(); };
+        // Parts starting with an escape are prefixed with an underscore.
+        parts.push(part.strip_prefix("_$").map_or(part, |_| &part[1..]));
+        rest = &rest[len_end + len..];
+    }
+    if parts.last().is_some_and(|p| p.starts_with('h') && p.len() == 17) { parts.pop(); }
+
+    let mut result = parts.join("::");
+    for (from, to) in [("$LT$", "<"), ("$GT$", ">"), ("$RF$", "&"), ("$BP$", "*"), ("$C$", ","), ("$u20$", " "), ("$u27$", "'"), ("$u5b$", "["), ("$u5d$", "]"), ("$u7b$", "{"), ("$u7d$", "}"), ("$u3b$", ";"), ("$u2b$", "+"), ("$u22$", "\""), ("..", "::")] {
+        result = result.replace(from, to);
+    }
+    result
+}
+
+/// Returns (name, size) for each function body in the module, biggest first. Functions are only
+/// named if the module still has its "name" section.
+fn function_sizes(wasm: &[u8]) -> Vec<(String, usize)> {
+    let mut imported_fns = 0;
+    let mut bodies = vec![];
+    let mut names = std::collections::HashMap::new();
+
+    let mut pos = 8; // Magic number and version.
+    while pos < wasm.len() {
+        let id = wasm[pos];
+        pos += 1;
+        let len = read_leb(wasm, &mut pos);
+        let end = pos + len;
+        let mut p = pos;
+
+        match id {
+            2 => { // Imports. These come before defined functions in the function index space.
+                for _ in 0..read_leb(wasm, &mut p) {
+                    read_name(wasm, &mut p);
+                    read_name(wasm, &mut p);
+                    let kind = wasm[p];
+                    p += 1;
+                    match kind {
+                        0 => { imported_fns += 1; read_leb(wasm, &mut p); }
+                        1 | 2 => {
+                            if kind == 1 { p += 1; } // Table element type.
+                            let flags = wasm[p];
+                            p += 1;
+                            read_leb(wasm, &mut p);
+                            if flags & 1 != 0 { read_leb(wasm, &mut p); }
+                        }
+                        3 => p += 2,
+                        _ => { p += 1; read_leb(wasm, &mut p); }
+                    }
+                }
+            }
+            10 => { // Code.
+                for _ in 0..read_leb(wasm, &mut p) {
+                    let size = read_leb(wasm, &mut p);
+                    bodies.push(size);
+                    p += size;
+                }
+            }
+            0 if read_name(wasm, &mut p) == "name" => {
+                while p < end {
+                    let sub_id = wasm[p];
+                    p += 1;
+                    let sub_len = read_leb(wasm, &mut p);
+                    let sub_end = p + sub_len;
+                    if sub_id == 1 { // Function names.
+                        for _ in 0..read_leb(wasm, &mut p) {
+                            let idx = read_leb(wasm, &mut p);
+                            names.insert(idx, read_name(wasm, &mut p).to_string());
+                        }
+                    }
+                    p = sub_end;
+                }
+            }
+            _ => {}
+        }
+        pos = end;
+    }
+
+    let mut result: Vec<_> = bodies.into_iter().enumerate().map(|(i, size)| {
+        let idx = i + imported_fns;
+        (names.remove(&idx).unwrap_or_else(|| format!("<function {idx}>")), size)
+    }).collect();
+    result.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    result
+}
+
+#[test]
+#[ignore]
+fn wasm_size_budget() {
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--release", "--target", TARGET, "-p", "dt-wasm"])
+        .status()
+        .expect("Could not run cargo");
+    assert!(status.success(), "dt-wasm failed to build for {TARGET}");
+
+    let built = target_dir().join(TARGET).join("release/dt_wasm.wasm");
+    let wasm = std::fs::read(&built).unwrap();
+    let stripped = built.with_file_name("dt_wasm.stripped.wasm");
+    std::fs::write(&stripped, strip_custom_sections(&wasm)).unwrap();
+
+    let optimized = built.with_file_name("dt_wasm.opt.wasm");
+    let measured = match Command::new("wasm-opt").arg("-O").arg(&stripped).arg("-o").arg(&optimized).status() {
+        Ok(s) if s.success() => &optimized,
+        _ => {
+            eprintln!("wasm-opt not found. Measuring the unoptimized module.");
+            &stripped
+        }
+    };
+
+    let size = std::fs::metadata(measured).unwrap().len() as usize;
+    let budget = std::env::var("DT_WASM_SIZE_BUDGET")
+        .map(|b| b.parse().expect("Invalid DT_WASM_SIZE_BUDGET"))
+        .unwrap_or(DEFAULT_BUDGET);
+    println!("{} is {size} bytes (budget {budget})", measured.display());
+
+    if size > budget {
+        // The name section has been stripped, so the breakdown comes from cargo's output.
+        println!("Largest functions:");
+        for (name, size) in function_sizes(&wasm).iter().take(20) {
+            println!("{size:>10}  {}", demangle(name));
+        }
+        panic!("wasm module is {size} bytes, which is over the budget of {budget} bytes");
+    }
+}
@@ -11,3 +11,62 @@ wasm_bindgen_test_configure!(run_in_browser);
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+#[wasm_bindgen_test]
+fn decoder_reads_chunks() {
+    use dt_wasm::{Decoder, DecodeStatus, OpLog};
+
+    let mut oplog = OpLog::new(Some("seph".into()));
+    oplog.add_insert(0, &"hi there".into(), None).unwrap();
+    oplog.add_delete(1, 2, None);
+    let bytes = oplog.to_bytes();
+    let (a, b) = bytes.split_at(bytes.len() / 2);
+
+    let mut decoder = Decoder::new();
+    decoder.push(a).unwrap();
+    // The second half hasn't arrived yet, so decoding can't finish.
+    assert_ne!(decoder.work(1).unwrap(), DecodeStatus::Done);
+    decoder.push(b).unwrap();
+    while decoder.work(1).unwrap() != DecodeStatus::Done {}
+
+    assert_eq!(decoder.finish(None).unwrap().to_bytes(), bytes);
+}
+
+#[wasm_bindgen_test]
+fn unpaired_surrogates_are_rejected() {
+    use js_sys::JsString;
+    use dt_wasm::{Doc, OpLog};
+
+    // "a", a lone high surrogate, then "b".
+    let bad = JsString::from_char_code(&[0x61, 0xD83D, 0x62]);
+
+    let mut doc = Doc::new(Some("seph".into()));
+    assert!(doc.ins(0, &bad).is_err());
+    assert_eq!(doc.len(), 0);
+
+    let mut oplog = OpLog::new(Some("seph".into()));
+    assert!(oplog.add_insert(0, &bad, None).is_err());
+    assert_eq!(oplog.get_local_version().len(), 0);
+}
+
+#[wasm_bindgen_test]
+fn wchar_positions_around_surrogate_pairs() {
+    use js_sys::JsString;
+    use dt_wasm::Doc;
+
+    // "a😀b" - the emoji is 2 UTF-16 code units but 1 character.
+    let s = JsString::from_char_code(&[0x61, 0xD83D, 0xDE00, 0x62]);
+    let mut doc = Doc::new(Some("seph".into()));
+    doc.ins(0, &s).unwrap();
+
+    assert_eq!(doc.wchars_to_chars(1).unwrap(), 1);
+    assert_eq!(doc.wchars_to_chars(3).unwrap(), 2);
+    assert_eq!(doc.wchars_to_chars(4).unwrap(), 3);
+    // Inside the surrogate pair, and past the end.
+    assert!(doc.wchars_to_chars(2).is_err());
+    assert!(doc.wchars_to_chars(5).is_err());
+
+    assert_eq!(doc.chars_to_wchars(2).unwrap(), 3);
+    assert_eq!(doc.chars_to_wchars(3).unwrap(), s.length() as usize);
+    assert!(doc.chars_to_wchars(4).is_err());
+}
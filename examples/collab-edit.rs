@@ -0,0 +1,242 @@
+// A tiny collaborative text editor, showing how diamond types is intended to be wired up to a
+// network. Two instances of this program connect over a localhost TCP socket, and keep their
+// documents in sync using a SyncSession. Every local edit is applied to a branch and announced to
+// the other peer, which merges whatever it's missing into its own oplog and branch. Both peers
+// always converge on the same document.
+//
+// Run with:
+// $ cargo run --example collab-edit -- listen 127.0.0.1:9123
+// $ cargo run --example collab-edit -- connect 127.0.0.1:9123
+//
+// Then type commands:
+//   i <pos> <text>   Insert text at pos (in unicode characters)
+//   d <start> <end>  Delete the characters in start..end
+//   p                Print the document
+//   q                Quit
+
+use std::env;
+use std::error::Error;
+use std::io::{BufRead, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use diamond_types::AgentId;
+use diamond_types::list::{ListBranch, ListOpLog};
+use diamond_types::list::sync::SyncSession;
+
+/// The sync core. This has nothing to do with the network - it just holds a document and produces
+/// and consumes sync messages.
+#[derive(Debug)]
+pub struct Peer {
+    oplog: ListOpLog,
+    branch: ListBranch,
+    agent: AgentId,
+
+    /// What we know about the remote peer. This works out what needs to be sent.
+    session: SyncSession,
+}
+
+impl Peer {
+    pub fn new(agent_name: &str) -> Self {
+        let mut oplog = ListOpLog::new();
+        let agent = oplog.get_or_create_agent_id(agent_name);
+        Self {
+            oplog,
+            branch: ListBranch::new(),
+            agent,
+            session: SyncSession::new(),
+        }
+    }
+
+    pub fn content(&self) -> String {
+        self.branch.content().to_string()
+    }
+
+    /// Insert text at the given character position. Returns the message to send to the remote
+    /// peer.
+    pub fn insert(&mut self, pos: usize, text: &str) -> Vec<u8> {
+        self.branch.insert(&mut self.oplog, self.agent, pos, text);
+        self.hello()
+    }
+
+    /// Delete the given range of characters. Returns the message to send to the remote peer.
+    pub fn delete(&mut self, start: usize, end: usize) -> Vec<u8> {
+        self.branch.delete(&mut self.oplog, self.agent, start..end);
+        self.hello()
+    }
+
+    /// A message telling the remote peer what we have, along with anything it's missing. This is
+    /// sent when connecting and after every local edit.
+    pub fn hello(&mut self) -> Vec<u8> {
+        self.session.start_message(&self.oplog)
+    }
+
+    /// Process a message from the remote peer, and bring the branch up to date. Returns the reply
+    /// to send back, if any.
+    pub fn on_message(&mut self, msg: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let step = self.session.on_message(&mut self.oplog, msg)?;
+        if step.applied.is_some() {
+            self.branch.merge(&self.oplog, self.oplog.local_version_ref());
+        }
+        Ok(step.reply)
+    }
+}
+
+// *** Networking. Messages are sent as a u32 (LE) length followed by the message bytes.
+
+fn write_frame(stream: &mut TcpStream, data: &[u8]) -> std::io::Result<()> {
+    stream.write_all(&(data.len() as u32).to_le_bytes())?;
+    stream.write_all(data)
+}
+
+fn read_frame(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn run(peer: Peer, mut stream: TcpStream) -> std::io::Result<()> {
+    let peer = Arc::new(Mutex::new(peer));
+
+    let hello = peer.lock().unwrap().hello();
+    write_frame(&mut stream, &hello)?;
+
+    let mut recv_stream = stream.try_clone()?;
+    let recv_peer = peer.clone();
+    thread::spawn(move || {
+        let mut reply_stream = recv_stream.try_clone().unwrap();
+        while let Ok(msg) = read_frame(&mut recv_stream) {
+            // Replies are written while holding the lock, so they don't interleave with frames
+            // written by the main thread.
+            let mut peer = recv_peer.lock().unwrap();
+            let before = peer.content();
+            match peer.on_message(&msg) {
+                Ok(reply) => {
+                    if peer.content() != before { println!("< {:?}", peer.content()); }
+                    if let Some(reply) = reply {
+                        if write_frame(&mut reply_stream, &reply).is_err() { break; }
+                    }
+                }
+                Err(e) => eprintln!("Could not process remote message: {e}"),
+            }
+        }
+        println!("Remote peer disconnected");
+        std::process::exit(0);
+    });
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let mut parts = line.splitn(3, ' ');
+        let cmd = parts.next().unwrap_or("");
+        let arg1 = parts.next().and_then(|s| s.parse::<usize>().ok());
+        let arg2 = parts.next();
+
+        let mut peer = peer.lock().unwrap();
+        let len = peer.branch.len();
+        let msg = match (cmd, arg1, arg2) {
+            ("i", Some(pos), Some(text)) if pos <= len => peer.insert(pos, text),
+            ("d", Some(start), Some(end)) => match end.parse::<usize>() {
+                Ok(end) if start < end && end <= len => peer.delete(start, end),
+                _ => { eprintln!("Invalid range"); continue; }
+            },
+            ("p", _, _) => { println!("{:?}", peer.content()); continue; }
+            ("q", _, _) => break,
+            _ => { eprintln!("Commands: i <pos> <text> | d <start> <end> | p | q"); continue; }
+        };
+
+        println!("> {:?}", peer.content());
+        write_frame(&mut stream, &msg)?;
+    }
+
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let (mode, addr) = match args.as_slice() {
+        [_, mode, addr] => (mode.as_str(), addr.as_str()),
+        _ => {
+            eprintln!("Usage: collab-edit (listen|connect) <addr>");
+            std::process::exit(1);
+        }
+    };
+
+    let stream = match mode {
+        "listen" => {
+            let listener = TcpListener::bind(addr)?;
+            println!("Waiting for a peer on {addr}");
+            listener.accept()?.0
+        }
+        "connect" => TcpStream::connect(addr)?,
+        _ => {
+            eprintln!("Mode must be listen or connect");
+            std::process::exit(1);
+        }
+    };
+
+    println!("Connected");
+    run(Peer::new(mode), stream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Pass messages back and forth until neither peer has anything more to say.
+    fn exchange(a: &mut Peer, b: &mut Peer, mut to_b: Vec<Vec<u8>>) {
+        let mut to_a = vec![];
+        while !to_a.is_empty() || !to_b.is_empty() {
+            for msg in std::mem::take(&mut to_b) {
+                to_a.extend(b.on_message(&msg).unwrap());
+            }
+            for msg in std::mem::take(&mut to_a) {
+                to_b.extend(a.on_message(&msg).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn peers_converge() {
+        let mut a = Peer::new("a");
+        let mut b = Peer::new("b");
+        let hello_a = a.hello();
+        let hello_b = b.hello();
+        exchange(&mut b, &mut a, vec![hello_b]);
+        exchange(&mut a, &mut b, vec![hello_a]);
+
+        let msg = a.insert(0, "hello world");
+        exchange(&mut a, &mut b, vec![msg]);
+        assert_eq!(b.content(), "hello world");
+
+        // Concurrent edits on both sides.
+        let ma = a.insert(5, " there");
+        b.delete(0, 1);
+        let mb = b.insert(0, "H");
+
+        exchange(&mut b, &mut a, vec![mb]);
+        exchange(&mut a, &mut b, vec![ma]);
+
+        assert_eq!(a.content(), "Hello there world");
+        assert_eq!(a.content(), b.content());
+    }
+
+    #[test]
+    fn duplicate_messages_are_ignored() {
+        let mut a = Peer::new("a");
+        let mut b = Peer::new("b");
+        let hello_b = b.hello();
+        a.on_message(&hello_b).unwrap();
+
+        let msg = a.insert(0, "abc");
+        b.on_message(&msg).unwrap();
+        b.on_message(&msg).unwrap();
+        assert_eq!(b.content(), "abc");
+
+        // Resending everything must also merge cleanly.
+        let everything = a.hello();
+        b.on_message(&everything).unwrap();
+        assert_eq!(b.content(), "abc");
+    }
+}
@@ -14,7 +14,7 @@ use diamond_types::list::*;
 use trace_alloc::*;
 #[cfg(feature = "memusage")]
 use humansize::{DECIMAL, format_size};
-use diamond_types::list::encoding::EncodeOptions;
+use diamond_types::list::encoding::{EncodeMode, EncodeOptions};
 
 pub fn apply_edits_direct(doc: &mut ListCRDT, txns: &Vec<TestTxn>) {
     let id = doc.get_or_create_agent_id("jeremy");
@@ -77,6 +77,12 @@ fn print_stats_for_testdata(name: &str) {
         store_inserted_content: true,
         store_deleted_content: false,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: true
     });
     println!("Regular file size {} bytes", data.len());
@@ -110,6 +116,12 @@ fn print_stats_for_file(name: &str) {
         store_inserted_content: false,
         store_deleted_content: false,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: true
     });
     println!("Smol size {}", data_smol.len());
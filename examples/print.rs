@@ -1,7 +1,7 @@
 #![allow(unused)]
 
 use std::env;
-use diamond_types::list::{ListOpLog, encoding::EncodeOptions};
+use diamond_types::list::{ListOpLog, encoding::{EncodeMode, EncodeOptions}};
 use rle::zip::rle_zip;
 
 fn print_stats_for_file(name: &str) {
@@ -67,6 +67,12 @@ fn print_stats_for_file(name: &str) {
         store_inserted_content: true,
         store_deleted_content: true,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: true,
     });
 }
@@ -33,8 +33,13 @@ impl RegisterState {
 }
 
 impl OpLog {
-    pub fn checkout_at_version(_frontier: &[LV]) -> Branch {
-        todo!()
+    /// Check out the document at some point in its history.
+    ///
+    /// This isn't implemented yet, and always returns [`Error::NotSupported`](crate::Error). Use
+    /// [`checkout_tip`](OpLog::checkout_tip) to check out the current version.
+    #[allow(unused)] // Not public until its implemented.
+    pub(crate) fn checkout_at_version(&self, _frontier: &[LV]) -> Result<Branch, crate::Error> {
+        Err(crate::Error::NotSupported)
     }
 
     /// Get the current value for this register, ignoring any other conflicting values.
@@ -117,8 +122,9 @@ impl OpLog {
                             // I could use recursion here but this avoids stack-smashing attacks.
                             maps_to_copy.push(*child_map);
                         }
-                        RegisterValue::OwnedCRDT(CRDTKind::Register, _) => { todo!() }
-                        RegisterValue::OwnedCRDT(CRDTKind::Collection, _) => { todo!() }
+                        // Registers and collections aren't implemented yet. They don't have any
+                        // state to copy.
+                        RegisterValue::OwnedCRDT(CRDTKind::Register | CRDTKind::Collection, _) => {}
                         RegisterValue::OwnedCRDT(CRDTKind::Text, text_crdt) => {
                             // Eventually (rich) text items might contain more embedded CRDTs. But for
                             // now this is fine.
@@ -177,12 +183,18 @@ impl Branch {
             CRDTKind::Text => {
                 self.texts.remove(&crdt); // Easy peasy!
             }
-            _ => { todo!() }
+            // Not implemented yet, so there's nothing stored for these.
+            CRDTKind::Register | CRDTKind::Collection => {}
         }
     }
 
     /// Returns the list of version ranges which were merged, in reverse order (!!!)
     pub fn merge_changes_to_tip(&mut self, oplog: &OpLog) -> SmallVec<[DTRange; 4]> {
+        // Everything else is looked up in the oplog each time, so this is the only thing which can
+        // go stale.
+        debug_assert!(self.frontier.iter().all(|&v| v < oplog.cg.len()),
+            "Branch frontier {:?} is not in the oplog", self.frontier);
+
         // Well, for now nothing can be deleted yet. So that makes things easier.
         let diff_rev = oplog.cg.diff_since_rev(self.frontier.as_ref());
 
@@ -228,47 +240,39 @@ impl Branch {
         diff_rev
     }
 
-    pub fn crdt_at_path(&self, path: &[&str]) -> (CRDTKind, LVKey) {
+    /// Find the CRDT at the specified path. Returns None if the path doesn't name a CRDT in the
+    /// document.
+    pub fn crdt_at_path(&self, path: &[&str]) -> Option<(CRDTKind, LVKey)> {
         let mut kind = CRDTKind::Map;
         let mut key = ROOT_CRDT_ID;
 
         for p in path {
-            match kind {
-                CRDTKind::Map => {
-                    let obj = self.maps.get(&key).unwrap();
-                    let state = obj.get(*p).unwrap();
-
-                    match state.value {
-                        RegisterValue::Primitive(_) => {
-                            panic!("Found primitive, not CRDT");
-                        }
-                        RegisterValue::OwnedCRDT(new_kind, new_key) => {
-                            kind = new_kind;
-                            key = new_key;
-                        }
-                    }
-                }
-                _ => {
-                    panic!("Invalid path in document");
+            if kind != CRDTKind::Map { return None; }
+
+            let state = self.maps.get(&key)?.get(*p)?;
+            match state.value {
+                RegisterValue::Primitive(_) => { return None; } // Found primitive, not CRDT.
+                RegisterValue::OwnedCRDT(new_kind, new_key) => {
+                    kind = new_kind;
+                    key = new_key;
                 }
             }
         }
 
-        (kind, key)
+        Some((kind, key))
     }
 
-    pub fn text_at_path(&self, path: &[&str]) -> LVKey {
-        let (kind, key) = self.crdt_at_path(path);
-        if kind != CRDTKind::Text {
-            panic!("Unexpected CRDT kind {:?}", kind);
-        } else { key }
+    /// Find the text CRDT at the specified path, if there is one.
+    pub fn text_at_path(&self, path: &[&str]) -> Option<LVKey> {
+        match self.crdt_at_path(path)? {
+            (CRDTKind::Text, key) => Some(key),
+            _ => None,
+        }
     }
 
     pub fn register_in_map(&self, path: &[&str], key: &str) -> Option<&RegisterValue> {
-        let (kind, crdt) = self.crdt_at_path(path);
-        if kind != CRDTKind::Map {
-            panic!("Expected a map, found a {:?}", kind);
-        }
+        let (kind, crdt) = self.crdt_at_path(path)?;
+        if kind != CRDTKind::Map { return None; }
 
         Some(&self.maps.get(&crdt)?.get(key)?.value)
     }
@@ -299,10 +303,10 @@ impl Branch {
                 reg_state.each_value(|v| {
                     if let RegisterValue::OwnedCRDT(kind, key) = v {
                         match kind {
-                            CRDTKind::Map => &mut owned_map_crdts,
-                            CRDTKind::Text => &mut owned_text_crdts,
-                            _ => { unimplemented!() }
-                        }.insert(*key);
+                            CRDTKind::Map => { owned_map_crdts.insert(*key); }
+                            CRDTKind::Text => { owned_text_crdts.insert(*key); }
+                            CRDTKind::Register | CRDTKind::Collection => {}
+                        }
                     }
                 });
             }
@@ -404,4 +408,44 @@ mod tests {
 
         assert_eq!(branch_expected, branch_incremental);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unsupported_crdt_kinds_dont_panic() {
+        // Registers and collections aren't implemented yet, but creating them shouldn't take the
+        // process down when the document is checked out or the value is overwritten.
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::NewCRDT(CRDTKind::Register));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "coll", CreateValue::NewCRDT(CRDTKind::Collection));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "n", CreateValue::Primitive(Primitive::I64(1)));
+
+        let mut branch = Branch::new();
+        branch.merge_changes_to_tip(&oplog);
+        assert_eq!(branch, check_oplog_checkouts_match(&oplog));
+        assert_eq!(oplog.checkout().keys().collect::<Vec<_>>(), vec!["n"]);
+
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "reg", CreateValue::Primitive(Primitive::I64(2)));
+        branch.merge_changes_to_tip(&oplog);
+        assert_eq!(branch, check_oplog_checkouts_match(&oplog));
+    }
+
+    #[test]
+    fn bad_paths_return_none() {
+        let mut oplog = OpLog::new();
+        let seph = oplog.cg.get_or_create_agent_id("seph");
+        let text = oplog.local_map_set(seph, ROOT_CRDT_ID, "text", CreateValue::NewCRDT(CRDTKind::Text));
+        oplog.local_map_set(seph, ROOT_CRDT_ID, "n", CreateValue::Primitive(Primitive::I64(1)));
+        let branch = oplog.checkout_tip();
+
+        assert_eq!(oplog.text_at_path(&["text"]), Some(text));
+        assert_eq!(branch.text_at_path(&["text"]), Some(text));
+
+        for path in [&["missing"][..], &["n"], &["text", "inner"], &[]] {
+            assert_eq!(oplog.text_at_path(path), None);
+            assert_eq!(branch.text_at_path(path), None);
+        }
+        assert_eq!(oplog.crdt_at_path(&["n"]), None);
+        assert_eq!(branch.crdt_at_path(&["text", "inner"]), None);
+        assert_eq!(branch.register_in_map(&["text"], "x"), None);
+    }
+}
@@ -111,6 +111,11 @@ impl AgentAssignment {
         self.client_data[agent as usize].name.as_str()
     }
 
+    /// The number of agents. Agent IDs are `0..num_agents()`.
+    pub fn num_agents(&self) -> usize {
+        self.client_data.len()
+    }
+
     pub fn len(&self) -> usize {
         self.client_with_localtime.end()
     }
@@ -1,15 +1,18 @@
 /// This file contains utilities to convert remote IDs to local version and back.
 
 
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use smartstring::alias::String as SmartString;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use rle::{HasLength, MergableSpan, SplitableSpanHelpers};
 use crate::dtrange::DTRange;
-use crate::{Frontier, LV};
+use crate::{CausalGraph, Frontier, LV};
 use crate::causalgraph::agent_assignment::AgentAssignment;
 use crate::causalgraph::agent_span::{AgentVersion, AgentSpan};
+use crate::rle::KVPair;
 
 /// Remote IDs are IDs you can pass to a remote peer.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -107,6 +110,33 @@ pub enum VersionConversionError {
     SeqInFuture,
 }
 
+impl Display for VersionConversionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionConversionError::UnknownAgent => write!(f, "Unknown agent"),
+            VersionConversionError::SeqInFuture => write!(f, "Sequence number is past the end of the agent's known operations"),
+        }
+    }
+}
+
+impl Error for VersionConversionError {}
+
+/// The result of resolving a batch of remote versions with
+/// [`CausalGraph::resolve_remote_versions`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ResolveResult<'a> {
+    /// The local version for each of the requested remote versions, in the same order as the input.
+    /// This is None for remote versions we don't know about.
+    pub resolved: Vec<Option<LV>>,
+
+    /// The requested remote versions which aren't known locally, in the order they were requested.
+    pub unknown: Vec<RemoteVersion<'a>>,
+
+    /// The dominators of all the known versions. This is the latest version which the peer who
+    /// sent the remote versions and this causal graph have in common.
+    pub frontier: Frontier,
+}
+
 impl AgentAssignment {
     pub fn try_remote_to_local_version(&self, rv: RemoteVersion) -> Result<LV, VersionConversionError> {
         let agent = self.get_agent_id(rv.0)
@@ -182,6 +212,36 @@ impl AgentAssignment {
         frontier
     }
 
+    /// Resolve a batch of remote versions, returning None for any which aren't known. The ids are
+    /// sorted by agent first, so each agent is only looked up once and each agent's list of seq
+    /// runs is only scanned once.
+    pub(crate) fn resolve_remote_versions(&self, ids: &[RemoteVersion]) -> Vec<Option<LV>> {
+        let mut order: Vec<usize> = (0..ids.len()).collect();
+        order.sort_unstable_by_key(|&i| (ids[i].0, ids[i].1));
+
+        let mut result = vec![None; ids.len()];
+        for group in order.chunk_by(|&a, &b| ids[a].0 == ids[b].0) {
+            let Some(agent) = self.get_agent_id(ids[group[0]].0) else { continue; };
+            let item_times = &self.client_data[agent as usize].item_times.0;
+
+            let mut idx = 0;
+            for &i in group {
+                let seq = ids[i].1;
+                while idx < item_times.len() && item_times[idx].0 + item_times[idx].1.len() <= seq {
+                    idx += 1;
+                }
+
+                if let Some(KVPair(seq_start, lvs)) = item_times.get(idx) {
+                    if *seq_start <= seq {
+                        result[i] = Some(lvs.start + seq - seq_start);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
     pub fn local_to_remote_frontier(&'_ self, local_frontier: &[LV]) -> RemoteFrontier<'_> {
         // Could return an impl Iterator here instead.
         local_frontier
@@ -211,6 +271,29 @@ impl AgentAssignment {
     }
 }
 
+impl CausalGraph {
+    /// Resolve as many of the given remote versions as possible. This is designed for sync
+    /// handshakes, where a peer sends us its frontier and we need to figure out which parts of it
+    /// we know about, which parts we're missing and the latest version we have in common.
+    ///
+    /// Unlike [`AgentAssignment::try_remote_to_local_frontier`], this doesn't fail if some of the
+    /// remote versions are unknown.
+    pub fn resolve_remote_versions<'a>(&self, ids: &[RemoteVersion<'a>]) -> ResolveResult<'a> {
+        let resolved = self.agent_assignment.resolve_remote_versions(ids);
+
+        let unknown = ids.iter().zip(resolved.iter())
+            .filter_map(|(rv, lv)| if lv.is_none() { Some(*rv) } else { None })
+            .collect();
+
+        let mut known: Vec<LV> = resolved.iter().flatten().copied().collect();
+        known.sort_unstable();
+        known.dedup();
+        let frontier = self.graph.find_dominators(&known);
+
+        ResolveResult { resolved, unknown, frontier }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::causalgraph::agent_assignment::remote_ids::{RemoteVersion, RemoteVersionOwned};
@@ -246,6 +329,76 @@ mod test {
         // ]);
     }
 
+    /// seph 0..5 and mike 0..3 are concurrent, then seph 5..7 merges them.
+    fn resolve_test_cg() -> CausalGraph {
+        let mut cg = CausalGraph::new();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.assign_local_op_with_parents(&[], seph, 5); // 0..5
+        cg.assign_local_op_with_parents(&[], mike, 3); // 5..8
+        cg.assign_local_op_with_parents(&[4, 7], seph, 2); // 8..10
+        cg
+    }
+
+    #[test]
+    fn resolve_known_versions() {
+        let cg = resolve_test_cg();
+
+        let result = cg.resolve_remote_versions(&[RemoteVersion("seph", 2), RemoteVersion("mike", 1)]);
+        assert_eq!(result.resolved, vec![Some(2), Some(6)]);
+        assert!(result.unknown.is_empty());
+        assert_eq!(result.frontier.as_ref(), &[2, 6]);
+
+        let result = cg.resolve_remote_versions(&[
+            RemoteVersion("seph", 6), RemoteVersion("mike", 2), RemoteVersion("seph", 1)
+        ]);
+        assert_eq!(result.resolved, vec![Some(9), Some(7), Some(1)]);
+        assert_eq!(result.frontier.as_ref(), &[9]);
+    }
+
+    #[test]
+    fn resolve_unknown_versions() {
+        let cg = resolve_test_cg();
+
+        let ids = [RemoteVersion("fred", 0), RemoteVersion("seph", 7), RemoteVersion("seph", 100)];
+        let result = cg.resolve_remote_versions(&ids);
+        assert_eq!(result.resolved, vec![None, None, None]);
+        assert_eq!(result.unknown, ids);
+        assert!(result.frontier.is_root());
+
+        assert!(cg.resolve_remote_versions(&[]).frontier.is_root());
+    }
+
+    #[test]
+    fn resolve_mixed_versions() {
+        let cg = resolve_test_cg();
+
+        let result = cg.resolve_remote_versions(&[
+            RemoteVersion("mike", 10), RemoteVersion("seph", 3),
+            RemoteVersion("fred", 1), RemoteVersion("mike", 0),
+        ]);
+        assert_eq!(result.resolved, vec![None, Some(3), None, Some(5)]);
+        assert_eq!(result.unknown, vec![RemoteVersion("mike", 10), RemoteVersion("fred", 1)]);
+        assert_eq!(result.frontier.as_ref(), &[3, 5]);
+    }
+
+    #[test]
+    fn resolve_matches_single_lookups() {
+        let cg = resolve_test_cg();
+
+        // Every seq for each agent, including the ones in the middle of runs, in a scrambled order
+        // with duplicates.
+        let ids: Vec<RemoteVersion> = (0..12).rev().chain(0..12)
+            .flat_map(|seq| ["seph", "mike", "fred"].map(|name| RemoteVersion(name, (seq * 7) % 12)))
+            .collect();
+        let result = cg.resolve_remote_versions(&ids);
+
+        for (rv, lv) in ids.iter().zip(result.resolved.iter()) {
+            assert_eq!(*lv, cg.agent_assignment.try_remote_to_local_version(*rv).ok());
+        }
+        assert_eq!(result.frontier.as_ref(), &[9]);
+    }
+
     #[test]
     fn remote_versions_can_be_empty() {
         let cg = CausalGraph::new();
@@ -98,8 +98,7 @@ impl CausalGraph {
 
         // Make sure the time isn't already assigned. Can I elide this check in release mode?
         // Note I only need to check the start of the seq_range.
-        let (x, _offset) = client_data.item_times.find_sparse(span.seq_range.start);
-        if let Err(range) = x {
+        if let Err(range) = client_data.item_times.find_sparse(span.seq_range.start) {
             assert!(range.end >= span.seq_range.end, "Time range already assigned");
         } else {
             panic!("Time range already assigned");
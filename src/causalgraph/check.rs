@@ -1,4 +1,6 @@
+use rle::HasLength;
 use crate::CausalGraph;
+use crate::causalgraph::topo::TopoOrder;
 use crate::causalgraph::agent_assignment::AgentAssignment;
 
 impl AgentAssignment {
@@ -33,6 +35,13 @@ impl CausalGraph {
     #[allow(unused)]
     pub fn dbg_check(&self, deep: bool) {
         if deep {
+            // The graph checks below assume the graph is acyclic, and some of them won't terminate
+            // if it isn't. A topological traversal only reaches every version if there's no cycle.
+            let reachable: usize = self.iter_topological(TopoOrder::DepthFirstMergeLast)
+                .map(|r| r.len())
+                .sum();
+            assert_eq!(reachable, self.len(), "Causal graph contains a cycle");
+
             self.graph.dbg_check(deep);
         }
 
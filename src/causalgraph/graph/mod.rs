@@ -96,8 +96,10 @@ impl Graph {
         }
 
         // let parents = replace(&mut self.frontier, txn_parents);
+        // Parents are sorted. Searching them matters when merging lots of concurrent branches,
+        // since this loop can step past each of them.
         let mut shadow = range.start;
-        while shadow >= 1 && txn_parents.contains(&(shadow - 1)) {
+        while shadow >= 1 && txn_parents.binary_search(&(shadow - 1)).is_ok() {
             shadow = self.entries.find(shadow - 1).unwrap().shadow;
         }
 
@@ -157,6 +159,23 @@ impl GraphEntryInternal {
         }
     }
 
+    /// The part of this entry which overlaps `range`. If the range starts part way through the
+    /// entry, the clipped entry's parent is the version just before it. Otherwise it keeps the
+    /// entry's parents.
+    ///
+    /// This is how history is split up everywhere it gets cut into ranges (iterating, walking and
+    /// encoding), so the results always line up.
+    pub(crate) fn clip(&self, range: DTRange) -> GraphEntrySimple {
+        let start = range.start.max(self.span.start);
+        let end = range.end.min(self.span.end);
+        debug_assert!(start < end);
+
+        GraphEntrySimple {
+            span: (start..end).into(),
+            parents: self.clone_parents_at_version(start),
+        }
+    }
+
     // fn next_child_after(&self, v: LV, parents: &Parents) -> Option<usize> {
     //     let span: DTRange = (v..self.span.end).into();
     //
@@ -368,8 +387,14 @@ impl<'a, I: Iterator<Item = &'a GraphEntrySimple>> From<I> for Graph {
 //     }
 // }
 impl Graph {
+    /// Iterate through the history entries in `range`, clipped to fit. See
+    /// [`GraphEntryInternal::clip`].
     pub(crate) fn iter_range(&self, range: DTRange) -> impl Iterator<Item =GraphEntrySimple> + '_ {
-        self.entries.iter_range_map(range, |e| e.into())
+        let start_idx = if range.is_empty() { self.entries.num_entries() }
+            else { self.entries.find_next_index(range.start) };
+        self.entries.0[start_idx..].iter()
+            .take_while(move |e| e.span.start < range.end)
+            .map(move |e| e.clip(range))
     }
 
     pub(crate) fn iter(&self) -> impl Iterator<Item =GraphEntrySimple> + '_ {
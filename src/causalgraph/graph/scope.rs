@@ -136,8 +136,7 @@ impl Graph {
                 0
             } else {
                 // TODO: Reuse binary search from above.
-                let r = info.owned_times.find_sparse(time).0;
-                r.unwrap_err().start
+                info.owned_times.find_sparse(time).unwrap_err().start
             };
             let base = min_safe_base.max(containing_txn.span.start);
 
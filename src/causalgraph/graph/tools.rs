@@ -541,6 +541,27 @@ impl Graph {
         Frontier(result)
     }
 
+    /// Find the version naming all the operations which are in both `a` and `b`. When `a` and `b`
+    /// are concurrent this is the version where their histories split. If one of the versions
+    /// contains the other, the smaller version is returned.
+    pub fn common_ancestor(&self, a: &[LV], b: &[LV]) -> Frontier {
+        let (only_a, _) = self.diff(a, b);
+
+        // The latest shared operations are either named in a directly, or they're parents of
+        // operations which are only in a.
+        let mut candidates: SmallVec<[LV; 4]> = a.into();
+        for span in only_a.iter() {
+            for e in self.iter_range(*span) {
+                candidates.extend_from_slice(e.parents.as_ref());
+            }
+        }
+        candidates.retain(|v| !only_a.iter().any(|span| span.contains(*v)));
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        self.find_dominators(&candidates)
+    }
+
     /// This method assumes v_1 and v_2 are already dominators.
     pub fn find_dominators_2(&self, v_1: &[LV], v_2: &[LV]) -> Frontier {
         if v_1.is_empty() { return v_2.into(); }
@@ -1129,6 +1150,46 @@ pub mod test {
         assert_diff_eq(&graph, &[4], &[], &[(3..5).into()], &[]);
     }
 
+    #[test]
+    fn common_ancestor_of_diamond() {
+        //   0
+        //  / \
+        // 1   2
+        //  \ /
+        //   3
+        let graph = Graph::from_simple_items(&[
+            GraphEntrySimple { span: (0..1).into(), parents: Frontier::root() },
+            GraphEntrySimple { span: (1..2).into(), parents: Frontier::new_1(0) },
+            GraphEntrySimple { span: (2..3).into(), parents: Frontier::new_1(0) },
+            GraphEntrySimple { span: (3..4).into(), parents: Frontier::from_sorted(&[1, 2]) },
+        ]);
+
+        assert_eq!(graph.common_ancestor(&[1], &[2]).as_ref(), &[0]);
+        assert_eq!(graph.common_ancestor(&[2], &[1]).as_ref(), &[0]);
+        assert_eq!(graph.common_ancestor(&[1, 2], &[2]).as_ref(), &[2]);
+        assert_eq!(graph.common_ancestor(&[3], &[1]).as_ref(), &[1]);
+        assert_eq!(graph.common_ancestor(&[3], &[3]).as_ref(), &[3]);
+        assert_eq!(graph.common_ancestor(&[3], &[]).as_ref(), &[] as &[LV]);
+        assert_eq!(graph.find_dominators(&[0, 1, 2]).as_ref(), &[1, 2]);
+        assert_eq!(graph.find_dominators(&[1, 2, 3]).as_ref(), &[3]);
+    }
+
+    #[test]
+    fn common_ancestor_smoke_test() {
+        let graph = fancy_graph();
+
+        // Separate roots share nothing.
+        assert_eq!(graph.common_ancestor(&[2], &[5]).as_ref(), &[] as &[LV]);
+        // One version dominates the other.
+        assert_eq!(graph.common_ancestor(&[10], &[4]).as_ref(), &[4]);
+        assert_eq!(graph.common_ancestor(&[4], &[10]).as_ref(), &[4]);
+        assert_eq!(graph.common_ancestor(&[6], &[1]).as_ref(), &[1]);
+        // Concurrent versions which share history from both roots.
+        assert_eq!(graph.common_ancestor(&[8], &[2, 4]).as_ref(), &[1, 4]);
+        assert_eq!(graph.common_ancestor(&[9], &[2, 7]).as_ref(), &[2, 7]);
+        assert_eq!(graph.common_ancestor(&[10], &[2, 5]).as_ref(), &[2, 4]);
+    }
+
     #[test]
     fn diff_common_branch_is_ordered() {
         // Regression
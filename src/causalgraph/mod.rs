@@ -3,13 +3,13 @@
 use crate::{DTRange, Frontier, KVPair, Graph};
 use crate::causalgraph::agent_assignment::AgentAssignment;
 
-pub(crate) mod storage;
 mod causalgraph;
 mod check;
 pub mod graph;
 mod eq;
 pub mod entry;
 pub mod summary;
+pub mod topo;
 pub mod agent_span;
 pub mod agent_assignment;
 
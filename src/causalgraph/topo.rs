@@ -0,0 +1,257 @@
+//! Walking the causal graph in a topological order.
+//!
+//! Local versions are always assigned such that parents come before their children, so simply
+//! iterating from 0..len is a valid topological order. But that order depends on the order in
+//! which each peer happened to receive changes. Code which needs the same order on every peer (or
+//! a different order for other reasons) should use [`CausalGraph::iter_topological`] rather than
+//! rolling its own traversal.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use rle::{AppendRle, HasLength};
+use smallvec::SmallVec;
+use crate::{CausalGraph, DTRange, LV};
+
+/// The order spans are emitted in by [`CausalGraph::iter_topological`]. Every order is a valid
+/// topological sort - that is, each operation is always emitted after all of its parents.
+///
+/// A few traversals don't use this, because they only walk part of the graph:
+///
+/// - The merge code (and the normal encoder) walk the operations between two versions with the
+///   spanning tree walker in `listmerge/txn_trace.rs`. As well as each span, it emits the
+///   retreat / advance steps needed to move the merge tracker between branches, and it picks its
+///   order to keep those steps small.
+/// - Diffs ([`Graph::diff`](crate::causalgraph::graph::Graph::diff), and finding the operations
+///   to copy in [`ListOpLog::add_missing_operations_from`](crate::list::ListOpLog::add_missing_operations_from))
+///   walk backwards from a version and stop at the operations both sides share. The spans they
+///   return are in [`LocalTime`](TopoOrder::LocalTime) order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopoOrder {
+    /// Local version order. This is the order the operations were added to this causal graph, and
+    /// it will often differ between peers.
+    LocalTime,
+
+    /// Whenever there's a choice, emit the operation with the lowest (agent name, seq) pair next.
+    /// This only depends on the operations themselves, so the emitted sequence of operations is
+    /// the same on every peer which has the same set of operations.
+    AgentSeqCanonical,
+
+    /// Follow each branch of concurrent changes as far as it goes before moving on to the next
+    /// one. Merges are emitted after everything they merge. This is mostly useful for
+    /// visualization.
+    DepthFirstMergeLast,
+}
+
+/// The set of pieces whose parents have all been emitted.
+enum Ready<'a> {
+    Heap(BinaryHeap<Reverse<(&'a str, usize, usize)>>),
+    Stack(Vec<usize>),
+}
+
+impl<'a> Ready<'a> {
+    fn push(&mut self, cg: &'a CausalGraph, order: TopoOrder, start: LV, piece: usize) {
+        match self {
+            Ready::Heap(heap) => {
+                let (name, seq) = if order == TopoOrder::LocalTime { ("", 0) } else {
+                    let (agent, seq) = cg.agent_assignment.local_to_agent_version(start);
+                    (cg.agent_assignment.get_agent_name(agent), seq)
+                };
+                heap.push(Reverse((name, seq, piece)));
+            }
+            Ready::Stack(stack) => stack.push(piece),
+        }
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        match self {
+            Ready::Heap(heap) => heap.pop().map(|r| r.0.2),
+            Ready::Stack(stack) => stack.pop(),
+        }
+    }
+}
+
+impl CausalGraph {
+    /// Iterate through all the operations in the causal graph in the requested topological order.
+    /// Adjacent runs of local versions are merged together.
+    pub fn iter_topological(&self, order: TopoOrder) -> impl Iterator<Item = DTRange> {
+        self.topological_order(order).into_iter()
+    }
+
+    fn topological_order(&self, order: TopoOrder) -> Vec<DTRange> {
+        let len = self.len();
+        if len == 0 { return vec![]; }
+
+        // Split history up into pieces such that each piece is a simple run of operations from a
+        // single agent, with parents only attached at the start and children only attached at the
+        // end. The traversal happens in units of whole pieces.
+        let mut splits: Vec<LV> = vec![len];
+        for e in self.graph.entries.iter() {
+            splits.push(e.span.start);
+            splits.extend(e.parents.iter().map(|p| *p + 1));
+        }
+        splits.extend(self.agent_assignment.client_with_localtime.iter().map(|e| e.0));
+        splits.sort_unstable();
+        splits.dedup();
+
+        let num_pieces = splits.len() - 1;
+        let piece_containing = |v: LV| splits.partition_point(|s| *s <= v) - 1;
+
+        let mut children: Vec<SmallVec<[usize; 2]>> = vec![SmallVec::new(); num_pieces];
+        let mut waiting = vec![0; num_pieces];
+        let mut roots = vec![];
+
+        let mut entries = self.graph.entries.iter().peekable();
+        for (i, &start) in splits[..num_pieces].iter().enumerate() {
+            if entries.peek().is_some_and(|e| e.span.start == start) {
+                let e = entries.next().unwrap();
+                if e.parents.is_empty() { roots.push(i); }
+                for p in e.parents.iter() {
+                    children[piece_containing(*p)].push(i);
+                    waiting[i] += 1;
+                }
+            } else {
+                // Pieces in the middle of a graph entry only depend on the piece before.
+                children[i - 1].push(i);
+                waiting[i] = 1;
+            }
+        }
+
+        let mut ready = match order {
+            TopoOrder::LocalTime | TopoOrder::AgentSeqCanonical => Ready::Heap(BinaryHeap::new()),
+            TopoOrder::DepthFirstMergeLast => Ready::Stack(vec![]),
+        };
+        // Roots and children are pushed in reverse so when there's a tie, the stack will emit the
+        // piece with the lowest local version first.
+        for &i in roots.iter().rev() { ready.push(self, order, splits[i], i); }
+
+        let mut result: Vec<DTRange> = vec![];
+        while let Some(i) = ready.pop() {
+            result.push_rle((splits[i]..splits[i + 1]).into());
+
+            for &c in children[i].iter().rev() {
+                waiting[c] -= 1;
+                if waiting[c] == 0 { ready.push(self, order, splits[c], c); }
+            }
+        }
+
+        debug_assert_eq!(result.iter().map(|r| r.len()).sum::<usize>(), len);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{AgentId, CausalGraph, DTRange};
+    use crate::causalgraph::agent_span::AgentSpan;
+    use crate::causalgraph::graph::random_graphs::with_random_cgs;
+    use super::TopoOrder;
+    use super::TopoOrder::*;
+
+    fn check_valid(cg: &CausalGraph, order: TopoOrder) -> Vec<DTRange> {
+        let spans: Vec<DTRange> = cg.iter_topological(order).collect();
+
+        let mut seen = vec![false; cg.len()];
+        for span in spans.iter() {
+            for v in span.iter() {
+                assert!(!seen[v], "{v} emitted twice");
+                cg.graph.with_parents(v, |parents| {
+                    for p in parents {
+                        assert!(seen[*p], "{v} emitted before its parent {p}");
+                    }
+                });
+                seen[v] = true;
+            }
+        }
+        assert!(seen.iter().all(|s| *s));
+        spans
+    }
+
+    fn agent_versions(cg: &CausalGraph, spans: &[DTRange]) -> Vec<(String, usize)> {
+        spans.iter().flat_map(|s| s.iter()).map(|v| {
+            let (agent, seq) = cg.agent_assignment.local_to_agent_version(v);
+            (cg.agent_assignment.get_agent_name(agent).to_string(), seq)
+        }).collect()
+    }
+
+    /// Copy all the operations from cg into a new causal graph, in a different order.
+    fn reorder(cg: &CausalGraph, order: TopoOrder) -> CausalGraph {
+        let mut result = CausalGraph::new();
+        for name in ["c", "b", "a"] { result.get_or_create_agent_id(name); }
+
+        for span in cg.iter_topological(order) {
+            for v in span.iter() {
+                let (agent, seq) = cg.agent_assignment.local_to_agent_version(v);
+                let name = cg.agent_assignment.get_agent_name(agent);
+                let parents = cg.graph.with_parents(v, |p| {
+                    let remote = cg.agent_assignment.local_to_remote_frontier(p);
+                    result.agent_assignment.remote_to_local_frontier(remote.into_iter())
+                });
+
+                let agent = result.get_or_create_agent_id(name);
+                result.merge_and_assign(parents.as_ref(), AgentSpan { agent, seq_range: (seq..seq + 1).into() });
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn empty() {
+        let cg = CausalGraph::new();
+        for order in [LocalTime, AgentSeqCanonical, DepthFirstMergeLast] {
+            assert_eq!(cg.iter_topological(order).count(), 0);
+        }
+    }
+
+    #[test]
+    fn local_time_is_in_order() {
+        with_random_cgs(321, (10, 30), |_, cg, _| {
+            let spans = check_valid(cg, LocalTime);
+            assert_eq!(spans, vec![DTRange::from(0..cg.len())]);
+        });
+    }
+
+    #[test]
+    fn orders_respect_parents() {
+        with_random_cgs(123, (10, 50), |_, cg, _| {
+            for order in [LocalTime, AgentSeqCanonical, DepthFirstMergeLast] {
+                check_valid(cg, order);
+            }
+        });
+    }
+
+    #[test]
+    fn canonical_order_matches_across_peers() {
+        with_random_cgs(55, (10, 30), |_, cg, _| {
+            let expected = agent_versions(cg, &check_valid(cg, AgentSeqCanonical));
+
+            for reorder_by in [AgentSeqCanonical, DepthFirstMergeLast] {
+                let cg2 = reorder(cg, reorder_by);
+                let actual = agent_versions(&cg2, &check_valid(&cg2, AgentSeqCanonical));
+                assert_eq!(expected, actual);
+            }
+        });
+    }
+
+    #[test]
+    fn depth_first_merges_last() {
+        let mut cg = CausalGraph::new();
+        let a: AgentId = cg.get_or_create_agent_id("a");
+        let b: AgentId = cg.get_or_create_agent_id("b");
+
+        // Two branches interleaved in local time, then merged.
+        cg.assign_local_op_with_parents(&[], a, 2); // 0..2
+        cg.assign_local_op_with_parents(&[], b, 2); // 2..4
+        cg.assign_local_op_with_parents(&[1], a, 2); // 4..6
+        cg.assign_local_op_with_parents(&[3], b, 2); // 6..8
+        cg.assign_local_op_with_parents(&[5, 7], a, 1); // 8
+
+        let spans: Vec<DTRange> = check_valid(&cg, DepthFirstMergeLast);
+        assert_eq!(spans, vec![
+            (0..2).into(), (4..6).into(), (2..4).into(), (6..9).into()
+        ]);
+
+        // a's changes have lower (agent, seq) pairs, so the canonical order happens to match.
+        assert_eq!(check_valid(&cg, AgentSeqCanonical), spans);
+        assert_eq!(check_valid(&cg, LocalTime), vec![(0..9).into()]);
+    }
+}
@@ -1,5 +1,5 @@
 use std::mem::size_of;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 use crate::encoding::varint::*;
 
 #[derive(Debug, Clone)]
@@ -13,7 +13,7 @@ impl<'a> BufParser<'a> {
 
     #[inline]
     pub(crate) fn check_has_bytes(&self, num: usize) -> Result<(), ParseError> {
-        if self.0.len() < num { Err(ParseError::UnexpectedEOF) } else { Ok(()) }
+        if self.0.len() < num { Err(ParseErrorKind::UnexpectedEOF.into()) } else { Ok(()) }
     }
 
     pub(crate) fn is_empty(&self) -> bool {
@@ -21,7 +21,7 @@ impl<'a> BufParser<'a> {
     }
 
     pub(crate) fn expect_empty(&self) -> Result<(), ParseError> {
-        if self.is_empty() { Ok(()) } else { Err(ParseError::InvalidLength) }
+        if self.is_empty() { Ok(()) } else { Err(ParseErrorKind::InvalidLength.into()) }
     }
 
     #[allow(unused)]
@@ -36,7 +36,7 @@ impl<'a> BufParser<'a> {
     // pub(crate) fn read_magic(&mut self) -> Result<(), ParseError> {
     //     self.check_has_bytes(8)?;
     //     if self.0[..MAGIC_BYTES.len()] != MAGIC_BYTES {
-    //         return Err(ParseError::InvalidMagic);
+    //         return Err(ParseErrorKind::InvalidMagic.into());
     //     }
     //     self.consume(8);
     //     Ok(())
@@ -57,7 +57,7 @@ impl<'a> BufParser<'a> {
 
     pub(crate) fn next_u32_le(&mut self) -> Result<u32, ParseError> {
         // self.check_has_bytes(size_of::<u32>())?;
-        let val = u32::from_le_bytes(self.0[0..4].try_into().map_err(|_| ParseError::UnexpectedEOF)?);
+        let val = u32::from_le_bytes(self.0[0..4].try_into().map_err(|_| ParseErrorKind::UnexpectedEOF)?);
         self.consume(size_of::<u32>());
         Ok(val)
     }
@@ -83,7 +83,7 @@ impl<'a> BufParser<'a> {
     }
 
     pub(crate) fn next_n_bytes(&mut self, num_bytes: usize) -> Result<&'a [u8], ParseError> {
-        if num_bytes > self.0.len() { return Err(ParseError::UnexpectedEOF); }
+        if num_bytes > self.0.len() { return Err(ParseErrorKind::UnexpectedEOF.into()); }
 
         let (data, remainder) = self.0.split_at(num_bytes);
         self.0 = remainder;
@@ -113,14 +113,14 @@ impl<'a> BufParser<'a> {
 
     // Note the result is attached to the lifetime 'a, not the lifetime of self.
     pub(crate) fn next_str(&mut self) -> Result<&'a str, ParseError> {
-        if self.0.is_empty() { return Err(ParseError::UnexpectedEOF); }
+        if self.0.is_empty() { return Err(ParseErrorKind::UnexpectedEOF.into()); }
 
         let len = self.next_usize()?;
-        if len > self.0.len() { return Err(ParseError::InvalidLength); }
+        if len > self.0.len() { return Err(ParseErrorKind::InvalidLength.into()); }
 
         let bytes = self.next_n_bytes(len)?;
         // std::str::from_utf8(bytes).map_err(InvalidUTF8)
-        std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUTF8)
+        std::str::from_utf8(bytes).map_err(|_| ParseErrorKind::InvalidUTF8.into())
     }
 
     // /// Read the next string thats encoded in this content chunk
@@ -128,12 +128,12 @@ impl<'a> BufParser<'a> {
     //     // dbg!(&self.0);
     //     let data_type = self.next_u32()?;
     //     if data_type != (DataType::PlainText as u32) {
-    //         return Err(ParseError::UnknownChunk);
+    //         return Err(ParseErrorKind::UnknownChunk.into());
     //     }
     //     // let len = self.next_usize()?;
     //     // if len > self.0.len() {
     //     //     return Err(InvalidLength);
     //     // }
-    //     std::str::from_utf8(self.0).map_err(|_| ParseError::InvalidUTF8)
+    //     std::str::from_utf8(self.0).map_err(|_| ParseErrorKind::InvalidUTF8)
     // }
 }
\ No newline at end of file
@@ -9,7 +9,7 @@ use crate::causalgraph::entry::CGEntry;
 use crate::causalgraph::agent_span::AgentSpan;
 use crate::encoding::bufparser::BufParser;
 use crate::encoding::Merger;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 use crate::encoding::map::{WriteMap, ReadMap};
 
 pub(crate) fn write_cg_aa<R: ExtendFromSlice>(result: &mut R, write_parents: bool, span: AgentSpan,
@@ -106,7 +106,7 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, r
     let mapped_agent = n;
 
     let (agent, last_seq, idx) = if !is_known {
-        if mapped_agent != 0 { return Err(ParseError::GenericInvalidData); }
+        if mapped_agent != 0 { return Err(ParseErrorKind::GenericInvalidData.into()); }
         let agent_name = reader.next_str()?;
         let agent = aa.get_or_create_agent_id(agent_name);
         let idx = read_map.agent_map.len();
@@ -126,7 +126,7 @@ fn read_cg_aa(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, r
     } else { 0 };
 
     let start = isize_try_add(last_seq, jump)
-        .ok_or(ParseError::GenericInvalidData)?;
+        .ok_or(ParseErrorKind::GenericInvalidData)?;
     let end = start + len;
 
     if persist {
@@ -154,7 +154,7 @@ fn read_raw(reader: &mut BufParser, persist: bool, aa: &mut AgentAssignment, nex
     let parents = if has_parents {
         read_parents_raw(reader, persist, aa, next_file_time, read_map)?
     } else {
-        let last_time = read_map.last_time().ok_or(ParseError::GenericInvalidData)?;
+        let last_time = read_map.last_time().ok_or(ParseErrorKind::GenericInvalidData)?;
         Frontier::new_1(last_time)
     };
 
@@ -1,6 +1,6 @@
 use crate::encoding::bufparser::BufParser;
 use crate::encoding::ChunkType;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 
 /// A ChunkReader is a wrapper around some bytes which just contain a series of chunks.
 #[derive(Debug, Clone)]
@@ -29,12 +29,12 @@ impl<'a> ChunkReader<'a> {
 
     fn next_chunk_raw(&mut self) -> Result<(ChunkType, BufParser<'a>), ParseError> {
         let chunk_type = ChunkType::try_from(self.0.next_u32()?)
-            .map_err(|_| ParseError::UnknownChunk);
+            .map_err(|_| ParseErrorKind::UnknownChunk);
 
         // This in no way guarantees we're good.
         let len = self.0.next_usize()?;
         if len > self.0.len() {
-            return Err(ParseError::InvalidLength);
+            return Err(ParseErrorKind::InvalidLength.into());
         }
 
         let reader = BufParser(self.0.next_n_bytes(len)?);
@@ -49,7 +49,7 @@ impl<'a> ChunkReader<'a> {
         loop {
             let c = self.next_chunk_raw();
             match c {
-                Err(ParseError::UnknownChunk) => {}, // Keep scanning.
+                Err(e) if e.kind == ParseErrorKind::UnknownChunk => {}, // Keep scanning.
                 _ => { return c; }
             }
         }
@@ -80,7 +80,7 @@ impl<'a> ChunkReader<'a> {
             // dbg!(expect_chunk_type, actual_chunk_type);
             Ok((actual_chunk_type, r))
         } else {
-            Err(ParseError::MissingChunk(err_type as _))
+            Err(ParseErrorKind::MissingChunk(err_type as _).into())
         }
     }
 
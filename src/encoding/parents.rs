@@ -6,7 +6,7 @@ use crate::{Frontier, LV};
 use smallvec::SmallVec;
 use crate::causalgraph::agent_assignment::AgentAssignment;
 use crate::encoding::bufparser::BufParser;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 use crate::encoding::map::{ReadMap, WriteMap};
 use crate::frontier::sort_frontier;
 
@@ -122,7 +122,7 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
             let agent = match n {
                 0 => {
                     // 0 is a dummy item for empty parent lists (ie, ROOT items).
-                    if has_more { return Err(ParseError::GenericInvalidData); }
+                    if has_more { return Err(ParseErrorKind::GenericInvalidData.into()); }
                     break;
                 },
                 1 => {
@@ -143,7 +143,7 @@ pub(crate) fn read_parents_raw(reader: &mut BufParser, persist: bool, aa: &mut A
 
             let seq = reader.next_usize()?;
             aa.try_agent_version_to_lv((agent, seq))
-                .ok_or(ParseError::DataMissing)? // missing expected (agent, seq).
+                .ok_or(ParseErrorKind::DataMissing)? // missing expected (agent, seq).
         };
 
         parents.push(parent);
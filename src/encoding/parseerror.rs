@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use crate::causalgraph::agent_assignment::remote_ids::VersionConversionError;
+use crate::list::limits::LimitExceeded;
+use crate::list::encoding::ListChunkType;
 
 
 // #[derive(Debug)]
@@ -11,9 +13,10 @@ use crate::causalgraph::agent_assignment::remote_ids::VersionConversionError;
 //     InvalidVarInt,
 // }
 
+/// What went wrong while parsing. See [`ParseError`].
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 #[non_exhaustive]
-pub enum ParseError {
+pub enum ParseErrorKind {
     InvalidMagic,
     UnsupportedProtocolVersion,
     DocIdMismatch,
@@ -41,9 +44,39 @@ pub enum ParseError {
     InvalidVarInt,
     InvalidContent,
 
+    /// A history entry names a parent which doesn't come before it. Accepting it would make the
+    /// causal graph cyclic.
+    InvalidParent,
+
+    /// The file lists more agents (or more bytes of agent names) than the limits set in
+    /// DecodeOptions allow.
+    TooManyAgents,
+
+    /// Merging the data would push the document past the limits set with
+    /// [`ListOpLog::set_limits`](crate::list::ListOpLog::set_limits). Nothing was merged.
+    LimitExceeded(LimitExceeded),
+
     GenericInvalidData,
 
-    ChecksumFailed,
+    /// The data doesn't match its checksum. Set
+    /// [`DecodeOptions::ignore_crc`](crate::list::encoding::DecodeOptions::ignore_crc) to load it
+    /// anyway.
+    ChecksumFailed(ChecksumMismatch),
+
+    /// The data is a snapshot written with
+    /// [`EncodeMode::Snapshot`](crate::list::encoding::EncodeMode::Snapshot). Snapshots have no
+    /// history, so they can't be loaded into an oplog. Use
+    /// [`ListBranch::load_snapshot`](crate::list::ListBranch::load_snapshot) instead.
+    SnapshotData,
+
+    /// The file requires a [`Capability`](crate::list::encoding::Capability) (identified by its
+    /// number) which this version of diamond types doesn't support.
+    UnsupportedCapability(u32),
+
+    /// The data contains operations which are concurrent with history collapsed by
+    /// [`ListOpLog::gc_before`](crate::list::ListOpLog::gc_before). They can't be merged, because
+    /// the content they were made against is gone. Nothing was merged.
+    VersionCollected,
 
     /// This error is interesting. We're loading a chunk but missing some of the data. In the future
     /// I'd like to explicitly support this case, and allow the oplog to contain a somewhat- sparse
@@ -51,10 +84,153 @@ pub enum ParseError {
     DataMissing,
 }
 
+impl Display for ParseErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseErrorKind::InvalidMagic => write!(f, "Not a diamond types file (invalid magic bytes)"),
+            ParseErrorKind::UnsupportedProtocolVersion => write!(f, "Unsupported protocol version"),
+            ParseErrorKind::DocIdMismatch => write!(f, "Data is for a different document"),
+            ParseErrorKind::BaseVersionUnknown => write!(f, "Data starts from a version which is not known locally"),
+            ParseErrorKind::UnknownChunk => write!(f, "Unknown chunk type"),
+            ParseErrorKind::LZ4DecoderNeeded => write!(f, "Data is LZ4 compressed, but diamond types was built without the lz4 feature"),
+            ParseErrorKind::LZ4DecompressionError => write!(f, "Could not decompress LZ4 data"),
+            ParseErrorKind::CompressedDataMissing => write!(f, "Data refers to a compressed chunk which is missing"),
+            ParseErrorKind::InvalidChunkHeader => write!(f, "Invalid chunk header"),
+            ParseErrorKind::MissingChunk(chunk_type) => write!(f, "Missing required chunk (type {chunk_type})"),
+            ParseErrorKind::InvalidLength => write!(f, "Invalid length"),
+            ParseErrorKind::UnexpectedEOF => write!(f, "Unexpected end of data"),
+            ParseErrorKind::InvalidUTF8 => write!(f, "Invalid UTF-8 in string"),
+            ParseErrorKind::InvalidRemoteID(_) => write!(f, "Invalid remote ID"),
+            ParseErrorKind::InvalidVarInt => write!(f, "Invalid variable length integer"),
+            ParseErrorKind::InvalidContent => write!(f, "Invalid content"),
+            ParseErrorKind::InvalidParent => write!(f, "History entry names a parent which doesn't come before it"),
+            ParseErrorKind::TooManyAgents => write!(f, "Data names more agents than allowed"),
+            ParseErrorKind::LimitExceeded(_) => write!(f, "Merging the data would exceed the document's limits"),
+            ParseErrorKind::GenericInvalidData => write!(f, "Invalid data"),
+            ParseErrorKind::ChecksumFailed(c) => write!(f, "Checksum mismatch (expected {:#010x}, calculated {:#010x})", c.expected, c.actual),
+            ParseErrorKind::SnapshotData => write!(f, "Data is a snapshot with no history, which can only be loaded as a branch"),
+            ParseErrorKind::UnsupportedCapability(c) => write!(f, "Data requires an unsupported capability ({c})"),
+            ParseErrorKind::VersionCollected => write!(f, "Data contains operations concurrent with the oplog's collapsed history"),
+            ParseErrorKind::DataMissing => write!(f, "Data depends on operations which are not known locally"),
+        }
+    }
+}
+
+impl Error for ParseErrorKind {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseErrorKind::InvalidRemoteID(e) => Some(e),
+            ParseErrorKind::LimitExceeded(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The checksum stored in the data, and the checksum calculated from the bytes it covers.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct ChecksumMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+/// The chunks enclosing the part of a file which failed to parse, outermost first. Eg,
+/// `Patches > OpTypeAndPosition`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct ChunkPath([u32; ChunkPath::MAX_DEPTH]);
+
+impl ChunkPath {
+    /// Chunks are never nested deeper than this. If they were, the innermost chunks are dropped.
+    const MAX_DEPTH: usize = 4;
+
+    /// The path to a chunk inside this one.
+    pub(crate) fn push(mut self, chunk_type: u32) -> Self {
+        // Chunk type 0 isn't used, so it marks the end of the path.
+        if let Some(slot) = self.0.iter_mut().find(|c| **c == 0) {
+            *slot = chunk_type;
+        }
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0[0] == 0
+    }
+
+    /// The type of each chunk in the path, outermost first.
+    pub fn chunk_types(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.iter().copied().take_while(|c| *c != 0)
+    }
+}
+
+impl Display for ChunkPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, chunk_type) in self.chunk_types().enumerate() {
+            if i > 0 { write!(f, " > ")?; }
+            match ListChunkType::try_from(chunk_type) {
+                Ok(c) => write!(f, "{c:?}")?,
+                Err(_) => write!(f, "unknown chunk {chunk_type}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An error from parsing encoded data. This says what went wrong, and (when its known) where in
+/// the data the problem was found.
+///
+/// Errors compare equal to their [`ParseErrorKind`], so `err == ParseErrorKind::InvalidMagic`
+/// works regardless of where the error happened.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+
+    /// The byte offset in the data being parsed where the error was found, if its known. Errors
+    /// found inside compressed data don't have a position, since it doesn't map to any one byte in
+    /// the file.
+    pub pos: Option<usize>,
+
+    /// The chunks containing the error. Empty if the error was outside any chunk (like in the file
+    /// header), or if its not known.
+    pub chunk: ChunkPath,
+}
+
+impl ParseError {
+    /// Fill in the location of the error, unless its already known. Errors are located by the
+    /// innermost reader which notices them.
+    pub(crate) fn or_at(mut self, pos: Option<usize>, chunk: ChunkPath) -> Self {
+        if self.pos.is_none() && self.chunk.is_empty() {
+            self.pos = pos;
+            self.chunk = chunk;
+        }
+        self
+    }
+}
+
+impl From<ParseErrorKind> for ParseError {
+    fn from(kind: ParseErrorKind) -> Self {
+        Self { kind, pos: None, chunk: ChunkPath::default() }
+    }
+}
+
+impl PartialEq<ParseErrorKind> for ParseError {
+    fn eq(&self, other: &ParseErrorKind) -> bool {
+        self.kind == *other
+    }
+}
+
 impl Display for ParseError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ParseError {:?}", self)
+        write!(f, "{}", self.kind)?;
+        match (self.pos, self.chunk.is_empty()) {
+            (Some(pos), true) => write!(f, " (at byte {pos:#x})"),
+            (Some(pos), false) => write!(f, " (at byte {pos:#x} in chunk {})", self.chunk),
+            (None, false) => write!(f, " (in chunk {})", self.chunk),
+            (None, true) => Ok(()),
+        }
     }
 }
 
-impl Error for ParseError {}
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.kind.source()
+    }
+}
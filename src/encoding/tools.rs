@@ -108,10 +108,12 @@ pub(crate) fn push_chunk<V: TryExtendFromSlice>(into: &mut V, chunk_type: ChunkT
     Ok(())
 }
 
+pub(crate) static CRC32C: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISCSI);
+
 pub fn calc_checksum(data: &[u8]) -> u32 {
     // This is crc32c. Using the crc library because the resulting binary size is much smaller.
     // let checksum = crc32c::crc32c(&result);
-    crc::Crc::<u32>::new(&crc::CRC_32_ISCSI).checksum(data)
+    CRC32C.checksum(data)
 }
 
 /// A DTSerializable object knows how to turn itself into a byte array.
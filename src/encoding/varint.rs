@@ -16,7 +16,7 @@
 //! ... And so on.
 
 use std::mem::size_of;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 use crate::encoding::tools::{ExtendFromSlice, TryExtendFromSlice};
 
 // const ENC_1_U64: u64 = 1u64 << 7;
@@ -149,14 +149,14 @@ const ENC_U64_VALS: [u64; 8] = [ENC_1_U64, ENC_2_U64, ENC_3_U64, ENC_4_U64, ENC_
 pub fn decode_prefix_varint_u64(buf: &[u8]) -> Result<(u64, usize), ParseError> {
     // This implementation actually produces more code than the unrolled version below.
     if buf.is_empty() {
-        return Err(ParseError::UnexpectedEOF);
+        return Err(ParseErrorKind::UnexpectedEOF.into());
     }
 
     let b0 = buf[0];
     if b0 <= 0b0111_1111 {
         Ok((b0 as u64, 1))
     } else if b0 <= 0b1011_1111 {
-        if buf.len() < 2 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 2 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let val: u64 = ((b0 as u64 & 0b0011_1111) << 8)
             + buf[1] as u64
             + ENC_1_U64;
@@ -168,7 +168,7 @@ pub fn decode_prefix_varint_u64(buf: &[u8]) -> Result<(u64, usize), ParseError>
         let idx = (b0.leading_ones() as usize - 1) & 0b111;
         let byte_len = idx + 2;
         if buf.len() < byte_len {
-            return Err(ParseError::UnexpectedEOF)
+            return Err(ParseErrorKind::UnexpectedEOF.into())
         }
 
         let mut val: u64 = (b0 & ((1 << (7 - idx)) - 1)) as u64;
@@ -198,7 +198,7 @@ fn decode_prefix_varint_u32_loop(buf: &[u8]) -> Result<(u32, usize), ParseError>
     decode_prefix_varint_u64(buf)
         .and_then(|(val, bytes)| {
             if val > u32::MAX as u64 {
-                Err(ParseError::InvalidVarInt)
+                Err(ParseErrorKind::InvalidVarInt.into())
             } else {
                 Ok((val as u32, bytes))
             }
@@ -209,33 +209,33 @@ fn decode_prefix_varint_u32_unroll(buf: &[u8]) -> Result<(u32, usize), ParseErro
     // println!("{:b} {:#04x} {:#04x} {:#04x} {:#04x} {:#04x}", buf[0], buf[0], buf[1], buf[2], buf[3], buf[4]);
     // assert!(buf.len() >= 5);
     if buf.is_empty() {
-        return Err(ParseError::UnexpectedEOF);
+        return Err(ParseErrorKind::UnexpectedEOF.into());
     }
 
     let b0 = buf[0];
     if b0 <= 0b0111_1111 {
         Ok((b0 as u32, 1))
     } else if b0 <= 0b1011_1111 {
-        if buf.len() < 2 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 2 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let val: u32 = ((b0 as u32 & 0b0011_1111) << 8)
             + buf[1] as u32
             + ENC_1_U32;
         Ok((val, 2))
     } else if b0 <= 0b1101_1111 {
-        if buf.len() < 3 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 3 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let val: u32 = ((b0 as u32 & 0b0001_1111) << 16)
             + ((buf[1] as u32) << 8)
             + buf[2] as u32
             + ENC_2_U32;
         Ok((val, 3))
     } else if b0 <= 0b1110_1111 {
-        if buf.len() < 4 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 4 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let n = unsafe { std::ptr::read_unaligned(&buf[0] as *const u8 as *const u32) };
         let val = u32::from_be(n) - (0b1110_0000 << 24) + ENC_3_U32;
         Ok((val, 4))
     } else {
-        if buf.len() < 5 { return Err(ParseError::UnexpectedEOF); }
-        if b0 != 0b1111_0000 { return Err(ParseError::InvalidVarInt); } // Well, this happens when the data does not fit!
+        if buf.len() < 5 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
+        if b0 != 0b1111_0000 { return Err(ParseErrorKind::InvalidVarInt.into()); } // Well, this happens when the data does not fit!
 
         // Here we're really parsing a u32 big endian value. The optimizer is clever enough to
         // figure that out and optimize this code with a read + byteswap.
@@ -252,33 +252,33 @@ pub fn decode_prefix_varint_u64_unroll(buf: &[u8]) -> Result<(u64, usize), Parse
     // println!("{:b} {:#04x} {:#04x} {:#04x} {:#04x} {:#04x}", buf[0], buf[0], buf[1], buf[2], buf[3], buf[4]);
     // assert!(buf.len() >= 5);
     if buf.is_empty() {
-        return Err(ParseError::UnexpectedEOF);
+        return Err(ParseErrorKind::UnexpectedEOF.into());
     }
 
     let b0 = buf[0];
     if b0 <= 0b0111_1111 {
         Ok((b0 as u64, 1))
     } else if b0 <= 0b1011_1111 {
-        if buf.len() < 2 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 2 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let val: u64 = ((b0 as u64 & 0b0011_1111) << 8)
             + buf[1] as u64
             + ENC_1_U64;
         Ok((val, 2))
     } else if b0 <= 0b1101_1111 {
-        if buf.len() < 3 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 3 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let val: u64 = ((b0 as u64 & 0b0001_1111) << 16)
             + ((buf[1] as u64) << 8)
             + buf[2] as u64
             + ENC_2_U64;
         Ok((val, 3))
     } else if b0 <= 0b1110_1111 {
-        if buf.len() < 4 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 4 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let n = unsafe { std::ptr::read_unaligned(&buf[0] as *const u8 as *const u32) };
         let val = u32::from_be(n) as u64 - (0b1110_0000 << 24)
             + ENC_3_U64;
         Ok((val, 4))
     } else if b0 <= 0b1111_0111 {
-        if buf.len() < 5 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 5 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
 
         // Here we're really parsing a u64 big endian value. The optimizer is clever enough to
         // figure that out and optimize this code with a read + byteswap.
@@ -290,7 +290,7 @@ pub fn decode_prefix_varint_u64_unroll(buf: &[u8]) -> Result<(u64, usize), Parse
             + ENC_4_U64;
         Ok((val, 5))
     } else if b0 <= 0b1111_1011 {
-        if buf.len() < 6 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 6 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
 
         let val: u64 = ((b0 as u64 & 0b0000_0011) << 40)
             + ((buf[1] as u64) << 32)
@@ -301,7 +301,7 @@ pub fn decode_prefix_varint_u64_unroll(buf: &[u8]) -> Result<(u64, usize), Parse
             + ENC_5_U64;
         Ok((val, 6))
     } else if b0 <= 0b1111_1101 {
-        if buf.len() < 7 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 7 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let val: u64 = ((b0 as u64 & 0b0000_0001) << 48)
             + ((buf[1] as u64) << 40)
             + ((buf[2] as u64) << 32)
@@ -312,14 +312,14 @@ pub fn decode_prefix_varint_u64_unroll(buf: &[u8]) -> Result<(u64, usize), Parse
             + ENC_6_U64;
         Ok((val, 7))
     } else if b0 == 0b1111_1110 {
-        if buf.len() < 8 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 8 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let n = unsafe { std::ptr::read_unaligned(&buf[0] as *const u8 as *const u64) };
 
         let val = u64::from_be(n) - (0b1111_1110 << 56)
             + ENC_7_U64;
         Ok((val, 8))
     } else {
-        if buf.len() < 9 { return Err(ParseError::UnexpectedEOF); }
+        if buf.len() < 9 { return Err(ParseErrorKind::UnexpectedEOF.into()); }
         let n = unsafe { std::ptr::read_unaligned(&buf[1] as *const u8 as *const u64) };
 
         let val = u64::from_be(n) + ENC_8_U64;
@@ -0,0 +1,372 @@
+//! A crate-wide error type.
+//!
+//! Functions in diamond types return the most specific error type they can (eg, loading an oplog
+//! returns a [`ParseError`]). Every public error type converts into [`Error`] with `?`, so code
+//! which calls lots of different diamond types methods can use a single error type.
+
+use std::fmt::{Display, Formatter};
+use crate::causalgraph::agent_assignment::remote_ids::VersionConversionError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+use crate::list::EditError;
+use crate::list::gc::GcError;
+use crate::list::limits::LimitExceeded;
+use crate::list::server::SubmitError;
+
+/// Any error returned by diamond types. The wrapped error is used for both the message and the
+/// [`source`](std::error::Error::source) chain.
+///
+/// This works with `?` in functions returning `Box<dyn Error>` (and so `anyhow::Result`), or in
+/// your own error enum:
+///
+/// ```
+/// use std::error::Error as _;
+/// use diamond_types::list::{ListCRDT, ListOpLog};
+///
+/// // Each call returns its own error type. They all convert into diamond_types::Error.
+/// fn append(bytes: &[u8]) -> Result<Vec<u8>, diamond_types::Error> {
+///     let mut doc = ListCRDT::load_from(bytes)?; // ParseError
+///     let seph = doc.get_or_create_agent_id("seph");
+///     doc.branch.try_insert(&mut doc.oplog, seph, 0, "hi ")?; // EditError
+///     Ok(doc.oplog.encode(Default::default()))
+/// }
+///
+/// fn append_boxed(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+///     Ok(append(bytes)?)
+/// }
+///
+/// #[derive(Debug)]
+/// enum AppError {
+///     Document(diamond_types::Error),
+/// }
+///
+/// impl From<diamond_types::Error> for AppError {
+///     fn from(e: diamond_types::Error) -> Self { AppError::Document(e) }
+/// }
+///
+/// impl std::fmt::Display for AppError {
+///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+///         f.write_str("could not edit document")
+///     }
+/// }
+///
+/// impl std::error::Error for AppError {
+///     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+///         match self { AppError::Document(e) => Some(e) }
+///     }
+/// }
+///
+/// fn append_app(bytes: &[u8]) -> Result<Vec<u8>, AppError> {
+///     Ok(append(bytes)?)
+/// }
+///
+/// let saved = append_boxed(&ListOpLog::new().encode(Default::default())).unwrap();
+/// assert!(append_app(&saved).is_ok());
+///
+/// let err = append_app(b"not a dt file").unwrap_err();
+/// assert_eq!(err.to_string(), "could not edit document");
+/// assert_eq!(err.source().unwrap().to_string(), "Not a diamond types file (invalid magic bytes) (at byte 0x0)");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    Parse(ParseError),
+    Edit(EditError),
+    LimitExceeded(LimitExceeded),
+    VersionConversion(VersionConversionError),
+    Gc(GcError),
+    Submit(SubmitError),
+    /// The method isn't implemented yet. Methods which return this say so in their docs.
+    NotSupported,
+}
+
+impl Error {
+    fn inner(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Edit(e) => Some(e),
+            Error::LimitExceeded(e) => Some(e),
+            Error::VersionConversion(e) => Some(e),
+            Error::Gc(e) => Some(e),
+            Error::Submit(e) => Some(e),
+            Error::NotSupported => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.inner() {
+            Some(e) => Display::fmt(e, f),
+            None => write!(f, "Not supported by this version of diamond types"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner()?.source()
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<EditError> for Error {
+    fn from(e: EditError) -> Self {
+        Error::Edit(e)
+    }
+}
+
+impl From<LimitExceeded> for Error {
+    fn from(e: LimitExceeded) -> Self {
+        Error::LimitExceeded(e)
+    }
+}
+
+impl From<VersionConversionError> for Error {
+    fn from(e: VersionConversionError) -> Self {
+        Error::VersionConversion(e)
+    }
+}
+
+impl From<GcError> for Error {
+    fn from(e: GcError) -> Self {
+        Error::Gc(e)
+    }
+}
+
+impl From<SubmitError> for Error {
+    fn from(e: SubmitError) -> Self {
+        Error::Submit(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::error::Error as _;
+    use std::io;
+    use super::*;
+    use crate::encoding::parseerror::ChecksumMismatch;
+
+    // The matches below have no wildcard arms, so adding an error variant without also adding it
+    // here (and giving it a message) won't compile.
+
+    fn all_version_conversion_errors() -> Vec<VersionConversionError> {
+        use VersionConversionError::*;
+        let all = vec![UnknownAgent, SeqInFuture];
+        for e in &all { match e { UnknownAgent | SeqInFuture => {} } }
+        all
+    }
+
+    fn all_limit_errors() -> Vec<LimitExceeded> {
+        use LimitExceeded::*;
+        let all = vec![InsertedBytes, Operations, Agents, PendingData];
+        for e in &all { match e { InsertedBytes | Operations | Agents | PendingData => {} } }
+        all
+    }
+
+    fn all_gc_errors() -> Vec<GcError> {
+        use GcError::*;
+        let all = vec![UnknownVersion, ConcurrentOperations, VersionCollected];
+        for e in &all { match e { UnknownVersion | ConcurrentOperations | VersionCollected => {} } }
+        all
+    }
+
+    fn all_submit_errors() -> Vec<SubmitError> {
+        use SubmitError::*;
+        let all = vec![UnknownVersion(VersionConversionError::SeqInFuture), InvalidOperation, LimitExceeded(super::LimitExceeded::Agents), VersionCollected];
+        for e in &all { match e { UnknownVersion(_) | InvalidOperation | LimitExceeded(_) | VersionCollected => {} } }
+        all
+    }
+
+    fn all_parse_errors() -> Vec<ParseError> {
+        use ParseErrorKind::*;
+        let all = vec![
+            InvalidMagic, UnsupportedProtocolVersion, DocIdMismatch, BaseVersionUnknown,
+            UnknownChunk, LZ4DecoderNeeded, LZ4DecompressionError, CompressedDataMissing,
+            InvalidChunkHeader, MissingChunk(3), InvalidLength, UnexpectedEOF, InvalidUTF8,
+            InvalidRemoteID(VersionConversionError::UnknownAgent), InvalidVarInt, InvalidContent,
+            InvalidParent, TooManyAgents, LimitExceeded(super::LimitExceeded::Agents),
+            GenericInvalidData, ChecksumFailed(ChecksumMismatch { expected: 1, actual: 2 }), SnapshotData,
+            UnsupportedCapability(40), VersionCollected, DataMissing,
+        ];
+        for e in &all {
+            match e {
+                InvalidMagic | UnsupportedProtocolVersion | DocIdMismatch | BaseVersionUnknown
+                | UnknownChunk | LZ4DecoderNeeded | LZ4DecompressionError | CompressedDataMissing
+                | InvalidChunkHeader | MissingChunk(_) | InvalidLength | UnexpectedEOF | InvalidUTF8
+                | InvalidRemoteID(_) | InvalidVarInt | InvalidContent | InvalidParent | TooManyAgents
+                | LimitExceeded(_) | GenericInvalidData | ChecksumFailed(_) | SnapshotData
+                | UnsupportedCapability(_) | VersionCollected | DataMissing => {}
+            }
+        }
+        all.into_iter().map(ParseError::from).collect()
+    }
+
+    fn all_edit_errors() -> Vec<EditError> {
+        use EditError::*;
+        let all = vec![BranchReadOnly, LimitExceeded(super::LimitExceeded::Operations), DeletedContentMissing, UnknownVersion, InvalidBytePosition, InvalidWcharPosition, StalePreview, VersionCollected];
+        for e in &all { match e { BranchReadOnly | LimitExceeded(_) | DeletedContentMissing | UnknownVersion | InvalidBytePosition | InvalidWcharPosition | StalePreview | VersionCollected => {} } }
+        all
+    }
+
+    fn all_errors() -> Vec<Error> {
+        let mut all: Vec<Error> = vec![];
+        all.extend(all_parse_errors().into_iter().map(Error::from));
+        all.extend(all_edit_errors().into_iter().map(Error::from));
+        all.extend(all_limit_errors().into_iter().map(Error::from));
+        all.extend(all_version_conversion_errors().into_iter().map(Error::from));
+        all.extend(all_gc_errors().into_iter().map(Error::from));
+        all.extend(all_submit_errors().into_iter().map(Error::from));
+        all.push(Error::NotSupported);
+        for e in &all {
+            match e {
+                Error::Parse(_) | Error::Edit(_) | Error::LimitExceeded(_) | Error::VersionConversion(_)
+                | Error::Gc(_) | Error::Submit(_) | Error::NotSupported => {}
+            }
+        }
+        all
+    }
+
+    /// Check each error has a unique message, which isn't just the debug output.
+    fn check_messages(errors: Vec<Box<dyn std::error::Error>>) {
+        let mut seen = HashSet::new();
+        for e in errors {
+            let msg = e.to_string();
+            assert!(!msg.is_empty());
+            assert!(!msg.contains("Error"), "{msg:?} looks like debug output");
+            assert!(seen.insert(msg.clone()), "Duplicate error message {msg:?}");
+        }
+    }
+
+    fn boxed<E: std::error::Error + 'static>(errors: Vec<E>) -> Vec<Box<dyn std::error::Error>> {
+        errors.into_iter().map(|e| Box::new(e) as Box<dyn std::error::Error>).collect()
+    }
+
+    #[test]
+    fn every_error_has_a_message() {
+        check_messages(boxed(all_parse_errors()));
+        check_messages(boxed(all_edit_errors()));
+        check_messages(boxed(all_limit_errors()));
+        check_messages(boxed(all_version_conversion_errors()));
+        check_messages(boxed(all_gc_errors()));
+        check_messages(boxed(all_submit_errors()));
+
+        // The crate error is transparent.
+        for e in all_errors() {
+            let Some(inner) = e.inner() else { continue; };
+            assert_eq!(e.to_string(), inner.to_string());
+            assert_eq!(e.source().map(|s| s.to_string()), inner.source().map(|s| s.to_string()));
+        }
+        check_messages(vec![Box::new(Error::NotSupported)]);
+    }
+
+    #[test]
+    fn source_chain() {
+        let e = Error::from(ParseError::from(ParseErrorKind::LimitExceeded(LimitExceeded::Agents)));
+        assert_eq!(e.to_string(), "Merging the data would exceed the document's limits");
+        assert_eq!(e.source().unwrap().to_string(), "Document limit exceeded: number of agents");
+    }
+
+    /// The storage errors only exist with the storage feature.
+    #[cfg(feature = "storage")]
+    mod storage {
+        use std::error::Error as _;
+        use std::io;
+        use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+        use crate::storage::{CodecError, CorruptPageError, SEError};
+        use crate::storage::cg_storage::CGError;
+        use crate::storage::wal::WALError;
+        use crate::causalgraph::agent_assignment::remote_ids::VersionConversionError;
+        use super::{boxed, check_messages};
+
+        fn all_wal_errors() -> Vec<WALError> {
+            use WALError::*;
+            let all = vec![
+                InvalidHeader, UnexpectedEOF, ChecksumMismatch, ParseError(ParseErrorKind::InvalidMagic.into()),
+                IO(io::Error::other("oh no")),
+            ];
+            for e in &all {
+                match e { InvalidHeader | UnexpectedEOF | ChecksumMismatch | ParseError(_) | IO(_) => {} }
+            }
+            all
+        }
+
+        fn all_cg_errors() -> Vec<CGError> {
+            use CGError::*;
+            let all = vec![
+                InvalidHeader, UnexpectedEOF, ChecksumMismatch, InvalidBlit, BlitTooLarge, DecodeFailed,
+                ParseError(ParseErrorKind::InvalidMagic.into()), IO(io::Error::other("oh no")),
+            ];
+            for e in &all {
+                match e {
+                    InvalidHeader | UnexpectedEOF | ChecksumMismatch | InvalidBlit | BlitTooLarge
+                    | DecodeFailed | ParseError(_) | IO(_) => {}
+                }
+            }
+            all
+        }
+
+        fn all_storage_errors() -> Vec<Box<dyn std::error::Error>> {
+            let pages = {
+                use CorruptPageError::*;
+                let all = vec![InvalidHeaderMagicBytes, InvalidChecksum, VersionTooNew(3), InvalidHeaderPageSize(10), PageLengthInvalid(10), DecodeFailed];
+                for e in &all {
+                    match e {
+                        InvalidHeaderMagicBytes | InvalidChecksum | VersionTooNew(_)
+                        | InvalidHeaderPageSize(_) | PageLengthInvalid(_) | DecodeFailed => {}
+                    }
+                }
+                all
+            };
+
+            let se = {
+                use SEError::*;
+                let all = vec![
+                    DataTooLarge, PageFull, UnexpectedPageType, GenericInvalidData, AlreadyLocked,
+                    ReadOnly, NotSupported, PageIsCorrupt(CorruptPageError::InvalidChecksum),
+                    ParseError(ParseErrorKind::InvalidMagic.into()), IO(io::Error::other("oh no")),
+                ];
+                for e in &all {
+                    match e {
+                        DataTooLarge | PageFull | UnexpectedPageType | GenericInvalidData | AlreadyLocked
+                        | ReadOnly | NotSupported | PageIsCorrupt(_) | ParseError(_) | IO(_) => {}
+                    }
+                }
+                all
+            };
+
+            pages.into_iter().map(|e| Box::new(e) as Box<dyn std::error::Error>)
+                .chain(se.into_iter().map(|e| Box::new(e) as Box<dyn std::error::Error>))
+                .chain(std::iter::once(Box::new(CodecError) as Box<dyn std::error::Error>))
+                .collect()
+        }
+
+        #[test]
+        fn every_storage_error_has_a_message() {
+            check_messages(boxed(all_wal_errors()));
+            check_messages(boxed(all_cg_errors()));
+            check_messages(all_storage_errors());
+        }
+
+        #[test]
+        fn cg_error_source_chain() {
+            let e = CGError::from(ParseError::from(ParseErrorKind::InvalidRemoteID(VersionConversionError::SeqInFuture)));
+            let mut chain = vec![];
+            let mut next: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+            while let Some(err) = next {
+                chain.push(err.to_string());
+                next = err.source();
+            }
+            assert_eq!(chain, [
+                "Could not parse causal graph file",
+                "Invalid remote ID",
+                "Sequence number is past the end of the agent's known operations",
+            ]);
+        }
+    }
+}
@@ -267,7 +267,14 @@ impl Frontier {
             assert!(!self.0.contains(&span.start)); // Remove this when branch_contains_version works.
             debug_assert_sorted(self.0.as_slice());
 
-            self.0.retain(|o| !parents.contains(o)); // Usually removes all elements.
+            // Usually removes all elements. Both lists are sorted, so this is linear even when
+            // merging lots of concurrent branches at once.
+            debug_assert_sorted(parents);
+            let mut parents = parents.iter().copied().peekable();
+            self.0.retain(|o| {
+                while parents.next_if(|p| p < o).is_some() {}
+                parents.peek() != Some(o)
+            });
 
             // In order to maintain the order of items in the branch, we want to insert the new item
             // in the appropriate place. This will almost always do self.0.push(), but when changes
@@ -52,7 +52,7 @@
 //! let mut oplog = ListOpLog::new();
 //! // ...
 //! let mut branch = ListBranch::new_at_tip(&oplog);
-//! // Equivalent to let mut branch = Branch::new_at_local_version(&oplog, oplog.get_local_version());
+//! // Equivalent to let mut branch = Branch::new_at_local_version(&oplog, oplog.local_version_ref());
 //! println!("branch content {}", branch.content().to_string());
 //! ```
 //!
@@ -65,7 +65,7 @@
 //! let mut branch = ListBranch::new_at_tip(&oplog);
 //! let george = oplog.get_or_create_agent_id("george");
 //! oplog.add_insert(george, 0, "asdf");
-//! branch.merge(&oplog, oplog.local_frontier_ref());
+//! branch.merge(&oplog, oplog.local_version_ref());
 //! ```
 //!
 //! If you aren't using branches, you can use the simplified [`ListCRDT` API](list::ListCRDT). The
@@ -192,12 +192,12 @@ use smartstring::alias::String as SmartString;
 pub use crate::causalgraph::CausalGraph;
 pub use crate::dtrange::DTRange;
 use causalgraph::graph::Graph;
-use crate::causalgraph::storage::CGStorage;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-use crate::rle::{KVPair, RleVec};
-use crate::wal::WriteAheadLog;
-pub use ::rle::HasLength;
+pub use ::rle::{HasLength, HasRleKey, MergableSpan, SplitableSpan};
+pub use crate::rle::{KVPair, RleVec};
 pub use frontier::Frontier;
+pub use crate::error::Error;
+pub use crate::encoding::parseerror::{ChecksumMismatch, ChunkPath, ParseError, ParseErrorKind};
 use crate::causalgraph::agent_span::AgentVersion;
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
@@ -215,7 +215,7 @@ pub mod frontier;
 mod check;
 mod encoding;
 pub mod causalgraph;
-mod wal;
+mod error;
 
 #[cfg(feature = "serde")]
 pub(crate) mod serde_helpers;
@@ -230,6 +230,8 @@ mod fuzzer;
 mod branch;
 mod textinfo;
 mod oplog;
+// Everything which reads and writes files lives in here, so builds without the storage feature
+// (like wasm) don't need a filesystem.
 #[cfg(feature = "storage")]
 mod storage;
 mod simple_checkout;
@@ -453,3 +455,45 @@ pub enum DTValue {
     // Collection(BTreeMap<LV, Box<DTValue>>),
     Text(String),
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    // Hitting a todo!() in a wasm build or a server takes down the whole tab / process. These are
+    // the only files allowed to have them, and neither can be reached through the public API:
+    // - rle/rle_vec.rs: RleVec::push_reversed_rle. Nothing calls it (RleVec has to stay sorted).
+    // - listmerge2/index_gap_buffer.rs: run_plan, in the unfinished merge rewrite. Also unused.
+    const TODO_ALLOWED: &[&str] = &["rle/rle_vec.rs", "listmerge2/index_gap_buffer.rs"];
+
+    fn scan_for_todos(dir: &Path, src_root: &Path, found: &mut Vec<String>) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                scan_for_todos(&path, src_root, found);
+                continue;
+            }
+            if path.extension().and_then(|ext| ext.to_str()) != Some("rs") { continue; }
+
+            let name = path.strip_prefix(src_root).unwrap().to_string_lossy().replace('\\', "/");
+            if TODO_ALLOWED.contains(&name.as_str()) { continue; }
+
+            for (i, line) in fs::read_to_string(&path).unwrap().lines().enumerate() {
+                let line = line.trim_start();
+                if line.starts_with("//") { continue; }
+                if line.contains(concat!("todo", "!(")) || line.contains(concat!("unimplemented", "!(")) {
+                    found.push(format!("{name}:{}", i + 1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn no_todos_in_public_code() {
+        let src_root = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let mut found = vec![];
+        scan_for_todos(&src_root, &src_root, &mut found);
+        assert!(found.is_empty(), "Unsupported paths should return errors, not panic: {:?}", found);
+    }
+}
@@ -0,0 +1,306 @@
+//! Per-character authorship ("blame") for a version of a document.
+
+use content_tree::{ContentTreeRaw, RawPositionMetricsUsize};
+use rle::{HasLength, MergableSpan};
+use smartstring::alias::String as SmartString;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use crate::dtrange::DTRange;
+use crate::list::{ListBranch, ListOpLog};
+use crate::list::operation::ListOpKind;
+use crate::listmerge::merge::TransformedResult::BaseMoved;
+use crate::{AgentId, LV};
+
+/// The version of the [`ProvenanceReport`] format. This is bumped whenever the serialized form
+/// of the report changes.
+pub const PROVENANCE_SCHEMA_VERSION: u32 = 1;
+
+/// Who wrote each part of a document, in a form which can be archived alongside the text. Made
+/// by [`ListBranch::attribution_report`].
+///
+/// When serialized, this looks like:
+///
+/// ```json
+/// {"schema_version":1,"len":11,"runs":[{"pos":[0,5],"agent":"seph","seq":[0,5]}, ...]}
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProvenanceReport {
+    /// Always [`PROVENANCE_SCHEMA_VERSION`] for reports made by this version of diamond types.
+    pub schema_version: u32,
+    /// The length of the document, in characters.
+    pub len: usize,
+    /// Runs of characters in document order. Together they cover the whole document.
+    pub runs: Vec<ProvenanceRun>,
+}
+
+/// A run of characters in a [`ProvenanceReport`] which were inserted by consecutive operations
+/// from the same agent.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProvenanceRun {
+    /// The range of characters in the document.
+    pub pos: DTRange,
+    /// The name of the agent which inserted the characters.
+    pub agent: SmartString,
+    /// The agent's sequence numbers for the inserts, in the same order as the characters. Together
+    /// with the agent name, these are the remote versions of the inserts.
+    pub seq: DTRange,
+}
+
+impl ListOpLog {
+    /// Figure out who wrote each character in the document at the named version. The result is a
+    /// list of runs of `(char_range, agent)`, in document order, which covers the whole document.
+    /// Adjacent characters written by the same agent are returned as a single run.
+    ///
+    /// Characters are attributed to the agent whose insert produced them, regardless of any edits
+    /// made around them since.
+    ///
+    /// This replays the operations in the version, so it's about as expensive as checking out the
+    /// document.
+    pub fn attribution_at(&self, version: &[LV]) -> Vec<(DTRange, AgentId)> {
+        let inserts = self.char_versions_at(version);
+
+        let mut result: Vec<(DTRange, AgentId)> = vec![];
+        let mut pos = 0;
+        for span in inserts {
+            for agent_span in self.iter_agent_mappings_range(span) {
+                let run: DTRange = (pos..pos + agent_span.len()).into();
+                pos = run.end;
+
+                match result.last_mut() {
+                    Some((last, agent)) if *agent == agent_span.agent && last.can_append(&run) => {
+                        last.append(run);
+                    }
+                    _ => result.push((run, agent_span.agent)),
+                }
+            }
+        }
+
+        result
+    }
+
+    /// The local versions of the insert operations for each character in the document at the
+    /// named version, in document order.
+    pub(crate) fn char_versions_at(&self, version: &[LV]) -> Vec<DTRange> {
+        let mut inserts = ContentTreeRaw::<DTRange, RawPositionMetricsUsize>::new();
+
+        for (lv, op, xf) in self.get_xf_operations_full(&[], version) {
+            let BaseMoved(pos) = xf else { continue; };
+            let len = op.len();
+
+            match op.kind {
+                ListOpKind::Ins if op.loc.fwd => {
+                    inserts.insert_at_offset(pos, (lv..lv + len).into());
+                }
+                ListOpKind::Ins => {
+                    // Reversed inserts put each character before the one inserted previously.
+                    for v in lv..lv + len {
+                        inserts.insert_at_offset(pos, v.into());
+                    }
+                }
+                ListOpKind::Del => {
+                    inserts.delete_at_offset(pos, len);
+                }
+            }
+        }
+
+        inserts.iter().collect()
+    }
+}
+
+impl ListBranch {
+    /// Figure out who wrote each character in the branch's content. See
+    /// [`ListOpLog::attribution_at`].
+    ///
+    /// The oplog must be the oplog this branch was checked out from.
+    pub fn attribution(&self, oplog: &ListOpLog) -> Vec<(DTRange, AgentId)> {
+        let result = oplog.attribution_at(self.version.as_ref());
+        debug_assert_eq!(result.last().map_or(0, |(r, _)| r.end), self.len());
+        result
+    }
+
+    /// Make a report of who wrote each character in the branch's content, with agents named and
+    /// each character's insert identified by its remote version. Unlike
+    /// [`attribution`](ListBranch::attribution), runs are only joined up when the characters were
+    /// inserted by consecutive operations.
+    ///
+    /// The oplog must be the oplog this branch was checked out from.
+    pub fn attribution_report(&self, oplog: &ListOpLog) -> ProvenanceReport {
+        let mut runs: Vec<ProvenanceRun> = vec![];
+        let mut last_agent = None;
+        let mut pos = 0;
+
+        for span in oplog.char_versions_at(self.version.as_ref()) {
+            for agent_span in oplog.iter_agent_mappings_range(span) {
+                let run_pos: DTRange = (pos..pos + agent_span.len()).into();
+                pos = run_pos.end;
+
+                match runs.last_mut() {
+                    Some(last) if last_agent == Some(agent_span.agent) && last.seq.can_append(&agent_span.seq_range) => {
+                        last.pos.append(run_pos);
+                        last.seq.append(agent_span.seq_range);
+                    }
+                    _ => {
+                        runs.push(ProvenanceRun {
+                            pos: run_pos,
+                            agent: oplog.get_agent_name(agent_span.agent).into(),
+                            seq: agent_span.seq_range,
+                        });
+                        last_agent = Some(agent_span.agent);
+                    }
+                }
+            }
+        }
+
+        debug_assert_eq!(pos, self.len());
+        ProvenanceReport { schema_version: PROVENANCE_SCHEMA_VERSION, len: pos, runs }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rle::HasLength;
+    use crate::list::{ListCRDT, ListOpLog};
+    use crate::dtrange::DTRange;
+    use crate::{AgentId, LV};
+    use super::*;
+
+    fn r(start: usize, end: usize, agent: AgentId) -> (DTRange, AgentId) {
+        ((start..end).into(), agent)
+    }
+
+    #[test]
+    fn attribution_survives_deletes() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        assert_eq!(doc.branch.attribution(&doc.oplog), []);
+
+        doc.insert(seph, 0, "hello world");
+        doc.insert(mike, 5, " there");
+        assert_eq!(doc.branch.content(), "hello there world");
+        assert_eq!(doc.branch.attribution(&doc.oplog), [r(0, 5, seph), r(5, 11, mike), r(11, 17, seph)]);
+
+        // Deleting mike's text joins seph's runs back together.
+        doc.delete(seph, 5..11);
+        assert_eq!(doc.branch.attribution(&doc.oplog), [r(0, 11, seph)]);
+
+        // Deleting part of a run doesn't change who wrote the rest.
+        doc.delete(mike, 2..8);
+        assert_eq!(doc.branch.content(), "herld");
+        assert_eq!(doc.branch.attribution(&doc.oplog), [r(0, 5, seph)]);
+    }
+
+    #[test]
+    fn attribution_at_old_versions_and_merges() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let base = oplog.add_insert(seph, 0, "abc");
+        // Concurrent edits at the same position.
+        let a = oplog.add_insert_at(seph, &[base], 1, "XX");
+        let b = oplog.add_insert_at(mike, &[base], 1, "YY");
+        oplog.add_delete_at(mike, &[a, b], 0..1);
+
+        let branch = oplog.checkout_tip();
+        let content = branch.content().to_string();
+        let attribution = branch.attribution(&oplog);
+        assert_eq!(attribution.iter().map(|(r, _)| r.len()).sum::<usize>(), content.chars().count());
+
+        // Each character maps back to the agent which typed it.
+        for (range, agent) in attribution {
+            let expected = if content[range.start..range.end].contains('Y') { mike } else { seph };
+            assert_eq!(agent, expected);
+        }
+
+        assert_eq!(oplog.attribution_at(&[base]), [r(0, 3, seph)]);
+        assert_eq!(oplog.attribution_at(&[b]), [r(0, 1, seph), r(1, 3, mike), r(3, 5, seph)]);
+    }
+
+    #[test]
+    fn backwards_typing_is_attributed() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(mike, 0, "ab");
+        // Each character is typed before the last one.
+        for c in ["z", "y", "x"] {
+            oplog.add_insert(seph, 1, c);
+        }
+
+        assert_eq!(oplog.checkout_tip().content(), "axyzb");
+        assert_eq!(oplog.attribution_at(oplog.local_version_ref()), [r(0, 1, mike), r(1, 4, seph), r(4, 5, mike)]);
+    }
+
+    #[test]
+    fn fixture_attribution_covers_content() {
+        for name in ["benchmark_data/git-makefile.dt", "benchmark_data/node_nodecc.dt"] {
+            let bytes = std::fs::read(name).unwrap();
+            let oplog = ListOpLog::load_from(&bytes).unwrap();
+            let branch = oplog.checkout_tip();
+            let attribution = branch.attribution(&oplog);
+            assert_eq!(attribution.iter().map(|(r, _)| r.len()).sum::<usize>(), branch.len());
+            for pair in attribution.windows(2) {
+                assert_eq!(pair[0].0.end, pair[1].0.start);
+                assert_ne!(pair[0].1, pair[1].1);
+            }
+        }
+    }
+
+    /// A small document with concurrent edits, deletes and backwards typing.
+    fn provenance_fixture() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "hello world");
+        let a = oplog.add_insert_at(mike, &[base], 5, " there");
+        let b = oplog.add_delete_at(seph, &[base], 0..1);
+        let b = oplog.add_insert_at(seph, &[b], 0, "J");
+        oplog.add_insert_at(mike, &[a, b], 17, "!");
+        for c in ["c", "b", "a"] {
+            oplog.add_insert(seph, 0, c);
+        }
+        oplog
+    }
+
+    #[test]
+    fn attribution_report_names_inserts() {
+        let oplog = provenance_fixture();
+        let branch = oplog.checkout_tip();
+        assert_eq!(branch.content(), "abcJello there world!");
+
+        let report = branch.attribution_report(&oplog);
+        assert_eq!(report.schema_version, PROVENANCE_SCHEMA_VERSION);
+        assert_eq!(report.len, branch.len());
+        assert_eq!(report.runs.iter().map(|run| run.pos.len()).sum::<usize>(), branch.len());
+        for pair in report.runs.windows(2) {
+            assert_eq!(pair[0].pos.end, pair[1].pos.start);
+        }
+
+        // Every character's remote version names the insert which made it.
+        let char_versions: Vec<LV> = oplog.char_versions_at(oplog.local_version_ref())
+            .into_iter().flat_map(|r| r.start..r.end).collect();
+        for run in &report.runs {
+            assert_eq!(run.pos.len(), run.seq.len());
+            let agent = oplog.get_agent_id(&run.agent).unwrap();
+            for (i, seq) in (run.seq.start..run.seq.end).enumerate() {
+                let lv = oplog.try_crdt_id_to_time((agent, seq)).unwrap();
+                assert_eq!(char_versions[run.pos.start + i], lv);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature = "serde", feature = "serde_json"))]
+    fn attribution_report_matches_golden_file() {
+        let oplog = provenance_fixture();
+        let report = oplog.checkout_tip().attribution_report(&oplog);
+
+        let json = serde_json::to_string_pretty(&report).unwrap();
+        let expected = std::fs::read_to_string("test_data/provenance_report.json").unwrap();
+        assert_eq!(json.trim(), expected.trim());
+        assert_eq!(serde_json::from_str::<ProvenanceReport>(&json).unwrap(), report);
+    }
+}
@@ -0,0 +1,291 @@
+//! Tools for shrinking an oplog down to the smallest set of operations which still reproduce some
+//! behaviour (like a bug). This is useful for minimizing fuzzer failures and user bug reports.
+
+use rle::HasLength;
+use crate::list::ListOpLog;
+use crate::list::origin::Origin;
+use crate::dtrange::DTRange;
+use crate::rle::{KVPair, RleSpanHelpers, RleVec};
+use crate::causalgraph::agent_span::AgentSpan;
+use crate::{Frontier, LV};
+
+impl ListOpLog {
+    /// Make a new oplog containing exactly the operations in the named version - which is to say,
+    /// the operations in `version` and all of their ancestors. The operations keep their agent IDs
+    /// and sequence numbers (and their checkpoints and timestamps), so the result can still be
+    /// merged with the original oplog. Local versions are renumbered.
+    pub fn truncate_to(&self, version: &[LV]) -> ListOpLog {
+        let mut result = ListOpLog::new();
+        result.doc_id = self.doc_id.clone();
+        result.user_data = self.user_data.clone();
+
+        // Map from versions in self -> versions in result. Versions only ever move down, so this
+        // mapping preserves order.
+        let mut version_map: RleVec<KVPair<DTRange>> = RleVec::new();
+        let map_version = |map: &RleVec<KVPair<DTRange>>, v: LV| -> LV {
+            let (KVPair(_, span), offset) = map.find_with_offset(v)
+                .expect("Parent missing from truncated version");
+            span.start + offset
+        };
+
+        let (_, spans) = self.cg.graph.diff(&[], version);
+        for range in spans {
+            for entry in self.iter_history_range(range) {
+                let start = result.len();
+                let parents = entry.parents.iter()
+                    .map(|p| map_version(&version_map, *p))
+                    .collect::<Frontier>();
+
+                let mut next_time = start;
+                for agent_span in self.iter_agent_mappings_range(entry.span) {
+                    let agent = result.get_or_create_agent_id(self.get_agent_name(agent_span.agent));
+                    result.assign_time_to_crdt_span(next_time, AgentSpan {
+                        agent,
+                        seq_range: agent_span.seq_range,
+                    });
+                    next_time += agent_span.len();
+                }
+
+                // Checkpoints have no operation, so the operations aren't always contiguous.
+                for (KVPair(v, op), content) in self.iter_range_simple(entry.span) {
+                    result.push_op_internal(v - entry.span.start + start, op.loc, op.kind, content);
+                }
+                for (v, message) in self.checkpoints.range(entry.span.start..entry.span.end) {
+                    result.checkpoints.insert(v - entry.span.start + start, message.clone());
+                }
+
+                let new_span: DTRange = (start..start + entry.len()).into();
+                result.cg.graph.push(parents.as_ref(), new_span);
+                result.cg.version.advance_by_known_run(parents.as_ref(), new_span);
+                version_map.push(KVPair(entry.span.start, new_span));
+
+                for remote in self.remote_spans.iter_range(entry.span) {
+                    let new_start = remote.start - entry.span.start + start;
+                    result.set_origin((new_start..new_start + remote.len()).into(), Origin::Remote);
+                }
+                for KVPair(ts_start, run) in self.timestamps.iter_range(entry.span) {
+                    let new_start = ts_start - entry.span.start + start;
+                    result.set_timestamp((new_start..new_start + run.len).into(), run.val);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Find the smallest version of the oplog which satisfies the predicate. The predicate is
+    /// called with truncated copies of the oplog (see [`truncate_to`](ListOpLog::truncate_to)).
+    /// It should return true when the oplog reproduces the behaviour we're looking for (eg,
+    /// `|oplog| oplog.checkout_tip().content().to_string().contains("bug")`).
+    ///
+    /// The search assumes that once a version satisfies the predicate, every later version does
+    /// too. First we binary search for the shortest prefix of the oplog (in local version order)
+    /// which satisfies the predicate. That prefix might contain concurrent branches which aren't
+    /// needed, so then each branch in the resulting version is (greedily) trimmed back as far as
+    /// possible.
+    ///
+    /// Returns None if the predicate isn't satisfied by the whole oplog.
+    pub fn bisect<F: FnMut(&ListOpLog) -> bool>(&self, mut predicate: F) -> Option<Frontier> {
+        if !predicate(self) { return None; }
+
+        let all = [DTRange::from(0..self.len())];
+        let mut version = self.bisect_ops(&[], &all, &mut predicate);
+
+        // Try and trim each concurrent branch in turn. When a branch gets shorter, the branches
+        // in the version might change, so we start again.
+        'outer: while version.len() > 1 {
+            for v in version.iter() {
+                let rest = version.iter().copied()
+                    .filter(|other| other != v)
+                    .collect::<Frontier>();
+                let (branch, _) = self.cg.graph.diff(version.as_ref(), rest.as_ref());
+
+                let trimmed = self.bisect_ops(rest.as_ref(), &branch, &mut predicate);
+                if trimmed != version {
+                    version = trimmed;
+                    continue 'outer;
+                }
+            }
+            break;
+        }
+
+        Some(version)
+    }
+
+    /// Find the smallest n where the predicate is satisfied by `base` plus the first n operations
+    /// in `ops`. The predicate must already be satisfied when all the operations are included.
+    ///
+    /// `ops` must be in ascending order, and the parents of each operation must be in base or
+    /// earlier in ops.
+    fn bisect_ops<F: FnMut(&ListOpLog) -> bool>(&self, base: &[LV], ops: &[DTRange], predicate: &mut F) -> Frontier {
+        let total: usize = ops.iter().map(|r| r.len()).sum();
+
+        // The predicate holds at high, and doesn't hold anywhere below low.
+        let (mut low, mut high) = (0, total);
+        while low < high {
+            let mid = (low + high) / 2;
+            if predicate(&self.truncate_to(self.version_with_ops(base, ops, mid).as_ref())) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        self.version_with_ops(base, ops, high)
+    }
+
+    /// The version containing base and the first n operations in ops.
+    fn version_with_ops(&self, base: &[LV], ops: &[DTRange], mut n: usize) -> Frontier {
+        let mut version = Frontier::from_sorted(base);
+        for range in ops {
+            if n == 0 { break; }
+            let range: DTRange = (range.start..range.start + n.min(range.len())).into();
+            for entry in self.iter_history_range(range) {
+                version.advance_by_known_run(entry.parents.as_ref(), entry.span);
+            }
+            n -= range.len();
+        }
+        version
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rle::HasLength;
+    use crate::list::ListOpLog;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::operation::TextOperation;
+    use crate::list::origin::Origin;
+    use crate::Frontier;
+
+    fn branchy_oplog() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let a = oplog.add_insert_at(seph, &[], 0, "hello");
+        let b = oplog.add_insert_at(mike, &[a], 5, " there");
+        oplog.add_insert_at(seph, &[a], 0, "oh ");
+        oplog.add_delete_at(mike, &[b], 0..2);
+        oplog.add_insert(seph, 0, "...");
+        oplog
+    }
+
+    #[test]
+    fn truncate_to_tip_is_a_copy() {
+        let oplog = branchy_oplog();
+        let copy = oplog.truncate_to(oplog.local_version_ref());
+        assert_eq!(copy, oplog);
+        assert_eq!(copy.local_version(), oplog.local_version());
+        assert_eq!(ListOpLog::load_from(&copy.encode(ENCODE_FULL)).unwrap(), oplog);
+
+        assert_eq!(oplog.truncate_to(&[]), ListOpLog::new());
+    }
+
+    #[test]
+    fn truncate_to_keeps_the_causal_closure() {
+        let oplog = branchy_oplog();
+
+        for v in 0..oplog.len() {
+            let truncated = oplog.truncate_to(&[v]);
+            let expected = oplog.checkout(&[v]);
+
+            // The truncated oplog has the same content, and the same remote IDs.
+            assert_eq!(truncated.checkout_tip().content(), expected.content());
+            assert_eq!(truncated.remote_version(), oplog.cg.agent_assignment.local_to_remote_frontier(&[v]));
+            let closure_len: usize = oplog.cg.graph.diff(&[], &[v]).1.iter().map(|r| r.len()).sum();
+            assert_eq!(truncated.len(), closure_len);
+
+            // And it can be merged back into the original.
+            let mut merged = truncated.clone();
+            merged.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+            assert_eq!(merged, oplog);
+        }
+    }
+
+    #[test]
+    fn truncate_to_keeps_checkpoints() {
+        let mut oplog = branchy_oplog();
+        let seph = oplog.get_agent_id("seph").unwrap();
+        let mike = oplog.get_agent_id("mike").unwrap();
+        // A checkpoint on a branch which gets dropped, and one which everything later is based on.
+        let tip = oplog.local_version();
+        let dropped = oplog.add_checkpoint(mike, &[0], "dropped");
+        let kept = oplog.add_checkpoint(seph, tip.as_ref(), "kept");
+        oplog.add_insert_at(seph, &[kept], 0, "more ");
+        oplog.dbg_check(true);
+
+        let messages = |o: &ListOpLog, v: &[usize]| o.iter_checkpoints()
+            .filter(|c| o.cg.graph.frontier_contains_version(v, c.version))
+            .map(|c| (c.id.0.to_string(), c.id.1, c.message.to_string()))
+            .collect::<Vec<_>>();
+
+        for v in 0..oplog.len() {
+            let truncated = oplog.truncate_to(&[v]);
+            truncated.dbg_check(true);
+            assert_eq!(truncated.checkout_tip().content(), oplog.checkout(&[v]).content());
+            assert_eq!(messages(&truncated, truncated.local_version_ref()), messages(&oplog, &[v]));
+
+            let mut merged = truncated.clone();
+            merged.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+            assert_eq!(merged, oplog);
+        }
+
+        let truncated = oplog.truncate_to(&[oplog.len() - 1]);
+        assert_eq!(truncated.len(), oplog.len() - 1);
+        assert!(truncated.iter_checkpoints().all(|c| c.message != "dropped"));
+        assert_eq!(oplog.checkpoint_message(dropped), Some("dropped"));
+
+        // Bisecting skips over checkpoints like any other change.
+        let has_more = |o: &ListOpLog| o.checkout_tip().content().to_string().contains("more");
+        assert_eq!(oplog.bisect(has_more), Some(Frontier::new_1(oplog.len() - 2)));
+    }
+
+    #[test]
+    fn truncate_to_keeps_origins() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "aaa");
+        // Merged in from a remote peer, concurrent with seph's insert. Truncating to it moves it
+        // down to version 0.
+        let b = oplog.add_operations_at_with_origin(mike, &[], &[TextOperation::new_insert(0, "bb")], Origin::Remote);
+        oplog.add_insert_at(seph, &[b], 0, "c");
+
+        let truncated = oplog.truncate_to(&[b]);
+        assert_eq!(truncated.checkout_tip().content(), "bb");
+        assert_eq!(truncated.iter_origins().collect::<Vec<_>>(), [((0..2).into(), Origin::Remote)]);
+
+        let truncated = oplog.truncate_to(&[b + 1]);
+        assert_eq!(truncated.iter_origins().collect::<Vec<_>>(), [
+            ((0..2).into(), Origin::Remote),
+            ((2..3).into(), Origin::Local),
+        ]);
+    }
+
+    #[test]
+    fn bisect_finds_smallest_version() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let base = oplog.add_insert(seph, 0, "hi ");
+        // Two concurrent branches. The marker is inserted in the middle of mike's branch.
+        let mut s = oplog.add_insert_at(seph, &[base], 3, "there");
+        let mut m = oplog.add_insert_at(mike, &[base], 0, "yo ");
+        s = oplog.add_insert_at(seph, &[s], 0, "well ");
+        let marker = oplog.add_insert_at(mike, &[m], 1, "MARKER");
+        m = oplog.add_insert_at(mike, &[marker], 0, "...");
+        oplog.add_insert_at(seph, &[s, m], 0, "!");
+
+        let has_marker = |o: &ListOpLog| o.checkout_tip().content().to_string().contains("MARKER");
+        assert_eq!(oplog.bisect(has_marker), Some(Frontier::new_1(marker)));
+
+        // Marker characters after the first aren't needed to make "MARK".
+        let has_mark = |o: &ListOpLog| o.checkout_tip().content().to_string().contains("MARK");
+        assert_eq!(oplog.bisect(has_mark), Some(Frontier::new_1(marker - 2)));
+
+        assert_eq!(oplog.bisect(|o| o.checkout_tip().content().to_string().contains("nope")), None);
+        assert_eq!(oplog.bisect(|_| true), Some(Frontier::root()));
+    }
+}
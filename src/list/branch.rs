@@ -1,6 +1,9 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::ops::Range;
 use jumprope::{JumpRope, JumpRopeBuf};
-use crate::list::{ListBranch, ListOpLog};
+use crate::list::{EditError, ListBranch, ListOpLog};
+use crate::list::limits::LimitExceeded;
 use smartstring::SmartString;
 use crate::list::list::{apply_local_operations};
 use crate::list::operation::ListOpKind::*;
@@ -8,6 +11,7 @@ use crate::list::operation::{TextOperation, ListOpKind};
 use crate::dtrange::DTRange;
 use crate::{AgentId, Frontier, LV};
 use crate::causalgraph::agent_assignment::remote_ids::RemoteFrontier;
+use crate::unicount::bytes_to_chars;
 
 impl ListBranch {
     /// Create a new (empty) branch at the start of history. The branch will be an empty list.
@@ -15,11 +19,14 @@ impl ListBranch {
         Self {
             version: Frontier::root(),
             content: JumpRopeBuf::new(),
+            read_only: false,
+            undo: Default::default(),
         }
     }
 
     /// Create a new branch as a checkout from the specified oplog, at the specified local time.
-    /// This method equivalent to calling [`oplog.checkout(version)`](OpLog::checkout).
+    /// This method equivalent to calling [`oplog.checkout(version)`](OpLog::checkout). Like
+    /// checkout, the branch is read-only unless the version is the oplog's tip.
     pub fn new_at_local_version(oplog: &ListOpLog, version: &[LV]) -> Self {
         oplog.checkout(version)
     }
@@ -35,16 +42,42 @@ impl ListBranch {
     ///
     /// This is provided because its slightly faster than calling local_version (since it prevents a
     /// clone(), and they're weirdly expensive with smallvec!)
-    pub fn local_frontier_ref(&self) -> &[LV] { self.version.as_ref() }
+    pub fn local_version_ref(&self) -> &[LV] { self.version.as_ref() }
 
     /// Return the current version of the branch
-    pub fn local_frontier(&self) -> Frontier { self.version.clone() }
+    pub fn local_version(&self) -> Frontier { self.version.clone() }
 
     /// Return the current version of the branch in remote form
-    pub fn remote_frontier<'a>(&self, oplog: &'a ListOpLog) -> RemoteFrontier<'a> {
+    pub fn remote_version<'a>(&self, oplog: &'a ListOpLog) -> RemoteFrontier<'a> {
         oplog.cg.agent_assignment.local_to_remote_frontier(self.version.as_ref())
     }
 
+    #[deprecated(since = "1.1.0", note = "Renamed to local_version_ref")]
+    pub fn local_frontier_ref(&self) -> &[LV] { self.local_version_ref() }
+
+    #[deprecated(since = "1.1.0", note = "Renamed to local_version")]
+    pub fn local_frontier(&self) -> Frontier { self.local_version() }
+
+    #[deprecated(since = "1.1.0", note = "Renamed to remote_version")]
+    pub fn remote_frontier<'a>(&self, oplog: &'a ListOpLog) -> RemoteFrontier<'a> {
+        self.remote_version(oplog)
+    }
+
+    /// Returns true if local edits to this branch are refused, because it was checked out at a
+    /// historical version. See [`fork_editable`](ListBranch::fork_editable).
+    pub fn is_read_only(&self) -> bool { self.read_only }
+
+    /// Make a read-only branch editable.
+    ///
+    /// Local edits are added to the oplog with the branch's version as their parents. When the
+    /// branch is behind the oplog's tip, that makes them concurrent with all the later changes,
+    /// and they'll be merged with them like any other concurrent edits. Only do this if that's
+    /// what you want.
+    pub fn fork_editable(mut self) -> Self {
+        self.read_only = false;
+        self
+    }
+
     /// Return the current document contents. Note there is no mutable variant of this method
     /// because mutating the document's content directly would violate the constraint that all
     /// changes must bump the document's version.
@@ -62,6 +95,30 @@ impl ListBranch {
         self.content.is_empty()
     }
 
+    /// Returns true if both branches contain the same text, regardless of how they got there. The
+    /// two branches can come from different oplogs.
+    ///
+    /// This is faster than comparing `content().to_string()`, since it compares the ropes chunk by
+    /// chunk without allocating.
+    pub fn content_eq(&self, other: &ListBranch) -> bool {
+        self.len() == other.len() && self.content == other.content
+    }
+
+    /// Returns true if this branch (from `oplog`) and `other` (from `other_oplog`) are at the same
+    /// version.
+    ///
+    /// Different oplogs can assign local versions to the same operations in a different order, so
+    /// the frontiers are compared by remote (agent, seq) ID.
+    pub fn version_eq(&self, oplog: &ListOpLog, other: &ListBranch, other_oplog: &ListOpLog) -> bool {
+        if self.version.len() != other.version.len() { return false; }
+
+        let mut a = self.remote_version(oplog);
+        let mut b = other.remote_version(other_oplog);
+        a.sort_unstable_by_key(|rv| (rv.0, rv.1));
+        b.sort_unstable_by_key(|rv| (rv.0, rv.1));
+        a == b
+    }
+
     /// Apply a single operation. This method does not update the version.
     fn apply_internal(&mut self, kind: ListOpKind, pos: DTRange, content: Option<&str>) {
         match kind {
@@ -135,12 +192,113 @@ impl ListBranch {
         apply_local_operations(oplog, self, agent, &[self.make_delete_op(start_pos .. end_pos)])
     }
 
+    /// Convert a byte offset in the document's (UTF-8) content into a character offset. Returns
+    /// [`EditError::InvalidBytePosition`] if the offset is past the end of the document or in the
+    /// middle of a character.
+    pub fn byte_pos_to_chars(&self, byte_pos: usize) -> Result<usize, EditError> {
+        let content = self.content.borrow();
+        let len_bytes = content.len_bytes();
+        if byte_pos > len_bytes { return Err(EditError::InvalidBytePosition); }
+
+        // The common cases don't need to look at the content at all: documents which are all
+        // ASCII, and edits at the end of the document.
+        let len_chars = content.len_chars();
+        if len_bytes == len_chars { return Ok(byte_pos); }
+        if byte_pos == len_bytes { return Ok(len_chars); }
+
+        // The rope only indexes characters, not bytes. But it stores the character count of each
+        // node, so only the node containing byte_pos needs to be scanned.
+        let mut bytes = 0;
+        let mut chars = 0;
+        for (s, s_chars) in content.substrings_with_len() {
+            if byte_pos < bytes + s.len() {
+                let offset = byte_pos - bytes;
+                if !s.is_char_boundary(offset) { return Err(EditError::InvalidBytePosition); }
+                return Ok(chars + bytes_to_chars(s, offset));
+            }
+            bytes += s.len();
+            chars += s_chars;
+        }
+        unreachable!("byte_pos is inside the document");
+    }
+
+    /// Like [`try_insert`](ListBranch::try_insert), but the position is a byte offset into the
+    /// document's content. This is handy for editors which track byte offsets. Inserting in the
+    /// middle of a character is an error.
+    pub fn insert_bytes(&mut self, oplog: &mut ListOpLog, agent: AgentId, byte_pos: usize, ins_content: &str) -> Result<LV, EditError> {
+        let pos = self.byte_pos_to_chars(byte_pos)?;
+        self.try_insert(oplog, agent, pos, ins_content)
+    }
+
+    /// Like [`try_delete`](ListBranch::try_delete), but the range is in bytes. Both ends of the
+    /// range must be on character boundaries.
+    pub fn delete_bytes(&mut self, oplog: &mut ListOpLog, agent: AgentId, del_span_bytes: Range<usize>) -> Result<LV, EditError> {
+        if del_span_bytes.start > del_span_bytes.end { return Err(EditError::InvalidBytePosition); }
+        let start = self.byte_pos_to_chars(del_span_bytes.start)?;
+        let end = self.byte_pos_to_chars(del_span_bytes.end)?;
+        self.try_delete(oplog, agent, start..end)
+    }
+
+    /// Convert a UTF-16 offset (as used by Javascript, Java and C#) in the document's content into
+    /// a character offset. Returns [`EditError::InvalidWcharPosition`] if the offset is past the
+    /// end of the document or between the two halves of a surrogate pair.
+    ///
+    /// The document can't contain unpaired surrogates (its content is a Rust string), so a valid
+    /// offset always names a position between two characters.
+    #[cfg(feature = "wchar_conversion")]
+    pub fn wchar_pos_to_chars(&self, wchar_pos: usize) -> Result<usize, EditError> {
+        let content = self.content.borrow();
+        if wchar_pos > content.len_wchars() { return Err(EditError::InvalidWcharPosition); }
+        let chars = content.wchars_to_chars(wchar_pos);
+        // Offsets inside a surrogate pair round to a neighbouring character.
+        if content.chars_to_wchars(chars) != wchar_pos { return Err(EditError::InvalidWcharPosition); }
+        Ok(chars)
+    }
+
     /// Consume the Branch and return the contained rope content.
     pub fn into_inner(self) -> JumpRope {
         self.content.into_inner()
     }
 }
 
+/// Used by the infallible methods for editing a branch, which panic if the branch is read-only.
+pub(crate) fn assert_editable(branch: &ListBranch) {
+    if branch.read_only {
+        panic!("Cannot edit a read-only branch. Call fork_editable() to edit historical versions");
+    }
+}
+
+impl PartialEq for ListBranch {
+    fn eq(&self, other: &Self) -> bool {
+        self.version == other.version && self.content == other.content
+    }
+}
+
+impl Eq for ListBranch {}
+
+impl Display for EditError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EditError::BranchReadOnly => write!(f, "branch is read-only"),
+            EditError::LimitExceeded(e) => Display::fmt(e, f),
+            EditError::DeletedContentMissing => write!(f, "deleted content needed for undo is not stored in the oplog"),
+            EditError::UnknownVersion => write!(f, "version is not in the oplog"),
+            EditError::InvalidBytePosition => write!(f, "byte position is past the end of the document or inside a character"),
+            EditError::InvalidWcharPosition => write!(f, "UTF-16 position is past the end of the document or inside a surrogate pair"),
+            EditError::StalePreview => write!(f, "branch has changed since the merge preview was made"),
+            EditError::VersionCollected => write!(f, "version is from before the oplog's history was collapsed"),
+        }
+    }
+}
+
+impl Error for EditError {}
+
+impl From<LimitExceeded> for EditError {
+    fn from(e: LimitExceeded) -> Self {
+        EditError::LimitExceeded(e)
+    }
+}
+
 impl Default for ListBranch {
     fn default() -> Self {
         Self::new()
@@ -161,6 +319,10 @@ impl From<ListBranch> for String {
 
 #[cfg(test)]
 mod test {
+    use rand::prelude::*;
+    use crate::list::ListCRDT;
+    use crate::list::encoding::{EncodeOptions, ENCODE_FULL};
+    use crate::list::old_fuzzer_tools::old_make_random_change;
     use super::*;
 
     #[test]
@@ -186,9 +348,346 @@ mod test {
         let mut branch1 = oplog.checkout(&[]);
         branch1.insert(&mut oplog, 0, 0, "aaa");
 
-        let mut branch2 = oplog.checkout(&[]);
+        let mut branch2 = oplog.checkout(&[]).fork_editable();
         branch2.insert(&mut oplog, 0, 0, "bbb");
 
         oplog.dbg_check(true);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn content_eq_ignores_history() {
+        let mut oplog_a = ListOpLog::new();
+        let seph = oplog_a.get_or_create_agent_id("seph");
+        let mut a = oplog_a.checkout_tip();
+        a.insert(&mut oplog_a, seph, 0, "hi");
+
+        let mut oplog_b = ListOpLog::new();
+        let mike = oplog_b.get_or_create_agent_id("mike");
+        let mut b = oplog_b.checkout_tip();
+        b.insert(&mut oplog_b, mike, 0, "hx");
+        b.delete(&mut oplog_b, mike, 1..2);
+        b.insert(&mut oplog_b, mike, 1, "i");
+
+        assert!(a.content_eq(&b));
+        assert!(!a.version_eq(&oplog_a, &b, &oplog_b));
+
+        b.insert(&mut oplog_b, mike, 2, "!");
+        assert!(!a.content_eq(&b));
+        assert!(a.content_eq(&a));
+    }
+
+    #[test]
+    fn version_eq_across_oplogs() {
+        // Two peers make concurrent changes, then sync. Each oplog assigns its own changes the
+        // earlier local versions, so the local frontiers differ.
+        let mut oplog_a = ListOpLog::new();
+        let seph = oplog_a.get_or_create_agent_id("seph");
+        oplog_a.add_insert(seph, 0, "aaa");
+
+        let mut oplog_b = ListOpLog::new();
+        let mike = oplog_b.get_or_create_agent_id("mike");
+        oplog_b.add_insert(mike, 0, "bb");
+
+        let a_before = oplog_a.checkout_tip();
+        let b_before = oplog_b.checkout_tip();
+        assert!(!a_before.version_eq(&oplog_a, &b_before, &oplog_b));
+        assert!(!a_before.content_eq(&b_before));
+
+        oplog_a.add_missing_operations_from(&oplog_b);
+        oplog_b.add_missing_operations_from(&oplog_a);
+
+        let a = oplog_a.checkout_tip();
+        let b = oplog_b.checkout_tip();
+        assert_ne!(a.local_version_ref(), b.local_version_ref());
+        assert!(a.version_eq(&oplog_a, &b, &oplog_b));
+        assert!(b.version_eq(&oplog_b, &a, &oplog_a));
+        assert!(a.content_eq(&b));
+    }
+
+    #[test]
+    fn historical_checkouts_are_read_only() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v1 = oplog.add_insert(seph, 0, "hi");
+        let v2 = oplog.add_insert(seph, 2, " there");
+
+        assert!(!ListBranch::new_at_tip(&oplog).is_read_only());
+        assert!(!oplog.checkout(&[v2]).is_read_only());
+
+        let mut branch = oplog.checkout(&[v1]);
+        assert!(branch.is_read_only());
+        assert_eq!(branch, ListBranch::new_at_local_version(&oplog, &[v1]));
+
+        let before = oplog.clone();
+        assert_eq!(branch.try_insert(&mut oplog, seph, 0, "x"), Err(EditError::BranchReadOnly));
+        assert_eq!(branch.try_delete(&mut oplog, seph, 0..1), Err(EditError::BranchReadOnly));
+        assert_eq!(oplog, before);
+        assert_eq!(branch.content(), "hi");
+
+        // Catching up to the tip makes the branch editable again.
+        branch.merge(&oplog, &[v2]);
+        assert!(!branch.is_read_only());
+        branch.insert(&mut oplog, seph, 0, "oh ");
+        assert_eq!(branch.content(), "oh hi there");
+    }
+
+    #[test]
+    #[should_panic]
+    fn insert_into_read_only_branch_panics() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+
+        let mut branch = oplog.checkout(&[]);
+        branch.insert(&mut oplog, seph, 0, "x");
+    }
+
+    #[test]
+    fn forked_branch_merges_like_concurrent_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v1 = oplog.add_insert(seph, 0, "abc");
+        oplog.add_delete_without_content(seph, 0..1);
+        let mut expected = oplog.clone();
+
+        let mut branch = oplog.checkout(&[v1]).fork_editable();
+        assert!(!branch.is_read_only());
+        branch.insert(&mut oplog, seph, 3, "d");
+        branch.delete_without_content(&mut oplog, seph, 1..2);
+        assert_eq!(branch.content(), "acd");
+
+        // The same changes added to the oplog directly.
+        let v = expected.add_insert_at(seph, &[v1], 3, "d");
+        expected.add_delete_at(seph, &[v], 1..2);
+        assert_eq!(oplog, expected);
+
+        branch.merge(&oplog, oplog.local_version_ref());
+        assert_eq!(branch, expected.checkout_tip());
+        assert_eq!(branch.content(), "cd");
+    }
+
+    #[test]
+    fn merge_after_merge_data_adds_agents() {
+        let mut rng = SmallRng::seed_from_u64(321);
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        // Checked out before any of the other agents existed, and only merged occasionally.
+        let mut lagging = doc.oplog.checkout_tip();
+        let mut peers: Vec<ListCRDT> = (0..3).map(|_| ListCRDT::new()).collect();
+
+        for i in 0..300 {
+            // Each change comes from a brand new agent, on a peer which may be behind.
+            let peer = &mut peers[i % 3];
+            let agent = peer.get_or_create_agent_id(&format!("agent {i}"));
+            old_make_random_change(peer, None, agent, &mut rng);
+
+            let stats = doc.oplog.merge_data(&peer.oplog.encode(ENCODE_FULL)).unwrap();
+            assert_eq!(stats.new_agents, 1);
+            doc.branch.merge(&doc.oplog, doc.oplog.local_version_ref());
+            assert!(doc.branch.content_eq(&doc.oplog.checkout_tip()));
+
+            if i % 4 == 0 {
+                old_make_random_change(&mut doc, None, seph, &mut rng);
+            }
+            if i % 7 == 0 {
+                lagging.try_merge(&doc.oplog, doc.oplog.local_version_ref()).unwrap();
+                assert!(lagging.content_eq(&doc.branch));
+            }
+            if i % 10 == 0 {
+                let data = doc.oplog.encode(ENCODE_FULL);
+                peer.merge_data_and_ff(&data).unwrap();
+            }
+        }
+
+        lagging.try_merge(&doc.oplog, doc.oplog.local_version_ref()).unwrap();
+        assert_eq!(lagging, doc.oplog.checkout_tip());
+        doc.oplog.dbg_check(true);
+    }
+
+    #[test]
+    fn try_merge_rejects_versions_from_other_oplogs() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+        let branch = oplog.checkout_tip();
+
+        let mut other = ListOpLog::new();
+        let mike = other.get_or_create_agent_id("mike");
+        let v = other.add_insert(mike, 0, "x");
+
+        let mut b = branch.clone();
+        assert_eq!(b.try_merge(&other, &[v]), Err(EditError::UnknownVersion));
+        assert_eq!(b, branch);
+
+        let mut empty = ListBranch::new();
+        assert_eq!(empty.try_merge(&other, &[v + 5]), Err(EditError::UnknownVersion));
+        empty.try_merge(&other, &[v]).unwrap();
+        assert_eq!(empty.content(), "x");
+    }
+
+    #[test]
+    fn advance_to_matches_checkout() {
+        let mut rng = SmallRng::seed_from_u64(77);
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mut peers: Vec<ListCRDT> = (0..3).map(|_| ListCRDT::new()).collect();
+        for (i, peer) in peers.iter_mut().enumerate() {
+            peer.get_or_create_agent_id(&format!("peer {i}"));
+        }
+
+        // Build up some concurrent history. Reverting deletes needs their content.
+        const OPTS: EncodeOptions = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+        for i in 0..60 {
+            let peer = &mut peers[i % 3];
+            old_make_random_change(peer, None, 0, &mut rng);
+            doc.oplog.merge_data(&peer.oplog.encode(OPTS)).unwrap();
+            if i % 5 == 0 {
+                old_make_random_change(&mut doc, None, seph, &mut rng);
+                peer.merge_data_and_ff(&doc.oplog.encode(OPTS)).unwrap();
+            }
+        }
+
+        let oplog = &doc.oplog;
+        let len = oplog.len();
+        let mut branch = oplog.checkout(&[len / 2]);
+        for _ in 0..100 {
+            let mut version = [rng.gen_range(0..len), rng.gen_range(0..len)];
+            version.sort_unstable();
+            let version = oplog.find_dominators(&version);
+
+            branch.advance_to(oplog, version.as_ref());
+            assert_eq!(branch, oplog.checkout(version.as_ref()));
+        }
+
+        branch.advance_to(oplog, oplog.local_version_ref());
+        assert_eq!(branch, oplog.checkout_tip());
+        assert!(!branch.is_read_only());
+        branch.advance_to(oplog, &[]);
+        assert_eq!(branch.content(), "");
+        assert!(branch.is_read_only());
+    }
+
+    #[test]
+    fn advance_to_without_deleted_content() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let a = oplog.add_insert(seph, 0, "hi there");
+        let b = oplog.add_delete_without_content(seph, 2..8);
+        oplog.add_insert(seph, 2, "!");
+
+        let mut branch = oplog.checkout_tip();
+        branch.advance_to(&oplog, &[a]);
+        assert_eq!(branch.content(), "hi there");
+        branch.advance_to(&oplog, &[b]);
+        assert_eq!(branch.content(), "hi");
+        assert_eq!(branch.local_version_ref(), &[b]);
+    }
+
+    #[test]
+    fn edit_at_byte_offsets() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        branch.insert_bytes(&mut oplog, seph, 0, "héllo ツ!").unwrap();
+
+        // 'é' is 2 bytes and 'ツ' is 3.
+        assert_eq!(branch.byte_pos_to_chars(3), Ok(2));
+        assert_eq!(branch.byte_pos_to_chars(11), Ok(8));
+        branch.insert_bytes(&mut oplog, seph, 10, "?").unwrap();
+        assert_eq!(branch.content(), "héllo ツ?!");
+        branch.delete_bytes(&mut oplog, seph, 7..10).unwrap();
+        assert_eq!(branch.content(), "héllo ?!");
+
+        // Positions inside a character or past the end change nothing.
+        let (before_oplog, before_branch) = (oplog.clone(), branch.clone());
+        assert_eq!(branch.insert_bytes(&mut oplog, seph, 2, "x"), Err(EditError::InvalidBytePosition));
+        assert_eq!(branch.delete_bytes(&mut oplog, seph, 0..2), Err(EditError::InvalidBytePosition));
+        assert_eq!(branch.delete_bytes(&mut oplog, seph, 5..100), Err(EditError::InvalidBytePosition));
+        assert_eq!(branch.delete_bytes(&mut oplog, seph, Range { start: 3, end: 1 }), Err(EditError::InvalidBytePosition));
+        assert_eq!(oplog, before_oplog);
+        assert_eq!(branch, before_branch);
+
+        // Big documents are split across lots of rope nodes.
+        let text = "ツé".repeat(2000);
+        branch.insert_bytes(&mut oplog, seph, 0, &text).unwrap();
+        assert_eq!(branch.byte_pos_to_chars(5 * 1234), Ok(2 * 1234));
+        assert_eq!(branch.byte_pos_to_chars(5 * 1234 + 1), Err(EditError::InvalidBytePosition));
+        let content = branch.content().to_string();
+        for (chars, (bytes, _)) in content.char_indices().enumerate() {
+            assert_eq!(branch.byte_pos_to_chars(bytes), Ok(chars));
+        }
+        branch.delete_bytes(&mut oplog, seph, 0..text.len()).unwrap();
+        assert_eq!(branch.content(), "héllo ?!");
+
+        // ASCII documents are converted without looking at the content.
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut ascii = oplog.checkout_tip();
+        ascii.insert_bytes(&mut oplog, seph, 0, "hello").unwrap();
+        assert_eq!(ascii.byte_pos_to_chars(5), Ok(5));
+        assert_eq!(ascii.byte_pos_to_chars(6), Err(EditError::InvalidBytePosition));
+    }
+
+    #[test]
+    #[cfg(feature = "wchar_conversion")]
+    fn wchar_positions_are_checked() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        // '😀' is a surrogate pair in UTF-16.
+        branch.insert(&mut oplog, seph, 0, "a😀b");
+
+        assert_eq!(branch.wchar_pos_to_chars(0), Ok(0));
+        assert_eq!(branch.wchar_pos_to_chars(1), Ok(1));
+        assert_eq!(branch.wchar_pos_to_chars(2), Err(EditError::InvalidWcharPosition));
+        assert_eq!(branch.wchar_pos_to_chars(3), Ok(2));
+        assert_eq!(branch.wchar_pos_to_chars(4), Ok(3));
+        assert_eq!(branch.wchar_pos_to_chars(5), Err(EditError::InvalidWcharPosition));
+
+        // Big documents are split across lots of rope nodes.
+        branch.insert(&mut oplog, seph, 0, &"x😀".repeat(2000));
+        assert_eq!(branch.wchar_pos_to_chars(3 * 1234), Ok(2 * 1234));
+        assert_eq!(branch.wchar_pos_to_chars(3 * 1234 + 2), Err(EditError::InvalidWcharPosition));
+    }
+
+    #[test]
+    fn wide_merges_check_out_correctly() {
+        // Hundreds of concurrent branches, merged by single changes in two layers.
+        let mut oplog = ListOpLog::new();
+        let mut layer_start: Vec<LV> = vec![];
+        let mut all_heads = vec![];
+        for layer in 0..2 {
+            let mut heads = vec![];
+            for i in 0..120 {
+                let agent = oplog.get_or_create_agent_id(&format!("agent {i}"));
+                let v = oplog.add_insert_at(agent, &layer_start, 0, &format!("{}", i % 10));
+                heads.push(oplog.add_insert_at(agent, &[v], 1, "."));
+            }
+            let merger = oplog.get_or_create_agent_id("merger");
+            layer_start = vec![oplog.add_insert_at(merger, &heads, 0, &format!("<{layer}>"))];
+            all_heads.extend(heads);
+        }
+
+        // Merging the branches one at a time has to give the same result.
+        let mut branch = ListBranch::new();
+        for &v in &all_heads {
+            branch.merge(&oplog, &[v]);
+        }
+        assert_eq!(branch.len(), 120 * 2 * 2 + "<0>".len());
+        branch.merge(&oplog, oplog.local_version_ref());
+        assert_eq!(branch, oplog.checkout_tip());
+        assert!(branch.content().to_string().starts_with("<1>"));
+
+        let half = oplog.find_dominators(&all_heads[..60]);
+        let mut branch = ListBranch::new();
+        for &v in &all_heads[..60] {
+            branch.merge(&oplog, &[v]);
+        }
+        assert_eq!(branch, oplog.checkout(half.as_ref()));
+
+        let loaded = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(loaded, oplog);
+        assert_eq!(loaded.checkout_tip(), oplog.checkout_tip());
+        oplog.dbg_check(true);
+    }
+}
@@ -34,6 +34,12 @@ impl<Iter: Iterator> BufferedIter<Iter> {
         assert!(self.buffer.is_none());
         self.buffer = Some(item);
     }
+
+    /// Split the iterator back into the inner iterator and the item which was pushed back (if
+    /// any).
+    pub fn into_parts(self) -> (Iter, Option<Iter::Item>) {
+        (self.inner, self.buffer)
+    }
 }
 
 impl<Iter: Iterator> From<Iter> for BufferedIter<Iter> {
@@ -1,5 +1,8 @@
 use jumprope::JumpRope;
+use rle::HasLength;
 use crate::list::{ListBranch, ListCRDT, ListOpLog};
+use crate::rle::KVPair;
+use crate::LV;
 
 /// This file contains debugging assertions to validate the document's internal state.
 ///
@@ -26,6 +29,35 @@ impl ListOpLog {
     #[allow(unused)]
     pub fn dbg_check(&self, deep: bool) {
         self.cg.dbg_check(deep);
+        assert_eq!(self.check_content_alignment(), Ok(()));
+
+        // Every version is either an operation or a checkpoint.
+        let mut next = 0;
+        let mut checkpoints = self.checkpoints.keys().copied().peekable();
+        for KVPair(v, op) in self.operations.iter() {
+            while checkpoints.next_if_eq(&next).is_some() { next += 1; }
+            assert_eq!(*v, next);
+            next += op.len();
+        }
+        while checkpoints.next_if_eq(&next).is_some() { next += 1; }
+        assert_eq!(next, self.len());
+        assert_eq!(checkpoints.next(), None);
+    }
+
+    /// Check the stored content of every operation starts and ends on a character boundary, and
+    /// contains one character for each item the operation inserts or deletes. If not, returns the
+    /// version of the first operation with bad content.
+    ///
+    /// Misaligned content is never panicked on when it's read - it's treated as missing instead.
+    pub(crate) fn check_content_alignment(&self) -> Result<(), LV> {
+        for KVPair(v, op) in self.operations.iter() {
+            let Some(pos) = op.content_pos else { continue; };
+            match self.operation_ctx.get_str(op.kind, pos) {
+                Some(content) if content.chars().count() == op.len() => {}
+                _ => { return Err(*v); }
+            }
+        }
+        Ok(())
     }
 
     #[allow(unused)]
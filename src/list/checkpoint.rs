@@ -0,0 +1,192 @@
+//! Checkpoints mark meaningful versions of a document (eg "sent to editor") with a message.
+//!
+//! A checkpoint is an entry in the causal graph like any other change. It has its own version,
+//! it's attributed to the agent which made it, and it's sent to other peers along with the rest of
+//! the history. But it has no operation, so it doesn't change the document. Merging and checking
+//! out skip over it.
+//!
+//! Files containing checkpoints store them in an extra chunk. The chunk can't just be skipped like
+//! other optional chunks, because every later operation would end up at the wrong version. So
+//! these files list [`Capability::Checkpoints`] as a required capability, and decoders which don't
+//! support it refuse to load them. (Decoders from before capabilities were added still reject
+//! them, because the file has fewer operations than versions.) Files without checkpoints are
+//! unchanged.
+//!
+//! [`Capability::Checkpoints`]: crate::list::encoding::Capability::Checkpoints
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+use crate::list::limits::assert_within_limits;
+use crate::list::ListOpLog;
+use crate::{AgentId, LV};
+
+/// A checkpoint made with [`ListOpLog::add_checkpoint`]. See [`ListOpLog::iter_checkpoints`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Checkpoint<'a> {
+    /// The checkpoint's local version.
+    pub version: LV,
+    /// The agent which made the checkpoint, and the checkpoint's sequence number.
+    pub id: RemoteVersion<'a>,
+    pub message: &'a str,
+}
+
+impl ListOpLog {
+    /// Mark a version of the document with a message, like a commit message. The checkpoint is
+    /// added to the history as a change from `agent` with the named parents, but it doesn't edit
+    /// the document. Returns the checkpoint's version.
+    ///
+    /// Later changes can use the checkpoint as a parent, so marking the current version is
+    /// usually done with `add_checkpoint(agent, oplog.local_version_ref(), message)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the checkpoint would exceed the oplog's operation limit. Checkpoints count as a
+    /// single operation.
+    pub fn add_checkpoint(&mut self, agent: AgentId, parents: &[LV], message: &str) -> LV {
+        assert_within_limits(self.check_local_change(agent, 1, 0));
+        let v = self.len();
+        self.cg.assign_span(agent, parents, (v..v + 1).into());
+        self.checkpoints.insert(v, message.to_string());
+        v
+    }
+
+    /// Iterate through the checkpoints in the oplog, in version order.
+    pub fn iter_checkpoints(&self) -> impl Iterator<Item = Checkpoint<'_>> + '_ {
+        self.checkpoints.iter().map(|(&version, message)| Checkpoint {
+            version,
+            id: self.cg.agent_assignment.local_to_remote_version(version),
+            message: message.as_str(),
+        })
+    }
+
+    /// Get the message of the checkpoint at `version`. Returns None if the version is an
+    /// ordinary operation.
+    pub fn checkpoint_message(&self, version: LV) -> Option<&str> {
+        self.checkpoints.get(&version).map(|m| m.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::encoding::{capabilities, Capability, iter_chunks, load_skipping_chunk, verify_data, ENCODE_FULL, EncodeOptions, ListChunkType};
+    use crate::list::ListOpLog;
+    use crate::ParseErrorKind;
+    use crate::causalgraph::agent_assignment::remote_ids::RemoteVersion;
+
+    /// Three oplogs with the same edits. The first has no checkpoints. The second has two,
+    /// including one merging concurrent changes which later edits are made on top of. The third
+    /// is the first with a checkpoint at the end.
+    fn with_and_without() -> (ListOpLog, ListOpLog, ListOpLog) {
+        let mut plain = ListOpLog::new();
+        let seph = plain.get_or_create_agent_id("seph");
+        let mike = plain.get_or_create_agent_id("mike");
+        let mut marked = plain.clone();
+
+        plain.add_insert(seph, 0, "hello");
+        marked.add_insert(seph, 0, "hello");
+        marked.add_checkpoint(seph, &[4], "first draft");
+
+        // Concurrent edits, merged by a checkpoint.
+        let a = plain.add_insert_at(seph, &[4], 5, " world");
+        let b = plain.add_delete_at(mike, &[4], 0..1);
+        plain.add_insert_at(mike, &[a, b], 0, "H");
+
+        let a = marked.add_insert_at(seph, &[4], 5, " world");
+        let b = marked.add_delete_at(mike, &[5], 0..1);
+        let c = marked.add_checkpoint(mike, &[a, b], "sent to editor");
+        marked.add_insert_at(mike, &[c], 0, "H");
+
+        let mut plain_marked = plain.clone();
+        plain_marked.add_checkpoint(seph, plain.cg.version.as_ref(), "done");
+        (plain, marked, plain_marked)
+    }
+
+    #[test]
+    fn checkpoints_dont_change_content() {
+        let (plain, marked, plain_marked) = with_and_without();
+        marked.dbg_check(true);
+        assert_eq!(marked.len(), plain.len() + 2);
+        assert_eq!(marked.checkout_tip().content(), "Hello world");
+        assert_eq!(plain.checkout_tip().content(), "Hello world");
+        assert_eq!(plain_marked.checkout_tip().content(), "Hello world");
+
+        assert_eq!(plain_marked.iter().collect::<Vec<_>>(), plain.iter().collect::<Vec<_>>());
+        assert_eq!(plain_marked.iter_xf_operations().filter_map(|(_, op)| op).collect::<Vec<_>>(),
+                   plain.iter_xf_operations().filter_map(|(_, op)| op).collect::<Vec<_>>());
+
+        // Checking out at a checkpoint is the same as checking out its parents.
+        let c = marked.iter_checkpoints().nth(1).unwrap().version;
+        let parents = marked.parents_at_time(c);
+        assert_eq!(marked.checkout(&[c]).content(), marked.checkout(parents.as_ref()).content());
+
+        // And merging a checkpoint into a branch leaves its content alone.
+        let mut branch = marked.checkout(parents.as_ref());
+        branch.merge(&marked, &[c]);
+        assert_eq!(branch.content(), "ello world");
+        assert_eq!(branch.local_version_ref(), &[c]);
+    }
+
+    #[test]
+    fn iter_checkpoints_lists_messages() {
+        let (_, marked, _) = with_and_without();
+        let checkpoints = marked.iter_checkpoints().collect::<Vec<_>>();
+        assert_eq!(checkpoints.len(), 2);
+        assert_eq!(checkpoints[0].version, 5);
+        assert_eq!(checkpoints[0].id, RemoteVersion("seph", 5));
+        assert_eq!(checkpoints[0].message, "first draft");
+        assert_eq!(checkpoints[1].id, RemoteVersion("mike", 1));
+        assert_eq!(checkpoints[1].message, "sent to editor");
+        assert_eq!(marked.checkpoint_message(checkpoints[1].version), Some("sent to editor"));
+        assert_eq!(marked.checkpoint_message(0), None);
+    }
+
+    #[test]
+    fn checkpoints_round_trip() {
+        let (plain, marked, plain_marked) = with_and_without();
+
+        let opts = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+        for oplog in [&marked, &plain_marked] {
+            let data = oplog.encode(opts.clone());
+            let loaded = ListOpLog::load_from(&data).unwrap();
+            assert_eq!(&loaded, oplog);
+            assert_eq!(loaded.iter_checkpoints().collect::<Vec<_>>(),
+                       oplog.iter_checkpoints().collect::<Vec<_>>());
+            assert_eq!(verify_data(&data).unwrap().num_operations, oplog.len());
+        }
+        assert_ne!(plain, plain_marked);
+
+        // Patches carry the checkpoints they contain.
+        let mut dest = plain.clone();
+        dest.decode_and_add(&plain_marked.encode_from(opts, plain.cg.version.as_ref())).unwrap();
+        assert_eq!(dest, plain_marked);
+
+        let mut dest = ListOpLog::new();
+        dest.add_missing_operations_from(&marked);
+        assert_eq!(dest, marked);
+        let mut copy = marked.clone();
+        copy.add_missing_operations_from(&dest);
+        assert_eq!(copy, marked);
+    }
+
+    #[test]
+    fn old_decoders_reject_checkpoints() {
+        let (plain, marked, _) = with_and_without();
+        let find_chunk = |data: &[u8]| iter_chunks(data)
+            .map(|c| c.unwrap())
+            .find(|c| c.chunk_type == ListChunkType::Checkpoints);
+
+        // Files without checkpoints don't have the chunk, so they're unchanged.
+        assert_eq!(find_chunk(&plain.encode(ENCODE_FULL)), None);
+
+        // Files with checkpoints say they need a decoder which understands them.
+        let (required, _) = capabilities(&marked.encode(ENCODE_FULL)).unwrap();
+        assert!(required.contains(Capability::Checkpoints));
+
+        // Decoders from before capabilities skip the chunk. The file has to be rejected, rather
+        // than loading with the operations at the wrong versions.
+        let err = load_skipping_chunk(&marked.encode(ENCODE_FULL), ListChunkType::Checkpoints).unwrap_err();
+        assert_eq!(err, ParseErrorKind::InvalidLength);
+    }
+}
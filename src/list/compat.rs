@@ -0,0 +1,581 @@
+//! This module contains tools for figuring out why two encoded oplogs won't merge cleanly.
+//!
+//! The main entry point is [`analyze`], which inspects two encoded files, attempts a dry-run merge
+//! in both directions and describes what it found in a [`CompatReport`]. This is used by the
+//! `dt doctor` command.
+
+use std::collections::HashMap;
+use smallvec::SmallVec;
+use smartstring::alias::String as SmartString;
+use rle::{HasLength, MergeableIterator, SplitableSpanCtx};
+use crate::list::ListOpLog;
+use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
+use crate::causalgraph::agent_assignment::ClientData;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteVersionOwned;
+use crate::dtrange::DTRange;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+use crate::rle::KVPair;
+use crate::{AgentId, LV};
+
+/// An agent which has edits in both files.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SharedAgent {
+    pub name: SmartString,
+    /// The sequence numbers used by this agent in the first file.
+    pub a_seqs: SmallVec<[DTRange; 2]>,
+    /// The sequence numbers used by this agent in the second file.
+    pub b_seqs: SmallVec<[DTRange; 2]>,
+    /// The sequence numbers which appear in both files. These should name identical operations.
+    pub overlap: SmallVec<[DTRange; 2]>,
+}
+
+/// A range of (agent, seq) IDs which name *different* operations in each file. This happens when
+/// an agent ID is reused, and it means the files can never be safely merged.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SeqConflict {
+    pub name: SmartString,
+    pub seqs: DTRange,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Recommendation {
+    /// The files can be merged (in at least one direction).
+    Mergeable,
+    /// Neither file contains the base version the other one needs. The missing operations need to
+    /// be found (eg from another peer) and merged in first.
+    NeedsIntermediatePatches,
+    /// The files describe different documents, or reuse IDs for different operations.
+    IrreconcilableFork,
+    /// One file is a squashed copy of the document, without the other file's history. See
+    /// [`Squashed`].
+    Squashed,
+    /// One of the files couldn't be read at all. (Eg, its corrupt or uses an unknown protocol
+    /// version).
+    Unreadable,
+}
+
+/// How one file holds a copy of the document without its history.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Squashed {
+    /// The first file is a snapshot (written with
+    /// [`EncodeMode::Snapshot`](crate::list::encoding::EncodeMode::Snapshot)). It only stores the
+    /// document's content, so it can't be merged with anything.
+    SnapshotA,
+    /// The second file is a snapshot.
+    SnapshotB,
+    /// The files have the same content, but no operations in common. One was probably made by
+    /// copying the other's content into a new document. Merging them would duplicate the content.
+    SameContent,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompatReport {
+    /// Error loading the first file into an empty oplog, if any.
+    pub a_load_error: Option<ParseError>,
+    /// Error loading the second file into an empty oplog, if any.
+    pub b_load_error: Option<ParseError>,
+
+    /// True if both files name a document ID, and they differ.
+    pub doc_id_mismatch: bool,
+
+    pub shared_agents: Vec<SharedAgent>,
+
+    /// (agent, seq) pairs which name different operations in each file. This is only computed when
+    /// both files can be loaded on their own.
+    pub conflicts: Vec<SeqConflict>,
+
+    /// Set if one of the files is a copy of the document without the other file's history.
+    pub squashed: Option<Squashed>,
+
+    /// The result of merging the first file into the second.
+    pub merge_a_into_b: Result<(), ParseError>,
+    /// The result of merging the second file into the first.
+    pub merge_b_into_a: Result<(), ParseError>,
+
+    pub recommendation: Recommendation,
+}
+
+fn is_missing_base(e: ParseError) -> bool {
+    matches!(e.kind, ParseErrorKind::BaseVersionUnknown | ParseErrorKind::DataMissing)
+}
+
+/// Try and load `into` and then merge `from` on top.
+fn dry_run_merge(into: &[u8], from: &[u8]) -> Result<(), ParseError> {
+    let mut oplog = ListOpLog::load_from(into)?;
+    oplog.decode_and_add(from)?;
+    Ok(())
+}
+
+/// Intersect two sorted lists of non-overlapping ranges.
+fn intersect_ranges(a: &[DTRange], b: &[DTRange]) -> SmallVec<[DTRange; 2]> {
+    let mut result: SmallVec<[DTRange; 2]> = SmallVec::new();
+    let (mut a, mut b) = (a.iter().peekable(), b.iter().peekable());
+    while let (Some(ra), Some(rb)) = (a.peek(), b.peek()) {
+        let start = ra.start.max(rb.start);
+        let end = ra.end.min(rb.end);
+        if start < end { result.push((start..end).into()); }
+
+        // Whichever range ends first can't overlap anything else in the other list.
+        if ra.end <= rb.end { a.next(); } else { b.next(); }
+    }
+    result
+}
+
+/// Part of an oplog's history in a range of LVs. Checkpoints don't have an operation, so they're
+/// listed separately (one LV each).
+enum Item {
+    Op(ListOpMetrics),
+    Checkpoint,
+}
+
+impl Item {
+    fn len(&self) -> usize {
+        match self {
+            Item::Op(op) => op.len(),
+            Item::Checkpoint => 1,
+        }
+    }
+
+    /// Split the first `at` LVs off the front of this item.
+    fn take_front(&mut self, at: usize, ctx: &ListOperationCtx) -> Item {
+        match self {
+            Item::Op(op) if at < op.len() => Item::Op(op.truncate_keeping_right_ctx(at, ctx)),
+            _ => std::mem::replace(self, Item::Checkpoint),
+        }
+    }
+}
+
+/// The operations in the named range, split at the boundaries of the oplog's operation runs.
+fn items_in(oplog: &ListOpLog, range: DTRange) -> Vec<Item> {
+    let mut result = Vec::new();
+    let mut next = range.start;
+    for KVPair(lv, op) in oplog.operations.iter_range_ctx(range, &oplog.operation_ctx) {
+        result.extend((next..lv).map(|_| Item::Checkpoint));
+        next = lv + op.len();
+        result.push(Item::Op(op));
+    }
+    result.extend((next..range.end).map(|_| Item::Checkpoint));
+    result
+}
+
+fn remote_parents_at(oplog: &ListOpLog, lv: LV) -> Vec<RemoteVersionOwned> {
+    let parents = oplog.cg.graph.parents_at_time(lv);
+    let mut result: Vec<RemoteVersionOwned> = oplog.cg.agent_assignment
+        .local_to_remote_frontier(parents.as_ref())
+        .iter()
+        .map(|rv| rv.into())
+        .collect();
+    result.sort_unstable_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+    result
+}
+
+/// Returns true if two runs of operations (with the same length) are the same. Content is only
+/// compared when both oplogs know it.
+fn ops_match(a: &ListOpLog, b: &ListOpLog, op_a: &ListOpMetrics, op_b: &ListOpMetrics) -> bool {
+    if op_a.kind != op_b.kind { return false; }
+    // The direction of a single item operation doesn't mean anything.
+    if op_a.loc.span != op_b.loc.span || (op_a.len() > 1 && op_a.loc.fwd != op_b.loc.fwd) { return false; }
+
+    match (op_a.get_content(&a.operation_ctx), op_b.get_content(&b.operation_ctx)) {
+        (Some(ca), Some(cb)) => ca == cb,
+        _ => true,
+    }
+}
+
+/// Compare the operations at the LVs `range_a` in `a` with the same number of operations from
+/// `lv_b` in `b`. The seqs (counted from `seq`) of any which differ are added to `bad`.
+///
+/// Operations are compared a run at a time, and only runs which differ are compared item by item.
+fn compare_span(a: &ListOpLog, b: &ListOpLog, range_a: DTRange, lv_b: LV, seq: usize, bad: &mut Vec<DTRange>) {
+    let range_b: DTRange = (lv_b..lv_b + range_a.len()).into();
+
+    // Inside a graph entry, each item's parent is the previous item. That has the previous seq in
+    // both oplogs, so parents only need to be checked where either oplog starts a new entry.
+    let mut entry_starts: Vec<usize> = a.cg.graph.iter_range(range_a).map(|e| e.span.start - range_a.start)
+        .chain(b.cg.graph.iter_range(range_b).map(|e| e.span.start - range_b.start))
+        .collect();
+    entry_starts.sort_unstable();
+    entry_starts.dedup();
+    for offset in entry_starts {
+        if remote_parents_at(a, range_a.start + offset) != remote_parents_at(b, range_b.start + offset) {
+            bad.push((seq + offset..seq + offset + 1).into());
+        }
+    }
+
+    let (mut items_a, mut items_b) = (items_in(a, range_a).into_iter(), items_in(b, range_b).into_iter());
+    let (mut item_a, mut item_b) = (items_a.next(), items_b.next());
+    let mut offset = 0;
+    while let (Some(x), Some(y)) = (item_a.as_mut(), item_b.as_mut()) {
+        let len = x.len().min(y.len());
+        let (done_a, done_b) = (x.len() == len, y.len() == len);
+        let mut x = x.take_front(len, &a.operation_ctx);
+        let mut y = y.take_front(len, &b.operation_ctx);
+
+        match (&mut x, &mut y) {
+            (Item::Op(op_a), Item::Op(op_b)) => {
+                if !ops_match(a, b, op_a, op_b) {
+                    for i in 0..len {
+                        let (xi, yi) = if i + 1 < len {
+                            (op_a.truncate_keeping_right_ctx(1, &a.operation_ctx), op_b.truncate_keeping_right_ctx(1, &b.operation_ctx))
+                        } else {
+                            (op_a.clone(), op_b.clone())
+                        };
+                        if !ops_match(a, b, &xi, &yi) {
+                            bad.push((seq + offset + i..seq + offset + i + 1).into());
+                        }
+                    }
+                }
+            }
+            (Item::Checkpoint, Item::Checkpoint) => {
+                if a.checkpoint_message(range_a.start + offset) != b.checkpoint_message(range_b.start + offset) {
+                    bad.push((seq + offset..seq + offset + 1).into());
+                }
+            }
+            _ => bad.push((seq + offset..seq + offset + len).into()),
+        }
+
+        offset += len;
+        if done_a { item_a = items_a.next(); }
+        if done_b { item_b = items_b.next(); }
+    }
+}
+
+/// Scan the overlapping sequence numbers for an agent and find any which name different
+/// operations. This walks the overlap a span at a time, so its linear in the size of the overlap.
+fn find_conflicts(a: &ListOpLog, b: &ListOpLog, (shared, agent_a, agent_b): &(SharedAgent, AgentId, AgentId), out: &mut Vec<SeqConflict>) {
+    let client_a = &a.cg.agent_assignment.client_data[*agent_a as usize];
+    let client_b = &b.cg.agent_assignment.client_data[*agent_b as usize];
+
+    let mut bad = Vec::new();
+    for range in shared.overlap.iter() {
+        let mut seq = range.start;
+        while seq < range.end {
+            // The overlap is only made of seqs which both oplogs know about.
+            let remaining: DTRange = (seq..range.end).into();
+            let mut lvs_a = client_a.try_seq_to_lv_span(remaining).unwrap();
+            let lvs_b = client_b.try_seq_to_lv_span(remaining).unwrap();
+            lvs_a.end = lvs_a.end.min(lvs_a.start + lvs_b.len());
+
+            compare_span(a, b, lvs_a, lvs_b.start, seq, &mut bad);
+            seq += lvs_a.len();
+        }
+    }
+
+    // The parents and operations are checked separately, so the conflicting ranges can overlap.
+    bad.sort_unstable_by_key(|r| r.start);
+    let mut current: Option<DTRange> = None;
+    for r in bad {
+        match current.as_mut() {
+            Some(c) if r.start <= c.end => c.end = c.end.max(r.end),
+            _ => {
+                if let Some(c) = current.replace(r) {
+                    out.push(SeqConflict { name: shared.name.clone(), seqs: c });
+                }
+            }
+        }
+    }
+    if let Some(c) = current {
+        out.push(SeqConflict { name: shared.name.clone(), seqs: c });
+    }
+}
+
+/// The agents with edits in both oplogs, along with their agent IDs in each.
+fn shared_agents(a: &ListOpLog, b: &ListOpLog) -> Vec<(SharedAgent, AgentId, AgentId)> {
+    let clients_a = &a.cg.agent_assignment.client_data;
+    let clients_b = &b.cg.agent_assignment.client_data;
+    let agents_b: HashMap<&str, AgentId> = clients_b.iter().enumerate()
+        .filter(|(_, c)| !c.item_times.is_empty())
+        .map(|(agent, c)| (c.name.as_str(), agent as AgentId))
+        .collect();
+
+    let seq_ranges = |c: &ClientData| -> SmallVec<[DTRange; 2]> {
+        c.item_times.iter().map(|e| e.range()).merge_spans().collect()
+    };
+
+    clients_a.iter().enumerate().filter_map(|(agent_a, c)| {
+        if c.item_times.is_empty() { return None; }
+        let agent_b = *agents_b.get(c.name.as_str())?;
+
+        let a_seqs = seq_ranges(c);
+        let b_seqs = seq_ranges(&clients_b[agent_b as usize]);
+        let shared = SharedAgent {
+            overlap: intersect_ranges(&a_seqs, &b_seqs),
+            name: c.name.clone(),
+            a_seqs,
+            b_seqs,
+        };
+        Some((shared, agent_a as AgentId, agent_b))
+    }).collect()
+}
+
+/// Find the (agent, seq) IDs which name different operations in the two oplogs.
+///
+/// Merging skips any operations whose IDs are already known, so merging oplogs with conflicting
+/// IDs silently keeps whichever version of the operation the destination already has. Check this
+/// first if the oplogs might have reused an agent ID.
+pub fn seq_conflicts(a: &ListOpLog, b: &ListOpLog) -> Vec<SeqConflict> {
+    let mut conflicts = Vec::new();
+    for s in shared_agents(a, b).iter() {
+        find_conflicts(a, b, s, &mut conflicts);
+    }
+    conflicts
+}
+
+/// Inspect two encoded oplogs and explain whether (and why not) they can be merged together.
+///
+/// Neither input is modified. Merges are attempted in memory, in both directions.
+pub fn analyze(a: &[u8], b: &[u8]) -> CompatReport {
+    let oplog_a = ListOpLog::load_from(a);
+    let oplog_b = ListOpLog::load_from(b);
+
+    let merge_a_into_b = dry_run_merge(b, a);
+    let merge_b_into_a = dry_run_merge(a, b);
+
+    let mut doc_id_mismatch = false;
+    let mut shared = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut squashed = None;
+
+    if let (Ok(oa), Ok(ob)) = (&oplog_a, &oplog_b) {
+        if let (Some(id_a), Some(id_b)) = (&oa.doc_id, &ob.doc_id) {
+            doc_id_mismatch = id_a != id_b;
+        }
+
+        let agents = shared_agents(oa, ob);
+        for s in agents.iter() {
+            find_conflicts(oa, ob, s, &mut conflicts);
+        }
+        shared = agents.into_iter().map(|(s, _, _)| s).collect();
+
+        let no_shared_ops = shared.iter().all(|s: &SharedAgent| s.overlap.is_empty());
+        if no_shared_ops && !oa.is_empty() && !ob.is_empty()
+            && oa.checkout_tip().content() == ob.checkout_tip().content()
+        {
+            squashed = Some(Squashed::SameContent);
+        }
+    }
+
+    let a_load_error = oplog_a.err();
+    let b_load_error = oplog_b.err();
+
+    let is_snapshot = |e: Option<ParseError>| e.is_some_and(|e| e.kind == ParseErrorKind::SnapshotData);
+    if is_snapshot(a_load_error) {
+        squashed = Some(Squashed::SnapshotA);
+    } else if is_snapshot(b_load_error) {
+        squashed = Some(Squashed::SnapshotB);
+    }
+
+    let unreadable = [a_load_error, b_load_error].iter().flatten()
+        .any(|e| !is_missing_base(*e));
+
+    let recommendation = if squashed.is_some() {
+        Recommendation::Squashed
+    } else if unreadable {
+        Recommendation::Unreadable
+    } else if doc_id_mismatch || !conflicts.is_empty() {
+        Recommendation::IrreconcilableFork
+    } else if merge_a_into_b.is_ok() || merge_b_into_a.is_ok() {
+        Recommendation::Mergeable
+    } else {
+        Recommendation::NeedsIntermediatePatches
+    };
+
+    CompatReport {
+        a_load_error,
+        b_load_error,
+        doc_id_mismatch,
+        shared_agents: shared,
+        conflicts,
+        squashed,
+        merge_a_into_b,
+        merge_b_into_a,
+        recommendation,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::list::encoding::{EncodeMode, EncodeOptions, ENCODE_FULL, ENCODE_PATCH};
+
+    #[test]
+    fn identical_files_are_mergeable() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+
+        let data = oplog.encode(ENCODE_FULL);
+        let report = analyze(&data, &data);
+        assert_eq!(report.recommendation, Recommendation::Mergeable);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(report.shared_agents.len(), 1);
+        assert_eq!(report.shared_agents[0].overlap.as_slice(), &[(0..8).into()]);
+    }
+
+    #[test]
+    fn disjoint_agents_are_mergeable() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "aaa");
+
+        let mut b = ListOpLog::new();
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert(mike, 0, "bbb");
+
+        let report = analyze(&a.encode(ENCODE_FULL), &b.encode(ENCODE_FULL));
+        assert_eq!(report.recommendation, Recommendation::Mergeable);
+        assert!(report.shared_agents.is_empty());
+    }
+
+    #[test]
+    fn reused_ids_are_a_fork() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "aaa");
+
+        let mut b = ListOpLog::new();
+        let seph = b.get_or_create_agent_id("seph");
+        b.add_insert(seph, 0, "abc");
+
+        let report = analyze(&a.encode(ENCODE_FULL), &b.encode(ENCODE_FULL));
+        assert_eq!(report.recommendation, Recommendation::IrreconcilableFork);
+        assert_eq!(report.conflicts, vec![
+            SeqConflict { name: "seph".into(), seqs: (1..3).into() }
+        ]);
+        assert_eq!(seq_conflicts(&a, &b), report.conflicts);
+    }
+
+    #[test]
+    fn conflicts_are_found_inside_runs() {
+        // The same agent pastes a large block of text in both files, but one character differs.
+        let text: String = "ツ€ab".repeat(10000);
+        let mut changed: Vec<char> = text.chars().collect();
+        changed[10] = 'x';
+        let changed: String = changed.into_iter().collect();
+
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, &text);
+
+        let mut b = ListOpLog::new();
+        let seph = b.get_or_create_agent_id("seph");
+        b.add_insert(seph, 0, &changed);
+
+        assert_eq!(seq_conflicts(&a, &b), vec![
+            SeqConflict { name: "seph".into(), seqs: (10..11).into() }
+        ]);
+        assert!(seq_conflicts(&a, &a).is_empty());
+
+        // a stores "abcd" as one run, and b splits it into two runs at different positions.
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "abcd");
+
+        let mut b = ListOpLog::new();
+        let seph = b.get_or_create_agent_id("seph");
+        b.add_insert(seph, 0, "ab");
+        b.add_insert(seph, 0, "cd");
+
+        assert_eq!(seq_conflicts(&a, &b), vec![
+            SeqConflict { name: "seph".into(), seqs: (2..4).into() }
+        ]);
+    }
+
+    #[test]
+    fn shared_history_has_no_conflicts() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi there");
+
+        // b starts with a's operations, then both files diverge.
+        let mut b = ListOpLog::load_from(&a.encode(ENCODE_FULL)).unwrap();
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert(mike, 2, " mike");
+        a.add_insert(seph, 8, "!");
+
+        assert!(seq_conflicts(&a, &b).is_empty());
+    }
+
+    #[test]
+    fn different_doc_ids_are_a_fork() {
+        let mut a = ListOpLog::new();
+        a.doc_id = Some("doc1".into());
+        let mut b = ListOpLog::new();
+        b.doc_id = Some("doc2".into());
+
+        let report = analyze(&a.encode(ENCODE_FULL), &b.encode(ENCODE_FULL));
+        assert!(report.doc_id_mismatch);
+        assert_eq!(report.recommendation, Recommendation::IrreconcilableFork);
+    }
+
+    #[test]
+    fn missing_base_needs_patches() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v1 = oplog.add_insert(seph, 0, "aaa");
+        let v2 = oplog.add_insert(seph, 3, "bbb");
+        oplog.add_insert(seph, 6, "ccc");
+
+        // Two patches which both depend on data neither of them contain.
+        let a = oplog.encode_from(ENCODE_PATCH, &[v1]);
+        let b = oplog.encode_from(ENCODE_PATCH, &[v2]);
+
+        let report = analyze(&a, &b);
+        assert_eq!(report.a_load_error.map(|e| e.kind), Some(ParseErrorKind::BaseVersionUnknown));
+        assert_eq!(report.recommendation, Recommendation::NeedsIntermediatePatches);
+
+        // But a patch on top of the full file is fine.
+        let full = oplog.encode(ENCODE_FULL);
+        let report = analyze(&full, &b);
+        assert_eq!(report.recommendation, Recommendation::Mergeable);
+        assert!(report.merge_b_into_a.is_ok());
+    }
+
+    #[test]
+    fn snapshots_are_squashed() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+
+        let full = oplog.encode(ENCODE_FULL);
+        let snapshot = oplog.encode(EncodeOptions { mode: EncodeMode::Snapshot, ..ENCODE_FULL });
+        let report = analyze(&full, &snapshot);
+        assert_eq!(report.b_load_error.map(|e| e.kind), Some(ParseErrorKind::SnapshotData));
+        assert_eq!(report.squashed, Some(Squashed::SnapshotB));
+        assert_eq!(report.recommendation, Recommendation::Squashed);
+
+        assert_eq!(analyze(&snapshot, &full).squashed, Some(Squashed::SnapshotA));
+    }
+
+    #[test]
+    fn copied_content_is_squashed() {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hi there");
+        a.add_delete_without_content(seph, 2..8);
+        a.add_insert(seph, 2, " everyone");
+
+        // The same text, pasted into a new document.
+        let mut b = ListOpLog::new();
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert(mike, 0, "hi everyone");
+
+        let report = analyze(&a.encode(ENCODE_FULL), &b.encode(ENCODE_FULL));
+        assert!(report.merge_a_into_b.is_ok());
+        assert_eq!(report.squashed, Some(Squashed::SameContent));
+        assert_eq!(report.recommendation, Recommendation::Squashed);
+
+        // Documents with shared history aren't copies, even with the same content.
+        let c = ListOpLog::load_from(&a.encode(ENCODE_FULL)).unwrap();
+        let report = analyze(&a.encode(ENCODE_FULL), &c.encode(ENCODE_FULL));
+        assert_eq!(report.squashed, None);
+        assert_eq!(report.recommendation, Recommendation::Mergeable);
+    }
+
+    #[test]
+    fn garbage_is_unreadable() {
+        let report = analyze(b"not a dt file", &ListOpLog::new().encode(ENCODE_FULL));
+        assert_eq!(report.a_load_error.map(|e| e.kind), Some(ParseErrorKind::InvalidMagic));
+        assert_eq!(report.recommendation, Recommendation::Unreadable);
+    }
+}
@@ -0,0 +1,190 @@
+//! Cursors which stay next to the same text as the document is edited.
+//!
+//! A raw character offset goes stale as soon as anyone edits text before it. Instead, a
+//! [`Cursor`] remembers the character it sits after (by the local version of the insert which
+//! created it), and works out its offset again when it's needed.
+
+use rle::HasLength;
+use crate::list::{ListBranch, ListOpLog};
+use crate::list::operation::ListOpKind;
+use crate::listmerge::merge::TransformedResult::BaseMoved;
+use crate::LV;
+
+/// A position in a document which moves along with concurrent edits. Make one with
+/// [`ListBranch::cursor_at`], and find its current offset with [`Cursor::resolve`].
+///
+/// Cursors name characters by local version, so they're only meaningful with the oplog they were
+/// made from (or one which has been merged into it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    /// The insert which created the character the cursor sits after, or `None` if the cursor is
+    /// at the start of the document.
+    anchor: Option<LV>,
+}
+
+impl Cursor {
+    /// A cursor at the start of the document. It stays there no matter what's inserted.
+    pub fn start() -> Self {
+        Self { anchor: None }
+    }
+
+    /// A cursor right after the character inserted at the named local version.
+    pub fn after(lv: LV) -> Self {
+        Self { anchor: Some(lv) }
+    }
+
+    /// The local version of the character the cursor sits after, or `None` for the start of the
+    /// document.
+    pub fn anchor(&self) -> Option<LV> {
+        self.anchor
+    }
+
+    /// Find the cursor's character offset in the branch's content.
+    ///
+    /// The oplog must be the oplog the branch was checked out from. See
+    /// [`resolve_at`](Cursor::resolve_at).
+    pub fn resolve(&self, oplog: &ListOpLog, branch: &ListBranch) -> usize {
+        let pos = self.resolve_at(oplog, branch.version.as_ref());
+        debug_assert!(pos <= branch.len());
+        pos
+    }
+
+    /// Find the cursor's character offset in the document at the named version. Text inserted
+    /// right at the cursor goes after it, and everything else moves it along like you'd expect.
+    ///
+    /// If the character the cursor sits after has been deleted, the cursor is placed where the
+    /// deleted character was, and moves with edits from there.
+    ///
+    /// This replays the operations in the version, so it's about as expensive as checking out the
+    /// document.
+    ///
+    /// Panics if the cursor's character isn't in the version.
+    pub fn resolve_at(&self, oplog: &ListOpLog, version: &[LV]) -> usize {
+        let Some(anchor) = self.anchor else { return 0; };
+        assert!(oplog.cg.graph.frontier_contains_version(version, anchor),
+            "Cursor anchor {anchor} is not in the version");
+
+        // The cursor's position, once the anchor character has been inserted.
+        let mut cursor = None;
+
+        for (lv, op, xf) in oplog.get_xf_operations_full(&[], version) {
+            let BaseMoved(pos) = xf else { continue; };
+            let len = op.len();
+
+            match (op.kind, cursor.as_mut()) {
+                (ListOpKind::Ins, None) if (lv..lv + len).contains(&anchor) => {
+                    // Reversed inserts put each character before the one inserted previously, so
+                    // the characters inserted after the anchor end up in front of it.
+                    let offset = if op.loc.fwd { anchor - lv } else { lv + len - 1 - anchor };
+                    cursor = Some(pos + offset + 1);
+                }
+                (_, None) => {}
+                (ListOpKind::Ins, Some(c)) => {
+                    if pos < *c { *c += len; }
+                }
+                (ListOpKind::Del, Some(c)) => {
+                    // This also covers deleting the anchor, which leaves the cursor at the start
+                    // of the delete.
+                    if pos < *c { *c -= len.min(*c - pos); }
+                }
+            }
+        }
+
+        cursor.expect("Cursor anchor is not an inserted character")
+    }
+}
+
+impl ListBranch {
+    /// Make a cursor at the named character offset in the branch. See [`Cursor`].
+    ///
+    /// The oplog must be the oplog the branch was checked out from.
+    ///
+    /// Panics if the position is past the end of the branch's content.
+    pub fn cursor_at(&self, oplog: &ListOpLog, pos: usize) -> Cursor {
+        assert!(pos <= self.len(), "Cursor position {pos} is past the end of the document");
+        if pos == 0 { return Cursor::start(); }
+
+        // The cursor sits after the character at pos - 1.
+        let mut offset = pos - 1;
+        for span in oplog.char_versions_at(self.version.as_ref()) {
+            if offset < span.len() {
+                return Cursor::after(span.start + offset);
+            }
+            offset -= span.len();
+        }
+        unreachable!("Branch content doesn't match the oplog")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::{ListCRDT, ListOpLog};
+    use super::Cursor;
+
+    #[test]
+    fn cursor_moves_with_remote_inserts() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "hello world");
+
+        let mut branch = oplog.checkout_tip();
+        let cursor = branch.cursor_at(&oplog, 6); // Before "world".
+        let start = branch.cursor_at(&oplog, 0);
+        assert_eq!(cursor, Cursor::after(5));
+        assert_eq!(cursor.resolve(&oplog, &branch), 6);
+
+        // Concurrent inserts before and after the cursor.
+        let a = oplog.add_insert_at(mike, &[base], 0, "oh, ");
+        let b = oplog.add_insert_at(seph, &[base], 11, "!");
+        branch.merge(&oplog, &[a, b]);
+        assert_eq!(branch.content(), "oh, hello world!");
+        assert_eq!(cursor.resolve(&oplog, &branch), 10);
+        assert_eq!(start.resolve(&oplog, &branch), 0);
+
+        // Text typed right at the cursor goes after it.
+        let c = oplog.add_insert(mike, 10, "big ");
+        branch.merge(&oplog, &[c]);
+        assert_eq!(cursor.resolve(&oplog, &branch), 10);
+        assert_eq!(&branch.content().to_string()[10..], "big world!");
+
+        // Older versions still work.
+        assert_eq!(cursor.resolve_at(&oplog, &[base]), 6);
+    }
+
+    #[test]
+    fn deleted_anchor_resolves_to_delete_position() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "abcdefgh");
+        let cursor = doc.branch.cursor_at(&doc.oplog, 4); // After "d".
+        assert_eq!(cursor.resolve(&doc.oplog, &doc.branch), 4);
+
+        doc.delete(seph, 2..6); // "abgh"
+        assert_eq!(cursor.resolve(&doc.oplog, &doc.branch), 2);
+
+        // The cursor keeps moving with edits around where the character was.
+        doc.insert(seph, 0, "xy");
+        assert_eq!(cursor.resolve(&doc.oplog, &doc.branch), 4);
+        doc.delete(seph, 3..5);
+        assert_eq!(doc.branch.content(), "xyah");
+        assert_eq!(cursor.resolve(&doc.oplog, &doc.branch), 3);
+    }
+
+    #[test]
+    fn cursor_in_backwards_typing() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        // Typed "c", then "b" before it, then "a" before that.
+        for c in ["c", "b", "a"] {
+            doc.insert(seph, 0, c);
+        }
+        assert_eq!(doc.branch.content(), "abc");
+
+        for pos in 0..=3 {
+            let cursor = doc.branch.cursor_at(&doc.oplog, pos);
+            assert_eq!(cursor.resolve(&doc.oplog, &doc.branch), pos);
+        }
+        assert_eq!(doc.branch.cursor_at(&doc.oplog, 1), Cursor::after(2));
+    }
+}
@@ -0,0 +1,246 @@
+//! Summary statistics for the changes between two versions of a document ("this revision added 312
+//! words and removed 48").
+//!
+//! When one version is an ancestor of the other, the statistics are computed directly from the
+//! operations in between, without checking out the document. Character counts come straight from
+//! the operation lengths. Word and line counts are computed from the inserted and deleted content
+//! stored in the oplog, so they're only available when that content was kept.
+
+use rle::HasLength;
+use crate::LV;
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+
+/// Counts of the characters, words and lines inserted and deleted moving between two versions.
+/// See [`ListOpLog::edit_stats_between`].
+///
+/// Word counts are approximate. Each run of inserted or deleted text is counted on its own, so an
+/// edit which splits an existing word in half (or joins two words together) won't change the
+/// counts the way a word counter run on the whole document would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct EditStats {
+    pub chars_inserted: usize,
+    pub chars_deleted: usize,
+
+    /// The number of words inserted, or None if some inserted content isn't stored in the oplog.
+    pub words_inserted: Option<usize>,
+    /// The number of words deleted, or None if some deleted content isn't stored in the oplog.
+    pub words_deleted: Option<usize>,
+
+    /// The number of newlines inserted, or None if some inserted content isn't stored in the oplog.
+    pub lines_inserted: Option<usize>,
+    /// The number of newlines deleted, or None if some deleted content isn't stored in the oplog.
+    pub lines_deleted: Option<usize>,
+}
+
+/// Count the words in a run of text. A word is counted wherever a non-whitespace character starts
+/// the run or follows whitespace.
+fn count_words(s: &str) -> usize {
+    let mut in_word = false;
+    let mut count = 0;
+    for c in s.chars() {
+        let is_word_char = !c.is_whitespace();
+        if is_word_char && !in_word { count += 1; }
+        in_word = is_word_char;
+    }
+    count
+}
+
+fn count_lines(s: &str) -> usize {
+    s.bytes().filter(|b| *b == b'\n').count()
+}
+
+impl EditStats {
+    fn new() -> Self {
+        Self {
+            words_inserted: Some(0),
+            words_deleted: Some(0),
+            lines_inserted: Some(0),
+            lines_deleted: Some(0),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, kind: ListOpKind, len: usize, content: Option<&str>) {
+        let (chars, words, lines) = match kind {
+            ListOpKind::Ins => (&mut self.chars_inserted, &mut self.words_inserted, &mut self.lines_inserted),
+            ListOpKind::Del => (&mut self.chars_deleted, &mut self.words_deleted, &mut self.lines_deleted),
+        };
+
+        *chars += len;
+        if let Some(content) = content {
+            *words = words.map(|w| w + count_words(content));
+            *lines = lines.map(|l| l + count_lines(content));
+        } else {
+            *words = None;
+            *lines = None;
+        }
+    }
+
+    /// Swap inserts and deletes. Moving backwards in time undoes each operation.
+    fn reversed(self) -> Self {
+        Self {
+            chars_inserted: self.chars_deleted,
+            chars_deleted: self.chars_inserted,
+            words_inserted: self.words_deleted,
+            words_deleted: self.words_inserted,
+            lines_inserted: self.lines_deleted,
+            lines_deleted: self.lines_inserted,
+        }
+    }
+}
+
+impl ListOpLog {
+    /// Count the characters, words and lines inserted and deleted moving from version `a` to
+    /// version `b`.
+    ///
+    /// If `a` is an ancestor of `b`, this sums up the operations in `b` which aren't in `a`. (And
+    /// if `b` is an ancestor of `a`, the operations in `a` are undone, so inserts count as deletes
+    /// and vice versa.) Text which was inserted and then deleted again in that range is counted in
+    /// both directions. Word and line counts are only available if the oplog stores the content of
+    /// all of those operations.
+    ///
+    /// If `a` and `b` are concurrent, this falls back to checking out both versions and comparing
+    /// them. The documents are compared by trimming off their common prefix and suffix, so separate
+    /// edits in the middle of the document are counted as one big replacement (along with any
+    /// unchanged text in between).
+    pub fn edit_stats_between(&self, a: &[LV], b: &[LV]) -> EditStats {
+        let (only_a, only_b) = self.cg.graph.diff(a, b);
+
+        if !only_a.is_empty() && !only_b.is_empty() {
+            return self.edit_stats_from_checkouts(a, b);
+        }
+
+        let mut stats = EditStats::new();
+        for range in only_a.iter().chain(only_b.iter()) {
+            for (op, content) in self.iter_range_simple(*range) {
+                stats.add(op.1.kind, op.len(), content);
+            }
+        }
+
+        if only_a.is_empty() { stats } else { stats.reversed() }
+    }
+
+    fn edit_stats_from_checkouts(&self, a: &[LV], b: &[LV]) -> EditStats {
+        let a_content = self.checkout(a).content().to_string();
+        let b_content = self.checkout(b).content().to_string();
+
+        let prefix: usize = a_content.chars().zip(b_content.chars())
+            .take_while(|(x, y)| x == y)
+            .map(|(x, _)| x.len_utf8())
+            .sum();
+        let suffix: usize = a_content[prefix..].chars().rev().zip(b_content[prefix..].chars().rev())
+            .take_while(|(x, y)| x == y)
+            .map(|(x, _)| x.len_utf8())
+            .sum();
+
+        let deleted = &a_content[prefix..a_content.len() - suffix];
+        let inserted = &b_content[prefix..b_content.len() - suffix];
+
+        let mut stats = EditStats::new();
+        stats.add(ListOpKind::Del, deleted.chars().count(), Some(deleted));
+        stats.add(ListOpKind::Ins, inserted.chars().count(), Some(inserted));
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+    use crate::list::operation::TextOperation;
+    use super::{count_words, EditStats};
+
+    fn stats(chars: (usize, usize), words: (Option<usize>, Option<usize>), lines: (Option<usize>, Option<usize>)) -> EditStats {
+        EditStats {
+            chars_inserted: chars.0,
+            chars_deleted: chars.1,
+            words_inserted: words.0,
+            words_deleted: words.1,
+            lines_inserted: lines.0,
+            lines_deleted: lines.1,
+        }
+    }
+
+    #[test]
+    fn word_counting() {
+        assert_eq!(count_words(""), 0);
+        assert_eq!(count_words("   "), 0);
+        assert_eq!(count_words("hi"), 1);
+        assert_eq!(count_words(" hi there\n  you "), 3);
+    }
+
+    #[test]
+    fn empty_range() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+
+        let v = oplog.local_version();
+        assert_eq!(oplog.edit_stats_between(v.as_ref(), v.as_ref()),
+                   stats((0, 0), (Some(0), Some(0)), (Some(0), Some(0))));
+    }
+
+    #[test]
+    fn inserts_and_deletes() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        branch.insert(&mut oplog, seph, 0, "hello world\nfoo bar\n");
+        let v1 = oplog.local_version();
+
+        // A single operation with several runs in it: an insert, then a delete.
+        branch.apply_local_operations(&mut oplog, seph, &[
+            TextOperation::new_insert(20, "baz qux\n"),
+            TextOperation::new_delete_with_content(0, "hello ".into()),
+        ]);
+        // A backspaced (reversed) delete of "foo".
+        for pos in [8, 7, 6] {
+            branch.delete(&mut oplog, seph, pos..pos + 1);
+        }
+        let v2 = oplog.local_version();
+        assert_eq!(branch.content().to_string(), "world\n bar\nbaz qux\n");
+
+        assert_eq!(oplog.edit_stats_between(&[], v1.as_ref()),
+                   stats((20, 0), (Some(4), Some(0)), (Some(2), Some(0))));
+        assert_eq!(oplog.edit_stats_between(v1.as_ref(), v2.as_ref()),
+                   stats((8, 9), (Some(2), Some(2)), (Some(1), Some(0))));
+        assert_eq!(oplog.edit_stats_between(&[], v2.as_ref()),
+                   stats((28, 9), (Some(6), Some(2)), (Some(3), Some(0))));
+
+        // Going backwards undoes the changes.
+        assert_eq!(oplog.edit_stats_between(v2.as_ref(), v1.as_ref()),
+                   stats((9, 8), (Some(2), Some(2)), (Some(0), Some(1))));
+    }
+
+    #[test]
+    fn deletes_without_content() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "one two three");
+        let v1 = oplog.local_version();
+        oplog.add_delete_without_content(seph, 0..4);
+        oplog.add_insert(seph, 0, "zero ");
+
+        assert_eq!(oplog.edit_stats_between(v1.as_ref(), oplog.local_version_ref()),
+                   stats((5, 4), (Some(1), None), (Some(0), None)));
+    }
+
+    #[test]
+    fn concurrent_versions_diff_checkouts() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "The quick fox\n");
+
+        let a = oplog.add_insert_at(seph, &[base], 10, "brown ");
+        let b = oplog.add_delete_at(mike, &[base], 4..10);
+        let b = oplog.add_insert_at(mike, &[b], 4, "lazy ");
+
+        // "The quick brown fox\n" -> "The lazy fox\n". The common prefix is "The " and the common
+        // suffix is " fox\n".
+        assert_eq!(oplog.edit_stats_between(&[a], &[b]),
+                   stats((4, 11), (Some(1), Some(2)), (Some(0), Some(0))));
+        assert_eq!(oplog.edit_stats_between(&[b], &[a]),
+                   stats((11, 4), (Some(2), Some(1)), (Some(0), Some(0))));
+    }
+}
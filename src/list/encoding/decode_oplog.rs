@@ -1,6 +1,14 @@
+use std::io;
+use std::io::Read;
+use std::cell::RefCell;
+use std::ops::Deref;
+use std::sync::Arc;
+use bumpalo::Bump;
 use smallvec::{smallvec, SmallVec};
 use crate::list::encoding::*;
-use crate::list::{ListOpLog, switch};
+use smartstring::alias::String as SmartString;
+use crate::list::{ListBranch, ListOpLog, switch};
+use crate::list::origin::Origin;
 use crate::frontier::*;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::list::operation::ListOpKind::{Del, Ins};
@@ -8,32 +16,97 @@ use crate::rev_range::RangeRev;
 use crate::{AgentId, Frontier, LV};
 use crate::unicount::*;
 use rle::*;
-use crate::list::buffered_iter::Buffered;
+use crate::list::buffered_iter::{Buffered, BufferedIter};
 use crate::list::encoding::ListChunkType::*;
 use crate::causalgraph::graph::GraphEntrySimple;
 use crate::list::operation::ListOpKind;
 use crate::dtrange::{DTRange, UNDERWATER_START};
-use crate::list::encoding::decode_tools::{BufReader, ChunkReader};
+use crate::list::encoding::dedup::MAX_EXPANSION;
+use crate::list::encoding::decode_tools::{BufReader, Chunk, ChunkReader, ReaderLoc, StreamChunkReader, TopLevelChunks};
 use crate::causalgraph::agent_span::AgentSpan;
+use crate::causalgraph::agent_assignment::MAX_AGENT_NAME_LENGTH;
 use crate::rle::{KVPair, RleKeyedAndSplitable, RleSpanHelpers, RleVec};
-use crate::encoding::parseerror::ParseError;
-use crate::encoding::tools::calc_checksum;
-use crate::list::encoding::leb::num_decode_zigzag_isize_old;
+use crate::encoding::parseerror::{ChecksumMismatch, ChunkPath, ParseError, ParseErrorKind};
+use crate::encoding::tools::{calc_checksum, CRC32C};
+use crate::list::encoding::leb::{num_decode_zigzag_i64_old, num_decode_zigzag_isize_old};
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontierOwned, RemoteVersionOwned};
+use jumprope::{JumpRope, JumpRopeBuf};
+use crate::listmerge::merge::reverse_str;
 
 // If this is set to false, the compiler can optimize out the verbose printing code. This makes the
 // compiled output slightly smaller.
 const ALLOW_VERBOSE: bool = false;
 // const ALLOW_VERBOSE: bool = true;
 
+/// An agent listed in the file's AgentNames chunk.
+#[derive(Debug, Clone)]
+struct FileAgent {
+    /// The name is copied out of the file, so the map can outlive the file's bytes. See
+    /// [`DecodeDriver`].
+    name: SmartString,
+    /// The corresponding agent in the oplog, once it has been looked up.
+    local: Option<AgentId>,
+    /// The next seq number for agent assignments.
+    next_seq: usize,
+}
+
+/// Map from agent indexes in the file to agents in the oplog.
+///
+/// Patches generated with encode_from can list many agents they never reference, so local agents
+/// are only looked up (or created) the first time each file agent is actually used.
+#[derive(Debug)]
+struct FileAgentMap(Vec<FileAgent>);
+
+impl FileAgentMap {
+    fn parse(mut chunk: BufReader, opts: &DecodeOptions) -> Result<Self, ParseError> {
+        // Check the limits before allocating anything for the names.
+        if chunk.len() > opts.max_agent_name_bytes { return Err(chunk.err(ParseErrorKind::TooManyAgents)); }
+
+        let mut agents = Vec::new();
+        while !chunk.is_empty() {
+            if agents.len() >= opts.max_agents { return Err(chunk.err(ParseErrorKind::TooManyAgents)); }
+
+            let name = chunk.next_str()?;
+            agents.push(FileAgent { name: name.into(), local: None, next_seq: 0 });
+        }
+        Ok(Self(agents))
+    }
+
+    /// Look up the local agent for the named file agent, without creating it. File agent indexes
+    /// here are 0-based (so they're 1 less than the mapped agent indexes stored in the file).
+    fn get_existing(&mut self, oplog: &ListOpLog, file_agent: usize) -> Result<Option<AgentId>, ParseError> {
+        let entry = self.0.get_mut(file_agent).ok_or(ParseErrorKind::InvalidLength)?;
+        if entry.local.is_none() {
+            entry.local = oplog.get_agent_id(&entry.name);
+        }
+        Ok(entry.local)
+    }
+
+    /// Look up the local agent for the named file agent, creating it if it doesn't exist yet.
+    fn get_or_create(&mut self, oplog: &mut ListOpLog, file_agent: usize) -> Result<&mut FileAgent, ParseError> {
+        let entry = self.0.get_mut(file_agent).ok_or(ParseErrorKind::InvalidLength)?;
+        if entry.local.is_none() {
+            // get_or_create_agent_id panics on these names, and they can't be written by a valid
+            // encoder anyway.
+            if entry.name.as_str() == "ROOT" || entry.name.len() >= MAX_AGENT_NAME_LENGTH {
+                return Err(ParseErrorKind::GenericInvalidData.into());
+            }
+            entry.local = Some(oplog.get_or_create_agent_id(&entry.name));
+        }
+        Ok(entry)
+    }
+}
+
 impl<'a> BufReader<'a> {
-    fn read_next_agent_assignment(&mut self, map: &mut [(AgentId, usize)]) -> Result<Option<AgentSpan>, ParseError> {
+    fn read_next_agent_assignment(&mut self, oplog: &mut ListOpLog, map: &mut FileAgentMap) -> Result<Option<AgentSpan>, ParseError> {
         // Agent assignments are almost always (but not always) linear. They can have gaps, and
         // they can be reordered if the same agent ID is used to contribute to multiple branches.
         //
         // I'm still not sure if this is a good idea.
 
-        if self.0.is_empty() { return Ok(None); }
+        if self.is_empty() { return Ok(None); }
 
+        let record = *self;
         let mut n = self.next_usize()?;
         let has_jump = strip_bit_usize_2(&mut n);
         let len = self.next_usize()?;
@@ -45,21 +118,17 @@ impl<'a> BufReader<'a> {
         // The agent mapping uses 0 to refer to ROOT, but no actual operations can be assigned to
         // the root agent.
         if n == 0 {
-            return Err(ParseError::InvalidLength);
-        }
-
-        let inner_agent = n - 1;
-        if inner_agent >= map.len() {
-            return Err(ParseError::InvalidLength);
+            return Err(record.err(ParseErrorKind::InvalidLength));
         }
 
-        let entry = &mut map[inner_agent];
-        let agent = entry.0;
+        let entry = map.get_or_create(oplog, n - 1).map_err(|e| record.locate(e))?;
+        let agent = entry.local.unwrap();
 
-        // TODO: Error if this overflows.
-        let start = (entry.1 as isize + jump) as usize;
-        let end = start + len;
-        entry.1 = end;
+        // The cursor tracks seq numbers in the file, whether or not we already have those
+        // operations locally.
+        let start = entry.next_seq.checked_add_signed(jump).ok_or_else(|| record.err(ParseErrorKind::InvalidLength))?;
+        let end = start.checked_add(len).ok_or_else(|| record.err(ParseErrorKind::InvalidLength))?;
+        entry.next_seq = end;
 
         Ok(Some(AgentSpan {
             agent,
@@ -67,7 +136,7 @@ impl<'a> BufReader<'a> {
         }))
     }
 
-    fn read_version(mut self, oplog: &ListOpLog, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
+    fn read_version(mut self, oplog: &ListOpLog, agent_map: &mut FileAgentMap) -> Result<Frontier, ParseError> {
         let mut result = smallvec![];
         // All frontiers contain at least one item.
         loop {
@@ -76,10 +145,11 @@ impl<'a> BufReader<'a> {
             let seq = self.next_usize()?; // Bleh. Skip me when root!
             if mapped_agent == 0 { break; } // Root.
 
-            let agent = agent_map[mapped_agent - 1].0;
+            let agent = agent_map.get_existing(oplog, mapped_agent - 1)?
+                .ok_or(ParseErrorKind::BaseVersionUnknown)?;
 
             let time = oplog.try_crdt_id_to_time((agent, seq))
-                .ok_or(ParseError::BaseVersionUnknown)?;
+                .ok_or(ParseErrorKind::BaseVersionUnknown)?;
             result.push(time);
 
             if !has_more { break; }
@@ -92,7 +162,11 @@ impl<'a> BufReader<'a> {
         Ok(Frontier(result))
     }
 
-    fn read_parents(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
+    /// Read the parents of the history entry starting at `next_time`. Local parents must point to
+    /// an earlier entry in the file (which starts at `file_start`), and foreign parents must name
+    /// changes which have already been added to the oplog's causal graph. Anything else (like a
+    /// parent pointing forwards) would make the graph cyclic, so its rejected.
+    fn read_parents(&mut self, oplog: &ListOpLog, file_start: LV, next_time: LV, agent_map: &mut FileAgentMap) -> Result<Frontier, ParseError> {
         let mut parents = SmallVec::<[usize; 2]>::new();
         loop {
             let mut n = self.next_usize()?;
@@ -104,20 +178,27 @@ impl<'a> BufReader<'a> {
                     // The parents list is empty (ie, our parent is ROOT).
                     break;
                 } else {
-                    let agent = agent_map[n - 1].0;
+                    // Foreign parents we don't have mean the file depends on data we're missing
+                    // (for example, files written with encode_filtered).
+                    let agent = agent_map.get_existing(oplog, n - 1)
+                        .map_err(|_| ParseErrorKind::InvalidParent)?
+                        .ok_or(ParseErrorKind::DataMissing)?;
                     let seq = self.next_usize()?;
                     // dbg!((agent, seq));
-                    if let Some(c) = oplog.cg.agent_assignment.client_data.get(agent as usize) {
-                        // Adding UNDERWATER_START for foreign parents in a horrible hack.
-                        // I'm so sorry. This gets pulled back out in history_entry_map_and_truncate
-                        c.try_seq_to_lv(seq).ok_or(ParseError::InvalidLength)?
-                    } else {
-                        return Err(ParseError::InvalidLength);
-                    }
+                    // Adding UNDERWATER_START for foreign parents in a horrible hack.
+                    // I'm so sorry. This gets pulled back out in history_entry_map_and_truncate
+                    let lv = oplog.cg.agent_assignment.client_data[agent as usize]
+                        .try_seq_to_lv(seq).ok_or(ParseErrorKind::DataMissing)?;
+
+                    // The agent assignment chunk has already been read, so the (agent, seq) pair
+                    // might name a change from this file which isn't in the graph yet.
+                    if lv >= oplog.cg.graph.len() { return Err(ParseErrorKind::InvalidParent.into()); }
+                    lv
                 }
             } else {
                 // Local parents (parents inside this chunk of data) are stored using their
                 // local time offset.
+                if n == 0 || n > next_time - file_start { return Err(ParseErrorKind::InvalidParent.into()); }
                 next_time - n
             };
 
@@ -136,9 +217,12 @@ impl<'a> BufReader<'a> {
         Ok(Frontier(parents))
     }
 
-    fn next_history_entry(&mut self, oplog: &ListOpLog, next_time: LV, agent_map: &[(AgentId, usize)]) -> Result<GraphEntrySimple, ParseError> {
+    fn next_history_entry(&mut self, oplog: &ListOpLog, file_start: LV, next_time: LV, agent_map: &mut FileAgentMap) -> Result<GraphEntrySimple, ParseError> {
+        // Problems with the parents are reported at the start of the entry.
+        let entry_start = *self;
         let len = self.next_usize()?;
-        let parents = self.read_parents(oplog, next_time, agent_map)?;
+        let parents = self.read_parents(oplog, file_start, next_time, agent_map)
+            .map_err(|e| entry_start.locate(e))?;
 
         // Bleh its gross passing a &[Time] into here when we have a Frontier already.
         Ok(GraphEntrySimple {
@@ -150,7 +234,7 @@ impl<'a> BufReader<'a> {
 }
 
 impl<'a> ChunkReader<'a> {
-    fn read_version(&mut self, oplog: &ListOpLog, agent_map: &[(AgentId, usize)]) -> Result<Frontier, ParseError> {
+    fn read_version(&mut self, oplog: &ListOpLog, agent_map: &mut FileAgentMap) -> Result<Frontier, ParseError> {
         let chunk = self.read_chunk_if_eq(ListChunkType::Version)?;
         if let Some(chunk) = chunk {
             chunk.read_version(oplog, agent_map).map_err(|e| {
@@ -163,9 +247,9 @@ impl<'a> ChunkReader<'a> {
                 // properties on the oplog. But thats NYI!
 
                 // TODO: Remove this!
-                if let ParseError::InvalidRemoteID(_) = e {
-                    ParseError::DataMissing
-                } else { e }
+                if let ParseErrorKind::InvalidRemoteID(_) = e.kind {
+                    chunk.err(ParseErrorKind::DataMissing)
+                } else { chunk.locate(e) }
             })
         } else {
             // If the start_frontier chunk is missing, it means we're reading from ROOT.
@@ -173,33 +257,115 @@ impl<'a> ChunkReader<'a> {
         }
     }
 
-    fn expect_content_str(&mut self, compressed: Option<&mut BufReader<'a>>) -> Result<&'a str, ParseError> {
-        let (c, mut r) = self.expect_chunk_pred(|c| c == Content || c == ContentCompressed, Content)?;
+    /// Read a content chunk. Deduplicated content is expanded into the arena.
+    fn expect_content_str(&mut self, compressed: Option<&mut BufReader<'a>>, arena: &'a Bump) -> Result<&'a str, ParseError> {
+        let (c, mut r) = self.expect_chunk_pred(|c| c == Content || c == ContentCompressed || c == ContentDeduped, Content)?;
+
+        if c == ContentDeduped {
+            let data_type = r.next_u32()?;
+            if data_type != (DataType::PlainText as u32) {
+                return Err(r.err(ParseErrorKind::UnknownChunk));
+            }
+            let len = r.next_usize()?;
+
+            let mut r = r.chunks();
+            let literals = r.expect_plain_content_str(compressed)?;
+            expand_deduped_content(literals, r.0, len, arena)
+        } else {
+            Self::finish_plain_content_str(c, r, compressed)
+        }
+    }
+
+    fn expect_plain_content_str(&mut self, compressed: Option<&mut BufReader<'a>>) -> Result<&'a str, ParseError> {
+        let (c, r) = self.expect_chunk_pred(|c| c == Content || c == ContentCompressed, Content)?;
+        Self::finish_plain_content_str(c, r, compressed)
+    }
 
+    fn finish_plain_content_str(c: ListChunkType, mut r: BufReader<'a>, compressed: Option<&mut BufReader<'a>>) -> Result<&'a str, ParseError> {
         if c == Content {
             // Just read the string straight out.
             r.into_content_str()
         } else {
             let data_type = r.next_u32()?;
             if data_type != (DataType::PlainText as u32) {
-                return Err(ParseError::UnknownChunk);
+                return Err(r.err(ParseErrorKind::UnknownChunk));
             }
             // The uncompressed length
             let len = r.next_usize()?;
 
-            let bytes = compressed.ok_or(ParseError::CompressedDataMissing)?
-                .next_n_bytes(len)?;
+            let compressed = compressed.ok_or_else(|| r.err(ParseErrorKind::CompressedDataMissing))?;
+            let bytes = compressed.next_n_bytes(len)?;
+
+            std::str::from_utf8(bytes).map_err(|_| compressed.err(ParseErrorKind::InvalidUTF8))
+        }
+    }
+}
+
+/// Rebuild deduplicated content from its literal bytes and the list of runs. See
+/// [`EncodeOptions::dedup_content`].
+fn expand_deduped_content<'a>(literals: &str, mut runs: BufReader, len: usize, arena: &'a Bump) -> Result<&'a str, ParseError> {
+    let literals = literals.as_bytes();
+    if len > (literals.len() + runs.len()).saturating_mul(MAX_EXPANSION) {
+        return Err(runs.err(ParseErrorKind::InvalidLength));
+    }
+
+    let mut literal_pos: usize = 0;
+    // The length is checked against the content actually produced, so the buffer grows as it's
+    // filled rather than trusting len up front.
+    let mut out: Vec<u8> = Vec::new();
 
-            std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUTF8)
+    while !runs.is_empty() {
+        let run = runs;
+        let (run_len, is_copy) = strip_bit_usize(runs.next_usize()?);
+        if run_len > len - out.len() {
+            return Err(run.err(ParseErrorKind::InvalidLength));
+        }
+
+        if is_copy {
+            let offset = runs.next_usize()?;
+            let end = offset.checked_add(run_len)
+                .ok_or_else(|| run.err(ParseErrorKind::InvalidLength))?;
+            if end > out.len() {
+                return Err(run.err(ParseErrorKind::InvalidContent));
+            }
+            out.extend_from_within(offset..end);
+        } else {
+            let end = literal_pos.checked_add(run_len)
+                .ok_or_else(|| run.err(ParseErrorKind::InvalidLength))?;
+            let bytes = literals.get(literal_pos..end)
+                .ok_or_else(|| run.err(ParseErrorKind::InvalidLength))?;
+            out.extend_from_slice(bytes);
+            literal_pos = end;
         }
     }
 
-    fn read_fileinfo(&mut self, oplog: &mut ListOpLog) -> Result<FileInfoData, ParseError> {
-        let mut fileinfo = self.expect_chunk(ListChunkType::FileInfo)?.chunks();
+    if out.len() != len || literal_pos != literals.len() {
+        return Err(runs.err(ParseErrorKind::InvalidLength));
+    }
+
+    let s = std::str::from_utf8(&out).map_err(|_| runs.err(ParseErrorKind::InvalidUTF8))?;
+    Ok(arena.alloc_str(s))
+}
+
+impl<'a> ChunkReader<'a> {
+
+    /// Read the contents of the FileInfo chunk.
+    fn read_fileinfo(mut self, opts: &DecodeOptions) -> Result<FileInfoData<'a>, ParseError> {
+        let fileinfo = &mut self;
 
         let doc_id = fileinfo.read_chunk_if_eq(ListChunkType::DocId)?;
-        let mut agent_names_chunk = fileinfo.expect_chunk(ListChunkType::AgentNames)?;
-        let userdata = fileinfo.read_chunk_if_eq(ListChunkType::UserData)?;
+        let agent_names_chunk = fileinfo.expect_chunk(ListChunkType::AgentNames)?;
+        let userdata = fileinfo.read_chunk_if_eq(ListChunkType::UserData)?.map(|r| r.buf);
+        let inserted_bytes = if let Some(mut usage) = fileinfo.read_chunk_if_eq(ListChunkType::Usage)? {
+            Some(usage.next_usize()?)
+        } else { None };
+
+        // Files we can't read correctly are rejected before anything else is done with them.
+        let capabilities_chunk = fileinfo.read_chunk_if_eq(ListChunkType::Capabilities)?;
+        let (required_capabilities, optional_capabilities) = read_capabilities(capabilities_chunk)?;
+        if let Some(c) = required_capabilities.unknown().next() {
+            return Err(capabilities_chunk.unwrap().err(ParseErrorKind::UnsupportedCapability(c)));
+        }
 
         let doc_id = if let Some(doc_id) = doc_id {
             Some(doc_id.into_content_str()?)
@@ -210,36 +376,49 @@ impl<'a> ChunkReader<'a> {
         // This will usually just be 0,1,2,3,4...
         //
         // 0 implicitly maps to ROOT.
-        // let mut file_to_self_agent_map = vec![(ROOT_AGENT, 0)];
-        let mut agent_map = Vec::new();
-        while !agent_names_chunk.0.is_empty() {
-            let name = agent_names_chunk.next_str()?;
-            let id = oplog.get_or_create_agent_id(name);
-            agent_map.push((id, 0));
-        }
+        let agent_map = FileAgentMap::parse(agent_names_chunk, opts)?;
 
         Ok(FileInfoData {
             userdata,
             doc_id,
             agent_map,
+            inserted_bytes,
+            required_capabilities,
+            optional_capabilities,
         })
     }
 }
 
+/// Read a Capabilities chunk as (required, optional). A missing chunk means the file doesn't list
+/// any capabilities.
+fn read_capabilities(chunk: Option<BufReader>) -> Result<(CapabilitySet, CapabilitySet), ParseError> {
+    let Some(mut chunk) = chunk else { return Ok(Default::default()); };
+    let required = CapabilitySet(chunk.next_u64()?);
+    let optional = CapabilitySet(chunk.next_u64()?);
+    Ok((required, optional))
+}
+
 
 // Returning a tuple was getting too unwieldy.
 #[derive(Debug)]
 struct FileInfoData<'a> {
-    userdata: Option<BufReader<'a>>,
+    userdata: Option<&'a [u8]>,
     doc_id: Option<&'a str>,
-    agent_map: Vec<(AgentId, usize)>,
+    agent_map: FileAgentMap,
+    /// The size of the inserted content, stored when the content itself isn't.
+    inserted_bytes: Option<usize>,
+    required_capabilities: CapabilitySet,
+    optional_capabilities: CapabilitySet,
 }
 
 
 /// Returns (mapped span, remainder).
 /// The returned remainder is *NOT MAPPED*. This allows this method to be called in a loop.
-fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map: &RleVec<KVPair<DTRange>>) -> (GraphEntrySimple, Option<GraphEntrySimple>) {
-    let (map_entry, offset) = version_map.find_packed_with_offset(hist_entry.span.start);
+///
+/// Corrupt files can describe history for operations which were never read. Thats an error.
+fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map: &RleVec<KVPair<DTRange>>) -> Result<(GraphEntrySimple, Option<GraphEntrySimple>), ParseErrorKind> {
+    let (map_entry, offset) = version_map.find_with_offset(hist_entry.span.start)
+        .ok_or(ParseErrorKind::InvalidLength)?;
 
     let mut map_entry = map_entry.1;
     map_entry.truncate_keeping_right(offset);
@@ -257,7 +436,8 @@ fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map:
     // const UNDERWATER_LAST: usize = ROOT_TIME - 1;
     for p in hist_entry.parents.0.iter_mut() {
         if *p >= UNDERWATER_START {
-            let (span, offset) = version_map.find_packed_with_offset(*p);
+            let (span, offset) = version_map.find_with_offset(*p)
+                .ok_or(ParseErrorKind::InvalidParent)?;
             *p = span.1.start + offset;
         }
     }
@@ -265,7 +445,7 @@ fn history_entry_map_and_truncate(mut hist_entry: GraphEntrySimple, version_map:
     // Parents can become unsorted here because they might not map cleanly. Thanks, fuzzer.
     sort_frontier(&mut hist_entry.parents.0);
 
-    (hist_entry, remainder)
+    Ok((hist_entry, remainder))
 }
 
 // I could just pass &mut last_cursor_pos to a flat read() function. Eh. Once again, generators
@@ -311,16 +491,20 @@ impl<'a> ReadPatchesIter<'a> {
         };
 
         // dbg!(self.last_cursor_pos, diff);
-        let raw_start = isize::wrapping_add(self.last_cursor_pos as isize, diff) as usize;
+        // Corrupt data can name positions which overflow.
+        let raw_start = self.last_cursor_pos.checked_add_signed(diff).ok_or(ParseErrorKind::InvalidLength)?;
 
         let (start, raw_end) = match (tag, fwd) {
-            (Ins, true) => (raw_start, raw_start + len),
+            (Ins, true) => (raw_start, raw_start.checked_add(len).ok_or(ParseErrorKind::InvalidLength)?),
             (Ins, false) | (Del, true) => (raw_start, raw_start), // Weird symmetry!
-            (Del, false) => (raw_start - len, raw_start - len),
+            (Del, false) => {
+                let start = raw_start.checked_sub(len).ok_or(ParseErrorKind::InvalidLength)?;
+                (start, start)
+            },
         };
         // dbg!((raw_start, tag, fwd, len, start, raw_end));
 
-        let end = start + len;
+        let end = start.checked_add(len).ok_or(ParseErrorKind::InvalidLength)?;
 
         // dbg!(pos);
         self.last_cursor_pos = raw_end;
@@ -341,7 +525,10 @@ impl<'a> Iterator for ReadPatchesIter<'a> {
     type Item = Result<ListOpMetrics, ParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.buf.is_empty() { None } else { Some(self.next_internal()) }
+        if self.buf.is_empty() { None } else {
+            let op_start = self.buf;
+            Some(self.next_internal().map_err(|e| op_start.locate(e)))
+        }
     }
 }
 
@@ -381,15 +568,15 @@ impl<'a> HasLength for ContentItem<'a> {
 }
 
 impl<'a> ReadPatchContentIter<'a> {
-    fn new(mut chunk: BufReader<'a>, compressed: Option<&mut BufReader<'a>>) -> Result<(ListOpKind, Self), ParseError> {
+    fn new(mut chunk: BufReader<'a>, compressed: Option<&mut BufReader<'a>>, arena: &'a Bump) -> Result<(ListOpKind, Self), ParseError> {
         let tag = match chunk.next_u32()? {
             0 => Ins,
             1 => Del,
-            _ => { return Err(ParseError::InvalidContent); }
+            _ => { return Err(chunk.err(ParseErrorKind::InvalidContent)); }
         };
 
         let mut chunk = chunk.chunks();
-        let content = chunk.expect_content_str(compressed)?;
+        let content = chunk.expect_content_str(compressed, arena)?;
 
         let run_chunk = chunk.expect_chunk(ContentIsKnown)?;
 
@@ -397,13 +584,14 @@ impl<'a> ReadPatchContentIter<'a> {
     }
 
     fn next_internal(&mut self) -> Result<ContentItem<'a>, ParseError> {
+        let run = self.run_chunk;
         let n = self.run_chunk.next_usize()?;
         let (len, known) = strip_bit_usize(n);
         let content = if known {
             let content = consume_chars(&mut self.content, len);
             if count_chars(content) != len { // Having a duplicate strlen here is gross.
                 // We couldn't pull as many chars as requested from self.content.
-                return Err(ParseError::UnexpectedEOF);
+                return Err(run.err(ParseErrorKind::UnexpectedEOF));
             }
             Some(content)
         } else { None };
@@ -419,7 +607,7 @@ impl<'a> Iterator for ReadPatchContentIter<'a> {
         match (self.run_chunk.is_empty(), self.content.is_empty()) {
             (false, _) => Some(self.next_internal()),
             (true, true) => None,
-            (true, false) => Some(Err(ParseError::UnexpectedEOF)),
+            (true, false) => Some(Err(self.run_chunk.err(ParseErrorKind::UnexpectedEOF))),
         }
     }
 }
@@ -427,38 +615,296 @@ impl<'a> Iterator for ReadPatchContentIter<'a> {
 
 #[derive(Debug, Clone)]
 pub struct DecodeOptions {
-    /// Ignore CRC check failures. This is mostly used for debugging.
+    /// Ignore CRC check failures. This is useful for recovering what's left of a damaged file.
+    /// Damage which breaks the structure of the file is still reported (with a more specific
+    /// error than [`ParseErrorKind::ChecksumFailed`]), but damaged content will load as-is.
+    ///
+    /// When the CRC is checked, it's checked before anything is added to the oplog.
     pub ignore_crc: bool,
 
     pub verbose: bool,
+
+    /// The maximum number of agents the file can list. The names are counted as they're read, and
+    /// decoding stops with [`ParseErrorKind::TooManyAgents`] at the first name past the limit.
+    /// Names the oplog already knows count too.
+    pub max_agents: usize,
+
+    /// The maximum size (in bytes) of the file's list of agent names.
+    pub max_agent_name_bytes: usize,
 }
 
-#[allow(clippy::derivable_impls)]
 impl Default for DecodeOptions {
     fn default() -> Self {
         Self {
             ignore_crc: false,
             verbose: false,
+            max_agents: 1 << 20,
+            max_agent_name_bytes: 32 << 20,
+        }
+    }
+}
+
+/// What was added to an oplog by [`ListOpLog::merge_data`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeStats {
+    /// The version of the merged data. See [`decode_and_add`](ListOpLog::decode_and_add).
+    pub version: Frontier,
+
+    /// The oplog's own version after merging.
+    pub oplog_version: Frontier,
+
+    /// The number of operations added to the oplog. Operations the oplog already had aren't
+    /// counted.
+    pub new_operations: usize,
+
+    /// The local versions of the added operations. New operations are always appended to the
+    /// oplog, so this is the range from the oplog's old length to its new length. It's empty if
+    /// nothing was added.
+    pub new_range: DTRange,
+
+    /// The number of agents added to the oplog. New agents are appended too, so they're the last
+    /// `new_agents` agent IDs.
+    pub new_agents: usize,
+
+    /// The user data stored in the merged file, if it differs from the oplog's own. The oplog
+    /// keeps its local user data when they conflict. See [`user_data`](ListOpLog::user_data).
+    pub incoming_user_data: Option<Vec<u8>>,
+}
+
+/// Walk through the file's top level chunks before anything is merged. This rejects truncated
+/// files, and checks the CRC chunk and any chunk CRCs (if the file has them and check_crc is set).
+/// `reader` starts at the first chunk after the file header.
+fn validate_chunks(data: &[u8], mut reader: ChunkReader, check_crc: bool) -> Result<(), ParseError> {
+    // The bytes of the last chunk read, and a reader for its body to report errors with.
+    let mut last_chunk: Option<(&[u8], BufReader)> = None;
+
+    while !reader.is_empty() {
+        // The checksum covers everything before the CRC chunk.
+        let reader_len = reader.0.len();
+        let chunk = reader.next_chunk_raw();
+        let chunk_bytes = &data[data.len() - reader_len..data.len() - reader.0.len()];
+        match chunk {
+            Ok((ListChunkType::Crc, crc_reader)) if check_crc => {
+                let expected = crc_reader.clone().next_u32_le()?;
+                let checksummed_data = &data[..data.len() - reader_len];
+                let actual = calc_checksum(checksummed_data);
+                if actual != expected {
+                    return Err(crc_reader.err(ParseErrorKind::ChecksumFailed(ChecksumMismatch { expected, actual })));
+                }
+            }
+            Ok((ListChunkType::ChunkCrc, crc_reader)) => {
+                if let (Some((bytes, body)), true) = (last_chunk.take(), check_crc) {
+                    let expected = crc_reader.clone().next_u32_le()?;
+                    let actual = calc_checksum(bytes);
+                    if actual != expected {
+                        return Err(body.err(ParseErrorKind::ChecksumFailed(ChecksumMismatch { expected, actual })));
+                    }
+                }
+                continue;
+            }
+            Ok((_, body)) => { last_chunk = Some((chunk_bytes, body)); continue; }
+            Err(e) if e.kind == ParseErrorKind::UnknownChunk => {}
+            Err(e) => { return Err(e); }
         }
+        last_chunk = None;
     }
+
+    Ok(())
+}
+
+/// Check an encoded file's structure and CRC without decoding it. `start` is the file's offset in
+/// the data being decoded.
+fn check_file(data: &[u8], start: usize) -> Result<(), ParseError> {
+    let mut reader = BufReader::at(data, start);
+    reader.read_magic()?;
+    reader.next_usize()?;
+    validate_chunks(data, reader.chunks(), true)
+}
+
+/// The length of the encoded file at the start of data, up to and including its CRC chunk. Returns
+/// None if the file has no CRC chunk, or it can't be found.
+fn first_file_len(data: &[u8]) -> Option<usize> {
+    let mut reader = BufReader::new(data);
+    reader.read_magic().ok()?;
+    reader.next_usize().ok()?;
+    let mut chunks = reader.chunks();
+    while !chunks.is_empty() {
+        match chunks.next_chunk_raw() {
+            Ok((ListChunkType::Crc, _)) => { return Some(data.len() - chunks.0.len()); }
+            Ok(_) => {}
+            Err(e) if e.kind == ParseErrorKind::UnknownChunk => {}
+            Err(_) => { return None; }
+        }
+    }
+    None
+}
+
+/// The encoded files which make up some data. See [`split_segments`].
+pub(super) struct Segments<'a> {
+    /// Each encoded file, with its offset in the data.
+    pub files: Vec<(usize, &'a [u8])>,
+    /// The length of the data covered by the files. Anything after this is an interrupted write.
+    pub len: usize,
+}
+
+/// Split data into the encoded file it starts with, and any segments appended after it with
+/// [`AppendableFile`](super::AppendableFile). Each appended segment is [`SEGMENT_MAGIC_BYTES`],
+/// the segment's length, then another encoded file.
+///
+/// Appends only ever add one segment at a time to the end of the data. So if the last segment is
+/// incomplete (its cut short or fails its CRC check, or its start was never written and reads as
+/// zeros), its assumed to be a write which was interrupted and it's left out. The CRC chunk is
+/// always the last chunk in an encoded file, so this never cuts off part of the first file.
+///
+/// Anything else is damage. A bad segment with more segments after it, or trailing data which
+/// doesn't look like the start of a segment, is an error.
+pub(super) fn split_segments(data: &[u8]) -> Result<Segments<'_>, ParseError> {
+    let Some(first_len) = first_file_len(data) else {
+        return Ok(Segments { files: vec![(0, data)], len: data.len() });
+    };
+
+    let mut result = Segments { files: vec![(0, &data[..first_len])], len: first_len };
+    while result.len < data.len() {
+        match read_segment(data, result.len) {
+            Ok((start, file)) => {
+                result.files.push((start, file));
+                result.len = start + file.len();
+            }
+            Err(e) => {
+                let tail = &data[result.len..];
+                let looks_torn = tail[0] == 0
+                    || SEGMENT_MAGIC_BYTES.starts_with(&tail[..tail.len().min(SEGMENT_MAGIC_BYTES.len())]);
+                if !looks_torn || has_later_segment(data, result.len + 1) { return Err(e); }
+                break;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Read the appended segment which starts at pos. Returns the offset of the segment's encoded file
+/// and the file itself. The last segment in the data is checked against its CRC here, to tell if
+/// it was completely written. Earlier segments are checked when they're decoded.
+fn read_segment(data: &[u8], pos: usize) -> Result<(usize, &[u8]), ParseError> {
+    let reader = BufReader::at(&data[pos..], pos);
+    let rest = reader.buf.strip_prefix(&SEGMENT_MAGIC_BYTES)
+        .ok_or_else(|| reader.err(ParseErrorKind::InvalidMagic))?;
+    let mut reader = BufReader::at(rest, data.len() - rest.len());
+    let len = reader.next_usize()?;
+    let file = reader.next_n_bytes(len)?;
+    let start = data.len() - reader.len() - file.len();
+    if reader.is_empty() { check_file(file, start)?; }
+    Ok((start, file))
+}
+
+/// Check if there's a valid segment anywhere in data after pos.
+fn has_later_segment(data: &[u8], pos: usize) -> bool {
+    (pos..data.len()).any(|p| data[p..].starts_with(&SEGMENT_MAGIC_BYTES) && read_segment(data, p).is_ok())
 }
 
 impl ListOpLog {
+    /// Load an oplog from encoded data. The data can also be a file which has had segments
+    /// appended to it by [`AppendableFile`](super::AppendableFile). The segments are merged in
+    /// order, and an interrupted append at the end of the file is ignored.
     pub fn load_from(data: &[u8]) -> Result<Self, ParseError> {
         let mut oplog = Self::new();
-        oplog.decode_internal(data, DecodeOptions::default())?;
+        oplog.decode_internal(data, DecodeOptions::default(), false)?;
         Ok(oplog)
     }
 
     pub fn load_from_opts(data: &[u8], opts: DecodeOptions) -> Result<Self, ParseError> {
         let mut oplog = Self::new();
-        oplog.decode_internal(data, opts)?;
+        oplog.decode_internal(data, opts, false)?;
+        Ok(oplog)
+    }
+
+    /// Load an oplog, like [`load_from`](OpLog::load_from), from data which the oplog is allowed
+    /// to keep. Inserted and deleted content is referenced from `data` in place rather than being
+    /// copied out of it. (Compressed content is always referenced from the decompressed data, even
+    /// when loading with `load_from`.)
+    ///
+    /// The oplog holds on to all of `data` while it references any of it. Adding more content to
+    /// the oplog copies the referenced content into a buffer of its own, and `data` is released
+    /// once nothing references it.
+    pub fn load_from_shared(data: Arc<Vec<u8>>) -> Result<Self, ParseError> {
+        let mut oplog = Self::new();
+        oplog.decode_internal_in(&data, DecodeOptions::default(), false, ContentArena::with_shared(data.clone()))?;
+        Ok(oplog)
+    }
+
+    /// Load a file which may have segments appended to it. Returns the oplog and the length of the
+    /// data it was loaded from. An interrupted append at the end of the data is left out (see
+    /// [`split_segments`]), so this can be shorter than data.
+    pub(crate) fn load_segments(data: &[u8]) -> Result<(Self, usize), ParseError> {
+        let Segments { files, len } = split_segments(data)?;
+        let mut oplog = Self::new();
+        oplog.decode_files(&files, DecodeOptions::default(), false, &ContentArena::default())?;
+        Ok((oplog, len))
+    }
+
+    /// Load an oplog from a stream, like [`load_from`](OpLog::load_from). The file is read one
+    /// chunk at a time, so it doesn't need to be read into memory before decoding starts. The
+    /// reader is read in small pieces, so wrap files in a [`std::io::BufReader`].
+    ///
+    /// Parse errors are returned as [`io::ErrorKind::InvalidData`] errors wrapping the
+    /// [`ParseError`]. Unlike `load_from`, the file isn't validated up front, so a truncated file
+    /// or a bad checksum is only noticed when the decoder reaches it.
+    pub fn load_from_reader<R: Read>(reader: R) -> io::Result<Self> {
+        Self::load_from_reader_opts(reader, DecodeOptions::default())
+    }
+
+    pub fn load_from_reader_opts<R: Read>(mut reader: R, opts: DecodeOptions) -> io::Result<Self> {
+        let mut oplog = Self::new();
+        oplog.decode_internal_from_reader(&mut reader, opts)?;
         Ok(oplog)
     }
 
+    /// Load an oplog from a binary chunk, and check out the document at the oplog's version. This
+    /// returns the same oplog, content and version as calling [`load_from`](OpLog::load_from)
+    /// followed by [`checkout_tip`](OpLog::checkout_tip).
+    ///
+    /// Any content stored at the end of the file is ignored, and the document is always checked
+    /// out from the operations. See [`decode_and_checkout_trusted`](OpLog::decode_and_checkout_trusted).
+    pub fn decode_and_checkout(data: &[u8]) -> Result<(Self, String, Frontier), ParseError> {
+        let oplog = Self::load_from(data)?;
+        let content = oplog.checkout_tip().content.to_string();
+        let version = oplog.cg.version.clone();
+        Ok((oplog, content, version))
+    }
+
+    /// Like [`decode_and_checkout`](OpLog::decode_and_checkout), but if the file stores the
+    /// document's content at the end of the file (written with
+    /// `experimentally_store_end_branch_content`), that content is returned directly and the
+    /// operations don't need to be merged at all. This only happens when the file's CRC covers the
+    /// content and the content's version matches the loaded oplog.
+    ///
+    /// The CRC only catches damage. The stored content isn't checked against the operations, so a
+    /// file can claim any content at all. Only use this for files you wrote yourself.
+    pub fn decode_and_checkout_trusted(data: &[u8]) -> Result<(Self, String, Frontier), ParseError> {
+        let (oplog, end_content) = Self::load_with_end_content(data)?;
+        let content = end_content.unwrap_or_else(|| oplog.checkout_tip().content.to_string());
+        let version = oplog.cg.version.clone();
+        Ok((oplog, content, version))
+    }
+
+    /// Load an oplog, and the document's content at the oplog's version if the file stores it.
+    pub(crate) fn load_with_end_content(data: &[u8]) -> Result<(Self, Option<String>), ParseError> {
+        let mut oplog = Self::new();
+        let (_, end_content) = oplog.decode_internal(data, DecodeOptions::default(), true)?;
+        Ok((oplog, end_content))
+    }
+
     /// Add all operations from a binary chunk into this document.
     ///
-    /// Any duplicate operations are ignored.
+    /// Any duplicate operations are ignored. Merging is idempotent: merging the same data twice,
+    /// or merging an oplog's own encoded data back into it, leaves the oplog unchanged. When the
+    /// data partially overlaps with the local oplog, only the operations which aren't known
+    /// locally are added.
+    ///
+    /// If the data fails to load (for example because its base version is unknown), or merging it
+    /// would exceed the limits set with [`set_limits`](OpLog::set_limits), the oplog is left
+    /// unmodified.
     ///
     /// This method is a convenience method for calling
     /// [`oplog.decode_and_add_opts(data, DecodeOptions::default())`](OpLog::decode_and_add_opts).
@@ -466,6 +912,40 @@ impl ListOpLog {
         self.decode_and_add_opts(data, DecodeOptions::default())
     }
 
+    /// Merge a binary chunk into this document, like [`decode_and_add`](OpLog::decode_and_add).
+    /// Returns what was added.
+    ///
+    /// Merging is atomic. Either all of the data is merged, or (if an error is returned) the oplog
+    /// is left exactly as it was.
+    pub fn merge_data(&mut self, data: &[u8]) -> Result<MergeStats, ParseError> {
+        let len = self.len();
+        let num_agents = self.cg.agent_assignment.client_data.len();
+
+        // Set our user data aside while decoding, so the file's user data is read into self.
+        let local_user_data = self.user_data.take();
+        let result = self.decode_and_add(data);
+        let file_user_data = std::mem::replace(&mut self.user_data, local_user_data);
+        let version = result?;
+
+        let incoming_user_data = match (&self.user_data, file_user_data) {
+            (None, file_user_data) => {
+                self.user_data = file_user_data;
+                None
+            }
+            (Some(local), Some(file_user_data)) if *local != file_user_data => Some(file_user_data),
+            _ => None,
+        };
+
+        Ok(MergeStats {
+            version,
+            oplog_version: self.cg.version.clone(),
+            new_operations: self.len() - len,
+            new_range: (len..self.len()).into(),
+            new_agents: self.cg.agent_assignment.client_data.len() - num_agents,
+            incoming_user_data,
+        })
+    }
+
     /// Add all operations from a binary chunk into this document.
     ///
     /// If successful, returns the version of the loaded data (which could be different from the
@@ -477,7 +957,7 @@ impl ListOpLog {
         // In order to merge data safely, when an error happens we need to unwind all the merged
         // operations before returning. Otherwise self is in an invalid state.
         //
-        // The merge_data method is append-only, so really we just need to trim back all the data
+        // decode_internal is append-only, so really we just need to trim back all the data
         // that has been (partially) added.
 
         // Total (unmerged) number of operations before this data is merged in.
@@ -485,18 +965,28 @@ impl ListOpLog {
 
         // We could regenerate the frontier, but this is much lazier.
         let doc_id = self.doc_id.clone();
+        let user_data = self.user_data.clone();
         let old_frontier = self.cg.version.clone();
         let num_known_agents = self.cg.agent_assignment.client_data.len();
         let ins_content_length = self.operation_ctx.ins_content.len();
         let del_content_length = self.operation_ctx.del_content.len();
-
-        let result = self.decode_internal(data, opts);
+        let usage = self.usage();
+
+        let mut result = self.decode_internal(data, opts, false).map(|(v, _)| v);
+        if result.is_ok() {
+            // The data is merged before we can tell how big it is. If its too big, its unwound
+            // below like any other error.
+            if let Err(e) = self.limits.check_growth(usage, self.usage()) {
+                result = Err(ParseErrorKind::LimitExceeded(e).into());
+            }
+        }
 
         if result.is_err() {
             // Unwind changes back to len.
             // This would be nicer with an RleVec iterator, but the iter implementation doesn't
             // support iterating backwards.
             self.doc_id = doc_id;
+            self.user_data = user_data;
 
             while let Some(last) = self.cg.agent_assignment.client_with_localtime.0.last_mut() {
                 debug_assert!(len <= last.end());
@@ -519,6 +1009,8 @@ impl ListOpLog {
             if num_operations > len {
                 self.operations.remove_ctx((len..num_operations).into(), &self.operation_ctx);
             }
+            self.checkpoints.split_off(&len);
+            self.truncate_timestamps(len);
 
             // Trim history
             let hist_entries = &mut self.cg.graph.entries;
@@ -575,8 +1067,12 @@ impl ListOpLog {
 
             self.operation_ctx.ins_content.truncate(ins_content_length);
             self.operation_ctx.del_content.truncate(del_content_length);
+            self.inserted_bytes = usage.inserted_bytes;
 
             self.cg.version = old_frontier;
+        } else {
+            self.set_origin((len..self.len()).into(), Origin::Remote);
+            if !self.pending.is_empty() { self.merge_pending(); }
         }
 
         result
@@ -584,12 +1080,37 @@ impl ListOpLog {
 
     /// Merge data from the remote source into our local document state.
     ///
-    /// NOTE: This code is quite new.
-    /// TODO: Currently if this method returns an error, the local state is undefined & invalid.
-    /// Until this is fixed, the signature of the method will stay kinda weird to prevent misuse.
-    fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions) -> Result<Frontier, ParseError> {
+    /// Truncated files and bad checksums are found before anything is merged. But other errors can
+    /// be found partway through, leaving the oplog partially modified. decode_and_add_opts unwinds
+    /// the changes when that happens.
+    ///
+    /// If read_end_content is set and the file stores the document's content at the oplog's
+    /// resulting version, that content is returned as well.
+    fn decode_internal(&mut self, data: &[u8], opts: DecodeOptions, read_end_content: bool) -> Result<(Frontier, Option<String>), ParseError> {
+        // Deduplicated content is expanded into here. It needs to outlive the reader.
+        self.decode_internal_in(data, opts, read_end_content, ContentArena::default())
+    }
+
+    fn decode_internal_in(&mut self, data: &[u8], opts: DecodeOptions, read_end_content: bool, content_arena: ContentArena) -> Result<(Frontier, Option<String>), ParseError> {
+        let Segments { files, .. } = split_segments(data)?;
+        self.decode_files(&files, opts, read_end_content, &content_arena)
+    }
+
+    /// Decode each of the encoded files in a segmented file in order. The returned version and
+    /// end content come from the last file.
+    fn decode_files<'a>(&mut self, files: &[(usize, &'a [u8])], opts: DecodeOptions, read_end_content: bool, content_arena: &'a ContentArena) -> Result<(Frontier, Option<String>), ParseError> {
+        let mut result = (Frontier::root(), None);
+        for (i, &(start, file)) in files.iter().enumerate() {
+            let is_last = i == files.len() - 1;
+            result = self.decode_file(file, start, opts.clone(), read_end_content && is_last, content_arena)?;
+        }
+        Ok(result)
+    }
+
+    /// Decode one encoded file. `start` is its offset in the data being decoded.
+    fn decode_file<'a>(&mut self, data: &'a [u8], start: usize, opts: DecodeOptions, read_end_content: bool, content_arena: &'a ContentArena) -> Result<(Frontier, Option<String>), ParseError> {
         // Written to be symmetric with encode functions.
-        let mut reader = BufReader(data);
+        let mut reader = BufReader::at(data, start);
 
         let verbose = ALLOW_VERBOSE && opts.verbose;
         if verbose {
@@ -597,72 +1118,164 @@ impl ListOpLog {
         }
 
         reader.read_magic()?;
+        let version_reader = reader;
         let protocol_version = reader.next_usize()?;
-        if protocol_version != PROTOCOL_VERSION {
-            return Err(ParseError::UnsupportedProtocolVersion);
+        if !protocol_version_supported(protocol_version) {
+            return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
         }
 
         // The rest of the file is made of chunks!
-        let mut reader = reader.chunks();
+        let reader = reader.chunks();
 
-        // *** Compressed data ***
-        // If there is a compressed chunk, it can contain data for other fields, all mushed
-        // together.
-        let mut compressed_chunk;
+        validate_chunks(data, reader.clone(), !opts.ignore_crc)?;
 
-        #[cfg(not(feature = "lz4"))] {
-            compressed_chunk = None;
-            if reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?.is_some() {
-                return Err(ParseError::LZ4DecoderNeeded);
-            }
+        self.decode_chunks(&mut TopLevelChunks::Slice(reader), opts, read_end_content, content_arena)
+    }
+
+    /// Read the version the patches in a file start from, by agent name and seq. Unlike
+    /// decode_header this doesn't need the oplog to know about the named operations, so it can
+    /// tell us what we're missing when data can't be merged yet.
+    pub(super) fn read_start_version(data: &[u8]) -> Result<Vec<RemoteVersionOwned>, ParseError> {
+        let mut reader = BufReader::new(data);
+        reader.read_magic()?;
+        let version_reader = reader;
+        if !protocol_version_supported(reader.next_usize()?) {
+            return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
         }
+        let mut reader = reader.chunks();
 
-        let _compressed_chunk_raw: Option<Vec<u8>>; // Pulled out so its lifetime escapes the block.
-        #[cfg(feature = "lz4")] {
-            _compressed_chunk_raw = if let Some(mut c) = reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)? {
-                let uncompressed_len = c.next_usize()?;
+        // Versions are never compressed, so the compressed chunk (if any) can be skipped.
+        reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?;
+        let FileInfoData { agent_map, .. } = reader.expect_chunk(ListChunkType::FileInfo)?
+            .chunks().read_fileinfo(&DecodeOptions::default())?;
 
-                // The rest of the bytes contain lz4 compressed data.
-                let data = lz4_flex::decompress(c.0, uncompressed_len)
-                    .map_err(|_e| ParseError::LZ4DecompressionError)?;
-                Some(data)
-            } else { None };
+        if reader.read_chunk_if_eq(ListChunkType::Snapshot)?.is_some() {
+            return Err(ParseErrorKind::SnapshotData.into());
+        }
+        let mut start_branch = reader.expect_chunk(ListChunkType::StartBranch)?.chunks();
+        read_remote_version(start_branch.read_chunk_if_eq(ListChunkType::Version)?, &agent_map)
+    }
 
-            // To consume from compressed_chunk_raw, we'll make a slice that we can iterate through.
-            compressed_chunk = _compressed_chunk_raw.as_ref().map(|b| BufReader(b));
+    /// Like decode_internal, but the file is read from a stream. The file isn't validated up
+    /// front, so truncated files and bad checksums are only found when we get to them.
+    ///
+    /// Any IO error (other than EOF) is returned in place of the parse error it causes.
+    fn decode_internal_from_reader(&mut self, reader: &mut dyn Read, opts: DecodeOptions) -> Result<Frontier, io::Error> {
+        let content_arena = ContentArena::default();
+        let mut chunks = TopLevelChunks::Stream(StreamChunkReader::new(reader, !opts.ignore_crc));
+
+        let result = chunks.read_stream_header()
+            .and_then(|()| self.decode_chunks(&mut chunks, opts, false, &content_arena));
+
+        match result {
+            Ok((frontier, _)) => Ok(frontier),
+            Err(e) => Err(chunks.take_io_error().unwrap_or_else(|| {
+                let kind = if e == ParseErrorKind::UnexpectedEOF { io::ErrorKind::UnexpectedEof } else { io::ErrorKind::InvalidData };
+                io::Error::new(kind, e)
+            })),
         }
+    }
+
+    /// Decode the file's top level chunks into self.
+    fn decode_chunks<'a>(&mut self, reader: &mut TopLevelChunks<'a, '_>, opts: DecodeOptions, read_end_content: bool, content_arena: &'a ContentArena) -> Result<(Frontier, Option<String>), ParseError> {
+        let DecodeHeader { mut patches, sources, end_branch } = self.decode_header(reader, &opts, content_arena)?;
+
+        while !patches.step(self, &sources, usize::MAX)? {}
+        let (file_frontier, mut agent_map) = patches.finish(self, &sources)?;
+
+        // The CRC was checked (by validate_chunks) before anything was merged.
+        let has_crc = reader.read_chunk_if_eq(ListChunkType::Crc)?.is_some() && !opts.ignore_crc;
+
+        // self.frontier = end_frontier_chunk.read_full_frontier(&self)?;
+
+        // The end content isn't checked against the operations, so its only used if the CRC covers
+        // it. And its only the checked out document if nothing else was merged in.
+        let end_content = match end_branch {
+            Some((version_chunk, content)) if read_end_content && has_crc => {
+                let end_version = match version_chunk {
+                    Some(chunk) => chunk.read_version(self, &mut agent_map)?,
+                    None => Frontier::root(),
+                };
+                if end_version == self.cg.version { Some(content.to_string()) } else { None }
+            }
+            _ => None,
+        };
+
+        Ok((file_frontier, end_content))
+    }
+
+    /// Read everything in the file up to the operations themselves. The operations are decoded
+    /// by the returned [`PatchDecoder`].
+    fn decode_header<'a>(&mut self, reader: &mut TopLevelChunks<'a, '_>, opts: &DecodeOptions, content_arena: &'a ContentArena) -> Result<DecodeHeader<'a>, ParseError> {
+        // *** Compressed data ***
+        // If there is a compressed chunk, it can contain data for other fields, all mushed
+        // together.
+        let mut compressed_chunk = {
+            let chunk = reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?;
+            decompress_fields(chunk.as_ref().map(Chunk::reader), content_arena)?
+        };
 
         // *** FileInfo ***
         // fileinfo has DocID, UserData and AgentNames.
         // The agent_map is a map from agent_id in the file to agent_id in self.
+        let file_info = reader.expect_chunk(ListChunkType::FileInfo)?;
         let FileInfoData {
-            userdata: _userdata, doc_id, mut agent_map,
-        } = reader.read_fileinfo(self)?;
+            userdata, doc_id, mut agent_map, inserted_bytes: file_inserted_bytes, ..
+        } = file_info.reader().chunks().read_fileinfo(opts)?;
 
         // If we already have a doc_id, make sure they match before merging.
         if let Some(file_doc_id) = doc_id {
             if let Some(local_doc_id) = self.doc_id.as_ref() {
                 if file_doc_id != local_doc_id && !self.is_empty() {
-                    return Err(ParseError::DocIdMismatch);
+                    return Err(ParseErrorKind::DocIdMismatch.into());
                 }
             }
             self.doc_id = Some(file_doc_id.into());
         }
 
+        // User data is only taken from the file if we don't have our own. See merge_data.
+        if self.user_data.is_none() {
+            self.user_data = userdata.map(|data| data.to_vec());
+        }
+
+        // Snapshots have no operations to load.
+        if reader.read_chunk_if_eq(ListChunkType::Snapshot)?.is_some() {
+            return Err(ParseErrorKind::SnapshotData.into());
+        }
+
         // *** StartBranch ***
-        let mut start_branch = reader.expect_chunk(ListChunkType::StartBranch)?.chunks();
+        let start_branch = reader.expect_chunk(ListChunkType::StartBranch)?;
+        let mut start_branch = start_branch.reader().chunks();
 
         // Start version - which if missing defaults to ROOT ([]).
-        let start_version = start_branch.read_version(self, &agent_map)?;
+        let start_version = start_branch.read_version(self, &mut agent_map)?;
 
         // The start branch also optionally contains the document content at this version. We can't
         // use it yet (NYI) but it needs to be parsed because it because it might be compressed.
         if !start_branch.is_empty() {
-            let _start_content = start_branch.expect_content_str(compressed_chunk.as_mut())?;
+            // The start branch isn't kept, so the content is read through a copy of the compressed
+            // reader (and into a scratch arena). The compressed reader is then skipped past it.
+            let mut compressed: Option<BufReader<'_>> = compressed_chunk;
+            let scratch = Bump::new();
+            let _start_content = start_branch.expect_content_str(compressed.as_mut(), &scratch)?;
+            if let (Some(c), Some(rest)) = (compressed_chunk.as_mut(), compressed) {
+                c.next_n_bytes(c.buf.len() - rest.buf.len())?;
+            }
             // dbg!(start_content);
             // TODO! Attach start_content if we're empty and start_version != ROOT.
         }
 
+        // *** ExperimentalEndBranch ***
+        // This optionally stores the document's content at the end of the file. Its version names
+        // operations in the file, so it can't be read until the patches have been merged. But the
+        // content needs to be read now, since it comes before the patches in the compressed data.
+        let end_branch = if let Some(end_branch) = reader.read_chunk_if_eq(ListChunkType::ExperimentalEndBranch)? {
+            let mut end_branch = keep_chunk(end_branch, content_arena).chunks();
+            let version_chunk = end_branch.read_chunk_if_eq(ListChunkType::Version)?;
+            let content = end_branch.expect_content_str(compressed_chunk.as_mut(), content_arena)?;
+            Some((version_chunk, content))
+        } else { None };
+
         // Usually the version data will be strictly separated. Either we're loading data into an
         // empty document, or we've been sent catchup data from a remote peer. If the data set
         // overlaps, we need to actively filter out operations & txns from that data set.
@@ -671,296 +1284,1747 @@ impl ListOpLog {
         // dbg!(patches_overlap);
 
         // *** Patches ***
-        let file_frontier = {
-            // This chunk contains the actual set of edits to the document.
-            let mut patch_chunk = reader.expect_chunk(ListChunkType::Patches)?
-                .chunks();
-
-            let mut ins_content = None;
-            let mut del_content = None;
-
-            while let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::PatchContent)? {
-                let (tag, content_chunk) = ReadPatchContentIter::new(chunk, compressed_chunk.as_mut())?;
-                // let iter = content_chunk.take_max();
-                let iter = content_chunk.buffered();
-                match tag {
-                    Ins => { ins_content = Some(iter); }
-                    Del => { del_content = Some(iter); }
-                }
+        // This chunk contains the actual set of edits to the document.
+        let mut patch_chunk = keep_chunk(reader.expect_chunk(ListChunkType::Patches)?, content_arena)
+            .chunks();
+
+        let mut ins_content = None;
+        let mut del_content = None;
+
+        while let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::PatchContent)? {
+            let (tag, iter) = ReadPatchContentIter::new(chunk, compressed_chunk.as_mut(), content_arena)?;
+            let source = ContentSource { runs: iter.run_chunk, content: iter.content };
+            match tag {
+                Ins => { ins_content = Some(source); }
+                Del => { del_content = Some(source); }
             }
+        }
 
-            // So note that the file we're loading from may contain changes we already have locally.
-            // We (may) need to filter out operations from the patch stream, which we read from
-            // below. To do that without extra need to read both the agent assignments and patches together.
-            let mut agent_assignment_chunk = patch_chunk.expect_chunk(ListChunkType::OpVersions)?;
-            let pos_patches_chunk = patch_chunk.expect_chunk(ListChunkType::OpTypeAndPosition)?;
-            let mut history_chunk = patch_chunk.expect_chunk(ListChunkType::OpParents)?;
-
-            // We need an insert ctx in some situations, though it'll never be accessed.
-            let dummy_ctx = ListOperationCtx::new();
-
-            let mut patches_iter = ReadPatchesIter::new(pos_patches_chunk)
-                .buffered();
-
-            let first_new_time = self.len();
-            let mut next_patch_time = first_new_time;
-
-            // The file we're loading has a list of operations. The list's item order is shared in a
-            // handful of lists of data - agent assignment, operations, content and txns.
-
-            // Only used for new (not overlapped) operations.
-            let mut next_assignment_time = first_new_time;
-            let new_op_start = if patches_overlap { UNDERWATER_START } else { first_new_time };
-            let mut next_file_time = new_op_start;
-
-            // Mapping from "file order" (numbered from 0) to the resulting local order. Using a
-            // smallvec here because it'll almost always just be a single entry, and that prevents
-            // an allocation in the common case. This is needed for merging overlapped file data.
-            //
-            // If the data (key) overlaps, the value is the location in the document where the
-            // overlap happens.
-            //
-            // If the data does not overlap (so we're gonna merge & keep this data), this maps to
-            // the set of local version numbers which will be used for this data.
-
-            // TODO: Replace with SmallVec to avoid an allocation in the common case here.
-            // let mut version_map: SmallVec<[KVPair<TimeSpan>; 1]> = SmallVec::new();
-            let mut version_map = RleVec::new();
-
-            // Take and merge the next exactly n patches
-            let mut parse_next_patches = |oplog: &mut ListOpLog, mut n: usize, keep: bool| -> Result<(), ParseError> {
-                while n > 0 {
-                    let mut max_len = n;
-
-                    if let Some(op) = patches_iter.next() {
-                        let mut op = op?;
-                        // dbg!((n, &op));
-                        max_len = max_len.min(op.len());
-
-                        // Trim down the operation to size.
-                        let content_here = if let Some(iter) = switch(op.kind, &mut ins_content, &mut del_content) {
-                            // There's probably a way to compact with Option helpers magic but ??
-                            if let Some(content) = iter.next() {
-                                let mut content = content?;
-                                max_len = max_len.min(content.len);
-                                // Put the rest (if any) back into the iterator.
-                                if let Some(r) = content.trim(max_len) {
-                                    iter.push_back(Ok(r));
-                                }
-                                content.content
-                            } else {
-                                return Err(ParseError::InvalidLength);
-                            }
-                        } else { None };
+        let sources = PatchSources {
+            assignments: patch_chunk.expect_chunk(ListChunkType::OpVersions)?,
+            positions: patch_chunk.expect_chunk(ListChunkType::OpTypeAndPosition)?,
+            parents: patch_chunk.expect_chunk(ListChunkType::OpParents)?,
+            ins_content,
+            del_content,
+            shared: &content_arena.shared,
+        };
+        let checkpoints = read_checkpoints(patch_chunk.read_chunk_if_eq(ListChunkType::Checkpoints)?)?;
+        let timestamps = read_timestamps(patch_chunk.read_chunk_if_eq(ListChunkType::Timestamps)?)?;
+        patch_chunk.expect_empty()?;
+
+        Ok(DecodeHeader {
+            patches: PatchDecoder::new(self, start_version, patches_overlap, agent_map, file_inserted_bytes, checkpoints, timestamps),
+            sources,
+            end_branch,
+        })
+    }
+}
 
-                        assert!(max_len > 0);
-                        n -= max_len;
+/// Read a Version chunk by agent name and seq, without looking up the named operations. A missing
+/// chunk means ROOT.
+fn read_remote_version(chunk: Option<BufReader>, agent_map: &FileAgentMap) -> Result<Vec<RemoteVersionOwned>, ParseError> {
+    let mut result = Vec::new();
+    if let Some(mut chunk) = chunk {
+        loop {
+            let (mapped_agent, has_more) = strip_bit_usize(chunk.next_usize()?);
+            let seq = chunk.next_usize()?;
+            if mapped_agent == 0 { break; } // Root.
 
-                        let remainder = op.trim_ctx(max_len, &dummy_ctx);
+            let agent = agent_map.0.get(mapped_agent - 1)
+                .ok_or_else(|| chunk.err(ParseErrorKind::InvalidLength))?;
+            result.push(RemoteVersionOwned(agent.name.clone(), seq));
 
-                        // dbg!(keep, (next_patch_time, &op, content_here));
+            if !has_more { break; }
+        }
+        chunk.expect_empty()?;
+    }
+    Ok(result)
+}
 
-                        // self.operations.push(KVPair(next_time, op));
-                        if keep {
-                            oplog.push_op_internal(next_patch_time, op.loc, op.kind, content_here);
-                            next_patch_time += max_len;
-                        }
+impl ListBranch {
+    /// Load a snapshot written with [`EncodeMode::Snapshot`]. This returns the document's content
+    /// and the version the snapshot was taken at.
+    ///
+    /// Snapshots have no history, so the branch isn't attached to any oplog. Its local version is
+    /// ROOT and it's read only, so it shouldn't be merged into or edited. Use the returned version
+    /// to ask a peer for the history after that point.
+    pub fn load_snapshot(data: &[u8]) -> Result<(Self, RemoteFrontierOwned), ParseError> {
+        let content_arena = ContentArena::default();
 
-                        if let Some(r) = remainder {
-                            patches_iter.push_back(Ok(r));
-                        }
-                    } else {
-                        return Err(ParseError::InvalidLength);
-                    }
-                }
+        let mut reader = BufReader::new(data);
+        reader.read_magic()?;
+        let version_reader = reader;
+        if !protocol_version_supported(reader.next_usize()?) {
+            return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
+        }
+        let mut reader = reader.chunks();
+        validate_chunks(data, reader.clone(), true)?;
+
+        let mut compressed_chunk = decompress_fields(
+            reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?, &content_arena
+        )?;
+        let FileInfoData { agent_map, .. } = reader.expect_chunk(ListChunkType::FileInfo)?
+            .chunks().read_fileinfo(&DecodeOptions::default())?;
+
+        let mut snapshot = reader.expect_chunk(ListChunkType::Snapshot)?.chunks();
+        let version = read_remote_version(snapshot.read_chunk_if_eq(ListChunkType::Version)?, &agent_map)?;
+        let content = snapshot.expect_content_str(compressed_chunk.as_mut(), &content_arena)?;
+        snapshot.expect_empty()?;
+
+        let branch = ListBranch {
+            version: Frontier::root(),
+            content: JumpRopeBuf::from(content),
+            read_only: true,
+            undo: Default::default(),
+        };
+        Ok((branch, version.into_iter().collect()))
+    }
+}
 
-                Ok(())
-            };
+/// Read the document's content at the version of everything in the file, without building an
+/// oplog. This returns the same content as [`ListOpLog::load_from`] followed by
+/// [`checkout_tip`](ListOpLog::checkout_tip).
+///
+/// Its much faster for files with linear history (where nothing was ever edited concurrently).
+/// Each of those operations is positioned relative to the document as it was just before it, so
+/// they can be applied straight to a rope in the order they're stored. No agents, operations or
+/// history are kept in memory.
+///
+/// Other files are loaded the normal way. That includes files with concurrent edits, appended
+/// segments, inserted content which isn't stored, or a start version other than ROOT. Snapshots
+/// return the content they store. Stored end content (`experimentally_store_end_branch_content`)
+/// is ignored - the content always comes from the operations.
+pub fn decode_document(data: &[u8]) -> Result<String, ParseError> {
+    let Segments { files, .. } = split_segments(data)?;
+    if let [(start, file)] = files[..] {
+        if let Some(content) = decode_linear_document(file, start)? {
+            return Ok(content);
+        }
+    }
 
-            while let Some(mut crdt_span) = agent_assignment_chunk.read_next_agent_assignment(&mut agent_map)? {
-                // let mut crdt_span = crdt_span; // TODO: Remove me. Blerp clion.
-                // dbg!(crdt_span);
-                if crdt_span.agent as usize >= self.cg.agent_assignment.client_data.len() {
-                    return Err(ParseError::InvalidLength);
-                }
+    let (_, content, _) = ListOpLog::decode_and_checkout(data)?;
+    Ok(content)
+}
 
-                if patches_overlap {
-                    // Sooo, if the current document overlaps with the data we're loading, we need
-                    // to filter out all the operations we already have from the stream.
-                    while !crdt_span.seq_range.is_empty() {
-                        // dbg!(&crdt_span);
-                        let client = &self.cg.agent_assignment.client_data[crdt_span.agent as usize];
-                        let (span, offset) = client.item_times.find_sparse(crdt_span.seq_range.start);
-                        // dbg!((crdt_span.seq_range, span, offset));
-                        let (span_end, overlap_start) = match span {
-                            // Skip the entry.
-                            Ok(entry) => (entry.end(), Some(entry.1.start + offset)),
-                            // Consume the entry
-                            Err(empty_span) => (empty_span.end, None),
-                        };
-
-                        let end = crdt_span.seq_range.end.min(span_end);
-                        let consume_here = crdt_span.seq_range.truncate_keeping_right_from(end);
-                        let len = consume_here.len();
-
-                        let keep = if let Some(overlap_start) = overlap_start {
-                            let overlap = (overlap_start .. overlap_start + len).into();
-                            // There's overlap. We'll filter out this item.
-                            version_map.push_rle(KVPair(next_file_time, overlap));
-                            // println!("push overlap {:?}", KVPair(next_file_time, overlap));
-                            false
-                        } else {
-                            self.assign_time_to_crdt_span(next_assignment_time, AgentSpan {
-                                agent: crdt_span.agent,
-                                seq_range: consume_here,
-                            });
-
-                            // println!("push to end {:?}", KVPair(
-                            //     next_file_time,
-                            //     TimeSpan::from(next_assignment_time..next_assignment_time + len),
-                            // ));
-                            version_map.push_rle(KVPair(
-                                next_file_time,
-                                (next_assignment_time..next_assignment_time + len).into(),
-                            ));
-                            next_assignment_time += len;
-                            true
-                        };
-                        next_file_time += len;
-
-                        // dbg!(&file_to_local_version_map);
-
-                        parse_next_patches(self, len, keep)?;
-
-                        // And deal with history.
-                        // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, keep)?;
-                    }
-                    // dbg!(span);
-                } else {
-                    // Optimization - don't bother with the filtering code above if loaded changes
-                    // follow local changes. Most calls to this function load into an empty
-                    // document, and this is the case.
-                    self.assign_time_to_crdt_span(next_assignment_time, crdt_span);
-                    let len = crdt_span.len();
-                    let timespan = (next_assignment_time..next_assignment_time+len).into();
-                    // file_to_local_version_map.push_rle((next_assignment_time..next_assignment_time + len).into());
-                    version_map.push_rle(KVPair(next_file_time, timespan));
-                    parse_next_patches(self, len, true)?;
-                    // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, true)?;
-
-                    next_assignment_time += len;
-                    next_file_time += len;
-                }
-            }
+/// The fast path for [`decode_document`]. Returns None if the file's operations can't be applied
+/// directly.
+pub(super) fn decode_linear_document(data: &[u8], start: usize) -> Result<Option<String>, ParseError> {
+    let content_arena = ContentArena::default();
 
-            next_file_time = new_op_start;
-            // dbg!(&version_map);
-            let mut next_history_time = first_new_time;
-
-            let mut file_frontier = start_version;
-
-            while !history_chunk.is_empty() {
-                let mut entry = history_chunk.next_history_entry(self, next_file_time, &agent_map)?;
-                // So at this point the entry has underwater entry spans, and parents are underwater
-                // when they're local to the file (and non-underwater when they refer to our items).
-                // This makes the entry safe to truncate(), but we need to map it before we can use
-                // it.
-
-                next_file_time += entry.len();
-                // dbg!(&entry);
-
-                // If patches don't overlap, this code can be simplified to this:
-                //     self.insert_history(&entry.parents, entry.span);
-                //     self.advance_frontier(&entry.parents, entry.span);
-                //     next_history_time += entry.len();
-                // But benchmarks show it doesn't make any real difference in practice, so I'm not
-                // going to sweat it.
-
-                loop {
-                    let (mut mapped, remainder)
-                        = history_entry_map_and_truncate(entry, &version_map);
-                    // dbg!(&mapped);
-                    mapped.parents.debug_check_sorted();
-                    assert!(mapped.span.start <= next_history_time);
-
-                    // We'll update merge parents even if nothing is merged.
-                    // dbg!((&file_frontier, &mapped));
-                    file_frontier.advance_by_known_run(mapped.parents.as_ref(), mapped.span);
-                    // dbg!(&file_frontier);
-
-                    if mapped.span.end > next_history_time {
-                        // We'll merge items from mapped.
-
-                        // This is needed because the overlapping & new items aren't strictly
-                        // separated in version_map. Its kinda ugly though - I'd like a better way
-                        // to deal with this case.
-                        if mapped.span.start < next_history_time {
-                            mapped.truncate_keeping_right(next_history_time - mapped.span.start);
-                        }
+    let mut reader = BufReader::at(data, start);
+    reader.read_magic()?;
+    let version_reader = reader;
+    if !protocol_version_supported(reader.next_usize()?) {
+        return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
+    }
+    let mut reader = reader.chunks();
+    validate_chunks(data, reader.clone(), true)?;
+
+    let mut compressed_chunk = decompress_fields(
+        reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?, &content_arena
+    )?;
+    // This checks the file's capabilities. The agent names aren't needed to find the content.
+    reader.expect_chunk(ListChunkType::FileInfo)?.chunks().read_fileinfo(&DecodeOptions::default())?;
+
+    if let Some(snapshot) = reader.read_chunk_if_eq(ListChunkType::Snapshot)? {
+        let mut snapshot = snapshot.chunks();
+        snapshot.read_chunk_if_eq(ListChunkType::Version)?;
+        let content = snapshot.expect_content_str(compressed_chunk.as_mut(), &content_arena)?;
+        return Ok(Some(content.to_string()));
+    }
 
-                        self.cg.graph.push(mapped.parents.as_ref(), mapped.span);
-                        self.cg.version.advance_by_known_run(mapped.parents.as_ref(), mapped.span);
+    // The start branch is empty when the file starts from ROOT.
+    if !reader.expect_chunk(ListChunkType::StartBranch)?.is_empty() { return Ok(None); }
 
-                        next_history_time += mapped.len();
-                    } // else we already have these entries. Filter them out.
+    // Replaying linear history is cheap, so any stored end content is ignored rather than
+    // checking its version.
+    if let Some(end_branch) = reader.read_chunk_if_eq(ListChunkType::ExperimentalEndBranch)? {
+        let mut end_branch = end_branch.chunks();
+        end_branch.read_chunk_if_eq(ListChunkType::Version)?;
+        end_branch.expect_content_str(compressed_chunk.as_mut(), &content_arena)?;
+    }
 
-                    if let Some(remainder) = remainder {
-                        entry = remainder;
-                    } else {
-                        break;
-                    }
+    let mut patch_chunk = reader.expect_chunk(ListChunkType::Patches)?.chunks();
+    let mut ins_content = None;
+    while let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::PatchContent)? {
+        // Deleted content still needs to be read, since it can come before the inserted content
+        // in the compressed data.
+        let (tag, iter) = ReadPatchContentIter::new(chunk, compressed_chunk.as_mut(), &content_arena)?;
+        if tag == Ins { ins_content = Some(iter); }
+    }
+    patch_chunk.expect_chunk(ListChunkType::OpVersions)?;
+    let positions = patch_chunk.expect_chunk(ListChunkType::OpTypeAndPosition)?;
+    let Some(history_len) = linear_history_len(patch_chunk.expect_chunk(ListChunkType::OpParents)?)? else {
+        return Ok(None);
+    };
+    let num_checkpoints = read_checkpoints(patch_chunk.read_chunk_if_eq(ListChunkType::Checkpoints)?)?.len();
+
+    let mut content = match ins_content {
+        Some(iter) => {
+            // Unknown content is filled in with replacement characters by checkout. Leave that to
+            // the normal path.
+            let mut runs = iter.run_chunk;
+            while !runs.is_empty() {
+                if !strip_bit_usize(runs.next_usize()?).1 { return Ok(None); }
+            }
+            iter.content
+        }
+        None => "",
+    };
+
+    let mut doc = JumpRope::new();
+    let mut ops_len = 0;
+    for op in ReadPatchesIter::new(positions) {
+        let op = op?;
+        let span = op.loc.span;
+        ops_len += span.len();
+        match op.kind {
+            Ins => {
+                if span.start > doc.len_chars() { return Err(ParseErrorKind::InvalidLength.into()); }
+                let s = consume_chars(&mut content, span.len());
+                // If there wasn't enough content, it will have all been used up.
+                if content.is_empty() && count_chars(s) != span.len() {
+                    return Err(ParseErrorKind::UnexpectedEOF.into());
+                }
+                if op.loc.fwd {
+                    doc.insert(span.start, s);
+                } else {
+                    doc.insert(span.start, &reverse_str(s));
                 }
             }
+            Del => {
+                if span.end > doc.len_chars() { return Err(ParseErrorKind::InvalidLength.into()); }
+                doc.remove(span.into());
+            }
+        }
+    }
 
-            // We'll count the lengths in each section to make sure they all match up with each other.
-            if next_patch_time != next_assignment_time { return Err(ParseError::InvalidLength); }
-            if next_patch_time != next_history_time { return Err(ParseError::InvalidLength); }
+    // If the sections of the file don't match up, let the normal path work out what's wrong.
+    if !content.is_empty() || ops_len + num_checkpoints != history_len { return Ok(None); }
+    Ok(Some(doc.to_string()))
+}
 
-            // dbg!(&patch_chunk);
-            patch_chunk.expect_empty()?;
-            history_chunk.expect_empty()?;
+/// If the history in an OpParents chunk is linear (the first entry's parent is ROOT, and every
+/// other entry's only parent is the operation just before it), returns the number of operations
+/// it contains.
+fn linear_history_len(mut chunk: BufReader) -> Result<Option<usize>, ParseError> {
+    let mut len: usize = 0;
+    while !chunk.is_empty() {
+        let entry_len = chunk.next_usize()?;
+        // Parents are written with is_foreign and has_more mixed into the low bits. ROOT is written
+        // as foreign agent 0, and the previous operation is the local parent 1 before the entry.
+        let expected = if len == 0 { 1 } else { 1 << 2 };
+        if chunk.next_usize()? != expected { return Ok(None); }
+        len = len.checked_add(entry_len).ok_or(ParseErrorKind::InvalidLength)?;
+    }
+    Ok(Some(len))
+}
 
-            if let Some(mut iter) = ins_content {
-                if iter.next().is_some() {
-                    return Err(ParseError::InvalidContent);
-                }
-            }
+/// Read a Checkpoints chunk (if the file has one) into a list of each checkpoint's position in
+/// the file's patches, along with its message.
+fn read_checkpoints(chunk: Option<BufReader>) -> Result<Vec<(usize, String)>, ParseError> {
+    let mut result = vec![];
+    let Some(mut chunk) = chunk else { return Ok(result); };
+
+    let mut next_pos: usize = 0;
+    while !chunk.is_empty() {
+        let record = chunk;
+        let pos = next_pos.checked_add(chunk.next_usize()?)
+            .ok_or_else(|| record.err(ParseErrorKind::InvalidLength))?;
+        result.push((pos, chunk.next_str()?.to_string()));
+        next_pos = pos.saturating_add(1);
+    }
+    Ok(result)
+}
 
-            if let Some(mut iter) = del_content {
-                if iter.next().is_some() {
-                    return Err(ParseError::InvalidContent);
-                }
-            }
+/// Read a Timestamps chunk (if the file has one) into runs of timestamps, by their position in the
+/// file's patches.
+fn read_timestamps(chunk: Option<BufReader>) -> Result<Vec<(DTRange, i64)>, ParseError> {
+    let mut result = vec![];
+    let Some(mut chunk) = chunk else { return Ok(result); };
+
+    let mut next_pos: usize = 0;
+    while !chunk.is_empty() {
+        let record = chunk;
+        let start = next_pos.checked_add(chunk.next_usize()?);
+        let len = chunk.next_usize()?;
+        let end = start.and_then(|start| start.checked_add(len))
+            .filter(|_| len > 0)
+            .ok_or_else(|| record.err(ParseErrorKind::InvalidLength))?;
+        let timestamp = num_decode_zigzag_i64_old(chunk.next_u64()?);
+        result.push(((end - len..end).into(), timestamp));
+        next_pos = end;
+    }
+    Ok(result)
+}
 
-            // dbg!(&version_map);
-            file_frontier
-        }; // End of patches
+/// Where content read from a file is kept while decoding. Deduplicated content is expanded into
+/// the bump arena. Decompressed data goes in [`SharedBuffers`] instead, so the oplog can keep
+/// referencing it after decoding rather than copying its content out.
+///
+/// Chunks read from a stream which are needed after the header is decoded are moved into `kept`.
+/// The oplog never references those, so they're dropped along with the arena.
+#[derive(Default)]
+struct ContentArena {
+    bump: Bump,
+    shared: SharedBuffers,
+    kept: SharedBuffers,
+}
 
-        // TODO: Move checksum check to the start, so if it fails we don't modify the document.
-        let reader_len = reader.0.len();
-        if let Some(mut crc_reader) = reader.read_chunk_if_eq(ListChunkType::Crc)? {
-            // So this is a bit dirty. The bytes which have been checksummed is everything up to
-            // (but NOT INCLUDING) the CRC chunk. I could adapt BufReader to store the offset /
-            // length. But we can just subtract off the remaining length from the original data??
-            // O_o
-            if !opts.ignore_crc {
-                let expected_crc = crc_reader.next_u32_le()?;
-                let checksummed_data = &data[..data.len() - reader_len];
+impl ContentArena {
+    fn with_shared(buf: Arc<Vec<u8>>) -> Self {
+        Self { shared: SharedBuffers(RefCell::new(vec![buf])), ..Default::default() }
+    }
+}
 
-                // TODO: Add flag to ignore invalid checksum.
-                if calc_checksum(checksummed_data) != expected_crc {
-                    return Err(ParseError::ChecksumFailed);
-                }
-            }
-        }
+/// Make a chunk live as long as the arena. Chunks read from a stream are moved into it (not
+/// copied).
+fn keep_chunk<'a>(chunk: Chunk<'a>, content_arena: &'a ContentArena) -> BufReader<'a> {
+    match chunk {
+        Chunk::Borrowed(reader) => reader,
+        Chunk::Owned(body, loc) => BufReader::with_loc(content_arena.kept.add(body), loc),
+    }
+}
 
-        // self.frontier = end_frontier_chunk.read_full_frontier(&self)?;
+impl Deref for ContentArena {
+    type Target = Bump;
+    fn deref(&self) -> &Bump { &self.bump }
+}
 
-        Ok(file_frontier)
+/// Buffers which decoded content may be referenced from in place. See
+/// [`ContentBuf`](crate::list::op_metrics::ContentBuf).
+///
+/// Buffers are only ever pushed. Nothing removes them, and nothing can modify them since they're
+/// only reachable through shared `Arc`s. [`add`](Self::add) relies on this.
+#[derive(Debug, Default)]
+struct SharedBuffers(RefCell<Vec<Arc<Vec<u8>>>>);
+
+impl SharedBuffers {
+    /// Keep `buf` until self is dropped, and return its bytes.
+    fn add(&self, buf: Vec<u8>) -> &[u8] {
+        let buf = Arc::new(buf);
+        // SAFETY: The returned slice borrows self, so it can't outlive the buffer list. Buffers
+        // are never removed from it or modified (see above), so the bytes stay valid and
+        // unchanged. Pushing onto the list (or cloning the Arc into an oplog) may move the Arc,
+        // but the Vec's heap allocation stays put. Arc clones handed to the oplog only keep the
+        // bytes alive for longer.
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr(), buf.len()) };
+        self.0.borrow_mut().push(buf);
+        bytes
     }
 }
 
+/// Decompress the body of a file's CompressedFieldsLZ4 chunk (if it has one). The data is
+/// decompressed into a shared buffer in the arena, because the content read from it needs to
+/// outlive the caller. The compressed chunk itself doesn't.
+#[allow(unused_variables, unused_mut)]
+fn decompress_fields<'a>(chunk: Option<BufReader<'_>>, content_arena: &'a ContentArena) -> Result<Option<BufReader<'a>>, ParseError> {
+    let Some(mut c) = chunk else { return Ok(None); };
+
+    #[cfg(not(feature = "lz4"))] {
+        Err(c.err(ParseErrorKind::LZ4DecoderNeeded))
+    }
+
+    #[cfg(feature = "lz4")] {
+        let uncompressed_len = c.next_usize()?;
+        // LZ4 can't compress better than 255:1. Checking this stops corrupt files from making us
+        // allocate huge buffers.
+        if uncompressed_len > c.buf.len().saturating_mul(255) {
+            return Err(c.err(ParseErrorKind::LZ4DecompressionError));
+        }
+
+        let mut data = vec![0u8; uncompressed_len];
+        let len = lz4_flex::decompress_into(c.buf, &mut data)
+            .map_err(|_e| c.err(ParseErrorKind::LZ4DecompressionError))?;
+        if len != uncompressed_len { return Err(c.err(ParseErrorKind::LZ4DecompressionError)); }
+
+        // To consume from the decompressed data, we'll make a slice that we can iterate
+        // through. Content in it is referenced by the oplog rather than copied.
+        Ok(Some(c.decompressed(content_arena.shared.add(data))))
+    }
+}
+
+/// Everything [`ListOpLog::decode_header`] reads out of a file.
+struct DecodeHeader<'a> {
+    patches: PatchDecoder,
+    sources: PatchSources<'a>,
+    /// The version chunk (if any) and content from the ExperimentalEndBranch chunk.
+    end_branch: Option<(Option<BufReader<'a>>, &'a str)>,
+}
+
+/// The bodies of the chunks inside the Patches chunk. The operations are read from here.
+#[derive(Debug, Clone, Copy)]
+struct PatchSources<'a> {
+    assignments: BufReader<'a>,
+    positions: BufReader<'a>,
+    parents: BufReader<'a>,
+    ins_content: Option<ContentSource<'a>>,
+    del_content: Option<ContentSource<'a>>,
+    /// Buffers the content may be in, which the oplog can reference instead of copying.
+    shared: &'a SharedBuffers,
+}
+
+/// Inserted or deleted content, and the list of runs saying which operations its for.
+#[derive(Debug, Clone, Copy)]
+struct ContentSource<'a> {
+    runs: BufReader<'a>,
+    content: &'a str,
+}
+
+/// How far a [`ReadPatchContentIter`] has read through a [`ContentSource`].
+#[derive(Debug, Clone, Copy, Default)]
+struct ContentCursor {
+    runs_pos: usize,
+    content_pos: usize,
+    /// A run which was split part way through. This is the length of the rest of the run, and
+    /// whether its content is known. The content (if known) starts at content_pos.
+    pending: Option<(usize, bool)>,
+}
+
+impl ContentCursor {
+    fn resume<'a>(&self, source: ContentSource<'a>) -> BufferedIter<ReadPatchContentIter<'a>> {
+        let mut content = &source.content[self.content_pos..];
+        let pending = self.pending.map(|(len, known)| ContentItem {
+            len,
+            content: if known { Some(consume_chars(&mut content, len)) } else { None },
+        });
+
+        let mut iter = ReadPatchContentIter {
+            run_chunk: source.runs.skip(self.runs_pos),
+            content,
+        }.buffered();
+        if let Some(item) = pending {
+            iter.push_back(Ok(item));
+        }
+        iter
+    }
+
+    fn suspend(source: ContentSource, iter: BufferedIter<ReadPatchContentIter>) -> Result<Self, ParseError> {
+        let (iter, pending) = iter.into_parts();
+        let mut content_pos = source.content.len() - iter.content.len();
+
+        // The content of a split run always comes right before the iterator's remaining content.
+        let pending = pending.transpose()?.map(|item| {
+            if let Some(content) = item.content {
+                content_pos -= content.len();
+            }
+            (item.len, item.content.is_some())
+        });
+
+        Ok(Self {
+            runs_pos: source.runs.len() - iter.run_chunk.len(),
+            content_pos,
+            pending,
+        })
+    }
+}
+
+/// The operations and content being read by a [`PatchDecoder`], during a single step.
+struct PatchReader<'a> {
+    patches_iter: BufferedIter<ReadPatchesIter<'a>>,
+    ins_content: Option<BufferedIter<ReadPatchContentIter<'a>>>,
+    del_content: Option<BufferedIter<ReadPatchContentIter<'a>>>,
+    shared: &'a SharedBuffers,
+
+    // We need an insert ctx in some situations, though it'll never be accessed.
+    dummy_ctx: ListOperationCtx,
+}
+
+impl<'a> PatchReader<'a> {
+    // Take and merge the next exactly n patches
+    fn parse_next_patches(&mut self, oplog: &mut ListOpLog, next_patch_time: &mut LV, mut n: usize, keep: bool) -> Result<(), ParseError> {
+        while n > 0 {
+            let mut max_len = n;
+
+            if let Some(op) = self.patches_iter.next() {
+                let mut op = op?;
+                // dbg!((n, &op));
+                max_len = max_len.min(op.len());
+
+                // Trim down the operation to size.
+                let content_here = if let Some(iter) = switch(op.kind, &mut self.ins_content, &mut self.del_content) {
+                    // There's probably a way to compact with Option helpers magic but ??
+                    if let Some(content) = iter.next() {
+                        let mut content = content?;
+                        max_len = max_len.min(content.len);
+                        // Put the rest (if any) back into the iterator.
+                        if let Some(r) = content.trim(max_len) {
+                            iter.push_back(Ok(r));
+                        }
+                        content.content
+                    } else {
+                        return Err(iter.run_chunk.err(ParseErrorKind::InvalidLength));
+                    }
+                } else { None };
+
+                // Zero length operations and content only show up in corrupt data.
+                if max_len == 0 { return Err(self.patches_iter.buf.err(ParseErrorKind::InvalidLength)); }
+                n -= max_len;
+
+                let remainder = op.trim_ctx(max_len, &self.dummy_ctx);
+
+                // dbg!(keep, (next_patch_time, &op, content_here));
+
+                // self.operations.push(KVPair(next_time, op));
+                if keep {
+                    oplog.push_op_internal_shared(*next_patch_time, op.loc, op.kind, content_here, &self.shared.0.borrow());
+                    *next_patch_time += max_len;
+                }
+
+                if let Some(r) = remainder {
+                    self.patches_iter.push_back(Ok(r));
+                }
+            } else {
+                return Err(self.patches_iter.buf.err(ParseErrorKind::InvalidLength));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes the operations in a file's Patches chunk into an oplog.
+///
+/// Decoding can be split up into steps which each decode a limited number of operations. That way
+/// big files can be loaded a bit at a time (see [`DecodeDriver`]). The decoder doesn't borrow the
+/// file's data. Instead it stores how far it has read, and the data is passed in to each step.
+#[derive(Debug)]
+struct PatchDecoder {
+    agent_map: FileAgentMap,
+    patches_overlap: bool,
+    first_new_time: LV,
+    /// The version of the first operation in the file. Underwater if the file overlaps with the
+    /// oplog.
+    new_op_start: LV,
+    file_inserted_bytes: Option<usize>,
+
+    // So note that the file we're loading from may contain changes we already have locally.
+    // We (may) need to filter out operations from the patch stream, which we read from
+    // below. To do that without extra need to read both the agent assignments and patches together.
+    //
+    // The first pass reads the agent assignments and the operations together. The second pass
+    // reads the parents.
+    assignments_pos: usize,
+    /// The rest of an agent assignment which was only partly decoded by the last step.
+    pending_assignment: Option<AgentSpan>,
+    positions_pos: usize,
+    last_cursor_pos: usize,
+    /// The rest of an operation which was split part way through.
+    pending_op: Option<ListOpMetrics>,
+    ins_content: ContentCursor,
+    del_content: ContentCursor,
+
+    next_patch_time: LV,
+    // The file we're loading has a list of operations. The list's item order is shared in a
+    // handful of lists of data - agent assignment, operations, content and txns.
+
+    // Only used for new (not overlapped) operations.
+    next_assignment_time: LV,
+    next_file_time: LV,
+
+    // Mapping from "file order" (numbered from 0) to the resulting local order. Using a
+    // smallvec here because it'll almost always just be a single entry, and that prevents
+    // an allocation in the common case. This is needed for merging overlapped file data.
+    //
+    // If the data (key) overlaps, the value is the location in the document where the
+    // overlap happens.
+    //
+    // If the data does not overlap (so we're gonna merge & keep this data), this maps to
+    // the set of local version numbers which will be used for this data.
+
+    // TODO: Replace with SmallVec to avoid an allocation in the common case here.
+    // let mut version_map: SmallVec<[KVPair<TimeSpan>; 1]> = SmallVec::new();
+    version_map: RleVec<KVPair<DTRange>>,
+
+    /// The checkpoints in the file, by their position in the file. These don't have an
+    /// operation in the positions chunk.
+    checkpoints: Vec<(usize, String)>,
+    next_checkpoint: usize,
+    /// Runs of timestamps in the file, by their position in the file.
+    timestamps: Vec<(DTRange, i64)>,
+    next_timestamp: usize,
+
+    parents_pos: usize,
+    /// The history entry being read, if it was split by the last step. Not mapped yet.
+    pending_entry: Option<GraphEntrySimple>,
+    next_history_file_time: LV,
+    next_history_time: LV,
+    file_frontier: Frontier,
+}
+
+impl PatchDecoder {
+    fn new(oplog: &ListOpLog, start_version: Frontier, patches_overlap: bool, agent_map: FileAgentMap, file_inserted_bytes: Option<usize>, checkpoints: Vec<(usize, String)>, timestamps: Vec<(DTRange, i64)>) -> Self {
+        let first_new_time = oplog.len();
+        let new_op_start = if patches_overlap { UNDERWATER_START } else { first_new_time };
+
+        Self {
+            agent_map,
+            patches_overlap,
+            first_new_time,
+            new_op_start,
+            file_inserted_bytes,
+            assignments_pos: 0,
+            pending_assignment: None,
+            positions_pos: 0,
+            last_cursor_pos: 0,
+            pending_op: None,
+            ins_content: ContentCursor::default(),
+            del_content: ContentCursor::default(),
+            next_patch_time: first_new_time,
+            next_assignment_time: first_new_time,
+            next_file_time: new_op_start,
+            version_map: RleVec::new(),
+            checkpoints,
+            next_checkpoint: 0,
+            timestamps,
+            next_timestamp: 0,
+            parents_pos: 0,
+            pending_entry: None,
+            next_history_file_time: new_op_start,
+            next_history_time: first_new_time,
+            file_frontier: start_version,
+        }
+    }
+
+    fn assignments_done(&self, src: &PatchSources) -> bool {
+        self.pending_assignment.is_none() && self.assignments_pos == src.assignments.len()
+    }
+
+    fn history_done(&self, src: &PatchSources) -> bool {
+        self.pending_entry.is_none() && self.parents_pos == src.parents.len()
+    }
+
+    /// Decode up to `budget` more operations into the oplog. Each operation is read twice (once
+    /// with its agent assignment, and again with its parents), and both passes count towards the
+    /// budget. Returns true once all the operations have been decoded.
+    ///
+    /// If this returns an error, the decoder can't be used again.
+    fn step(&mut self, oplog: &mut ListOpLog, src: &PatchSources, mut budget: usize) -> Result<bool, ParseError> {
+        if !self.assignments_done(src) {
+            self.read_assignments(oplog, src, &mut budget)?;
+        }
+        if self.assignments_done(src) {
+            self.read_history(oplog, src, &mut budget)?;
+        }
+        Ok(self.assignments_done(src) && self.history_done(src))
+    }
+
+    fn resume_reader<'a>(&mut self, src: &PatchSources<'a>) -> PatchReader<'a> {
+        let mut patches_iter = ReadPatchesIter {
+            buf: src.positions.skip(self.positions_pos),
+            last_cursor_pos: self.last_cursor_pos,
+        }.buffered();
+        if let Some(op) = self.pending_op.take() {
+            patches_iter.push_back(Ok(op));
+        }
+
+        PatchReader {
+            patches_iter,
+            ins_content: src.ins_content.map(|source| self.ins_content.resume(source)),
+            del_content: src.del_content.map(|source| self.del_content.resume(source)),
+            shared: src.shared,
+            dummy_ctx: ListOperationCtx::new(),
+        }
+    }
+
+    fn suspend_reader(&mut self, src: &PatchSources, reader: PatchReader) -> Result<(), ParseError> {
+        let (patches_iter, pending_op) = reader.patches_iter.into_parts();
+        self.positions_pos = src.positions.len() - patches_iter.buf.len();
+        self.last_cursor_pos = patches_iter.last_cursor_pos;
+        self.pending_op = pending_op.transpose()?;
+
+        if let (Some(source), Some(iter)) = (src.ins_content, reader.ins_content) {
+            self.ins_content = ContentCursor::suspend(source, iter)?;
+        }
+        if let (Some(source), Some(iter)) = (src.del_content, reader.del_content) {
+            self.del_content = ContentCursor::suspend(source, iter)?;
+        }
+        Ok(())
+    }
+
+    /// Read the next `len` patches, which start at `file_pos` in the file. Checkpoints are
+    /// added in place of an operation.
+    fn read_patches(&mut self, reader: &mut PatchReader, oplog: &mut ListOpLog, file_pos: usize, len: usize, keep: bool) -> Result<(), ParseError> {
+        let end = file_pos + len;
+
+        // The patches we keep are added with consecutive versions. Timestamps for patches we
+        // already have are left alone.
+        let first_version = self.next_patch_time;
+        while let Some(&(range, timestamp)) = self.timestamps.get(self.next_timestamp).filter(|(r, _)| r.start < end) {
+            let start = range.start.max(file_pos);
+            let run_end = range.end.min(end);
+            if keep && start < run_end {
+                oplog.timestamps.push(KVPair(first_version + start - file_pos, RleRun::new(timestamp, run_end - start)));
+            }
+            if range.end > end { break; }
+            self.next_timestamp += 1;
+        }
+
+        let mut pos = file_pos;
+        while let Some((c, message)) = self.checkpoints.get(self.next_checkpoint).filter(|(c, _)| *c < end) {
+            reader.parse_next_patches(oplog, &mut self.next_patch_time, c - pos, keep)?;
+            if keep {
+                oplog.checkpoints.insert(self.next_patch_time, message.clone());
+                self.next_patch_time += 1;
+            }
+            pos = c + 1;
+            self.next_checkpoint += 1;
+        }
+        reader.parse_next_patches(oplog, &mut self.next_patch_time, end - pos, keep)
+    }
+
+    fn read_assignments(&mut self, oplog: &mut ListOpLog, src: &PatchSources, budget: &mut usize) -> Result<(), ParseError> {
+        let mut agent_assignment_chunk = src.assignments.skip(self.assignments_pos);
+        let mut reader = self.resume_reader(src);
+
+        while *budget > 0 {
+            let mut crdt_span = match self.pending_assignment.take() {
+                Some(span) => span,
+                None => match agent_assignment_chunk.read_next_agent_assignment(oplog, &mut self.agent_map)? {
+                    Some(span) => span,
+                    None => break,
+                }
+            };
+
+            // let mut crdt_span = crdt_span; // TODO: Remove me. Blerp clion.
+            // dbg!(crdt_span);
+            if crdt_span.agent as usize >= oplog.cg.agent_assignment.client_data.len() {
+                return Err(agent_assignment_chunk.err(ParseErrorKind::InvalidLength));
+            }
+
+            // Only decode as much of the assignment as the budget allows.
+            if crdt_span.len() > *budget {
+                self.pending_assignment = Some(crdt_span.truncate(*budget));
+            }
+            *budget -= crdt_span.len();
+
+            if self.patches_overlap {
+                // Sooo, if the current document overlaps with the data we're loading, we need
+                // to filter out all the operations we already have from the stream.
+                while !crdt_span.seq_range.is_empty() {
+                    // dbg!(&crdt_span);
+                    let client = &oplog.cg.agent_assignment.client_data[crdt_span.agent as usize];
+                    let span = client.item_times.find_sparse(crdt_span.seq_range.start);
+                    // dbg!((crdt_span.seq_range, span));
+                    let (span_end, overlap_start) = match span {
+                        // Skip the entry.
+                        Ok((entry, offset)) => (entry.end(), Some(entry.1.start + offset)),
+                        // Consume the entry
+                        Err(empty_span) => (empty_span.end, None),
+                    };
+
+                    let end = crdt_span.seq_range.end.min(span_end);
+                    let consume_here = crdt_span.seq_range.truncate_keeping_right_from(end);
+                    let len = consume_here.len();
+
+                    let file_pos = self.next_file_time - self.new_op_start;
+                    let keep = if let Some(overlap_start) = overlap_start {
+                        let overlap = (overlap_start .. overlap_start + len).into();
+                        // There's overlap. We'll filter out this item.
+                        self.version_map.push_rle(KVPair(self.next_file_time, overlap));
+                        // println!("push overlap {:?}", KVPair(next_file_time, overlap));
+                        false
+                    } else {
+                        oplog.assign_time_to_crdt_span(self.next_assignment_time, AgentSpan {
+                            agent: crdt_span.agent,
+                            seq_range: consume_here,
+                        });
+
+                        // println!("push to end {:?}", KVPair(
+                        //     next_file_time,
+                        //     TimeSpan::from(next_assignment_time..next_assignment_time + len),
+                        // ));
+                        self.version_map.push_rle(KVPair(
+                            self.next_file_time,
+                            (self.next_assignment_time..self.next_assignment_time + len).into(),
+                        ));
+                        self.next_assignment_time += len;
+                        true
+                    };
+                    self.next_file_time += len;
+
+                    // dbg!(&file_to_local_version_map);
+
+                    self.read_patches(&mut reader, oplog, file_pos, len, keep)?;
+
+                    // And deal with history.
+                    // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, keep)?;
+                }
+                // dbg!(span);
+            } else {
+                // Optimization - don't bother with the filtering code above if loaded changes
+                // follow local changes. Most calls to this function load into an empty
+                // document, and this is the case.
+                //
+                // A corrupt file can jump backwards and reuse seq numbers assigned earlier in the
+                // file.
+                let client = &oplog.cg.agent_assignment.client_data[crdt_span.agent as usize];
+                match client.item_times.find_sparse(crdt_span.seq_range.start) {
+                    Err(gap) if gap.end >= crdt_span.seq_range.end => {},
+                    _ => { return Err(agent_assignment_chunk.err(ParseErrorKind::InvalidLength)); }
+                }
+                oplog.assign_time_to_crdt_span(self.next_assignment_time, crdt_span);
+                let len = crdt_span.len();
+                let timespan = (self.next_assignment_time..self.next_assignment_time+len).into();
+                // file_to_local_version_map.push_rle((next_assignment_time..next_assignment_time + len).into());
+                self.version_map.push_rle(KVPair(self.next_file_time, timespan));
+                self.read_patches(&mut reader, oplog, self.next_file_time - self.new_op_start, len, true)?;
+                // parse_next_history(&mut self, &file_to_self_agent_map, &version_map, len, true)?;
+
+                self.next_assignment_time += len;
+                self.next_file_time += len;
+            }
+        }
+
+        self.assignments_pos = src.assignments.len() - agent_assignment_chunk.len();
+        self.suspend_reader(src, reader)
+    }
+
+    fn read_history(&mut self, oplog: &mut ListOpLog, src: &PatchSources, budget: &mut usize) -> Result<(), ParseError> {
+        let mut history_chunk = src.parents.skip(self.parents_pos);
+
+        while *budget > 0 {
+            let mut entry = match self.pending_entry.take() {
+                Some(entry) => entry,
+                None if history_chunk.is_empty() => break,
+                None => {
+                    let entry = history_chunk.next_history_entry(oplog, self.new_op_start, self.next_history_file_time, &mut self.agent_map)?;
+                    // So at this point the entry has underwater entry spans, and parents are underwater
+                    // when they're local to the file (and non-underwater when they refer to our items).
+                    // This makes the entry safe to truncate(), but we need to map it before we can use
+                    // it.
+
+                    self.next_history_file_time += entry.len();
+                    // dbg!(&entry);
+                    entry
+                }
+            };
+
+            // Only decode as much of the entry as the budget allows. The rest is mapped later.
+            if entry.len() > *budget {
+                self.pending_entry = Some(entry.truncate(*budget));
+            }
+            *budget -= entry.len();
+
+            // If patches don't overlap, this code can be simplified to this:
+            //     self.insert_history(&entry.parents, entry.span);
+            //     self.advance_frontier(&entry.parents, entry.span);
+            //     next_history_time += entry.len();
+            // But benchmarks show it doesn't make any real difference in practice, so I'm not
+            // going to sweat it.
+
+            loop {
+                let (mut mapped, remainder)
+                    = history_entry_map_and_truncate(entry, &self.version_map)?;
+                // dbg!(&mapped);
+                mapped.parents.debug_check_sorted();
+                assert!(mapped.span.start <= self.next_history_time);
+
+                // We'll update merge parents even if nothing is merged.
+                // dbg!((&file_frontier, &mapped));
+                self.file_frontier.advance_by_known_run(mapped.parents.as_ref(), mapped.span);
+                // dbg!(&file_frontier);
+
+                if mapped.span.end > self.next_history_time {
+                    // We'll merge items from mapped.
+
+                    // This is needed because the overlapping & new items aren't strictly
+                    // separated in version_map. Its kinda ugly though - I'd like a better way
+                    // to deal with this case.
+                    if mapped.span.start < self.next_history_time {
+                        mapped.truncate_keeping_right(self.next_history_time - mapped.span.start);
+                    }
+
+                    // Operations concurrent with collapsed history would be merged against the
+                    // wrong content.
+                    if !oplog.can_checkout(mapped.parents.as_ref()) {
+                        return Err(ParseErrorKind::VersionCollected.into());
+                    }
+
+                    oplog.cg.graph.push(mapped.parents.as_ref(), mapped.span);
+                    oplog.cg.version.advance_by_known_run(mapped.parents.as_ref(), mapped.span);
+
+                    self.next_history_time += mapped.len();
+                } // else we already have these entries. Filter them out.
+
+                if let Some(remainder) = remainder {
+                    entry = remainder;
+                } else {
+                    break;
+                }
+            }
+        }
+
+        self.parents_pos = src.parents.len() - history_chunk.len();
+        Ok(())
+    }
+
+    /// Check everything in the Patches chunk was used, once all the steps are done. Returns the
+    /// version of the file's data.
+    fn finish(self, oplog: &mut ListOpLog, src: &PatchSources) -> Result<(Frontier, FileAgentMap), ParseError> {
+        debug_assert!(self.assignments_done(src) && self.history_done(src));
+
+        // We'll count the lengths in each section to make sure they all match up with each other.
+        // Mismatches are reported at the end of the section which ran out first.
+        if self.next_patch_time != self.next_assignment_time {
+            return Err(src.positions.skip(src.positions.len()).err(ParseErrorKind::InvalidLength));
+        }
+        if self.next_patch_time != self.next_history_time {
+            return Err(src.parents.skip(src.parents.len()).err(ParseErrorKind::InvalidLength));
+        }
+        // Checkpoints past the end of the patches were never added.
+        if self.next_checkpoint != self.checkpoints.len() || self.next_timestamp != self.timestamps.len() {
+            return Err(ParseErrorKind::InvalidLength.into());
+        }
+
+        // Files without inserted content can store its size instead. We can only use it when
+        // we're loading the whole file into an empty oplog. Otherwise push_op_internal counts
+        // a byte per character.
+        if let (Some(bytes), None, 0) = (self.file_inserted_bytes, src.ins_content, self.first_new_time) {
+            // Every character is between 1 and 4 bytes.
+            if bytes < oplog.inserted_bytes || bytes > oplog.inserted_bytes.saturating_mul(4) {
+                return Err(ParseErrorKind::InvalidLength.into());
+            }
+            oplog.inserted_bytes = bytes;
+        }
+
+        // Any content left over doesn't belong to an operation.
+        for (source, cursor) in [(src.ins_content, self.ins_content), (src.del_content, self.del_content)] {
+            if let Some(source) = source {
+                if cursor.pending.is_some() || cursor.runs_pos < source.runs.len() || cursor.content_pos < source.content.len() {
+                    return Err(source.runs.skip(cursor.runs_pos).err(ParseErrorKind::InvalidContent));
+                }
+            }
+        }
+
+        // dbg!(&version_map);
+        Ok((self.file_frontier, self.agent_map))
+    }
+}
+
+/// The top level chunks of a file, in the order they appear. The bool says whether the chunk is
+/// required. Unknown chunks can appear anywhere, and are skipped.
+const TOP_LEVEL_CHUNKS: [(ListChunkType, bool); 6] = [
+    (ListChunkType::CompressedFieldsLZ4, false),
+    (ListChunkType::FileInfo, true),
+    (ListChunkType::StartBranch, true),
+    (ListChunkType::ExperimentalEndBranch, false),
+    (ListChunkType::Patches, true),
+    (ListChunkType::Crc, false),
+];
+
+/// Decodes an oplog from data which arrives in pieces (for example, off the network). This
+/// produces the same result as [`ListOpLog::load_from`], without needing the whole file up front.
+///
+/// ```
+/// # use diamond_types::list::ListOpLog;
+/// # use diamond_types::list::encoding::{ENCODE_FULL, StreamingDecoder};
+/// # let mut oplog = ListOpLog::new();
+/// # let seph = oplog.get_or_create_agent_id("seph");
+/// # oplog.add_insert(seph, 0, "hi there");
+/// let data = oplog.encode(ENCODE_FULL);
+///
+/// let mut decoder = StreamingDecoder::new();
+/// for piece in data.chunks(10) {
+///     decoder.push(piece).unwrap();
+/// }
+/// assert_eq!(decoder.finish().unwrap(), oplog);
+/// ```
+///
+/// Pushed bytes are buffered until the next top level chunk is complete. As each chunk arrives,
+/// the decoder checks the chunks are in the right order and updates the file's checksum. So a
+/// corrupt file is usually rejected by `push` before the rest of it is downloaded.
+///
+/// The operations are decoded as soon as the `Patches` chunk arrives, and everything buffered up
+/// to that point is dropped. After that, each chunk is dropped as soon as its been checked. The
+/// chunks before the operations (and the `Patches` chunk itself, which is most of the file) are
+/// still buffered in full, because each operation is spread across several chunks nested inside
+/// `Patches`. To decode straight from a file or socket, use
+/// [`load_from_reader`](ListOpLog::load_from_reader).
+///
+/// Once `push` returns an error the decoder shouldn't be used again.
+pub struct StreamingDecoder {
+    input: StreamInput,
+    /// The decoded oplog, once the Patches chunk has arrived.
+    oplog: Option<ListOpLog>,
+}
+
+impl std::fmt::Debug for StreamingDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamingDecoder")
+            .field("input", &self.input)
+            .field("decoded_operations", &self.oplog.as_ref().map(|o| o.len()))
+            .finish()
+    }
+}
+
+impl Default for StreamingDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamingDecoder {
+    pub fn new() -> Self {
+        Self::with_opts(DecodeOptions::default())
+    }
+
+    pub fn with_opts(opts: DecodeOptions) -> Self {
+        Self { input: StreamInput::new(opts), oplog: None }
+    }
+
+    /// Add the next piece of the file.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), ParseError> {
+        self.input.push(bytes)?;
+        if self.oplog.is_none() && self.input.has_read(ListChunkType::Patches) {
+            self.oplog = Some(self.input.decode()?);
+        }
+        Ok(())
+    }
+
+    /// The number of pushed bytes the decoder is holding on to.
+    pub fn buffered_len(&self) -> usize {
+        self.input.data.len()
+    }
+
+    /// Finish decoding, and return the loaded oplog. This fails with
+    /// [`ParseErrorKind::UnexpectedEOF`] if the data ends part way through a chunk.
+    pub fn finish(mut self) -> Result<ListOpLog, ParseError> {
+        self.input.check_complete()?;
+        match self.oplog {
+            Some(oplog) => Ok(oplog),
+            // The file has no operations. This finds the error.
+            None => self.input.decode(),
+        }
+    }
+}
+
+/// The data pushed into a [`StreamingDecoder`] or [`DecodeDriver`]. Pieces are buffered until
+/// each top level chunk is complete, and the chunks are checked as they arrive.
+struct StreamInput {
+    opts: DecodeOptions,
+
+    /// All the bytes pushed so far.
+    data: Vec<u8>,
+
+    /// The number of bytes thrown away from the start of data by discard_checked. This is the
+    /// offset of data in the file.
+    discarded_len: usize,
+
+    /// The length of the prefix of data which has been checked. This is always at the end of the
+    /// file header or a top level chunk.
+    checked_len: usize,
+
+    /// Index into TOP_LEVEL_CHUNKS of the next chunk we can see. None until the header is read.
+    next_chunk: Option<usize>,
+
+    /// Checksum of data[..checked_len].
+    digest: crc::Digest<'static, u32>,
+
+    /// The checksum, type and body position of the last top level chunk, in case the next chunk
+    /// is its chunk CRC.
+    last_chunk: Option<(u32, ListChunkType, usize)>,
+
+    /// Set once the operations have been decoded. From then on, chunks are thrown away as soon
+    /// as they've been checked.
+    discard: bool,
+}
+
+impl std::fmt::Debug for StreamInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamInput")
+            .field("opts", &self.opts)
+            .field("buffered_bytes", &self.data.len())
+            .field("checked_len", &self.checked_len)
+            .field("next_chunk", &self.next_chunk)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StreamInput {
+    fn new(opts: DecodeOptions) -> Self {
+        Self {
+            opts,
+            data: Vec::new(),
+            discarded_len: 0,
+            checked_len: 0,
+            next_chunk: None,
+            digest: CRC32C.digest(),
+            last_chunk: None,
+            discard: false,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) -> Result<(), ParseError> {
+        self.data.extend_from_slice(bytes);
+
+        if self.next_chunk.is_none() {
+            let mut reader = BufReader::new(&self.data);
+            // The magic bytes are checked as soon as they arrive.
+            if reader.len() < MAGIC_BYTES.len() { return Ok(()); }
+            reader.read_magic()?;
+
+            let version_reader = reader;
+            let protocol_version = match reader.next_usize() {
+                Err(e) if e.kind == ParseErrorKind::UnexpectedEOF => { return Ok(()); }
+                r => r?,
+            };
+            if !protocol_version_supported(protocol_version) {
+                return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
+            }
+
+            self.consume_checked(self.data.len() - reader.len());
+            self.next_chunk = Some(0);
+        }
+
+        while let Some((chunk_type, header_len, body_len)) = self.next_complete_chunk()? {
+            let body_start = self.checked_len + header_len;
+
+            if chunk_type == Some(ListChunkType::ChunkCrc) {
+                if let (Some((checksum, last_type, last_start)), false) = (self.last_chunk.take(), self.opts.ignore_crc) {
+                    let expected = self.chunk_body(body_start, body_len, ListChunkType::ChunkCrc).next_u32_le()?;
+                    if checksum != expected {
+                        return Err(ParseError::from(ParseErrorKind::ChecksumFailed(ChecksumMismatch { expected, actual: checksum }))
+                            .or_at(Some(last_start), ChunkPath::default().push(last_type as u32)));
+                    }
+                }
+            } else if let Some(chunk_type) = chunk_type {
+                if !self.opts.ignore_crc {
+                    let chunk_bytes = &self.data[self.checked_len..body_start + body_len];
+                    self.last_chunk = Some((calc_checksum(chunk_bytes), chunk_type, self.discarded_len + body_start));
+                }
+                self.check_chunk_order(chunk_type)
+                    .map_err(|e| e.or_at(Some(self.discarded_len + self.checked_len), Default::default()))?;
+
+                if chunk_type == ListChunkType::Crc && !self.opts.ignore_crc {
+                    // The checksum covers everything before the CRC chunk.
+                    let crc_reader = self.chunk_body(body_start, body_len, ListChunkType::Crc);
+                    let expected = crc_reader.clone().next_u32_le()?;
+                    let actual = self.digest.clone().finalize();
+                    if actual != expected {
+                        return Err(crc_reader.err(ParseErrorKind::ChecksumFailed(ChecksumMismatch { expected, actual })));
+                    }
+                }
+            } else { // Unknown chunks are skipped.
+                self.last_chunk = None;
+            }
+
+            self.consume_checked(header_len + body_len);
+        }
+
+        if self.discard { self.discard_checked(); }
+        Ok(())
+    }
+
+    /// Decode the file's operations into a new oplog. This is called once the Patches chunk has
+    /// arrived (or the file has ended), and afterwards the data is thrown away.
+    fn decode(&mut self) -> Result<ListOpLog, ParseError> {
+        let mut oplog = ListOpLog::new();
+        let arena = ContentArena::default();
+        let mut reader = BufReader::at(&self.data[..self.checked_len], self.discarded_len);
+        reader.read_magic()?;
+        reader.next_usize()?; // The protocol version was checked by push.
+
+        // The checksums are checked as the data is pushed.
+        let opts = DecodeOptions { ignore_crc: true, ..self.opts.clone() };
+        oplog.decode_chunks(&mut TopLevelChunks::Slice(reader.chunks()), opts, false, &arena)?;
+
+        self.start_discarding();
+        Ok(oplog)
+    }
+
+    /// Check the data pushed so far is made of whole chunks.
+    fn check_complete(&self) -> Result<(), ParseError> {
+        if self.next_chunk.is_none() || self.checked_len != self.data.len() {
+            Err(BufReader::at(&self.data[self.checked_len..], self.discarded_len + self.checked_len)
+                .err(ParseErrorKind::UnexpectedEOF))
+        } else { Ok(()) }
+    }
+
+    /// Has the named top level chunk been pushed in full?
+    fn has_read(&self, chunk_type: ListChunkType) -> bool {
+        let idx = TOP_LEVEL_CHUNKS.iter().position(|(c, _)| *c == chunk_type).unwrap();
+        self.next_chunk.is_some_and(|next| next > idx)
+    }
+
+    /// Throw away the data which has already been checked, and anything checked from now on. Only
+    /// the checksum of that data is kept, so it can't be decoded afterwards.
+    fn start_discarding(&mut self) {
+        self.discard = true;
+        self.discard_checked();
+    }
+
+    fn discard_checked(&mut self) {
+        self.data.drain(..self.checked_len);
+        self.discarded_len += self.checked_len;
+        self.checked_len = 0;
+    }
+
+    fn consume_checked(&mut self, len: usize) {
+        let end = self.checked_len + len;
+        self.digest.update(&self.data[self.checked_len..end]);
+        self.checked_len = end;
+    }
+
+    /// A reader for the body of a chunk which has been pushed. body_start is an index into data.
+    fn chunk_body(&self, body_start: usize, body_len: usize, chunk_type: ListChunkType) -> BufReader<'_> {
+        BufReader::at(&self.data[body_start..body_start + body_len], self.discarded_len + body_start)
+            .into_chunk(chunk_type as u32)
+    }
+
+    /// If the next top level chunk has been pushed in full, returns its type (or None if the type
+    /// is unknown), and the length of its header and body.
+    fn next_complete_chunk(&self) -> Result<Option<(Option<ListChunkType>, usize, usize)>, ParseError> {
+        let mut reader = BufReader::at(&self.data[self.checked_len..], self.discarded_len + self.checked_len);
+        let header = reader.next_u32()
+            .and_then(|chunk_type| Ok((chunk_type, reader.next_usize()?)));
+
+        let (chunk_type, body_len) = match header {
+            Err(e) if e.kind == ParseErrorKind::UnexpectedEOF => { return Ok(None); }
+            h => h?,
+        };
+        if body_len > reader.len() { return Ok(None); }
+
+        let header_len = self.data.len() - self.checked_len - reader.len();
+        Ok(Some((ListChunkType::try_from(chunk_type).ok(), header_len, body_len)))
+    }
+
+    /// Chunks need to appear in the same order that decode_internal reads them.
+    fn check_chunk_order(&mut self, chunk_type: ListChunkType) -> Result<(), ParseError> {
+        if chunk_type == ListChunkType::Snapshot {
+            return Err(ParseErrorKind::SnapshotData.into());
+        }
+
+        let next = self.next_chunk.unwrap();
+        let missing = TOP_LEVEL_CHUNKS[next..].iter()
+            .find(|(_, required)| *required);
+
+        match TOP_LEVEL_CHUNKS[next..].iter().position(|(c, _)| *c == chunk_type) {
+            Some(i) => {
+                // Make sure we didn't skip past any required chunks.
+                if let Some((c, _)) = TOP_LEVEL_CHUNKS[next..next + i].iter().find(|(_, required)| *required) {
+                    return Err(ParseErrorKind::MissingChunk(*c as u32).into());
+                }
+                self.next_chunk = Some(next + i + 1);
+            }
+            // Once all the required chunks have been read, anything else in the file is ignored.
+            None => if let Some((c, _)) = missing {
+                return Err(ParseErrorKind::MissingChunk(*c as u32).into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The progress of a [`DecodeDriver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeStatus {
+    /// The file's operations haven't arrived yet. Push more data.
+    NeedsData,
+    /// There are more operations to decode. Call [`work`](DecodeDriver::work) again.
+    InProgress,
+    /// All the operations have been decoded. Call [`finish`](DecodeDriver::finish) once the rest
+    /// of the file has been pushed.
+    Done,
+}
+
+/// Decodes an oplog a little bit at a time. This produces the same result as
+/// [`ListOpLog::load_from`], but the work can be spread out - for example, over several
+/// `setTimeout` callbacks in a browser, so loading a big document doesn't freeze the page.
+///
+/// Data is pushed in pieces, like with [`StreamingDecoder`]. Each call to
+/// [`work`](DecodeDriver::work) decodes at most the requested number of operations, and decoding
+/// starts as soon as the file's operations have arrived.
+///
+/// ```
+/// use diamond_types::list::ListOpLog;
+/// use diamond_types::list::encoding::{DecodeDriver, DecodeStatus, ENCODE_FULL};
+///
+/// let mut oplog = ListOpLog::new();
+/// let seph = oplog.get_or_create_agent_id("seph");
+/// oplog.add_insert(seph, 0, "hi there");
+/// let data = oplog.encode(ENCODE_FULL);
+///
+/// let mut driver = DecodeDriver::new(Default::default());
+/// for piece in data.chunks(10) {
+///     driver.push(piece).unwrap();
+/// }
+/// while driver.work(3).unwrap() != DecodeStatus::Done {
+///     // Let the rest of the application run.
+/// }
+/// assert_eq!(driver.finish().unwrap(), oplog);
+/// ```
+///
+/// Decoding can start before the file's checksum arrives, so a corrupt file might only be
+/// rejected by the last call to `push` or `finish`. Once any method returns an error the driver
+/// shouldn't be used again.
+pub struct DecodeDriver {
+    input: StreamInput,
+    oplog: ListOpLog,
+    state: DriverState,
+}
+
+enum DriverState {
+    /// Waiting for the Patches chunk.
+    Waiting,
+    Decoding(Box<PatchDecoder>, Box<OwnedPatchSources>),
+    Done,
+}
+
+/// A copy of a file's [`PatchSources`], which doesn't borrow from the file. Each chunk's bytes are
+/// stored with where they came from, so errors are still reported at the right place in the file.
+struct OwnedPatchSources {
+    assignments: (Vec<u8>, ReaderLoc),
+    positions: (Vec<u8>, ReaderLoc),
+    parents: (Vec<u8>, ReaderLoc),
+    ins_content: Option<((Vec<u8>, ReaderLoc), String)>,
+    del_content: Option<((Vec<u8>, ReaderLoc), String)>,
+    /// Always empty, since the content has been copied into the fields above.
+    shared: SharedBuffers,
+}
+
+impl OwnedPatchSources {
+    fn new(src: &PatchSources) -> Self {
+        let chunk = |r: BufReader| (r.buf.to_vec(), r.loc());
+        let content = |c: Option<ContentSource>| c.map(|c| (chunk(c.runs), c.content.to_string()));
+        Self {
+            assignments: chunk(src.assignments),
+            positions: chunk(src.positions),
+            parents: chunk(src.parents),
+            ins_content: content(src.ins_content),
+            del_content: content(src.del_content),
+            shared: SharedBuffers::default(),
+        }
+    }
+
+    fn borrow(&self) -> PatchSources<'_> {
+        fn chunk((bytes, loc): &(Vec<u8>, ReaderLoc)) -> BufReader<'_> {
+            BufReader::with_loc(bytes, *loc)
+        }
+        fn content(c: &Option<((Vec<u8>, ReaderLoc), String)>) -> Option<ContentSource<'_>> {
+            c.as_ref().map(|(runs, content)| ContentSource { runs: chunk(runs), content })
+        }
+        PatchSources {
+            assignments: chunk(&self.assignments),
+            positions: chunk(&self.positions),
+            parents: chunk(&self.parents),
+            ins_content: content(&self.ins_content),
+            del_content: content(&self.del_content),
+            shared: &self.shared,
+        }
+    }
+}
+
+impl std::fmt::Debug for DecodeDriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self.state {
+            DriverState::Waiting => DecodeStatus::NeedsData,
+            DriverState::Decoding(..) => DecodeStatus::InProgress,
+            DriverState::Done => DecodeStatus::Done,
+        };
+        f.debug_struct("DecodeDriver")
+            .field("input", &self.input)
+            .field("status", &status)
+            .field("decoded_operations", &self.oplog.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl DecodeDriver {
+    pub fn new(opts: DecodeOptions) -> Self {
+        Self {
+            input: StreamInput::new(opts),
+            oplog: ListOpLog::new(),
+            state: DriverState::Waiting,
+        }
+    }
+
+    /// Add the next piece of the file. See [`StreamingDecoder::push`].
+    pub fn push(&mut self, bytes: &[u8]) -> Result<(), ParseError> {
+        self.input.push(bytes)
+    }
+
+    /// Decode up to `budget` more operations. Each operation is counted twice - once when its
+    /// content is read and again when its parents are read.
+    ///
+    /// The first call after the operations arrive also reads the rest of the file's header, and
+    /// decompresses any compressed content. That work isn't counted against the budget.
+    pub fn work(&mut self, budget: usize) -> Result<DecodeStatus, ParseError> {
+        if let DriverState::Waiting = self.state {
+            if !self.input.has_read(ListChunkType::Patches) {
+                return Ok(DecodeStatus::NeedsData);
+            }
+            self.start()?;
+        }
+
+        let finished = match &mut self.state {
+            DriverState::Decoding(patches, sources) => patches.step(&mut self.oplog, &sources.borrow(), budget)?,
+            DriverState::Waiting | DriverState::Done => true,
+        };
+        if !finished { return Ok(DecodeStatus::InProgress); }
+
+        if let DriverState::Decoding(patches, sources) = std::mem::replace(&mut self.state, DriverState::Done) {
+            patches.finish(&mut self.oplog, &sources.borrow())?;
+        }
+        Ok(DecodeStatus::Done)
+    }
+
+    /// Read everything in the file before the operations, and copy out the operation data.
+    fn start(&mut self) -> Result<(), ParseError> {
+        let arena = ContentArena::default();
+        let mut reader = BufReader::new(&self.input.data);
+        reader.read_magic()?;
+        reader.next_usize()?; // The protocol version was checked by push.
+
+        let header = self.oplog.decode_header(&mut TopLevelChunks::Slice(reader.chunks()), &self.input.opts, &arena)?;
+        self.state = DriverState::Decoding(Box::new(header.patches), Box::new(OwnedPatchSources::new(&header.sources)));
+
+        // Everything we need has been copied out, so there's no need to keep the data around.
+        self.input.start_discarding();
+        Ok(())
+    }
+
+    /// Finish decoding, and return the loaded oplog. Any operations which
+    /// [`work`](DecodeDriver::work) hasn't decoded yet are decoded now.
+    ///
+    /// This fails with [`ParseErrorKind::UnexpectedEOF`] if the data ends part way through a chunk.
+    pub fn finish(mut self) -> Result<ListOpLog, ParseError> {
+        self.input.check_complete()?;
+        // If the file has no operations, this finds the error.
+        if let DriverState::Waiting = self.state {
+            self.start()?;
+        }
+        self.work(usize::MAX)?;
+        Ok(self.oplog)
+    }
+}
+
+/// Read the protocol version from the header of an encoded file, without parsing the rest of it.
+/// This works even if this build can't read the version, so tools can say what they're dealing
+/// with.
+pub fn detect_version(data: &[u8]) -> Result<usize, ParseError> {
+    let mut reader = BufReader::new(data);
+    reader.read_magic()?;
+    reader.next_usize()
+}
+
+/// Read the [`Capability`] flags an encoded file lists, as (required, optional), without parsing
+/// the rest of the file. Files written before capabilities were added list none.
+///
+/// Unlike loading the file, this doesn't fail when the file requires capabilities this version
+/// doesn't know about. They're listed by [`CapabilitySet::unknown`]. The file's checksum isn't
+/// checked.
+pub fn capabilities(data: &[u8]) -> Result<(CapabilitySet, CapabilitySet), ParseError> {
+    let mut reader = BufReader::new(data);
+    reader.read_magic()?;
+    let version_reader = reader;
+    if !protocol_version_supported(reader.next_usize()?) {
+        return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
+    }
+    let mut reader = reader.chunks();
+    reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?;
+
+    let mut fileinfo = reader.expect_chunk(ListChunkType::FileInfo)?.chunks();
+    while !fileinfo.is_empty() {
+        if let (ListChunkType::Capabilities, chunk) = fileinfo.next_chunk()? {
+            return read_capabilities(Some(chunk));
+        }
+    }
+    Ok(Default::default())
+}
+
+/// The size of one chunk in an encoded file. See [`chunk_sizes`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkSize {
+    /// The chunk's type, eg `"Patches"` or `"AgentNames"`.
+    pub name: String,
+    /// 0 for top level chunks, 1 for chunks nested inside them.
+    pub depth: usize,
+    /// The size of the chunk's body in bytes, not counting its type and length header.
+    pub len: usize,
+}
+
+/// One chunk in an encoded file. See [`iter_chunks`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub chunk_type: ListChunkType,
+    /// The offset of the chunk's header (its type) from the start of the file.
+    pub offset: usize,
+    /// The size of the chunk's body in bytes, not counting its type and length header.
+    pub len: usize,
+    /// 0 for top level chunks, 1 for chunks nested inside them.
+    pub depth: usize,
+}
+
+/// Iterate through the chunks in an encoded file, in the order they appear. Chunks nested inside
+/// `FileInfo`, `StartBranch`, `Snapshot` and `Patches` are listed straight after their parent.
+/// Chunks with unknown types are skipped.
+///
+/// This only reads the chunk headers. The contents aren't checked, so this works on files which
+/// are too big (or too broken) to load. The iterator stops after the first error.
+pub fn iter_chunks(data: &[u8]) -> impl Iterator<Item = Result<ChunkInfo, ParseError>> + '_ {
+    let mut reader = BufReader::new(data);
+    let mut header_err = reader.read_magic().and_then(|_| reader.next_usize()).err();
+    let mut outer = Some(reader.chunks());
+    let mut inner: Option<ChunkReader> = None;
+
+    std::iter::from_fn(move || {
+        if let Some(e) = header_err.take() {
+            outer = None;
+            return Some(Err(e));
+        }
+
+        loop {
+            if inner.as_ref().is_some_and(|c| c.is_empty()) { inner = None; }
+            let (chunks, depth) = match (inner.as_mut(), outer.as_mut()) {
+                (Some(inner), _) => (inner, 1),
+                (None, Some(outer)) if !outer.is_empty() => (outer, 0),
+                _ => { return None; }
+            };
+
+            let offset = chunks.0.pos().unwrap();
+            // Unlike chunks().next(), this doesn't skip chunk CRCs.
+            match chunks.next_chunk_raw() {
+                Err(e) if e.kind == ParseErrorKind::UnknownChunk => {}
+                Err(e) => {
+                    (inner, outer) = (None, None);
+                    return Some(Err(e));
+                }
+                Ok((chunk_type, body)) => {
+                    if depth == 0 && matches!(chunk_type, FileInfo | StartBranch | Snapshot | Patches) {
+                        inner = Some(body.chunks());
+                    }
+                    return Some(Ok(ChunkInfo { chunk_type, offset, len: body.len(), depth }));
+                }
+            }
+        }
+    })
+}
+
+/// List the chunks in an encoded file along with their sizes, in the order they appear. This is
+/// a summary of [`iter_chunks`].
+pub fn chunk_sizes(data: &[u8]) -> Result<Vec<ChunkSize>, ParseError> {
+    iter_chunks(data)
+        .map(|c| c.map(|c| ChunkSize { name: format!("{:?}", c.chunk_type), depth: c.depth, len: c.len }))
+        .collect()
+}
+
+/// What [`verify_data`] found in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSummary {
+    /// The number of operations stored in the file.
+    pub num_operations: usize,
+    /// The number of agents listed in the file.
+    pub num_agents: usize,
+    /// The number of entries in the file's causal graph.
+    pub num_history_entries: usize,
+    /// The version the file's operations start from. This is empty (ROOT) unless the file was
+    /// written with [`encode_from`](ListOpLog::encode_from).
+    pub start_version: Vec<RemoteVersionOwned>,
+    /// The version of the data in the file. This is what
+    /// [`decode_and_add`](ListOpLog::decode_and_add) returns when the file is loaded.
+    pub version: Vec<RemoteVersionOwned>,
+    /// The file's chunks and their sizes. See [`chunk_sizes`].
+    pub chunks: Vec<ChunkSize>,
+    /// The capabilities the file requires. See [`capabilities`].
+    pub required_capabilities: CapabilitySet,
+    /// The optional capabilities the file uses.
+    pub optional_capabilities: CapabilitySet,
+}
+
+/// Read one entry of an agent assignment chunk as (file agent index, seq range), using
+/// `next_seqs` to track each agent's seq cursor. This is the same as
+/// `read_next_agent_assignment`, but without an oplog.
+fn verify_agent_assignment(reader: &mut BufReader, agent_map: &FileAgentMap, next_seqs: &mut [usize]) -> Result<(usize, DTRange), ParseError> {
+    let record = *reader;
+    let mut n = reader.next_usize()?;
+    let has_jump = strip_bit_usize_2(&mut n);
+    let len = reader.next_usize()?;
+    let jump = if has_jump { reader.next_zigzag_isize()? } else { 0 };
+
+    if n == 0 || n > next_seqs.len() { return Err(record.err(ParseErrorKind::InvalidLength)); }
+    let agent = n - 1;
+    let name = agent_map.0[agent].name.as_str();
+    if name == "ROOT" || name.len() >= MAX_AGENT_NAME_LENGTH {
+        return Err(record.err(ParseErrorKind::GenericInvalidData));
+    }
+
+    let start = next_seqs[agent].checked_add_signed(jump).ok_or_else(|| record.err(ParseErrorKind::InvalidLength))?;
+    let end = start.checked_add(len).ok_or_else(|| record.err(ParseErrorKind::InvalidLength))?;
+    next_seqs[agent] = end;
+    Ok((agent, (start..end).into()))
+}
+
+/// Check a file is valid without loading it into an oplog.
+///
+/// This reads through the whole file, checking the checksums, the structure of every chunk, the
+/// content (which must be valid UTF-8), and that the agent assignments, operations, content and
+/// causal graph all describe the same number of operations. Nothing is added to an oplog, so this
+/// uses much less memory than [`ListOpLog::load_from`].
+///
+/// Passing doesn't guarantee the file will load. Patches written with
+/// [`encode_from`](ListOpLog::encode_from) refer to operations which aren't in the file, and those
+/// can only be checked against the oplog they're merged into.
+pub fn verify_data(data: &[u8]) -> Result<FileSummary, ParseError> {
+    let content_arena = ContentArena::default();
+    let opts = DecodeOptions::default();
+
+    let mut reader = BufReader::new(data);
+    reader.read_magic()?;
+    let version_reader = reader;
+    if !protocol_version_supported(reader.next_usize()?) {
+        return Err(version_reader.err(ParseErrorKind::UnsupportedProtocolVersion));
+    }
+    let mut reader = reader.chunks();
+    validate_chunks(data, reader.clone(), true)?;
+
+    let mut compressed_chunk = decompress_fields(
+        reader.read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4)?, &content_arena
+    )?;
+
+    let FileInfoData { agent_map, required_capabilities, optional_capabilities, .. } = reader.expect_chunk(ListChunkType::FileInfo)?
+        .chunks().read_fileinfo(&opts)?;
+    let num_agents = agent_map.0.len();
+    let remote = |agent: usize, seq: usize| RemoteVersionOwned(agent_map.0[agent].name.clone(), seq);
+
+    if reader.read_chunk_if_eq(ListChunkType::Snapshot)?.is_some() {
+        return Err(ParseErrorKind::SnapshotData.into());
+    }
+
+    // The start version is read the same way as read_start_version.
+    let mut start_branch = reader.expect_chunk(ListChunkType::StartBranch)?.chunks();
+    let start_version = read_remote_version(start_branch.read_chunk_if_eq(ListChunkType::Version)?, &agent_map)?;
+    if !start_branch.is_empty() {
+        start_branch.expect_content_str(compressed_chunk.as_mut(), &content_arena)?;
+    }
+    start_branch.expect_empty()?;
+
+    if let Some(end_branch) = reader.read_chunk_if_eq(ListChunkType::ExperimentalEndBranch)? {
+        let mut end_branch = end_branch.chunks();
+        end_branch.read_chunk_if_eq(ListChunkType::Version)?;
+        end_branch.expect_content_str(compressed_chunk.as_mut(), &content_arena)?;
+    }
+
+    let mut patch_chunk = reader.expect_chunk(ListChunkType::Patches)?.chunks();
+
+    // The number of characters of content listed for inserts and deletes.
+    let mut content_len = [None, None];
+    let mut content_end = [None, None];
+    while let Some(chunk) = patch_chunk.read_chunk_if_eq(ListChunkType::PatchContent)? {
+        let (tag, iter) = ReadPatchContentIter::new(chunk, compressed_chunk.as_mut(), &content_arena)?;
+        content_end[tag as usize] = Some(iter.run_chunk.skip(iter.run_chunk.len()));
+        let mut len = 0;
+        for item in iter { len += item?.len; }
+        content_len[tag as usize] = Some(len);
+    }
+
+    // Agent assignments. These are kept (in file order) to name the file's version at the end.
+    let mut assignments_chunk = patch_chunk.expect_chunk(ListChunkType::OpVersions)?;
+    let mut next_seqs = vec![0; num_agents];
+    let mut assignments: Vec<(LV, usize, DTRange)> = vec![];
+    let mut num_operations = 0;
+    while !assignments_chunk.is_empty() {
+        let (agent, seq_range) = verify_agent_assignment(&mut assignments_chunk, &agent_map, &mut next_seqs)?;
+        assignments.push((num_operations, agent, seq_range));
+        num_operations += seq_range.len();
+    }
+
+    // Operations.
+    let positions_chunk = patch_chunk.expect_chunk(ListChunkType::OpTypeAndPosition)?;
+    let positions_end = positions_chunk.skip(positions_chunk.len());
+    let mut op_len = [0, 0];
+    for op in ReadPatchesIter::new(positions_chunk) {
+        let op = op?;
+        op_len[op.kind as usize] += op.len();
+    }
+
+    // The causal graph. Foreign parents name operations from before the file, by agent and seq.
+    let mut parents_chunk = patch_chunk.expect_chunk(ListChunkType::OpParents)?;
+    let parents_end = parents_chunk.skip(parents_chunk.len());
+    let checkpoints_chunk = patch_chunk.read_chunk_if_eq(ListChunkType::Checkpoints)?;
+    let checkpoints_end = checkpoints_chunk.map(|c| c.skip(c.len()));
+    let checkpoints = read_checkpoints(checkpoints_chunk)?;
+    let timestamps_chunk = patch_chunk.read_chunk_if_eq(ListChunkType::Timestamps)?;
+    let timestamps_end = timestamps_chunk.map(|c| c.skip(c.len()));
+    let timestamps = read_timestamps(timestamps_chunk)?;
+    patch_chunk.expect_empty()?;
+
+    let mut frontier = Frontier::root();
+    let mut foreign_frontier = start_version.clone();
+    let mut num_history_entries = 0;
+    let mut next_time = 0;
+    while !parents_chunk.is_empty() {
+        let entry_start = parents_chunk;
+        let len = parents_chunk.next_usize()?;
+        let end = next_time + len;
+        let mut parents = SmallVec::<[LV; 2]>::new();
+        loop {
+            let mut n = parents_chunk.next_usize()?;
+            let is_foreign = strip_bit_usize_2(&mut n);
+            let has_more = strip_bit_usize_2(&mut n);
+
+            if is_foreign {
+                if n == 0 { break; }
+                if n > num_agents { return Err(entry_start.err(ParseErrorKind::InvalidParent)); }
+                let seq = parents_chunk.next_usize()?;
+                let id = remote(n - 1, seq);
+                foreign_frontier.retain(|v| *v != id);
+            } else {
+                if n == 0 || n > next_time { return Err(entry_start.err(ParseErrorKind::InvalidParent)); }
+                parents.push(next_time - n);
+            }
+
+            if !has_more { break; }
+        }
+        sort_frontier(&mut parents);
+
+        if len > 0 {
+            frontier.advance_by_known_run(&parents, (next_time..end).into());
+        }
+        num_history_entries += 1;
+        next_time = end;
+    }
+
+    // Every section has to describe the same operations. Checkpoints don't have a position.
+    if checkpoints.last().is_some_and(|(pos, _)| *pos >= num_operations) {
+        return Err(checkpoints_end.unwrap().err(ParseErrorKind::InvalidLength));
+    }
+    if timestamps.last().is_some_and(|(range, _)| range.end > num_operations) {
+        return Err(timestamps_end.unwrap().err(ParseErrorKind::InvalidLength));
+    }
+    if op_len[0] + op_len[1] + checkpoints.len() != num_operations {
+        return Err(positions_end.err(ParseErrorKind::InvalidLength));
+    }
+    if next_time != num_operations {
+        return Err(parents_end.err(ParseErrorKind::InvalidLength));
+    }
+    for kind in [Ins, Del] {
+        if let (Some(len), Some(end)) = (content_len[kind as usize], content_end[kind as usize]) {
+            if len != op_len[kind as usize] { return Err(end.err(ParseErrorKind::InvalidContent)); }
+        }
+    }
+
+    let mut version = foreign_frontier;
+    for &lv in frontier.iter() {
+        let idx = assignments.partition_point(|(start, _, _)| *start <= lv) - 1;
+        let (start, agent, seq_range) = assignments[idx];
+        version.push(remote(agent, seq_range.start + lv - start));
+    }
+
+    Ok(FileSummary {
+        num_operations,
+        num_agents,
+        num_history_entries,
+        start_version,
+        version,
+        chunks: chunk_sizes(data)?,
+        required_capabilities,
+        optional_capabilities,
+    })
+}
+
 #[allow(unused)]
 pub(super) fn dbg_print_chunks_in(bytes: &[u8]) {
-    BufReader(bytes).dbg_print_chunk_tree();
+    BufReader::new(bytes).dbg_print_chunk_tree();
 }
@@ -1,15 +1,77 @@
+use std::io;
+use std::io::Read;
 use std::mem::size_of;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::tools::CRC32C;
+use crate::encoding::parseerror::{ChecksumMismatch, ChunkPath, ParseError, ParseErrorKind};
 use crate::list::encoding::leb::num_decode_zigzag_isize_old;
-use crate::list::encoding::{DataType, ListChunkType, MAGIC_BYTES};
+use crate::list::encoding::{DataType, ListChunkType, MAGIC_BYTES, protocol_version_supported};
 use crate::list::encoding::leb::{decode_leb_u32, decode_leb_u64, decode_leb_usize};
 
-#[derive(Debug, Clone)]
-pub struct BufReader<'a>(pub(super) &'a [u8]);
+#[derive(Debug, Clone, Copy)]
+pub struct BufReader<'a> {
+    pub(super) buf: &'a [u8],
+    loc: ReaderLoc,
+}
+
+/// Where the bytes in a [`BufReader`] came from. This is used to say where parse errors happen.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct ReaderLoc {
+    /// The offset of the end of the reader's bytes in the data being decoded. None if the bytes
+    /// aren't in the data directly (because they were decompressed).
+    end: Option<usize>,
+    /// The chunks the bytes are inside.
+    chunk: ChunkPath,
+}
 
 impl<'a> BufReader<'a> {
+    /// Read from the start of the data being decoded.
+    pub(super) fn new(buf: &'a [u8]) -> Self {
+        Self::at(buf, 0)
+    }
+
+    /// Read bytes which start at the named offset in the data being decoded.
+    pub(super) fn at(buf: &'a [u8], start: usize) -> Self {
+        Self { buf, loc: ReaderLoc { end: Some(start + buf.len()), chunk: ChunkPath::default() } }
+    }
+
+    /// Read a copy of bytes which came from somewhere else. Errors are reported at loc.
+    pub(super) fn with_loc(buf: &'a [u8], loc: ReaderLoc) -> Self {
+        Self { buf, loc }
+    }
+
+    /// Read data which was decompressed out of this reader's chunk. Positions in the decompressed
+    /// data don't correspond to positions in the file, so errors only name the chunk.
+    pub(super) fn decompressed<'b>(&self, buf: &'b [u8]) -> BufReader<'b> {
+        BufReader { buf, loc: ReaderLoc { end: None, chunk: self.loc.chunk } }
+    }
+
+    /// Read these bytes as the body of the named chunk.
+    pub(super) fn into_chunk(mut self, chunk_type: u32) -> Self {
+        self.loc.chunk = self.loc.chunk.push(chunk_type);
+        self
+    }
+
+    pub(super) fn loc(&self) -> ReaderLoc {
+        self.loc
+    }
+
+    /// The offset of the next byte to be read in the data being decoded.
+    pub(super) fn pos(&self) -> Option<usize> {
+        self.loc.end.map(|end| end - self.buf.len())
+    }
+
+    /// Make an error located at the reader's current position.
+    pub(super) fn err(&self, kind: ParseErrorKind) -> ParseError {
+        self.locate(kind.into())
+    }
+
+    /// Fill in the location of an error found while reading from here, if it isn't known already.
+    pub(super) fn locate(&self, e: ParseError) -> ParseError {
+        e.or_at(self.pos(), self.loc.chunk)
+    }
+
     // fn check_has_bytes(&self, num: usize) {
-    //     assert!(self.0.len() >= num);
+    //     assert!(self.buf.len() >= num);
     // }
 
     #[inline]
@@ -19,30 +81,36 @@ impl<'a> BufReader<'a> {
 
     #[inline]
     pub(super) fn check_has_bytes(&self, num: usize) -> Result<(), ParseError> {
-        if self.0.len() < num { Err(ParseError::UnexpectedEOF) } else { Ok(()) }
+        if self.buf.len() < num { Err(self.err(ParseErrorKind::UnexpectedEOF)) } else { Ok(()) }
     }
 
     pub(super) fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.buf.is_empty()
     }
 
     pub(super) fn expect_empty(&self) -> Result<(), ParseError> {
-        if self.is_empty() { Ok(()) } else { Err(ParseError::InvalidLength) }
+        if self.is_empty() { Ok(()) } else { Err(self.err(ParseErrorKind::InvalidLength)) }
     }
 
     #[allow(unused)]
     pub(super) fn len(&self) -> usize {
-        self.0.len()
+        self.buf.len()
     }
 
     pub(super) fn consume(&mut self, num: usize) {
-        self.0 = unsafe { self.0.get_unchecked(num..) };
+        self.buf = unsafe { self.buf.get_unchecked(num..) };
+    }
+
+    /// A reader for the rest of the bytes, starting `num` bytes in.
+    pub(super) fn skip(mut self, num: usize) -> Self {
+        self.consume(num);
+        self
     }
 
     pub(super) fn read_magic(&mut self) -> Result<(), ParseError> {
         self.check_has_bytes(8)?;
-        if self.0[..MAGIC_BYTES.len()] != MAGIC_BYTES {
-            return Err(ParseError::InvalidMagic);
+        if self.buf[..MAGIC_BYTES.len()] != MAGIC_BYTES {
+            return Err(self.err(ParseErrorKind::InvalidMagic));
         }
         self.consume(8);
         Ok(())
@@ -50,20 +118,22 @@ impl<'a> BufReader<'a> {
 
     pub(super) fn peek_u32(&self) -> Result<Option<u32>, ParseError> {
         if self.is_empty() { return Ok(None); }
-        // Some(decode_u32(self.0))
-        Ok(Some(decode_leb_u32(self.0)?.0))
+        // Some(decode_u32(self.buf))
+        Ok(Some(decode_leb_u32(self.buf).map_err(|e| self.locate(e))?.0))
     }
 
     pub(super) fn next_u32(&mut self) -> Result<u32, ParseError> {
         self.check_not_empty()?;
-        let (val, count) = decode_leb_u32(self.0)?;
+        let (val, count) = decode_leb_u32(self.buf).map_err(|e| self.locate(e))?;
         self.consume(count);
         Ok(val)
     }
 
     pub(super) fn next_u32_le(&mut self) -> Result<u32, ParseError> {
         // self.check_has_bytes(size_of::<u32>())?;
-        let val = u32::from_le_bytes(self.0[0..4].try_into().map_err(|_| ParseError::UnexpectedEOF)?);
+        let val = u32::from_le_bytes(self.buf.get(0..4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| self.err(ParseErrorKind::UnexpectedEOF))?);
         self.consume(size_of::<u32>());
         Ok(val)
     }
@@ -71,14 +141,14 @@ impl<'a> BufReader<'a> {
     #[allow(unused)]
     pub(super) fn next_u64(&mut self) -> Result<u64, ParseError> {
         self.check_not_empty()?;
-        let (val, count) = decode_leb_u64(self.0)?;
+        let (val, count) = decode_leb_u64(self.buf).map_err(|e| self.locate(e))?;
         self.consume(count);
         Ok(val)
     }
 
     pub(super) fn next_usize(&mut self) -> Result<usize, ParseError> {
         self.check_not_empty()?;
-        let (val, count) = decode_leb_usize(self.0)?;
+        let (val, count) = decode_leb_usize(self.buf).map_err(|e| self.locate(e))?;
         self.consume(count);
         Ok(val)
     }
@@ -89,13 +159,20 @@ impl<'a> BufReader<'a> {
     }
 
     pub(super) fn next_n_bytes(&mut self, num_bytes: usize) -> Result<&'a [u8], ParseError> {
-        if num_bytes > self.0.len() { return Err(ParseError::UnexpectedEOF); }
+        if num_bytes > self.buf.len() { return Err(self.err(ParseErrorKind::UnexpectedEOF)); }
 
-        let (data, remainder) = self.0.split_at(num_bytes);
-        self.0 = remainder;
+        let (data, remainder) = self.buf.split_at(num_bytes);
+        self.buf = remainder;
         Ok(data)
     }
 
+    /// Read the next num_bytes bytes into a reader for the body of the named chunk.
+    fn next_chunk_body(&mut self, num_bytes: usize, chunk_type: u32) -> Result<BufReader<'a>, ParseError> {
+        let end = self.pos().map(|pos| pos + num_bytes);
+        let buf = self.next_n_bytes(num_bytes)?;
+        Ok(BufReader { buf, loc: ReaderLoc { end, chunk: self.loc.chunk.push(chunk_type) } })
+    }
+
     // fn split(self, num_bytes: usize) -> Result<(Self, Self), ParseError> {
     //     if num_bytes > self.0.len() { return Err(UnexpectedEOF); }
     //
@@ -119,28 +196,29 @@ impl<'a> BufReader<'a> {
 
     // Note the result is attached to the lifetime 'a, not the lifetime of self.
     pub(super) fn next_str(&mut self) -> Result<&'a str, ParseError> {
-        if self.0.is_empty() { return Err(ParseError::UnexpectedEOF); }
+        if self.buf.is_empty() { return Err(self.err(ParseErrorKind::UnexpectedEOF)); }
 
         let len = self.next_usize()?;
-        if len > self.0.len() { return Err(ParseError::InvalidLength); }
+        if len > self.buf.len() { return Err(self.err(ParseErrorKind::InvalidLength)); }
 
+        let start = *self;
         let bytes = self.next_n_bytes(len)?;
         // std::str::from_utf8(bytes).map_err(InvalidUTF8)
-        std::str::from_utf8(bytes).map_err(|_| ParseError::InvalidUTF8)
+        std::str::from_utf8(bytes).map_err(|_| start.err(ParseErrorKind::InvalidUTF8))
     }
 
     /// Read the next string thats encoded in this content chunk
     pub(super) fn into_content_str(mut self) -> Result<&'a str, ParseError> {
-        // dbg!(&self.0);
+        // dbg!(&self.buf);
         let data_type = self.next_u32()?;
         if data_type != (DataType::PlainText as u32) {
-            return Err(ParseError::UnknownChunk);
+            return Err(self.err(ParseErrorKind::UnknownChunk));
         }
         // let len = self.next_usize()?;
-        // if len > self.0.len() {
+        // if len > self.buf.len() {
         //     return Err(InvalidLength);
         // }
-        std::str::from_utf8(self.0).map_err(|_| ParseError::InvalidUTF8)
+        std::str::from_utf8(self.buf).map_err(|_| self.err(ParseErrorKind::InvalidUTF8))
     }
 
     pub fn dbg_print_chunk_tree_internal(mut self) -> Result<(), ParseError> {
@@ -205,37 +283,50 @@ impl<'a> ChunkReader<'a> {
         self.0.expect_empty()
     }
 
-    fn next_chunk_raw(&mut self) -> Result<(ListChunkType, BufReader<'a>), ParseError> {
-        let chunk_type = ListChunkType::try_from(self.0.next_u32()?)
-            .map_err(|_| ParseError::UnknownChunk);
+    /// Read the next chunk, including unknown chunks (which return ParseErrorKind::UnknownChunk).
+    pub(super) fn next_chunk_raw(&mut self) -> Result<(ListChunkType, BufReader<'a>), ParseError> {
+        let start = self.0;
+        let raw_chunk_type = self.0.next_u32()?;
+        let chunk_type = ListChunkType::try_from(raw_chunk_type)
+            .map_err(|_| start.err(ParseErrorKind::UnknownChunk));
 
         // This in no way guarantees we're good.
         let len = self.0.next_usize()?;
         if len > self.0.len() {
-            return Err(ParseError::InvalidLength);
+            return Err(self.0.err(ParseErrorKind::InvalidLength));
         }
 
-        let reader = BufReader(self.0.next_n_bytes(len)?);
+        let reader = self.0.next_chunk_body(len, raw_chunk_type)?;
 
         // Note we're try-ing chunk_type here so we still read all the bytes if we can, even if
         // the chunk type is unknown.
         Ok((chunk_type?, reader))
     }
 
-    /// Read the next chunk, skipping unknown chunks for forwards compatibility.
+    /// Read the next chunk, skipping unknown chunks for forwards compatibility. Chunk CRCs are
+    /// skipped too. They're checked before parsing starts.
     pub(super) fn next_chunk(&mut self) -> Result<(ListChunkType, BufReader<'a>), ParseError> {
         loop {
             let c = self.next_chunk_raw();
             match c {
-                Err(ParseError::UnknownChunk) => {}, // Keep scanning.
+                Err(e) if e.kind == ParseErrorKind::UnknownChunk => {}, // Keep scanning.
+                Ok((ListChunkType::ChunkCrc, _)) => {},
                 _ => { return c; }
             }
         }
     }
 
     /// Read a chunk with the named type. Returns None if the next chunk isn't the specified type,
-    /// or we hit EOF.
+    /// or we hit EOF. Chunk CRCs and unknown chunks before it are skipped.
     pub(super) fn read_chunk_if_eq(&mut self, expect_chunk_type: ListChunkType) -> Result<Option<BufReader<'a>>, ParseError> {
+        while let Some(t) = self.0.peek_u32()? {
+            if t != ListChunkType::ChunkCrc as u32 && ListChunkType::try_from(t).is_ok() { break; }
+            match self.next_chunk_raw() {
+                Err(e) if e.kind == ParseErrorKind::UnknownChunk => {},
+                r => { r?; }
+            }
+        }
+
         if let Some(actual_chunk_type) = self.0.peek_u32()? {
             if actual_chunk_type != (expect_chunk_type as u32) {
                 // Chunk doesn't match requested type.
@@ -252,13 +343,14 @@ impl<'a> ChunkReader<'a> {
     pub(super) fn expect_chunk_pred<P>(&mut self, pred: P, err_type: ListChunkType) -> Result<(ListChunkType, BufReader<'a>), ParseError>
         where P: FnOnce(ListChunkType) -> bool
     {
+        let start = self.0;
         let (actual_chunk_type, r) = self.next_chunk()?;
 
         if pred(actual_chunk_type) {
             // dbg!(expect_chunk_type, actual_chunk_type);
             Ok((actual_chunk_type, r))
         } else {
-            Err(ParseError::MissingChunk(err_type as _))
+            Err(start.err(ParseErrorKind::MissingChunk(err_type as _)))
         }
     }
 
@@ -266,4 +358,246 @@ impl<'a> ChunkReader<'a> {
         self.expect_chunk_pred(|c| c == expect_chunk_type, expect_chunk_type)
             .map(|(_c, r)| r)
     }
-}
\ No newline at end of file
+}
+/// Reads the top level chunks of a file from an [`io::Read`], one chunk at a time. Each chunk is
+/// read into its own buffer, which is handed to the decoder as a [`Chunk`].
+pub(super) struct StreamChunkReader<'r> {
+    reader: &'r mut dyn Read,
+
+    /// The next chunk (type, body and location), if its been read but not consumed.
+    peeked: Option<(u32, Vec<u8>, ReaderLoc)>,
+    at_eof: bool,
+    /// The number of bytes read so far.
+    pos: usize,
+
+    check_crc: bool,
+    /// Checksum of all the bytes read so far.
+    digest: crc::Digest<'static, u32>,
+    /// Checksum of the bytes read since the start of the current chunk.
+    chunk_digest: crc::Digest<'static, u32>,
+    /// The checksum of the last chunk read (other than a chunk CRC), along with where its body
+    /// starts and its type to report errors with.
+    last_chunk: Option<(u32, usize, u32)>,
+
+    /// IO errors (other than EOF) are stashed here, and show up to the decoder as UnexpectedEOF.
+    io_error: Option<io::Error>,
+}
+
+impl<'r> StreamChunkReader<'r> {
+    pub(super) fn new(reader: &'r mut dyn Read, check_crc: bool) -> Self {
+        Self {
+            reader,
+            peeked: None,
+            at_eof: false,
+            pos: 0,
+            check_crc,
+            digest: CRC32C.digest(),
+            chunk_digest: CRC32C.digest(),
+            last_chunk: None,
+            io_error: None,
+        }
+    }
+
+    fn err(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError::from(kind).or_at(Some(self.pos), ChunkPath::default())
+    }
+
+    fn io_err(&mut self, e: io::Error) -> ParseError {
+        if e.kind() != io::ErrorKind::UnexpectedEof {
+            self.io_error = Some(e);
+        }
+        self.err(ParseErrorKind::UnexpectedEOF)
+    }
+
+    /// Read the next byte, or None at EOF.
+    fn next_byte(&mut self) -> Result<Option<u8>, ParseError> {
+        let mut buf = [0u8];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => {
+                    self.digest.update(&buf);
+                    self.chunk_digest.update(&buf);
+                    self.pos += 1;
+                    return Ok(Some(buf[0]));
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(self.io_err(e)),
+            }
+        }
+    }
+
+    /// Read a LEB128 varint which starts with first_byte.
+    fn next_leb_usize(&mut self, first_byte: u8) -> Result<usize, ParseError> {
+        let mut buf = [first_byte; 10];
+        let mut len = 1;
+        while buf[len - 1] >= 0x80 && len < buf.len() {
+            buf[len] = self.next_byte()?.ok_or_else(|| self.err(ParseErrorKind::UnexpectedEOF))?;
+            len += 1;
+        }
+        Ok(decode_leb_usize(&buf[..len]).map_err(|e| e.or_at(Some(self.pos - len), ChunkPath::default()))?.0)
+    }
+
+    fn next_n_bytes(&mut self, len: usize) -> Result<Vec<u8>, ParseError> {
+        // The length comes from the file, so it isn't trusted enough to allocate up front.
+        let mut bytes = Vec::new();
+        if let Err(e) = Read::take(&mut *self.reader, len as u64).read_to_end(&mut bytes) {
+            return Err(self.io_err(e));
+        }
+        self.pos += bytes.len();
+        if bytes.len() != len { return Err(self.err(ParseErrorKind::UnexpectedEOF)); }
+
+        self.digest.update(&bytes);
+        self.chunk_digest.update(&bytes);
+        Ok(bytes)
+    }
+
+    /// Read the magic bytes and protocol version at the start of the file.
+    fn read_header(&mut self) -> Result<usize, ParseError> {
+        let magic = self.next_n_bytes(MAGIC_BYTES.len())?;
+        if magic != MAGIC_BYTES {
+            return Err(ParseErrorKind::InvalidMagic.into());
+        }
+        let first = self.next_byte()?.ok_or_else(|| self.err(ParseErrorKind::UnexpectedEOF))?;
+        self.next_leb_usize(first)
+    }
+
+    fn fill_peeked(&mut self) -> Result<(), ParseError> {
+        while self.peeked.is_none() && !self.at_eof {
+            self.read_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Read the next chunk into self.peeked. Chunk CRCs are checked and then dropped.
+    fn read_chunk(&mut self) -> Result<(), ParseError> {
+        // The checksum covers everything before the CRC chunk.
+        let checksum = self.digest.clone().finalize();
+        self.chunk_digest = CRC32C.digest();
+
+        let Some(first) = self.next_byte()? else {
+            self.at_eof = true;
+            return Ok(());
+        };
+        let chunk_start = self.pos - 1;
+        let chunk_type = self.next_leb_usize(first)?;
+        let chunk_type = u32::try_from(chunk_type)
+            .map_err(|_| ParseError::from(ParseErrorKind::InvalidChunkHeader).or_at(Some(chunk_start), ChunkPath::default()))?;
+        let first = self.next_byte()?.ok_or_else(|| self.err(ParseErrorKind::UnexpectedEOF))?;
+        let len = self.next_leb_usize(first)?;
+        let body_start = self.pos;
+        let body = self.next_n_bytes(len)?;
+        let reader = BufReader::at(&body, body_start).into_chunk(chunk_type);
+
+        if chunk_type == ListChunkType::Crc as u32 && self.check_crc {
+            let expected = reader.clone().next_u32_le()?;
+            if expected != checksum {
+                return Err(reader.err(ParseErrorKind::ChecksumFailed(ChecksumMismatch { expected, actual: checksum })));
+            }
+        }
+
+        if chunk_type == ListChunkType::ChunkCrc as u32 {
+            if let (Some((chunk_checksum, start, last_type)), true) = (self.last_chunk.take(), self.check_crc) {
+                let expected = reader.clone().next_u32_le()?;
+                if expected != chunk_checksum {
+                    return Err(ParseError::from(ParseErrorKind::ChecksumFailed(ChecksumMismatch { expected, actual: chunk_checksum }))
+                        .or_at(Some(start), ChunkPath::default().push(last_type)));
+                }
+            }
+        } else {
+            let loc = reader.loc();
+            self.last_chunk = Some((self.chunk_digest.clone().finalize(), body_start, chunk_type));
+            self.peeked = Some((chunk_type, body, loc));
+        }
+        Ok(())
+    }
+
+    fn peek_u32(&mut self) -> Result<Option<u32>, ParseError> {
+        self.fill_peeked()?;
+        Ok(self.peeked.as_ref().map(|(chunk_type, ..)| *chunk_type))
+    }
+
+    /// Read the next chunk, skipping unknown chunks for forwards compatibility.
+    fn next_chunk<'a>(&mut self) -> Result<(ListChunkType, Chunk<'a>), ParseError> {
+        loop {
+            self.fill_peeked()?;
+            let (chunk_type, body, loc) = self.peeked.take().ok_or_else(|| self.err(ParseErrorKind::UnexpectedEOF))?;
+            if let Ok(chunk_type) = ListChunkType::try_from(chunk_type) {
+                return Ok((chunk_type, Chunk::Owned(body, loc)));
+            }
+        }
+    }
+}
+
+/// A top level chunk read from [`TopLevelChunks`]. Chunks read from a slice borrow from it. Chunks
+/// read from a stream own their bytes, which are dropped along with the chunk.
+pub(super) enum Chunk<'a> {
+    Borrowed(BufReader<'a>),
+    Owned(Vec<u8>, ReaderLoc),
+}
+
+impl<'a> Chunk<'a> {
+    pub(super) fn reader(&self) -> BufReader<'_> {
+        match self {
+            Chunk::Borrowed(reader) => *reader,
+            Chunk::Owned(body, loc) => BufReader::with_loc(body, *loc),
+        }
+    }
+}
+
+/// The top level chunks of a file being decoded. These either come from a slice containing the
+/// whole file, or are read one at a time from a stream.
+pub(super) enum TopLevelChunks<'a, 'r> {
+    Slice(ChunkReader<'a>),
+    Stream(StreamChunkReader<'r>),
+}
+
+impl<'a, 'r> TopLevelChunks<'a, 'r> {
+    /// Streams start with the file header (magic bytes and protocol version). When decoding from
+    /// a slice, the header is read before the chunks.
+    pub(super) fn read_stream_header(&mut self) -> Result<(), ParseError> {
+        if let TopLevelChunks::Stream(reader) = self {
+            if !protocol_version_supported(reader.read_header()?) {
+                return Err(ParseErrorKind::UnsupportedProtocolVersion.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the IO error (if any) which stopped a stream from being read.
+    pub(super) fn take_io_error(&mut self) -> Option<io::Error> {
+        match self {
+            TopLevelChunks::Slice(_) => None,
+            TopLevelChunks::Stream(reader) => reader.io_error.take(),
+        }
+    }
+
+    /// Read a chunk with the named type. Returns None if the next chunk isn't the specified type,
+    /// or we hit EOF.
+    pub(super) fn read_chunk_if_eq(&mut self, expect_chunk_type: ListChunkType) -> Result<Option<Chunk<'a>>, ParseError> {
+        match self {
+            TopLevelChunks::Slice(reader) => Ok(reader.read_chunk_if_eq(expect_chunk_type)?.map(Chunk::Borrowed)),
+            TopLevelChunks::Stream(reader) => {
+                if reader.peek_u32()? != Some(expect_chunk_type as u32) {
+                    return Ok(None);
+                }
+                reader.next_chunk().map(|(_type, c)| Some(c))
+            }
+        }
+    }
+
+    pub(super) fn expect_chunk(&mut self, expect_chunk_type: ListChunkType) -> Result<Chunk<'a>, ParseError> {
+        match self {
+            TopLevelChunks::Slice(reader) => reader.expect_chunk(expect_chunk_type).map(Chunk::Borrowed),
+            TopLevelChunks::Stream(reader) => {
+                let (actual_chunk_type, chunk) = reader.next_chunk()?;
+                if actual_chunk_type == expect_chunk_type {
+                    Ok(chunk)
+                } else {
+                    Err(ParseError::from(ParseErrorKind::MissingChunk(expect_chunk_type as _))
+                        .or_at(chunk.reader().pos(), ChunkPath::default()))
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Content deduplication for encoded files.
+//!
+//! Documents often contain the same block of text many times over (templates, pasted code, etc).
+//! When [`EncodeOptions::dedup_content`](super::EncodeOptions::dedup_content) is set, inserted and
+//! deleted content is scanned for repeated sections, and later copies are written as references
+//! back to the first copy.
+//!
+//! Only repeats of at least [`MIN_MATCH_LEN`] bytes are found. Every MIN_MATCH_LEN bytes we store
+//! a hash of the next MIN_MATCH_LEN bytes, and a rolling hash of the content at each position is
+//! looked up in that table. So repeated sections shorter than about 2 * MIN_MATCH_LEN might be
+//! missed.
+
+use std::collections::HashMap;
+
+/// The shortest repeat which will be replaced with a reference.
+///
+/// Source code histories mostly repeat individual lines, so this is fairly short. Saving
+/// benchmark_data/node_nodecc.dt (from git-reader) with dedup_content:
+///
+/// | MIN_MATCH_LEN | LZ4      | Uncompressed |
+/// |---------------|----------|--------------|
+/// | (no dedup)    | 335378   | 601953       |
+/// | 64            | 332921   | 562109       |
+/// | 32            | 330227   | 512144       |
+/// | 16            | 328063   | 434091       |
+/// | 8             | 341786   | 369691       |
+///
+/// Below 16 the references start getting in the way of LZ4.
+pub(super) const MIN_MATCH_LEN: usize = 16;
+
+/// Deduplicated content can expand to at most this many times the size of its literal bytes and
+/// runs. Without a limit, a tiny file could claim (or use chains of copies to build) terabytes of
+/// content. The encoder doesn't dedup content which would exceed it.
+pub(super) const MAX_EXPANSION: usize = 1024;
+
+const HASH_BASE: u64 = 0x100000001b3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ContentRun {
+    /// The next len bytes are stored literally.
+    Literal(usize),
+    /// The next len bytes are a copy of content starting at offset (in bytes). The copied content
+    /// always ends before the copy starts.
+    Copy { offset: usize, len: usize },
+}
+
+fn window_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |h, &b| h.wrapping_mul(HASH_BASE).wrapping_add(b as u64))
+}
+
+/// Split s into a list of literal and copied runs. If nothing in s repeats, this returns a single
+/// literal run.
+pub(super) fn find_repeats(s: &str) -> Vec<ContentRun> {
+    let bytes = s.as_bytes();
+    let n = bytes.len();
+    let mut runs = vec![];
+
+    if n >= 2 * MIN_MATCH_LEN {
+        // HASH_BASE ^ (MIN_MATCH_LEN - 1), for removing the outgoing byte from the rolling hash.
+        let outgoing_mul = (1..MIN_MATCH_LEN).fold(1u64, |p, _| p.wrapping_mul(HASH_BASE));
+
+        // Map from the hash of a window to the first offset with that hash.
+        let mut anchors: HashMap<u64, usize> = HashMap::new();
+        let mut literal_start = 0;
+        let mut i = 0;
+        let mut hash = window_hash(&bytes[..MIN_MATCH_LEN]);
+
+        while i + MIN_MATCH_LEN <= n {
+            if let Some(&anchor) = anchors.get(&hash) {
+                if let Some((offset, start, len)) = extend_match(s, anchor, i, literal_start) {
+                    if literal_start < start { runs.push(ContentRun::Literal(start - literal_start)); }
+                    runs.push(ContentRun::Copy { offset, len });
+                    i = start + len;
+                    literal_start = i;
+                    if i + MIN_MATCH_LEN <= n { hash = window_hash(&bytes[i..i + MIN_MATCH_LEN]); }
+                    continue;
+                }
+            }
+
+            if i % MIN_MATCH_LEN == 0 { anchors.entry(hash).or_insert(i); }
+            if i + MIN_MATCH_LEN < n {
+                hash = hash.wrapping_sub((bytes[i] as u64).wrapping_mul(outgoing_mul))
+                    .wrapping_mul(HASH_BASE)
+                    .wrapping_add(bytes[i + MIN_MATCH_LEN] as u64);
+            }
+            i += 1;
+        }
+
+        if literal_start < n { runs.push(ContentRun::Literal(n - literal_start)); }
+    } else if n > 0 {
+        runs.push(ContentRun::Literal(n));
+    }
+
+    runs
+}
+
+/// Check if the window at pos is a copy of the window at anchor, and if so extend the match as far
+/// as possible in both directions. Returns (source offset, start, len) of the match.
+///
+/// The match can't extend backwards past literal_start (the end of the previous match), and the
+/// source can't overlap the copy.
+fn extend_match(s: &str, anchor: usize, pos: usize, literal_start: usize) -> Option<(usize, usize, usize)> {
+    let bytes = s.as_bytes();
+    // The distance between the source and the copy. The match can be at most this long.
+    let delta = pos - anchor;
+    if delta < MIN_MATCH_LEN || bytes[anchor..anchor + MIN_MATCH_LEN] != bytes[pos..pos + MIN_MATCH_LEN] {
+        return None;
+    }
+
+    let mut start = pos;
+    let mut end = pos + MIN_MATCH_LEN;
+    while start > literal_start && start > delta && end - start < delta
+        && bytes[start - 1 - delta] == bytes[start - 1] {
+        start -= 1;
+    }
+    while end < bytes.len() && end - start < delta && bytes[end - delta] == bytes[end] {
+        end += 1;
+    }
+
+    while !s.is_char_boundary(start) { start += 1; }
+    while !s.is_char_boundary(end) { end -= 1; }
+
+    if end - start >= MIN_MATCH_LEN {
+        Some((start - delta, start, end - start))
+    } else { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Rebuild the string from its runs, in the same way the decoder does.
+    fn expand(s: &str, runs: &[ContentRun]) -> String {
+        let mut out: Vec<u8> = vec![];
+        for run in runs {
+            match *run {
+                ContentRun::Literal(len) => {
+                    out.extend_from_slice(&s.as_bytes()[out.len()..out.len() + len]);
+                }
+                ContentRun::Copy { offset, len } => {
+                    assert!(offset + len <= out.len());
+                    out.extend_from_within(offset..offset + len);
+                }
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn check(s: &str) -> Vec<ContentRun> {
+        let runs = find_repeats(s);
+        assert_eq!(expand(s, &runs), s);
+        for run in runs.iter() {
+            match run {
+                ContentRun::Literal(len) => assert!(*len > 0),
+                ContentRun::Copy { len, .. } => assert!(*len >= MIN_MATCH_LEN),
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn no_repeats() {
+        assert_eq!(check(""), vec![]);
+        assert_eq!(check("hi there"), vec![ContentRun::Literal(8)]);
+
+        let mut x: u32 = 12345;
+        let s: String = (0..1000).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            char::from(b'a' + ((x >> 16) % 26) as u8)
+        }).collect();
+        assert!(check(&s).iter().all(|r| matches!(r, ContentRun::Literal(_))));
+    }
+
+    #[test]
+    fn repeated_blocks() {
+        let block: String = (0..500).map(|i| format!("{i} ")).collect();
+        let s = format!("header {block} middle {block}{block} footer");
+        let runs = check(&s);
+        let copied: usize = runs.iter().map(|r| match r {
+            ContentRun::Copy { len, .. } => *len,
+            _ => 0,
+        }).sum();
+        assert!(copied >= 2 * block.len() - 2 * MIN_MATCH_LEN, "{runs:?}");
+    }
+
+    #[test]
+    fn repeated_short_lines() {
+        // Lines of code deleted and inserted again elsewhere are usually much shorter than 64
+        // bytes.
+        let lines = ["    int x = foo(y);\n", "    return bar(x, z);\n", "  }\n"];
+        let s = format!("{}// moved\n{}", lines.concat(), lines.concat());
+        let runs = check(&s);
+        assert!(runs.contains(&ContentRun::Copy { offset: 0, len: lines.concat().len() }), "{runs:?}");
+    }
+
+    #[test]
+    fn copies_respect_char_boundaries() {
+        // Use multibyte characters with a byte length which doesn't divide MIN_MATCH_LEN, so the
+        // anchors land in the middle of characters.
+        let block = "ツ€x".repeat(100);
+        let s = format!("é{block}ü{block}{block}");
+        let runs = check(&s);
+        assert!(runs.iter().any(|r| matches!(r, ContentRun::Copy { .. })));
+    }
+}
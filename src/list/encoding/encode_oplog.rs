@@ -1,18 +1,22 @@
+use std::io::Write;
 use jumprope::JumpRope;
 use rle::{HasLength, RleRun};
+use smallvec::{smallvec, SmallVec};
 use crate::list::encoding::*;
 use crate::causalgraph::graph::GraphEntrySimple;
+use crate::causalgraph::topo::TopoOrder;
 use crate::list::operation::ListOpKind::{Del, Ins};
 use crate::list::{ListBranch, ListOpLog, switch};
 use crate::rle::{KVPair, RleVec};
-use crate::{AgentId, LV};
+use crate::{AgentId, Frontier, LV};
 use crate::frontier::local_frontier_is_root;
 use crate::list::op_metrics::ListOpMetrics;
 use crate::list::operation::ListOpKind;
 use crate::dtrange::DTRange;
-use crate::encoding::tools::calc_checksum;
-use crate::list::encoding::encode_tools::{Merger, push_leb_chunk, push_leb_str, push_leb_u32, push_leb_usize, push_u32_le, write_leb_bit_run};
-use crate::list::encoding::leb::{encode_leb_u32, encode_leb_usize, num_encode_zigzag_isize_old};
+use crate::list::encoding::encode_tools::{ChecksumWriter, Merger, push_leb_chunk, push_leb_chunk_header, push_leb_str, push_leb_u32, push_leb_u64, push_leb_usize, push_u32_le, write_leb_bit_run, write_leb_chunk};
+use crate::list::encoding::leb::{encode_leb_u32, encode_leb_usize, num_encode_zigzag_i64_old, num_encode_zigzag_isize_old};
+use crate::listmerge::txn_trace::TxnWalkItem;
+use crate::list::encoding::dedup::{ContentRun, find_repeats, MAX_EXPANSION};
 
 const ALLOW_VERBOSE: bool = false;
 
@@ -92,6 +96,7 @@ fn write_op(dest: &mut Vec<u8>, op: &ListOpMetrics, cursor: &mut usize) {
 // TODO: Make a builder API for this
 #[derive(Debug, Clone)]
 pub struct EncodeOptions<'a> {
+    /// Stored in the file in place of the oplog's own [`user_data`](ListOpLog::user_data).
     pub user_data: Option<&'a [u8]>,
 
     // NYI.
@@ -104,11 +109,74 @@ pub struct EncodeOptions<'a> {
     pub store_inserted_content: bool,
     pub store_deleted_content: bool,
 
+    /// Compress inserted & deleted content (and any stored branch content) with LZ4. The content
+    /// chunks are marked as ContentCompressed, and the compressed data is stored (prefixed with its
+    /// uncompressed length) in a CompressedFieldsLZ4 chunk at the start of the file. Files without
+    /// that chunk load as before.
+    ///
+    /// This does nothing if diamond types is built without the `lz4` feature. Files written with
+    /// compression can't be read by builds without it.
     pub compress_content: bool,
 
+    /// Replace repeated sections of inserted & deleted content (eg, the same block pasted many
+    /// times) with references to the first copy. Files written with this option can't be read by
+    /// older versions of diamond types, so it's off by default.
+    pub dedup_content: bool,
+
+    /// Leave out the text inserted by these agents. Their inserts are still written (with the same
+    /// positions and lengths), but they're marked as having unknown content, so checkouts show
+    /// U+FFFD replacement characters in their place. This is useful for erasing what a user typed
+    /// while keeping the rest of the history valid.
+    ///
+    /// Redacted text could otherwise leak through other parts of the file, so when this is
+    /// non-empty deleted content and start / end branch content aren't stored either.
+    pub redact_content_for: &'a [AgentId],
+
+    /// Only store the deleted content of deletes made after this version. Older deletes are
+    /// written without their content, as if the oplog had been trimmed with
+    /// [`trim_deleted_content`](ListOpLog::trim_deleted_content) (but the oplog itself isn't
+    /// changed). This does nothing unless `store_deleted_content` is set.
+    pub keep_deleted_content_after: Option<&'a [LV]>,
+
+    /// Write a CRC after each top level chunk, as well as the CRC for the whole file. If the file
+    /// is damaged, the decoder can then say which chunk is bad. Files written with this option use
+    /// a newer protocol version, so older versions of diamond types can't load them.
+    pub chunk_checksums: bool,
+
+    /// Write the same bytes for any two oplogs which contain the same operations, no matter what
+    /// order each peer added them in. That is, `canonical_encode(a) == canonical_encode(b)` if and
+    /// only if `a` and `b` hold the same set of operations (and encoding options). This is useful
+    /// for hashing a file to check whether two peers are in sync.
+    ///
+    /// The operations are copied into [`TopoOrder::AgentSeqCanonical`] order before they're
+    /// written, so this is slower and uses more memory than a normal encode.
+    ///
+    /// [`TopoOrder::AgentSeqCanonical`]: crate::causalgraph::topo::TopoOrder::AgentSeqCanonical
+    pub canonical: bool,
+
+    /// What to write. See [`EncodeMode`].
+    pub mode: EncodeMode,
+
     pub verbose: bool,
 }
 
+/// The kind of file written by the encoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncodeMode {
+    /// The operations and their history. This is the normal file format.
+    #[default]
+    Full,
+
+    /// Only the document's content at the encoded version, along with the version itself (by
+    /// agent name and seq). This is about the size of the document, so its useful for publishing
+    /// a document to read-only consumers which don't need the history.
+    ///
+    /// Snapshots are loaded with [`ListBranch::load_snapshot`]. They can't be loaded or merged
+    /// into an oplog. The start version is ignored, and so are the options controlling which
+    /// operations and deleted content are stored. Snapshots can't be filtered by agent or redacted.
+    Snapshot,
+}
+
 pub const ENCODE_PATCH: EncodeOptions = EncodeOptions {
     user_data: None,
     store_start_branch_content: false,
@@ -116,6 +184,12 @@ pub const ENCODE_PATCH: EncodeOptions = EncodeOptions {
     store_inserted_content: true,
     store_deleted_content: false,
     compress_content: true,
+    dedup_content: false,
+    redact_content_for: &[],
+    keep_deleted_content_after: None,
+    chunk_checksums: false,
+    canonical: false,
+    mode: EncodeMode::Full,
     verbose: false
 };
 
@@ -126,6 +200,12 @@ pub const ENCODE_FULL: EncodeOptions = EncodeOptions {
     store_inserted_content: true,
     store_deleted_content: false, // ?? Not sure about this one!
     compress_content: true,
+    dedup_content: false,
+    redact_content_for: &[],
+    keep_deleted_content_after: None,
+    chunk_checksums: false,
+    canonical: false,
+    mode: EncodeMode::Full,
     verbose: false
 };
 
@@ -162,6 +242,22 @@ impl HasLength for AgentAssignmentRun {
     }
 }
 
+/// Split range into pieces, marking whether each piece is inside one of the (sorted) spans.
+fn split_at_spans(range: DTRange, spans: &[DTRange]) -> impl Iterator<Item = (DTRange, bool)> + '_ {
+    let mut pos = range.start;
+    std::iter::from_fn(move || {
+        if pos >= range.end { return None; }
+        let (end, inside) = match spans.iter().find(|s| s.end > pos) {
+            Some(s) if s.start <= pos => (s.end.min(range.end), true),
+            Some(s) => (s.start.min(range.end), false),
+            None => (range.end, false),
+        };
+        let piece = (pos..end).into();
+        pos = end;
+        Some((piece, inside))
+    })
+}
+
 fn write_assignment_run(dest: &mut Vec<u8>, run: AgentAssignmentRun) {
     // Its rare, but possible for the agent assignment sequence to jump around a little.
     // This can happen when:
@@ -193,38 +289,36 @@ struct AgentMapping {
     /// Map from oplog's agent ID to the agent id in the file. Paired with the last assigned agent
     /// ID, to support agent IDs bouncing around.
     map: Vec<Option<(AgentId, usize)>>,
-    next_mapped_agent: AgentId,
     output: Vec<u8>,
 }
 
 impl AgentMapping {
-    // TODO: This should only need the agent assignment I think!
-    fn new(oplog: &ListOpLog) -> Self {
-        let client_len = oplog.cg.agent_assignment.client_data.len();
-        let mut result = Self {
-            map: Vec::with_capacity(client_len),
-            next_mapped_agent: 1, // 0 is implicitly assigned to ROOT.
-            output: Vec::new()
-        };
-        result.map.resize(client_len, None);
-        result
+    /// Build the mapping for the set of agents which will be referenced in the file.
+    ///
+    /// Agents are numbered (and their names written) in sorted name order, so the output doesn't
+    /// depend on the order agent IDs happened to be assigned locally.
+    fn new(oplog: &ListOpLog, used: &[bool]) -> Self {
+        let client_data = &oplog.cg.agent_assignment.client_data;
+
+        let mut agents: Vec<usize> = (0..client_data.len()).filter(|a| used[*a]).collect();
+        agents.sort_unstable_by(|a, b| client_data[*a].name.cmp(&client_data[*b].name));
+
+        let mut map = vec![None; client_data.len()];
+        let mut output = Vec::new();
+        for (i, agent) in agents.into_iter().enumerate() {
+            // 0 is implicitly assigned to ROOT.
+            map[agent] = Some(((i + 1) as AgentId, 0));
+            push_leb_str(&mut output, client_data[agent].name.as_str());
+        }
+
+        Self { map, output }
     }
 
-    // TODO: Narrow arguments to &AgentAssignment
-    fn map(&mut self, oplog: &ListOpLog, agent: AgentId) -> AgentId {
-        // 0 is implicitly ROOT.
+    fn map(&self, agent: AgentId) -> AgentId {
         assert_ne!(agent, AgentId::MAX);
-
-        let agent = agent as usize;
-
-        self.map[agent].map_or_else(|| {
-            let mapped = self.next_mapped_agent;
-            self.map[agent] = Some((mapped, 0));
-            push_leb_str(&mut self.output, oplog.cg.agent_assignment.client_data[agent].name.as_str());
-            // println!("Mapped agent {} -> {}", oplog.cg.client_data[agent].name, mapped);
-            self.next_mapped_agent += 1;
-            mapped
-        }, |v| v.0)
+        self.map[agent as usize]
+            .expect("Agent missing from file agent mapping")
+            .0
     }
 
     fn seq_delta(&mut self, agent: AgentId, span: DTRange) -> isize {
@@ -239,7 +333,7 @@ impl AgentMapping {
     }
 }
 
-fn write_local_version(dest: &mut Vec<u8>, version: &[LV], map: &mut AgentMapping, oplog: &ListOpLog) {
+fn write_local_version(dest: &mut Vec<u8>, version: &[LV], map: &AgentMapping, oplog: &ListOpLog) {
     // Skip writing a version chunk if the version is ROOT.
     if local_frontier_is_root(version) {
         return;
@@ -253,7 +347,7 @@ fn write_local_version(dest: &mut Vec<u8>, version: &[LV], map: &mut AgentMappin
         let (agent, seq) = oplog.lv_to_agent_version(*t);
 
         // (Mapped agent ID, seq) pairs. Agent id has mixed in bit for has_more.
-        let mapped = map.map(oplog, agent);
+        let mapped = map.map(agent);
         let n = mix_bit_usize(mapped as _, has_more);
         push_leb_usize(&mut buf, n);
         push_leb_usize(&mut buf, seq);
@@ -304,6 +398,63 @@ fn write_content_str(dest: &mut Vec<u8>, s: &str, compressed: Option<&mut Vec<u8
     write_content(dest, DataType::PlainText, s.len(), std::iter::once(s.as_bytes()), compressed);
 }
 
+/// Write content, replacing repeated sections with references back to the first copy. If nothing
+/// repeats, this writes a normal content chunk. Returns whether a ContentDeduped chunk was written.
+fn write_content_deduped(dest: &mut Vec<u8>, s: &str, compressed: Option<&mut Vec<u8>>) -> bool {
+    let runs = find_repeats(s);
+    if !runs.iter().any(|r| matches!(r, ContentRun::Copy { .. })) {
+        write_content_str(dest, s, compressed);
+        return false;
+    }
+
+    let mut literals = String::new();
+    let mut runs_buf = Vec::new();
+    let mut pos = 0;
+    for run in runs {
+        match run {
+            ContentRun::Literal(len) => {
+                literals.push_str(&s[pos..pos + len]);
+                push_leb_usize(&mut runs_buf, mix_bit_usize(len, false));
+                pos += len;
+            }
+            ContentRun::Copy { offset, len } => {
+                push_leb_usize(&mut runs_buf, mix_bit_usize(len, true));
+                push_leb_usize(&mut runs_buf, offset);
+                pos += len;
+            }
+        }
+    }
+
+    // Decoders reject content which expands by more than this.
+    if s.len() > (literals.len() + runs_buf.len()) * MAX_EXPANSION {
+        write_content_str(dest, s, compressed);
+        return false;
+    }
+
+    let mut buf = Vec::new();
+    push_leb_u32(&mut buf, DataType::PlainText as _);
+    push_leb_usize(&mut buf, s.len());
+    write_content_str(&mut buf, &literals, compressed);
+    buf.extend_from_slice(&runs_buf);
+    push_leb_chunk(dest, ListChunkType::ContentDeduped, &buf);
+    true
+}
+
+/// Write the Capabilities chunk (into FileInfo), if the file uses any capabilities.
+fn write_capabilities(dest: &mut Vec<u8>, used: CapabilitySet) {
+    if used.is_empty() { return; }
+
+    let (mut required, mut optional) = (CapabilitySet::default(), CapabilitySet::default());
+    for c in used.known() {
+        if c.is_required() { required.insert(c); } else { optional.insert(c); }
+    }
+
+    let mut buf = Vec::new();
+    push_leb_u64(&mut buf, required.0);
+    push_leb_u64(&mut buf, optional.0);
+    push_leb_chunk(dest, ListChunkType::Capabilities, &buf);
+}
+
 fn write_content_rope(dest: &mut Vec<u8>, rope: &JumpRope, compressed: Option<&mut Vec<u8>>) {
     write_content(dest, DataType::PlainText, rope.len_bytes(),rope.substrings().map(|s| s.as_bytes()), compressed);
 }
@@ -317,9 +468,38 @@ fn write_chunk_str(dest: &mut Vec<u8>, s: &str, chunk_type: ListChunkType) {
     push_leb_chunk(dest, chunk_type, &buf);
 }
 
+/// Write the file's magic bytes and protocol version. Files with chunk CRCs get their own version,
+/// so older decoders don't misread them. The header isn't covered by the first chunk's CRC.
+fn write_file_header<W: Write>(result: &mut ChecksumWriter<W>, chunk_checksums: bool) -> std::io::Result<()> {
+    let mut header = MAGIC_BYTES.to_vec();
+    push_leb_usize(&mut header, if chunk_checksums { CHUNK_CRC_PROTOCOL_VERSION } else { PROTOCOL_VERSION });
+    result.write_all(&header)?;
+    result.take_chunk_checksum();
+    Ok(())
+}
+
+/// Called after each top level chunk. This writes the chunk's CRC if chunk checksums are enabled.
+fn write_chunk_crc<W: Write>(result: &mut ChecksumWriter<W>, chunk_checksums: bool) -> std::io::Result<()> {
+    if chunk_checksums {
+        let mut crc_buf = Vec::new();
+        push_u32_le(&mut crc_buf, result.take_chunk_checksum());
+        write_leb_chunk(result, ListChunkType::ChunkCrc, &crc_buf)?;
+        // The chunk CRC isn't covered by the next chunk's CRC.
+        result.take_chunk_checksum();
+    }
+    Ok(())
+}
+
+/// Write the CRC of everything written so far. This is the last chunk in the file.
+fn write_file_crc<W: Write>(result: &mut ChecksumWriter<W>) -> std::io::Result<()> {
+    let mut crc_buf = Vec::new();
+    push_u32_le(&mut crc_buf, result.checksum());
+    write_leb_chunk(result, ListChunkType::Crc, &crc_buf)
+}
+
 /// Returns compressed chunk size
 #[cfg(feature = "lz4")]
-fn write_compressed_chunk(dest: &mut Vec<u8>, data: &[u8]) -> usize {
+fn write_compressed_chunk<W: Write>(dest: &mut W, data: &[u8]) -> std::io::Result<usize> {
     // dbg!(&compress_bytes);
     let max_compressed_size = lz4_flex::block::get_maximum_output_size(data.len());
 
@@ -339,9 +519,9 @@ fn write_compressed_chunk(dest: &mut Vec<u8>, data: &[u8]) -> usize {
     pos += lz4_flex::compress_into(data, &mut compressed[pos..]).unwrap();
     compressed.truncate(pos);
     // write_chunk(ChunkType::CompressedFields, &mut compressed);
-    push_leb_chunk(dest, ListChunkType::CompressedFieldsLZ4, &compressed[..pos]);
+    write_leb_chunk(dest, ListChunkType::CompressedFieldsLZ4, &compressed[..pos])?;
 
-    pos
+    Ok(pos)
 }
 
 /// Simple helper struct for content (ins / del) chunks. These have two parts:
@@ -379,7 +559,7 @@ impl<F: FnMut(RleRun<bool>, &mut Vec<u8>)> ContentChunk<F> {
         self.bit_writer.push2(RleRun::new(known, len), &mut self.known_out);
     }
 
-    fn flush(mut self, compressed_out: Option<&mut Vec<u8>>) -> Option<Vec<u8>> {
+    fn flush(mut self, compressed_out: Option<&mut Vec<u8>>, dedup: bool, used: &mut CapabilitySet) -> Option<Vec<u8>> {
         self.bit_writer.flush2(&mut self.known_out);
 
         if self.content.is_empty() {
@@ -390,7 +570,13 @@ impl<F: FnMut(RleRun<bool>, &mut Vec<u8>)> ContentChunk<F> {
             push_leb_u32(&mut buf, match self.kind { Ins => 0, Del => 1 });
 
             // This writes a length-prefixed string, which it really doesn't need to do.
-            write_content_str(&mut buf, &self.content, compressed_out);
+            if dedup {
+                if write_content_deduped(&mut buf, &self.content, compressed_out) {
+                    used.insert(Capability::DedupedContent);
+                }
+            } else {
+                write_content_str(&mut buf, &self.content, compressed_out);
+            }
 
             push_leb_chunk(&mut buf, ListChunkType::ContentIsKnown, &self.known_out);
             Some(buf)
@@ -399,12 +585,139 @@ impl<F: FnMut(RleRun<bool>, &mut Vec<u8>)> ContentChunk<F> {
 }
 
 impl ListOpLog {
-    /// Encode the data stored in the OpLog into a (custom) compact binary form suitable for saving
-    /// to disk, or sending over the network.
-    pub fn encode_from(&self, opts: EncodeOptions, from_version: &[LV]) -> Vec<u8> {
+    /// Find every agent which will be referenced by an encoded file. Thats the agents who made
+    /// the encoded changes, and the agents named by foreign parents and the stored versions.
+    fn agents_used_in_encoding(&self, walks: &[TxnWalkItem], from_version: &[LV], end_version: Option<&[LV]>) -> Vec<bool> {
+        let aa = &self.cg.agent_assignment;
+        let mut used = vec![false; aa.client_data.len()];
+        let mark = |used: &mut Vec<bool>, v: LV| used[aa.local_to_agent_version(v).0 as usize] = true;
+
+        let mut consumed: Vec<DTRange> = walks.iter().map(|w| w.consume).collect();
+        consumed.sort_unstable_by_key(|r| r.start);
+        let is_local = |v: LV| {
+            let idx = consumed.partition_point(|r| r.start <= v);
+            idx > 0 && consumed[idx - 1].contains(v)
+        };
+
+        for walk in walks {
+            for KVPair(_, span) in aa.client_with_localtime.iter_range_ctx(walk.consume, &()) {
+                used[span.agent as usize] = true;
+            }
+            for &p in walk.parents.iter() {
+                if !is_local(p) { mark(&mut used, p); }
+            }
+        }
+
+        for &v in from_version { mark(&mut used, v); }
+        if let Some(end_version) = end_version {
+            for &v in end_version { mark(&mut used, v); }
+        }
+
+        used
+    }
+
+    /// Split the walks up so they only consume operations made by the named agents. The parents
+    /// of the remaining spans can point to skipped operations, which are written as foreign
+    /// parents.
+    fn filter_walks_by_agent(&self, walks: Vec<TxnWalkItem>, only_agents: &[AgentId]) -> Vec<TxnWalkItem> {
+        let mut result = Vec::new();
+        for walk in walks {
+            for KVPair(lv, span) in self.cg.agent_assignment.client_with_localtime.iter_range_ctx(walk.consume, &()) {
+                if !only_agents.contains(&span.agent) { continue; }
+
+                let consume: DTRange = (lv..lv + span.len()).into();
+                result.push(TxnWalkItem {
+                    retreat: Default::default(),
+                    advance_rev: Default::default(),
+                    parents: if consume.start == walk.consume.start {
+                        walk.parents.clone()
+                    } else {
+                        Frontier::new_1(consume.start - 1)
+                    },
+                    consume,
+                });
+            }
+        }
+        result
+    }
+
+    /// Like [`encode_between`](ListOpLog::encode_between), but the encoded data is written to
+    /// `writer`. See [`encode_to`](ListOpLog::encode_to).
+    pub fn encode_between_to<W: Write>(&self, opts: EncodeOptions, from_version: &[LV], to_version: &[LV], writer: W) -> std::io::Result<()> {
+        self.encode_filtered_between_to(opts, from_version, to_version, None, writer)
+    }
+
+    /// Copy the oplog, adding the operations in [`TopoOrder::AgentSeqCanonical`] order. The copy's
+    /// local versions (and so everything the encoder writes) only depend on the set of operations.
+    /// Agents keep the same IDs, so agent lists in the encode options still apply to the copy.
+    fn canonical_copy(&self) -> ListOpLog {
+        let mut copy = ListOpLog::new();
+        copy.doc_id = self.doc_id.clone();
+        copy.user_data = self.user_data.clone();
+        copy.max_run_bytes = self.max_run_bytes;
+        for agent in 0..self.cg.agent_assignment.num_agents() {
+            copy.get_or_create_agent_id(self.get_agent_name(agent as AgentId));
+        }
+
+        for span in self.iter_topological(TopoOrder::AgentSeqCanonical) {
+            for entry in self.cg.graph.iter_range(span) {
+                for KVPair(lv, agent_span) in self.cg.agent_assignment.client_with_localtime.iter_range_ctx(entry.span, &()) {
+                    let parents = self.cg.graph.with_parents(lv, |p| {
+                        copy.cg.agent_assignment.remote_to_local_frontier(
+                            self.cg.agent_assignment.local_to_remote_frontier(p).into_iter()
+                        )
+                    });
+
+                    let start = copy.len();
+                    for (KVPair(op_lv, op), content) in self.iter_range_simple((lv..lv + agent_span.len()).into()) {
+                        copy.push_op_internal(start + op_lv - lv, op.loc, op.kind, content);
+                    }
+                    for (&c, message) in self.checkpoints.range(lv..lv + agent_span.len()) {
+                        copy.checkpoints.insert(start + c - lv, message.clone());
+                    }
+                    for KVPair(t, run) in self.timestamps.iter_range((lv..lv + agent_span.len()).into()) {
+                        copy.timestamps.push(KVPair(start + t - lv, run));
+                    }
+                    copy.cg.merge_and_assign(parents.as_ref(), agent_span);
+                }
+            }
+        }
+        copy
+    }
+
+    /// Encode the changes between two versions. If only_agents is set, changes made by any other
+    /// agent are left out.
+    fn encode_filtered_between_to<W: Write>(&self, opts: EncodeOptions, from_version: &[LV], to_version: &[LV], only_agents: Option<&[AgentId]>, writer: W) -> std::io::Result<()> {
         // if !frontier_is_root(from_frontier) {
         //     unimplemented!("Encoding from a non-root frontier is not implemented");
         // }
+
+        // from_version can be any set of versions. Reduce it to the frontier it names, which is
+        // also what gets written as the file's start version.
+        let from_frontier;
+        let from_version = if from_version.len() > 1 {
+            let mut versions = from_version.to_vec();
+            versions.sort_unstable();
+            versions.dedup();
+            from_frontier = self.cg.graph.find_dominators(&versions);
+            from_frontier.as_ref()
+        } else { from_version };
+
+        if opts.canonical {
+            let copy = self.canonical_copy();
+            let map_version = |v: &[LV]| copy.cg.agent_assignment.remote_to_local_frontier(
+                self.cg.agent_assignment.local_to_remote_frontier(v).into_iter()
+            );
+            let (from_version, to_version) = (map_version(from_version), map_version(to_version));
+            let opts = EncodeOptions { canonical: false, ..opts };
+            return copy.encode_filtered_between_to(opts, from_version.as_ref(), to_version.as_ref(), only_agents, writer);
+        }
+
+        if opts.mode == EncodeMode::Snapshot {
+            assert!(only_agents.is_none() && opts.redact_content_for.is_empty(), "Snapshots can't be filtered or redacted");
+            return self.encode_snapshot_to(opts, to_version, writer);
+        }
+
         let verbose = ALLOW_VERBOSE && opts.verbose;
 
         // Before anything else, we'll scan the oplog and assemble all the data in memory that we
@@ -444,14 +757,31 @@ impl ListOpLog {
         let mut inserted_content = if opts.store_inserted_content {
             Some(ContentChunk::new(write_leb_bit_run, Ins))
         } else { None };
-        let mut deleted_content = if opts.store_deleted_content {
+        let redacting = !opts.redact_content_for.is_empty();
+        let mut deleted_content = if opts.store_deleted_content && !redacting {
             Some(ContentChunk::new(write_leb_bit_run, Del))
         } else { None };
 
+        // Deletes in these (sorted) spans are written without their content.
+        let trimmed_spans = match opts.keep_deleted_content_after {
+            Some(v) if deleted_content.is_some() => self.ops_at_or_before(v),
+            _ => vec![],
+        };
+
+        let mut walks: Vec<_> = self.cg.graph.optimized_txns_between(from_version, to_version).collect();
+        if let Some(only_agents) = only_agents {
+            walks = self.filter_walks_by_agent(walks, only_agents);
+        }
+
+        // The end branch content would include the changes we're leaving out.
+        let store_end_branch_content = opts.experimentally_store_end_branch_content && only_agents.is_none() && !redacting;
+
         // Map from old agent ID -> new agent ID in the file.
         //
         // (Agent ID 0 is reserved for ROOT, to make special parents slightly simpler.)
-        let mut agent_mapping = AgentMapping::new(self);
+        let mut agent_mapping = AgentMapping::new(self, &self.agents_used_in_encoding(
+            &walks, from_version, store_end_branch_content.then_some(to_version)
+        ));
 
         // let mut agent_assignment_chunk = SpanWriter::new(push_run_u32);
         let mut agent_assignment_chunk = Vec::new();
@@ -524,7 +854,7 @@ impl ListOpLog {
                         // println!("Region does not contain parent for {}", p);
 
                         let (local_agent, seq) = self.lv_to_agent_version(p);
-                        let mapped_agent = agent_mapping.map(self, local_agent);
+                        let mapped_agent = agent_mapping.map(local_agent);
                         debug_assert!(mapped_agent >= 1);
 
                         // There are probably more compact ways to do this, but the txn data set is
@@ -541,10 +871,27 @@ impl ListOpLog {
         });
 
 
+        // Checkpoints are listed by their position in the file, relative to the end of the last
+        // checkpoint.
+        let mut checkpoints_chunk = Vec::new();
+        let mut next_walk_output_time = 0;
+        let mut next_checkpoint_pos = 0;
+
+        // Timestamps are written as runs of (gap since the end of the last run, length,
+        // timestamp), by position in the file.
+        let mut timestamps_chunk = Vec::new();
+        let mut next_timestamp_pos = 0;
+        let mut timestamps_writer = Merger::new(move |KVPair(pos, run): KVPair<RleRun<i64>>, chunk: &mut Vec<u8>| {
+            push_leb_usize(chunk, pos - next_timestamp_pos);
+            push_leb_usize(chunk, run.len);
+            push_leb_u64(chunk, num_encode_zigzag_i64_old(run.val));
+            next_timestamp_pos = pos + run.len;
+        });
+
         // If we just iterate in the current order, this code would be way simpler :p
         // let iter = self.cg.history.optimized_txns_between(from_frontier, &self.frontier);
         // for walk in self.cg.parents.iter() {
-        for walk in self.cg.graph.optimized_txns_between(from_version, self.cg.version.as_ref()) {
+        for walk in walks {
             // We only care about walk.consume and parents.
 
             // We need to update *lots* of stuff in here!!
@@ -552,7 +899,7 @@ impl ListOpLog {
             // 1. Agent names and agent assignment
             for KVPair(_, span) in self.cg.agent_assignment.client_with_localtime.iter_range_ctx(walk.consume, &()) {
                 // Mark the agent as in-use (if we haven't already)
-                let mapped_agent = agent_mapping.map(self, span.agent);
+                let mapped_agent = agent_mapping.map(span.agent);
 
                 // dbg!(&span);
 
@@ -565,30 +912,59 @@ impl ListOpLog {
                 });
             }
 
-            // 2. Operations!
-            for (op, content) in self.iter_range_simple(walk.consume) {
-                let op = op.1;
-
-                // DANGER!! Its super important we pull out the content here rather than in
-                // ops_writer somehow. The reason is that the content_pos field on the merged
-                // OperationInternal objects will be invalid! Total foot gun there :p
-
-                if op.kind == Ins && opts.store_inserted_content {
-                    // For now at least, we can't skip inserted content for inserts.
-                    // TODO: Reconsider this at some point.
-                    assert!(content.is_some());
-                }
+            // 2. Operations! When redacting, operations are split up by agent so we know whose
+            // content to leave out.
+            let op_ranges: SmallVec<[(DTRange, bool); 1]> = if redacting {
+                self.cg.agent_assignment.client_with_localtime.iter_range_ctx(walk.consume, &())
+                    .map(|KVPair(lv, span)| {
+                        ((lv..lv + span.len()).into(), opts.redact_content_for.contains(&span.agent))
+                    })
+                    .collect()
+            } else {
+                smallvec![(walk.consume, false)]
+            };
+
+            // Ranges are split again where deleted content starts being kept.
+            let op_ranges = op_ranges.into_iter().flat_map(|(range, redact)| {
+                split_at_spans(range, &trimmed_spans).map(move |(r, trim)| (r, redact, trim))
+            });
+
+            for (range, redact, trim) in op_ranges {
+                for (op, content) in self.iter_range_simple(range) {
+                    let trimmed = trim && op.1.kind == Del;
+                    let op = op.1;
+
+                    // DANGER!! Its super important we pull out the content here rather than in
+                    // ops_writer somehow. The reason is that the content_pos field on the merged
+                    // OperationInternal objects will be invalid! Total foot gun there :p
+
+                    // Inserted content can be missing here (eg after redact_content). It's written
+                    // out as unknown content.
+
+                    let content_chunk = switch(op.kind,
+                                               &mut inserted_content,
+                                               &mut deleted_content
+                    );
+                    if let Some(content_chunk) = content_chunk {
+                        content_chunk.push(if redact || trimmed { None } else { content }, op.len());
+                    }
 
-                let content_chunk = switch(op.kind,
-                                           &mut inserted_content,
-                                           &mut deleted_content
-                );
-                if let Some(content_chunk) = content_chunk {
-                    content_chunk.push(content, op.len());
+                    ops_writer.push(op);
                 }
+            }
 
-                ops_writer.push(op);
+            // Checkpoints don't have an operation, so the ops chunk skips over them.
+            for (&lv, message) in self.checkpoints.range(walk.consume.start..walk.consume.end) {
+                let pos = next_walk_output_time + lv - walk.consume.start;
+                push_leb_usize(&mut checkpoints_chunk, pos - next_checkpoint_pos);
+                push_leb_str(&mut checkpoints_chunk, message);
+                next_checkpoint_pos = pos + 1;
             }
+            for KVPair(lv, run) in self.timestamps.iter_range(walk.consume) {
+                let pos = next_walk_output_time + lv - walk.consume.start;
+                timestamps_writer.push2(KVPair(pos, run), &mut timestamps_chunk);
+            }
+            next_walk_output_time += walk.consume.len();
 
             // 3. Parents!
             txns_writer.push2(GraphEntrySimple {
@@ -600,28 +976,31 @@ impl ListOpLog {
         agent_assignment_writer.flush();
         ops_writer.flush();
         txns_writer.flush2(&mut agent_mapping);
+        timestamps_writer.flush2(&mut timestamps_chunk);
 
         // This nominally needs to happen before we write out agent_mapping.
         // TODO: Support partial data sets. (from_frontier)
         let mut start_branch = Vec::new();
+        let mut start_content_stored = false;
 
         // If the local version is root, start_branch is just an empty chunk.
         if !local_frontier_is_root(from_version) {
             // This will skip writing the version if from_version is ROOT.
-            write_local_version(&mut start_branch, from_version, &mut agent_mapping, self);
+            write_local_version(&mut start_branch, from_version, &agent_mapping, self);
 
-            if opts.store_start_branch_content {
+            if opts.store_start_branch_content && !redacting {
+                start_content_stored = true;
                 let branch_here = ListBranch::new_at_local_version(self, from_version);
                 // dbg!(&branch_here);
                 write_content_rope(&mut start_branch, &branch_here.content.borrow(), compress_bytes.as_mut());
             }
         }
 
-        let end_branch = if opts.experimentally_store_end_branch_content {
+        let end_branch = if store_end_branch_content {
             let mut end_branch = Vec::new();
-            write_local_version(&mut end_branch, self.cg.version.as_ref(), &mut agent_mapping, self);
+            write_local_version(&mut end_branch, to_version, &agent_mapping, self);
 
-            let branch_here = ListBranch::new_at_tip(self);
+            let branch_here = ListBranch::new_at_local_version(self, to_version);
             write_content_rope(&mut end_branch, &branch_here.content.borrow(), compress_bytes.as_mut());
 
             Some(end_branch)
@@ -645,33 +1024,51 @@ impl ListOpLog {
         push_leb_chunk(&mut fileinfo_buf, ListChunkType::AgentNames, &agent_mapping.consume());
 
         // User data
-        if let Some(data) = opts.user_data {
+        if let Some(data) = opts.user_data.or(self.user_data.as_deref()) {
             push_leb_chunk(&mut fileinfo_buf, ListChunkType::UserData, data);
         }
 
+        // Without the inserted content, the decoder can't tell how big it was.
+        if !opts.store_inserted_content && from_version.is_empty() && to_version == self.cg.version.as_ref() && only_agents.is_none() {
+            let mut usage = Vec::new();
+            push_leb_usize(&mut usage, self.inserted_bytes);
+            push_leb_chunk(&mut fileinfo_buf, ListChunkType::Usage, &usage);
+        }
+
         // Bake inserted & deleted content. I need to do this here because the CompressedFields
         // chunk goes first in the file, so if we compress anything, it needs to be filled up.
+        let mut used = CapabilitySet::default();
         let inserted_content = inserted_content.and_then(|inserted_content| {
             if verbose {
                 println!("Inserted text length {}", inserted_content.content.len());
             }
 
-            inserted_content.flush(compress_bytes.as_mut())
+            inserted_content.flush(compress_bytes.as_mut(), opts.dedup_content, &mut used)
         });
         let deleted_content = deleted_content.and_then(|deleted_content| {
             if verbose {
                 println!("Deleted text length {}", deleted_content.content.len());
             }
 
-            deleted_content.flush(compress_bytes.as_mut())
+            deleted_content.flush(compress_bytes.as_mut(), opts.dedup_content, &mut used)
         });
 
+        // Now we know everything the file uses, FileInfo can be finished.
+        if compress_bytes.as_ref().is_some_and(|c| !c.is_empty()) { used.insert(Capability::CompressedContent); }
+        if !checkpoints_chunk.is_empty() { used.insert(Capability::Checkpoints); }
+        if deleted_content.is_some() { used.insert(Capability::DeletedContent); }
+        if start_content_stored { used.insert(Capability::StartContent); }
+        if end_branch.is_some() { used.insert(Capability::EndContent); }
+        if !timestamps_chunk.is_empty() { used.insert(Capability::Timestamps); }
+        if opts.chunk_checksums { used.insert(Capability::ChunkChecksums); }
+        write_capabilities(&mut fileinfo_buf, used);
+
 
         // *** Actually start writing to Result!! YAAAAYYY ***
-        let mut result = Vec::new();
-        // The file starts with MAGIC_BYTES
-        result.extend_from_slice(&MAGIC_BYTES);
-        push_leb_usize(&mut result, PROTOCOL_VERSION);
+        // Everything written goes through the checksum writer, so we can write the CRC at the end.
+        let mut result = ChecksumWriter::new(writer);
+        write_file_header(&mut result, opts.chunk_checksums)?;
+
 
         // We'll write a series of chunks. Each chunk has a chunk header (chunk type, length).
         // The first chunk is CompressedFields, in case we need compressed content later.
@@ -683,7 +1080,8 @@ impl ListOpLog {
         #[cfg(feature = "lz4")] {
             if let Some(compress_bytes) = compress_bytes {
                 if !compress_bytes.is_empty() {
-                    let compressed_len = write_compressed_chunk(&mut result, &compress_bytes);
+                    let compressed_len = write_compressed_chunk(&mut result, &compress_bytes)?;
+                    write_chunk_crc(&mut result, opts.chunk_checksums)?;
                     if verbose {
                         println!("Compressed {} bytes in the file to {}", compress_bytes.len(), compressed_len);
                     }
@@ -691,66 +1089,212 @@ impl ListOpLog {
             }
         }
 
-        let mut write_chunk = |c: ListChunkType, data: &mut Vec<u8>| {
+        let write_chunk = |result: &mut ChecksumWriter<W>, c: ListChunkType, data: &[u8]| {
             if verbose {
                 println!("{:?} length {}", c, data.len());
             }
             // dbg!(&data);
-            push_leb_chunk(&mut result, c, data.as_slice());
-            data.clear();
+            write_leb_chunk(result, c, data)
         };
 
-        write_chunk(ListChunkType::FileInfo, &mut fileinfo_buf);
+        write_chunk(&mut result, ListChunkType::FileInfo, &fileinfo_buf)?;
+        write_chunk_crc(&mut result, opts.chunk_checksums)?;
 
         // *** Start Branch - which was filled in above. ***
-        write_chunk(ListChunkType::StartBranch, &mut start_branch);
+        write_chunk(&mut result, ListChunkType::StartBranch, &start_branch)?;
+        write_chunk_crc(&mut result, opts.chunk_checksums)?;
 
-        if let Some(mut bytes) = end_branch {
-            write_chunk(ListChunkType::ExperimentalEndBranch, &mut bytes);
+        if let Some(bytes) = end_branch {
+            write_chunk(&mut result, ListChunkType::ExperimentalEndBranch, &bytes)?;
+            write_chunk_crc(&mut result, opts.chunk_checksums)?;
         }
 
         // *** Patches ***
-        // I'll just assemble it in buf. There's a lot of sloppy use of vec<u8>'s in here.
-        let mut patches_buf = fileinfo_buf;
-
-        if let Some(bytes) = inserted_content {
-            push_leb_chunk(&mut patches_buf, ListChunkType::PatchContent, &bytes);
+        // The patches chunk contains a list of child chunks. Rather than copying them all into a
+        // buffer, we write the chunk header and then write each child chunk straight out.
+        let mut children: Vec<(ListChunkType, &[u8])> = Vec::with_capacity(7);
+        if let Some(bytes) = inserted_content.as_ref() {
+            children.push((ListChunkType::PatchContent, bytes));
+        }
+        if let Some(bytes) = deleted_content.as_ref() {
+            children.push((ListChunkType::PatchContent, bytes));
         }
-        if let Some(bytes) = deleted_content {
-            push_leb_chunk(&mut patches_buf, ListChunkType::PatchContent, &bytes);
+        children.push((ListChunkType::OpVersions, &agent_assignment_chunk));
+        children.push((ListChunkType::OpTypeAndPosition, &ops_chunk));
+        children.push((ListChunkType::OpParents, &txns_chunk));
+        if !checkpoints_chunk.is_empty() {
+            children.push((ListChunkType::Checkpoints, &checkpoints_chunk));
+        }
+        if !timestamps_chunk.is_empty() {
+            children.push((ListChunkType::Timestamps, &timestamps_chunk));
         }
 
-        push_leb_chunk(&mut patches_buf, ListChunkType::OpVersions, &agent_assignment_chunk);
-        push_leb_chunk(&mut patches_buf, ListChunkType::OpTypeAndPosition, &ops_chunk);
-        push_leb_chunk(&mut patches_buf, ListChunkType::OpParents, &txns_chunk);
+        let mut child_headers = Vec::new();
+        let patches_len: usize = children.iter().map(|(c, data)| {
+            child_headers.clear();
+            push_leb_chunk_header(&mut child_headers, *c, data.len());
+            child_headers.len() + data.len()
+        }).sum();
 
-        write_chunk(ListChunkType::Patches, &mut patches_buf);
+        if verbose {
+            println!("{:?} length {}", ListChunkType::Patches, patches_len);
+        }
+        let mut patches_header = Vec::new();
+        push_leb_chunk_header(&mut patches_header, ListChunkType::Patches, patches_len);
+        result.write_all(&patches_header)?;
+        for (c, data) in children {
+            write_leb_chunk(&mut result, c, data)?;
+        }
+        write_chunk_crc(&mut result, opts.chunk_checksums)?;
 
         // TODO (later): Final branch content.
 
         // println!("checksum {checksum}");
-        let checksum = calc_checksum(&result);
-        push_u32_le(&mut patches_buf, checksum);
-        push_leb_chunk(&mut result, ListChunkType::Crc, &patches_buf);
-        // write_chunk(Chunk::CRC, &mut buf);
+        write_file_crc(&mut result)?;
         // push_u32(&mut result, checksum);
 
         if verbose {
-            println!("== Total length {}", result.len());
+            println!("== Total length {}", result.len);
+        }
+
+        result.into_inner().flush()
+    }
+
+    /// Write a snapshot of the document at `version`. See [`EncodeMode::Snapshot`].
+    fn encode_snapshot_to<W: Write>(&self, opts: EncodeOptions, version: &[LV], writer: W) -> std::io::Result<()> {
+        let mut compress_bytes = if opts.compress_content && cfg!(feature = "lz4") {
+            Some(Vec::new())
+        } else { None };
+
+        // The only agents named in a snapshot are the ones in its version.
+        let mut used = vec![false; self.cg.agent_assignment.client_data.len()];
+        for &v in version {
+            used[self.lv_to_agent_version(v).0 as usize] = true;
+        }
+        let agent_mapping = AgentMapping::new(self, &used);
+
+        let mut snapshot = Vec::new();
+        write_local_version(&mut snapshot, version, &agent_mapping, self);
+        let branch = ListBranch::new_at_local_version(self, version);
+        let mut used = CapabilitySet::default();
+        used.insert(Capability::Snapshot);
+        if opts.dedup_content {
+            if write_content_deduped(&mut snapshot, &branch.content.to_string(), compress_bytes.as_mut()) {
+                used.insert(Capability::DedupedContent);
+            }
+        } else {
+            write_content_rope(&mut snapshot, &branch.content.borrow(), compress_bytes.as_mut());
+        }
+        if compress_bytes.as_ref().is_some_and(|c| !c.is_empty()) { used.insert(Capability::CompressedContent); }
+        if opts.chunk_checksums { used.insert(Capability::ChunkChecksums); }
+
+        let mut fileinfo_buf = Vec::new();
+        if let Some(name) = self.doc_id.as_ref() {
+            write_chunk_str(&mut fileinfo_buf, name.as_str(), ListChunkType::DocId);
+        }
+        push_leb_chunk(&mut fileinfo_buf, ListChunkType::AgentNames, &agent_mapping.consume());
+        if let Some(data) = opts.user_data.or(self.user_data.as_deref()) {
+            push_leb_chunk(&mut fileinfo_buf, ListChunkType::UserData, data);
+        }
+        write_capabilities(&mut fileinfo_buf, used);
+
+        let mut result = ChecksumWriter::new(writer);
+        write_file_header(&mut result, opts.chunk_checksums)?;
+
+        #[cfg(feature = "lz4")] {
+            if let Some(compress_bytes) = compress_bytes {
+                if !compress_bytes.is_empty() {
+                    write_compressed_chunk(&mut result, &compress_bytes)?;
+                    write_chunk_crc(&mut result, opts.chunk_checksums)?;
+                }
+            }
         }
 
+        write_leb_chunk(&mut result, ListChunkType::FileInfo, &fileinfo_buf)?;
+        write_chunk_crc(&mut result, opts.chunk_checksums)?;
+        write_leb_chunk(&mut result, ListChunkType::Snapshot, &snapshot)?;
+        write_chunk_crc(&mut result, opts.chunk_checksums)?;
+        write_file_crc(&mut result)?;
+
+        result.into_inner().flush()
+    }
+
+    /// Like [`encode_from`](ListOpLog::encode_from), but the encoded data is written to `writer`.
+    /// See [`encode_to`](ListOpLog::encode_to).
+    pub fn encode_from_to<W: Write>(&self, opts: EncodeOptions, from_version: &[LV], writer: W) -> std::io::Result<()> {
+        self.encode_between_to(opts, from_version, self.cg.version.as_ref(), writer)
+    }
+
+    /// Encode a patch containing exactly the operations in `to_version` which aren't in
+    /// `from_version`. This is useful for filling in a missing slice of a peer's history.
+    ///
+    /// The patch records `from_version` as its start version. Merging it into an oplog which
+    /// doesn't contain `from_version` fails with `ParseErrorKind::BaseVersionUnknown`, and leaves the oplog
+    /// unchanged.
+    pub fn encode_between(&self, opts: EncodeOptions, from_version: &[LV], to_version: &[LV]) -> Vec<u8> {
+        let mut result = Vec::new();
+        // Writing to a Vec can't fail.
+        self.encode_between_to(opts, from_version, to_version, &mut result).unwrap();
+        result
+    }
+
+    /// Encode the data stored in the OpLog into a (custom) compact binary form suitable for saving
+    /// to disk, or sending over the network.
+    ///
+    /// Only the operations which aren't in the history of `from_version` are included, so the
+    /// result is a delta for a peer which already has `from_version`. This can be any set of
+    /// versions - for example all the branches a peer is known to have. Versions which are in the
+    /// history of others in the set are ignored.
+    pub fn encode_from(&self, opts: EncodeOptions, from_version: &[LV]) -> Vec<u8> {
+        let mut result = Vec::new();
+        // Writing to a Vec can't fail.
+        self.encode_from_to(opts, from_version, &mut result).unwrap();
+        result
+    }
+
+    /// Encode the oplog, writing the result to `writer`. This produces exactly the same bytes as
+    /// [`encode`](ListOpLog::encode), but each chunk is written out as soon as it's ready instead
+    /// of being collected into one big buffer first. Use this when saving large oplogs straight
+    /// to a file or socket.
+    ///
+    /// The writer is written to in many small pieces, so wrap files in a `BufWriter`.
+    pub fn encode_to<W: Write>(&self, opts: EncodeOptions, writer: W) -> std::io::Result<()> {
+        self.encode_from_to(opts, &[], writer)
+    }
+
+    /// Encode only the changes made by the named agents. For example, this can be used to remove
+    /// someone's edits before sharing a document.
+    ///
+    /// Changes by the named agents which depend on changes which have been left out still name
+    /// them as parents. So the result can only be merged into an oplog which already has those
+    /// changes (otherwise merging fails with `DataMissing`). The document content at the
+    /// end of the file is never stored, since it would include the changes left out.
+    pub fn encode_filtered(&self, opts: EncodeOptions, only_agents: &[AgentId]) -> Vec<u8> {
+        let mut result = Vec::new();
+        // Writing to a Vec can't fail.
+        self.encode_filtered_between_to(opts, &[], self.cg.version.as_ref(), Some(only_agents), &mut result).unwrap();
         result
     }
 
+    /// Encode the oplog into a new buffer. This is a convenience wrapper around
+    /// [`encode_to`](ListOpLog::encode_to).
     pub fn encode(&self, opts: EncodeOptions) -> Vec<u8> {
-        self.encode_from(opts, &[])
+        let mut result = Vec::new();
+        // Writing to a Vec can't fail.
+        self.encode_to(opts, &mut result).unwrap();
+        result
     }
 
     /// Encode the data stored in the OpLog into a (custom) compact binary form suitable for saving
     /// to disk, or sending over the network.
-    pub fn encode_simple(&self, _opts: EncodeOptions) -> Vec<u8> {
-        unimplemented!()
+    ///
+    /// This isn't implemented yet, and always returns [`Error::NotSupported`](crate::Error). Use
+    /// [`encode`](ListOpLog::encode) instead.
+    #[allow(unused)] // Not public until its implemented.
+    pub(crate) fn encode_simple(&self, _opts: EncodeOptions) -> Result<Vec<u8>, crate::Error> {
+        Err(crate::Error::NotSupported)
     }
+
     // pub fn encode_simple(&self, opts: EncodeOptions) -> Vec<u8> {
     //     let mut result = Vec::new();
     //     // The file starts with MAGIC_BYTES
@@ -909,21 +1453,123 @@ impl ListOpLog {
 
 #[cfg(test)]
 mod tests {
-    use crate::list::encoding::EncodeOptions;
+    use crate::list::encoding::{ENCODE_FULL, ENCODE_PATCH, EncodeOptions, ListChunkType};
+    use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+    use crate::list::encoding::decode_tools::BufReader;
     use crate::list::{ListCRDT, ListOpLog};
 
+    /// Returns the raw bytes of the chunk at the given path of (nested) chunk types in a file.
+    fn chunk_at<'a>(data: &'a [u8], path: &[ListChunkType]) -> &'a [u8] {
+        let mut reader = BufReader::new(data);
+        reader.read_magic().unwrap();
+        reader.next_usize().unwrap(); // Protocol version
+
+        for chunk_type in path {
+            reader = reader.chunks()
+                .map(|c| c.unwrap())
+                .find(|(t, _)| t == chunk_type)
+                .unwrap().1;
+        }
+        reader.buf
+    }
+
+    fn agent_names(data: &[u8]) -> Vec<&str> {
+        let mut names = BufReader::new(chunk_at(data, &[ListChunkType::FileInfo, ListChunkType::AgentNames]));
+        let mut result = vec![];
+        while !names.is_empty() {
+            result.push(names.next_str().unwrap());
+        }
+        result
+    }
+
     #[test]
-    #[ignore]
-    fn encoding_smoke_test() {
-        let mut doc = ListCRDT::new();
-        doc.get_or_create_agent_id("seph");
-        doc.insert(0, 0, "hi there");
+    fn agent_order_is_independent_of_creation_order() {
+        let build = |agents: &[&str]| {
+            let mut oplog = ListOpLog::new();
+            for name in agents { oplog.get_or_create_agent_id(name); }
+            let seph = oplog.get_or_create_agent_id("seph");
+            let mike = oplog.get_or_create_agent_id("mike");
+            let zoe = oplog.get_or_create_agent_id("zoe");
+
+            // seph makes the first change, so seph is the first agent the encoder runs into.
+            let v1 = oplog.add_insert(seph, 0, "hi there");
+            let v2 = oplog.add_insert_at(zoe, &[v1], 2, " and");
+            let v3 = oplog.add_delete_at(mike, &[v1], 0..2);
+            oplog.add_insert_at(seph, &[v2, v3], 0, "Oh");
+            (oplog, v1)
+        };
 
-        let d1 = doc.oplog.encode_simple(EncodeOptions::default());
-        let d2 = doc.oplog.encode(EncodeOptions::default());
-        assert_eq!(d1, d2);
-        // let data = doc.ops.encode_old(EncodeOptions::default());
-        // dbg!(data.len(), data);
+        let (a, a_v1) = build(&["seph", "mike", "zoe"]);
+        let (b, b_v1) = build(&["zoe", "mike", "seph"]);
+
+        for (data_a, data_b) in [
+            (a.encode(EncodeOptions::default()), b.encode(EncodeOptions::default())),
+            (a.encode_from(EncodeOptions::default(), &[a_v1]), b.encode_from(EncodeOptions::default(), &[b_v1])),
+        ] {
+            for path in [
+                &[ListChunkType::FileInfo][..],
+                &[ListChunkType::Patches, ListChunkType::OpVersions][..],
+            ] {
+                assert_eq!(chunk_at(&data_a, path), chunk_at(&data_b, path));
+            }
+            assert_eq!(data_a, data_b);
+
+            let names = agent_names(&data_a);
+            let mut sorted = names.clone();
+            sorted.sort_unstable();
+            assert_eq!(names, sorted);
+        }
+
+        assert_eq!(agent_names(&a.encode(EncodeOptions::default())), ["mike", "seph", "zoe"]);
+
+        let mut c = ListOpLog::new();
+        c.decode_and_add(&b.encode(EncodeOptions::default())).unwrap();
+        assert_eq!(c.checkout_tip().content(), a.checkout_tip().content());
+    }
+
+    #[test]
+    fn canonical_encoding_ignores_insertion_order() {
+        // The same history, with the concurrent edits added in the opposite order.
+        let build = |mike_first: bool| {
+            let mut oplog = ListOpLog::new();
+            let seph = oplog.get_or_create_agent_id("seph");
+            let mike = oplog.get_or_create_agent_id("mike");
+            let v1 = oplog.add_insert(seph, 0, "hi there");
+
+            let seph_edits = |oplog: &mut ListOpLog| {
+                let v = oplog.add_insert_at(seph, &[v1], 8, "!!");
+                oplog.add_delete_at(seph, &[v], 0..1)
+            };
+            let mike_edit = |oplog: &mut ListOpLog| oplog.add_insert_at(mike, &[v1], 0, "Oh ");
+            let (a, b) = if mike_first {
+                let b = mike_edit(&mut oplog);
+                (seph_edits(&mut oplog), b)
+            } else {
+                (seph_edits(&mut oplog), mike_edit(&mut oplog))
+            };
+            oplog.add_insert_at(mike, &[a.min(b), a.max(b)], 0, "x");
+            (oplog, v1)
+        };
+
+        let (a, a_v1) = build(false);
+        let (b, b_v1) = build(true);
+        assert_ne!(a.encode(ENCODE_FULL), b.encode(ENCODE_FULL));
+
+        let canonical = EncodeOptions { canonical: true, ..ENCODE_FULL };
+        let data = a.encode(canonical.clone());
+        assert_eq!(data, b.encode(canonical.clone()));
+        assert_eq!(a.encode_from(canonical.clone(), &[a_v1]), b.encode_from(canonical.clone(), &[b_v1]));
+
+        // Canonical files are normal files, which decode to the same operations.
+        let c = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(c.checkout_tip().content(), a.checkout_tip().content());
+        assert_eq!(c.encode(canonical), data);
+    }
+
+    #[test]
+    fn encode_simple_is_not_supported() {
+        let oplog = ListOpLog::new();
+        assert_eq!(oplog.encode_simple(EncodeOptions::default()), Err(crate::Error::NotSupported));
     }
 
     #[test]
@@ -946,6 +1592,144 @@ mod tests {
         // dbg!(data.len(), data);
     }
 
+    /// A writer which only accepts a few bytes at a time, and fails after `limit` bytes.
+    struct SmallWriter {
+        data: Vec<u8>,
+        limit: usize,
+    }
+
+    impl std::io::Write for SmallWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.data.len() >= self.limit {
+                return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "full"));
+            }
+            let n = buf.len().min(3).min(self.limit - self.data.len());
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn encode_to_matches_encode() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let v1 = oplog.add_insert(seph, 0, "hi there");
+        oplog.add_insert_at(mike, &[v1], 2, " and hi again");
+        oplog.add_delete_at(seph, &[v1], 0..3);
+
+        for opts in [ENCODE_FULL, ENCODE_PATCH, EncodeOptions { compress_content: false, ..ENCODE_FULL }] {
+            let expected = oplog.encode(opts.clone());
+
+            let mut writer = SmallWriter { data: vec![], limit: usize::MAX };
+            oplog.encode_to(opts.clone(), &mut writer).unwrap();
+            assert_eq!(writer.data, expected);
+
+            let mut writer = SmallWriter { data: vec![], limit: usize::MAX };
+            oplog.encode_from_to(opts.clone(), &[v1], &mut writer).unwrap();
+            assert_eq!(writer.data, oplog.encode_from(opts.clone(), &[v1]));
+
+            // Errors from the writer are passed back to the caller.
+            let mut writer = SmallWriter { data: vec![], limit: expected.len() / 2 };
+            assert!(oplog.encode_to(opts, &mut writer).is_err());
+        }
+
+        let data = oplog.encode(ENCODE_FULL);
+        assert_eq!(ListOpLog::load_from(&data).unwrap(), oplog);
+    }
+
+    #[test]
+    fn encode_between_three_way_split() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let v1 = oplog.add_insert(seph, 0, "hi there");
+        oplog.add_insert_at(mike, &[v1], 2, " everyone");
+        oplog.add_delete_at(seph, &[v1], 0..3);
+        let v2 = oplog.local_version();
+        oplog.add_insert(mike, 0, "oh ");
+        let v3 = oplog.local_version();
+
+        let patches = [
+            oplog.encode_between(ENCODE_FULL, &[], &[v1]),
+            oplog.encode_between(ENCODE_PATCH, &[v1], v2.as_ref()),
+            oplog.encode_between(ENCODE_PATCH, v2.as_ref(), v3.as_ref()),
+        ];
+
+        for order in [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]] {
+            let mut result = ListOpLog::new();
+            let mut pending = order.to_vec();
+
+            // Patches which arrive before the data they depend on are retried later.
+            while !pending.is_empty() {
+                let before = pending.len();
+                pending.retain(|&i| {
+                    match result.decode_and_add(&patches[i]) {
+                        Ok(_) => false,
+                        Err(e) => {
+                            assert_eq!(e, ParseErrorKind::BaseVersionUnknown);
+                            true
+                        }
+                    }
+                });
+                assert!(pending.len() < before, "No progress merging {:?}", order);
+            }
+
+            assert_eq!(result, oplog);
+        }
+
+        // The middle patch only contains the middle slice of history.
+        let mut result = ListOpLog::new();
+        result.decode_and_add(&patches[0]).unwrap();
+        assert_eq!(result.clone().decode_and_add(&patches[2]).unwrap_err(), ParseErrorKind::BaseVersionUnknown);
+        result.decode_and_add(&patches[1]).unwrap();
+        assert_eq!(result.local_version(), oplog.checkout(v2.as_ref()).version);
+        assert_eq!(result.checkout_tip().content(), oplog.checkout(v2.as_ref()).content());
+    }
+
+    #[test]
+    fn encode_filtered_by_agent() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let v1 = oplog.add_insert(seph, 0, "hi there");
+        let m = oplog.add_insert_at(mike, &[v1], 2, " everyone");
+        let s = oplog.add_delete_at(seph, &[v1], 0..3);
+        let s = oplog.add_insert_at(seph, &[s], 0, "oh ");
+
+        let only_seph = oplog.encode_filtered(ENCODE_FULL, &[seph]);
+        let only_mike = oplog.encode_filtered(ENCODE_FULL, &[mike]);
+
+        // Mike's changes depend on seph's, so they can't be merged on their own.
+        let mut result = ListOpLog::new();
+        assert_eq!(result.decode_and_add(&only_mike).unwrap_err(), ParseErrorKind::DataMissing);
+        assert_eq!(result, ListOpLog::new());
+
+        result.decode_and_add(&only_seph).unwrap();
+        assert_eq!(result.checkout_tip().content(), "oh there");
+        assert_eq!(result.cg.agent_assignment.client_data.len(), 1);
+        result.decode_and_add(&only_mike).unwrap();
+        assert_eq!(result, oplog);
+
+        // Seph merging mike's change makes seph's later changes depend on mike's.
+        let v2 = oplog.add_insert_at(seph, &[m, s], 0, "!");
+        let only_seph = oplog.encode_filtered(ENCODE_FULL, &[seph]);
+        assert_eq!(ListOpLog::new().decode_and_add(&only_seph).unwrap_err(), ParseErrorKind::DataMissing);
+
+        let mut result = ListOpLog::new();
+        result.decode_and_add(&oplog.encode_between(ENCODE_FULL, &[], &[m, s])).unwrap();
+        result.decode_and_add(&only_seph).unwrap();
+        assert_eq!(result, oplog);
+        assert_eq!(result.local_version_ref(), &[v2]);
+
+        // Filtering by everyone is the same as not filtering.
+        assert_eq!(oplog.encode_filtered(ENCODE_FULL, &[seph, mike]), oplog.encode(ENCODE_FULL));
+    }
+
     #[test]
     fn encode_simple() {
         let mut oplog = ListOpLog::new();
@@ -1,8 +1,10 @@
+use std::io::Write;
 use std::mem::{replace, size_of};
 use rle::{MergableSpan, RleRun};
 use std::marker::PhantomData;
 use crate::list::encoding::ListChunkType;
 use crate::encoding::varint::mix_bit_usize;
+use crate::encoding::tools::CRC32C;
 
 #[cfg(feature = "serde")]
 use serde::Serialize;
@@ -20,7 +22,7 @@ pub(super) fn push_leb_u64(into: &mut Vec<u8>, val: u64) {
     into.extend_from_slice(&buf[..pos]);
 }
 
-pub(super) fn push_leb_usize(into: &mut Vec<u8>, val: usize) {
+pub(crate) fn push_leb_usize(into: &mut Vec<u8>, val: usize) {
     if size_of::<usize>() <= size_of::<u32>() {
         push_leb_u32(into, val as u32);
     } else if size_of::<usize>() == size_of::<u64>() {
@@ -42,7 +44,7 @@ pub(super) fn push_u32_le(into: &mut Vec<u8>, val: u32) {
     into.extend_from_slice(&bytes);
 }
 
-fn push_leb_chunk_header(into: &mut Vec<u8>, chunk_type: ListChunkType, len: usize) {
+pub(super) fn push_leb_chunk_header(into: &mut Vec<u8>, chunk_type: ListChunkType, len: usize) {
     push_leb_u32(into, chunk_type as u32);
     push_leb_usize(into, len);
 }
@@ -52,6 +54,58 @@ pub(super) fn push_leb_chunk(into: &mut Vec<u8>, chunk_type: ListChunkType, data
     into.extend_from_slice(data);
 }
 
+pub(super) fn write_leb_chunk<W: Write>(dest: &mut W, chunk_type: ListChunkType, data: &[u8]) -> std::io::Result<()> {
+    let mut header = Vec::with_capacity(15);
+    push_leb_chunk_header(&mut header, chunk_type, data.len());
+    dest.write_all(&header)?;
+    dest.write_all(data)
+}
+
+/// A writer which passes everything through to the wrapped writer, keeping a running checksum
+/// (and count) of the bytes written. This lets the CRC chunk at the end of a file be computed
+/// without keeping the rest of the file around.
+pub(super) struct ChecksumWriter<W: Write> {
+    inner: W,
+    digest: crc::Digest<'static, u32>,
+    /// Checksum of the bytes written since the last call to take_chunk_checksum.
+    chunk_digest: crc::Digest<'static, u32>,
+    pub(super) len: usize,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(super) fn new(inner: W) -> Self {
+        Self { inner, digest: CRC32C.digest(), chunk_digest: CRC32C.digest(), len: 0 }
+    }
+
+    pub(super) fn checksum(&self) -> u32 {
+        self.digest.clone().finalize()
+    }
+
+    /// The checksum of everything written since this was last called (or since the writer was
+    /// created).
+    pub(super) fn take_chunk_checksum(&mut self) -> u32 {
+        std::mem::replace(&mut self.chunk_digest, CRC32C.digest()).finalize()
+    }
+
+    pub(super) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        self.chunk_digest.update(&buf[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 pub(super) fn write_leb_bit_run(run: RleRun<bool>, into: &mut Vec<u8>) {
     // dbg!(run);
     let mut n = run.len;
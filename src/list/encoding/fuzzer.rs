@@ -1,6 +1,9 @@
+use std::ops::Range;
 use rand::prelude::*;
 use crate::list::{ListCRDT, ListOpLog};
-use crate::list::encoding::EncodeOptions;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+use crate::list::encoding::{DecodeOptions, ENCODE_FULL, EncodeMode, EncodeOptions, ListChunkType};
+use crate::list::encoding::decode_tools::BufReader;
 use crate::list::old_fuzzer_tools::old_make_random_change;
 use crate::list_fuzzer_tools::{choose_2, make_random_change};
 use crate::listmerge::simple_oplog::{SimpleBranch, SimpleOpLog};
@@ -29,6 +32,12 @@ fn fuzz_encode_decode_once(seed: u64) {
             store_inserted_content: true,
             store_deleted_content: true,
             compress_content: true,
+            dedup_content: false,
+            redact_content_for: &[],
+            keep_deleted_content_after: None,
+            chunk_checksums: false,
+            canonical: false,
+            mode: EncodeMode::Full,
             verbose: false
         });
 
@@ -96,6 +105,12 @@ fn fuzz_encode_decode_multi(seed: u64, verbose: bool) {
             store_inserted_content: true,
             store_deleted_content: true,
             compress_content: true,
+            dedup_content: false,
+            redact_content_for: &[],
+            keep_deleted_content_after: None,
+            chunk_checksums: false,
+            canonical: false,
+            mode: EncodeMode::Full,
             verbose: false
         };
         let a_data = a.oplog.encode(encode_opts.clone());
@@ -110,6 +125,8 @@ fn fuzz_encode_decode_multi(seed: u64, verbose: bool) {
             dbg!(&b);
             panic!("Documents do not match");
         } else {
+            assert!(a.branch.content_eq(&b.branch));
+            assert!(a.branch.version_eq(&a.oplog, &b.branch, &b.oplog));
             if verbose {
                 println!("Merge {:?} -> '{}'", &a.oplog.cg.version, &a.branch.content);
             }
@@ -131,3 +148,218 @@ fn encode_decode_multi_fuzz_forever() {
         fuzz_encode_decode_multi(seed, false);
     }
 }
+
+// This fuzzer builds up an oplog from 3 peers, saving patches from each peer along the way. Then
+// it merges random patches into a fresh oplog over and over. Merging data which is (partly or
+// entirely) already known should only ever add the operations which are new.
+fn fuzz_merge_idempotent(seed: u64) {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut docs = [ListCRDT::new(), ListCRDT::new(), ListCRDT::new()];
+    for (i, doc) in docs.iter_mut().enumerate() {
+        doc.get_or_create_agent_id(agent_name(i).as_str());
+    }
+
+    let mut patches = vec![];
+
+    for _i in 0..30 {
+        let idx = rng.gen_range(0..docs.len());
+        for _j in 0..rng.gen_range(1..=3) {
+            old_make_random_change(&mut docs[idx], None, 0, &mut rng);
+        }
+
+        // Save a patch from some random point in the doc's history. Patches from ROOT are fully
+        // self contained. Other patches can only be merged once their base version is known.
+        let oplog = &docs[idx].oplog;
+        let from = if rng.gen_bool(0.3) || oplog.is_empty() {
+            vec![]
+        } else {
+            vec![rng.gen_range(0..oplog.len())]
+        };
+        let from = oplog.cg.graph.find_dominators(&from);
+        patches.push(oplog.encode_from(ENCODE_FULL, from.as_ref()));
+
+        if rng.gen_bool(0.5) {
+            let (_, a, _, b) = choose_2(&mut docs, &mut rng);
+            b.merge_data_and_ff(&a.oplog.encode(ENCODE_FULL)).unwrap();
+        }
+    }
+
+    // The oplog containing everything.
+    let mut all = ListOpLog::new();
+    for doc in docs.iter() {
+        all.decode_and_add(&doc.oplog.encode(ENCODE_FULL)).unwrap();
+    }
+    patches.extend(docs.iter().map(|doc| doc.oplog.encode(ENCODE_FULL)));
+
+    let mut oplog = ListOpLog::new();
+    for _i in 0..100 {
+        let data = &patches[rng.gen_range(0..patches.len())];
+        let before = oplog.clone();
+
+        match oplog.decode_and_add(data) {
+            Ok(_) => {
+                oplog.dbg_check(true);
+                assert!(oplog.len() <= all.len());
+
+                // Merging the same data again does nothing.
+                let after = oplog.clone();
+                oplog.decode_and_add(data).unwrap();
+                assert_eq!(oplog, after);
+                assert_eq!(oplog.len(), after.len());
+            }
+            Err(e) => {
+                assert_eq!(e, ParseErrorKind::BaseVersionUnknown);
+                assert_eq!(oplog, before);
+            }
+        }
+    }
+
+    // Merging everything results in the same oplog, no matter what was already merged.
+    for data in patches.iter().rev() {
+        oplog.decode_and_add(data).unwrap();
+    }
+    oplog.dbg_check(true);
+    assert_eq!(oplog, all);
+    assert_eq!(oplog.len(), all.len());
+
+    // And merging an oplog's own data into itself is a no-op.
+    let data = all.encode(ENCODE_FULL);
+    all.decode_and_add(&data).unwrap();
+    assert_eq!(oplog, all);
+    assert_eq!(oplog.len(), all.len());
+}
+
+#[test]
+fn merge_idempotent_fuzz_once() {
+    for seed in 0..10 {
+        fuzz_merge_idempotent(seed);
+    }
+}
+
+#[test]
+#[ignore]
+fn merge_idempotent_fuzz_forever() {
+    for seed in 0.. {
+        if seed % 20 == 0 { println!("seed {seed}"); }
+        fuzz_merge_idempotent(seed);
+    }
+}
+
+/// The body of the first chunk in the reader with the given type.
+fn find_chunk(reader: BufReader<'_>, chunk_type: ListChunkType) -> BufReader<'_> {
+    reader.chunks()
+        .map(|c| c.unwrap())
+        .find(|(t, _)| *t == chunk_type)
+        .unwrap().1
+}
+
+/// The range of bytes in the file which the reader is reading.
+fn file_range(reader: BufReader) -> Range<usize> {
+    let start = reader.pos().unwrap();
+    start..start + reader.len()
+}
+
+// This fuzzer throws damaged files at the decoder. Decoding should fail with an error (or succeed)
+// but never panic. Most random changes are caught by the checksum, so they're ignored here.
+fn fuzz_decode_garbage(seed: u64) {
+    use ListChunkType::*;
+
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut doc = ListCRDT::new();
+    let a = doc.get_or_create_agent_id("a");
+    doc.get_or_create_agent_id("b");
+    for _i in 0..10 {
+        let agent = rng.gen_range(0..2);
+        old_make_random_change(&mut doc, None, agent, &mut rng);
+    }
+    // Repeated content is stored deduped.
+    doc.insert(a, 0, &"the quick brown fox jumps. ".repeat(3));
+    let v = doc.oplog.local_version();
+    doc.oplog.add_checkpoint(a, v.as_ref(), "checkpoint");
+    doc.oplog.set_timestamp((0..5).into(), 1_000_000);
+    doc.oplog.set_timestamp((5..doc.oplog.len()).into(), 2_000_000);
+    let valid = doc.oplog.encode(EncodeOptions {
+        store_deleted_content: true,
+        compress_content: false,
+        dedup_content: true,
+        ..ENCODE_FULL
+    });
+    let opts = DecodeOptions { ignore_crc: true, ..Default::default() };
+
+    // Chunks with their own structure, which random changes to the whole file rarely reach.
+    let mut file = BufReader::new(&valid);
+    file.read_magic().unwrap();
+    file.next_usize().unwrap(); // Protocol version
+    let patches = find_chunk(file, Patches);
+    let mut content = find_chunk(patches, PatchContent);
+    content.next_usize().unwrap(); // Operation type
+    let targets = [
+        find_chunk(content, ContentDeduped),
+        find_chunk(patches, Checkpoints),
+        find_chunk(patches, Timestamps),
+    ].map(file_range);
+
+    for _i in 0..200 {
+        let data: Vec<u8> = match rng.gen_range(0..4) {
+            // Random bytes.
+            0 => (0..rng.gen_range(0..100)).map(|_| rng.gen()).collect(),
+            // A valid header followed by random bytes. Lots of continuation bytes make for
+            // invalid varints.
+            1 => {
+                let mut data = valid[..10].to_vec();
+                data.extend((0..rng.gen_range(0..100)).map(|_| if rng.gen_bool(0.3) { 0xff } else { rng.gen() }));
+                data
+            }
+            // A valid file with some bytes changed, or truncated.
+            2 => {
+                let mut data = valid.clone();
+                for _j in 0..rng.gen_range(1..4) {
+                    let pos = rng.gen_range(0..data.len());
+                    data[pos] = rng.gen();
+                }
+                if rng.gen_bool(0.2) { data.truncate(rng.gen_range(0..data.len())); }
+                data
+            }
+            // A valid file with one of the targeted chunks changed. The chunk keeps its length,
+            // so the rest of the file still parses around it.
+            _ => {
+                let mut data = valid.clone();
+                let body = targets.choose(&mut rng).unwrap().clone();
+                if rng.gen_bool(0.3) {
+                    for b in &mut data[body] {
+                        *b = if rng.gen_bool(0.3) { 0xff } else { rng.gen() };
+                    }
+                } else {
+                    for _j in 0..rng.gen_range(1..4) {
+                        let pos = rng.gen_range(body.clone());
+                        data[pos] = rng.gen();
+                    }
+                }
+                data
+            }
+        };
+
+        let _ = ListOpLog::load_from(&data);
+
+        let mut oplog = doc.oplog.clone();
+        if oplog.decode_and_add_opts(&data, opts.clone()).is_err() {
+            assert_eq!(oplog, doc.oplog);
+        }
+    }
+}
+
+#[test]
+fn decode_garbage_fuzz_once() {
+    for seed in 0..20 {
+        fuzz_decode_garbage(seed);
+    }
+}
+
+#[test]
+#[ignore]
+fn decode_garbage_fuzz_forever() {
+    for seed in 0.. {
+        if seed % 100 == 0 { println!("seed {seed}"); }
+        fuzz_decode_garbage(seed);
+    }
+}
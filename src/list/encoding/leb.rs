@@ -1,5 +1,5 @@
 use std::mem::size_of;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 
 /// We're using protobuf's encoding system for variable sized integers. Most numbers we store here
 /// follow a Parato distribution, so this ends up being a space savings overall.
@@ -115,11 +115,11 @@ fn decode_leb_u64_slow(buf: &[u8]) -> Result<(u64, usize), ParseError> {
     let mut i = 0;
     while i < buf.len() {
         if i == 10 {
-            return Err(ParseError::InvalidVarInt)
+            return Err(ParseErrorKind::InvalidVarInt.into())
         }
         let b = buf[i];
         if i == 9 && (b & 0x7f) > 1 {
-            return Err(ParseError::InvalidVarInt)
+            return Err(ParseErrorKind::InvalidVarInt.into())
         }
         r |= ((b & 0x7f) as u64) << (i * 7);
         i += 1;
@@ -127,14 +127,14 @@ fn decode_leb_u64_slow(buf: &[u8]) -> Result<(u64, usize), ParseError> {
             return Ok((r, i))
         }
     }
-    Err(ParseError::UnexpectedEOF)
+    Err(ParseErrorKind::UnexpectedEOF.into())
 }
 
 // TODO: This is from rust-protobuf. Check this is actually faster than decode_u64_slow.
 /// Returns (varint, number of bytes read).
 pub fn decode_leb_u64(buf: &[u8]) -> Result<(u64, usize), ParseError> {
     if buf.is_empty() {
-        Err(ParseError::UnexpectedEOF)
+        Err(ParseErrorKind::UnexpectedEOF.into())
     } else if buf[0] < 0x80 {
         // The most common case
         Ok((buf[0] as u64, 1))
@@ -154,7 +154,7 @@ pub fn decode_leb_u64(buf: &[u8]) -> Result<(u64, usize), ParseError> {
             let b = buf[i];
 
             if i == 9 && (b & 0x7f) > 1 {
-                return Err(ParseError::InvalidVarInt);
+                return Err(ParseErrorKind::InvalidVarInt.into());
             }
             r |= ((b & 0x7f) as u64) << (i as u64 * 7);
             i += 1;
@@ -162,7 +162,7 @@ pub fn decode_leb_u64(buf: &[u8]) -> Result<(u64, usize), ParseError> {
                 return Ok((r, i));
             }
         }
-        Err(ParseError::InvalidVarInt)
+        Err(ParseErrorKind::InvalidVarInt.into())
     } else {
         decode_leb_u64_slow(buf)
     }
@@ -172,7 +172,7 @@ pub fn decode_leb_u32(buf: &[u8]) -> Result<(u32, usize), ParseError> {
     let (val, bytes_consumed) = decode_leb_u64(buf)?;
     if val >= u32::MAX as u64 {
         // varint is not a u32!
-        return Err(ParseError::InvalidVarInt);
+        return Err(ParseErrorKind::InvalidVarInt.into());
     }
     debug_assert!(bytes_consumed <= 5);
     Ok((val as u32, bytes_consumed))
@@ -13,20 +13,48 @@ pub mod encode_tools;
 mod decode_tools;
 pub mod save_transformed;
 pub(crate) mod leb;
+mod dedup;
+mod pending;
 
 use rle::MergableSpan;
 use crate::encoding::varint::*;
 use num_enum::TryFromPrimitive;
-pub use encode_oplog::{ENCODE_FULL, ENCODE_PATCH, EncodeOptions};
+pub use encode_oplog::{ENCODE_FULL, ENCODE_PATCH, EncodeMode, EncodeOptions};
+pub use decode_oplog::{capabilities, chunk_sizes, ChunkInfo, ChunkSize, decode_document, detect_version, DecodeDriver, DecodeOptions, DecodeStatus, FileSummary, iter_chunks, MergeStats, StreamingDecoder, verify_data};
+pub(crate) use pending::PendingPatch;
+#[cfg(feature = "storage")]
+pub use crate::storage::AppendableFile;
 
 const MAGIC_BYTES: [u8; 8] = *b"DMNDTYPS";
+/// The start of each segment added to a file by [`AppendableFile`].
+pub(crate) const SEGMENT_MAGIC_BYTES: [u8; 8] = *b"DMNDSGMT";
 
+/// The protocol version written by this build. When the format changes, the decoders for the old
+/// version should be kept (converting old data into the current in-memory representation) and
+/// [`protocol_version_supported`] should accept both versions.
 const PROTOCOL_VERSION: usize = 0;
 
+/// The protocol version of files written with [`EncodeOptions::chunk_checksums`]. Version 0
+/// decoders only skip unknown chunks in some places, so the chunk CRCs between the top level
+/// chunks would make them miss the end branch and skip checking the file's CRC. Otherwise these
+/// files are the same as version 0 files.
+const CHUNK_CRC_PROTOCOL_VERSION: usize = 1;
+
+/// Whether this build can read files with the given protocol version. See [`detect_version`].
+fn protocol_version_supported(version: usize) -> bool {
+    version == PROTOCOL_VERSION || version == CHUNK_CRC_PROTOCOL_VERSION
+}
+
 // #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+/// The type of a chunk in an encoded file. See [`iter_chunks`].
+///
+/// Decoders skip chunks with types they don't know, so newer versions can add chunks without
+/// breaking older decoders. That's only safe for data which can be ignored. Chunks which change
+/// how the rest of the file is read are declared as required capabilities (see [`Capability`]).
 #[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
 #[repr(u32)]
-enum ListChunkType {
+#[non_exhaustive]
+pub enum ListChunkType {
     /// Packed bytes storing any data compressed in later parts of the file.
     CompressedFieldsLZ4 = 5,
 
@@ -35,6 +63,12 @@ enum ListChunkType {
     DocId = 2,
     AgentNames = 3,
     UserData = 4,
+    /// The total size of inserted content in the oplog. Only written when the inserted content
+    /// isn't stored in the file.
+    Usage = 6,
+    /// The features the file uses, as required and optional [`CapabilitySet`]s. This is the last
+    /// chunk in FileInfo. Files written before capabilities were added don't have it.
+    Capabilities = 7,
 
     /// The StartBranch chunk describes the state of the document before included patches have been
     /// applied.
@@ -44,6 +78,13 @@ enum ListChunkType {
     /// StartBranch content is optional.
     Content = 13,
     ContentCompressed = 14, // Might make more sense to have a generic compression tag for chunks.
+    /// Content with repeated sections replaced by references to earlier copies. This contains a
+    /// Content or ContentCompressed chunk with the literal sections, followed by the list of runs.
+    ContentDeduped = 15,
+    /// The document's content at some version, with no history. This takes the place of
+    /// StartBranch and Patches in files written with [`EncodeMode::Snapshot`]. It contains a
+    /// Version and a content chunk.
+    Snapshot = 16,
 
     Patches = 20,
     OpVersions = 21,
@@ -53,10 +94,112 @@ enum ListChunkType {
     PatchContent = 24,
     /// ContentKnown is a RLE expressing which ranges of patches have known content
     ContentIsKnown = 25,
+    /// The positions and messages of checkpoints in the patches. Checkpoints have no operation,
+    /// so they're left out of OpTypeAndPosition. Only written if there are any.
+    Checkpoints = 26,
 
     TransformedPositions = 27, // Currently unused
+    /// Runs of operations with the same timestamp, by their position in the patches. Only
+    /// written if any operations have a timestamp.
+    Timestamps = 28,
 
     Crc = 100,
+    /// The CRC of the top level chunk just before this one (header and body). These are only
+    /// written with [`EncodeOptions::chunk_checksums`] (in [`CHUNK_CRC_PROTOCOL_VERSION`] files),
+    /// and they're skipped when parsing.
+    ChunkCrc = 101,
+}
+
+/// A feature an encoded file can use. Files list the capabilities they use in their FileInfo. See
+/// [`capabilities`].
+///
+/// Required capabilities change how the file must be read, so a decoder which doesn't know one
+/// refuses to load the file with [`UnsupportedCapability`]. Optional capabilities are stored in
+/// chunks which older decoders can skip. They're only informational.
+///
+/// The numbers are bit positions in a [`CapabilitySet`], so they can't be changed.
+///
+/// [`UnsupportedCapability`]: crate::encoding::parseerror::ParseErrorKind::UnsupportedCapability
+#[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
+#[repr(u32)]
+#[non_exhaustive]
+pub enum Capability {
+    /// (Required) Content is compressed into the CompressedFieldsLZ4 chunk.
+    CompressedContent = 0,
+    /// (Required) Some content is stored in ContentDeduped chunks.
+    DedupedContent = 1,
+    /// (Required) The file is a snapshot, with no operations. See [`EncodeMode::Snapshot`].
+    Snapshot = 2,
+    /// (Required) The patches contain checkpoints. Checkpoints take up a version without an
+    /// operation, so a decoder which skipped them would misnumber the operations after them.
+    Checkpoints = 3,
+
+    /// (Optional) Deleted content is stored.
+    DeletedContent = 16,
+    /// (Optional) The document's content at the start version is stored.
+    StartContent = 17,
+    /// (Optional) The document's content at the end of the file is stored.
+    EndContent = 18,
+    /// (Optional) Operations have timestamps.
+    Timestamps = 19,
+    /// (Optional) Each top level chunk is followed by its CRC.
+    ChunkChecksums = 20,
+}
+
+impl Capability {
+    /// Whether decoders need to understand this capability to read a file which uses it.
+    pub fn is_required(self) -> bool {
+        use Capability::*;
+        matches!(self, CompressedContent | DedupedContent | Snapshot | Checkpoints)
+    }
+}
+
+/// A set of [`Capability`] flags. This can include capabilities from newer versions of diamond
+/// types, which this version doesn't know about.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct CapabilitySet(pub u64);
+
+impl CapabilitySet {
+    pub fn contains(&self, capability: Capability) -> bool {
+        self.0 & (1 << capability as u32) != 0
+    }
+
+    pub fn insert(&mut self, capability: Capability) {
+        self.0 |= 1 << capability as u32;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The capabilities in the set which this version knows about.
+    pub fn known(&self) -> impl Iterator<Item = Capability> + '_ {
+        self.bits().filter_map(|bit| Capability::try_from(bit).ok())
+    }
+
+    /// The bit positions of the capabilities in the set which this version doesn't know about.
+    pub fn unknown(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bits().filter(|&bit| Capability::try_from(bit).is_err())
+    }
+
+    fn bits(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..64).filter(|bit| self.0 & (1 << bit) != 0)
+    }
+}
+
+/// Load data the way a decoder which doesn't know about `chunk_type` would, by changing the
+/// chunk's type to one nobody knows. The CRC is ignored, since it no longer matches. Panics if the
+/// data doesn't have the chunk.
+#[cfg(test)]
+pub(crate) fn load_skipping_chunk(data: &[u8], chunk_type: ListChunkType) -> Result<crate::list::ListOpLog, crate::encoding::parseerror::ParseError> {
+    let chunk = iter_chunks(data)
+        .map(|c| c.unwrap())
+        .find(|c| c.chunk_type == chunk_type)
+        .expect("Data is missing the chunk");
+    let mut data = data.to_vec();
+    assert_eq!(data[chunk.offset], chunk_type as u8);
+    data[chunk.offset] = 99;
+    crate::list::ListOpLog::load_from_opts(&data, DecodeOptions { ignore_crc: true, ..Default::default() })
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, TryFromPrimitive)]
@@ -0,0 +1,166 @@
+//! Patches which can't be merged yet, because they start from a version the oplog doesn't have.
+//!
+//! Peers can send data out of order - for example, a patch from B to C might arrive before the
+//! patch from A to B. [`ListOpLog::merge_data_or_defer`] holds onto data like that until the
+//! operations it depends on have been merged, and [`ListOpLog::missing_ranges`] says what those
+//! operations are so they can be requested. [`ListOpLog::merge_data_many`] does the same within
+//! a batch of data.
+
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteVersionOwned, RemoteVersionSpanOwned};
+use crate::dtrange::DTRange;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+use crate::list::encoding::MergeStats;
+use crate::list::limits::LimitExceeded;
+use crate::list::ListOpLog;
+
+/// Encoded data waiting for the operations in its start version.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingPatch {
+    data: Vec<u8>,
+    /// The file's start version. At least one of these operations was missing when the data
+    /// arrived.
+    start_version: Vec<RemoteVersionOwned>,
+}
+
+impl ListOpLog {
+    /// Merge a binary chunk into this document like [`merge_data`](ListOpLog::merge_data). But if
+    /// the data starts from a version we don't have yet, it's kept aside instead of failing with
+    /// `BaseVersionUnknown`, and `None` is returned.
+    ///
+    /// Kept data is merged automatically once the operations it needs have been merged in (with
+    /// this method, [`merge_data`](ListOpLog::merge_data) or
+    /// [`decode_and_add`](ListOpLog::decode_and_add)). Use
+    /// [`missing_ranges`](ListOpLog::missing_ranges) to find out what to ask peers for. If kept
+    /// data still fails to merge after that, it's dropped. The returned stats count the
+    /// operations from kept data which was merged along the way.
+    ///
+    /// The amount of data kept is capped by the oplog's
+    /// [`max_pending_patches`](crate::list::limits::DocLimits::max_pending_patches) and
+    /// [`max_pending_bytes`](crate::list::limits::DocLimits::max_pending_bytes) limits. Once
+    /// they're reached, data which can't be merged yet is rejected with
+    /// `ParseErrorKind::LimitExceeded`.
+    pub fn merge_data_or_defer(&mut self, data: &[u8]) -> Result<Option<MergeStats>, ParseError> {
+        match self.merge_data(data) {
+            Err(e) if e.kind == ParseErrorKind::BaseVersionUnknown => {
+                let start_version = Self::read_start_version(data)?;
+                // BaseVersionUnknown can also come from a bad parent in the patches. There's no
+                // point waiting if we already have everything the data starts from.
+                if start_version.iter().all(|v| self.has_remote_version(v)) { return Err(e); }
+
+                let pending_bytes: usize = self.pending.iter().map(|p| p.data.len()).sum();
+                if self.pending.len() >= self.limits.max_pending_patches
+                    || pending_bytes.saturating_add(data.len()) > self.limits.max_pending_bytes
+                {
+                    return Err(ParseErrorKind::LimitExceeded(LimitExceeded::PendingData).into());
+                }
+
+                self.pending.push(PendingPatch { data: data.into(), start_version });
+                Ok(None)
+            }
+            result => result.map(Some),
+        }
+    }
+
+    /// Merge a batch of binary chunks, like calling [`merge_data`](ListOpLog::merge_data) on each
+    /// of them. The chunks can arrive in any order. Ones which start from a version we don't have
+    /// yet are held back, and merged as soon as the chunks they depend on have been.
+    ///
+    /// Each chunk is merged atomically, but the batch isn't. If a chunk fails to merge, the error
+    /// is returned and the chunks merged before it stay merged. Chunks which are still waiting at
+    /// the end (because nothing in the batch or the oplog provides their start version) are
+    /// reported as `DataMissing`, after everything else has been merged.
+    ///
+    /// The returned stats cover the whole batch. Their `version` is the combined version of all
+    /// the merged chunks.
+    pub fn merge_data_many<'a>(&mut self, data: impl IntoIterator<Item = &'a [u8]>) -> Result<MergeStats, ParseError> {
+        let len = self.len();
+        let num_agents = self.cg.agent_assignment.client_data.len();
+        let mut versions = Vec::new();
+        let mut incoming_user_data = None;
+        let mut waiting: Vec<(&[u8], Vec<RemoteVersionOwned>)> = Vec::new();
+
+        for data in data {
+            let stats = match self.merge_data(data) {
+                Err(e) if e.kind == ParseErrorKind::BaseVersionUnknown => {
+                    let start_version = Self::read_start_version(data)?;
+                    if start_version.iter().all(|v| self.has_remote_version(v)) { return Err(e); }
+                    waiting.push((data, start_version));
+                    continue;
+                }
+                result => result?,
+            };
+            versions.extend_from_slice(stats.version.as_ref());
+            incoming_user_data = stats.incoming_user_data.or(incoming_user_data);
+
+            // Merging this chunk might have made some of the waiting ones mergeable.
+            while let Some(idx) = waiting.iter()
+                .position(|(_, start)| start.iter().all(|v| self.has_remote_version(v)))
+            {
+                let (data, _) = waiting.remove(idx);
+                let stats = self.merge_data(data)?;
+                versions.extend_from_slice(stats.version.as_ref());
+                incoming_user_data = stats.incoming_user_data.or(incoming_user_data);
+            }
+        }
+
+        if !waiting.is_empty() { return Err(ParseErrorKind::DataMissing.into()); }
+
+        Ok(MergeStats {
+            version: self.cg.graph.find_dominators(&versions),
+            oplog_version: self.cg.version.clone(),
+            new_operations: self.len() - len,
+            new_range: (len..self.len()).into(),
+            new_agents: self.cg.agent_assignment.client_data.len() - num_agents,
+            incoming_user_data,
+        })
+    }
+
+    /// The operations needed before data kept by
+    /// [`merge_data_or_defer`](ListOpLog::merge_data_or_defer) can be merged, as ranges of seq
+    /// numbers for each agent. This is empty when nothing is waiting.
+    ///
+    /// Only operations named in the kept data's start versions are listed. Their history might
+    /// include other operations we're missing too, so it's usually simplest to ask peers for
+    /// everything since our current version.
+    pub fn missing_ranges(&self) -> Vec<RemoteVersionSpanOwned> {
+        let mut result: Vec<RemoteVersionSpanOwned> = Vec::new();
+
+        for RemoteVersionOwned(name, seq) in self.pending.iter().flat_map(|p| p.start_version.iter()) {
+            let needed: DTRange = (0..seq + 1).into();
+            let gaps: Vec<DTRange> = match self.get_agent_id(name) {
+                Some(agent) => self.cg.agent_assignment.client_data[agent as usize].item_times
+                    .iter_gaps(needed).collect(),
+                None => vec![needed],
+            };
+            result.extend(gaps.into_iter().map(|gap| RemoteVersionSpanOwned(name.clone(), gap)));
+        }
+
+        // Several patches can be waiting on the same operations.
+        result.sort_by(|a, b| (&a.0, a.1.start).cmp(&(&b.0, b.1.start)));
+        result.dedup_by(|next, prev| {
+            if next.0 != prev.0 || next.1.start > prev.1.end { return false; }
+            prev.1.end = prev.1.end.max(next.1.end);
+            true
+        });
+        result
+    }
+
+    fn has_remote_version(&self, RemoteVersionOwned(name, seq): &RemoteVersionOwned) -> bool {
+        self.get_agent_id(name)
+            .and_then(|agent| self.try_crdt_id_to_time((agent, *seq)))
+            .is_some()
+    }
+
+    /// Merge any kept data whose start version is now known. Called after data has been merged.
+    pub(super) fn merge_pending(&mut self) {
+        // Merging one patch can make others mergeable in turn. Each patch is removed before it's
+        // merged, so the nested calls from decode_and_add never see it again.
+        while let Some(idx) = self.pending.iter()
+            .position(|p| p.start_version.iter().all(|v| self.has_remote_version(v)))
+        {
+            let patch = self.pending.remove(idx);
+            // The data can still be bad. There's nobody to report the error to, so it's dropped.
+            let _ = self.decode_and_add(&patch.data);
+        }
+    }
+}
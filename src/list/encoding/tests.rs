@@ -1,7 +1,10 @@
-use crate::encoding::parseerror::ParseError;
-use crate::list::{ListCRDT, ListOpLog};
+use crate::encoding::parseerror::{ChecksumMismatch, ParseError, ParseErrorKind};
+use crate::encoding::tools::calc_checksum;
+use crate::list::{ListBranch, ListCRDT, ListOpLog};
 use crate::list::encoding::decode_oplog::{dbg_print_chunks_in, DecodeOptions};
+use crate::list::encoding::decode_tools::{BufReader, ChunkReader};
 use crate::frontier::local_frontier_eq;
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteVersionOwned, RemoteVersionSpanOwned};
 use super::*;
 
 fn simple_doc() -> ListCRDT {
@@ -15,21 +18,35 @@ fn simple_doc() -> ListCRDT {
 }
 
 fn check_encode_decode_matches(oplog: &ListOpLog) {
-    let data = oplog.encode(EncodeOptions {
+    let opts = EncodeOptions {
         user_data: None,
         store_start_branch_content: true,
         experimentally_store_end_branch_content: false,
         store_inserted_content: true,
         store_deleted_content: true,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: false,
-    });
+    };
+    let data = oplog.encode(opts.clone());
 
     let oplog2 = ListOpLog::load_from(&data).unwrap();
 
     // dbg!(oplog, &oplog2);
 
     assert_eq!(oplog, &oplog2);
+
+    // User data is stored in the file header, and doesn't change anything else.
+    let mut with_data = oplog.clone();
+    with_data.set_user_data(b"{\"title\": \"hi\", \"schema\": 2}".to_vec());
+    let oplog3 = ListOpLog::load_from(&with_data.encode(opts)).unwrap();
+    assert_eq!(oplog3.user_data(), with_data.user_data());
+    assert_eq!(with_data, oplog3);
 }
 
 #[test]
@@ -96,7 +113,7 @@ fn merge_future_patch_errors() {
     let bytes = oplog.encode_from(ENCODE_FULL, &[v-1]);
 
     let err = ListOpLog::load_from(&bytes).unwrap_err();
-    assert_eq!(err, ParseError::BaseVersionUnknown);
+    assert_eq!(err, ParseErrorKind::BaseVersionUnknown);
 }
 
 // This test is ignored because it errors (arguably correctly) when reading the base version at
@@ -188,41 +205,52 @@ fn check_unroll_works(dest: &ListOpLog, src: &ListOpLog) {
         store_inserted_content: true,
         store_deleted_content: true,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: false
     });
 
-    // dbg!(encoded_proper.len());
-    for i in 0..encoded_proper.len() {
-        // let i = 55;
-        // println!("{i}");
-        // We'll corrupt that byte and try to read the document back.
-        let mut corrupted = encoded_proper.clone();
-        corrupted[i] = !corrupted[i];
-        // dbg!(corrupted[i]);
+    check_failed_merges_leave_oplog_unchanged(dest, src, &encoded_proper);
+}
 
-        let mut actual_output = dest.clone();
-        // dbg!(&actual_output.cg);
+/// Merge `data` into `dest` with each byte corrupted, and truncated at each length. Any merge
+/// which fails must leave the oplog exactly as it was - down to its internal representation.
+fn check_failed_merges_leave_oplog_unchanged(dest: &ListOpLog, expected: &ListOpLog, data: &[u8]) {
+    let dest_debug = format!("{:?}", dest);
 
-        // In theory, we should always get an error here. But we don't, because the CRC check
-        // is optional and the corrupted data can just remove the CRC check entirely!
+    let check = |corrupted: &[u8], ignore_crc: bool| {
+        let mut actual_output = dest.clone();
 
-        let result = actual_output.decode_and_add_opts(&corrupted, DecodeOptions {
-            ignore_crc: false,
-            verbose: true,
+        let result = actual_output.decode_and_add_opts(corrupted, DecodeOptions {
+            ignore_crc,
+            ..Default::default()
         });
 
-        if let Err(_err) = result {
-            // dbg!(&actual_output.cg);
-            // dbg!(&dest.cg);
-
-            assert_eq!(&actual_output, dest);
-        } else {
-            // dbg!(&actual_output);
-            // dbg!(src);
-            assert_eq!(&actual_output, src);
+        match result {
+            Err(_) => assert_eq!(format!("{:?}", actual_output), dest_debug),
+            // In theory, we should always get an error with the CRC check. But we don't, because
+            // the CRC check is optional and the corrupted data can just remove the CRC check
+            // entirely! Without it, corrupted data can decode to anything.
+            Ok(_) if !ignore_crc => assert_eq!(&actual_output, expected),
+            Ok(_) => {}
         }
-        // Otherwise the data loaded correctly!
+    };
 
+    for i in 0..data.len() {
+        // We'll corrupt that byte and try to read the document back.
+        let mut corrupted = data.to_vec();
+        corrupted[i] = !corrupted[i];
+        check(&corrupted, false);
+        // Ignoring the CRC means the corruption is found partway through merging, and the
+        // changes need to be unwound.
+        check(&corrupted, true);
+
+        check(&data[..i], false);
+        check(&data[..i], true);
     }
 }
 
@@ -233,6 +261,168 @@ fn error_unrolling() {
     check_unroll_works(&ListOpLog::new(), &doc.oplog);
 }
 
+#[test]
+fn failed_merges_into_existing_oplog_are_unwound() {
+    // Both peers share some history. Then the source adds edits from a new agent and an existing
+    // agent, and the destination makes a concurrent edit.
+    let mut src = simple_doc().oplog;
+    let mut dest = src.clone();
+    let shared = src.local_version();
+
+    let mike = src.get_or_create_agent_id("mike");
+    src.add_insert(mike, 0, "yo ");
+    src.add_delete_without_content(0, 1..4);
+    dest.add_insert(0, 2, "!!");
+
+    let mut expected = dest.clone();
+    expected.decode_and_add(&src.encode(ENCODE_FULL)).unwrap();
+
+    check_failed_merges_leave_oplog_unchanged(&dest, &expected, &src.encode(ENCODE_FULL));
+    check_failed_merges_leave_oplog_unchanged(&dest, &expected, &src.encode_from(ENCODE_FULL, shared.as_ref()));
+}
+
+#[test]
+fn truncated_files_are_rejected() {
+    // Untrusted files might be cut off anywhere. Even if the CRC is ignored, the error should come
+    // from the file's framing and leave the oplog untouched.
+    let mut src = simple_doc().oplog;
+    src.add_insert(0, 0, "yooo");
+    let data = src.encode(ENCODE_FULL);
+
+    // Cutting the file right before the CRC chunk (6 bytes: type, length, u32) leaves a valid
+    // file without a checksum.
+    let crc_start = data.len() - 6;
+    for i in (0..data.len()).filter(|i| *i != crc_start) {
+        let mut oplog = ListOpLog::new();
+        let err = oplog.decode_and_add_opts(&data[..i], DecodeOptions {
+            ignore_crc: true,
+            ..Default::default()
+        }).unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::InvalidLength | ParseErrorKind::UnexpectedEOF), "{i}: {err:?}");
+        assert_eq!(oplog, ListOpLog::new());
+    }
+}
+
+#[test]
+fn encode_from_concurrent_branches() {
+    // A diamond: a and b are concurrent, and m merges them.
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let base = oplog.add_insert(seph, 0, "hi ");
+    let a = oplog.add_insert_at(seph, &[base], 3, "there");
+    let b = oplog.add_insert_at(mike, &[base], 0, "oh ");
+    oplog.add_delete_at(seph, &[a, b], 0..3);
+
+    // A peer with just one of the branches.
+    for branch in [a, b] {
+        let mut peer = oplog.truncate_to(&[branch]);
+        peer.decode_and_add(&oplog.encode_from(ENCODE_PATCH, &[branch])).unwrap();
+        assert_eq!(peer, oplog);
+
+        // Versions already in the history of the base don't change anything.
+        let with_base = oplog.encode_from(ENCODE_PATCH, &[base, branch]);
+        assert_eq!(with_base, oplog.encode_from(ENCODE_PATCH, &[branch]));
+    }
+
+    // A peer with both branches, but not the merge.
+    let mut peer = oplog.truncate_to(&[a, b]);
+    let patch = oplog.encode_from(ENCODE_PATCH, &[b, a]);
+    assert_eq!(patch, oplog.encode_from(ENCODE_PATCH, &[a, b]));
+    let stats = peer.merge_data(&patch).unwrap();
+    assert_eq!(stats.new_operations, 3);
+    assert_eq!(peer, oplog);
+
+    // The patch doesn't apply to a peer which is missing either branch.
+    let mut peer = oplog.truncate_to(&[a]);
+    assert_eq!(peer.decode_and_add(&patch).unwrap_err(), ParseErrorKind::BaseVersionUnknown);
+}
+
+#[test]
+fn merge_data_reports_what_was_added() {
+    let src = simple_doc().oplog;
+    let mut dest = ListOpLog::new();
+
+    let stats = dest.merge_data(&src.encode(ENCODE_FULL)).unwrap();
+    assert_eq!(stats, MergeStats {
+        version: src.local_version(),
+        oplog_version: src.local_version(),
+        new_operations: src.len(),
+        new_range: (0..src.len()).into(),
+        new_agents: 1,
+        incoming_user_data: None,
+    });
+    assert_eq!(dest, src);
+
+    // Merging the same data again doesn't add anything.
+    let stats = dest.merge_data(&src.encode(ENCODE_FULL)).unwrap();
+    assert_eq!((stats.new_operations, stats.new_agents), (0, 0));
+    assert!(stats.new_range.is_empty());
+    assert_eq!(stats.oplog_version, src.local_version());
+
+    // Concurrent changes from another peer are appended after the local ones.
+    let mut peer = src.clone();
+    let mike = peer.get_or_create_agent_id("mike");
+    peer.add_insert(mike, 0, "yo ");
+    let local = dest.get_or_create_agent_id("seph");
+    dest.add_insert(local, 0, "x");
+    let stats = dest.merge_data(&peer.encode_from(ENCODE_PATCH, src.local_version().as_ref())).unwrap();
+    assert_eq!(stats.new_range, (src.len() + 1..src.len() + 4).into());
+    assert_eq!(stats.new_agents, 1);
+    assert_eq!(stats.version.as_ref(), &[src.len() + 3]);
+    assert_eq!(stats.oplog_version.as_ref(), &[src.len(), src.len() + 3]);
+
+    // A bad checksum is found before anything is merged.
+    let mut bytes = src.encode(ENCODE_FULL);
+    let last_byte = bytes.last_mut().unwrap();
+    *last_byte = !*last_byte;
+    let mut empty = ListOpLog::new();
+    assert!(matches!(empty.merge_data(&bytes).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+    assert_eq!(empty, ListOpLog::new());
+}
+
+#[test]
+fn ignore_crc_recovers_damaged_content() {
+    let oplog = simple_doc().oplog;
+    let data = oplog.encode(EncodeOptions { compress_content: false, ..ENCODE_FULL });
+    let content_pos = data.windows(5).position(|w| w == b"there").unwrap();
+    let ignore_crc = DecodeOptions { ignore_crc: true, ..Default::default() };
+
+    // Changing a character of the inserted content leaves the file structurally valid. Only the
+    // CRC notices, and without it the document loads with the damaged text.
+    let mut corrupt = data.clone();
+    corrupt[content_pos + 4] = b'a';
+    assert!(matches!(ListOpLog::load_from(&corrupt).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+    let loaded = ListOpLog::load_from_opts(&corrupt, ignore_crc.clone()).unwrap();
+    assert_eq!(loaded.checkout_tip().content(), "hi ma");
+    assert_eq!(loaded.cg, oplog.cg);
+
+    // Damage which breaks the file's structure is still reported, with a more specific error.
+    let mut corrupt = data.clone();
+    corrupt[content_pos] = 0xff;
+    assert!(matches!(ListOpLog::load_from(&corrupt).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+    let err = ListOpLog::load_from_opts(&corrupt, ignore_crc).unwrap_err();
+    assert_eq!(err, ParseErrorKind::InvalidUTF8);
+    assert!(err.pos.is_some());
+}
+
+#[test]
+fn checksum_failure_reports_crcs() {
+    let data = simple_doc().oplog.encode(ENCODE_FULL);
+    // The file ends with its CRC.
+    let stored = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap());
+    let mut corrupt = data.clone();
+    *corrupt.last_mut().unwrap() ^= 0xff;
+    let expected = u32::from_le_bytes(corrupt[corrupt.len() - 4..].try_into().unwrap());
+
+    let mismatch = ChecksumMismatch { expected, actual: stored };
+    let err = ListOpLog::load_from(&corrupt).unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::ChecksumFailed(mismatch));
+    assert!(err.to_string().contains(&format!("{expected:#010x}")));
+    assert!(err.to_string().contains(&format!("{stored:#010x}")));
+    assert_eq!(StreamingDecoder::new().push(&corrupt).unwrap_err().kind, ParseErrorKind::ChecksumFailed(mismatch));
+}
+
 #[test]
 fn save_load_save_load() {
     let oplog1 = simple_doc().oplog;
@@ -245,6 +435,12 @@ fn save_load_save_load() {
         store_inserted_content: false,
         store_deleted_content: false,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: false
     });
     dbg_print_chunks_in(&bytes);
@@ -258,6 +454,12 @@ fn save_load_save_load() {
         store_inserted_content: false, // Need to say false here to avoid an assert for this.
         store_deleted_content: true,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: false
     });
     let oplog3 = ListOpLog::load_from(&bytes2).unwrap();
@@ -279,6 +481,59 @@ fn doc_id_preserved() {
     assert_eq!(oplog.doc_id, result.doc_id);
 }
 
+#[test]
+fn user_data_round_trips() {
+    let mut oplog = simple_doc().oplog;
+    let result = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+    assert_eq!(result.user_data(), None);
+
+    oplog.set_user_data(b"title: hi".to_vec());
+    let result = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+    assert_eq!(result.user_data(), Some(&b"title: hi"[..]));
+    assert_eq!(oplog, result);
+
+    // User data in the encode options is saved instead.
+    let bytes = oplog.encode(EncodeOptions { user_data: Some(b"v2"), ..ENCODE_FULL });
+    assert_eq!(ListOpLog::load_from(&bytes).unwrap().user_data(), Some(&b"v2"[..]));
+
+    oplog.clear_user_data();
+    let result = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+    assert_eq!(result.user_data(), None);
+}
+
+#[test]
+fn merge_keeps_local_user_data() {
+    let mut src = simple_doc().oplog;
+    src.set_user_data(b"theirs".to_vec());
+    let bytes = src.encode(ENCODE_FULL);
+
+    // With no user data of our own, we take the file's.
+    let mut dest = ListOpLog::new();
+    assert_eq!(dest.merge_data(&bytes).unwrap().incoming_user_data, None);
+    assert_eq!(dest.user_data(), Some(&b"theirs"[..]));
+
+    // Otherwise ours is kept, and theirs is returned if it differs.
+    let mut dest = ListOpLog::new();
+    dest.set_user_data(b"ours".to_vec());
+    let stats = dest.merge_data(&bytes).unwrap();
+    assert_eq!(stats.incoming_user_data, Some(b"theirs".to_vec()));
+    assert_eq!(dest.user_data(), Some(&b"ours"[..]));
+
+    dest.set_user_data(b"theirs".to_vec());
+    assert_eq!(dest.merge_data(&bytes).unwrap().incoming_user_data, None);
+
+    // A failed merge leaves the user data alone.
+    let mut bytes = bytes;
+    let last_byte = bytes.last_mut().unwrap();
+    *last_byte = !*last_byte;
+    let mut dest = ListOpLog::new();
+    dest.merge_data(&bytes).unwrap_err();
+    assert_eq!(dest.user_data(), None);
+    dest.set_user_data(b"ours".to_vec());
+    dest.merge_data(&bytes).unwrap_err();
+    assert_eq!(dest.user_data(), Some(&b"ours"[..]));
+}
+
 #[test]
 fn mismatched_doc_id_errors() {
     let mut oplog1 = simple_doc().oplog;
@@ -288,7 +543,7 @@ fn mismatched_doc_id_errors() {
     oplog2.doc_id = Some("bbb".into());
 
     let bytes = oplog1.encode(ENCODE_FULL);
-    assert_eq!(oplog2.decode_and_add(&bytes).unwrap_err(), ParseError::DocIdMismatch);
+    assert_eq!(oplog2.decode_and_add(&bytes).unwrap_err(), ParseErrorKind::DocIdMismatch);
     assert_eq!(oplog2.doc_id, Some("bbb".into())); // And the doc ID should be unchanged
 }
 
@@ -327,7 +582,7 @@ fn merge_returns_version_even_with_overlap() {
     let mut oplog2 = oplog.clone();
     let version = oplog2.decode_and_add(&bytes).unwrap();
 
-    assert!(local_frontier_eq(&version, oplog2.local_frontier_ref()));
+    assert!(local_frontier_eq(&version, oplog2.local_version_ref()));
 }
 
 #[test]
@@ -344,7 +599,7 @@ fn merge_patch_returns_correct_version() {
     let version = oplog2.decode_and_add(&bytes).unwrap();
 
     // dbg!(version);
-    assert!(local_frontier_eq(&version, oplog2.local_frontier_ref()));
+    assert!(local_frontier_eq(&version, oplog2.local_version_ref()));
 }
 
 #[test]
@@ -406,6 +661,12 @@ fn compat_simple_doc() {
         store_inserted_content: true,
         store_deleted_content: false,
         compress_content: true,
+        dedup_content: false,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
         verbose: false
     }));
 
@@ -422,4 +683,1441 @@ fn compat_simple_doc() {
         let bytes2_compressed_full = &[68, 77, 78, 68, 84, 89, 80, 83, 0, 5, 11, 9, 144, 104, 105, 32, 116, 104, 101, 114, 101, 109, 1, 7, 3, 5, 4, 115, 101, 112, 104, 10, 0, 20, 24, 24, 8, 0, 14, 2, 4, 9, 25, 1, 19, 21, 2, 2, 13, 22, 4, 65, 79, 11, 0, 23, 2, 13, 1, 100, 4, 128, 32, 8, 191];
         assert_eq!(ListOpLog::load_from(bytes2_compressed_full).unwrap(), doc.oplog);
     }
-}
\ No newline at end of file
+}
+
+/// Rewrite the encoded file, replacing the contents of the inner chunk inside the outer chunk. The
+/// CRC is dropped, since it would no longer match.
+fn replace_inner_chunk(data: &[u8], outer: ListChunkType, inner: ListChunkType, contents: &[u8]) -> Vec<u8> {
+    let mut reader = decode_tools::BufReader::new(data);
+    reader.read_magic().unwrap();
+    reader.next_usize().unwrap();
+    let mut result = data[..data.len() - reader.len()].to_vec();
+
+    for chunk in reader.chunks() {
+        let (chunk_type, chunk) = chunk.unwrap();
+        match chunk_type {
+            ListChunkType::Crc => {},
+            _ if chunk_type == outer => {
+                let mut outer_data = Vec::new();
+                for c in chunk.chunks() {
+                    let (inner_type, c) = c.unwrap();
+                    let c = if inner_type == inner { contents } else { c.buf };
+                    encode_tools::push_leb_chunk(&mut outer_data, inner_type, c);
+                }
+                encode_tools::push_leb_chunk(&mut result, chunk_type, &outer_data);
+            }
+            _ => encode_tools::push_leb_chunk(&mut result, chunk_type, chunk.buf),
+        }
+    }
+    result
+}
+
+/// Rewrite the encoded file with its OpParents chunk replaced. The CRC is dropped.
+fn replace_parents_chunk(data: &[u8], parents: &[u8]) -> Vec<u8> {
+    replace_inner_chunk(data, ListChunkType::Patches, ListChunkType::OpParents, parents)
+}
+
+fn replace_agent_names(data: &[u8], names: impl Iterator<Item = String>) -> Vec<u8> {
+    let mut chunk = Vec::new();
+    for name in names { encode_tools::push_leb_str(&mut chunk, &name); }
+    replace_inner_chunk(data, ListChunkType::FileInfo, ListChunkType::AgentNames, &chunk)
+}
+
+fn push_local_parent(dest: &mut Vec<u8>, diff: usize) {
+    encode_tools::push_leb_usize(dest, mix_bit_usize(mix_bit_usize(diff, false), false));
+}
+
+fn push_foreign_parent(dest: &mut Vec<u8>, mapped_agent: usize, seq: usize) {
+    encode_tools::push_leb_usize(dest, mix_bit_usize(mix_bit_usize(mapped_agent, false), true));
+    encode_tools::push_leb_usize(dest, seq);
+}
+
+fn push_root_parent(dest: &mut Vec<u8>) {
+    encode_tools::push_leb_usize(dest, mix_bit_usize(mix_bit_usize(0, false), true));
+}
+
+#[test]
+fn forward_parents_are_rejected() {
+    let oplog = simple_doc().oplog;
+    assert_eq!(oplog.len(), 13);
+    let data = oplog.encode(EncodeOptions::default());
+
+    let with_parents = |second_entry_parent: &dyn Fn(&mut Vec<u8>)| {
+        // Two history entries: 0..5 and 5..13.
+        let mut parents = Vec::new();
+        encode_tools::push_leb_usize(&mut parents, 5);
+        push_root_parent(&mut parents);
+        encode_tools::push_leb_usize(&mut parents, 8);
+        second_entry_parent(&mut parents);
+        replace_parents_chunk(&data, &parents)
+    };
+
+    // Sanity check. Rewriting the parents in a valid way should still load.
+    let valid = ListOpLog::load_from(&with_parents(&|p| push_local_parent(p, 1))).unwrap();
+    assert_eq!(valid.checkout_tip().content(), oplog.checkout_tip().content());
+    valid.dbg_check(true);
+
+    // The entry names itself as its parent.
+    assert_eq!(ListOpLog::load_from(&with_parents(&|p| push_local_parent(p, 0))).unwrap_err(),
+               ParseErrorKind::InvalidParent);
+    // The parent is before the start of the file.
+    assert_eq!(ListOpLog::load_from(&with_parents(&|p| push_local_parent(p, 6))).unwrap_err(),
+               ParseErrorKind::InvalidParent);
+    // Foreign reference to a later change in the same file.
+    assert_eq!(ListOpLog::load_from(&with_parents(&|p| push_foreign_parent(p, 1, 10))).unwrap_err(),
+               ParseErrorKind::InvalidParent);
+    // Foreign reference to an agent which doesn't exist.
+    assert_eq!(ListOpLog::load_from(&with_parents(&|p| push_foreign_parent(p, 5, 0))).unwrap_err(),
+               ParseErrorKind::InvalidParent);
+
+    // And failed loads don't leave anything behind.
+    let mut dest = ListOpLog::new();
+    dest.decode_and_add(&with_parents(&|p| push_foreign_parent(p, 1, 10))).unwrap_err();
+    assert_eq!(dest, ListOpLog::new());
+}
+
+#[test]
+fn mutually_referencing_parents_are_rejected() {
+    let mut oplog = ListOpLog::new();
+    let a = oplog.get_or_create_agent_id("a");
+    let b = oplog.get_or_create_agent_id("b");
+    oplog.add_insert_at(a, &[], 0, "aaa");
+    oplog.add_insert_at(b, &[], 0, "bbb");
+    let data = oplog.encode(EncodeOptions::default());
+
+    // Agents are written in name order, so a is agent 1 and b is agent 2.
+    let mut parents = Vec::new();
+    encode_tools::push_leb_usize(&mut parents, 3);
+    push_foreign_parent(&mut parents, 2, 0);
+    encode_tools::push_leb_usize(&mut parents, 3);
+    push_foreign_parent(&mut parents, 1, 2);
+
+    assert_eq!(ListOpLog::load_from(&replace_parents_chunk(&data, &parents)).unwrap_err(),
+               ParseErrorKind::InvalidParent);
+}
+
+/// The byte range of the body of the inner chunk inside the outer chunk in an encoded file.
+fn inner_chunk_range(data: &[u8], outer: ListChunkType, inner: ListChunkType) -> std::ops::Range<usize> {
+    let mut reader = BufReader::new(data);
+    reader.read_magic().unwrap();
+    reader.next_usize().unwrap();
+    fn find(mut chunks: ChunkReader, chunk_type: ListChunkType) -> BufReader {
+        chunks.find_map(|c| {
+            let (c, body) = c.unwrap();
+            if c == chunk_type { Some(body) } else { None }
+        }).unwrap()
+    }
+    let body = find(find(reader.chunks(), outer).chunks(), inner);
+    let start = body.pos().unwrap();
+    start..start + body.len()
+}
+
+#[test]
+fn detect_version_reads_header() {
+    // This file was written by an older build of diamond types.
+    let old = std::fs::read("benchmark_data/git-makefile.dt").unwrap();
+    assert_eq!(detect_version(&old).unwrap(), 0);
+    assert!(ListOpLog::load_from(&old).is_ok());
+
+    let data = simple_doc().oplog.encode(EncodeOptions::default());
+    assert_eq!(detect_version(&data).unwrap(), PROTOCOL_VERSION);
+
+    // Versions this build can't read are still reported.
+    let mut future = data.clone();
+    future[MAGIC_BYTES.len()] = 3;
+    assert_eq!(detect_version(&future).unwrap(), 3);
+    assert_eq!(ListOpLog::load_from(&future).unwrap_err(), ParseErrorKind::UnsupportedProtocolVersion);
+
+    assert_eq!(detect_version(b"hi there").unwrap_err(), ParseErrorKind::InvalidMagic);
+    assert_eq!(detect_version(&MAGIC_BYTES).unwrap_err(), ParseErrorKind::UnexpectedEOF);
+}
+
+#[test]
+fn verify_data_summarizes_without_loading() {
+    let oplog = ListOpLog::load_from(&std::fs::read("benchmark_data/git-makefile.dt").unwrap()).unwrap();
+    let mut doc = simple_doc();
+    let mike = doc.get_or_create_agent_id("mike");
+    let v = doc.oplog.local_version();
+    doc.oplog.add_insert_at(mike, &[1], 0, "yo ");
+
+    for (oplog, opts) in [(&oplog, ENCODE_FULL), (&doc.oplog, ENCODE_FULL), (&doc.oplog, EncodeOptions {
+        store_deleted_content: true,
+        compress_content: false,
+        dedup_content: true,
+        ..ENCODE_FULL
+    })] {
+        let data = oplog.encode(opts);
+        let summary = verify_data(&data).unwrap();
+        assert_eq!(summary.num_operations, oplog.len());
+        assert_eq!(summary.num_agents, oplog.cg.agent_assignment.num_agents());
+        assert!(summary.start_version.is_empty());
+        let mut version = summary.version.clone();
+        version.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected: Vec<RemoteVersionOwned> = oplog.remote_version().iter().map(|rv| rv.into()).collect();
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(version, expected);
+        assert_eq!(summary.chunks, chunk_sizes(&data).unwrap());
+    }
+
+    // Patches name the version they start from.
+    let patch = doc.oplog.encode_from(ENCODE_PATCH, v.as_ref());
+    let summary = verify_data(&patch).unwrap();
+    assert_eq!(summary.num_operations, 3);
+    assert_eq!(summary.num_history_entries, 1);
+    assert_eq!(summary.start_version, [RemoteVersionOwned("seph".into(), 12)]);
+    let mut version = summary.version.clone();
+    version.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(version, [RemoteVersionOwned("mike".into(), 2), RemoteVersionOwned("seph".into(), 12)]);
+
+    // Anything load_from rejects is rejected here too.
+    let data = doc.oplog.encode(ENCODE_FULL);
+    for len in 0..data.len() {
+        if ListOpLog::load_from(&data[..len]).is_err() {
+            assert!(verify_data(&data[..len]).is_err());
+        }
+    }
+    let mut corrupt = data.clone();
+    let last = corrupt.len() - 10;
+    corrupt[last] ^= 0xff;
+    assert!(matches!(verify_data(&corrupt).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+}
+
+#[test]
+fn errors_report_position_of_corruption() {
+    let oplog = simple_doc().oplog;
+    let data = oplog.encode(EncodeOptions::default());
+
+    // Bad magic bytes are reported at the start of the file.
+    let mut corrupt = data.clone();
+    corrupt[2] = b'X';
+    let err = ListOpLog::load_from(&corrupt).unwrap_err();
+    assert_eq!(err, ParseErrorKind::InvalidMagic);
+    assert_eq!(err.pos, Some(0));
+    assert!(err.chunk.is_empty());
+
+    // The protocol version comes straight after the magic bytes.
+    let mut corrupt = data.clone();
+    corrupt[MAGIC_BYTES.len()] = 3;
+    let err = ListOpLog::load_from(&corrupt).unwrap_err();
+    assert_eq!(err, ParseErrorKind::UnsupportedProtocolVersion);
+    assert_eq!(err.pos, Some(MAGIC_BYTES.len()));
+
+    // The second history entry names itself as its parent.
+    let mut parents = Vec::new();
+    encode_tools::push_leb_usize(&mut parents, 5);
+    push_root_parent(&mut parents);
+    encode_tools::push_leb_usize(&mut parents, 8);
+    push_local_parent(&mut parents, 0);
+    let corrupt = replace_parents_chunk(&data, &parents);
+    let range = inner_chunk_range(&corrupt, ListChunkType::Patches, ListChunkType::OpParents);
+
+    let err = ListOpLog::load_from(&corrupt).unwrap_err();
+    assert_eq!(err, ParseErrorKind::InvalidParent);
+    assert!(range.contains(&err.pos.unwrap()), "{err:?} not in {range:?}");
+    assert_eq!(err.chunk.chunk_types().collect::<Vec<_>>(),
+               [ListChunkType::Patches as u32, ListChunkType::OpParents as u32]);
+    assert_eq!(err.to_string(), format!(
+        "History entry names a parent which doesn't come before it (at byte {:#x} in chunk Patches > OpParents)",
+        err.pos.unwrap()
+    ));
+
+    // Corrupting the first operation's length means the operations no longer add up. Thats
+    // noticed somewhere in the chunk, or at its end.
+    let range = inner_chunk_range(&data, ListChunkType::Patches, ListChunkType::OpTypeAndPosition);
+    let range = range.start..=range.end;
+    let mut corrupt = data.clone();
+    corrupt[*range.start()] = 0x7f;
+    let err = ListOpLog::new().decode_and_add_opts(&corrupt, DecodeOptions {
+        ignore_crc: true,
+        ..Default::default()
+    }).unwrap_err();
+    assert!(range.contains(&err.pos.unwrap()), "{err:?} not in {range:?}");
+    assert_eq!(err.chunk.chunk_types().last(), Some(ListChunkType::OpTypeAndPosition as u32));
+}
+
+#[test]
+fn truncated_file_errors_are_reported_before_the_cut() {
+    let mut src = simple_doc().oplog;
+    src.add_insert(0, 0, "yooo");
+    let data = src.encode(ENCODE_FULL);
+
+    let crc_start = data.len() - 6;
+    for i in (0..data.len()).filter(|i| *i != crc_start) {
+        let err = ListOpLog::new().decode_and_add_opts(&data[..i], DecodeOptions {
+            ignore_crc: true,
+            ..Default::default()
+        }).unwrap_err();
+        let pos = err.pos.unwrap_or_else(|| panic!("{i}: {err:?} has no position"));
+        assert!(pos <= i, "{i}: {err:?}");
+    }
+}
+
+/// A patch containing changes from 3 agents, where the file's agent list is padded out to 10,000
+/// agents.
+fn patch_with_unused_agents() -> (ListOpLog, ListOpLog, Vec<u8>) {
+    let mut base = ListOpLog::new();
+    let seph = base.get_or_create_agent_id("seph");
+    base.add_insert(seph, 0, "hi");
+
+    let mut oplog = base.clone();
+    for name in ["a", "b", "c"] {
+        let agent = oplog.get_or_create_agent_id(name);
+        oplog.add_insert(agent, 0, name);
+    }
+
+    let patch = oplog.encode_from(ENCODE_PATCH, base.local_version_ref());
+    // The patch names seph (in its start version) and a, b and c.
+    let names = ["a", "b", "c", "seph"].into_iter().map(String::from)
+        .chain((4..10_000).map(|i| format!("unused{i}")));
+    (base, oplog, replace_agent_names(&patch, names))
+}
+
+#[test]
+fn unused_agents_are_not_created() {
+    let (mut base, oplog, patch) = patch_with_unused_agents();
+
+    base.decode_and_add(&patch).unwrap();
+    assert_eq!(base.cg.agent_assignment.client_data.len(), 4);
+    assert_eq!(base.checkout_tip().content(), oplog.checkout_tip().content());
+    base.dbg_check(true);
+
+    // Loading the patch into an empty document fails (it doesn't contain the base version), and
+    // shouldn't leave any agents behind.
+    let mut empty = ListOpLog::new();
+    assert_eq!(empty.decode_and_add(&patch).unwrap_err(), ParseErrorKind::BaseVersionUnknown);
+    assert_eq!(empty, ListOpLog::new());
+}
+
+#[test]
+fn agent_limits_are_enforced() {
+    let (mut base, _, patch) = patch_with_unused_agents();
+
+    let opts = DecodeOptions { max_agents: 9_999, ..Default::default() };
+    assert_eq!(base.decode_and_add_opts(&patch, opts).unwrap_err(), ParseErrorKind::TooManyAgents);
+
+    let opts = DecodeOptions { max_agent_name_bytes: 1000, ..Default::default() };
+    assert_eq!(base.decode_and_add_opts(&patch, opts).unwrap_err(), ParseErrorKind::TooManyAgents);
+
+    // The size limit is checked before any names are read, so a large garbage agent names chunk
+    // is rejected with the same error.
+    let garbage = vec![0xff; 1 << 20];
+    let data = replace_inner_chunk(&patch, ListChunkType::FileInfo, ListChunkType::AgentNames, &garbage);
+    let opts = DecodeOptions { max_agent_name_bytes: 1 << 16, ..Default::default() };
+    assert_eq!(base.decode_and_add_opts(&data, opts).unwrap_err(), ParseErrorKind::TooManyAgents);
+
+    // Nothing was added by the failed loads.
+    assert_eq!(base.cg.agent_assignment.client_data.len(), 1);
+    base.decode_and_add(&patch).unwrap();
+}
+
+fn encode_opts_with(compress_content: bool, dedup_content: bool) -> EncodeOptions<'static> {
+    EncodeOptions {
+        store_deleted_content: true,
+        compress_content,
+        dedup_content,
+        redact_content_for: &[],
+        keep_deleted_content_after: None,
+        chunk_checksums: false,
+        canonical: false,
+        mode: EncodeMode::Full,
+        ..ENCODE_FULL
+    }
+}
+
+/// A document where the same 10kb block is pasted 100 times (with some typing in between).
+fn repeated_paste_oplog() -> ListOpLog {
+    let block = (0..3000).map(|i| format!("{i} ")).collect::<String>()[..10_000].to_string();
+
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mut branch = oplog.checkout_tip();
+    for i in 0..100 {
+        branch.insert(&mut oplog, seph, branch.len(), &block);
+        branch.insert(&mut oplog, seph, branch.len(), &format!("\nSection {i}\n"));
+    }
+    // And delete a couple of copies, so there's repeated deleted content too.
+    branch.delete(&mut oplog, seph, 0..block.len() * 2);
+    oplog
+}
+
+#[test]
+fn dedup_round_trips() {
+    let mut oplogs = vec![simple_doc().oplog, repeated_paste_oplog()];
+    for name in ["benchmark_data/git-makefile.dt", "benchmark_data/node_nodecc.dt"] {
+        oplogs.push(ListOpLog::load_from(&std::fs::read(name).unwrap()).unwrap());
+    }
+
+    for oplog in oplogs.iter() {
+        for compress in [false, true] {
+            let plain = oplog.encode(encode_opts_with(compress, false));
+            let deduped = oplog.encode(encode_opts_with(compress, true));
+            assert!(deduped.len() <= plain.len());
+
+            let decoded = ListOpLog::load_from(&deduped).unwrap();
+            assert_eq!(&decoded, oplog);
+            assert_eq!(decoded.checkout_tip().content(), oplog.checkout_tip().content());
+        }
+    }
+}
+
+#[test]
+fn dedup_shrinks_repeated_pastes() {
+    let oplog = repeated_paste_oplog();
+
+    let content_len = |data: &[u8]| {
+        let mut chunks = BufReader::new(data);
+        chunks.read_magic().unwrap();
+        chunks.next_usize().unwrap();
+        let mut chunks = chunks.chunks();
+        let mut len = 0;
+        while !chunks.0.is_empty() {
+            let (chunk_type, chunk) = chunks.next_chunk().unwrap();
+            if chunk_type == ListChunkType::Patches {
+                let mut patches = chunk.chunks();
+                while !patches.0.is_empty() {
+                    let (inner_type, inner) = patches.next_chunk().unwrap();
+                    if inner_type == ListChunkType::PatchContent { len += inner.len(); }
+                }
+            }
+        }
+        len
+    };
+
+    let plain = oplog.encode(encode_opts_with(false, false));
+    let deduped = oplog.encode(encode_opts_with(false, true));
+    let (plain_len, deduped_len) = (content_len(&plain), content_len(&deduped));
+    assert!(plain_len > 1_000_000);
+    assert!(deduped_len * 5 < plain_len, "{deduped_len} vs {plain_len}");
+}
+
+#[test]
+fn crafted_deduped_content_is_rejected() {
+    // Deduped content where the copy runs are either a literal run (len) or a copy (len, offset).
+    let deduped = |len: usize, literals: &str, runs: &[(usize, Option<usize>)]| {
+        let mut body = Vec::new();
+        encode_tools::push_leb_u32(&mut body, DataType::PlainText as _);
+        encode_tools::push_leb_usize(&mut body, len);
+        let mut content = Vec::new();
+        encode_tools::push_leb_u32(&mut content, DataType::PlainText as _);
+        content.extend_from_slice(literals.as_bytes());
+        encode_tools::push_leb_chunk(&mut body, ListChunkType::Content, &content);
+        for &(run_len, offset) in runs {
+            encode_tools::push_leb_usize(&mut body, mix_bit_usize(run_len, offset.is_some()));
+            if let Some(offset) = offset { encode_tools::push_leb_usize(&mut body, offset); }
+        }
+        body
+    };
+
+    let oplog = repeated_paste_oplog();
+    let data = oplog.encode(EncodeOptions { mode: EncodeMode::Snapshot, ..encode_opts_with(false, true) });
+    let load = |body: &[u8]| {
+        let data = replace_inner_chunk(&data, ListChunkType::Snapshot, ListChunkType::ContentDeduped, body);
+        ListBranch::load_snapshot(&data).map(|(branch, _)| branch.content().to_string())
+    };
+
+    let block = "0123456789abcdef";
+    assert_eq!(load(&deduped(32, block, &[(16, None), (16, Some(0))])).unwrap(), block.repeat(2));
+
+    // A tiny chunk claiming a terabyte of content. This used to try to allocate all of it.
+    assert_eq!(load(&deduped(1 << 40, block, &[(16, None)])).unwrap_err(), ParseErrorKind::InvalidLength);
+
+    // Each copy doubles the content, so a few bytes of copies could otherwise expand to anything.
+    let mut runs = vec![(16, None)];
+    runs.extend((0..20).map(|i| (16 << i, Some(0))));
+    assert_eq!(load(&deduped(16 << 20, block, &runs)).unwrap_err(), ParseErrorKind::InvalidLength);
+
+    // Copies and literals reaching past the end of usize.
+    assert_eq!(load(&deduped(32, block, &[(16, None), (16, Some(usize::MAX - 4))])).unwrap_err(), ParseErrorKind::InvalidLength);
+    assert_eq!(load(&deduped(32, block, &[(16, None), (16, Some(8))])).unwrap_err(), ParseErrorKind::InvalidContent);
+    assert_eq!(load(&deduped(32, block, &[(16, None), (16, None)])).unwrap_err(), ParseErrorKind::InvalidLength);
+}
+
+#[test]
+fn merging_own_data_is_a_no_op() {
+    let oplog = repeated_paste_oplog();
+    let mut merged = oplog.clone();
+
+    for opts in [ENCODE_FULL, ENCODE_PATCH] {
+        let data = oplog.encode(opts);
+        assert_eq!(merged.decode_and_add(&data).unwrap(), oplog.local_version());
+        assert_eq!(merged, oplog);
+        assert_eq!(merged.len(), oplog.len());
+    }
+
+    // Patches from a version in the middle of the history, applied twice.
+    let v = [oplog.len() / 2];
+    let data = oplog.encode_from(ENCODE_PATCH, &v);
+    merged.decode_and_add(&data).unwrap();
+    merged.decode_and_add(&data).unwrap();
+    assert_eq!(merged, oplog);
+    merged.dbg_check(true);
+}
+
+#[test]
+fn partial_overlap_only_adds_new_operations() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    oplog.add_insert(seph, 0, "hi there");
+    let v1 = oplog.local_version();
+    oplog.add_insert(mike, 2, " you");
+    oplog.add_delete_without_content(seph, 0..2);
+
+    // The remote peer has everything up to here.
+    let mut remote = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+    let remote_len = remote.len();
+
+    oplog.add_insert(seph, 0, "yo");
+    oplog.add_insert(mike, 0, "!!");
+
+    // The remote is sent everything since v1, which mostly overlaps with what it has already.
+    let data = oplog.encode_from(ENCODE_PATCH, v1.as_ref());
+    remote.decode_and_add(&data).unwrap();
+    assert_eq!(remote.len(), remote_len + 4);
+    assert_eq!(remote, oplog);
+    remote.dbg_check(true);
+}
+
+#[test]
+fn diverged_peers_sync_with_merge_data() {
+    // Two peers share some history, then both make interleaved edits with several agents.
+    let mut a = ListOpLog::new();
+    let seph = a.get_or_create_agent_id("seph");
+    let mike = a.get_or_create_agent_id("mike");
+    a.add_insert(seph, 0, "hello");
+    a.add_insert(mike, 5, " world");
+    let shared = a.local_version();
+
+    let mut b = ListOpLog::load_from(&a.encode(ENCODE_FULL)).unwrap();
+    let kaarina = b.get_or_create_agent_id("kaarina");
+    let b_mike = b.get_agent_id("mike").unwrap();
+    b.add_insert(kaarina, 0, "oh ");
+    b.add_delete_without_content(b_mike, 3..4);
+    b.add_insert(kaarina, 3, "H");
+
+    a.add_insert(seph, 11, "!");
+    let jeremy = a.get_or_create_agent_id("jeremy");
+    a.add_insert(jeremy, 0, "> ");
+    a.add_delete_without_content(seph, 2..3);
+
+    let a_new = a.len() - shared[0] - 1;
+    let b_new = b.len() - shared[0] - 1;
+
+    // Full files overlap with the shared history. Patches from the shared version don't.
+    for full in [true, false] {
+        let encode = |oplog: &ListOpLog| if full {
+            oplog.encode(ENCODE_FULL)
+        } else {
+            oplog.encode_from(ENCODE_FULL, shared.as_ref())
+        };
+
+        let mut a2 = a.clone();
+        let mut b2 = b.clone();
+        let a_data = encode(&a);
+        let b_data = encode(&b);
+
+        let stats = a2.merge_data(&b_data).unwrap();
+        assert_eq!((stats.new_operations, stats.new_agents), (b_new, 1));
+        let stats = b2.merge_data(&a_data).unwrap();
+        assert_eq!((stats.new_operations, stats.new_agents), (a_new, 1));
+
+        assert_eq!(a2, b2);
+        assert_eq!(a2.checkout_tip().content(), b2.checkout_tip().content());
+        assert_eq!(a2.checkout_tip().content(), "> oh Hello world!");
+        a2.dbg_check(true);
+        b2.dbg_check(true);
+
+        // And syncing again is a no-op.
+        let stats = a2.merge_data(&b_data).unwrap();
+        assert_eq!((stats.new_operations, stats.new_agents), (0, 0));
+        assert_eq!(a2, b2);
+    }
+}
+
+#[test]
+fn decode_and_checkout_matches_load_and_checkout() {
+    let mut oplogs = vec![simple_doc().oplog, ListOpLog::new()];
+    for name in ["benchmark_data/git-makefile.dt", "benchmark_data/node_nodecc.dt"] {
+        oplogs.push(ListOpLog::load_from(&std::fs::read(name).unwrap()).unwrap());
+    }
+
+    for oplog in oplogs.iter() {
+        let expected = oplog.checkout_tip();
+
+        for (compress, store_end) in [(false, false), (true, false), (false, true), (true, true)] {
+            let data = oplog.encode(EncodeOptions {
+                compress_content: compress,
+                experimentally_store_end_branch_content: store_end,
+                ..ENCODE_FULL
+            });
+
+            let (decoded, content, version) = ListOpLog::decode_and_checkout(&data).unwrap();
+            assert_eq!(&decoded, oplog);
+            assert_eq!(content, expected.content().to_string());
+            assert_eq!(version, expected.local_version());
+            assert_eq!(ListOpLog::decode_and_checkout_trusted(&data).unwrap(), (decoded.clone(), content, version));
+            assert_eq!(ListOpLog::load_from(&data).unwrap(), decoded);
+
+            let doc = ListCRDT::load_from(&data).unwrap();
+            assert_eq!(doc.branch, expected);
+        }
+    }
+}
+
+#[test]
+fn end_content_is_only_used_at_the_oplog_version() {
+    let doc = simple_doc();
+    let data = doc.oplog.encode(EncodeOptions {
+        experimentally_store_end_branch_content: true,
+        ..ENCODE_FULL
+    });
+
+    // Merging into a document with other changes still works, and the file's content is ignored.
+    let mut oplog = ListOpLog::new();
+    let mike = oplog.get_or_create_agent_id("mike");
+    oplog.add_insert(mike, 0, "yo ");
+    oplog.decode_and_add(&data).unwrap();
+    assert_eq!(oplog.len(), doc.oplog.len() + 3);
+    assert_ne!(oplog.checkout_tip().content(), doc.branch.content());
+}
+
+#[test]
+fn end_content_is_only_used_when_the_crc_covers_it() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    oplog.add_insert(seph, 0, "hello world");
+    let mut data = oplog.encode(EncodeOptions {
+        experimentally_store_end_branch_content: true,
+        ..ENCODE_FULL
+    });
+
+    // The end content comes before the inserted content. Change it, then strip off the CRC chunk
+    // (the chunk type, its length and a 4 byte checksum) which would catch the change.
+    let pos = data.windows(11).position(|w| w == b"hello world").unwrap();
+    data[pos] = b'j';
+    assert!(matches!(ListOpLog::decode_and_checkout(&data).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+    data.truncate(data.len() - 6);
+
+    let (decoded, content, _) = ListOpLog::decode_and_checkout_trusted(&data).unwrap();
+    assert_eq!(decoded, oplog);
+    assert_eq!(content, "hello world");
+    assert_eq!(decode_document(&data).unwrap(), "hello world");
+}
+
+#[test]
+fn tampered_end_content_is_ignored() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    oplog.add_insert(seph, 0, "hello world");
+    let mut data = oplog.encode(EncodeOptions {
+        experimentally_store_end_branch_content: true,
+        compress_content: false,
+        ..ENCODE_FULL
+    });
+
+    // Change the end content, and rewrite the file's CRC so the file still looks valid.
+    let pos = data.windows(11).position(|w| w == b"hello world").unwrap();
+    data[pos] = b'j';
+    let crc_pos = data.len() - 4;
+    let crc = calc_checksum(&data[..crc_pos - 2]);
+    data[crc_pos..].copy_from_slice(&crc.to_le_bytes());
+
+    let expected = ListOpLog::load_from(&data).unwrap().checkout_tip();
+    assert_eq!(expected.content(), "hello world");
+    assert_eq!(ListCRDT::load_from(&data).unwrap().branch, expected);
+    assert_eq!(ListOpLog::decode_and_checkout(&data).unwrap().1, "hello world");
+    assert_eq!(decode_document(&data).unwrap(), "hello world");
+
+    // Only the trusted API reads the stored content.
+    assert_eq!(ListOpLog::decode_and_checkout_trusted(&data).unwrap().1, "jello world");
+}
+
+/// A document with linear history, including backwards typing and multibyte characters.
+fn linear_oplog() -> ListOpLog {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let mut branch = oplog.checkout_tip();
+    branch.insert(&mut oplog, seph, 0, "hello world");
+    for c in ["c", "b", "a"] {
+        branch.insert(&mut oplog, mike, 0, c);
+    }
+    branch.delete(&mut oplog, seph, 3..6);
+    branch.insert(&mut oplog, mike, 2, "ツ€");
+
+    let mut x: usize = 12345;
+    for i in 0..1000 {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        let pos = (x >> 16) % (branch.len() + 1);
+        if i % 3 == 2 && pos < branch.len() {
+            branch.delete(&mut oplog, seph, pos..(pos + 3).min(branch.len()));
+        } else {
+            branch.insert(&mut oplog, seph, pos, &format!("{i} "));
+        }
+    }
+    oplog
+}
+
+#[test]
+fn decode_document_matches_checkout() {
+    let mut concurrent = simple_doc().oplog;
+    let mike = concurrent.get_or_create_agent_id("mike");
+    concurrent.add_insert_at(mike, &[], 0, "concurrent ");
+
+    let linear = [ListOpLog::new(), simple_doc().oplog, linear_oplog()];
+    let mut other = vec![concurrent];
+    for name in ["benchmark_data/git-makefile.dt", "benchmark_data/node_nodecc.dt"] {
+        other.push(ListOpLog::load_from(&std::fs::read(name).unwrap()).unwrap());
+    }
+
+    for (oplog, is_linear) in linear.iter().map(|o| (o, true)).chain(other.iter().map(|o| (o, false))) {
+        let expected = oplog.checkout_tip().content().to_string();
+
+        for (compress, dedup) in [(false, false), (true, false), (true, true)] {
+            let data = oplog.encode(encode_opts_with(compress, dedup));
+            assert_eq!(decode_document(&data).unwrap(), expected);
+            // Linear files are read without building an oplog.
+            assert_eq!(decode_oplog::decode_linear_document(&data, 0).unwrap().is_some(), is_linear);
+
+            let data = oplog.encode(EncodeOptions {
+                experimentally_store_end_branch_content: true,
+                ..encode_opts_with(compress, dedup)
+            });
+            assert_eq!(decode_document(&data).unwrap(), expected);
+
+            let data = oplog.encode(EncodeOptions { mode: EncodeMode::Snapshot, ..encode_opts_with(compress, dedup) });
+            assert_eq!(decode_document(&data).unwrap(), expected);
+        }
+    }
+}
+
+#[test]
+fn decode_document_falls_back() {
+    let oplog = linear_oplog();
+    let seph = oplog.get_agent_id("seph").unwrap();
+
+    // Unknown content is filled in by checkout.
+    let data = oplog.encode(EncodeOptions { redact_content_for: &[seph], ..ENCODE_FULL });
+    assert!(decode_oplog::decode_linear_document(&data, 0).unwrap().is_none());
+    assert_eq!(decode_document(&data).unwrap(), ListOpLog::load_from(&data).unwrap().checkout_tip().content().to_string());
+
+    // Patches which don't start from ROOT.
+    let half = oplog.len() / 2;
+    let data = oplog.encode_from(ENCODE_FULL, &[half - 1]);
+    assert!(decode_oplog::decode_linear_document(&data, 0).unwrap().is_none());
+
+    // Files with appended segments.
+    let mut data = ListOpLog::load_from(&oplog.encode_between(ENCODE_FULL, &[], &[half - 1])).unwrap().encode(ENCODE_FULL);
+    let patch = oplog.encode_from(ENCODE_PATCH, &[half - 1]);
+    data.extend_from_slice(&SEGMENT_MAGIC_BYTES);
+    encode_tools::push_leb_usize(&mut data, patch.len());
+    data.extend_from_slice(&patch);
+    assert_eq!(decode_document(&data).unwrap(), oplog.checkout_tip().content().to_string());
+
+    // Damaged files are still rejected.
+    let mut data = oplog.encode(ENCODE_FULL);
+    let mid = data.len() / 2;
+    data[mid] ^= 1;
+    assert!(matches!(decode_document(&data).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+}
+
+#[test]
+fn streaming_decoder_matches_load_from() {
+    let mut oplog = simple_doc().oplog;
+    let mike = oplog.get_or_create_agent_id("mike");
+    let v = oplog.add_insert_at(mike, &[], 0, "concurrent ");
+    oplog.add_delete_at(mike, &[v], 0..2);
+
+    for data in [
+        oplog.encode(ENCODE_FULL),
+        oplog.encode(encode_opts_with(true, false)),
+        oplog.encode(EncodeOptions { experimentally_store_end_branch_content: true, ..ENCODE_FULL }),
+        oplog.encode(encode_opts_with(false, true)),
+    ] {
+        let expected = ListOpLog::load_from(&data).unwrap();
+
+        for i in 0..=data.len() {
+            let mut decoder = StreamingDecoder::new();
+            decoder.push(&data[..i]).unwrap();
+            decoder.push(&data[i..]).unwrap();
+            assert_eq!(decoder.finish().unwrap(), expected);
+        }
+
+        // And one byte at a time.
+        let mut decoder = StreamingDecoder::new();
+        for b in data.iter() {
+            decoder.push(std::slice::from_ref(b)).unwrap();
+        }
+        assert_eq!(decoder.finish().unwrap(), expected);
+    }
+}
+
+#[test]
+fn decode_driver_matches_load_from() {
+    let mut oplog = simple_doc().oplog;
+    let mike = oplog.get_or_create_agent_id("mike");
+    let v = oplog.add_insert_at(mike, &[], 0, "concurrent ");
+    oplog.add_delete_at(mike, &[v], 0..2);
+    oplog.add_insert(mike, 3, "ツ and then some more text");
+
+    for data in [
+        oplog.encode(ENCODE_FULL),
+        oplog.encode(encode_opts_with(true, false)),
+        oplog.encode(EncodeOptions { experimentally_store_end_branch_content: true, ..ENCODE_FULL }),
+        oplog.encode(EncodeOptions { store_inserted_content: false, store_deleted_content: false, ..ENCODE_FULL }),
+        oplog.encode(EncodeOptions { chunk_checksums: true, ..ENCODE_FULL }),
+        oplog.encode(encode_opts_with(false, true)),
+    ] {
+        let expected = ListOpLog::load_from(&data).unwrap();
+
+        for budget in [1, 2, 3, 10, usize::MAX] {
+            for piece_len in [1, 7, data.len()] {
+                let mut driver = DecodeDriver::new(DecodeOptions::default());
+                let mut working_calls = 0;
+                let mut done = false;
+
+                for piece in data.chunks(piece_len) {
+                    driver.push(piece).unwrap();
+                    if !done {
+                        let status = driver.work(budget).unwrap();
+                        if status != DecodeStatus::NeedsData { working_calls += 1; }
+                        done = status == DecodeStatus::Done;
+                    }
+                }
+                while !done {
+                    let status = driver.work(budget).unwrap();
+                    assert_ne!(status, DecodeStatus::NeedsData);
+                    working_calls += 1;
+                    done = status == DecodeStatus::Done;
+                }
+
+                // Every operation is counted twice.
+                assert!(working_calls >= (2 * expected.len()).div_ceil(budget));
+                assert_eq!(driver.work(budget).unwrap(), DecodeStatus::Done);
+                assert_eq!(driver.finish().unwrap(), expected);
+            }
+        }
+
+        // Finish decodes anything work didn't get to.
+        let mut driver = DecodeDriver::new(DecodeOptions::default());
+        driver.push(&data).unwrap();
+        driver.work(5).unwrap();
+        assert_eq!(driver.finish().unwrap(), expected);
+    }
+}
+
+#[test]
+fn decode_driver_rejects_bad_data() {
+    let data = simple_doc().oplog.encode(ENCODE_FULL);
+
+    // Nothing can be decoded until the operations arrive.
+    let mut driver = DecodeDriver::new(DecodeOptions::default());
+    driver.push(&data[..data.len() / 2]).unwrap();
+    assert_eq!(driver.work(10).unwrap(), DecodeStatus::NeedsData);
+    assert_eq!(driver.finish().unwrap_err(), ParseErrorKind::UnexpectedEOF);
+
+    // Stopping right before the (optional) CRC chunk is fine.
+    for i in (0..data.len()).filter(|i| *i != data.len() - 6) {
+        let mut driver = DecodeDriver::new(DecodeOptions::default());
+        driver.push(&data[..i]).unwrap();
+        driver.work(usize::MAX).unwrap();
+        assert_eq!(driver.finish().unwrap_err(), ParseErrorKind::UnexpectedEOF, "{i}");
+    }
+
+    // The operations are decoded before the checksum arrives.
+    let mut corrupt = data.clone();
+    let last_byte = corrupt.last_mut().unwrap();
+    *last_byte = !*last_byte;
+    let mut driver = DecodeDriver::new(DecodeOptions::default());
+    driver.push(&corrupt[..corrupt.len() - 6]).unwrap();
+    assert_eq!(driver.work(usize::MAX).unwrap(), DecodeStatus::Done);
+    assert!(matches!(driver.push(&corrupt[corrupt.len() - 6..]).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+}
+
+/// Reads the wrapped data a few bytes at a time, then fails with an IO error.
+struct DribbleReader<'a> {
+    data: &'a [u8],
+    fail_at_end: bool,
+}
+
+impl<'a> std::io::Read for DribbleReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.data.is_empty() && self.fail_at_end {
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "oh no"));
+        }
+        let n = buf.len().min(self.data.len()).min(3);
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Ok(n)
+    }
+}
+
+#[test]
+fn load_from_reader_matches_load_from() {
+    let mut oplog = simple_doc().oplog;
+    let mike = oplog.get_or_create_agent_id("mike");
+    let v = oplog.add_insert_at(mike, &[], 0, "concurrent ");
+    oplog.add_delete_at(mike, &[v], 0..2);
+
+    for data in [
+        oplog.encode(ENCODE_FULL),
+        oplog.encode(encode_opts_with(true, false)),
+        oplog.encode(EncodeOptions { experimentally_store_end_branch_content: true, ..ENCODE_FULL }),
+        oplog.encode(EncodeOptions { dedup_content: true, ..ENCODE_FULL }),
+        oplog.encode(EncodeOptions { chunk_checksums: true, ..ENCODE_FULL }),
+        oplog.encode(encode_opts_with(false, true)),
+    ] {
+        let expected = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(ListOpLog::load_from_reader(&data[..]).unwrap(), expected);
+        let reader = DribbleReader { data: &data, fail_at_end: false };
+        assert_eq!(ListOpLog::load_from_reader(reader).unwrap(), expected);
+    }
+}
+
+#[test]
+fn load_from_reader_errors() {
+    let data = simple_doc().oplog.encode(ENCODE_FULL);
+    let parse_error = |e: std::io::Error| *e.get_ref().unwrap().downcast_ref::<ParseError>().unwrap();
+
+    // Stopping right before the (optional) CRC chunk is fine.
+    for i in (0..data.len()).filter(|i| *i != data.len() - 6) {
+        let err = ListOpLog::load_from_reader(&data[..i]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "{i}");
+        assert_eq!(parse_error(err), ParseErrorKind::UnexpectedEOF);
+
+        // Other IO errors are passed through.
+        let err = ListOpLog::load_from_reader(DribbleReader { data: &data[..i], fail_at_end: true }).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset, "{i}");
+    }
+
+    let err = ListOpLog::load_from_reader(&b"NOTDTYPSxxxx"[..]).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(parse_error(err), ParseErrorKind::InvalidMagic);
+
+    let mut corrupt = data.clone();
+    let last_byte = corrupt.last_mut().unwrap();
+    *last_byte = !*last_byte;
+    assert!(matches!(parse_error(ListOpLog::load_from_reader(&corrupt[..]).unwrap_err()).kind, ParseErrorKind::ChecksumFailed(_)));
+
+    let opts = DecodeOptions { ignore_crc: true, ..Default::default() };
+    assert_eq!(ListOpLog::load_from_reader_opts(&corrupt[..], opts).unwrap(), simple_doc().oplog);
+}
+
+#[test]
+fn streaming_decoder_rejects_bad_data() {
+    let data = simple_doc().oplog.encode(ENCODE_FULL);
+
+    // Truncated data is only an error once we know no more is coming.
+    for i in (0..data.len()).filter(|i| *i != data.len() - 6) {
+        let mut decoder = StreamingDecoder::new();
+        decoder.push(&data[..i]).unwrap();
+        assert_eq!(decoder.finish().unwrap_err(), ParseErrorKind::UnexpectedEOF, "{i}");
+    }
+
+    // Bad magic bytes are noticed straight away.
+    let mut decoder = StreamingDecoder::new();
+    assert_eq!(decoder.push(b"NOTDTYPSxxxx").unwrap_err(), ParseErrorKind::InvalidMagic);
+
+    // The checksum is checked as soon as the CRC chunk arrives.
+    let mut corrupt = data.clone();
+    let last_byte = corrupt.last_mut().unwrap();
+    *last_byte = !*last_byte;
+    let mut decoder = StreamingDecoder::new();
+    assert!(matches!(decoder.push(&corrupt).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+
+    let mut decoder = StreamingDecoder::with_opts(DecodeOptions {
+        ignore_crc: true,
+        ..Default::default()
+    });
+    decoder.push(&corrupt).unwrap();
+    assert_eq!(decoder.finish().unwrap(), simple_doc().oplog);
+
+    // Chunks in the wrong order are rejected without waiting for the rest of the file.
+    let header_len = MAGIC_BYTES.len() + 1; // Magic bytes and protocol version.
+    let mut chunks = BufReader::new(&data[header_len..]).chunks();
+    assert_eq!(chunks.next_chunk().unwrap().0, ListChunkType::FileInfo);
+    let mut reordered = data[..header_len].to_vec();
+    reordered.extend_from_slice(chunks.0.buf);
+    let mut decoder = StreamingDecoder::new();
+    assert_eq!(decoder.push(&reordered).unwrap_err(), ParseErrorKind::MissingChunk(ListChunkType::FileInfo as u32));
+}
+
+#[test]
+fn streaming_decoder_drops_decoded_data() {
+    let oplog = ListOpLog::load_from(&std::fs::read("benchmark_data/git-makefile.dt").unwrap()).unwrap();
+    let data = oplog.encode(ENCODE_FULL);
+
+    // Everything up to the end of the Patches chunk is buffered. Then the operations are decoded
+    // and the buffer is dropped.
+    let top_level: Vec<_> = iter_chunks(&data).map(|c| c.unwrap()).filter(|c| c.depth == 0).collect();
+    let patches = top_level.iter().position(|c| c.chunk_type == ListChunkType::Patches).unwrap();
+    let patches_end = top_level[patches + 1].offset;
+    let mut decoder = StreamingDecoder::new();
+    decoder.push(&data[..patches_end - 1]).unwrap();
+    assert_eq!(decoder.buffered_len(), patches_end - 1);
+    decoder.push(&data[patches_end - 1..patches_end]).unwrap();
+    assert_eq!(decoder.buffered_len(), 0);
+
+    // The CRC chunk is only held until its complete.
+    decoder.push(&data[patches_end..data.len() - 1]).unwrap();
+    assert_eq!(decoder.buffered_len(), data.len() - 1 - patches_end);
+    decoder.push(&data[data.len() - 1..]).unwrap();
+    assert_eq!(decoder.buffered_len(), 0);
+    assert_eq!(decoder.finish().unwrap(), oplog);
+}
+
+#[test]
+#[cfg(feature = "lz4")]
+fn compressed_content_is_smaller() {
+    let has_compressed_chunk = |data: &[u8]| {
+        let mut reader = BufReader::new(data);
+        reader.read_magic().unwrap();
+        reader.next_usize().unwrap();
+        reader.chunks().read_chunk_if_eq(ListChunkType::CompressedFieldsLZ4).unwrap().is_some()
+    };
+
+    let oplog = ListOpLog::load_from(&std::fs::read("benchmark_data/node_nodecc.dt").unwrap()).unwrap();
+    let plain = oplog.encode(encode_opts_with(false, false));
+    let compressed = oplog.encode(encode_opts_with(true, false));
+
+    // Uncompressed files don't use the compressed chunk at all, so older decoders can read them.
+    assert!(!has_compressed_chunk(&plain));
+    assert!(has_compressed_chunk(&compressed));
+    assert!(compressed.len() < plain.len(), "{} vs {}", compressed.len(), plain.len());
+
+    assert_eq!(ListOpLog::load_from(&plain).unwrap(), oplog);
+    assert_eq!(ListOpLog::load_from(&compressed).unwrap(), oplog);
+}
+
+#[test]
+#[cfg(not(feature = "lz4"))]
+fn compressed_files_need_lz4() {
+    // Builds without LZ4 support should refuse compressed files rather than misread them.
+    let bytes = std::fs::read("benchmark_data/node_nodecc.dt").unwrap();
+    assert_eq!(ListOpLog::load_from(&bytes).unwrap_err(), ParseErrorKind::LZ4DecoderNeeded);
+
+    // Asking for compression is ignored, so the files we write can still be read.
+    let oplog = simple_doc().oplog;
+    let data = oplog.encode(encode_opts_with(true, false));
+    assert_eq!(ListOpLog::load_from(&data).unwrap(), oplog);
+}
+
+#[test]
+fn out_of_order_patches_are_merged_once_possible() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let a = oplog.add_insert(seph, 0, "hi there");
+    let b = oplog.add_insert(mike, 2, " you");
+    let c = oplog.add_delete_without_content(seph, 0..3);
+
+    let to_a = oplog.encode_between(ENCODE_PATCH, &[], &[a]);
+    let a_to_b = oplog.encode_between(ENCODE_PATCH, &[a], &[b]);
+    let b_to_c = oplog.encode_between(ENCODE_PATCH, &[b], &[c]);
+
+    let mut in_order = ListOpLog::new();
+    for data in [&to_a, &a_to_b, &b_to_c] {
+        in_order.merge_data_or_defer(data).unwrap().unwrap();
+    }
+    assert_eq!(in_order, oplog);
+
+    // merge_data still fails on data we can't merge yet.
+    let mut dest = ListOpLog::new();
+    dest.merge_data(&to_a).unwrap();
+    assert_eq!(dest.clone().merge_data(&b_to_c).unwrap_err(), ParseErrorKind::BaseVersionUnknown);
+
+    assert_eq!(dest.merge_data_or_defer(&b_to_c).unwrap(), None);
+    assert_eq!(dest.missing_ranges(), vec![RemoteVersionSpanOwned("mike".into(), (0..4).into())]);
+
+    // Merging the missing patch merges the waiting one too.
+    dest.merge_data_or_defer(&a_to_b).unwrap().unwrap();
+    assert!(dest.missing_ranges().is_empty());
+    assert_eq!(dest, in_order);
+    assert_eq!(dest.checkout_tip().content(), "you there");
+    dest.dbg_check(true);
+}
+
+#[test]
+fn merge_data_many_reorders_patches() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let a = oplog.add_insert(seph, 0, "hi there");
+    let b = oplog.add_insert(mike, 2, " you");
+    let c = oplog.add_delete_without_content(seph, 0..3);
+
+    let to_a = oplog.encode_between(ENCODE_PATCH, &[], &[a]);
+    let a_to_b = oplog.encode_between(ENCODE_PATCH, &[a], &[b]);
+    let b_to_c = oplog.encode_between(ENCODE_PATCH, &[b], &[c]);
+
+    let mut dest = ListOpLog::new();
+    let stats = dest.merge_data_many([&b_to_c[..], &a_to_b, &to_a]).unwrap();
+    assert_eq!(dest, oplog);
+    assert_eq!(stats.new_operations, oplog.len());
+    assert_eq!(stats.new_agents, 2);
+    assert_eq!(stats.version.as_ref(), &[c]);
+    assert!(dest.missing_ranges().is_empty());
+
+    // Merging the same patches again does nothing.
+    let stats = dest.merge_data_many([&to_a[..], &b_to_c, &a_to_b]).unwrap();
+    assert_eq!(stats.new_operations, 0);
+    assert!(stats.new_range.is_empty());
+    assert_eq!(dest, oplog);
+
+    // Patches which can never be merged are reported after merging the rest.
+    let mut dest = ListOpLog::new();
+    let err = dest.merge_data_many([&b_to_c[..], &to_a]).unwrap_err();
+    assert_eq!(err, ParseErrorKind::DataMissing);
+    assert_eq!(dest.checkout_tip().content(), "hi there");
+    assert!(dest.missing_ranges().is_empty());
+}
+
+#[test]
+fn waiting_patches_which_fail_are_dropped() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let a = oplog.add_insert(seph, 0, "abc");
+    let b = oplog.add_insert(seph, 3, "def");
+
+    let to_a = oplog.encode_between(ENCODE_PATCH, &[], &[a]);
+    let a_to_b = oplog.encode_between(ENCODE_PATCH, &[a], &[b]);
+
+    let mut dest = ListOpLog::new();
+    dest.set_limits(crate::list::limits::DocLimits { max_operations: 4, ..Default::default() });
+    assert_eq!(dest.merge_data_or_defer(&a_to_b).unwrap(), None);
+    assert_eq!(dest.missing_ranges(), vec![RemoteVersionSpanOwned("seph".into(), (0..3).into())]);
+
+    // The waiting patch would go over the limit, so only the first patch is merged.
+    dest.merge_data_or_defer(&to_a).unwrap().unwrap();
+    assert!(dest.missing_ranges().is_empty());
+    assert_eq!(dest.checkout_tip().content(), "abc");
+
+    // Data which is corrupt is rejected straight away.
+    let mut bytes = a_to_b.clone();
+    let last_byte = bytes.last_mut().unwrap();
+    *last_byte = !*last_byte;
+    assert!(matches!(ListOpLog::new().merge_data_or_defer(&bytes).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+    assert_eq!(ListOpLog::new().merge_data_or_defer(b"garbage!").unwrap_err(), ParseErrorKind::InvalidMagic);
+}
+
+#[test]
+fn waiting_patches_are_capped() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let a = oplog.add_insert(seph, 0, "abc");
+    let b = oplog.add_insert(seph, 3, "def");
+    let c = oplog.add_insert_at(mike, &[a], 0, "xyz");
+
+    let to_a = oplog.encode_between(ENCODE_PATCH, &[], &[a]);
+    let a_to_b = oplog.encode_between(ENCODE_PATCH, &[a], &[b]);
+    let a_to_c = oplog.encode_between(ENCODE_PATCH, &[a], &[c]);
+    let full = ParseErrorKind::LimitExceeded(crate::list::limits::LimitExceeded::PendingData);
+
+    let mut dest = ListOpLog::new();
+    dest.set_limits(crate::list::limits::DocLimits { max_pending_patches: 1, ..Default::default() });
+    assert_eq!(dest.merge_data_or_defer(&a_to_b).unwrap(), None);
+    assert_eq!(dest.merge_data_or_defer(&a_to_c).unwrap_err(), full);
+
+    // Data which can be merged straight away is still accepted, and makes room.
+    dest.merge_data_or_defer(&to_a).unwrap().unwrap();
+    assert_eq!(dest.merge_data_or_defer(&a_to_c).unwrap().unwrap().new_operations, 3);
+
+    let mut dest = ListOpLog::new();
+    dest.set_limits(crate::list::limits::DocLimits { max_pending_bytes: a_to_b.len(), ..Default::default() });
+    assert_eq!(dest.merge_data_or_defer(&a_to_b).unwrap(), None);
+    assert_eq!(dest.merge_data_or_defer(&a_to_c).unwrap_err(), full);
+    assert_eq!(dest.missing_ranges(), vec![RemoteVersionSpanOwned("seph".into(), (0..3).into())]);
+}
+
+#[test]
+fn chunk_sizes_add_up() {
+    let doc = simple_doc();
+    let bytes = doc.oplog.encode(ENCODE_FULL);
+    let chunks = chunk_sizes(&bytes).unwrap();
+
+    let names: Vec<&str> = chunks.iter().filter(|c| c.depth == 0).map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["FileInfo", "StartBranch", "Patches", "Crc"]);
+    assert!(chunks.iter().any(|c| c.depth == 1 && c.name == "OpParents"));
+
+    // Each nested chunk fits inside its parent.
+    let patches_idx = chunks.iter().position(|c| c.name == "Patches").unwrap();
+    let nested: usize = chunks[patches_idx + 1..].iter()
+        .take_while(|c| c.depth == 1)
+        .map(|c| c.len)
+        .sum();
+    assert!(nested < chunks[patches_idx].len);
+
+    assert_eq!(chunk_sizes(b"garbage!").unwrap_err(), ParseErrorKind::InvalidMagic);
+}
+
+#[test]
+fn iter_chunks_lists_simple_doc() {
+    let data = simple_doc().oplog.encode(ENCODE_FULL);
+    let chunks: Vec<ChunkInfo> = iter_chunks(&data).collect::<Result<_, _>>().unwrap();
+
+    use ListChunkType::*;
+    let types: Vec<(ListChunkType, usize)> = chunks.iter().map(|c| (c.chunk_type, c.depth)).collect();
+    assert_eq!(types, [
+        (FileInfo, 0), (AgentNames, 1), (StartBranch, 0),
+        (Patches, 0), (PatchContent, 1), (OpVersions, 1), (OpTypeAndPosition, 1), (OpParents, 1),
+        (Crc, 0),
+    ]);
+
+    // Offsets point at each chunk's header, and the chunks fill the file after the header.
+    for c in &chunks {
+        assert_eq!(data[c.offset] as u32, c.chunk_type as u32);
+    }
+    let top: Vec<&ChunkInfo> = chunks.iter().filter(|c| c.depth == 0).collect();
+    for pair in top.windows(2) {
+        assert_eq!(pair[0].offset + 2 + pair[0].len, pair[1].offset);
+    }
+    assert_eq!(top.last().unwrap().offset + 2 + top.last().unwrap().len, data.len());
+
+    // Truncated files list the chunks before the damage, then stop at an error.
+    let truncated: Vec<_> = iter_chunks(&data[..30]).collect();
+    assert_eq!(truncated.len(), 4);
+    assert_eq!(truncated[3].unwrap_err(), ParseErrorKind::InvalidLength);
+    assert_eq!(iter_chunks(b"garbage!").count(), 1);
+}
+
+#[test]
+fn redacted_agents_content_is_left_out() {
+    let mut oplog = ListOpLog::new();
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    oplog.add_insert(seph, 0, "hello ");
+    oplog.add_insert(mike, 6, "secret");
+    oplog.add_insert(seph, 12, " world");
+    oplog.add_delete_without_content(mike, 0..1);
+
+    let opts = EncodeOptions {
+        compress_content: false,
+        store_deleted_content: true,
+        redact_content_for: &[mike],
+        keep_deleted_content_after: None,
+        ..ENCODE_FULL
+    };
+    let bytes = oplog.encode(opts);
+    assert!(!bytes.windows(6).any(|w| w == b"secret"));
+
+    let result = ListOpLog::load_from(&bytes).unwrap();
+    assert_eq!(result.len(), oplog.len());
+    assert_eq!(result.checkout_tip().content(), "ello \u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD} world");
+
+    // The other agent's text is still all there, and versions without any of mike's changes check
+    // out exactly as before.
+    let seph_text: String = result.iter()
+        .filter_map(|op| op.content.map(|c| c.to_string()))
+        .collect();
+    assert_eq!(seph_text, "hello  world");
+    assert_eq!(result.checkout(&[5]).content(), "hello ");
+}
+
+#[test]
+fn chunk_checksums_name_the_damaged_chunk() {
+    let oplog = simple_doc().oplog;
+    let plain = oplog.encode(EncodeOptions { compress_content: false, ..ENCODE_FULL });
+    let data = oplog.encode(EncodeOptions { compress_content: false, chunk_checksums: true, ..ENCODE_FULL });
+    assert_eq!(ListOpLog::load_from(&data).unwrap(), oplog);
+    assert_eq!(chunk_sizes(&data).unwrap().iter().filter(|c| c.name == "ChunkCrc").count(), 3);
+
+    // Files with chunk checksums have their own protocol version, so older decoders reject them.
+    assert_eq!(detect_version(&plain).unwrap(), PROTOCOL_VERSION);
+    assert_eq!(detect_version(&data).unwrap(), CHUNK_CRC_PROTOCOL_VERSION);
+
+    let chunk_path = |e: ParseError| e.chunk.chunk_types().collect::<Vec<_>>();
+    for (needle, chunk) in [(&b"seph"[..], ListChunkType::FileInfo), (&b"there"[..], ListChunkType::Patches)] {
+        let pos = data.windows(needle.len()).position(|w| w == needle).unwrap();
+        let mut corrupt = data.clone();
+        corrupt[pos] ^= 1;
+
+        let err = ListOpLog::load_from(&corrupt).unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::ChecksumFailed(_)));
+        assert_eq!(chunk_path(err), [chunk as u32]);
+
+        let err = ListOpLog::load_from_reader(&corrupt[..]).unwrap_err();
+        let err = *err.get_ref().unwrap().downcast_ref::<ParseError>().unwrap();
+        assert_eq!(chunk_path(err), [chunk as u32]);
+
+        let mut decoder = StreamingDecoder::new();
+        let err = decoder.push(&corrupt).unwrap_err();
+        assert!(matches!(err.kind, ParseErrorKind::ChecksumFailed(_)));
+        assert_eq!(chunk_path(err), [chunk as u32]);
+
+        // Without chunk checksums, only the CRC at the end of the file notices.
+        let mut corrupt = plain.clone();
+        corrupt[plain.windows(needle.len()).position(|w| w == needle).unwrap()] ^= 1;
+        let err = ListOpLog::load_from(&corrupt).unwrap_err();
+        assert_eq!(chunk_path(err), [ListChunkType::Crc as u32]);
+    }
+}
+
+#[test]
+fn snapshot_round_trip() {
+    let mut oplog = ListOpLog::new();
+    oplog.doc_id = Some("doc".into());
+    let seph = oplog.get_or_create_agent_id("seph");
+    let mike = oplog.get_or_create_agent_id("mike");
+    let kaarina = oplog.get_or_create_agent_id("kaarina");
+    oplog.add_insert(kaarina, 0, "unused");
+    oplog.add_delete_without_content(kaarina, 0..6);
+    let a = oplog.add_insert(seph, 0, "hi there");
+    oplog.add_insert_at(mike, &[a], 0, "yo ");
+    oplog.add_delete_without_content(seph, 0..3);
+
+    for opts in [ENCODE_FULL, EncodeOptions { compress_content: false, dedup_content: true, chunk_checksums: true, ..ENCODE_FULL }] {
+        let data = oplog.encode(EncodeOptions { mode: EncodeMode::Snapshot, ..opts });
+        let (branch, version) = ListBranch::load_snapshot(&data).unwrap();
+        assert!(branch.content_eq(&oplog.checkout_tip()));
+        assert!(branch.is_read_only());
+        assert_eq!(version, oplog.cg.agent_assignment.local_to_remote_frontier_owned(oplog.cg.version.as_ref()));
+
+        // Only the agents in the version are named, and there's no history.
+        assert!(!data.windows(7).any(|w| w == b"kaarina"));
+        assert!(iter_chunks(&data).all(|c| c.unwrap().chunk_type != ListChunkType::Patches));
+    }
+
+    // Snapshots of older versions, from encode_between.
+    let data = oplog.encode_between(EncodeOptions { mode: EncodeMode::Snapshot, ..ENCODE_FULL }, &[], &[a]);
+    let (branch, version) = ListBranch::load_snapshot(&data).unwrap();
+    assert_eq!(branch.content(), "hi there");
+    assert_eq!(version.as_slice(), &[RemoteVersionOwned("seph".into(), 7)]);
+
+    let data = ListOpLog::new().encode(EncodeOptions { mode: EncodeMode::Snapshot, ..ENCODE_FULL });
+    let (branch, version) = ListBranch::load_snapshot(&data).unwrap();
+    assert!(branch.is_empty());
+    assert!(version.is_empty());
+}
+
+#[test]
+fn snapshots_are_not_oplogs() {
+    let oplog = simple_doc().oplog;
+    let data = oplog.encode(EncodeOptions { mode: EncodeMode::Snapshot, ..ENCODE_FULL });
+
+    assert_eq!(ListOpLog::load_from(&data).unwrap_err(), ParseErrorKind::SnapshotData);
+    assert_eq!(ListOpLog::new().decode_and_add(&data).unwrap_err(), ParseErrorKind::SnapshotData);
+    assert_eq!(verify_data(&data).unwrap_err(), ParseErrorKind::SnapshotData);
+    assert_eq!(StreamingDecoder::new().push(&data).unwrap_err(), ParseErrorKind::SnapshotData);
+
+    // And the other way around.
+    let full = oplog.encode(ENCODE_FULL);
+    assert_eq!(ListBranch::load_snapshot(&full).unwrap_err(), ParseErrorKind::MissingChunk(ListChunkType::Snapshot as u32));
+}
+
+#[test]
+fn decoded_content_is_not_copied() {
+    use std::sync::Arc;
+    use crate::list::op_metrics::ContentBuf;
+    let is_shared = |c: &ContentBuf| matches!(c, ContentBuf::Shared { .. });
+
+    // Content is only compressed if there's enough of it.
+    let mut doc = simple_doc();
+    doc.insert(0, 0, "Some longer text, which is worth compressing. ");
+    doc.delete(0, 5..30); // Deleted content is stored locally.
+    let mike = doc.get_or_create_agent_id("mike");
+    doc.oplog.add_insert_at(mike, &[], 0, "yo ");
+    let mut oplog = doc.oplog;
+    const OPTS: EncodeOptions = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+
+    // Compressed content is read from the decompressed data.
+    let mut loaded = ListOpLog::load_from(&oplog.encode(OPTS)).unwrap();
+    assert_eq!(loaded, oplog);
+    if cfg!(feature = "lz4") {
+        assert!(is_shared(&loaded.operation_ctx.ins_content));
+        assert!(is_shared(&loaded.operation_ctx.del_content));
+    }
+
+    // Adding content copies it.
+    loaded.add_insert(0, 0, "x");
+    assert!(!is_shared(&loaded.operation_ctx.ins_content));
+    oplog.add_insert(0, 0, "x");
+    assert_eq!(loaded, oplog);
+
+    // Uncompressed content is read straight from shared data.
+    let data = Arc::new(oplog.encode(EncodeOptions { compress_content: false, ..OPTS }));
+    let loaded = ListOpLog::load_from_shared(data.clone()).unwrap();
+    assert_eq!(loaded, oplog);
+    assert!(is_shared(&loaded.operation_ctx.ins_content));
+    assert!(is_shared(&loaded.operation_ctx.del_content));
+    assert_eq!(Arc::strong_count(&data), 3);
+    drop(loaded);
+    assert_eq!(Arc::strong_count(&data), 1);
+
+    // Merging into an oplog with content of its own copies the new content.
+    let mut other = ListOpLog::new();
+    let kaarina = other.get_or_create_agent_id("kaarina");
+    other.add_insert(kaarina, 0, "hi");
+    other.decode_and_add(&data).unwrap();
+    assert!(!is_shared(&other.operation_ctx.ins_content));
+    assert_eq!(Arc::strong_count(&data), 1);
+}
+
+#[test]
+fn files_declare_the_capabilities_they_use() {
+    use Capability::*;
+    let list = |set: CapabilitySet| set.known().collect::<Vec<_>>();
+
+    // Files which only use the original features don't list any capabilities, so they're
+    // byte-for-byte the same as before.
+    let data = simple_doc().oplog.encode(ENCODE_FULL);
+    assert_eq!(capabilities(&data).unwrap(), (CapabilitySet::default(), CapabilitySet::default()));
+
+    let mut oplog = simple_doc().oplog;
+    let seph = oplog.get_or_create_agent_id("seph");
+    oplog.add_checkpoint(seph, &[oplog.len() - 1], "v1");
+    oplog.set_timestamp((0..3).into(), 1000);
+    let data = oplog.encode(EncodeOptions {
+        store_deleted_content: true,
+        experimentally_store_end_branch_content: true,
+        ..ENCODE_FULL
+    });
+    let (required, optional) = capabilities(&data).unwrap();
+    assert_eq!(list(required), [Checkpoints]);
+    assert_eq!(list(optional), [EndContent, Timestamps]);
+
+    let summary = verify_data(&data).unwrap();
+    assert_eq!((summary.required_capabilities, summary.optional_capabilities), (required, optional));
+
+    let oplog = repeated_paste_oplog();
+    let data = oplog.encode(EncodeOptions { mode: EncodeMode::Snapshot, ..encode_opts_with(cfg!(feature = "lz4"), true) });
+    let (required, optional) = capabilities(&data).unwrap();
+    let mut expected = vec![DedupedContent, Snapshot];
+    if cfg!(feature = "lz4") { expected.insert(0, CompressedContent); }
+    assert_eq!(list(required), expected);
+    assert!(optional.is_empty());
+}
+
+#[test]
+fn unknown_required_capabilities_are_rejected() {
+    let mut oplog = simple_doc().oplog;
+    oplog.set_timestamp((0..3).into(), 1000);
+    let with_capabilities = |required: u64, optional: u64| {
+        let mut chunk = Vec::new();
+        encode_tools::push_leb_u64(&mut chunk, required);
+        encode_tools::push_leb_u64(&mut chunk, optional);
+        replace_inner_chunk(&oplog.encode(ENCODE_FULL), ListChunkType::FileInfo, ListChunkType::Capabilities, &chunk)
+    };
+    let timestamps = 1 << Capability::Timestamps as u32;
+
+    // Unknown optional capabilities are reported, but the file still loads.
+    let data = with_capabilities(0, timestamps | 1 << 40);
+    let (required, optional) = capabilities(&data).unwrap();
+    assert!(required.is_empty());
+    assert_eq!(optional.known().collect::<Vec<_>>(), [Capability::Timestamps]);
+    assert_eq!(optional.unknown().collect::<Vec<_>>(), [40]);
+    assert_eq!(ListOpLog::load_from(&data).unwrap(), oplog);
+    assert_eq!(decode_document(&data).unwrap(), "hi me");
+    assert_eq!(verify_data(&data).unwrap().optional_capabilities, optional);
+
+    // An unknown required capability means we can't read the file correctly.
+    let data = with_capabilities(1 << 12, timestamps);
+    assert_eq!(capabilities(&data).unwrap().0.unknown().collect::<Vec<_>>(), [12]);
+    let range = inner_chunk_range(&data, ListChunkType::FileInfo, ListChunkType::Capabilities);
+    let err = ListOpLog::load_from(&data).unwrap_err();
+    assert_eq!(err, ParseErrorKind::UnsupportedCapability(12));
+    assert_eq!(err.pos, Some(range.start));
+    assert_eq!(ListOpLog::new().decode_and_add(&data).unwrap_err(), ParseErrorKind::UnsupportedCapability(12));
+    assert_eq!(decode_document(&data).unwrap_err(), ParseErrorKind::UnsupportedCapability(12));
+    assert_eq!(verify_data(&data).unwrap_err(), ParseErrorKind::UnsupportedCapability(12));
+}
@@ -14,6 +14,8 @@ use crate::list::ListOpLog;
 use crate::frontier::sort_frontier;
 use crate::causalgraph::graph::GraphEntrySimple;
 use crate::rle::KVPair;
+use crate::dtrange::DTRange;
+use crate::list::operation::TextOperation;
 
 const VERBOSE: bool = true;
 // const VERBOSE: bool = false;
@@ -21,6 +23,7 @@ const VERBOSE: bool = true;
 impl PartialEq<Self> for ListOpLog {
     fn eq(&self, other: &Self) -> bool {
         if self.doc_id != other.doc_id { return false; }
+        if self.user_data != other.user_data { return false; }
 
         // This implementation is based on the equivalent version in the original diamond types
         // implementation.
@@ -83,6 +86,42 @@ impl PartialEq<Self> for ListOpLog {
             }
         }
 
+        let map_parents = |parents: &[LV]| -> Option<Frontier> {
+            let mut mapped = Frontier(parents.iter().map(|t| map_lv_to_other(*t)).collect::<Option<_>>()?);
+            sort_frontier(&mut mapped.0);
+            Some(mapped)
+        };
+
+        // Checkpoints have no operation, so they're checked separately. Each of our checkpoints
+        // has to be a checkpoint in other with the same message and parents. Along with the same
+        // number of checkpoints, this makes sure other's checkpoints don't line up with our
+        // operations either.
+        if self.checkpoints.len() != other.checkpoints.len() { return false; }
+        for (&lv, message) in self.checkpoints.iter() {
+            let Some(other_lv) = map_lv_to_other(lv) else { return false; };
+            if other.checkpoints.get(&other_lv) != Some(message) {
+                if VERBOSE { println!("Checkpoints do not match at {lv}"); }
+                return false;
+            }
+            if map_parents(self.cg.graph.parents_at_time(lv).as_ref()) != Some(other.cg.graph.parents_at_time(other_lv)) {
+                if VERBOSE { println!("Checkpoint parents do not match at {lv}"); }
+                return false;
+            }
+        }
+
+        // Each of our timestamped operations has to have the same timestamp in other. Along with
+        // the same number of timestamped operations, that makes sure the timestamps match.
+        let timestamped_len = |oplog: &ListOpLog| oplog.iter_timestamps().map(|(range, _)| range.len()).sum::<usize>();
+        if timestamped_len(self) != timestamped_len(other) { return false; }
+        for (range, timestamp) in self.iter_timestamps() {
+            for lv in range.start..range.end {
+                if map_lv_to_other(lv).and_then(|v| other.timestamp_of(v)) != Some(timestamp) {
+                    if VERBOSE { println!("Timestamps do not match at {lv}"); }
+                    return false;
+                }
+            }
+        }
+
         // The core strategy here is we'll iterate through our local operations and make sure they
         // each have a corresponding operation in other. Because self.len == other.len, this will be
         // sufficient.
@@ -92,11 +131,15 @@ impl PartialEq<Self> for ListOpLog {
 
         // Note this should be optimized if its going to be used for more than fuzz testing.
         // But this is pretty neat!
-        for (mut op, mut txn, mut crdt_id) in rle_zip3(
-            self.iter(),
-            self.iter_history(),
-            self.cg.agent_assignment.client_with_localtime.iter().map(|pair| pair.1)
-        ) {
+        let op_runs = self.iter_fast().flat_map(|(KVPair(lv, op), content)| {
+            let range: DTRange = (lv..lv + op.len()).into();
+            rle_zip3(
+                std::iter::once(TextOperation::from((op, content))),
+                self.iter_history_range(range),
+                self.cg.agent_assignment.client_with_localtime.iter_range(range).map(|pair| pair.1)
+            )
+        });
+        for (mut op, mut txn, mut crdt_id) in op_runs {
 
             // println!("op {:?} txn {:?} crdt {:?}", op, txn, crdt_id);
 
@@ -111,7 +154,10 @@ impl PartialEq<Self> for ListOpLog {
                 };
 
                 // Lets take a look at the operation.
-                let (KVPair(_, other_op_int), offset) = other.operations.find_packed_with_offset(other_time);
+                let Some((KVPair(_, other_op_int), offset)) = other.operations.find_with_offset(other_time) else {
+                    if VERBOSE { println!("Operation is missing in other at {other_time}"); }
+                    return false;
+                };
 
                 let mut other_op = other_op_int.to_operation(&other.operation_ctx);
                 if offset > 0 { other_op.truncate_keeping_right(offset); }
@@ -162,14 +208,12 @@ impl PartialEq<Self> for ListOpLog {
                     // return false;
                 };
 
-                let mut mapped_txn = GraphEntrySimple {
+                let mapped_txn = GraphEntrySimple {
                     span: (mapped_start..mapped_start + len_here).into(),
                     // .unwrap() should be safe here because we've already walked past this item's
                     // parents.
-                    parents: Frontier(txn.parents.iter().map(|t| map_lv_to_other(*t).unwrap()).collect())
+                    parents: map_parents(txn.parents.as_ref()).unwrap(),
                 };
-                // mapped_txn.parents.sort_unstable();
-                sort_frontier(&mut mapped_txn.parents.0);
 
                 if other_txn != mapped_txn {
                     if VERBOSE { println!("Txns do not match {:?} (was {:?}) != {:?}", mapped_txn, txn, other_txn); }
@@ -0,0 +1,322 @@
+//! Collapsing old history.
+//!
+//! An oplog keeps every operation forever, but once a version has been seen by every peer, nothing
+//! new can ever be concurrent with the operations before it. [`ListOpLog::gc_before`] replaces
+//! those operations with a short baseline which recreates the document's content at that version
+//! in a single linear run, so they no longer need their own history entries or content.
+//!
+//! The collapsed operations keep their local versions and their agent / seq IDs, so remote peers
+//! can still name them (eg as parents of new changes).
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use rle::HasLength;
+use crate::causalgraph::graph::Graph;
+use crate::dtrange::DTRange;
+use crate::list::{ListBranch, ListOpLog};
+use crate::list::op_iter::OpMetricsIter;
+use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
+use crate::list::operation::ListOpKind;
+use crate::rle::RleVec;
+use crate::unicount::{consume_chars, count_chars};
+use crate::LV;
+
+/// Returned by [`ListOpLog::gc_before`] and [`ListOpLog::try_checkout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcError {
+    /// The version names operations which aren't in the oplog.
+    UnknownVersion,
+
+    /// The oplog contains operations which are concurrent with the version. Their history can't
+    /// be collapsed until they've been merged.
+    ConcurrentOperations,
+
+    /// The requested version is from before history was collapsed with
+    /// [`gc_before`](ListOpLog::gc_before), so its content is gone.
+    VersionCollected,
+}
+
+impl Display for GcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GcError::UnknownVersion => write!(f, "version names operations which aren't in the oplog"),
+            GcError::ConcurrentOperations => write!(f, "oplog contains operations concurrent with the version"),
+            GcError::VersionCollected => write!(f, "version is from before the oplog's history was collapsed"),
+        }
+    }
+}
+
+impl Error for GcError {}
+
+impl ListOpLog {
+    /// The number of operations (from the start of the oplog) whose history has been collapsed
+    /// with [`gc_before`](ListOpLog::gc_before). Versions from before this point can't be checked
+    /// out.
+    pub fn gc_len(&self) -> usize {
+        self.gc_len
+    }
+
+    /// Returns true if the document can be checked out at `local_version`. Everything can be
+    /// checked out unless history has been collapsed with [`gc_before`](ListOpLog::gc_before).
+    pub fn can_checkout(&self, local_version: &[LV]) -> bool {
+        self.gc_len == 0 || self.cg.graph.frontier_contains_version(local_version, self.gc_len - 1)
+    }
+
+    /// Like [`checkout`](ListOpLog::checkout), but returns an error instead of panicking if the
+    /// version isn't in the oplog, or if it's from before the point passed to
+    /// [`gc_before`](ListOpLog::gc_before).
+    pub fn try_checkout(&self, local_version: &[LV]) -> Result<ListBranch, GcError> {
+        if local_version.iter().any(|&v| v >= self.len()) { return Err(GcError::UnknownVersion); }
+        if !self.can_checkout(local_version) { return Err(GcError::VersionCollected); }
+        Ok(self.checkout(local_version))
+    }
+
+    /// Collapse all the operations before `version` into a compact baseline, which inserts the
+    /// document's content at `version` in one linear run. The baseline replaces their history
+    /// entries and the content they inserted and deleted.
+    ///
+    /// Checkouts at `version` or anything after it are unchanged. Checking out an older version
+    /// (or one concurrent with it) is an error - see [`try_checkout`](ListOpLog::try_checkout).
+    ///
+    /// Only do this with a version every peer has already merged. Merging a remote change made
+    /// concurrently with `version` produces the wrong content. This returns an error if the
+    /// oplog already contains any such changes.
+    ///
+    /// The collapsed point isn't saved when the oplog is encoded. A file written afterwards loads
+    /// with the baseline as ordinary history.
+    pub fn gc_before(&mut self, version: &[LV]) -> Result<(), GcError> {
+        if version.iter().any(|&v| v >= self.len()) { return Err(GcError::UnknownVersion); }
+        let Some(&last) = version.iter().max() else { return Ok(()); };
+        if !self.can_checkout(version) { return Err(GcError::VersionCollected); }
+
+        // Everything from before `version` has to be in its history, and everything after has to
+        // come after it. Local versions are assigned in causal order, so that means the history
+        // of `version` is exactly the first `len` operations.
+        let len = last + 1;
+        let graph = &self.cg.graph;
+        let before_ok = graph.diff(version, &[]).0.iter().map(|r| r.len()).sum::<usize>() == len;
+        let after_ok = graph.iter_range((len..self.len()).into()).all(|e| {
+            // Entries whose parents all come later are checked through their parents.
+            (!e.parents.is_empty() && e.parents.iter().all(|&p| p >= len))
+                || graph.frontier_contains_frontier(e.parents.as_ref(), version)
+        });
+        if !before_ok || !after_ok { return Err(GcError::ConcurrentOperations); }
+
+        // Checkpoints are kept, so the baseline needs an operation for every other version before
+        // `len`. It inserts the content, then inserts and deletes some padding. Inserts and
+        // deletes always differ by the content length, so the padding comes out even.
+        let content = self.checkout(version).content().to_string();
+        let content_len = count_chars(&content);
+        let num_checkpoints = self.checkpoints.range(..len).count();
+        let padding = (len - num_checkpoints - content_len) / 2;
+        debug_assert_eq!(content_len + 2 * padding + num_checkpoints, len);
+
+        // The versions between the checkpoints, which the baseline's operations go in.
+        let mut gaps = VecDeque::new();
+        let mut next = 0;
+        for &v in self.checkpoints.range(..len).map(|(v, _)| v) {
+            if next < v { gaps.push_back(DTRange::from(next..v)); }
+            next = v + 1;
+        }
+        if next < len { gaps.push_back((next..len).into()); }
+
+        let old_ops = std::mem::replace(&mut self.operations, RleVec::new());
+        let old_ctx = std::mem::replace(&mut self.operation_ctx, ListOperationCtx::new());
+        self.inserted_bytes = 0;
+
+        let mut rest = content.as_str();
+        let mut pos = 0;
+        for span in take_versions(&mut gaps, content_len) {
+            let piece = consume_chars(&mut rest, span.len());
+            self.push_op_internal(span.start, (pos..pos + span.len()).into(), ListOpKind::Ins, Some(piece));
+            pos += span.len();
+        }
+        for span in take_versions(&mut gaps, padding) {
+            self.push_op_internal(span.start, (pos..pos + span.len()).into(), ListOpKind::Ins, None);
+            pos += span.len();
+        }
+        for span in take_versions(&mut gaps, padding) {
+            let del: DTRange = (content_len..content_len + span.len()).into();
+            self.push_op_internal(span.start, del.into(), ListOpKind::Del, None);
+        }
+
+        if len < self.len() {
+            let mut iter = OpMetricsIter::new(&old_ops, &old_ctx, (len..self.len()).into());
+            while let Some(op) = iter.next() {
+                let content = iter.get_content(&op);
+                let ListOpMetrics { loc, kind, .. } = op.1;
+                self.push_op_internal(op.0, loc, kind, content);
+            }
+        }
+
+        let mut new_graph = Graph::new();
+        new_graph.push(&[], (0..len).into());
+        for e in self.cg.graph.iter_range((len..self.len()).into()) {
+            new_graph.push(e.parents.as_ref(), e.span);
+        }
+        self.cg.graph = new_graph;
+
+        self.timestamps = self.timestamps.iter_range((len..self.len()).into()).collect();
+        self.gc_len = len;
+        Ok(())
+    }
+}
+
+/// Take the first n versions from a list of ranges of versions. They're returned as one range for
+/// each (part of a) range they came from.
+fn take_versions(gaps: &mut VecDeque<DTRange>, mut n: usize) -> Vec<DTRange> {
+    let mut result = vec![];
+    while n > 0 {
+        let gap = gaps.front_mut().unwrap();
+        let len = gap.len().min(n);
+        result.push((gap.start..gap.start + len).into());
+        gap.start += len;
+        n -= len;
+        if gap.is_empty() { gaps.pop_front(); }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use crate::encoding::parseerror::ParseErrorKind;
+    use crate::list::{EditError, ListBranch, ListCRDT, ListOpLog};
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::operation::TextOperation;
+    use crate::list::server::{CentralServer, SubmitError};
+    use super::GcError;
+
+    #[test]
+    fn gc_keeps_later_checkouts() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        doc.insert(seph, 0, "hello world");
+        doc.delete(seph, 0..6);
+        // Concurrent edits before the GC point, merged by a later edit.
+        let v = doc.oplog.local_version();
+        let x = doc.oplog.add_insert_at(mike, v.as_ref(), 5, "!!");
+        let y = doc.oplog.add_insert_at(seph, v.as_ref(), 0, "oh ");
+        doc.oplog.add_delete_at(mike, &[x, y], 0..1);
+        let gc_point = doc.oplog.local_version();
+
+        // Some concurrent changes after the GC point, and a merge.
+        let a = doc.oplog.add_insert_at(seph, gc_point.as_ref(), 0, "aa");
+        let b = doc.oplog.add_delete_at(mike, gc_point.as_ref(), 1..3);
+        doc.oplog.add_insert_at(seph, &[a, b], 0, "c");
+
+        let before = doc.oplog.clone();
+        let c = before.local_version();
+        let versions: [&[usize]; 5] = [gc_point.as_ref(), &[a], &[b], &[a, b], c.as_ref()];
+
+        doc.oplog.gc_before(gc_point.as_ref()).unwrap();
+        assert_eq!(doc.oplog.gc_len(), gc_point[0] + 1);
+        assert_eq!(doc.oplog.len(), before.len());
+        assert!(doc.oplog.cg.graph.entries.num_entries() < before.cg.graph.entries.num_entries());
+        doc.oplog.dbg_check(true);
+
+        for v in versions.iter() {
+            assert_eq!(doc.oplog.try_checkout(v).unwrap().content(),
+                       before.checkout(v).content());
+        }
+        assert_eq!(doc.oplog.remote_version(), before.remote_version());
+
+        assert_eq!(doc.oplog.try_checkout(&[5]).unwrap_err(), GcError::VersionCollected);
+        assert_eq!(doc.oplog.try_checkout(&[]).unwrap_err(), GcError::VersionCollected);
+        assert_eq!(doc.oplog.try_checkout(&[100]).unwrap_err(), GcError::UnknownVersion);
+
+        // The collapsed history still encodes and merges.
+        let loaded = ListOpLog::load_from(&doc.oplog.encode(Default::default())).unwrap();
+        assert_eq!(loaded.checkout_tip().content(), before.checkout_tip().content());
+        let mut other = before.clone();
+        other.add_insert(mike, 0, "x");
+        doc.oplog.add_missing_operations_from(&other);
+        assert_eq!(doc.oplog.checkout_tip().content(), other.checkout_tip().content());
+    }
+
+    #[test]
+    fn gc_keeps_checkpoints() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hello world");
+        let first = oplog.add_checkpoint(seph, oplog.local_version().as_ref(), "first");
+        oplog.add_delete_at(seph, &[first], 0..6);
+        // Ending the collapsed history with a checkpoint leaves an odd number of versions for the
+        // baseline's operations.
+        let gc_point = oplog.add_checkpoint(seph, oplog.local_version().as_ref(), "second");
+        oplog.add_insert(seph, 5, "!");
+        let later = oplog.add_checkpoint(seph, oplog.local_version().as_ref(), "later");
+
+        let before = oplog.clone();
+        oplog.gc_before(&[gc_point]).unwrap();
+        oplog.dbg_check(true);
+        assert_eq!(oplog.len(), before.len());
+        assert_eq!(oplog.checkout_tip().content(), "world!");
+        assert_eq!(oplog.checkout(&[gc_point]).content(), "world");
+        assert_eq!(oplog.iter_checkpoints().collect::<Vec<_>>(), before.iter_checkpoints().collect::<Vec<_>>());
+        assert_eq!(oplog.checkpoint_message(later), Some("later"));
+
+        let loaded = ListOpLog::load_from(&oplog.encode(Default::default())).unwrap();
+        assert_eq!(loaded.checkout_tip().content(), "world!");
+        assert_eq!(loaded.iter_checkpoints().count(), 3);
+    }
+
+    #[test]
+    fn gc_refuses_concurrent_operations() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let a = oplog.add_insert(seph, 0, "aaa");
+        let b = oplog.add_insert_at(seph, &[], 0, "bb");
+
+        let before = oplog.clone();
+        assert_eq!(oplog.gc_before(&[a]), Err(GcError::ConcurrentOperations));
+        assert_eq!(oplog.gc_before(&[b]), Err(GcError::ConcurrentOperations));
+        assert_eq!(oplog.gc_before(&[b + 1]), Err(GcError::UnknownVersion));
+        assert_eq!(oplog, before);
+
+        oplog.gc_before(&[a, b]).unwrap();
+        assert_eq!(oplog.checkout_tip().content(), before.checkout_tip().content());
+        assert_eq!(oplog.gc_before(&[a]), Err(GcError::VersionCollected));
+    }
+
+    #[test]
+    fn gc_rejects_collapsed_versions() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let early = oplog.add_insert(seph, 0, "hello");
+        let gc_point = oplog.add_insert(seph, 5, " world");
+
+        // A peer which hasn't seen the GC point makes a concurrent change.
+        let mut remote = oplog.clone();
+        remote.add_insert_at(mike, &[early], 0, "oh ");
+        // And one which has.
+        let mut up_to_date = oplog.clone();
+        up_to_date.add_insert(mike, 0, "yo ");
+
+        oplog.gc_before(&[gc_point]).unwrap();
+        let before = oplog.clone();
+        assert_eq!(oplog.decode_and_add(&remote.encode(ENCODE_FULL)).unwrap_err(),
+                   ParseErrorKind::VersionCollected);
+        assert_eq!(oplog, before);
+        oplog.decode_and_add(&up_to_date.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(oplog.checkout_tip().content(), "yo hello world");
+
+        // Merging into or out of a collapsed version.
+        let mut branch = ListBranch::new();
+        assert_eq!(branch.try_merge(&oplog, &[early]), Err(EditError::VersionCollected));
+        branch.try_merge(&oplog, &[gc_point]).unwrap();
+        assert_eq!(branch.content(), "hello world");
+        let mut stale = remote.checkout(&[early]);
+        assert_eq!(stale.try_merge(&oplog, oplog.local_version_ref()), Err(EditError::VersionCollected));
+        assert_eq!(stale, remote.checkout(&[early]));
+
+        // The same goes for clients of a central server.
+        let mut server = CentralServer::from_oplog(oplog.clone());
+        let version = oplog.cg.agent_assignment.local_to_remote_frontier_owned(&[early]);
+        let ops = [TextOperation::new_insert(0, "x")];
+        assert_eq!(server.submit("mike", &version, &ops).unwrap_err(), SubmitError::VersionCollected);
+        assert_eq!(server.catchup(&version).unwrap_err(), SubmitError::VersionCollected);
+    }
+}
@@ -0,0 +1,400 @@
+//! Soft wrapping support for text editors.
+//!
+//! A [`LayoutCache`] sits alongside a [`ListBranch`] and maps between character offsets in the
+//! document and visual (row, column) positions when lines are wrapped at a fixed width. When the
+//! branch changes, [`refresh`](LayoutCache::refresh) uses the transformed operations from the oplog
+//! to figure out which lines were touched, and only rewraps those lines.
+
+use smallvec::{SmallVec, smallvec};
+use rle::HasLength;
+use crate::{Frontier, LV};
+use crate::list::{ListBranch, ListOpLog};
+use crate::list::operation::ListOpKind;
+
+/// Decides how many columns each character takes up on screen.
+pub trait CharWidth {
+    fn char_width(&self, c: char) -> usize;
+}
+
+/// The simplest [`CharWidth`], where every character takes up exactly 1 column.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnitWidth;
+
+impl CharWidth for UnitWidth {
+    fn char_width(&self, _c: char) -> usize { 1 }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    /// Length of the line in characters, not including the trailing newline.
+    len: usize,
+
+    /// The offset (within the line) of the first character in each visual row. The first entry is
+    /// always 0.
+    row_starts: SmallVec<[usize; 1]>,
+
+    /// Set when the line has been edited and row_starts needs to be recomputed.
+    dirty: bool,
+}
+
+impl Line {
+    fn new(len: usize) -> Self {
+        Self { len, row_starts: smallvec![0], dirty: true }
+    }
+}
+
+/// Maps character positions in a branch to and from visual (row, column) positions, with lines
+/// wrapped at a fixed width. Characters wider than the wrap width get a row to themselves.
+///
+/// Call [`refresh`](LayoutCache::refresh) after the branch changes, before querying positions.
+#[derive(Debug, Clone)]
+pub struct LayoutCache<W: CharWidth = UnitWidth> {
+    width: usize,
+    char_width: W,
+
+    /// The branch version this layout describes. None if the layout needs to be rebuilt from
+    /// scratch.
+    version: Option<Frontier>,
+
+    lines: Vec<Line>,
+    /// The character offset of the start of each line.
+    line_starts: Vec<usize>,
+    /// The first visual row of each line, plus a trailing entry with the total number of rows.
+    line_rows: Vec<usize>,
+}
+
+impl LayoutCache<UnitWidth> {
+    /// Create a new layout cache which wraps lines at `width` columns.
+    pub fn new(width: usize) -> Self {
+        Self::with_char_width(width, UnitWidth)
+    }
+}
+
+impl<W: CharWidth> LayoutCache<W> {
+    /// Create a new layout cache which wraps lines at `width` columns, with custom character widths.
+    pub fn with_char_width(width: usize, char_width: W) -> Self {
+        Self {
+            width: width.max(1),
+            char_width,
+            version: None,
+            lines: vec![Line::new(0)],
+            line_starts: vec![0],
+            line_rows: vec![0, 1],
+        }
+    }
+
+    pub fn width(&self) -> usize { self.width }
+
+    /// Change the wrap width. This invalidates the whole layout, so the next call to
+    /// [`refresh`](LayoutCache::refresh) will rewrap every line.
+    pub fn set_width(&mut self, width: usize) {
+        self.width = width.max(1);
+        for line in self.lines.iter_mut() { line.dirty = true; }
+    }
+
+    /// The total number of visual rows in the document.
+    pub fn num_rows(&self) -> usize {
+        *self.line_rows.last().unwrap()
+    }
+
+    /// Bring the layout up to date with the branch. Only lines touched by changes since the last
+    /// refresh are rewrapped.
+    pub fn refresh(&mut self, oplog: &ListOpLog, branch: &ListBranch) {
+        match &self.version {
+            Some(v) if oplog.cg.graph.frontier_contains_frontier(branch.local_version_ref(), v.as_ref()) => {
+                if v != &branch.version {
+                    let v = v.clone();
+                    if !self.apply_changes(oplog, v.as_ref(), branch.local_version_ref()) {
+                        self.rebuild_lines(branch);
+                    }
+                }
+            }
+            _ => self.rebuild_lines(branch),
+        }
+
+        self.version = Some(branch.local_version());
+        self.rewrap_dirty(branch);
+    }
+
+    fn rebuild_lines(&mut self, branch: &ListBranch) {
+        self.lines.clear();
+        let mut len = 0;
+        for c in branch.content().borrow().chars() {
+            if c == '\n' {
+                self.lines.push(Line::new(len));
+                len = 0;
+            } else { len += 1; }
+        }
+        self.lines.push(Line::new(len));
+        self.update_line_starts(0);
+    }
+
+    /// Replay the transformed operations between two versions onto the line lengths, marking
+    /// every line they touch as dirty. Returns false if the layout needs to be rebuilt instead.
+    fn apply_changes(&mut self, oplog: &ListOpLog, from: &[LV], to: &[LV]) -> bool {
+        for (_, op) in oplog.iter_xf_operations_from(from, to) {
+            let Some(op) = op else { continue; };
+            let pos = op.start();
+            let (k, offset) = self.find_line(pos);
+
+            match op.kind {
+                ListOpKind::Ins => {
+                    let Some(content) = op.content_as_str() else {
+                        // Without the content we can't tell where the newlines are.
+                        return false;
+                    };
+
+                    // Lengths of each newline-separated segment of the inserted text.
+                    let mut segments: SmallVec<[usize; 2]> = smallvec![0];
+                    let mut count = |c: char| {
+                        if c == '\n' { segments.push(0); } else { *segments.last_mut().unwrap() += 1; }
+                    };
+                    // Reversed inserts store their content in the order it was typed.
+                    if op.loc.fwd { content.chars().for_each(&mut count); } else { content.chars().rev().for_each(&mut count); }
+
+                    let tail = self.lines[k].len - offset;
+                    let last = segments.len() - 1;
+                    if last == 0 {
+                        self.lines[k].len += segments[0];
+                    } else {
+                        self.lines[k].len = offset + segments[0];
+                        let new_lines = segments[1..].iter().enumerate().map(|(i, &len)| {
+                            Line::new(if i + 1 == last { len + tail } else { len })
+                        });
+                        self.lines.splice(k + 1..k + 1, new_lines);
+                    }
+                }
+                ListOpKind::Del => {
+                    let (m, end_offset) = self.find_line(pos + op.len());
+                    self.lines[k].len = offset + self.lines[m].len - end_offset;
+                    self.lines.drain(k + 1..m + 1);
+                }
+            }
+
+            self.lines[k].dirty = true;
+            self.update_line_starts(k);
+        }
+        true
+    }
+
+    fn update_line_starts(&mut self, from_line: usize) {
+        self.line_starts.truncate(from_line + 1);
+        if self.line_starts.is_empty() { self.line_starts.push(0); }
+        for i in self.line_starts.len()..self.lines.len() {
+            let prev = self.line_starts[i - 1] + self.lines[i - 1].len + 1;
+            self.line_starts.push(prev);
+        }
+    }
+
+    fn rewrap_dirty(&mut self, branch: &ListBranch) {
+        let content = branch.content().borrow();
+
+        for (line, &start) in self.lines.iter_mut().zip(self.line_starts.iter()) {
+            if !line.dirty { continue; }
+
+            line.row_starts.clear();
+            line.row_starts.push(0);
+            let mut col = 0;
+            for (i, c) in content.slice_chars(start..start + line.len).enumerate() {
+                let w = self.char_width.char_width(c);
+                if col > 0 && col + w > self.width {
+                    line.row_starts.push(i);
+                    col = 0;
+                }
+                col += w;
+            }
+            line.dirty = false;
+        }
+
+        self.line_rows.clear();
+        self.line_rows.push(0);
+        for line in self.lines.iter() {
+            let next = self.line_rows.last().unwrap() + line.row_starts.len();
+            self.line_rows.push(next);
+        }
+    }
+
+    /// Returns the line containing the character position, and the offset within that line.
+    fn find_line(&self, pos: usize) -> (usize, usize) {
+        let k = self.line_starts.partition_point(|s| *s <= pos) - 1;
+        (k, pos - self.line_starts[k])
+    }
+
+    fn cols_between(&self, branch: &ListBranch, start: usize, end: usize) -> usize {
+        branch.content().borrow().slice_chars(start..end)
+            .map(|c| self.char_width.char_width(c))
+            .sum()
+    }
+
+    /// Find the visual (row, column) of the character position `pos`. The position at the end of a
+    /// wrapped row is shown at the start of the next row.
+    pub fn pos_to_visual(&self, branch: &ListBranch, pos: usize) -> (usize, usize) {
+        debug_assert_eq!(self.version.as_ref(), Some(&branch.version), "LayoutCache is out of date");
+        assert!(pos <= branch.len());
+
+        let (k, offset) = self.find_line(pos);
+        let line = &self.lines[k];
+        let r = line.row_starts.partition_point(|s| *s <= offset) - 1;
+        let row_start = self.line_starts[k] + line.row_starts[r];
+        (self.line_rows[k] + r, self.cols_between(branch, row_start, pos))
+    }
+
+    /// Find the character position shown at the visual (row, column). Positions past the end of a
+    /// row are clamped to the last position in that row, and rows past the end of the document are
+    /// clamped to the end of the document.
+    pub fn visual_to_pos(&self, branch: &ListBranch, row: usize, col: usize) -> usize {
+        debug_assert_eq!(self.version.as_ref(), Some(&branch.version), "LayoutCache is out of date");
+        if row >= self.num_rows() { return branch.len(); }
+
+        let k = self.line_rows.partition_point(|r| *r <= row) - 1;
+        let line = &self.lines[k];
+        let r = row - self.line_rows[k];
+        let line_start = self.line_starts[k];
+        let row_start = line_start + line.row_starts[r];
+        let is_last_row = r + 1 == line.row_starts.len();
+        let row_end = if is_last_row { line_start + line.len } else { line_start + line.row_starts[r + 1] };
+
+        let mut pos = row_start;
+        let mut c = 0;
+        for ch in branch.content().borrow().slice_chars(row_start..row_end) {
+            let w = self.char_width.char_width(ch);
+            if c + w > col { break; }
+            c += w;
+            pos += 1;
+        }
+
+        // The end of a wrapped row is the start of the next one.
+        if !is_last_row && pos == row_end { pos - 1 } else { pos }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::{ListBranch, ListOpLog};
+    use crate::list::layout::{CharWidth, LayoutCache};
+
+    // Wide characters are handy for testing rows which can't be filled exactly.
+    struct TestWidth;
+    impl CharWidth for TestWidth {
+        fn char_width(&self, c: char) -> usize {
+            if c == 'W' || c == '界' { 2 } else { 1 }
+        }
+    }
+
+    /// Work out the visual position of every character position from scratch.
+    fn naive_layout<W: CharWidth>(content: &str, width: usize, cw: &W) -> Vec<(usize, usize)> {
+        let mut result = vec![];
+        let (mut row, mut col) = (0, 0);
+        for c in content.chars() {
+            let w = cw.char_width(c);
+            if c != '\n' && col > 0 && col + w > width {
+                row += 1;
+                col = 0;
+            }
+            result.push((row, col));
+            if c == '\n' {
+                row += 1;
+                col = 0;
+            } else { col += w; }
+        }
+        result.push((row, col));
+        result
+    }
+
+    fn check<W: CharWidth>(cache: &LayoutCache<W>, branch: &ListBranch) {
+        let content = branch.content().to_string();
+        let expected = naive_layout(&content, cache.width(), &cache.char_width);
+        assert_eq!(cache.num_rows(), expected.last().unwrap().0 + 1);
+
+        for (pos, &visual) in expected.iter().enumerate() {
+            assert_eq!(cache.pos_to_visual(branch, pos), visual, "pos {pos} in {content:?}");
+            assert_eq!(cache.visual_to_pos(branch, visual.0, visual.1), pos);
+        }
+        assert_eq!(cache.visual_to_pos(branch, cache.num_rows(), 0), content.chars().count());
+    }
+
+    fn random_str(rng: &mut SmallRng) -> String {
+        let len = rng.gen_range(1..8);
+        (0..len).map(|_| *[ 'a', 'b', 'W', '界', '\n', ' '].choose(rng).unwrap()).collect()
+    }
+
+    fn random_edit(oplog: &mut ListOpLog, branch: &mut ListBranch, agent: usize, rng: &mut SmallRng) {
+        let len = branch.len();
+        if len == 0 || rng.gen_bool(0.6) {
+            let pos = rng.gen_range(0..=len);
+            branch.insert(oplog, agent as _, pos, &random_str(rng));
+        } else {
+            let start = rng.gen_range(0..len);
+            let end = rng.gen_range(start + 1..=len.min(start + 10));
+            branch.delete(oplog, agent as _, start..end);
+        }
+    }
+
+    #[test]
+    fn empty_document() {
+        let oplog = ListOpLog::new();
+        let branch = ListBranch::new();
+        let mut cache = LayoutCache::new(10);
+        cache.refresh(&oplog, &branch);
+        assert_eq!(cache.num_rows(), 1);
+        assert_eq!(cache.pos_to_visual(&branch, 0), (0, 0));
+        assert_eq!(cache.visual_to_pos(&branch, 5, 5), 0);
+    }
+
+    #[test]
+    fn wraps_lines() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = ListBranch::new();
+        branch.insert(&mut oplog, seph, 0, "abcdefgh\nxy");
+
+        let mut cache = LayoutCache::new(3);
+        cache.refresh(&oplog, &branch);
+        assert_eq!(cache.num_rows(), 4);
+        assert_eq!(cache.pos_to_visual(&branch, 2), (0, 2));
+        assert_eq!(cache.pos_to_visual(&branch, 3), (1, 0));
+        assert_eq!(cache.pos_to_visual(&branch, 8), (2, 2)); // The newline
+        assert_eq!(cache.pos_to_visual(&branch, 9), (3, 0));
+        assert_eq!(cache.visual_to_pos(&branch, 0, 100), 2);
+        assert_eq!(cache.visual_to_pos(&branch, 2, 100), 8);
+
+        // Edit straddling a wrap boundary.
+        branch.delete(&mut oplog, seph, 2..4);
+        cache.refresh(&oplog, &branch);
+        check(&cache, &branch);
+    }
+
+    #[test]
+    fn incremental_matches_naive() {
+        for seed in 0..40 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let mut oplog = ListOpLog::new();
+            oplog.get_or_create_agent_id("a");
+            oplog.get_or_create_agent_id("b");
+            let mut branch = ListBranch::new();
+            let mut other = ListBranch::new();
+
+            let mut cache = LayoutCache::with_char_width(rng.gen_range(1..8), TestWidth);
+            cache.refresh(&oplog, &branch);
+
+            for _i in 0..30 {
+                for _j in 0..rng.gen_range(1..4) {
+                    random_edit(&mut oplog, &mut branch, 0, &mut rng);
+                }
+
+                if rng.gen_bool(0.3) {
+                    // Concurrent changes from another peer.
+                    random_edit(&mut oplog, &mut other, 1, &mut rng);
+                    branch.merge(&oplog, oplog.local_version_ref());
+                }
+                if rng.gen_bool(0.1) {
+                    cache.set_width(rng.gen_range(1..8));
+                }
+
+                cache.refresh(&oplog, &branch);
+                check(&cache, &branch);
+            }
+        }
+    }
+}
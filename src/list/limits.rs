@@ -0,0 +1,357 @@
+//! Per-document quotas. Hosted services can cap how big a document is allowed to grow, and the
+//! oplog enforces the caps on every change - both local edits and data merged from remote peers.
+//! That way a misbehaving peer can't push a document past its limits by sending a big patch.
+//!
+//! The usage counters are maintained as changes are added, so checking them is cheap.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use rle::HasLength;
+use crate::{AgentId, LV};
+use crate::list::{EditError, ListBranch, ListOpLog};
+use crate::list::operation::{ListOpKind, TextOperation};
+
+/// Limits on how large a document can grow. See [`ListOpLog::set_limits`].
+///
+/// The limits on the document itself default to `usize::MAX` (unlimited). The limits on data
+/// waiting to be merged have finite defaults, since that data comes straight from peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocLimits {
+    /// The maximum total size (in bytes) of all content ever inserted into the document. Content
+    /// which is later deleted still counts.
+    pub max_inserted_bytes: usize,
+
+    /// The maximum number of operations (inserted + deleted characters) in the oplog.
+    pub max_operations: usize,
+
+    /// The maximum number of agents which can edit the document.
+    pub max_agents: usize,
+
+    /// The maximum number of patches kept by
+    /// [`merge_data_or_defer`](ListOpLog::merge_data_or_defer) while they wait for the operations
+    /// they depend on. Defaults to 1000.
+    pub max_pending_patches: usize,
+
+    /// The maximum total size (in bytes) of the patches kept by
+    /// [`merge_data_or_defer`](ListOpLog::merge_data_or_defer). Defaults to 64MB.
+    pub max_pending_bytes: usize,
+}
+
+impl Default for DocLimits {
+    fn default() -> Self {
+        Self {
+            max_inserted_bytes: usize::MAX,
+            max_operations: usize::MAX,
+            max_agents: usize::MAX,
+            max_pending_patches: 1000,
+            max_pending_bytes: 64 << 20,
+        }
+    }
+}
+
+/// The current size of a document, as counted by [`DocLimits`]. See [`ListOpLog::usage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DocUsage {
+    /// The total size of all content ever inserted into the document.
+    ///
+    /// If the oplog is missing some inserted content (because it was merged from a file which
+    /// didn't store it), each of those characters is counted as a single byte.
+    pub inserted_bytes: usize,
+
+    /// The number of operations in the oplog. This is the same as `oplog.len()`.
+    pub operations: usize,
+
+    /// The number of agents known to the oplog.
+    pub agents: usize,
+}
+
+/// A change was rejected because it would push the document past one of its [`DocLimits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    InsertedBytes,
+    Operations,
+    Agents,
+    /// Too much data is already waiting to be merged.
+    PendingData,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let what = match self {
+            LimitExceeded::InsertedBytes => "inserted content size",
+            LimitExceeded::Operations => "number of operations",
+            LimitExceeded::Agents => "number of agents",
+            LimitExceeded::PendingData => "data waiting to be merged",
+        };
+        write!(f, "Document limit exceeded: {what}")
+    }
+}
+
+impl Error for LimitExceeded {}
+
+impl DocLimits {
+    /// Check that the usage after a change is within these limits. Counters which didn't grow are
+    /// allowed past their limit, so lowering a limit doesn't stop peers from re-sending data the
+    /// document already has.
+    pub(crate) fn check_growth(&self, before: DocUsage, after: DocUsage) -> Result<(), LimitExceeded> {
+        let over = |limit: usize, before: usize, after: usize| after > limit && after > before;
+
+        if over(self.max_inserted_bytes, before.inserted_bytes, after.inserted_bytes) {
+            Err(LimitExceeded::InsertedBytes)
+        } else if over(self.max_operations, before.operations, after.operations) {
+            Err(LimitExceeded::Operations)
+        } else if over(self.max_agents, before.agents, after.agents) {
+            Err(LimitExceeded::Agents)
+        } else { Ok(()) }
+    }
+}
+
+/// The number of operations and inserted bytes in a list of operations.
+fn ops_size(ops: &[TextOperation]) -> (usize, usize) {
+    ops.iter().fold((0, 0), |(len, bytes), op| {
+        let op_bytes = match (op.kind, op.content_as_str()) {
+            (ListOpKind::Ins, Some(content)) => content.len(),
+            (ListOpKind::Ins, None) => op.len(),
+            (ListOpKind::Del, _) => 0,
+        };
+        (len + op.len(), bytes + op_bytes)
+    })
+}
+
+/// Used by the infallible methods for adding local changes, which panic if the change would
+/// exceed the oplog's limits.
+pub(crate) fn assert_within_limits(result: Result<(), LimitExceeded>) {
+    if let Err(e) = result {
+        panic!("Cannot add change to oplog: {e}");
+    }
+}
+
+impl ListOpLog {
+    /// Set the limits on how big this document can grow.
+    ///
+    /// The limits are checked whenever changes are added to the oplog:
+    ///
+    /// - Data merged with [`decode_and_add`](ListOpLog::decode_and_add) which would exceed a limit
+    ///   is rejected with `ParseErrorKind::LimitExceeded`, and none of it is added.
+    /// - The `try_` methods (like [`try_add_operations_at`](ListOpLog::try_add_operations_at) and
+    ///   [`ListBranch::try_insert`]) return an error without modifying anything.
+    /// - The other methods for adding local changes panic if the change would exceed a limit.
+    ///
+    /// Limits aren't stored in the oplog's encoded data. Set them again after loading.
+    pub fn set_limits(&mut self, limits: DocLimits) {
+        self.limits = limits;
+    }
+
+    /// Get the limits set with [`set_limits`](ListOpLog::set_limits).
+    pub fn limits(&self) -> DocLimits {
+        self.limits
+    }
+
+    /// Get the current size of the document, as counted by its limits. This is cheap to call.
+    ///
+    /// When the oplog is encoded without its inserted content, the inserted content size is
+    /// stored in the file instead. So this survives a round trip through
+    /// [`encode`](ListOpLog::encode) and [`load_from`](ListOpLog::load_from) either way.
+    pub fn usage(&self) -> DocUsage {
+        DocUsage {
+            inserted_bytes: self.inserted_bytes,
+            operations: self.len(),
+            agents: self.cg.agent_assignment.client_data.len(),
+        }
+    }
+
+    /// Check if a local change of `len` operations by the specified agent, inserting
+    /// `inserted_bytes` of content, would exceed the oplog's limits.
+    pub(crate) fn check_local_change(&self, agent: AgentId, len: usize, inserted_bytes: usize) -> Result<(), LimitExceeded> {
+        // Agents are numbered from 0, so this only lets the first max_agents agents make changes.
+        if agent as usize >= self.limits.max_agents {
+            return Err(LimitExceeded::Agents);
+        }
+
+        let before = self.usage();
+        self.limits.check_growth(before, DocUsage {
+            inserted_bytes: before.inserted_bytes.saturating_add(inserted_bytes),
+            operations: before.operations.saturating_add(len),
+            ..before
+        })
+    }
+
+    pub(crate) fn check_local_ops(&self, agent: AgentId, ops: &[TextOperation]) -> Result<(), LimitExceeded> {
+        let (len, bytes) = ops_size(ops);
+        self.check_local_change(agent, len, bytes)
+    }
+
+    /// Like [`add_operations_at`](ListOpLog::add_operations_at), but returns an error instead of
+    /// panicking if the operations would exceed the oplog's limits.
+    pub fn try_add_operations_at(&mut self, agent: AgentId, parents: &[LV], ops: &[TextOperation]) -> Result<LV, LimitExceeded> {
+        self.check_local_ops(agent, ops)?;
+        Ok(self.add_operations_at(agent, parents, ops))
+    }
+
+    /// Like [`add_operations`](ListOpLog::add_operations), but returns an error instead of
+    /// panicking if the operations would exceed the oplog's limits.
+    pub fn try_add_operations(&mut self, agent: AgentId, ops: &[TextOperation]) -> Result<LV, LimitExceeded> {
+        self.check_local_ops(agent, ops)?;
+        Ok(self.add_operations(agent, ops))
+    }
+}
+
+impl ListBranch {
+    /// Like [`apply_local_operations`](ListBranch::apply_local_operations), but returns an error
+    /// instead of panicking if the branch is read-only or the operations would exceed the oplog's
+    /// limits. When an error is returned, neither the branch nor the oplog are modified.
+    pub fn try_apply_local_operations(&mut self, oplog: &mut ListOpLog, agent: AgentId, ops: &[TextOperation]) -> Result<LV, EditError> {
+        if self.read_only { return Err(EditError::BranchReadOnly); }
+        oplog.check_local_ops(agent, ops)?;
+        Ok(self.apply_local_operations(oplog, agent, ops))
+    }
+
+    /// Like [`insert`](ListBranch::insert), but returns an error instead of panicking if the
+    /// branch is read-only or the insert would exceed the oplog's limits.
+    pub fn try_insert(&mut self, oplog: &mut ListOpLog, agent: AgentId, pos: usize, ins_content: &str) -> Result<LV, EditError> {
+        self.try_apply_local_operations(oplog, agent, &[TextOperation::new_insert(pos, ins_content)])
+    }
+
+    /// Like [`delete`](ListBranch::delete), but returns an error instead of panicking if the
+    /// branch is read-only or the delete would exceed the oplog's limits.
+    pub fn try_delete(&mut self, oplog: &mut ListOpLog, agent: AgentId, del_span: Range<usize>) -> Result<LV, EditError> {
+        let op = self.make_delete_op(del_span);
+        self.try_apply_local_operations(oplog, agent, &[op])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::{EditError, ListBranch, ListOpLog};
+    use crate::list::encoding::{ENCODE_FULL, EncodeOptions};
+    use crate::list::operation::TextOperation;
+    use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+    use super::{DocLimits, DocUsage, LimitExceeded};
+
+    fn limits(max_inserted_bytes: usize, max_operations: usize, max_agents: usize) -> DocLimits {
+        DocLimits { max_inserted_bytes, max_operations, max_agents, ..Default::default() }
+    }
+
+    #[test]
+    fn usage_is_counted() {
+        let mut oplog = ListOpLog::new();
+        assert_eq!(oplog.usage(), DocUsage::default());
+
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let v = oplog.add_insert(seph, 0, "hi there");
+        oplog.add_insert_at(mike, &[v], 2, "! ツ");
+        oplog.add_delete_without_content(seph, 0..3);
+
+        assert_eq!(oplog.usage(), DocUsage {
+            inserted_bytes: 8 + 5,
+            operations: 8 + 3 + 3,
+            agents: 2,
+        });
+    }
+
+    #[test]
+    fn oversized_patch_is_rejected_atomically() {
+        let mut source = ListOpLog::new();
+        let seph = source.get_or_create_agent_id("seph");
+        let v1 = source.add_insert(seph, 0, "0123456789");
+        let first = source.encode(ENCODE_FULL);
+        let mike = source.get_or_create_agent_id("mike");
+        source.add_insert(mike, 10, "abcdefghij");
+        let second = source.encode_from(ENCODE_FULL, &[v1]);
+
+        let mut oplog = ListOpLog::new();
+        oplog.set_limits(limits(15, usize::MAX, usize::MAX));
+        oplog.decode_and_add(&first).unwrap();
+
+        // The second patch would take the document to 20 bytes.
+        let before = oplog.clone();
+        assert_eq!(oplog.decode_and_add(&second).unwrap_err(),
+                   ParseErrorKind::LimitExceeded(LimitExceeded::InsertedBytes));
+        assert_eq!(oplog, before);
+        assert_eq!(oplog.usage(), before.usage());
+        assert_eq!(oplog.checkout_tip().content(), "0123456789");
+
+        // The same goes for the number of agents and operations.
+        oplog.set_limits(limits(usize::MAX, usize::MAX, 1));
+        assert_eq!(oplog.decode_and_add(&second).unwrap_err(),
+                   ParseErrorKind::LimitExceeded(LimitExceeded::Agents));
+        oplog.set_limits(limits(usize::MAX, 19, usize::MAX));
+        assert_eq!(oplog.decode_and_add(&second).unwrap_err(),
+                   ParseErrorKind::LimitExceeded(LimitExceeded::Operations));
+        assert_eq!(oplog, before);
+
+        oplog.set_limits(limits(20, 20, 2));
+        oplog.decode_and_add(&second).unwrap();
+        assert_eq!(oplog, source);
+    }
+
+    #[test]
+    fn known_data_merges_past_lowered_limits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+
+        oplog.set_limits(limits(1, 1, 0));
+        let data = oplog.encode(ENCODE_FULL);
+        oplog.decode_and_add(&data).unwrap();
+    }
+
+    #[test]
+    fn local_edits_hit_limits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.set_limits(limits(10, 5, 1));
+
+        let mut branch = ListBranch::new();
+        branch.try_insert(&mut oplog, seph, 0, "ツツツ").unwrap(); // 9 bytes, 3 operations.
+        let before = (oplog.clone(), branch.clone());
+
+        assert_eq!(branch.try_insert(&mut oplog, seph, 0, "ab"), Err(LimitExceeded::InsertedBytes.into()));
+        assert_eq!(branch.try_insert(&mut oplog, mike, 0, "a"), Err(LimitExceeded::Agents.into()));
+        assert_eq!(branch.try_delete(&mut oplog, seph, 0..3), Err(LimitExceeded::Operations.into()));
+        assert_eq!(oplog.try_add_operations(mike, &[TextOperation::new_delete(0..1)]),
+                   Err(LimitExceeded::Agents));
+        assert_eq!(oplog.try_add_operations_at(seph, &[], &[TextOperation::new_insert(0, "ab")]),
+                   Err(LimitExceeded::InsertedBytes));
+        assert_eq!((oplog.clone(), branch.clone()), before);
+
+        // Deletes don't add any content.
+        branch.try_delete(&mut oplog, seph, 0..2).unwrap();
+        assert_eq!(branch.content(), "ツ");
+        assert_eq!(oplog.usage(), DocUsage { inserted_bytes: 9, operations: 5, agents: 2 });
+    }
+
+    #[test]
+    #[should_panic]
+    fn infallible_methods_panic_past_limits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.set_limits(limits(usize::MAX, 5, usize::MAX));
+        let mut branch = ListBranch::new();
+        branch.insert(&mut oplog, seph, 0, "hi there");
+    }
+
+    #[test]
+    fn usage_survives_encode_decode() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let v = oplog.add_insert(seph, 0, "héllo ツ");
+        oplog.add_insert_at(mike, &[v], 0, "€€");
+        oplog.add_delete_without_content(seph, 1..4);
+        let usage = oplog.usage();
+        assert_eq!(usage.inserted_bytes, 10 + 6);
+
+        for store_inserted_content in [true, false] {
+            let data = oplog.encode(EncodeOptions {
+                store_inserted_content,
+                ..ENCODE_FULL
+            });
+            let loaded = ListOpLog::load_from(&data).unwrap();
+            assert_eq!(loaded.usage(), usage);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::ops::Range;
 use humansize::{BINARY, format_size};
+use jumprope::JumpRopeBuf;
 use crate::list::{ListBranch, ListCRDT, ListOpLog};
 use crate::{AgentId, Frontier, LV};
 use rle::HasLength;
@@ -8,6 +9,9 @@ use crate::list::operation::{ListOpKind, TextOperation};
 use crate::dtrange::DTRange;
 use crate::encoding::parseerror::ParseError;
 use crate::unicount::count_chars;
+use crate::causalgraph::agent_assignment::remote_ids::RemoteFrontier;
+use crate::list::limits::assert_within_limits;
+use crate::list::branch::assert_editable;
 
 // For local changes to a branch, we take the checkout's frontier as the new parents list.
 fn insert_history_local(oplog: &mut ListOpLog, frontier: &mut Frontier, range: DTRange) {
@@ -45,6 +49,8 @@ fn insert_history_local(oplog: &mut ListOpLog, frontier: &mut Frontier, range: D
 ///
 /// (I low key hate the duplicated code though.)
 pub(crate) fn apply_local_operations(oplog: &mut ListOpLog, branch: &mut ListBranch, agent: AgentId, local_ops: &[TextOperation]) -> LV {
+    assert_editable(branch);
+    assert_within_limits(oplog.check_local_ops(agent, local_ops));
     let first_time = oplog.len();
     let mut next_time = first_time;
 
@@ -88,9 +94,11 @@ pub(crate) fn apply_local_operations(oplog: &mut ListOpLog, branch: &mut ListBra
 // These methods exist to make benchmark numbers better. I'm the worst!
 
 fn internal_do_insert(oplog: &mut ListOpLog, branch: &mut ListBranch, agent: AgentId, pos: usize, content: &str) -> LV {
-    let start = oplog.len();
-
     let len = count_chars(content);
+    assert_editable(branch);
+    assert_within_limits(oplog.check_local_change(agent, len, content.len()));
+
+    let start = oplog.len();
 
     branch.content.insert(pos, content);
 
@@ -114,6 +122,8 @@ fn internal_do_insert(oplog: &mut ListOpLog, branch: &mut ListBranch, agent: Age
 }
 
 fn internal_do_delete(oplog: &mut ListOpLog, branch: &mut ListBranch, agent: AgentId, pos: DTRange) -> LV {
+    assert_editable(branch);
+    assert_within_limits(oplog.check_local_change(agent, pos.len(), 0));
     let start = oplog.len();
 
     branch.content.remove(pos.into());
@@ -149,11 +159,12 @@ impl ListCRDT {
         }
     }
 
+    /// Load a document from a binary chunk, and check out its content from the operations.
     pub fn load_from(bytes: &[u8]) -> Result<Self, ParseError> {
         let oplog = ListOpLog::load_from(bytes)?;
-        let branch = oplog.checkout_tip();
         Ok(Self {
-            branch, oplog
+            branch: oplog.checkout_tip(),
+            oplog
         })
     }
 
@@ -163,6 +174,23 @@ impl ListCRDT {
         Ok(v)
     }
 
+    /// Return the version of the document's branch as a `&[usize]`. This is the version of the
+    /// document's content, which may lag behind the oplog if changes were merged into the oplog
+    /// directly.
+    pub fn local_version_ref(&self) -> &[LV] {
+        self.branch.local_version_ref()
+    }
+
+    /// Return the version of the document's branch.
+    pub fn local_version(&self) -> Frontier {
+        self.branch.local_version()
+    }
+
+    /// Return the version of the document's branch in remote form.
+    pub fn remote_version(&self) -> RemoteFrontier<'_> {
+        self.branch.remote_version(&self.oplog)
+    }
+
     pub fn len(&self) -> usize {
         self.branch.len()
     }
@@ -244,4 +272,25 @@ mod tests {
 
         doc.oplog.dbg_print_all();
     }
+
+    #[test]
+    fn version_accessors_agree() {
+        let mut doc = ListCRDT::new();
+        assert!(doc.local_version().is_root());
+        assert!(doc.remote_version().is_empty());
+
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "hi");
+        assert_eq!(doc.local_version_ref(), &[1]);
+        assert_eq!(doc.local_version(), doc.oplog.local_version());
+        assert_eq!(doc.local_version_ref(), doc.branch.local_version_ref());
+        assert_eq!(doc.remote_version(), doc.oplog.remote_version());
+        assert_eq!(doc.remote_version(), doc.branch.remote_version(&doc.oplog));
+
+        // Changes added straight to the oplog don't show up in the document's version until
+        // they're merged.
+        doc.oplog.add_insert(seph, 2, "!");
+        assert_eq!(doc.local_version_ref(), &[1]);
+        assert_eq!(doc.oplog.local_version_ref(), &[2]);
+    }
 }
\ No newline at end of file
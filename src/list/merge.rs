@@ -1,6 +1,6 @@
 use rle::HasLength;
 use crate::frontier::FrontierRef;
-use crate::list::{ListBranch, ListOpLog};
+use crate::list::{EditError, ListBranch, ListOpLog};
 use crate::list::operation::{ListOpKind, TextOperation};
 use crate::listmerge::merge::{reverse_str, TransformedOpsIter};
 use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
@@ -50,22 +50,38 @@ impl ListOpLog {
 
 
 impl ListBranch {
+    /// Returns true if every version named by the branch and by `merge_frontier` is in `oplog`.
+    pub(crate) fn versions_in(&self, oplog: &ListOpLog, merge_frontier: &[LV]) -> bool {
+        let len = oplog.len();
+        self.version.iter().chain(merge_frontier.iter()).all(|&v| v < len)
+    }
+
     /// Add everything in merge_frontier into the set..
+    ///
+    /// The branch doesn't cache anything about the oplog (like its agents or length), so it's fine
+    /// to merge after the oplog has grown, including with operations from new agents added by
+    /// [`merge_data`](ListOpLog::merge_data). But the branch must have come from this oplog.
+    ///
+    /// In debug builds this panics if the branch's version or `merge_frontier` isn't in the oplog.
+    /// Use [`try_merge`](ListBranch::try_merge) to check.
     pub fn merge(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) {
+        debug_assert!(self.versions_in(oplog, merge_frontier),
+            "Branch version {:?} or merge frontier {:?} is not in the oplog (length {})",
+            self.version, merge_frontier, oplog.len());
         let mut iter = oplog.get_xf_operations_full(self.version.as_ref(), merge_frontier);
 
         for (_lv, origin_op, xf) in &mut iter {
             match (origin_op.kind, xf) {
                 (ListOpKind::Ins, BaseMoved(pos)) => {
                     // println!("Insert '{}' at {} (len {})", op.content, ins_pos, op.len());
-                    debug_assert!(origin_op.content_pos.is_some()); // Ok if this is false - we'll just fill with junk.
-                    let content = origin_op.get_content(&oplog.operation_ctx).unwrap();
+                    // Content can be missing (eg if it was redacted). Its filled with replacement characters.
+                    let content = origin_op.get_content_or_replacement(&oplog.operation_ctx);
                     assert!(pos <= self.content.len_chars());
                     if origin_op.loc.fwd {
-                        self.content.insert(pos, content);
+                        self.content.insert(pos, &content);
                     } else {
                         // We need to insert the content in reverse order.
-                        let c = reverse_str(content);
+                        let c = reverse_str(&content);
                         self.content.insert(pos, &c);
                     }
                 }
@@ -83,6 +99,87 @@ impl ListBranch {
 
         // dbg!(iter.count_range_tracker_size());
         self.version = iter.into_frontier();
+
+        // A historical checkout which catches up to the oplog can be edited again.
+        if self.read_only && self.version == oplog.cg.version {
+            self.read_only = false;
+        }
+    }
+
+    /// Like [`merge`](ListBranch::merge), but returns [`EditError::UnknownVersion`] instead of
+    /// panicking (or worse) if the branch's version or `merge_frontier` isn't in the oplog. When an
+    /// error is returned, the branch is not modified.
+    ///
+    /// If the oplog's history was collapsed with [`gc_before`](ListOpLog::gc_before), merging
+    /// from (or into) a version before that point returns [`EditError::VersionCollected`].
+    pub fn try_merge(&mut self, oplog: &ListOpLog, merge_frontier: &[LV]) -> Result<(), EditError> {
+        if !self.versions_in(oplog, merge_frontier) { return Err(EditError::UnknownVersion); }
+        // An empty branch is the start of a checkout, which replays the collapsed baseline.
+        let from = if self.version.is_empty() { merge_frontier } else { self.version.as_ref() };
+        if !oplog.can_checkout(from) { return Err(EditError::VersionCollected); }
+        self.merge(oplog, merge_frontier);
+        Ok(())
+    }
+
+    /// Move the branch to `version`, reusing its current content. Operations in `version` which
+    /// the branch is missing are merged in, and operations the branch has which aren't in
+    /// `version` are reverted. When the branch is only moving forward, this only applies the new
+    /// operations - which is much faster than checking out `version` again on a big document.
+    ///
+    /// Reverting a delete needs the deleted content. If the oplog doesn't store it (or the history
+    /// was collapsed with [`gc_before`](ListOpLog::gc_before)), this falls back to a full
+    /// checkout.
+    ///
+    /// Like [`checkout`](ListOpLog::checkout), the branch ends up read-only unless `version` is
+    /// the oplog's tip, and this panics if `version` is from before history was collapsed.
+    pub fn advance_to(&mut self, oplog: &ListOpLog, version: &[LV]) {
+        debug_assert!(self.versions_in(oplog, version),
+            "Branch version {:?} or target {:?} is not in the oplog (length {})",
+            self.version, version, oplog.len());
+
+        let common = oplog.cg.graph.common_ancestor(self.version.as_ref(), version);
+        if !oplog.can_checkout(common.as_ref()) || !self.retreat_to(oplog, common.as_ref()) {
+            *self = oplog.checkout(version);
+            return;
+        }
+
+        self.merge(oplog, version);
+        self.read_only = self.version != oplog.cg.version;
+    }
+
+    /// Revert the operations in the branch which aren't in `version`, which must be contained by
+    /// the branch's version. Returns false (leaving the branch untouched) if some of the deleted
+    /// content needed to do so isn't stored.
+    fn retreat_to(&mut self, oplog: &ListOpLog, version: &[LV]) -> bool {
+        if self.version.as_ref() == version { return true; }
+
+        // These are the operations which took the document from `version` to the branch's
+        // version. Applying their inverses backwards goes the other way.
+        let mut ops = Vec::new();
+        for (_, op) in oplog.iter_xf_operations_from(version, self.version.as_ref()) {
+            let Some(op) = op else { continue; }; // The delete already happened.
+            if op.kind == ListOpKind::Del && op.content.is_none() { return false; }
+            ops.push(op);
+        }
+
+        for op in ops.iter().rev() {
+            match op.kind {
+                ListOpKind::Ins => {
+                    self.content.remove(op.loc.span.into());
+                }
+                ListOpKind::Del => {
+                    let content = op.content.as_ref().unwrap();
+                    if op.loc.fwd {
+                        self.content.insert(op.loc.span.start, content);
+                    } else {
+                        self.content.insert(op.loc.span.start, &reverse_str(content));
+                    }
+                }
+            }
+        }
+
+        self.version = version.into();
+        true
     }
 
 }
\ No newline at end of file
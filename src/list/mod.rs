@@ -6,11 +6,15 @@
 //! more data types will be added over time.
 
 use smartstring::alias::String as SmartString;
+use std::collections::BTreeMap;
+use rle::RleRun;
 
 use crate::list::operation::ListOpKind;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
-use crate::{CausalGraph, Frontier};
+use crate::{CausalGraph, Frontier, LV};
 use crate::rle::{KVPair, RleVec};
+use crate::list::limits::{DocLimits, LimitExceeded};
+use crate::dtrange::DTRange;
 
 pub mod operation;
 mod list;
@@ -24,9 +28,30 @@ pub mod encoding;
 pub mod op_metrics;
 mod eq;
 mod oplog_merge;
+pub mod compat;
+pub mod layout;
+pub mod edit_stats;
+pub mod render;
+pub mod limits;
+mod bisect;
+pub mod attribution;
+pub mod summary;
+pub mod redact;
+pub mod gc;
+pub mod checkpoint;
+mod timestamps;
+pub mod origin;
+pub mod cursor;
+pub mod preview;
+pub mod sync;
+pub mod server;
+mod undo;
 
-#[cfg(test)]
-mod old_fuzzer_tools;
+#[cfg(any(test, feature = "fuzzer_tools"))]
+pub(crate) mod old_fuzzer_tools;
+/// Random edits for building test oplogs outside this crate (eg in benchmarks).
+#[cfg(feature = "fuzzer_tools")]
+pub use old_fuzzer_tools::old_make_random_change_raw as make_random_change;
 #[cfg(test)]
 mod oplog_merge_fuzzer;
 
@@ -62,7 +87,11 @@ mod merge;
 /// Branches also provide a simple way to edit documents, via the [`insert`](Branch::insert) and
 /// [`delete`](Branch::delete) methods. These methods append new operations to the oplog, and modify
 /// the branch to contain the named changes.
-#[derive(Debug, Clone, Eq, PartialEq)]
+///
+/// Branches checked out at a historical version (anything other than the oplog's tip) are
+/// read-only, since editing them creates a new concurrent branch in the document's history. Call
+/// [`fork_editable`](ListBranch::fork_editable) if that's what you want.
+#[derive(Debug, Clone)]
 pub struct ListBranch {
     /// The version the branch is currently at. This is used to track which changes the branch has
     /// or has not locally merged.
@@ -73,6 +102,53 @@ pub struct ListBranch {
 
     /// The document's content.
     content: jumprope::JumpRopeBuf,
+
+    /// Set when the branch is checked out behind the oplog's tip. Local edits are refused until
+    /// the branch is forked or merged up to the tip. This isn't compared by `==`.
+    read_only: bool,
+
+    /// What [`undo`](ListBranch::undo) and [`redo`](ListBranch::redo) have done so far. This
+    /// isn't compared by `==`.
+    undo: undo::UndoHistory,
+}
+
+/// The error returned by the `try_` methods for editing a [`ListBranch`], like
+/// [`try_insert`](ListBranch::try_insert). When an error is returned, neither the branch nor the
+/// oplog are modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditError {
+    /// The branch was checked out at a historical version. See
+    /// [`fork_editable`](ListBranch::fork_editable).
+    BranchReadOnly,
+
+    /// The edit would push the document past the limits set with
+    /// [`ListOpLog::set_limits`].
+    LimitExceeded(LimitExceeded),
+
+    /// Undoing a delete needs the deleted content, but the oplog doesn't have it. See
+    /// [`ListBranch::undo`].
+    DeletedContentMissing,
+
+    /// The branch's version (or the version being merged) names operations which aren't in the
+    /// oplog. This happens when a branch is used with a different oplog than the one it was
+    /// checked out from. See [`ListBranch::try_merge`].
+    UnknownVersion,
+
+    /// A byte offset passed to [`ListBranch::insert_bytes`] or [`ListBranch::delete_bytes`] is
+    /// past the end of the document, or in the middle of a multi-byte character.
+    InvalidBytePosition,
+
+    /// A UTF-16 offset passed to [`ListBranch::wchar_pos_to_chars`] is past the end of the
+    /// document, or between the two halves of a surrogate pair.
+    InvalidWcharPosition,
+
+    /// The branch has changed since the [`MergePreview`](preview::MergePreview) was made. See
+    /// [`ListBranch::apply_preview`].
+    StalePreview,
+
+    /// The branch's version (or the version being merged) is from before history was collapsed
+    /// with [`ListOpLog::gc_before`], so its content is gone. See [`ListBranch::try_merge`].
+    VersionCollected,
 }
 
 /// An OpLog is a collection of Diamond Types operations, stored in a super fancy compact way. Each
@@ -108,6 +184,10 @@ pub struct ListOpLog {
     /// Optional - only used if you set it.
     doc_id: Option<SmartString>,
 
+    /// Opaque application data stored alongside the document. See
+    /// [`set_user_data`](ListOpLog::set_user_data).
+    user_data: Option<Vec<u8>>,
+
     pub cg: CausalGraph,
 
     /// This contains all content ever inserted into the document, in time order (not document
@@ -116,6 +196,37 @@ pub struct ListOpLog {
     // TODO: Replace me with a compact form of this data.
     pub(crate) operations: RleVec<KVPair<ListOpMetrics>>,
 
+    /// The maximum number of content bytes stored in a single operation run. Larger edits are
+    /// split into multiple consecutive runs. See [`set_max_run_bytes`](ListOpLog::set_max_run_bytes).
+    max_run_bytes: usize,
+
+    /// Quotas checked whenever changes are added. See [`set_limits`](ListOpLog::set_limits).
+    limits: DocLimits,
+
+    /// The total size of all inserted content, for [`usage`](ListOpLog::usage). Updated by
+    /// push_op_internal.
+    inserted_bytes: usize,
+
+    /// The operations which were merged in from remote peers. Everything else was made locally.
+    /// See [`origin_of`](ListOpLog::origin_of).
+    remote_spans: RleVec<DTRange>,
+
+    /// Received data which is waiting for operations we don't have yet. See
+    /// [`merge_data_or_defer`](ListOpLog::merge_data_or_defer).
+    pending: Vec<encoding::PendingPatch>,
+
+    /// History before this version has been collapsed by [`gc_before`](ListOpLog::gc_before), so
+    /// it can't be checked out.
+    gc_len: usize,
+
+    /// Messages for the checkpoints made with [`add_checkpoint`](ListOpLog::add_checkpoint),
+    /// keyed by version. Checkpoints are in the causal graph, but they have no operation.
+    checkpoints: BTreeMap<LV, String>,
+
+    /// Wall clock times for operations, set with [`set_timestamp`](ListOpLog::set_timestamp).
+    /// Operations without a timestamp are left out.
+    timestamps: RleVec<KVPair<RleRun<i64>>>,
+
     // /// This is the LocalVersion for the entire oplog. So, if you merged every change we store into
     // /// a branch, this is the version of that branch.
     // ///
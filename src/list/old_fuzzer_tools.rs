@@ -5,10 +5,36 @@ use smallvec::smallvec;
 use rle::MergeableIterator;
 use rle::zip::{rle_zip, rle_zip3};
 use crate::{AgentId, LV};
-use crate::list::{ListBranch, ListCRDT, ListOpLog};
-use crate::list_fuzzer_tools::random_str;
+use crate::list::{ListBranch, ListOpLog};
+#[cfg(test)]
+use crate::list::ListCRDT;
 
-fn old_make_random_change_raw(oplog: &mut ListOpLog, branch: &ListBranch, mut rope: Option<&mut JumpRope>, agent: AgentId, rng: &mut SmallRng) -> LV {
+const USE_UNICODE: bool = true;
+
+const UCHARS: [char; 23] = [
+    'a', 'b', 'c', '1', '2', '3', ' ', '\n', // ASCII
+    '©', '¥', '½', // The Latin-1 suppliment (U+80 - U+ff)
+    'Ύ', 'Δ', 'δ', 'Ϡ', // Greek (U+0370 - U+03FF)
+    '←', '↯', '↻', '⇈', // Arrows (U+2190 – U+21FF)
+    '𐆐', '𐆔', '𐆘', '𐆚', // Ancient roman symbols (U+10190 – U+101CF)
+];
+
+pub(crate) fn random_str(len: usize, rng: &mut SmallRng) -> String {
+    let mut str = String::new();
+    let alphabet: Vec<char> = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_".chars().collect();
+
+    for _ in 0..len {
+        let charset = if USE_UNICODE { &UCHARS[..] } else { &alphabet };
+        str.push(charset[rng.gen_range(0..charset.len())]);
+            // str.push(UCHARS[rng.gen_range(0..UCHARS.len())]);
+            // str.push(alphabet[rng.gen_range(0..alphabet.len())]);
+    }
+    str
+}
+
+/// Make a random insert or delete on the branch, and add it to the oplog. The branch isn't changed.
+/// If a rope is passed in, the same change is made to it too. Returns the version of the change.
+pub fn old_make_random_change_raw(oplog: &mut ListOpLog, branch: &ListBranch, mut rope: Option<&mut JumpRope>, agent: AgentId, rng: &mut SmallRng) -> LV {
     let doc_len = branch.len();
     let insert_weight = if doc_len < 100 { 0.55 } else { 0.45 };
     let v = if doc_len == 0 || rng.gen_bool(insert_weight) {
@@ -24,9 +50,9 @@ fn old_make_random_change_raw(oplog: &mut ListOpLog, branch: &ListBranch, mut ro
         }
 
         if fwd {
-            oplog.add_insert_at(agent, branch.version.as_ref(), pos, &content)
+            oplog.add_insert_at(agent, branch.local_version_ref(), pos, &content)
         } else {
-            let mut frontier = branch.version.clone();
+            let mut frontier = branch.local_version();
             for c in content.chars().rev() {
                 let mut buf = [0u8; 8]; // Not sure what the biggest utf8 char is but eh.
                 let str = c.encode_utf8(&mut buf);
@@ -55,10 +81,10 @@ fn old_make_random_change_raw(oplog: &mut ListOpLog, branch: &ListBranch, mut ro
         // I'm using this rather than push_delete to preserve the deleted content.
         if fwd {
             let op = branch.make_delete_op(del_loc);
-            oplog.add_operations_at(agent, branch.version.as_ref(), &[op])
+            oplog.add_operations_at(agent, branch.local_version_ref(), &[op])
         } else {
             // Backspace each character individually.
-            let mut frontier = branch.version.clone(); // Not the most elegant but eh.
+            let mut frontier = branch.local_version(); // Not the most elegant but eh.
             for i in del_loc.rev() {
                 // println!("Delete {}", pos + i);
                 let op = branch.make_delete_op(i .. i + 1);
@@ -70,10 +96,13 @@ fn old_make_random_change_raw(oplog: &mut ListOpLog, branch: &ListBranch, mut ro
         // doc.local_delete(agent, pos, span)
     };
     // dbg!(&doc.markers);
+    // This is O(n), so it's only done in tests. Benchmarks use this to build big oplogs.
+    #[cfg(test)]
     oplog.dbg_check(false);
     v
 }
 
+#[cfg(test)]
 pub(crate) fn old_make_random_change(doc: &mut ListCRDT, rope: Option<&mut JumpRope>, agent: AgentId, rng: &mut SmallRng) {
     let v = old_make_random_change_raw(&mut doc.oplog, &doc.branch, rope, agent, rng);
     doc.branch.merge(&doc.oplog, &[v]);
@@ -98,7 +98,7 @@ impl<'a> OpMetricsIter<'a> {
     }
 
     pub(crate) fn get_content(&self, metrics: &KVPair<ListOpMetrics>) -> Option<&'a str> {
-        metrics.1.content_pos.map(|pos| {
+        metrics.1.content_pos.and_then(|pos| {
             self.ctx.get_str(metrics.1.kind, pos)
         })
     }
@@ -170,6 +170,26 @@ impl ListOpLog {
             .map(|pair| (pair.0.1, pair.1).into())
     }
 
+    /// Find the operations which differ between two versions of the document. This returns
+    /// `(only_from, only_to)`:
+    ///
+    /// - `only_to` contains the operations in `to` which aren't in `from`. This is what a peer at
+    ///   `from` is missing.
+    /// - `only_from` contains the operations in `from` which aren't in `to`. This is only
+    ///   non-empty when the versions are concurrent.
+    ///
+    /// Operations are yielded in local version order, and their positions are relative to the
+    /// document at their own parents (not at `from`). Use
+    /// [`iter_xf_operations_from`](ListOpLog::iter_xf_operations_from) for transformed positions.
+    pub fn diff_versions(&self, from: &[LV], to: &[LV]) -> (impl Iterator<Item=TextOperation> + '_, impl Iterator<Item=TextOperation> + '_) {
+        let (only_from, only_to) = self.cg.graph.diff_rev(from, to);
+
+        (
+            OpIterRanges::new(self, only_from).map(|pair| (pair.0.1, pair.1).into()),
+            OpIterRanges::new(self, only_to).map(|pair| (pair.0.1, pair.1).into()),
+        )
+    }
+
     pub(crate) fn iter_fast(&self) -> OpMetricsWithContent {
         OpMetricsWithContent::new(self, (0..self.len()).into())
     }
@@ -232,8 +252,11 @@ impl ListOpLog {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::list::ListCRDT;
     use crate::list::operation::ListOpKind;
     use crate::rle::{KVPair, RleVec};
+    use crate::rev_range::RangeRev;
+    use crate::list::encoding::{ENCODE_FULL, EncodeOptions};
     use ListOpKind::*;
 
     #[test]
@@ -252,8 +275,8 @@ mod test {
         }));
 
         let ctx = ListOperationCtx {
-            ins_content: "0123456789".to_string().into_bytes(),
-            del_content: "".to_string().into_bytes()
+            ins_content: "0123456789".to_string().into_bytes().into(),
+            del_content: "".to_string().into_bytes().into()
         };
 
         assert_eq!(OpMetricsIter::new(&ops, &ctx, (0..30).into()).collect::<Vec<_>>(), ops.0.as_slice());
@@ -277,4 +300,115 @@ mod test {
             }),
         ]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn split_multibyte_content_everywhere() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "aé💖ñb"); // 0..5
+        doc.delete(seph, 1..3); // 5..7 deletes "é💖".
+        // Backspacing "b", "ñ" is a single reversed delete, with content "bñ".
+        doc.delete(seph, 2..3); // 7
+        doc.delete(seph, 1..2); // 8
+        doc.insert(seph, 1, "ü€"); // 9..11
+        let oplog = &doc.oplog;
+        assert_eq!(oplog.check_content_alignment(), Ok(()));
+
+        // The content of each version, as it was stored for the whole operation.
+        let mut chars = vec![];
+        for (KVPair(lv, op), content) in oplog.iter_fast() {
+            chars.extend(content.unwrap().chars());
+            assert_eq!(chars.len(), lv + op.len());
+        }
+
+        for start in 0..oplog.len() {
+            for end in start + 1..=oplog.len() {
+                let mut v = start;
+                for (KVPair(lv, op), content) in oplog.iter_range_simple((start..end).into()) {
+                    assert_eq!(lv, v);
+                    let expected: String = chars[lv..lv + op.len()].iter().collect();
+                    assert_eq!(content, Some(expected.as_str()), "{start}..{end}");
+                    v += op.len();
+                }
+                assert_eq!(v, end);
+            }
+        }
+    }
+
+    #[test]
+    fn misaligned_content_is_treated_as_missing() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "héllo");
+        // Point the insert's content at the middle of the "é".
+        oplog.operations.0[0].1.content_pos = Some((2..6).into());
+        assert_eq!(oplog.check_content_alignment(), Err(0));
+
+        assert_eq!(oplog.iter_fast().next().unwrap().1, None);
+        // Splitting the operation drops the content rather than splitting mid-character.
+        for (_, content) in oplog.iter_range_simple((1..3).into()) {
+            assert_eq!(content, None);
+        }
+        assert_eq!(oplog.iter().next().unwrap().content, None);
+
+        // Checking out the document fills in the missing characters.
+        assert_eq!(oplog.checkout_tip().content(), "\u{FFFD}".repeat(5).as_str());
+    }
+
+    #[test]
+    fn diff_concurrent_versions() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let base = oplog.add_insert(seph, 0, "hi there");
+        let a = oplog.add_insert_at(seph, &[base], 2, " you");
+        let b = oplog.add_delete_at(mike, &[base], 0..3);
+        let b = oplog.add_insert_at(mike, &[b], 0, "yo");
+
+        let (only_from, only_to) = oplog.diff_versions(&[a], &[b]);
+        assert_eq!(only_from.collect::<Vec<_>>(), vec![TextOperation::new_insert(2, " you")]);
+        assert_eq!(only_to.collect::<Vec<_>>(), vec![
+            TextOperation::new_delete(0..3),
+            TextOperation::new_insert(0, "yo"),
+        ]);
+
+        // Going forwards, nothing is only in the old version.
+        let (only_from, only_to) = oplog.diff_versions(&[base], &[a, b]);
+        assert_eq!(only_from.count(), 0);
+        assert_eq!(only_to.collect::<Vec<_>>(), oplog.iter_range_since(&[base]).collect::<Vec<_>>());
+
+        let (only_from, only_to) = oplog.diff_versions(&[a], &[a]);
+        assert_eq!((only_from.count(), only_to.count()), (0, 0));
+    }
+
+    #[test]
+    fn deleted_content_is_read_from_files() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "hi there");
+        doc.delete(seph, 2..8);
+        // Backspacing "i" then "h".
+        doc.delete(seph, 1..2);
+        doc.delete(seph, 0..1);
+
+        let expected = vec![
+            TextOperation::new_insert(0, "hi there"),
+            TextOperation::new_delete_with_content(2, " there".into()),
+            TextOperation { loc: RangeRev { span: (0..2).into(), fwd: false }, kind: Del, content: Some("ih".into()) },
+        ];
+        assert_eq!(doc.oplog.iter().collect::<Vec<_>>(), expected);
+
+        let opts = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+        let loaded = ListOpLog::load_from(&doc.oplog.encode(opts)).unwrap();
+        assert_eq!(loaded.iter().collect::<Vec<_>>(), expected);
+        let xf_deletes: Vec<_> = loaded.iter_xf_operations()
+            .filter_map(|(_, op)| op.filter(|op| op.kind == Del))
+            .map(|op| op.content)
+            .collect();
+        assert_eq!(xf_deletes, [Some(" there".into()), Some("ih".into())]);
+
+        // Without the deleted content, deletes have none.
+        let loaded = ListOpLog::load_from(&doc.oplog.encode(ENCODE_FULL)).unwrap();
+        assert!(loaded.iter().filter(|op| op.kind == Del).all(|op| op.content.is_none()));
+    }
+}
@@ -1,4 +1,7 @@
+use std::borrow::Cow;
 use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
 use rle::{HasLength, MergableSpan, SplitableSpan, SplitableSpanCtx};
 use crate::list::operation::{ListOpKind, TextOperation};
 use crate::list::operation::ListOpKind::*;
@@ -52,16 +55,48 @@ impl ListOpMetrics {
         self.loc.span.end
     }
 
+    /// Get the content of the operation, if it's known. This also returns `None` if the content
+    /// position doesn't line up with character boundaries. That should never happen, but if it
+    /// does the content is treated as missing rather than panicking.
     pub(crate) fn get_content<'a>(&self, ctx: &'a ListOperationCtx) -> Option<&'a str> {
-        self.content_pos.map(|span| {
+        self.content_pos.and_then(|span| {
             ctx.get_str(self.kind, span)
         })
     }
 
+    /// Get the content of the operation for inserting it into a document. Missing content is
+    /// replaced with U+FFFD characters, so the document still ends up the right length.
+    pub(crate) fn get_content_or_replacement<'a>(&self, ctx: &'a ListOperationCtx) -> Cow<'a, str> {
+        match self.get_content(ctx) {
+            Some(content) => Cow::Borrowed(content),
+            None => Cow::Owned(char::REPLACEMENT_CHARACTER.to_string().repeat(self.len())),
+        }
+    }
+
     pub(crate) fn to_operation(&self, ctx: &ListOperationCtx) -> TextOperation {
         let content = self.get_content(ctx);
         (self, content).into()
     }
+
+    /// The merge code only understands forward inserts. A reversed insert (where each character is
+    /// typed in front of the previous one) is equivalent to a run of single character inserts at
+    /// the same position, so this splits it up into those. Content stays in chronological order.
+    ///
+    /// Any other operation is returned as-is.
+    pub(crate) fn split_reversed_insert(mut self, ctx: &ListOperationCtx) -> Vec<ListOpMetrics> {
+        if self.kind != Ins || self.loc.fwd { return vec![self]; }
+
+        let mut result = Vec::with_capacity(self.len());
+        while self.len() > 1 {
+            let rest = self.truncate_ctx(1, ctx);
+            self.loc.fwd = true;
+            result.push(self);
+            self = rest;
+        }
+        self.loc.fwd = true;
+        result.push(self);
+        result
+    }
 }
 
 impl HasLength for ListOpMetrics {
@@ -70,11 +105,127 @@ impl HasLength for ListOpMetrics {
     }
 }
 
+/// The bytes backing one kind of content (inserted or deleted) in a [`ListOperationCtx`].
+///
+/// Content decoded from a buffer which is shared with the decoder - like the output of LZ4
+/// decompression, or data passed to [`ListOpLog::load_from_shared`](crate::list::ListOpLog::load_from_shared) -
+/// is referenced in place rather than copied. The first time anything else is appended, the
+/// referenced bytes are copied into an owned buffer (and the shared one is released).
+///
+/// The bytes are always valid UTF-8. Content is only ever appended as whole strings, and only
+/// truncated back to a length it had before. [`ListOperationCtx::get_str`] relies on this.
+#[derive(Clone)]
+pub(crate) enum ContentBuf {
+    Owned(Vec<u8>),
+    Shared { buf: Arc<Vec<u8>>, range: DTRange },
+}
+
+impl Default for ContentBuf {
+    fn default() -> Self { ContentBuf::Owned(Vec::new()) }
+}
+
+impl From<Vec<u8>> for ContentBuf {
+    fn from(v: Vec<u8>) -> Self { ContentBuf::Owned(v) }
+}
+
+impl Deref for ContentBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ContentBuf::Owned(v) => v,
+            ContentBuf::Shared { buf, range } => &buf[range.start..range.end],
+        }
+    }
+}
+
+impl PartialEq for ContentBuf {
+    fn eq(&self, other: &Self) -> bool { **self == **other }
+}
+impl Eq for ContentBuf {}
+
+#[cfg(feature = "serde")]
+impl Serialize for ContentBuf {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for ContentBuf {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // The bytes have to be checked, since they're read back as strings without checking.
+        let bytes = Vec::deserialize(deserializer)?;
+        String::from_utf8(bytes)
+            .map(|s| ContentBuf::Owned(s.into_bytes()))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl ContentBuf {
+    /// Copy shared content into an owned buffer, so it can be modified.
+    fn to_mut(&mut self) -> &mut Vec<u8> {
+        if let ContentBuf::Shared { .. } = self {
+            *self = ContentBuf::Owned(self.to_vec());
+        }
+        match self {
+            ContentBuf::Owned(v) => v,
+            ContentBuf::Shared { .. } => unreachable!(),
+        }
+    }
+
+    /// Truncate the content back to `len` bytes. This must be a length the content had before, so
+    /// it's on a character boundary.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        debug_assert!(len >= self.len() || (self[len] as i8) >= -0x40);
+        match self {
+            ContentBuf::Owned(v) => v.truncate(len),
+            ContentBuf::Shared { range, .. } if len > 0 => {
+                range.end = range.end.min(range.start + len);
+            }
+            ContentBuf::Shared { .. } => *self = ContentBuf::default(),
+        }
+    }
+
+    /// Append `s`, referencing it in place if its in one of the `shared` buffers and follows on
+    /// from what's already here.
+    fn extend(&mut self, s: &str, shared: &[Arc<Vec<u8>>]) {
+        let bytes = s.as_bytes();
+        if bytes.is_empty() { return; }
+        let addr = bytes.as_ptr() as usize;
+        // The offset of `bytes` in `buf`, if they're in it.
+        let offset_in = |buf: &Vec<u8>| {
+            addr.checked_sub(buf.as_ptr() as usize)
+                .filter(|&offset| offset + bytes.len() <= buf.len())
+        };
+
+        match self {
+            ContentBuf::Shared { buf, range } => {
+                if offset_in(buf) == Some(range.end) {
+                    range.end += bytes.len();
+                    return;
+                }
+            }
+            ContentBuf::Owned(v) if v.is_empty() => {
+                if let Some((buf, start)) = shared.iter()
+                    .find_map(|buf| offset_in(buf).map(|start| (buf, start)))
+                {
+                    *self = ContentBuf::Shared { buf: buf.clone(), range: (start..start + bytes.len()).into() };
+                    return;
+                }
+            }
+            ContentBuf::Owned(_) => {}
+        }
+
+        self.to_mut().extend_from_slice(bytes);
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub(crate) struct ListOperationCtx {
-    pub(crate) ins_content: Vec<u8>,
-    pub(crate) del_content: Vec<u8>,
+    pub(crate) ins_content: ContentBuf,
+    pub(crate) del_content: ContentBuf,
 }
 
 // Not using the derived Debug so we can from_utf8 the internal content.
@@ -92,13 +243,22 @@ impl Debug for ListOperationCtx {
 impl ListOperationCtx {
     pub fn new() -> Self {
         Self {
-            ins_content: Vec::new(),
-            del_content: Vec::new()
+            ins_content: ContentBuf::default(),
+            del_content: ContentBuf::default(),
         }
     }
 
-    pub(crate) fn get_str(&self, kind: ListOpKind, range: DTRange) -> &str {
-        unsafe { std::str::from_utf8_unchecked(&self.switch(kind)[range.start..range.end]) }
+    /// Get the stored content in the named byte range. Returns `None` if the range is out of
+    /// bounds or doesn't start and end on character boundaries.
+    pub(crate) fn get_str(&self, kind: ListOpKind, range: DTRange) -> Option<&str> {
+        let bytes = self.switch(kind);
+        // SAFETY: The stored bytes are always valid UTF-8 (see ContentBuf), so any range between
+        // character boundaries is too. The boundaries are checked here.
+        let is_boundary = |i: usize| i == bytes.len() || (i < bytes.len() && (bytes[i] as i8) >= -0x40);
+        if range.start > range.end || !is_boundary(range.start) || !is_boundary(range.end) {
+            return None;
+        }
+        Some(unsafe { std::str::from_utf8_unchecked(&bytes[range.start..range.end]) })
     }
 
     // pub(crate) fn switch_str(&self, kind: InsDelTag) -> &str {
@@ -110,14 +270,20 @@ impl ListOperationCtx {
         switch(kind, &self.ins_content, &self.del_content)
     }
 
-    pub(crate) fn switch_mut(&mut self, kind: ListOpKind) -> &mut Vec<u8> {
+    pub(crate) fn switch_mut(&mut self, kind: ListOpKind) -> &mut ContentBuf {
         switch(kind, &mut self.ins_content, &mut self.del_content)
     }
 
     pub(crate) fn push_str(&mut self, kind: ListOpKind, s: &str) -> DTRange {
+        self.push_str_shared(kind, s, &[])
+    }
+
+    /// Like [`push_str`](Self::push_str), but if `s` is part of one of the `shared` buffers it
+    /// may be referenced in place instead of being copied.
+    pub(crate) fn push_str_shared(&mut self, kind: ListOpKind, s: &str, shared: &[Arc<Vec<u8>>]) -> DTRange {
         let storage = self.switch_mut(kind);
         let start = storage.len();
-        storage.extend_from_slice(s.as_bytes());
+        storage.extend(s, shared);
         let end = storage.len();
 
         (start..end).into()
@@ -141,12 +307,24 @@ impl SplitableSpanCtx for ListOpMetrics {
             let byte_offset = if p.len() == self.loc.len() {
                 // If the string is all ASCII (guaranteed by equal lengths) then we don't need to
                 // calculate the byte offset.
-                at
+                Some(at)
             } else {
-                let content = ctx.get_str(self.kind, *p);
-                chars_to_bytes(content, at)
+                ctx.get_str(self.kind, *p).map(|content| {
+                    let offset = chars_to_bytes(content, at);
+                    debug_assert!(content.is_char_boundary(offset));
+                    offset
+                })
             };
-            Some(p.truncate(byte_offset))
+
+            match byte_offset {
+                Some(offset) => Some(p.truncate(offset)),
+                None => {
+                    // The content doesn't line up with its characters, so we can't tell where to
+                    // split it. Treat it as missing in both halves.
+                    self.content_pos = None;
+                    None
+                }
+            }
         } else { None };
 
         let loc = self.loc.truncate_tagged_span(self.kind, at);
@@ -294,7 +472,7 @@ impl MergableSpan for ListOpMetrics {
 
 #[cfg(test)]
 mod test {
-    use rle::{SplitableSpanCtx, test_splitable_methods_valid_ctx};
+    use rle::{MergableSpan, SplitableSpanCtx, test_splitable_methods_valid_ctx};
     use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
     use crate::list::operation::ListOpKind;
     use crate::dtrange::DTRange;
@@ -307,8 +485,8 @@ mod test {
             kind: ListOpKind::Ins,
             content_pos: Some((0..10).into()),
         }, &ListOperationCtx {
-            ins_content: "0123456789".as_bytes().to_owned(),
-            del_content: "".as_bytes().to_owned()
+            ins_content: "0123456789".as_bytes().to_owned().into(),
+            del_content: "".as_bytes().to_owned().into()
         });
 
         let s2 = "↯1↯3↯5↯7↯9";
@@ -317,8 +495,8 @@ mod test {
             kind: ListOpKind::Ins,
             content_pos: Some((0..s2.len()).into()),
         }, &ListOperationCtx {
-            ins_content: s2.as_bytes().to_owned(), // too easy? Maybe..
-            del_content: "".as_bytes().to_owned()
+            ins_content: s2.as_bytes().to_owned().into(), // too easy? Maybe..
+            del_content: "".as_bytes().to_owned().into()
         });
 
         // I can't test the other splitablespan variants like this because they don't support
@@ -336,8 +514,8 @@ mod test {
 
         // let rem = op.truncate(2, "abcde");
         let rem = op.truncate_ctx(2, &ListOperationCtx {
-            ins_content: "".as_bytes().to_owned(),
-            del_content: "abcde".as_bytes().to_owned()
+            ins_content: "".as_bytes().to_owned().into(),
+            del_content: "abcde".as_bytes().to_owned().into()
         });
 
         assert_eq!(op, ListOpMetrics {
@@ -355,12 +533,35 @@ mod test {
         dbg!(op, rem);
     }
 
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn content_pos_past_4gb() {
+        // Documents with years of history can store more than 4GB of content. Content positions
+        // are usize, so splitting and joining around the u32 boundary mustn't wrap. (ASCII content
+        // is split without looking at the content itself, so the context can be empty here.)
+        let start = u32::MAX as usize - 2;
+        let ctx = ListOperationCtx::new();
+        let mut op = ListOpMetrics {
+            loc: (10..15).into(),
+            kind: ListOpKind::Ins,
+            content_pos: Some((start..start + 5).into())
+        };
+
+        let rem = op.truncate_ctx(3, &ctx);
+        assert_eq!(op.content_pos, Some((start..u32::MAX as usize + 1).into()));
+        assert_eq!(rem.content_pos, Some((u32::MAX as usize + 1..start + 5).into()));
+
+        assert!(op.can_append(&rem));
+        op.append(rem);
+        assert_eq!(op.content_pos, Some((start..start + 5).into()));
+    }
+
     #[test]
     fn split_around_unicode() {
         // The ¥ symbol is a 2-byte encoding. And ↯ is 3 bytes.
         let ctx = ListOperationCtx {
-            ins_content: "¥123↯".as_bytes().to_owned(),
-            del_content: "¥123↯".as_bytes().to_owned()
+            ins_content: "¥123↯".as_bytes().to_owned().into(),
+            del_content: "¥123↯".as_bytes().to_owned().into()
         };
 
         let op = ListOpMetrics {
@@ -66,6 +66,10 @@ pub struct TextOperation {
     /// What content is being inserted or deleted. This is optional for deletes. (And eventually
     /// inserts too, though that code path isn't exercised and may for now cause panics in some
     /// cases).
+    ///
+    /// Operations read from an oplog have the deleted text if the oplog has it - that is, if it was
+    /// deleted locally, or loaded from a file encoded with `store_deleted_content`. Backspaced
+    /// (reversed) deletes list the characters in the order they were deleted.
     #[cfg_attr(feature = "serde", serde(default))]
     pub content: Option<SmartString>,
 }
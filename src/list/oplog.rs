@@ -1,16 +1,25 @@
+use std::collections::BTreeMap;
 use std::ops::Range;
+use std::sync::Arc;
 use rle::HasLength;
 use crate::{AgentId, Frontier, LV};
 use crate::list::{ListBranch, ListOpLog};
 use crate::causalgraph::graph::GraphEntrySimple;
+use crate::causalgraph::topo::TopoOrder;
 use crate::list::op_metrics::{ListOperationCtx, ListOpMetrics};
 use crate::list::operation::{TextOperation, ListOpKind};
-use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontier, RemoteVersionSpan};
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontier, RemoteVersion, RemoteVersionSpan, ResolveResult};
 use crate::dtrange::DTRange;
 use crate::causalgraph::agent_span::*;
 use crate::rev_range::RangeRev;
-use crate::rle::KVPair;
+use crate::rle::{KVPair, RleVec};
 use crate::unicount::count_chars;
+use rle::SplitableSpanCtx;
+use crate::list::limits::{assert_within_limits, DocLimits};
+use crate::list::origin::Origin;
+
+// The default for ListOpLog::max_run_bytes.
+const DEFAULT_MAX_RUN_BYTES: usize = 256 * 1024;
 
 impl Default for ListOpLog {
     fn default() -> Self {
@@ -22,16 +31,78 @@ impl ListOpLog {
     pub fn new() -> Self {
         Self {
             doc_id: None,
+            user_data: None,
             cg: Default::default(),
             operation_ctx: ListOperationCtx::new(),
             operations: Default::default(),
+            max_run_bytes: DEFAULT_MAX_RUN_BYTES,
+            limits: DocLimits::default(),
+            inserted_bytes: 0,
+            remote_spans: RleVec::new(),
+            pending: Vec::new(),
+            gc_len: 0,
+            checkpoints: BTreeMap::new(),
+            timestamps: RleVec::new(),
             // inserted_content: "".to_string(),
         }
     }
 
+    /// Set the maximum amount of content (in bytes) stored in a single operation run.
+    ///
+    /// Diamond types run-length encodes operations aggressively, so a single paste of a huge
+    /// string would normally become one enormous run. Consumers which convert runs into owned
+    /// values (like the wasm bindings) then need a contiguous buffer of that size. Edits with more
+    /// content than this are transparently split into multiple consecutive runs. They still share
+    /// a single history entry, and the split has no effect on the document, its versions or the
+    /// encoded output.
+    ///
+    /// Split points always fall on unicode character boundaries. (So a run can only exceed the
+    /// limit if the limit is smaller than a single character.)
+    ///
+    /// The limit defaults to 256kb, and applies to edits added after this is called. Pass
+    /// `usize::MAX` to disable it.
+    pub fn set_max_run_bytes(&mut self, max_bytes: usize) {
+        self.max_run_bytes = max_bytes.max(1);
+    }
+
+    /// Get the maximum amount of content (in bytes) stored in a single operation run.
+    pub fn max_run_bytes(&self) -> usize {
+        self.max_run_bytes
+    }
+
+    /// Attach application data (eg a title or schema version) to the document. The data is opaque
+    /// to diamond types. It's saved when the oplog is encoded, and loaded back from the file.
+    ///
+    /// When data is merged in from a file with different user data, the local value is kept. The
+    /// file's value is returned by [`merge_data`](ListOpLog::merge_data).
+    pub fn set_user_data(&mut self, data: Vec<u8>) {
+        self.user_data = Some(data);
+    }
+
+    /// Get the data set with [`set_user_data`](ListOpLog::set_user_data) (or loaded from a file).
+    pub fn user_data(&self) -> Option<&[u8]> {
+        self.user_data.as_deref()
+    }
+
+    /// Remove the document's user data.
+    pub fn clear_user_data(&mut self) {
+        self.user_data = None;
+    }
+
+    /// Check out the document at the specified version.
+    ///
+    /// If the version isn't the oplog's tip, the returned branch is read-only. Editing it would
+    /// add changes concurrent with everything after `local_version`, so that has to be asked for
+    /// explicitly with [`fork_editable`](ListBranch::fork_editable).
+    ///
+    /// Panics if the version is from before history was collapsed with
+    /// [`gc_before`](ListOpLog::gc_before). See [`try_checkout`](ListOpLog::try_checkout).
     pub fn checkout(&self, local_version: &[LV]) -> ListBranch {
+        assert!(self.can_checkout(local_version),
+            "Cannot check out version {local_version:?}. History before {} was collapsed by gc_before", self.gc_len);
         let mut branch = ListBranch::new();
         branch.merge(self, local_version);
+        branch.read_only = branch.version != self.cg.version;
         branch
     }
 
@@ -157,21 +228,82 @@ impl ListOpLog {
     /// NOTE: This method is destructive on its own. It must be paired with assign_internal() or
     /// something like that.
     pub(crate) fn push_op_internal(&mut self, next_time: LV, loc: RangeRev, kind: ListOpKind, content: Option<&str>) {
+        self.push_op_internal_shared(next_time, loc, kind, content, &[]);
+    }
+
+    /// Like push_op_internal, but if the content is in one of the `shared` buffers it may be
+    /// referenced in place instead of copied. See [`ContentBuf`](crate::list::op_metrics::ContentBuf).
+    pub(crate) fn push_op_internal_shared(&mut self, next_time: LV, loc: RangeRev, kind: ListOpKind, content: Option<&str>, shared: &[Arc<Vec<u8>>]) {
         // next_time should almost always be self.len - except when loading, or modifying the data
         // in some complex way.
+        if kind == ListOpKind::Ins {
+            // If the content is missing, count each character as a single byte.
+            self.inserted_bytes += content.map_or(loc.len(), str::len);
+        }
+
         let content_pos = content.map(|c|
-            self.operation_ctx.push_str(kind, c)
+            self.operation_ctx.push_str_shared(kind, c, shared)
         );
         // let content_pos = if let Some(c) = content {
         //     Some(self.operation_ctx.push_str(kind, c))
         // } else { None };
 
-        // self.operations.push(KVPair(next_time, c.clone()));
-        self.operations.push(KVPair(next_time, ListOpMetrics {
+        let mut op = ListOpMetrics {
             loc,
             kind,
             content_pos
-        }));
+        };
+
+        if kind == ListOpKind::Ins && !loc.fwd {
+            // Each piece holds a single character, so there's no need to check max_run_bytes.
+            for (i, piece) in op.split_reversed_insert(&self.operation_ctx).into_iter().enumerate() {
+                self.push_op_metrics(next_time + i, piece);
+            }
+            return;
+        }
+
+        // Big edits get split into multiple runs. See set_max_run_bytes.
+        let mut next_time = next_time;
+        if let Some(mut content) = content {
+            while content.len() > self.max_run_bytes {
+                let mut split_byte = self.max_run_bytes;
+                while !content.is_char_boundary(split_byte) { split_byte -= 1; }
+                if split_byte == 0 {
+                    // The limit is smaller than the first character. Keep the whole character.
+                    split_byte = content.chars().next().unwrap().len_utf8();
+                    if split_byte == content.len() { break; }
+                }
+
+                let (here, rest) = content.split_at(split_byte);
+                let len = count_chars(here);
+                let remainder = op.truncate_ctx(len, &self.operation_ctx);
+                self.push_op_metrics(next_time, op);
+
+                op = remainder;
+                next_time += len;
+                content = rest;
+            }
+        }
+
+        // self.operations.push(KVPair(next_time, c.clone()));
+        self.push_op_metrics(next_time, op);
+    }
+
+    /// Push to self.operations, only merging with the previous run if the merged run would stay
+    /// within max_run_bytes.
+    fn push_op_metrics(&mut self, time: LV, op: ListOpMetrics) {
+        let fits = match (self.operations.last_entry(), op.content_pos) {
+            (Some(KVPair(_, last)), Some(pos)) => {
+                last.content_pos.map_or(0, |p| p.len()) + pos.len() <= self.max_run_bytes
+            }
+            _ => true,
+        };
+
+        if fits {
+            self.operations.push(KVPair(time, op));
+        } else {
+            self.operations.0.push(KVPair(time, op));
+        }
     }
 
     /// Push new operations to the opset. Operation parents specified by parents parameter.
@@ -179,6 +311,7 @@ impl ListOpLog {
     /// Returns the single item version after merging. (The resulting LocalVersion after calling
     /// this method will be `[time]`).
     fn add_operations_local(&mut self, agent: AgentId, ops: &[TextOperation]) -> LV {
+        assert_within_limits(self.check_local_ops(agent, ops));
         let first_time = self.len();
         let mut next_time = first_time;
 
@@ -200,7 +333,17 @@ impl ListOpLog {
     ///
     /// Returns the single item version after merging. (The resulting LocalVersion after calling
     /// this method will be `[time]`).
+    ///
+    /// `parents` can name any number of concurrent versions. Merging hundreds of branches in a
+    /// single change costs a couple of bytes per parent when encoded, and history queries stay
+    /// linear in the number of parents.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the operations would exceed the oplog's limits. See
+    /// [`try_add_operations_at`](ListOpLog::try_add_operations_at).
     pub fn add_operations_at(&mut self, agent: AgentId, parents: &[LV], ops: &[TextOperation]) -> LV {
+        assert_within_limits(self.check_local_ops(agent, ops));
         let first_time = self.len();
         let mut next_time = first_time;
 
@@ -217,12 +360,45 @@ impl ListOpLog {
         next_time - 1
     }
 
+    /// Add operations made by another peer, keeping the seq numbers the agent gave them there
+    /// (starting at `seq_start`). This is useful when importing changes stored in some other
+    /// format. The operations are marked as [remote](Origin::Remote).
+    ///
+    /// Returns the local version of the last operation added.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the seq numbers are already assigned to operations from the agent, if
+    /// the operations would exceed the oplog's limits, or if `parents` is from before history was
+    /// collapsed with [`gc_before`](ListOpLog::gc_before).
+    pub fn add_remote_operations_at(&mut self, agent: AgentId, seq_start: usize, parents: &[LV], ops: &[TextOperation]) -> LV {
+        assert_within_limits(self.check_local_ops(agent, ops));
+        assert!(self.can_checkout(parents),
+            "Cannot add operations at {parents:?}. History before {} was collapsed by gc_before", self.gc_len);
+        let first_time = self.len();
+        let mut next_time = first_time;
+
+        for op in ops {
+            self.push_op_internal(next_time, op.loc, op.kind, op.content_as_str());
+            next_time += op.len();
+        }
+
+        let len = next_time - first_time;
+        self.cg.merge_and_assign_nonoverlapping(parents, AgentSpan {
+            agent,
+            seq_range: (seq_start..seq_start + len).into(),
+        });
+        self.set_origin((first_time..next_time).into(), Origin::Remote);
+        next_time - 1
+    }
+
     /// Returns the single item localtime after the inserted change.
     pub fn add_insert_at(&mut self, agent: AgentId, parents: &[LV], pos: usize, ins_content: &str) -> LV {
         // This could just call add_operations_at() but this is significantly faster according to benchmarks.
         // Equivalent to:
         // self.add_operations_at(agent, parents, &[Operation::new_insert(pos, ins_content)])
         let len = count_chars(ins_content);
+        assert_within_limits(self.check_local_change(agent, len, ins_content.len()));
         let start = self.len();
         let end = start + len;
 
@@ -238,6 +414,7 @@ impl ListOpLog {
     pub fn add_delete_at(&mut self, agent: AgentId, parents: &[LV], loc: Range<usize>) -> LV {
         // Equivalent to:
         // self.push_at(agent, parents, &[Operation::new_delete(pos, len)])
+        assert_within_limits(self.check_local_change(agent, loc.len(), 0));
         let start_time = self.len();
         let end_time = start_time + loc.len();
 
@@ -298,29 +475,61 @@ impl ListOpLog {
         self.cg.graph.iter()
     }
 
+    /// Iterate through the history entries which overlap `range`, clipped to fit inside it. If the
+    /// range starts part way through an entry, the clipped entry's parent is the version just
+    /// before the range. Otherwise entries keep their own parents. The encoder splits history up
+    /// the same way.
     pub fn iter_history_range(&self, range: DTRange) -> impl Iterator<Item =GraphEntrySimple> + '_ {
         self.cg.graph.iter_range(range)
     }
 
+    /// Iterate through the spans of operations in the oplog in the requested topological order.
+    /// See [`TopoOrder`] for the available orderings.
+    pub fn iter_topological(&self, order: TopoOrder) -> impl Iterator<Item = DTRange> {
+        self.cg.iter_topological(order)
+    }
+
     /// Returns a `&[usize]` reference to the tip of the oplog. This version contains all
     /// known operations.
     ///
-    /// This method is provided alongside [`local_version`](OpLog::local_version) because its
+    /// This method is provided alongside [`local_version`](ListOpLog::local_version) because its
     /// slightly faster.
-    pub fn local_frontier_ref(&self) -> &[LV] {
+    pub fn local_version_ref(&self) -> &[LV] {
         self.cg.version.as_ref()
     }
 
     /// Return the current tip version of the oplog. This is the version which contains all
     /// operations in the oplog.
-    pub fn local_frontier(&self) -> Frontier {
+    pub fn local_version(&self) -> Frontier {
         self.cg.version.clone()
     }
 
-    pub fn remote_frontier(&self) -> RemoteFrontier {
+    /// Return the current tip version of the oplog in remote form, for sending to other peers.
+    pub fn remote_version(&self) -> RemoteFrontier<'_> {
         self.cg.agent_assignment.local_to_remote_frontier(self.cg.version.as_ref())
     }
 
+    #[deprecated(since = "1.1.0", note = "Renamed to local_version_ref")]
+    pub fn local_frontier_ref(&self) -> &[LV] {
+        self.local_version_ref()
+    }
+
+    #[deprecated(since = "1.1.0", note = "Renamed to local_version")]
+    pub fn local_frontier(&self) -> Frontier {
+        self.local_version()
+    }
+
+    #[deprecated(since = "1.1.0", note = "Renamed to remote_version")]
+    pub fn remote_frontier(&self) -> RemoteFrontier<'_> {
+        self.remote_version()
+    }
+
+    /// Resolve as many of the given remote versions as possible, for sync handshakes. See
+    /// [`CausalGraph::resolve_remote_versions`](crate::CausalGraph::resolve_remote_versions).
+    pub fn resolve_remote_versions<'a>(&self, ids: &[RemoteVersion<'a>]) -> ResolveResult<'a> {
+        self.cg.resolve_remote_versions(ids)
+    }
+
     // pub(crate) fn content_str(&self, tag: InsDelTag) -> &str {
     //     switch(tag, &self.ins_content, &self.del_content)
     // }
@@ -429,14 +638,209 @@ impl ListOpLog {
         self.cg.graph.version_union(a, b)
     }
 
+    /// Find the smallest set of versions which contains all of the passed versions. The passed
+    /// versions must be sorted.
+    ///
+    /// For example, `find_dominators(&[a, b])` is `[b]` if `b` already contains `a`.
+    pub fn find_dominators(&self, versions: &[LV]) -> Frontier {
+        self.cg.graph.find_dominators(versions)
+    }
+
+    /// Find the version containing just the operations which are in both `a` and `b`. This is
+    /// the version `a` and `b` branched from (or the smaller of the two, if one contains the
+    /// other). It's a good base for a 3-way merge.
+    pub fn common_ancestor(&self, a: &[LV], b: &[LV]) -> Frontier {
+        self.cg.graph.common_ancestor(a, b)
+    }
+
     pub fn parents_at_time(&self, time: LV) -> Frontier {
         self.cg.graph.parents_at_time(time)
     }
 
     pub(crate) fn estimate_cost(&self, op_range: DTRange) -> usize {
-        let start_idx = self.operations.find_index(op_range.start).unwrap();
-        let end_idx = self.operations.find_index(op_range.last()).unwrap();
+        // The range can start or end on a checkpoint, which has no operation.
+        let start_idx = self.operations.find_next_index(op_range.start);
+        let end_idx = match self.operations.find_index(op_range.last()) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        };
 
-        end_idx - start_idx + 1
+        end_idx.saturating_sub(start_idx)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::ops::Range;
+    use rle::HasLength;
+    use crate::{Frontier, LV};
+    use crate::causalgraph::graph::GraphEntrySimple;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::{ListBranch, ListOpLog};
+    use crate::list::operation::TextOperation;
+
+    fn paste_into(oplog: &mut ListOpLog, content: &str) -> ListBranch {
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mut branch = oplog.checkout_tip();
+        branch.insert(oplog, seph, 0, "hi");
+        branch.insert(oplog, seph, 1, content);
+        branch
+    }
+
+    #[test]
+    fn big_paste_is_split() {
+        let content = "x".repeat(10 * 1024 * 1024);
+
+        let mut capped = ListOpLog::new();
+        capped.set_max_run_bytes(64 * 1024);
+        let branch = paste_into(&mut capped, &content);
+
+        let mut uncapped = ListOpLog::new();
+        uncapped.set_max_run_bytes(usize::MAX);
+        paste_into(&mut uncapped, &content);
+
+        // "hi" and then 160 runs of 64kb each.
+        assert_eq!(capped.operations.num_entries(), 161);
+        assert_eq!(uncapped.operations.num_entries(), 2);
+        assert!(capped.operations.iter().all(|op| op.1.content_pos.unwrap().len() <= 64 * 1024));
+        assert_eq!(capped.iter_history().count(), 1);
+
+        assert_eq!(capped, uncapped);
+        assert_eq!(branch.content(), &uncapped.checkout_tip().content);
+
+        // The encoded files are byte-for-byte identical.
+        let data = capped.encode(ENCODE_FULL);
+        assert_eq!(data, uncapped.encode(ENCODE_FULL));
+
+        // And loading into a capped oplog splits the runs again.
+        let mut loaded = ListOpLog::new();
+        loaded.set_max_run_bytes(64 * 1024);
+        loaded.decode_and_add(&data).unwrap();
+        assert_eq!(loaded.operations.num_entries(), 161);
+        assert_eq!(loaded, uncapped);
+
+        // Concurrent edits merge identically.
+        let mike = capped.get_or_create_agent_id("mike");
+        capped.add_insert_at(mike, &[0], 1, "abc");
+        let mike = uncapped.get_or_create_agent_id("mike");
+        uncapped.add_insert_at(mike, &[0], 1, "abc");
+        assert_eq!(capped.checkout_tip().content, uncapped.checkout_tip().content);
+    }
+
+    #[test]
+    fn split_points_are_char_boundaries() {
+        let content = "aé😀".repeat(100);
+
+        let mut oplog = ListOpLog::new();
+        oplog.set_max_run_bytes(3);
+        let branch = paste_into(&mut oplog, &content);
+
+        for op in oplog.operations.iter() {
+            let pos = op.1.content_pos.unwrap();
+            let bytes = &oplog.operation_ctx.ins_content[pos.start..pos.end];
+            assert!(std::str::from_utf8(bytes).is_ok());
+            // The limit is smaller than "😀", so that needs to get a run of its own.
+            assert!(bytes.len() <= 3 || bytes == "😀".as_bytes());
+        }
+
+        oplog.dbg_check(true);
+        assert_eq!(branch.content().to_string(), format!("h{content}i"));
+    }
+
+    #[test]
+    fn reversed_inserts() {
+        // Each character here is inserted in front of the previous one. This isn't something
+        // the merge code handles directly, so it gets stored as a series of single inserts.
+        let mut op = TextOperation::new_insert(1, "abc");
+        op.loc.fwd = false;
+
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "xy");
+        oplog.add_operations(seph, &[op]);
+        oplog.dbg_check(true);
+
+        assert_eq!(oplog.len(), 5);
+        assert_eq!(oplog.checkout_tip().content().to_string(), "xcbay");
+
+        let data = oplog.encode(ENCODE_FULL);
+        let oplog2 = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(oplog, oplog2);
+        assert_eq!(oplog2.checkout_tip().content().to_string(), "xcbay");
+    }
+
+    #[test]
+    fn add_remote_operations_keeps_seqs() {
+        let mut src = ListOpLog::new();
+        let seph = src.get_or_create_agent_id("seph");
+        let mike = src.get_or_create_agent_id("mike");
+        let a = src.add_insert(seph, 0, "hi there");
+        src.add_insert_at(mike, &[a], 2, " you");
+        src.add_delete_at(seph, &[a], 0..3);
+
+        // Copy the operations over one entry at a time, in a different order.
+        let mut dest = ListOpLog::new();
+        let mike2 = dest.get_or_create_agent_id("mike");
+        let seph2 = dest.get_or_create_agent_id("seph");
+        let a2 = dest.add_remote_operations_at(seph2, 0, &[], &[TextOperation::new_insert(0, "hi there")]);
+        dest.add_remote_operations_at(seph2, 8, &[a2], &[TextOperation::new_delete(0..3)]);
+        dest.add_remote_operations_at(mike2, 0, &[a2], &[TextOperation::new_insert(2, " you")]);
+
+        assert_eq!(dest, src);
+        assert!(dest.iter_origins().all(|(_, origin)| origin == crate::list::origin::Origin::Remote));
+    }
+
+    #[test]
+    #[should_panic]
+    fn add_remote_operations_rejects_reused_seqs() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "abc");
+        oplog.add_remote_operations_at(seph, 2, &[], &[TextOperation::new_insert(0, "x")]);
+    }
+
+    #[test]
+    fn history_range_clips_entries() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert_at(seph, &[], 0, "abc"); // 0..3
+        oplog.add_insert_at(mike, &[], 0, "xy"); // 3..5
+        oplog.add_insert_at(seph, &[2, 4], 0, "zzz"); // 5..8, a merge
+
+        let e = |span: Range<usize>, parents: &[LV]| GraphEntrySimple {
+            span: span.into(), parents: Frontier::from_sorted(parents)
+        };
+        let check = |oplog: &ListOpLog, range: Range<usize>, expect: &[GraphEntrySimple]| {
+            assert_eq!(oplog.iter_history_range(range.into()).collect::<Vec<_>>(), expect);
+        };
+
+        let cases = [
+            (0..8, vec![e(0..3, &[]), e(3..5, &[]), e(5..8, &[2, 4])]),
+            // Starting part way through an entry makes the previous version the parent.
+            (1..4, vec![e(1..3, &[0]), e(3..4, &[])]),
+            (2..7, vec![e(2..3, &[1]), e(3..5, &[]), e(5..7, &[2, 4])]),
+            (6..8, vec![e(6..8, &[5])]),
+            // Exactly on entry boundaries.
+            (3..5, vec![e(3..5, &[])]),
+            (5..6, vec![e(5..6, &[2, 4])]),
+            (3..3, vec![]),
+            (8..10, vec![]),
+        ];
+        for (range, expect) in &cases {
+            check(&oplog, range.clone(), expect);
+        }
+
+        // Entries are split the same way when they're encoded, so a round trip through the
+        // encoder gives the same answers.
+        let decoded = ListOpLog::load_from(&oplog.encode(ENCODE_FULL)).unwrap();
+        for (range, expect) in &cases {
+            check(&decoded, range.clone(), expect);
+        }
+
+        let patch = oplog.encode_from(ENCODE_FULL, &[2, 4]);
+        let mut partial = ListOpLog::load_from(&oplog.encode_between(ENCODE_FULL, &[], &[2, 4])).unwrap();
+        partial.decode_and_add(&patch).unwrap();
+        check(&partial, 5..8, &[e(5..8, &[2, 4])]);
+    }
+}
@@ -2,6 +2,7 @@ use std::collections::BinaryHeap;
 use smallvec::SmallVec;
 use rle::{AppendRle, HasLength};
 use crate::list::ListOpLog;
+use crate::list::origin::Origin;
 use crate::dtrange::DTRange;
 use crate::rle::KVPair;
 use crate::{AgentId, CausalGraph};
@@ -56,12 +57,12 @@ impl CausalGraph {
                 let seq = other_span.1.seq_range.start + offset;
 
                 // Find out how many items we can eat
-                let (r, offset) = self.agent_assignment.client_data[self_agent as usize]
-                    .item_times.find_sparse(seq);
-                if r.is_ok() {
+                let offset = match self.agent_assignment.client_data[self_agent as usize]
+                    .item_times.find_sparse(seq) {
                     // Overlap here. Discard from the queue.
-                    break;
-                }
+                    Ok(_) => break,
+                    Err(gap) => seq - gap.start,
+                };
 
                 let id_start = ord - offset;
                 if containing_txn.span.start >= id_start {
@@ -89,6 +90,10 @@ impl CausalGraph {
 impl ListOpLog {
     /// Add all missing operations from the other oplog into this oplog. This method is mostly used
     /// by testing code, since you rarely have two local oplogs to merge together.
+    ///
+    /// Panics if any of the missing operations are concurrent with history collapsed by
+    /// [`gc_before`](ListOpLog::gc_before). Use [`merge_data`](ListOpLog::merge_data) to get an
+    /// error instead.
     pub fn add_missing_operations_from(&mut self, other: &Self) {
         // [other.agent] => self.agent
         let mut agent_map = Vec::with_capacity(other.cg.agent_assignment.client_data.len());
@@ -105,19 +110,24 @@ impl ListOpLog {
         let spans = self.cg.to_merge(&other.cg, &agent_map);
         // dbg!(&spans);
 
-        let mut time = self.len();
+        let start = self.len();
+        let mut time = start;
         for &s in spans.iter().rev() {
             // Operations
-            let mut t = time;
-            for (KVPair(_, op), content) in other.iter_range_simple(s) {
+            for (KVPair(lv, op), content) in other.iter_range_simple(s) {
                 // Operations don't need to be mapped at all.
                 // dbg!(&op, content);
-                self.push_op_internal(t, op.loc, op.kind, content);
-                t += op.len();
+                self.push_op_internal(time + lv - s.start, op.loc, op.kind, content);
+            }
+            for (&lv, message) in other.checkpoints.range(s.start..s.end) {
+                self.checkpoints.insert(time + lv - s.start, message.clone());
+            }
+            for KVPair(lv, run) in other.timestamps.iter_range(s) {
+                self.timestamps.push(KVPair(time + lv - s.start, run));
             }
 
             // Agent assignments
-            t = time;
+            let mut t = time;
             for mut span in other.iter_agent_mappings_range(s) {
                 // Map other agent ID -> self agent IDs.
                 span.agent = agent_map[span.agent as usize];
@@ -145,6 +155,8 @@ impl ListOpLog {
                 // hist_entry.parents.sort_unstable_by(|a, b| a.cmp(b));
                 hist_entry.parents.debug_check_sorted();
                 // dbg!(&hist_entry.parents);
+                assert!(self.can_checkout(hist_entry.parents.as_ref()),
+                    "Cannot merge operations concurrent with history collapsed by gc_before");
 
                 self.cg.graph.push(hist_entry.parents.as_ref(), span);
                 self.cg.version.advance_by_known_run(hist_entry.parents.as_ref(), span);
@@ -153,6 +165,8 @@ impl ListOpLog {
 
             time += s.len();
         }
+
+        self.set_origin((start..time).into(), Origin::Remote);
     }
 }
 
@@ -56,7 +56,8 @@ fn oplog_merge_fuzz(seed: u64, verbose: bool) {
 
         a.branch.merge(&a.oplog, a.oplog.cg.version.as_ref());
         b.branch.merge(&b.oplog, b.oplog.cg.version.as_ref());
-        assert_eq!(a.branch.content, b.branch.content);
+        assert!(a.branch.content_eq(&b.branch));
+        assert!(a.branch.version_eq(&a.oplog, &b.branch, &b.oplog));
     }
 
     for doc in &docs {
@@ -0,0 +1,248 @@
+//! Tracks where each operation in an oplog came from - whether it was made locally on this
+//! replica, or merged in from somewhere else. Applications use this to show which changes came
+//! from other devices, and to figure out which local changes still need to be uploaded.
+//!
+//! Origins are replica-local. They aren't part of the encoded oplog, so two peers can disagree
+//! about the origin of the same operation.
+
+use crate::dtrange::DTRange;
+use crate::list::ListOpLog;
+use crate::list::operation::TextOperation;
+use crate::rle::RleVec;
+use crate::{AgentId, LV};
+
+/// Where an operation in the oplog came from. See [`ListOpLog::origin_of`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Origin {
+    /// The operation was created on this replica. This includes edits made through
+    /// [`ListBranch`](crate::list::ListBranch) and [`ListCRDT`](crate::list::ListCRDT), and
+    /// operations added directly to the oplog (like with
+    /// [`add_operations_at`](ListOpLog::add_operations_at)).
+    Local,
+
+    /// The operation was merged in from another peer, with
+    /// [`merge_data`](ListOpLog::merge_data) / [`decode_and_add`](ListOpLog::decode_and_add) or
+    /// [`add_missing_operations_from`](ListOpLog::add_missing_operations_from).
+    Remote,
+}
+
+impl ListOpLog {
+    /// Get the origin of the named operation.
+    ///
+    /// Operations in an oplog loaded with [`load_from`](ListOpLog::load_from) are all local, since
+    /// origins aren't stored in the file. Use [`set_origin`](ListOpLog::set_origin) to restore them.
+    ///
+    /// Panics if the version isn't in the oplog.
+    pub fn origin_of(&self, v: LV) -> Origin {
+        assert!(v < self.len(), "Version {v} is not in the oplog");
+        if self.remote_spans.contains_needle(v) { Origin::Remote } else { Origin::Local }
+    }
+
+    /// Iterate through the origins of every operation in the oplog, as maximal runs of operations
+    /// with the same origin. The runs are in local version order, and cover the whole oplog.
+    ///
+    /// Origins aren't encoded with the oplog. If you want to keep them across restarts, save these
+    /// runs alongside the oplog and restore them with [`set_origin`](ListOpLog::set_origin).
+    pub fn iter_origins(&self) -> impl Iterator<Item = (DTRange, Origin)> + '_ {
+        let len = self.len();
+        let mut pos = 0;
+        let mut remote = self.remote_spans.iter_merged().peekable();
+
+        std::iter::from_fn(move || {
+            if pos >= len { return None; }
+
+            let run = match remote.peek() {
+                Some(&span) if span.start == pos => {
+                    remote.next();
+                    (span, Origin::Remote)
+                }
+                Some(span) => ((pos..span.start).into(), Origin::Local),
+                None => ((pos..len).into(), Origin::Local),
+            };
+            pos = run.0.end;
+            Some(run)
+        })
+    }
+
+    /// Set the origin of a range of operations in the oplog.
+    ///
+    /// Panics if the range isn't in the oplog.
+    pub fn set_origin(&mut self, range: DTRange, origin: Origin) {
+        assert!(range.end <= self.len(), "Range {range:?} is not in the oplog");
+        if range.is_empty() { return; }
+
+        // Fast path. Newly added operations are always at the end.
+        if range.start >= self.remote_spans.end() {
+            if origin == Origin::Remote { self.remote_spans.push(range); }
+            return;
+        }
+
+        let mut spans = RleVec::new();
+        for (run, run_origin) in self.iter_origins() {
+            let pieces = [
+                (run.start, run.end.min(range.start), run_origin),
+                (run.start.max(range.start), run.end.min(range.end), origin),
+                (run.start.max(range.end), run.end, run_origin),
+            ];
+            for (start, end, o) in pieces {
+                if start < end && o == Origin::Remote {
+                    spans.push((start..end).into());
+                }
+            }
+        }
+        self.remote_spans = spans;
+    }
+
+    /// Like [`add_operations_at`](ListOpLog::add_operations_at), but the added operations are
+    /// marked with the specified origin. This is useful when operations received from a peer are
+    /// added one at a time, rather than merged in with [`merge_data`](ListOpLog::merge_data).
+    pub fn add_operations_at_with_origin(&mut self, agent: AgentId, parents: &[LV], ops: &[TextOperation], origin: Origin) -> LV {
+        let start = self.len();
+        let last = self.add_operations_at(agent, parents, ops);
+        self.set_origin((start..self.len()).into(), origin);
+        last
+    }
+
+    /// Iterate through the local operations which aren't in the `synced` version - which is to
+    /// say, the local changes which still need to be sent to a server which has everything up to
+    /// `synced`. Spans are returned in ascending order.
+    ///
+    /// Remote operations are never returned, since they came from somewhere else.
+    pub fn iter_unsynced(&self, synced: &[LV]) -> impl Iterator<Item = DTRange> + '_ {
+        let (_, unsynced) = self.cg.graph.diff(synced, self.cg.version.as_ref());
+        unsynced.into_iter().flat_map(move |range| self.remote_spans.iter_gaps(range))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::{ListBranch, ListCRDT, ListOpLog};
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::operation::TextOperation;
+    use crate::dtrange::DTRange;
+    use super::Origin::{self, *};
+
+    fn runs(oplog: &ListOpLog) -> Vec<(DTRange, Origin)> {
+        oplog.iter_origins().collect()
+    }
+
+    fn r(start: usize, end: usize, origin: Origin) -> (DTRange, Origin) {
+        ((start..end).into(), origin)
+    }
+
+    #[test]
+    fn origins_follow_local_edits_and_merges() {
+        let mut phone = ListCRDT::new();
+        let phone_agent = phone.get_or_create_agent_id("seph-phone");
+
+        let mut laptop = ListCRDT::new();
+        let laptop_agent = laptop.get_or_create_agent_id("seph-laptop");
+        assert_eq!(runs(&laptop.oplog), []);
+
+        laptop.insert(laptop_agent, 0, "hi "); // 0..3
+
+        phone.merge_data_and_ff(&laptop.oplog.encode(ENCODE_FULL)).unwrap();
+        phone.insert(phone_agent, 3, "there"); // 3..8
+        laptop.insert(laptop_agent, 3, "yo"); // 3..5
+
+        laptop.oplog.merge_data(&phone.oplog.encode(ENCODE_FULL)).unwrap(); // 5..10
+        laptop.oplog.merge_data(&phone.oplog.encode(ENCODE_FULL)).unwrap(); // Nothing new.
+
+        let mut branch = ListBranch::new_at_tip(&laptop.oplog);
+        branch.insert(&mut laptop.oplog, laptop_agent, 0, "!"); // 10
+        let v = laptop.oplog.add_operations_at_with_origin(laptop_agent, &[], &[TextOperation::new_insert(0, "x")], Remote); // 11
+        laptop.oplog.add_operations_at(laptop_agent, &[v], &[TextOperation::new_delete(0..1)]); // 12
+
+        let mut other = ListOpLog::new();
+        let mike = other.get_or_create_agent_id("mike");
+        other.add_insert(mike, 0, "abc");
+        laptop.oplog.add_missing_operations_from(&other); // 13..16
+
+        assert_eq!(runs(&laptop.oplog), [
+            r(0, 5, Local), r(5, 10, Remote), r(10, 11, Local), r(11, 12, Remote),
+            r(12, 13, Local), r(13, 16, Remote),
+        ]);
+        assert_eq!(runs(&phone.oplog), [r(0, 3, Remote), r(3, 8, Local)]);
+
+        assert_eq!(laptop.oplog.origin_of(4), Local);
+        assert_eq!(laptop.oplog.origin_of(5), Remote);
+        assert_eq!(laptop.oplog.origin_of(15), Remote);
+    }
+
+    #[test]
+    fn failed_merge_leaves_origins_unchanged() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+
+        let mut other = ListOpLog::new();
+        let mike = other.get_or_create_agent_id("mike");
+        let v = other.add_insert(mike, 0, "abc");
+        other.add_insert(mike, 0, "def");
+
+        // The base version of the patch is missing.
+        assert!(oplog.merge_data(&other.encode_from(ENCODE_FULL, &[v])).is_err());
+        assert_eq!(runs(&oplog), [r(0, 2, Local)]);
+
+        oplog.merge_data(&other.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(runs(&oplog), [r(0, 2, Local), r(2, 8, Remote)]);
+    }
+
+    #[test]
+    fn origins_are_not_encoded() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi");
+        let before = oplog.encode(ENCODE_FULL);
+
+        oplog.set_origin((0..2).into(), Remote);
+        assert_eq!(oplog.encode(ENCODE_FULL), before);
+
+        // Loading a saved oplog marks everything local. The origins can be restored afterwards.
+        let saved: Vec<_> = oplog.iter_origins().collect();
+        let mut loaded = ListOpLog::load_from(&before).unwrap();
+        assert_eq!(runs(&loaded), [r(0, 2, Local)]);
+        for (range, origin) in saved {
+            loaded.set_origin(range, origin);
+        }
+        assert_eq!(runs(&loaded), [r(0, 2, Remote)]);
+    }
+
+    #[test]
+    fn set_origin_splits_and_joins_runs() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "0123456789");
+
+        oplog.set_origin((2..8).into(), Remote);
+        assert_eq!(runs(&oplog), [r(0, 2, Local), r(2, 8, Remote), r(8, 10, Local)]);
+        oplog.set_origin((4..5).into(), Local);
+        assert_eq!(runs(&oplog), [r(0, 2, Local), r(2, 4, Remote), r(4, 5, Local), r(5, 8, Remote), r(8, 10, Local)]);
+        oplog.set_origin((3..9).into(), Remote);
+        assert_eq!(runs(&oplog), [r(0, 2, Local), r(2, 9, Remote), r(9, 10, Local)]);
+        oplog.set_origin((0..10).into(), Local);
+        assert_eq!(runs(&oplog), [r(0, 10, Local)]);
+    }
+
+    #[test]
+    fn iter_unsynced_skips_remote_and_synced_ops() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let synced = oplog.add_insert(seph, 0, "abc"); // 0..3, uploaded.
+
+        let mut other = ListOpLog::new();
+        let mike = other.get_or_create_agent_id("mike");
+        other.add_insert(mike, 0, "xy");
+        oplog.add_insert_at(seph, &[synced], 0, "de"); // 3..5
+        oplog.merge_data(&other.encode(ENCODE_FULL)).unwrap(); // 5..7
+        oplog.add_insert(seph, 0, "f"); // 7
+
+        let unsynced: Vec<DTRange> = oplog.iter_unsynced(&[synced]).collect();
+        assert_eq!(unsynced, [(3..5).into(), (7..8).into()]);
+
+        let unsynced: Vec<DTRange> = oplog.iter_unsynced(&[]).collect();
+        assert_eq!(unsynced, [(0..5).into(), (7..8).into()]);
+
+        assert_eq!(oplog.iter_unsynced(oplog.local_version_ref()).count(), 0);
+    }
+}
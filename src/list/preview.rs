@@ -0,0 +1,205 @@
+//! Two-phase merges, for editors which want to look at incoming changes before applying them.
+//!
+//! [`ListBranch::preview_merge`] does all the work of transforming the operations being merged,
+//! without touching the branch. The resulting [`MergePreview`] lists the edits a merge would make,
+//! which can be shown to the user (or used to move selections around). Then
+//! [`ListBranch::apply_preview`] applies exactly those edits, without transforming anything again.
+
+use rle::HasLength;
+use crate::frontier::Frontier;
+use crate::list::{EditError, ListBranch, ListOpLog};
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::listmerge::merge::reverse_str;
+use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
+use crate::LV;
+
+/// The edits merging some version into a branch would make. Made by
+/// [`ListBranch::preview_merge`], and applied with [`ListBranch::apply_preview`].
+///
+/// A preview only applies to the branch version it was made at. If the branch is edited or merged
+/// in the meantime, applying it fails with [`EditError::StalePreview`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergePreview {
+    /// The branch version the preview was made from.
+    base: Frontier,
+    /// The branch version after the preview is applied.
+    version: Frontier,
+    ops: Vec<TextOperation>,
+}
+
+impl MergePreview {
+    /// The edits the merge would make, in order. Each operation's position is in the document
+    /// after the previous operations have been applied. Operations are never reversed, and insert
+    /// content is in document order. Deletes carry the deleted text if the oplog stores it.
+    pub fn ops(&self) -> &[TextOperation] {
+        &self.ops
+    }
+
+    /// The version the branch will have once the preview is applied.
+    pub fn version(&self) -> &[LV] {
+        self.version.as_ref()
+    }
+
+    /// Returns true if the merge wouldn't change the branch's content. (The branch's version may
+    /// still move forward.)
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl ListBranch {
+    /// Work out what merging `merge_frontier` into the branch would do, without modifying the
+    /// branch. Apply the result with [`apply_preview`](ListBranch::apply_preview).
+    ///
+    /// Like [`merge`](ListBranch::merge), in debug builds this panics if the branch's version or
+    /// `merge_frontier` isn't in the oplog.
+    pub fn preview_merge(&self, oplog: &ListOpLog, merge_frontier: &[LV]) -> MergePreview {
+        debug_assert!(self.versions_in(oplog, merge_frontier),
+            "Branch version {:?} or merge frontier {:?} is not in the oplog (length {})",
+            self.version, merge_frontier, oplog.len());
+        let mut iter = oplog.get_xf_operations_full(self.version.as_ref(), merge_frontier);
+        let mut ops = vec![];
+
+        for (_lv, origin_op, xf) in &mut iter {
+            let BaseMoved(pos) = xf else {
+                debug_assert!(matches!(xf, DeleteAlreadyHappened));
+                continue;
+            };
+            let len = origin_op.len();
+            let fwd = origin_op.loc.fwd;
+
+            let op = match origin_op.kind {
+                ListOpKind::Ins => {
+                    let content = origin_op.get_content_or_replacement(&oplog.operation_ctx);
+                    let content = if fwd { content.as_ref().into() } else { reverse_str(&content) };
+                    TextOperation { loc: (pos..pos + len).into(), kind: ListOpKind::Ins, content: Some(content) }
+                }
+                ListOpKind::Del => {
+                    let content = origin_op.get_content(&oplog.operation_ctx)
+                        .map(|c| if fwd { c.into() } else { reverse_str(c) });
+                    TextOperation { loc: (pos..pos + len).into(), kind: ListOpKind::Del, content }
+                }
+            };
+            ops.push(op);
+        }
+
+        MergePreview {
+            base: self.version.clone(),
+            version: iter.into_frontier(),
+            ops,
+        }
+    }
+
+    /// Apply a preview made with [`preview_merge`](ListBranch::preview_merge). The result is the
+    /// same as calling [`merge`](ListBranch::merge) with the same frontier, but the operations
+    /// aren't transformed again.
+    ///
+    /// Returns [`EditError::StalePreview`] if the branch has changed since the preview was made,
+    /// and [`EditError::UnknownVersion`] if the preview's versions aren't in `oplog`. The oplog
+    /// growing in the meantime is fine - the preview doesn't depend on anything after its version.
+    /// When an error is returned, the branch is not modified.
+    pub fn apply_preview(&mut self, oplog: &ListOpLog, preview: MergePreview) -> Result<(), EditError> {
+        if self.version != preview.base { return Err(EditError::StalePreview); }
+        if !self.versions_in(oplog, preview.version.as_ref()) { return Err(EditError::UnknownVersion); }
+
+        for op in preview.ops {
+            match op.kind {
+                ListOpKind::Ins => {
+                    debug_assert!(op.start() <= self.content.len_chars());
+                    self.content.insert(op.start(), op.content.as_deref().unwrap());
+                }
+                ListOpKind::Del => {
+                    debug_assert!(op.end() <= self.content.len_chars());
+                    self.content.remove(op.range().into());
+                }
+            }
+        }
+
+        self.version = preview.version;
+        if self.read_only && self.version == oplog.cg.version {
+            self.read_only = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::ListCRDT;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::list::old_fuzzer_tools::old_make_random_change;
+    use super::*;
+
+    #[test]
+    fn preview_lists_transformed_edits() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let v = oplog.add_insert(seph, 0, "hello");
+        let a = oplog.add_insert_at(seph, &[v], 5, " world");
+        let b = oplog.add_delete_at(mike, &[v], 0..1);
+
+        let branch = oplog.checkout(&[a]);
+        let preview = branch.preview_merge(&oplog, &[b]);
+        assert_eq!(preview.ops(), &[TextOperation::new_delete(0..1)]);
+        assert_eq!(preview.version(), oplog.local_version_ref());
+
+        // Nothing was changed yet.
+        assert_eq!(branch, oplog.checkout(&[a]));
+        assert!(branch.preview_merge(&oplog, &[a]).is_empty());
+    }
+
+    #[test]
+    fn apply_preview_matches_merge() {
+        let mut rng = SmallRng::seed_from_u64(2273);
+        let mut doc = ListCRDT::new();
+        let mut peers: Vec<ListCRDT> = (0..3).map(|_| ListCRDT::new()).collect();
+        for (i, peer) in peers.iter_mut().enumerate() {
+            peer.get_or_create_agent_id(&format!("peer {i}"));
+        }
+        let mut previewed = doc.oplog.checkout_tip();
+
+        for i in 0..200 {
+            let peer = &mut peers[i % 3];
+            old_make_random_change(peer, None, 0, &mut rng);
+            doc.oplog.merge_data(&peer.oplog.encode(ENCODE_FULL)).unwrap();
+            if i % 5 == 0 {
+                peer.merge_data_and_ff(&doc.oplog.encode(ENCODE_FULL)).unwrap();
+            }
+
+            if i % 3 == 2 {
+                let version = doc.oplog.local_version();
+                let preview = previewed.preview_merge(&doc.oplog, version.as_ref());
+                previewed.apply_preview(&doc.oplog, preview).unwrap();
+
+                doc.branch.merge(&doc.oplog, version.as_ref());
+                assert_eq!(previewed, doc.branch);
+            }
+        }
+    }
+
+    #[test]
+    fn stale_previews_are_rejected() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let v = oplog.add_insert(seph, 0, "abc");
+        let mut branch = oplog.checkout_tip();
+        let remote = oplog.add_insert_at(mike, &[v], 0, "xyz");
+
+        let preview = branch.preview_merge(&oplog, &[remote]);
+
+        // An edit made after the preview means the positions in it no longer line up.
+        branch.insert(&mut oplog, seph, 3, "!");
+        let before = branch.clone();
+        assert_eq!(branch.apply_preview(&oplog, preview), Err(EditError::StalePreview));
+        assert_eq!(branch, before);
+
+        // A fresh preview works, even though the oplog has grown.
+        let preview = branch.preview_merge(&oplog, &[remote]);
+        oplog.add_insert(seph, 0, "more");
+        branch.apply_preview(&oplog, preview).unwrap();
+        assert_eq!(branch.content(), "xyzabc!");
+    }
+}
@@ -0,0 +1,231 @@
+//! Finding and erasing text stored in an oplog.
+//!
+//! The oplog keeps the text of every insert (and, if it was loaded from a file which stored it,
+//! every delete). So text which has since been deleted from the document can still be recovered
+//! from the oplog, and from any file it's saved to. These methods find text anywhere in the
+//! document's history, and replace it with unknown content so it isn't saved again.
+
+use std::borrow::Cow;
+use rle::HasLength;
+use crate::dtrange::DTRange;
+use crate::list::ListOpLog;
+use crate::list::op_iter::OpMetricsIter;
+use crate::list::op_metrics::ListOperationCtx;
+use crate::list::operation::ListOpKind;
+use crate::rle::{KVPair, RleVec};
+use crate::unicount::count_chars;
+use crate::LV;
+
+/// Some stored content which matched a search. See [`ListOpLog::find_content_matches`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentMatch {
+    /// Whether the matching text was inserted or deleted.
+    pub kind: ListOpKind,
+    /// The operations which inserted (or deleted) the matching text, one per character.
+    pub span: DTRange,
+}
+
+impl ListOpLog {
+    /// Find every place `pattern` appears in the inserted and deleted content stored in the
+    /// oplog. Unlike searching a checkout, this finds text from any point in the document's
+    /// history, including text which was later deleted.
+    ///
+    /// Each run of operations is searched separately, so text which was typed out of order (or
+    /// deleted in several pieces) isn't found.
+    pub fn find_content_matches(&self, pattern: &str) -> Vec<ContentMatch> {
+        if pattern.is_empty() { return vec![]; }
+        let pattern_len = count_chars(pattern);
+
+        let mut result = vec![];
+        for (KVPair(lv, op), content) in self.iter_fast() {
+            let Some(content) = content else { continue; };
+
+            // Content is stored in operation order. When a run of operations goes backwards (eg
+            // backspacing), that's the reverse of the order the text appeared in the document.
+            let text: Cow<str> = if op.loc.fwd { content.into() } else {
+                content.chars().rev().collect::<String>().into()
+            };
+
+            for (byte_pos, _) in text.match_indices(pattern) {
+                let start = count_chars(&text[..byte_pos]);
+                let span = if op.loc.fwd {
+                    lv + start..lv + start + pattern_len
+                } else {
+                    let end = lv + op.len() - start;
+                    end - pattern_len..end
+                };
+                result.push(ContentMatch { kind: op.kind, span: span.into() });
+            }
+        }
+        result
+    }
+
+    /// Forget the content inserted or deleted by the operations in `spans`. The operations are
+    /// kept with the same positions and lengths, but they're marked as having unknown content,
+    /// so checkouts show U+FFFD replacement characters in their place.
+    ///
+    /// The stored content is rebuilt without the redacted text, so it's gone from memory and
+    /// from any files the oplog is saved to afterwards.
+    pub fn redact_content(&mut self, spans: &[DTRange]) {
+        let mut spans: Vec<DTRange> = spans.iter().filter(|s| !s.is_empty()).copied().collect();
+        spans.sort_unstable_by_key(|s| s.start);
+        self.forget_content(&spans, None);
+    }
+
+    /// Forget the deleted content of every delete at or before `keep_after`. Deletes made since
+    /// then keep their content, so they can still be undone. Inserts are untouched, so checkouts
+    /// don't change.
+    ///
+    /// Deleted content is only needed to undo deletes (and to show what was deleted), and storing
+    /// all of it can double the size of a file. This keeps the recent part. Undoing a delete whose
+    /// content was dropped fails with [`EditError::DeletedContentMissing`](crate::list::EditError::DeletedContentMissing).
+    ///
+    /// To drop old deleted content from a file without changing the oplog, use
+    /// [`EncodeOptions::keep_deleted_content_after`](crate::list::encoding::EncodeOptions::keep_deleted_content_after).
+    pub fn trim_deleted_content(&mut self, keep_after: &[LV]) {
+        let spans = self.ops_at_or_before(keep_after);
+        self.forget_content(&spans, Some(ListOpKind::Del));
+    }
+
+    /// The operations in the history of `version` (including `version` itself), in order.
+    pub(crate) fn ops_at_or_before(&self, version: &[LV]) -> Vec<DTRange> {
+        self.cg.graph.diff(version, &[]).0.to_vec()
+    }
+
+    /// Rebuild the stored content without the content of the operations in `spans` (which must be
+    /// sorted). If `only_kind` is set, other operations keep their content.
+    fn forget_content(&mut self, spans: &[DTRange], only_kind: Option<ListOpKind>) {
+        let old_ops = std::mem::replace(&mut self.operations, RleVec::new());
+        let old_ctx = std::mem::replace(&mut self.operation_ctx, ListOperationCtx::new());
+        self.inserted_bytes = 0;
+
+        let copy = |oplog: &mut ListOpLog, range: DTRange, forget: bool| {
+            if range.is_empty() { return; }
+            let mut iter = OpMetricsIter::new(&old_ops, &old_ctx, range);
+            while let Some(op) = iter.next() {
+                let content = if forget && only_kind.is_none_or(|k| k == op.1.kind) { None }
+                    else { iter.get_content(&op) };
+                oplog.push_op_internal(op.0, op.1.loc, op.1.kind, content);
+            }
+        };
+
+        let mut pos = 0;
+        for span in spans {
+            let start = span.start.max(pos);
+            let end = span.end.min(self.len());
+            if start >= end { continue; }
+
+            copy(self, (pos..start).into(), false);
+            copy(self, (start..end).into(), true);
+            pos = end;
+        }
+        copy(self, (pos..self.len()).into(), false);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::{EditError, ListCRDT, ListOpLog};
+    use crate::list::encoding::{ENCODE_FULL, EncodeOptions};
+    use crate::list::operation::ListOpKind::*;
+    use super::ContentMatch;
+
+    #[test]
+    fn find_and_redact_deleted_text() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "my secret is secret!");
+        // Backspace over the second "secret", one character at a time.
+        for pos in (13..19).rev() {
+            doc.delete(seph, pos..pos + 1);
+        }
+        let mut oplog = doc.oplog;
+
+        let matches = oplog.find_content_matches("secret");
+        assert_eq!(matches, [
+            ContentMatch { kind: Ins, span: (3..9).into() },
+            ContentMatch { kind: Ins, span: (13..19).into() },
+            ContentMatch { kind: Del, span: (20..26).into() },
+        ]);
+
+        let before = oplog.checkout_tip().content().to_string();
+        oplog.redact_content(&matches.iter().map(|m| m.span).collect::<Vec<_>>());
+        assert!(oplog.find_content_matches("secret").is_empty());
+        assert_eq!(oplog.find_content_matches("my ").len(), 1);
+
+        let after = oplog.checkout_tip().content().to_string();
+        assert_eq!(after, "my \u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD} is !");
+        assert_eq!(after.chars().count(), before.chars().count());
+
+        // The text isn't written out either.
+        let data = oplog.encode(EncodeOptions {
+            store_deleted_content: true,
+            compress_content: false,
+            ..ENCODE_FULL
+        });
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+        let loaded = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(loaded.checkout_tip().content().to_string(), after);
+    }
+
+    #[test]
+    fn trim_keeps_recent_deleted_content() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        let old_text = "an old paragraph which nobody needs back. ".repeat(10);
+        doc.insert(seph, 0, &old_text);
+        doc.insert(seph, old_text.len(), "hello world");
+        doc.delete(seph, 0..old_text.len());
+        doc.insert(mike, 0, ">");
+        let keep_after = doc.oplog.local_version();
+        doc.delete(seph, 1..7);
+        assert_eq!(doc.branch.content(), ">world");
+
+        let opts = EncodeOptions { store_deleted_content: true, compress_content: false, ..ENCODE_FULL };
+        let full = doc.oplog.encode(opts.clone());
+        let trimmed_file = doc.oplog.encode(EncodeOptions {
+            keep_deleted_content_after: Some(keep_after.as_ref()),
+            ..opts.clone()
+        });
+
+        let mut trimmed = doc.clone();
+        trimmed.oplog.trim_deleted_content(keep_after.as_ref());
+        trimmed.oplog.dbg_check(true);
+        assert_eq!(trimmed.oplog.checkout_tip(), doc.oplog.checkout_tip());
+        assert_eq!(trimmed.oplog.encode(opts.clone()), trimmed_file);
+        assert!(trimmed_file.len() + old_text.len() <= full.len());
+        assert_eq!(doc.oplog.encode(opts), full);
+
+        // The old delete can't be undone any more, but the recent one can.
+        let mut loaded = ListCRDT::load_from(&trimmed_file).unwrap();
+        for doc in [&mut trimmed, &mut loaded] {
+            doc.undo(seph).unwrap();
+            assert_eq!(doc.branch.content(), ">hello world");
+            assert_eq!(doc.undo(seph), Err(EditError::DeletedContentMissing));
+        }
+    }
+
+    #[test]
+    fn trim_splits_deletes() {
+        // These two deletes are stored as a single operation, but only the first is trimmed.
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "abc");
+        doc.delete(seph, 0..1);
+        let keep_after = doc.oplog.local_version();
+        doc.delete(seph, 0..1);
+
+        let opts = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+        let file = doc.oplog.encode(EncodeOptions {
+            keep_deleted_content_after: Some(keep_after.as_ref()),
+            ..opts.clone()
+        });
+        let mut oplog = doc.oplog;
+        oplog.trim_deleted_content(keep_after.as_ref());
+        assert_eq!(oplog.find_content_matches("a"), [ContentMatch { kind: Ins, span: (0..1).into() }]);
+        assert_eq!(oplog.find_content_matches("b").len(), 2);
+        assert_eq!(ListOpLog::load_from(&file).unwrap(), oplog);
+        assert_eq!(ListOpLog::load_from(&oplog.encode(opts)).unwrap(), oplog);
+    }
+}
@@ -0,0 +1,341 @@
+//! Render the changes between two versions of a document as annotated text ("what changed between
+//! revisions"), with inserted text and deleted text highlighted in place.
+//!
+//! When one version is an ancestor of the other, the annotations come straight from the
+//! transformed operations between the two versions. The deleted text is read out of the older
+//! version of the document, so this works even if the oplog doesn't store deleted content.
+//! Concurrent versions are compared by checking both of them out and diffing the text.
+
+use content_tree::{ContentLength, ContentMetrics, ContentTreeRaw, null_notify, Toggleable};
+use rle::{HasLength, MergableSpan, SplitableSpanHelpers};
+use crate::dtrange::DTRange;
+use crate::LV;
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+use crate::listmerge::merge::TransformedResult::{BaseMoved, DeleteAlreadyHappened};
+
+/// The output format for [`diff_annotated`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderStyle {
+    /// Inserted text is green and deleted text is red and struck through. Control characters in
+    /// the document are replaced with U+FFFD so they can't be interpreted by the terminal.
+    Ansi,
+    /// Inserted text is wrapped in `<ins class="dt-ins">` and deleted text in
+    /// `<del class="dt-del">`. All document content is HTML escaped.
+    Html,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Mark {
+    #[default]
+    Same,
+    Inserted,
+    Deleted,
+    /// Inserted and then deleted again. This text isn't in either version, so it isn't rendered.
+    Removed,
+}
+
+impl Mark {
+    fn swapped(self) -> Self {
+        match self {
+            Mark::Same => Mark::Same,
+            Mark::Inserted => Mark::Deleted,
+            Mark::Deleted => Mark::Inserted,
+            Mark::Removed => Mark::Removed,
+        }
+    }
+}
+
+/// A run of characters with the same mark, stored in a content tree while the operations are
+/// replayed. The characters themselves live in a separate buffer.
+#[derive(Debug, Clone, Copy, Default)]
+struct MarkedSpan {
+    mark: Mark,
+    /// The span's characters in the buffer.
+    chars: DTRange,
+}
+
+impl HasLength for MarkedSpan {
+    fn len(&self) -> usize { self.chars.len() }
+}
+
+impl SplitableSpanHelpers for MarkedSpan {
+    fn truncate_h(&mut self, at: usize) -> Self {
+        MarkedSpan { mark: self.mark, chars: self.chars.truncate_h(at) }
+    }
+}
+
+impl MergableSpan for MarkedSpan {
+    fn can_append(&self, other: &Self) -> bool {
+        self.mark == other.mark && self.chars.can_append(&other.chars)
+    }
+
+    fn append(&mut self, other: Self) { self.chars.append(other.chars); }
+}
+
+// The tree is indexed by position in the newer version, which doesn't include deleted text.
+impl ContentLength for MarkedSpan {
+    fn content_len(&self) -> usize {
+        if self.is_activated() { self.len() } else { 0 }
+    }
+
+    fn content_len_at_offset(&self, offset: usize) -> usize {
+        if self.is_activated() { offset } else { 0 }
+    }
+}
+
+impl Toggleable for MarkedSpan {
+    fn is_activated(&self) -> bool {
+        matches!(self.mark, Mark::Same | Mark::Inserted)
+    }
+
+    fn mark_activated(&mut self) {
+        panic!("Deleted text is never reinserted");
+    }
+
+    fn mark_deactivated(&mut self) {
+        self.mark = match self.mark {
+            Mark::Same => Mark::Deleted,
+            Mark::Inserted => Mark::Removed,
+            Mark::Deleted | Mark::Removed => unreachable!(),
+        };
+    }
+}
+
+/// Render the document at version `to`, annotated with the changes made since version `from`.
+///
+/// Text which was inserted and deleted again between the two versions doesn't appear in the
+/// output. If `from` and `to` are concurrent, the changes are found by trimming off the common
+/// prefix and suffix of the two documents, so separate edits are shown as one big replacement.
+///
+/// The output only depends on the oplog's contents, so rendering the same versions always produces
+/// the same string.
+pub fn diff_annotated(oplog: &ListOpLog, from: &[LV], to: &[LV], style: RenderStyle) -> String {
+    let (only_from, only_to) = oplog.cg.graph.diff(from, to);
+
+    let chars = if only_from.is_empty() {
+        annotate_forward(oplog, from, to)
+    } else if only_to.is_empty() {
+        // Moving backwards. Render the changes from `to` to `from` and swap the marks.
+        let mut chars = annotate_forward(oplog, to, from);
+        for (_, mark) in chars.iter_mut() { *mark = mark.swapped(); }
+        chars
+    } else {
+        annotate_from_checkouts(oplog, from, to)
+    };
+
+    render(&chars, style)
+}
+
+fn annotate_forward(oplog: &ListOpLog, from: &[LV], to: &[LV]) -> Vec<(char, Mark)> {
+    // Every character which appears in either version, in order. The tree refers to ranges in here.
+    let mut buf: Vec<char> = oplog.checkout(from).content().borrow().chars().collect();
+
+    let mut spans = ContentTreeRaw::<MarkedSpan, ContentMetrics>::new();
+    if !buf.is_empty() {
+        spans.insert_at_content(0, MarkedSpan { mark: Mark::Same, chars: (0..buf.len()).into() });
+    }
+
+    for (_lv, origin_op, xf) in oplog.get_xf_operations_full(from, to) {
+        let BaseMoved(pos) = xf else {
+            debug_assert!(matches!(xf, DeleteAlreadyHappened));
+            continue;
+        };
+
+        match origin_op.kind {
+            ListOpKind::Ins => {
+                let start = buf.len();
+                match origin_op.get_content(&oplog.operation_ctx) {
+                    Some(s) if origin_op.loc.fwd => buf.extend(s.chars()),
+                    Some(s) => buf.extend(s.chars().rev()),
+                    None => buf.resize(start + origin_op.len(), char::REPLACEMENT_CHARACTER),
+                }
+                let span = MarkedSpan { mark: Mark::Inserted, chars: (start..buf.len()).into() };
+
+                // Inserts go after any deleted text at the same position.
+                if pos == spans.content_len() {
+                    spans.mut_cursor_at_end().insert(span);
+                } else {
+                    spans.mut_cursor_at_content_pos(pos, false).insert(span);
+                }
+            }
+            ListOpKind::Del => {
+                spans.local_deactivate_at_content_notify(pos, origin_op.len(), null_notify);
+            }
+        }
+    }
+
+    spans.iter()
+        .filter(|span| span.mark != Mark::Removed)
+        .flat_map(|span| buf[span.chars.start..span.chars.end].iter().map(move |c| (*c, span.mark)))
+        .collect()
+}
+
+fn annotate_from_checkouts(oplog: &ListOpLog, from: &[LV], to: &[LV]) -> Vec<(char, Mark)> {
+    let a: Vec<char> = oplog.checkout(from).content().borrow().chars().collect();
+    let b: Vec<char> = oplog.checkout(to).content().borrow().chars().collect();
+
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..].iter().rev().zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    fn mark(s: &[char], mark: Mark) -> impl Iterator<Item = (char, Mark)> + '_ {
+        s.iter().map(move |c| (*c, mark))
+    }
+
+    mark(&a[..prefix], Mark::Same)
+        .chain(mark(&a[prefix..a.len() - suffix], Mark::Deleted))
+        .chain(mark(&b[prefix..b.len() - suffix], Mark::Inserted))
+        .chain(mark(&b[b.len() - suffix..], Mark::Same))
+        .collect()
+}
+
+fn push_escaped(out: &mut String, c: char, style: RenderStyle) {
+    match style {
+        RenderStyle::Html => match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        },
+        RenderStyle::Ansi => {
+            if c.is_control() && c != '\n' && c != '\t' {
+                out.push(char::REPLACEMENT_CHARACTER);
+            } else {
+                out.push(c);
+            }
+        }
+    }
+}
+
+fn render(chars: &[(char, Mark)], style: RenderStyle) -> String {
+    let mut out = String::new();
+
+    for run in chars.chunk_by(|a, b| a.1 == b.1) {
+        let (start, end) = match (style, run[0].1) {
+            (_, Mark::Same) => ("", ""),
+            (RenderStyle::Html, Mark::Inserted) => ("<ins class=\"dt-ins\">", "</ins>"),
+            (RenderStyle::Html, Mark::Deleted) => ("<del class=\"dt-del\">", "</del>"),
+            (RenderStyle::Ansi, Mark::Inserted) => ("\x1b[32m", "\x1b[0m"),
+            (RenderStyle::Ansi, Mark::Deleted) => ("\x1b[31;9m", "\x1b[0m"),
+            (_, Mark::Removed) => unreachable!(),
+        };
+
+        out.push_str(start);
+        for (c, _) in run {
+            push_escaped(&mut out, *c, style);
+        }
+        out.push_str(end);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::{ListCRDT, ListOpLog};
+    use crate::list::old_fuzzer_tools::old_make_random_change;
+    use super::*;
+
+    /// seph writes a document. Then seph and mike edit it concurrently, and the edits are merged.
+    fn two_agent_history() -> (ListOpLog, [usize; 3]) {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let base = oplog.add_insert(seph, 0, "The quick fox jumps");
+        let a = oplog.add_insert_at(seph, &[base], 10, "brown ");
+        let b = oplog.add_delete_at(mike, &[base], 14..19);
+        let b = oplog.add_insert_at(mike, &[b], 14, "leaps");
+        let merged = oplog.add_insert_at(seph, &[a, b], 0, "> ");
+
+        assert_eq!(oplog.checkout(&[merged]).content(), "> The quick brown fox leaps");
+        (oplog, [base, a, b])
+    }
+
+    #[test]
+    fn html_golden() {
+        let (oplog, [base, a, b]) = two_agent_history();
+        let tip = oplog.local_version();
+
+        assert_eq!(diff_annotated(&oplog, &[base], tip.as_ref(), RenderStyle::Html),
+            "<ins class=\"dt-ins\">&gt; </ins>The quick <ins class=\"dt-ins\">brown </ins>fox \
+            <del class=\"dt-del\">jumps</del><ins class=\"dt-ins\">leaps</ins>");
+
+        // Backwards, everything is swapped.
+        assert_eq!(diff_annotated(&oplog, tip.as_ref(), &[base], RenderStyle::Html),
+            "<del class=\"dt-del\">&gt; </del>The quick <del class=\"dt-del\">brown </del>fox \
+            <ins class=\"dt-ins\">jumps</ins><del class=\"dt-del\">leaps</del>");
+
+        // The two branches are concurrent, so they're compared as text.
+        assert_eq!(diff_annotated(&oplog, &[a], &[b], RenderStyle::Html),
+            "The quick <del class=\"dt-del\">brown fox jum</del><ins class=\"dt-ins\">fox lea</ins>ps");
+
+        assert_eq!(diff_annotated(&oplog, &[b], &[b], RenderStyle::Html), "The quick fox leaps");
+    }
+
+    #[test]
+    fn ansi_golden() {
+        let (oplog, [base, _, _]) = two_agent_history();
+
+        assert_eq!(diff_annotated(&oplog, &[base], oplog.local_version_ref(), RenderStyle::Ansi),
+            "\x1b[32m> \x1b[0mThe quick \x1b[32mbrown \x1b[0mfox \x1b[31;9mjumps\x1b[0m\x1b[32mleaps\x1b[0m");
+    }
+
+    #[test]
+    fn content_is_escaped() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "<b>hi</b>");
+        oplog.add_insert(seph, 9, "<script>alert('x' & \"y\")</script>\x1b[2J");
+        oplog.add_delete_without_content(seph, 0..3);
+
+        assert_eq!(diff_annotated(&oplog, &[v], oplog.local_version_ref(), RenderStyle::Html),
+            "<del class=\"dt-del\">&lt;b&gt;</del>hi&lt;/b&gt;<ins class=\"dt-ins\">&lt;script&gt;\
+            alert(&#39;x&#39; &amp; &quot;y&quot;)&lt;/script&gt;\x1b[2J</ins>");
+
+        assert_eq!(diff_annotated(&oplog, &[v], oplog.local_version_ref(), RenderStyle::Ansi),
+            "\x1b[31;9m<b>\x1b[0mhi</b>\x1b[32m<script>alert('x' & \"y\")</script>\u{fffd}[2J\x1b[0m");
+    }
+
+    #[test]
+    fn inserted_then_deleted_text_is_hidden() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "abc");
+        oplog.add_insert(seph, 1, "xyz");
+        oplog.add_delete_without_content(seph, 0..3); // "axy"
+
+        assert_eq!(diff_annotated(&oplog, &[v], oplog.local_version_ref(), RenderStyle::Html),
+            "<del class=\"dt-del\">a</del><ins class=\"dt-ins\">z</ins>bc");
+    }
+
+    #[test]
+    fn annotations_match_both_versions() {
+        // Long enough histories that the annotations don't fit in one tree node.
+        let mut rng = SmallRng::seed_from_u64(2248);
+        let mut doc = ListCRDT::new();
+        let agent = doc.get_or_create_agent_id("seph");
+        let mut versions = vec![doc.oplog.local_version()];
+        for i in 0..500 {
+            old_make_random_change(&mut doc, None, agent, &mut rng);
+            if i % 50 == 49 { versions.push(doc.oplog.local_version()); }
+        }
+
+        for (i, from) in versions.iter().enumerate() {
+            for to in &versions[i..] {
+                let chars = annotate_forward(&doc.oplog, from.as_ref(), to.as_ref());
+                let text = |skip: Mark| chars.iter()
+                    .filter(|(_, mark)| *mark != skip)
+                    .map(|(c, _)| *c)
+                    .collect::<String>();
+                assert_eq!(text(Mark::Inserted), doc.oplog.checkout(from.as_ref()).content().to_string());
+                assert_eq!(text(Mark::Deleted), doc.oplog.checkout(to.as_ref()).content().to_string());
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! The logic for a central server, for projects moving to diamond types from operational
+//! transform.
+//!
+//! In a central-server OT system, clients only keep a plain string and the server version it
+//! matches. They send their edits to the server along with that version, and the server
+//! transforms them against everything the client hadn't seen yet. [`CentralServer`] does the same
+//! thing with an oplog:
+//!
+//! - [`submit`](CentralServer::submit) adds a client's operations to the oplog, and returns them
+//!   transformed to apply at the server's previous version. Send these to every client which is
+//!   up to date.
+//! - [`catchup`](CentralServer::catchup) returns everything a client at some older version is
+//!   missing, transformed so it can be applied to the client's string in order.
+//!
+//! There's no networking here. Getting messages to and from clients is up to you.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use rle::HasLength;
+use crate::causalgraph::agent_assignment::remote_ids::{RemoteFrontierOwned, RemoteVersionOwned, VersionConversionError};
+use crate::dtrange::DTRange;
+use crate::list::{ListBranch, ListOpLog};
+use crate::list::limits::LimitExceeded;
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::unicount::count_chars;
+use crate::{Frontier, LV};
+
+/// A central server, which holds the authoritative oplog. See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct CentralServer {
+    oplog: ListOpLog,
+
+    /// The document at the oplog's tip. This is used to check submitted operations.
+    branch: ListBranch,
+}
+
+/// Returned by [`CentralServer::submit`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubmitResult {
+    /// The local versions the server assigned to the submitted operations.
+    pub assigned: DTRange,
+
+    /// The version of the submitting client's document, once its own operations are applied. Pass
+    /// this to [`catchup`](CentralServer::catchup) to get the operations it hasn't seen yet.
+    pub client_version: RemoteFrontierOwned,
+
+    /// The submitted operations, transformed to apply to the document at the server's version from
+    /// before they were submitted.
+    pub broadcast: Vec<TextOperation>,
+}
+
+/// The error returned by [`CentralServer`]. When an error is returned, the server is not modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError {
+    /// The client named a version the server doesn't know about.
+    UnknownVersion(VersionConversionError),
+
+    /// The submitted operations were empty, or they don't fit in the document at the client's
+    /// version (eg, deleting past the end of the document).
+    InvalidOperation,
+
+    /// The operations would push the document past the limits set with
+    /// [`ListOpLog::set_limits`].
+    LimitExceeded(LimitExceeded),
+
+    /// The client's version is from before the server's history was collapsed with
+    /// [`ListOpLog::gc_before`]. The client needs to start again from the server's current
+    /// version.
+    VersionCollected,
+}
+
+impl Display for SubmitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubmitError::UnknownVersion(_) => write!(f, "client version is not known to the server"),
+            SubmitError::InvalidOperation => write!(f, "operations don't fit the document at the client's version"),
+            SubmitError::LimitExceeded(e) => Display::fmt(e, f),
+            SubmitError::VersionCollected => write!(f, "client version is from before the server's history was collapsed"),
+        }
+    }
+}
+
+impl Error for SubmitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SubmitError::UnknownVersion(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<VersionConversionError> for SubmitError {
+    fn from(e: VersionConversionError) -> Self {
+        SubmitError::UnknownVersion(e)
+    }
+}
+
+impl From<LimitExceeded> for SubmitError {
+    fn from(e: LimitExceeded) -> Self {
+        SubmitError::LimitExceeded(e)
+    }
+}
+
+/// Check the operations apply one after another to a document of length `len`.
+fn ops_fit(mut len: usize, ops: &[TextOperation]) -> bool {
+    if ops.is_empty() { return false; }
+
+    for op in ops {
+        let op_len = op.len();
+        if op_len == 0 { return false; }
+        if let Some(content) = &op.content {
+            if count_chars(content) != op_len { return false; }
+        }
+
+        match op.kind {
+            ListOpKind::Ins => {
+                if op.content.is_none() || op.loc.span.start > len { return false; }
+                len += op_len;
+            }
+            ListOpKind::Del => {
+                if op.loc.span.end > len { return false; }
+                len -= op_len;
+            }
+        }
+    }
+    true
+}
+
+impl CentralServer {
+    /// Create a server with an empty document.
+    pub fn new() -> Self {
+        Self::from_oplog(ListOpLog::new())
+    }
+
+    /// Create a server which starts with the operations in `oplog`.
+    pub fn from_oplog(oplog: ListOpLog) -> Self {
+        let branch = oplog.checkout_tip();
+        Self { oplog, branch }
+    }
+
+    /// The server's oplog.
+    pub fn oplog(&self) -> &ListOpLog { &self.oplog }
+
+    /// The document at the server's current version.
+    pub fn branch(&self) -> &ListBranch { &self.branch }
+
+    /// The server's current version. New clients start here, with a copy of the branch's content.
+    pub fn version(&self) -> RemoteFrontierOwned {
+        self.oplog.cg.agent_assignment.local_to_remote_frontier_owned(self.oplog.local_version_ref())
+    }
+
+    fn local_version(&self, version: &[RemoteVersionOwned]) -> Result<Frontier, SubmitError> {
+        let mut version = self.oplog.cg.agent_assignment.try_remote_to_local_frontier(version.iter())?;
+        // The client might not name the smallest version.
+        version = self.oplog.cg.graph.find_dominators(version.as_ref());
+        if !self.oplog.can_checkout(version.as_ref()) { return Err(SubmitError::VersionCollected); }
+        Ok(version)
+    }
+
+    fn transformed_ops(&self, from: &[LV], to: &[LV]) -> Vec<TextOperation> {
+        self.oplog.iter_xf_operations_from(from, to)
+            .filter_map(|(_, op)| op)
+            .collect()
+    }
+
+    /// Add operations from a client. The operations were made one after another by `client` (an
+    /// agent name), starting from the document at `parent_version`.
+    ///
+    /// The result says which local versions the operations were given, and contains the
+    /// operations transformed to apply at the server's previous version.
+    pub fn submit(&mut self, client: &str, parent_version: &[RemoteVersionOwned], ops: &[TextOperation]) -> Result<SubmitResult, SubmitError> {
+        let parents = self.local_version(parent_version)?;
+
+        // The client's operations have to make sense in the document the client saw.
+        let len_at_parents = if parents == self.oplog.cg.version {
+            self.branch.len()
+        } else {
+            let mut branch = self.branch.clone();
+            branch.advance_to(&self.oplog, parents.as_ref());
+            branch.len()
+        };
+        if !ops_fit(len_at_parents, ops) { return Err(SubmitError::InvalidOperation); }
+
+        let agent = self.oplog.cg.agent_assignment.get_agent_id(client);
+        self.oplog.check_local_ops(agent.unwrap_or(self.oplog.cg.agent_assignment.num_agents() as _), ops)?;
+        let agent = agent.unwrap_or_else(|| self.oplog.get_or_create_agent_id(client));
+
+        let before = self.oplog.local_version();
+        let start = self.oplog.len();
+        let last = self.oplog.add_operations_at(agent, parents.as_ref(), ops);
+        self.branch.merge(&self.oplog, self.oplog.cg.version.as_ref());
+
+        Ok(SubmitResult {
+            assigned: (start..last + 1).into(),
+            client_version: self.oplog.cg.agent_assignment.local_to_remote_frontier_owned(&[last]),
+            broadcast: self.transformed_ops(before.as_ref(), &[last]),
+        })
+    }
+
+    /// Get the operations a client at `version` is missing, transformed so they can be applied to
+    /// its document in order. Afterwards the client is at the server's current
+    /// [`version`](CentralServer::version).
+    pub fn catchup(&self, version: &[RemoteVersionOwned]) -> Result<Vec<TextOperation>, SubmitError> {
+        let version = self.local_version(version)?;
+        Ok(self.transformed_ops(version.as_ref(), self.oplog.cg.version.as_ref()))
+    }
+}
+
+impl Default for CentralServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::list::operation::ListOpKind;
+    use super::*;
+
+    /// A client which only keeps a plain string, like an OT client.
+    struct Client {
+        name: String,
+        text: Vec<char>,
+        /// The server version `text` matches, not counting pending edits.
+        version: RemoteFrontierOwned,
+        /// Local edits which haven't been sent to the server yet.
+        pending: Vec<TextOperation>,
+    }
+
+    fn apply(text: &mut Vec<char>, ops: &[TextOperation]) {
+        for op in ops {
+            let span = op.loc.span;
+            match op.kind {
+                ListOpKind::Ins => {
+                    let content = op.content.as_ref().unwrap();
+                    text.splice(span.start..span.start, content.chars());
+                }
+                ListOpKind::Del => { text.drain(span.start..span.end); }
+            }
+        }
+    }
+
+    impl Client {
+        fn edit(&mut self, rng: &mut SmallRng) {
+            let op = if self.text.is_empty() || rng.gen_bool(0.6) {
+                let pos = rng.gen_range(0..=self.text.len());
+                let content: String = (0..rng.gen_range(1..4)).map(|_| rng.gen_range('a'..='z')).collect();
+                TextOperation::new_insert(pos, &content)
+            } else {
+                let start = rng.gen_range(0..self.text.len());
+                let end = rng.gen_range(start + 1..=self.text.len().min(start + 3));
+                TextOperation::new_delete(start..end)
+            };
+            apply(&mut self.text, std::slice::from_ref(&op));
+            self.pending.push(op);
+        }
+
+        fn sync(&mut self, server: &mut CentralServer, live: &mut Vec<char>) {
+            if !self.pending.is_empty() {
+                let ops = std::mem::take(&mut self.pending);
+                let result = server.submit(&self.name, &self.version, &ops).unwrap();
+                assert_eq!(result.assigned.len(), ops.iter().map(|op| op.len()).sum::<usize>());
+                apply(live, &result.broadcast);
+                self.version = result.client_version;
+            }
+            apply(&mut self.text, &server.catchup(&self.version).unwrap());
+            self.version = server.version();
+        }
+    }
+
+    #[test]
+    fn lagging_clients_converge() {
+        let mut rng = SmallRng::seed_from_u64(5);
+        let mut server = CentralServer::new();
+        // A client which is always up to date, and only sees the broadcast operations.
+        let mut live = vec![];
+        let mut clients: Vec<Client> = (0..3).map(|i| Client {
+            name: format!("client {i}"),
+            text: vec![],
+            version: server.version(),
+            pending: vec![],
+        }).collect();
+
+        for _ in 0..300 {
+            let client = &mut clients[rng.gen_range(0..3)];
+            if rng.gen_bool(0.7) {
+                client.edit(&mut rng);
+            } else {
+                client.sync(&mut server, &mut live);
+            }
+            assert_eq!(live.iter().collect::<String>(), server.branch().content().to_string());
+        }
+
+        // Two rounds, so everyone sees the last edits.
+        for _ in 0..2 {
+            for client in clients.iter_mut() {
+                client.sync(&mut server, &mut live);
+            }
+        }
+
+        let expected = server.oplog().checkout_tip().content().to_string();
+        assert_eq!(server.branch().content().to_string(), expected);
+        for client in &clients {
+            assert!(client.pending.is_empty());
+            assert_eq!(client.text.iter().collect::<String>(), expected);
+        }
+        server.oplog().dbg_check(true);
+    }
+
+    #[test]
+    fn submit_rejects_bad_input() {
+        let mut server = CentralServer::new();
+        let v0 = server.version();
+        server.submit("seph", &v0, &[TextOperation::new_insert(0, "hello")]).unwrap();
+        let before = server.oplog().clone();
+
+        // The delete is past the end of the document the client saw.
+        assert_eq!(server.submit("mike", &v0, &[TextOperation::new_delete(0..1)]),
+                   Err(SubmitError::InvalidOperation));
+        assert_eq!(server.submit("mike", &v0, &[]), Err(SubmitError::InvalidOperation));
+        let unknown = [RemoteVersionOwned("nobody".into(), 0)];
+        assert_eq!(server.submit("mike", &unknown, &[TextOperation::new_insert(0, "x")]),
+                   Err(SubmitError::UnknownVersion(VersionConversionError::UnknownAgent)));
+        assert_eq!(server.catchup(&[RemoteVersionOwned("seph".into(), 10)]),
+                   Err(SubmitError::UnknownVersion(VersionConversionError::SeqInFuture)));
+        assert_eq!(server.oplog(), &before);
+        assert_eq!(server.oplog().cg.agent_assignment.get_agent_id("mike"), None);
+
+        // A client at the start can still insert, and catch up from there.
+        let result = server.submit("mike", &v0, &[TextOperation::new_insert(0, "oh ")]).unwrap();
+        assert_eq!(result.assigned, (5..8).into());
+        assert_eq!(result.broadcast, [TextOperation::new_insert(0, "oh ")]);
+        assert_eq!(server.catchup(&v0).unwrap().len(), 2);
+        assert_eq!(server.branch().content(), "oh hello");
+    }
+}
@@ -0,0 +1,116 @@
+//! A quick overview of what's in an oplog: who edited it, how much history it has and how
+//! branchy that history is. Everything here is read straight out of the oplog's indexes, so it
+//! stays fast on huge documents (no checkout needed).
+
+use std::collections::BTreeMap;
+use smartstring::alias::String as SmartString;
+use crate::list::ListOpLog;
+
+/// An agent which has edited the document, and the next seq number it would use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentSummary {
+    pub name: SmartString,
+    pub next_seq: usize,
+}
+
+/// See [`ListOpLog::summary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpLogSummary {
+    pub agents: Vec<AgentSummary>,
+    /// The number of operations (keystrokes) in the oplog. This is the same as
+    /// [`ListOpLog::len`].
+    pub num_operations: usize,
+    /// The number of entries in the causal graph. Runs of operations made one after another are
+    /// stored as a single entry, so this is a rough measure of how fragmented the history is.
+    pub num_history_entries: usize,
+    /// The number of concurrent branches in the history. Linear history has 1 branch, and every
+    /// time history forks each extra child adds another.
+    pub num_branches: usize,
+    /// The number of history entries which merge 2 or more branches together.
+    pub num_merges: usize,
+}
+
+impl ListOpLog {
+    /// Summarize the contents of the oplog. See [`OpLogSummary`].
+    pub fn summary(&self) -> OpLogSummary {
+        let aa = &self.cg.agent_assignment;
+        let agents = (0..aa.num_agents())
+            .map(|agent| AgentSummary {
+                name: aa.get_agent_name(agent as _).into(),
+                next_seq: aa.client_data[agent].get_next_seq(),
+            })
+            .collect();
+
+        let entries = &self.cg.graph.entries;
+
+        // Count the children of each version. None is the root.
+        let mut children: BTreeMap<Option<usize>, usize> = BTreeMap::new();
+        let mut num_merges = 0;
+        for e in entries.iter() {
+            if e.parents.is_empty() {
+                *children.entry(None).or_default() += 1;
+            }
+            for p in e.parents.iter() {
+                *children.entry(Some(*p)).or_default() += 1;
+            }
+            if e.parents.len() >= 2 { num_merges += 1; }
+        }
+
+        let num_branches = if entries.is_empty() { 0 } else {
+            1 + children.iter().map(|(v, &count)| {
+                // Versions in the middle of an entry are also followed by the rest of the entry.
+                let continues = v.is_some_and(|v| entries.find_packed(v).span.last() != v);
+                (count + continues as usize).saturating_sub(1)
+            }).sum::<usize>()
+        };
+
+        OpLogSummary {
+            agents,
+            num_operations: self.len(),
+            num_history_entries: entries.num_entries(),
+            num_branches,
+            num_merges,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::ListOpLog;
+
+    #[test]
+    fn summary_counts_branches() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+
+        let t = oplog.add_insert(seph, 0, "hi");
+        let s = oplog.summary();
+        assert_eq!(s.num_operations, 2);
+        assert_eq!(s.num_history_entries, 1);
+        assert_eq!(s.num_branches, 1);
+        assert_eq!(s.agents[0].name, "seph");
+        assert_eq!(s.agents[0].next_seq, 2);
+        assert_eq!(s.agents[1].next_seq, 0);
+
+        // Two concurrent edits from the middle of the first entry make 3 branches in total.
+        let a = oplog.add_insert_at(mike, &[t - 1], 0, "x");
+        let b = oplog.add_insert_at(mike, &[t - 1], 0, "y");
+        assert_eq!(oplog.summary().num_branches, 3);
+        assert_eq!(oplog.summary().num_merges, 0);
+
+        oplog.add_insert_at(seph, &[t, a, b], 0, "z");
+        let s = oplog.summary();
+        assert_eq!(s.num_branches, 3);
+        assert_eq!(s.num_merges, 1);
+        assert_eq!(s.num_history_entries, 4);
+    }
+
+    #[test]
+    fn summary_of_empty_oplog() {
+        let s = ListOpLog::new().summary();
+        assert!(s.agents.is_empty());
+        assert_eq!(s.num_operations, 0);
+        assert_eq!(s.num_branches, 0);
+    }
+}
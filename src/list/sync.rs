@@ -0,0 +1,364 @@
+//! A small, transport-agnostic protocol for syncing two oplogs.
+//!
+//! Each peer makes a [`SyncSession`] and sends its [`start_message`](SyncSession::start_message)
+//! to the other. Every message passed to [`on_message`](SyncSession::on_message) might produce a
+//! reply, which should be sent back. Once neither side has anything to reply with, both oplogs
+//! contain the same operations.
+//!
+//! Every message names everything its sender has (as a [`VersionSummary`]), and optionally
+//! carries a patch of operations the receiver is missing. Patches always start from a version the
+//! receiver is known to have, and oplogs never forget operations. So messages can be duplicated,
+//! reordered or dropped without breaking anything - dropped messages just need to be resent. When
+//! two peers connect, they converge after 3 messages each.
+//!
+//! Messages are encoded as:
+//!
+//! - The protocol version ([`SYNC_PROTOCOL_VERSION`])
+//! - The number of agents in the summary, then for each agent its name and the number of known
+//!   sequence number ranges, followed by each range as (gap since the previous range, length)
+//! - The length of the patch in bytes (0 if there's no patch), and the patch. Patches are encoded
+//!   with [`ENCODE_PATCH`].
+//!
+//! All numbers are varints.
+
+use rle::HasLength;
+use smallvec::SmallVec;
+use crate::causalgraph::summary::{VersionSummary, VSEntry};
+use crate::encoding::bufparser::BufParser;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
+use crate::encoding::tools::push_str;
+use crate::encoding::varint::push_usize;
+use crate::list::ListOpLog;
+use crate::list::encoding::ENCODE_PATCH;
+use crate::{DTRange, Frontier, LV};
+
+/// The version of the message format used by [`SyncSession`]. Messages from other versions are
+/// rejected with [`ParseErrorKind::UnsupportedProtocolVersion`].
+pub const SYNC_PROTOCOL_VERSION: usize = 1;
+
+/// One side of a sync between two peers. See the [module documentation](self).
+///
+/// The session only holds what it knows about the remote peer. The oplog is passed in with each
+/// call, and can be edited between calls.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncSession {
+    /// Everything the remote peer is known to have. This only grows, since peers never lose
+    /// operations.
+    remote_version: Frontier,
+
+    /// Everything which has already been sent to the remote peer in a patch (along with what it
+    /// had). Replies only include a patch when there's something new to send.
+    sent_version: Frontier,
+
+    /// Set once the first message from the remote peer arrives. Until then we don't know what to
+    /// send.
+    heard_from_remote: bool,
+}
+
+/// What to do after receiving a message. See [`SyncSession::on_message`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncStep {
+    /// A message to send back to the remote peer, if there is one.
+    pub reply: Option<Vec<u8>>,
+
+    /// The range of local versions merged into the oplog from the message, if any. Branches need
+    /// to merge these changes to see them.
+    pub applied: Option<DTRange>,
+
+    /// True if both peers have all the same operations, as far as this peer knows.
+    pub done: bool,
+}
+
+#[derive(Debug)]
+struct SyncMessage<'a> {
+    summary: VersionSummary,
+    patch: Option<&'a [u8]>,
+}
+
+impl SyncMessage<'_> {
+    fn encode(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        push_usize(&mut result, SYNC_PROTOCOL_VERSION);
+
+        push_usize(&mut result, self.summary.0.len());
+        for VSEntry { name, seq_ranges } in self.summary.0.iter() {
+            push_str(&mut result, name);
+            push_usize(&mut result, seq_ranges.len());
+            let mut prev_end = 0;
+            for r in seq_ranges {
+                push_usize(&mut result, r.start - prev_end);
+                push_usize(&mut result, r.len());
+                prev_end = r.end;
+            }
+        }
+
+        let patch = self.patch.unwrap_or(&[]);
+        push_usize(&mut result, patch.len());
+        result.extend_from_slice(patch);
+        result
+    }
+
+    fn decode(data: &[u8]) -> Result<SyncMessage<'_>, ParseError> {
+        let mut reader = BufParser(data);
+        if reader.next_usize()? != SYNC_PROTOCOL_VERSION {
+            return Err(ParseErrorKind::UnsupportedProtocolVersion.into());
+        }
+
+        let num_agents = reader.next_usize()?;
+        let mut summary = VersionSummary::default();
+        for _ in 0..num_agents {
+            let name = reader.next_str()?;
+            let num_ranges = reader.next_usize()?;
+            let mut seq_ranges = SmallVec::new();
+            let mut prev_end: usize = 0;
+            for _ in 0..num_ranges {
+                let gap = reader.next_usize()?;
+                let len = reader.next_usize()?;
+                let start = prev_end.checked_add(gap).ok_or(ParseErrorKind::InvalidLength)?;
+                let end = start.checked_add(len).ok_or(ParseErrorKind::InvalidLength)?;
+                seq_ranges.push((start..end).into());
+                prev_end = end;
+            }
+            summary.0.push(VSEntry { name: name.into(), seq_ranges });
+        }
+
+        let patch_len = reader.next_usize()?;
+        let patch = reader.next_n_bytes(patch_len)?;
+        reader.expect_empty()?;
+
+        Ok(SyncMessage {
+            summary,
+            patch: if patch.is_empty() { None } else { Some(patch) },
+        })
+    }
+}
+
+impl SyncSession {
+    /// Make a session for syncing with a remote peer we haven't heard from yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make a message naming everything in the oplog, along with a patch of everything the remote
+    /// peer is missing (if we've heard from it).
+    ///
+    /// Send this when connecting, after making local changes, and whenever a message might have
+    /// been lost (like after a timeout). It's always safe to send.
+    pub fn start_message(&mut self, oplog: &ListOpLog) -> Vec<u8> {
+        self.make_message(oplog, true)
+    }
+
+    /// Process a message from the remote peer. Any operations in the message are merged into the
+    /// oplog, and the reply (if any) should be sent back to the remote peer.
+    ///
+    /// If the message can't be parsed or merged, an error is returned and neither the oplog nor
+    /// the session are modified.
+    pub fn on_message(&mut self, oplog: &mut ListOpLog, msg: &[u8]) -> Result<SyncStep, ParseError> {
+        let msg = SyncMessage::decode(msg)?;
+
+        let start = oplog.len();
+        if let Some(patch) = msg.patch {
+            oplog.merge_data(patch)?;
+        }
+        let applied = if oplog.len() > start { Some((start..oplog.len()).into()) } else { None };
+
+        // The summary names everything the remote peer had when it sent the message.
+        let (remote_version, missing) = oplog.cg.intersect_with_summary(&msg.summary, self.remote_version.as_ref());
+        self.remote_version = remote_version;
+        self.heard_from_remote = true;
+
+        let remote_up_to_date = self.remote_version == oplog.cg.version;
+        let done = remote_up_to_date && missing.is_none();
+
+        // Reply if we have something new to send, or if the remote peer needs to know we got its
+        // patch. If the remote peer has operations we're missing, it'll send them once it gets
+        // our start message.
+        let reply = if self.has_unsent(oplog) || msg.patch.is_some() {
+            Some(self.make_message(oplog, false))
+        } else { None };
+
+        Ok(SyncStep { reply, applied, done })
+    }
+
+    /// True if the oplog has operations which the remote peer doesn't have, and which we haven't
+    /// sent it yet.
+    fn has_unsent(&self, oplog: &ListOpLog) -> bool {
+        let mut known: SmallVec<[LV; 4]> = self.remote_version.iter().copied().collect();
+        known.extend(self.sent_version.iter().copied());
+        oplog.cg.graph.find_dominators(&known) != oplog.cg.version
+    }
+
+    fn make_message(&mut self, oplog: &ListOpLog, resend: bool) -> Vec<u8> {
+        let send_patch = self.heard_from_remote
+            && self.remote_version != oplog.cg.version
+            && (resend || self.has_unsent(oplog));
+
+        let patch = if send_patch {
+            self.sent_version = oplog.cg.version.clone();
+            Some(oplog.encode_from(ENCODE_PATCH, self.remote_version.as_ref()))
+        } else { None };
+
+        SyncMessage {
+            summary: oplog.cg.agent_assignment.summarize_versions(),
+            patch: patch.as_deref(),
+        }.encode()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::prelude::*;
+    use crate::encoding::parseerror::ParseErrorKind;
+    use crate::list::ListOpLog;
+    use super::*;
+
+    fn sample_oplogs() -> (ListOpLog, ListOpLog) {
+        let mut a = ListOpLog::new();
+        let seph = a.get_or_create_agent_id("seph");
+        a.add_insert(seph, 0, "hello world");
+
+        let mut b = ListOpLog::new();
+        b.add_missing_operations_from(&a);
+        let mike = b.get_or_create_agent_id("mike");
+        b.add_insert(mike, 0, "oh, ");
+        a.add_insert(seph, 11, "!");
+        (a, b)
+    }
+
+    fn assert_converged(a: &ListOpLog, b: &ListOpLog) {
+        assert_eq!(a.checkout_tip().content(), b.checkout_tip().content());
+        assert_eq!(a.cg.agent_assignment.summarize_versions(), b.cg.agent_assignment.summarize_versions());
+    }
+
+    #[test]
+    fn sync_without_loss() {
+        let (mut a, mut b) = sample_oplogs();
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+
+        // Both peers send their start messages at the same time.
+        let mut to_a = vec![sb.start_message(&b)];
+        let mut to_b = vec![sa.start_message(&a)];
+        let mut sent = 2;
+        let (mut done_a, mut done_b) = (false, false);
+
+        while !to_a.is_empty() || !to_b.is_empty() {
+            let (msgs_a, msgs_b) = (std::mem::take(&mut to_a), std::mem::take(&mut to_b));
+            for msg in msgs_a {
+                let step = sa.on_message(&mut a, &msg).unwrap();
+                done_a = step.done;
+                to_b.extend(step.reply);
+            }
+            for msg in msgs_b {
+                let step = sb.on_message(&mut b, &msg).unwrap();
+                done_b = step.done;
+                to_a.extend(step.reply);
+            }
+            sent += to_a.len() + to_b.len();
+        }
+
+        assert!(done_a && done_b);
+        assert_converged(&a, &b);
+        assert_eq!(a.checkout_tip().content(), "oh, hello world!");
+        assert_eq!(sent, 6);
+    }
+
+    #[test]
+    fn one_sided_sync() {
+        let (a, _) = sample_oplogs();
+        let mut b = ListOpLog::new();
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+
+        let msg = sb.start_message(&b);
+        let patch = sa.on_message(&mut a.clone(), &msg).unwrap().reply.unwrap();
+        let step = sb.on_message(&mut b, &patch).unwrap();
+        assert_eq!(step.applied, Some((0..a.len()).into()));
+        assert!(step.done);
+        assert_converged(&a, &b);
+
+        // The acknowledgement finishes the sync without any more messages.
+        let step = sa.on_message(&mut a.clone(), &step.reply.unwrap()).unwrap();
+        assert_eq!(step, SyncStep { reply: None, applied: None, done: true });
+    }
+
+    /// A channel which drops, duplicates and reorders messages.
+    struct LossyChannel {
+        rng: SmallRng,
+        queue: Vec<Vec<u8>>,
+    }
+
+    impl LossyChannel {
+        fn send(&mut self, msg: Vec<u8>) {
+            match self.rng.gen_range(0..10) {
+                0..=1 => {} // Dropped.
+                2 => {
+                    self.queue.push(msg.clone());
+                    self.queue.push(msg);
+                }
+                _ => self.queue.push(msg),
+            }
+        }
+
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            if self.queue.is_empty() { return None; }
+            let i = self.rng.gen_range(0..self.queue.len());
+            Some(self.queue.swap_remove(i))
+        }
+    }
+
+    #[test]
+    fn sync_over_lossy_channel() {
+        for seed in 0..100 {
+            let (mut a, mut b) = sample_oplogs();
+            let mut sa = SyncSession::new();
+            let mut sb = SyncSession::new();
+            let mut to_a = LossyChannel { rng: SmallRng::seed_from_u64(seed), queue: vec![] };
+            let mut to_b = LossyChannel { rng: SmallRng::seed_from_u64(seed + 1000), queue: vec![] };
+            let (mut done_a, mut done_b) = (false, false);
+            let mut sent = 0;
+
+            for _round in 0..20 {
+                if done_a && done_b { break; }
+                // Nothing in flight, so resend (like after a timeout).
+                to_b.send(sa.start_message(&a));
+                to_a.send(sb.start_message(&b));
+                sent += 2;
+
+                loop {
+                    if let Some(msg) = to_a.recv() {
+                        let step = sa.on_message(&mut a, &msg).unwrap();
+                        done_a = step.done;
+                        if let Some(reply) = step.reply { to_b.send(reply); sent += 1; }
+                    } else if let Some(msg) = to_b.recv() {
+                        let step = sb.on_message(&mut b, &msg).unwrap();
+                        done_b = step.done;
+                        if let Some(reply) = step.reply { to_a.send(reply); sent += 1; }
+                    } else { break; }
+                }
+            }
+
+            assert!(done_a && done_b, "seed {seed} did not finish");
+            assert_converged(&a, &b);
+            assert!(sent <= 30, "seed {seed} sent {sent} messages");
+        }
+    }
+
+    #[test]
+    fn bad_messages_are_rejected() {
+        let (mut a, b) = sample_oplogs();
+        let before = a.clone();
+        let mut sa = SyncSession::new();
+        let mut sb = SyncSession::new();
+        let msg = sb.start_message(&b);
+
+        let mut wrong_version = msg.clone();
+        wrong_version[0] = 2;
+        assert_eq!(sa.on_message(&mut a, &wrong_version).unwrap_err(), ParseErrorKind::UnsupportedProtocolVersion);
+        for len in 0..msg.len() {
+            assert!(sa.on_message(&mut a, &msg[..len]).is_err());
+        }
+        assert_eq!(sa, SyncSession::new());
+        assert_eq!(a, before);
+    }
+}
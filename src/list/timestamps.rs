@@ -0,0 +1,193 @@
+//! Wall clock timestamps for operations. Editors use these to show when a document was last
+//! modified, or to sort changes for display.
+//!
+//! Timestamps are optional. They're stored as runs of operations made at the same time, and
+//! operations without a timestamp just aren't in the list. The oplog never looks at them itself -
+//! merging and checking out work the same either way - so applications can pick their own units.
+//!
+//! Timestamps are sent to other peers along with the operations they belong to. Files store them
+//! in an extra chunk, which is only written if there are any. Decoders which don't know about
+//! timestamps skip the chunk and load the rest of the file as normal.
+
+use rle::{HasLength, RleRun};
+use crate::dtrange::DTRange;
+use crate::list::ListOpLog;
+use crate::rle::{KVPair, RleVec};
+use crate::LV;
+
+impl ListOpLog {
+    /// Set the wall clock time at which a range of operations was made. This replaces any
+    /// timestamp previously set for those operations.
+    ///
+    /// The oplog doesn't interpret timestamps. `dt git-import` stores seconds since the unix epoch.
+    ///
+    /// Panics if the range isn't in the oplog.
+    pub fn set_timestamp(&mut self, range: DTRange, timestamp: i64) {
+        assert!(range.end <= self.len(), "Range {range:?} is not in the oplog");
+        if range.is_empty() { return; }
+
+        // Fast path. Timestamps are usually set for newly added operations, at the end.
+        if range.start >= self.timestamps.end() {
+            self.timestamps.push(KVPair(range.start, RleRun::new(timestamp, range.len())));
+            return;
+        }
+
+        let mut pieces = vec![(range.start, range.end, timestamp)];
+        for KVPair(start, run) in self.timestamps.iter() {
+            let end = start + run.len;
+            pieces.push((*start, end.min(range.start), run.val));
+            pieces.push(((*start).max(range.end), end, run.val));
+        }
+        pieces.sort_unstable_by_key(|(start, _, _)| *start);
+
+        let mut timestamps = RleVec::new();
+        for (start, end, val) in pieces {
+            if start < end {
+                timestamps.push(KVPair(start, RleRun::new(val, end - start)));
+            }
+        }
+        self.timestamps = timestamps;
+    }
+
+    /// Get the timestamp of the named operation, if one was set.
+    pub fn timestamp_of(&self, v: LV) -> Option<i64> {
+        self.timestamps.find(v).map(|KVPair(_, run)| run.val)
+    }
+
+    /// Iterate through the timestamped operations in the oplog, as runs of operations with the same
+    /// timestamp. The runs are in local version order. Operations without a timestamp are skipped.
+    pub fn iter_timestamps(&self) -> impl Iterator<Item = (DTRange, i64)> + '_ {
+        self.timestamps.iter().map(|KVPair(start, run)| ((*start..start + run.len).into(), run.val))
+    }
+
+    /// Drop the timestamps for operations from `len` onwards.
+    pub(crate) fn truncate_timestamps(&mut self, len: usize) {
+        let idx = match self.timestamps.find_index(len) {
+            // A run which starts before len (and was maybe extended past it) is trimmed below.
+            Ok(idx) if self.timestamps.0[idx].0 < len => idx + 1,
+            Ok(idx) | Err(idx) => idx,
+        };
+        self.timestamps.0.truncate(idx);
+        if let Some(KVPair(start, run)) = self.timestamps.0.last_mut() {
+            run.len = run.len.min(len - *start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::encoding::{iter_chunks, load_skipping_chunk, verify_data, ENCODE_FULL, EncodeOptions, ListChunkType};
+    use crate::list::ListOpLog;
+
+    fn timestamped() -> ListOpLog {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        oplog.add_insert(seph, 0, "hello");
+        oplog.set_timestamp((0..5).into(), 1000);
+        oplog.add_insert(seph, 5, " world");
+        let b = oplog.add_delete_at(mike, &[4], 0..1);
+        oplog.set_timestamp((b..b + 1).into(), -20);
+        oplog
+    }
+
+    #[test]
+    fn set_and_get_timestamps() {
+        let mut oplog = timestamped();
+        assert_eq!(oplog.timestamp_of(0), Some(1000));
+        assert_eq!(oplog.timestamp_of(4), Some(1000));
+        assert_eq!(oplog.timestamp_of(5), None);
+        assert_eq!(oplog.timestamp_of(11), Some(-20));
+        assert_eq!(oplog.iter_timestamps().collect::<Vec<_>>(), vec![
+            ((0..5).into(), 1000),
+            ((11..12).into(), -20),
+        ]);
+
+        // Overwriting part of a run splits it.
+        oplog.set_timestamp((3..7).into(), 2000);
+        assert_eq!(oplog.iter_timestamps().collect::<Vec<_>>(), vec![
+            ((0..3).into(), 1000),
+            ((3..7).into(), 2000),
+            ((11..12).into(), -20),
+        ]);
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        let oplog = timestamped();
+        let opts = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+        let data = oplog.encode(opts.clone());
+        let loaded = ListOpLog::load_from(&data).unwrap();
+        assert_eq!(loaded, oplog);
+        assert_eq!(loaded.iter_timestamps().collect::<Vec<_>>(), oplog.iter_timestamps().collect::<Vec<_>>());
+        verify_data(&data).unwrap();
+
+        // Oplogs with different timestamps aren't equal.
+        let mut other = oplog.clone();
+        other.set_timestamp((5..6).into(), 1);
+        assert_ne!(other, oplog);
+
+        // Patches carry the timestamps of the operations they contain, whether or not they
+        // overlap with what we already have.
+        let mut hello = ListOpLog::new();
+        let seph = hello.get_or_create_agent_id("seph");
+        hello.add_insert(seph, 0, "hello");
+        hello.set_timestamp((0..5).into(), 1000);
+        for patch in [oplog.encode_from(opts.clone(), &[4]), data] {
+            let mut dest = hello.clone();
+            dest.decode_and_add(&patch).unwrap();
+            assert_eq!(dest, oplog);
+        }
+
+        let mut dest = ListOpLog::new();
+        dest.add_missing_operations_from(&oplog);
+        assert_eq!(dest, oplog);
+    }
+
+    #[test]
+    fn truncate_to_keeps_timestamps() {
+        let oplog = timestamped();
+        assert_eq!(oplog.truncate_to(oplog.cg.version.as_ref()), oplog);
+
+        // Dropping the concurrent delete (and the insert after it) moves the delete down to
+        // version 5, along with its timestamp.
+        let truncated = oplog.truncate_to(&[4, 11]);
+        assert_eq!(truncated.iter_timestamps().collect::<Vec<_>>(), vec![
+            ((0..5).into(), 1000),
+            ((5..6).into(), -20),
+        ]);
+    }
+
+    #[test]
+    fn truncate_trims_extended_runs() {
+        let mut oplog = timestamped();
+        // Operations added with the same timestamp extend the last run.
+        let len = oplog.len();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "abc");
+        oplog.set_timestamp((len..v + 1).into(), -20);
+        assert_eq!(oplog.timestamps.0.len(), 2);
+
+        oplog.truncate_timestamps(len);
+        assert_eq!(oplog.iter_timestamps().collect::<Vec<_>>(), timestamped().iter_timestamps().collect::<Vec<_>>());
+        oplog.truncate_timestamps(3);
+        assert_eq!(oplog.iter_timestamps().collect::<Vec<_>>(), vec![((0..3).into(), 1000)]);
+    }
+
+    #[test]
+    fn timestamps_are_optional() {
+        let oplog = timestamped();
+        let find_chunk = |data: &[u8]| iter_chunks(data)
+            .map(|c| c.unwrap())
+            .find(|c| c.chunk_type == ListChunkType::Timestamps);
+
+        let mut plain = oplog.clone();
+        plain.timestamps.0.clear();
+        let plain_data = plain.encode(ENCODE_FULL);
+        assert_eq!(find_chunk(&plain_data), None);
+        assert_eq!(ListOpLog::load_from(&plain_data).unwrap().iter_timestamps().count(), 0);
+
+        // Decoders which don't know about timestamps skip the chunk and load everything else.
+        assert_eq!(load_skipping_chunk(&oplog.encode(ENCODE_FULL), ListChunkType::Timestamps).unwrap(), plain);
+    }
+}
@@ -0,0 +1,315 @@
+//! Local undo and redo for [`ListBranch`].
+//!
+//! Undoing a group of operations adds new operations to the oplog which invert them (a delete to
+//! undo an insert, and an insert to undo a delete). The inverse operations are created at the
+//! version right after the group, and then merged into the branch like any other concurrent
+//! change. That way they're transformed past everything which happened since, and they only
+//! affect the characters the original group touched.
+
+use crate::dtrange::DTRange;
+use crate::list::{EditError, ListBranch, ListCRDT, ListOpLog};
+use crate::list::operation::{ListOpKind, TextOperation};
+use crate::list::origin::Origin;
+use crate::listmerge::merge::reverse_str;
+use crate::rle::KVPair;
+use crate::{AgentId, LV};
+
+/// The undo state of a branch. This is replica-local, and isn't stored with the oplog.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UndoHistory {
+    /// Operations created by undo. Undo skips these - undoing an undo is what redo is for.
+    undo_ops: Vec<DTRange>,
+
+    /// Operations created by redo. Unlike undo operations these can be undone again.
+    redo_ops: Vec<DTRange>,
+
+    /// Groups of operations which have been undone, so undo skips them.
+    undone: Vec<DTRange>,
+
+    /// Each undo which can be redone, as (agent, undo operations). The most recent undo is last.
+    redo_stack: Vec<(AgentId, DTRange)>,
+}
+
+fn find_range(ranges: &[DTRange], v: LV) -> Option<DTRange> {
+    ranges.iter().copied().find(|r| r.contains(v))
+}
+
+impl UndoHistory {
+    /// If undo should skip the operation at v, returns the range of operations to skip over.
+    fn skipped(&self, v: LV) -> Option<DTRange> {
+        find_range(&self.undo_ops, v).or_else(|| find_range(&self.undone, v))
+    }
+
+    fn is_generated(&self, v: LV) -> bool {
+        find_range(&self.undo_ops, v).is_some() || find_range(&self.redo_ops, v).is_some()
+    }
+}
+
+impl ListOpLog {
+    /// The operations which invert the named range of (linear) operations, in the order they
+    /// should be applied. The operations are positioned at the version right after the range.
+    fn inverse_ops(&self, range: DTRange) -> Result<Vec<TextOperation>, EditError> {
+        let mut ops = Vec::new();
+        for (KVPair(_, op), content) in self.iter_range_simple(range) {
+            // Content is stored in the order the operation happened, which is backwards for
+            // reversed operations (like backspacing).
+            let doc_content = content.map(|c| if op.loc.fwd { c.into() } else { reverse_str(c) });
+
+            ops.push(match (op.kind, doc_content) {
+                (ListOpKind::Ins, Some(c)) => TextOperation::new_delete_with_content_range(op.loc.span.into(), c),
+                (ListOpKind::Ins, None) => TextOperation::new_delete(op.loc.span.into()),
+                (ListOpKind::Del, Some(c)) => TextOperation::new_insert(op.loc.span.start, &c),
+                (ListOpKind::Del, None) => { return Err(EditError::DeletedContentMissing); }
+            });
+        }
+        ops.reverse();
+        Ok(ops)
+    }
+}
+
+impl ListBranch {
+    /// Find the most recent group of operations by the agent which undo should revert. Groups are
+    /// runs of consecutive local operations, so typing a word and then undoing removes the whole
+    /// word.
+    fn last_undo_group(&self, oplog: &ListOpLog, agent: AgentId) -> Option<DTRange> {
+        let client = oplog.cg.agent_assignment.client_data.get(agent as usize)?;
+        let can_undo = |v: LV| oplog.origin_of(v) == Origin::Local
+            && oplog.cg.graph.frontier_contains_version(self.version.as_ref(), v);
+
+        for KVPair(_, lvs) in client.item_times.0.iter().rev() {
+            let mut end = lvs.end;
+            while end > lvs.start {
+                let last = end - 1;
+                if let Some(skip) = self.undo.skipped(last) {
+                    end = skip.start.max(lvs.start);
+                } else if !can_undo(last) {
+                    end = last;
+                } else {
+                    // Operations in a graph entry are linear, so they can be inverted in order.
+                    let min = lvs.start.max(oplog.cg.graph.entries.find_packed(last).span.start);
+                    let mut start = last;
+                    while start > min && self.undo.skipped(start - 1).is_none() && can_undo(start - 1) {
+                        start -= 1;
+                    }
+                    return Some((start..end).into());
+                }
+            }
+        }
+        None
+    }
+
+    /// Add the inverse of the range of operations to the oplog, and merge it into the branch.
+    /// Returns the range of the new operations.
+    fn apply_inverse(&mut self, oplog: &mut ListOpLog, agent: AgentId, range: DTRange) -> Result<DTRange, EditError> {
+        if self.read_only { return Err(EditError::BranchReadOnly); }
+        let ops = oplog.inverse_ops(range)?;
+
+        let start = oplog.len();
+        let last = oplog.try_add_operations_at(agent, &[range.last()], &ops)?;
+        self.merge(oplog, &[last]);
+        Ok((start..last + 1).into())
+    }
+
+    /// Undo the agent's most recent group of local edits in this branch, by adding operations to
+    /// the oplog which revert them. Groups are runs of consecutive local operations by the agent,
+    /// so typing a word and then calling undo removes the whole word. Calling undo again reverts
+    /// the group before that, and so on.
+    ///
+    /// Returns the version of the last new operation, or `None` if there was nothing to undo.
+    ///
+    /// Undoing a delete reinserts the deleted text, so the oplog must store deleted content. Deletes
+    /// made with [`delete_without_content`](ListBranch::delete_without_content), or loaded from
+    /// files encoded without `store_deleted_content`, fail with
+    /// [`EditError::DeletedContentMissing`]. When an error is returned, neither the branch nor the
+    /// oplog are modified.
+    ///
+    /// Restored text is inserted as new characters. Undoing the insert which originally created
+    /// the text only removes the original characters, not copies restored by later undos.
+    ///
+    /// Undo history is stored in the branch. It isn't shared with other branches, or saved with
+    /// the oplog.
+    pub fn undo(&mut self, oplog: &mut ListOpLog, agent: AgentId) -> Result<Option<LV>, EditError> {
+        let Some(group) = self.last_undo_group(oplog, agent) else { return Ok(None); };
+        let inverse = self.apply_inverse(oplog, agent, group)?;
+
+        self.undo.undone.push(group);
+        self.undo.undo_ops.push(inverse);
+        self.undo.redo_stack.push((agent, inverse));
+        Ok(Some(inverse.last()))
+    }
+
+    /// Redo the agent's most recently undone group of edits. Redo is only possible until the agent
+    /// makes a new edit (other than undo or redo).
+    ///
+    /// Returns the version of the last new operation, or `None` if there was nothing to redo.
+    pub fn redo(&mut self, oplog: &mut ListOpLog, agent: AgentId) -> Result<Option<LV>, EditError> {
+        let Some(idx) = self.undo.redo_stack.iter().rposition(|(a, _)| *a == agent) else { return Ok(None); };
+        let (_, undo_ops) = self.undo.redo_stack[idx];
+
+        // Any new edits from the agent since the undo clear its redo history.
+        let client = &oplog.cg.agent_assignment.client_data[agent as usize];
+        let edited_since = client.item_times.0.iter()
+            .flat_map(|KVPair(_, lvs)| lvs.start.max(undo_ops.end)..lvs.end)
+            .any(|v| !self.undo.is_generated(v));
+        if edited_since {
+            self.undo.redo_stack.retain(|(a, _)| *a != agent);
+            return Ok(None);
+        }
+
+        let redo_ops = self.apply_inverse(oplog, agent, undo_ops)?;
+        self.undo.redo_stack.remove(idx);
+        self.undo.redo_ops.push(redo_ops);
+        Ok(Some(redo_ops.last()))
+    }
+}
+
+impl ListCRDT {
+    /// Undo the agent's most recent group of edits. See [`ListBranch::undo`].
+    pub fn undo(&mut self, agent: AgentId) -> Result<Option<LV>, EditError> {
+        self.branch.undo(&mut self.oplog, agent)
+    }
+
+    /// Redo the agent's most recently undone edits. See [`ListBranch::redo`].
+    pub fn redo(&mut self, agent: AgentId) -> Result<Option<LV>, EditError> {
+        self.branch.redo(&mut self.oplog, agent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::list::{EditError, ListCRDT, ListOpLog};
+    use crate::list::encoding::{ENCODE_FULL, EncodeOptions};
+
+    #[test]
+    fn undo_removes_typed_word() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        doc.insert(seph, 0, "hi");
+        doc.insert(mike, 2, "!");
+        for (i, c) in " there".chars().enumerate() {
+            doc.insert(seph, 3 + i, &c.to_string());
+        }
+        assert_eq!(doc.branch.content(), "hi! there");
+
+        doc.undo(seph).unwrap().unwrap();
+        assert_eq!(doc.branch.content(), "hi!");
+        doc.undo(seph).unwrap().unwrap();
+        assert_eq!(doc.branch.content(), "!");
+        assert_eq!(doc.undo(seph), Ok(None));
+
+        doc.redo(seph).unwrap().unwrap();
+        assert_eq!(doc.branch.content(), "hi!");
+        doc.redo(seph).unwrap().unwrap();
+        assert_eq!(doc.branch.content(), "hi! there");
+        assert_eq!(doc.redo(seph), Ok(None));
+
+        // Redone edits can be undone again.
+        doc.undo(seph).unwrap().unwrap();
+        assert_eq!(doc.branch.content(), "hi!");
+        doc.dbg_check(true);
+    }
+
+    #[test]
+    fn undo_delete_restores_content() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        doc.insert(seph, 0, "abcdef");
+        doc.insert(mike, 6, "!");
+        doc.delete(seph, 1..3);
+        // Backspacing is stored as a single reversed delete.
+        doc.delete(seph, 3..4);
+        doc.delete(seph, 2..3);
+        assert_eq!(doc.branch.content(), "ad!");
+
+        // All the deletes are consecutive, so they're undone together.
+        doc.undo(seph).unwrap();
+        assert_eq!(doc.branch.content(), "abcdef!");
+        doc.redo(seph).unwrap();
+        assert_eq!(doc.branch.content(), "ad!");
+        doc.undo(seph).unwrap();
+        assert_eq!(doc.branch.content(), "abcdef!");
+
+        // Everything was done through the oplog, so checking it out gives the same result.
+        assert_eq!(doc.oplog.checkout_tip(), doc.branch);
+    }
+
+    #[test]
+    fn undo_is_transformed_past_other_edits() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        doc.insert(seph, 0, "world");
+        doc.insert(mike, 0, "hello ");
+        doc.insert(seph, 11, "!!");
+        doc.insert(mike, 0, "oh, ");
+        assert_eq!(doc.branch.content(), "oh, hello world!!");
+
+        // Only seph's edits are undone, in the position they've moved to.
+        doc.undo(seph).unwrap();
+        assert_eq!(doc.branch.content(), "oh, hello world");
+        doc.undo(seph).unwrap();
+        assert_eq!(doc.branch.content(), "oh, hello ");
+        doc.undo(mike).unwrap();
+        assert_eq!(doc.branch.content(), "hello ");
+
+        // Other peers see the same document.
+        let other = ListOpLog::load_from(&doc.oplog.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(other.checkout_tip().content(), "hello ");
+    }
+
+    #[test]
+    fn new_edits_clear_redo() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "abc");
+        doc.undo(seph).unwrap();
+        doc.insert(seph, 0, "x");
+        assert_eq!(doc.redo(seph), Ok(None));
+        assert_eq!(doc.branch.content(), "x");
+
+        // The new edit is undone, and then the group before it, which was already undone, is
+        // skipped.
+        doc.undo(seph).unwrap();
+        assert_eq!(doc.branch.content(), "");
+        assert_eq!(doc.undo(seph), Ok(None));
+    }
+
+    #[test]
+    fn undo_delete_needs_content() {
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        doc.insert(seph, 0, "abc");
+        doc.delete_without_content(seph, 0..1);
+        let before = doc.clone();
+        assert_eq!(doc.undo(seph), Err(EditError::DeletedContentMissing));
+        assert_eq!(doc.oplog, before.oplog);
+        assert_eq!(doc.branch, before.branch);
+
+        // Deleted content is only available after loading if it was encoded.
+        let mut doc = ListCRDT::new();
+        let seph = doc.get_or_create_agent_id("seph");
+        let mike = doc.get_or_create_agent_id("mike");
+        doc.insert(seph, 0, "abc");
+        doc.insert(mike, 3, "!");
+        doc.delete(seph, 0..1);
+        let mut loaded = ListCRDT::load_from(&doc.oplog.encode(ENCODE_FULL)).unwrap();
+        assert_eq!(loaded.undo(seph), Err(EditError::DeletedContentMissing));
+        let with_content = EncodeOptions { store_deleted_content: true, ..ENCODE_FULL };
+        let mut loaded = ListCRDT::load_from(&doc.oplog.encode(with_content)).unwrap();
+        loaded.undo(seph).unwrap();
+        assert_eq!(loaded.branch.content(), "abc!");
+    }
+
+    #[test]
+    fn undo_on_read_only_branch_fails() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let v = oplog.add_insert(seph, 0, "hi");
+        oplog.add_insert(seph, 2, "!");
+
+        let mut branch = oplog.checkout(&[v]);
+        assert_eq!(branch.undo(&mut oplog, seph), Err(EditError::BranchReadOnly));
+    }
+}
@@ -15,7 +15,10 @@ pub(super) struct QueryResult {
     tag: ListOpKind,
     target: RangeRev,
     offset: usize,
-    ptr: Option<NonNull<NodeLeaf<YjsSpan, DocRangeIndex>>>
+    ptr: Option<NonNull<NodeLeaf<YjsSpan, DocRangeIndex>>>,
+    /// Checkpoints have no operation, so they're never applied and the index still has its
+    /// placeholder at their versions. There's nothing to advance or retreat there.
+    is_gap: bool,
 }
 
 impl M2Tracker {
@@ -38,18 +41,19 @@ impl M2Tracker {
 
             match entry.inner {
                 InsPtr(ptr) => {
-                    debug_assert!(ptr != NonNull::dangling());
                     // For inserts, the target is simply the range of the item.
                     let start = time - cursor.offset;
+                    let is_gap = ptr == NonNull::dangling();
                     QueryResult {
                         tag: Ins,
                         target: (start..start+entry.len).into(),
                         offset: cursor.offset,
-                        ptr: Some(ptr)
+                        ptr: if is_gap { None } else { Some(ptr) },
+                        is_gap,
                     }
                 }
                 DelTarget(target) => {
-                    QueryResult { tag: Del, target, offset: cursor.offset, ptr: None }
+                    QueryResult { tag: Del, target, offset: cursor.offset, ptr: None, is_gap: false }
                 }
             }
         }
@@ -60,9 +64,13 @@ impl M2Tracker {
             // Note the delete could be reversed - but we don't really care here; we just mark the
             // whole range anyway.
             // let (tag, target, mut len) = self.next_action(range.start);
-            let QueryResult { tag, target, offset, mut ptr } = self.index_query(range.start);
+            let QueryResult { tag, target, offset, mut ptr, is_gap } = self.index_query(range.start);
 
             let len = usize::min(target.len() - offset, range.len());
+            if is_gap {
+                range.truncate_keeping_right(len);
+                continue;
+            }
 
             // If the target span is reversed, the part of target we eat each iteration changes.
             let mut target_range = target.range(offset, offset + len);
@@ -106,7 +114,7 @@ impl M2Tracker {
         while !range.is_empty() {
             // TODO: This is gross. Clean this up. There's totally a nicer way to write this.
             let req_time = range.last();
-            let QueryResult { tag, target, offset, mut ptr } = self.index_query(req_time);
+            let QueryResult { tag, target, offset, mut ptr, is_gap } = self.index_query(req_time);
 
             let chunk_start = req_time - offset;
             let start = range.start.max(chunk_start);
@@ -117,6 +125,7 @@ impl M2Tracker {
             let len = end - start;
             debug_assert!(len <= range.len());
             range.end -= len;
+            if is_gap { continue; }
 
             let mut target_range = target.range(e_offset, e_offset + len);
 
@@ -0,0 +1,294 @@
+//! Lifetimes of inserted characters. For every character ever inserted into a document, this
+//! reports the version it was inserted at, and the version at which it was deleted (if it has
+//! been deleted).
+//!
+//! Delete operations only store the position they deleted at, not which character they deleted.
+//! To figure that out we replay the whole history through the merge tracker, which records each
+//! delete's target in its index as it goes.
+
+use std::collections::BTreeMap;
+use rle::{HasLength, MergableSpan, SplitableSpanHelpers};
+use crate::{DTRange, Frontier, LV};
+use crate::list::ListOpLog;
+use crate::list::operation::ListOpKind;
+use crate::listmerge::M2Tracker;
+use crate::listmerge::markers::Marker::DelTarget;
+use crate::rev_range::RangeRev;
+
+/// A run of inserted characters, and when (if ever) they were deleted. See
+/// [`ListOpLog::char_lifetimes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CharLifetime {
+    /// The local versions of the insert operations which inserted these characters.
+    pub insert_span: DTRange,
+
+    /// The versions of the delete operations which deleted these characters, or None if the
+    /// characters are still in the document.
+    ///
+    /// This has the same length as insert_span. If `fwd` is true, the first inserted character
+    /// was deleted by `span.start`, the second by `span.start + 1` and so on. If it's false (eg, the
+    /// characters were deleted using backspace), the characters were deleted in reverse order.
+    ///
+    /// When a character was deleted by multiple concurrent operations, this names the first of
+    /// those operations (in local version order).
+    pub deleted_at: Option<RangeRev>,
+}
+
+impl CharLifetime {
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+}
+
+impl HasLength for CharLifetime {
+    fn len(&self) -> usize {
+        self.insert_span.len()
+    }
+}
+
+impl SplitableSpanHelpers for CharLifetime {
+    fn truncate_h(&mut self, at: usize) -> Self {
+        CharLifetime {
+            insert_span: self.insert_span.truncate_h(at),
+            deleted_at: self.deleted_at.as_mut().map(|d| d.truncate_h(at)),
+        }
+    }
+}
+
+impl MergableSpan for CharLifetime {
+    fn can_append(&self, other: &Self) -> bool {
+        self.insert_span.can_append(&other.insert_span) && match (&self.deleted_at, &other.deleted_at) {
+            (None, None) => true,
+            (Some(a), Some(b)) => a.can_append(b),
+            _ => false,
+        }
+    }
+
+    fn append(&mut self, other: Self) {
+        self.insert_span.append(other.insert_span);
+        if let (Some(a), Some(b)) = (self.deleted_at.as_mut(), other.deleted_at) {
+            a.append(b);
+        }
+    }
+}
+
+/// A delete operation's run of targeted characters. The delete at `del_start` targets `target`.
+#[derive(Debug, Clone, Copy)]
+struct DelRun {
+    target: DTRange,
+    del_start: LV,
+    fwd: bool,
+}
+
+impl DelRun {
+    /// The versions of the deletes which deleted the subrange `r` of target.
+    fn deleted_at(&self, r: DTRange) -> RangeRev {
+        let span = if self.fwd {
+            (self.del_start + r.start - self.target.start..self.del_start + r.end - self.target.start).into()
+        } else {
+            (self.del_start + self.target.end - r.end..self.del_start + self.target.end - r.start).into()
+        };
+        RangeRev { span, fwd: self.fwd }
+    }
+}
+
+impl ListOpLog {
+    /// Iterate through every character ever inserted into the document, along with the operation
+    /// which deleted it (if any). Runs of characters which were inserted and deleted together are
+    /// returned as a single item.
+    ///
+    /// Items are returned in the order the characters were inserted (by local version). The
+    /// characters with `deleted_at: None` are the ones in the current document.
+    ///
+    /// This replays the entire history of the document, so it's about as expensive as checking
+    /// out the document from scratch.
+    pub fn char_lifetimes(&self) -> impl Iterator<Item = CharLifetime> {
+        let deletes = self.earliest_deletes();
+        let mut deletes = deletes.into_iter().peekable();
+        let mut result: Vec<CharLifetime> = vec![];
+
+        for op in self.operations.iter() {
+            if op.1.kind != ListOpKind::Ins { continue; }
+            let mut span: DTRange = (op.0..op.0 + op.1.len()).into();
+
+            while !span.is_empty() {
+                // Skip any deletes which target things before here.
+                while let Some((_, d)) = deletes.peek() {
+                    if d.target.end <= span.start { deletes.next(); } else { break; }
+                }
+
+                let item = match deletes.peek() {
+                    Some((_, d)) if d.target.start <= span.start => {
+                        let end = span.end.min(d.target.end);
+                        let r = (span.start..end).into();
+                        CharLifetime { insert_span: r, deleted_at: Some(d.deleted_at(r)) }
+                    }
+                    Some((_, d)) if d.target.start < span.end => {
+                        CharLifetime { insert_span: (span.start..d.target.start).into(), deleted_at: None }
+                    }
+                    _ => CharLifetime { insert_span: span, deleted_at: None },
+                };
+
+                span.start = item.insert_span.end;
+                if let Some(last) = result.last_mut() {
+                    if last.can_append(&item) {
+                        last.append(item);
+                        continue;
+                    }
+                }
+                result.push(item);
+            }
+        }
+
+        result.into_iter()
+    }
+
+    /// Figure out which delete first deleted each character. Returns a map from the start of each
+    /// (non-overlapping) run of deleted characters to the delete which targeted it.
+    fn earliest_deletes(&self) -> BTreeMap<LV, DelRun> {
+        let mut tracker = M2Tracker::new();
+        tracker.walk(&self.cg.graph, &self.cg.agent_assignment, &self.operation_ctx, &self.operations,
+                     Frontier::root(), &[(0..self.len()).into()], None);
+
+        // The tracker's index contains the target of every delete, in local version order.
+        let mut result: BTreeMap<LV, DelRun> = BTreeMap::new();
+        let mut lv = 0;
+        for entry in tracker.index.iter() {
+            if lv >= self.len() { break; }
+
+            if let DelTarget(target) = entry.inner {
+                let run = DelRun { target: target.span, del_start: lv, fwd: target.fwd };
+                // Deletes are visited in version order, so the first delete of any character is
+                // always seen first. Later deletes only fill in the gaps.
+                insert_gaps(&mut result, run);
+            }
+            lv += entry.len;
+        }
+
+        result
+    }
+}
+
+/// Add the parts of run which don't overlap with anything already in map.
+fn insert_gaps(map: &mut BTreeMap<LV, DelRun>, run: DelRun) {
+    let DTRange { mut start, end } = run.target;
+
+    // Find everything which overlaps with the run, starting with the entry before it (if any).
+    let first = map.range(..start).next_back().map_or(start, |(k, _)| *k);
+    let overlapping: Vec<DTRange> = map.range(first..end)
+        .map(|(_, d)| d.target)
+        .filter(|t| t.end > start)
+        .collect();
+
+    let add = |map: &mut BTreeMap<LV, DelRun>, r: DTRange| {
+        if r.is_empty() { return; }
+        let deleted_at = run.deleted_at(r);
+        map.insert(r.start, DelRun { target: r, del_start: deleted_at.span.start, fwd: run.fwd });
+    };
+
+    for t in overlapping {
+        add(map, (start..t.start.max(start)).into());
+        start = start.max(t.end);
+    }
+    if start < end { add(map, (start..end).into()); }
+}
+
+#[cfg(test)]
+mod test {
+    use rle::HasLength;
+    use crate::list::ListOpLog;
+    use crate::list::operation::ListOpKind;
+    use crate::rev_range::RangeRev;
+    use super::CharLifetime;
+
+    fn lifetime(ins: (usize, usize), del: Option<(usize, usize, bool)>) -> CharLifetime {
+        CharLifetime {
+            insert_span: (ins.0..ins.1).into(),
+            deleted_at: del.map(|(start, end, fwd)| RangeRev { span: (start..end).into(), fwd }),
+        }
+    }
+
+    /// Check the lifetimes add up to the total number of inserted characters, and the surviving
+    /// characters make up the document.
+    fn check_totals(oplog: &ListOpLog) -> Vec<CharLifetime> {
+        let lifetimes: Vec<_> = oplog.char_lifetimes().collect();
+
+        let inserted: usize = oplog.operations.iter()
+            .filter(|op| op.1.kind == ListOpKind::Ins)
+            .map(|op| op.len())
+            .sum();
+        assert_eq!(lifetimes.iter().map(|l| l.len()).sum::<usize>(), inserted);
+
+        let alive: usize = lifetimes.iter().filter(|l| !l.is_deleted()).map(|l| l.len()).sum();
+        assert_eq!(alive, oplog.checkout_tip().len());
+
+        for l in lifetimes.iter() {
+            if let Some(d) = l.deleted_at {
+                assert_eq!(d.len(), l.len());
+            }
+        }
+
+        lifetimes
+    }
+
+    #[test]
+    fn simple_lifetimes() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there"); // 0..8
+        oplog.add_delete_without_content(seph, 2..5); // 8..11 deletes " th" (2..5).
+        oplog.add_insert(seph, 2, "!"); // 11
+
+        assert_eq!(check_totals(&oplog), vec![
+            lifetime((0, 2), None),
+            lifetime((2, 5), Some((8, 11, true))),
+            lifetime((5, 8), None),
+            lifetime((11, 12), None),
+        ]);
+    }
+
+    #[test]
+    fn backspace_deletes_are_reversed() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "abcd"); // 0..4
+        // Backspace "d", "c", "b".
+        for pos in [3, 2, 1] {
+            oplog.add_delete_without_content(seph, pos..pos + 1);
+        }
+
+        assert_eq!(check_totals(&oplog), vec![
+            lifetime((0, 1), None),
+            lifetime((1, 4), Some((4, 7, false))),
+        ]);
+    }
+
+    #[test]
+    fn concurrent_deletes_report_the_first() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let v = oplog.add_insert(seph, 0, "abcdef"); // 0..6
+
+        // Concurrently seph deletes "bcd" and mike deletes "cde".
+        oplog.add_delete_at(seph, &[v], 1..4); // 6..9
+        oplog.add_delete_at(mike, &[v], 2..5); // 9..12
+
+        assert_eq!(oplog.checkout_tip().content(), "af");
+        assert_eq!(check_totals(&oplog), vec![
+            lifetime((0, 1), None),
+            lifetime((1, 4), Some((6, 9, true))),
+            lifetime((4, 5), Some((11, 12, true))),
+            lifetime((5, 6), None),
+        ]);
+    }
+
+    #[test]
+    fn fixture_totals() {
+        for name in ["benchmark_data/git-makefile.dt", "benchmark_data/node_nodecc.dt"] {
+            let bytes = std::fs::read(name).unwrap();
+            let oplog = ListOpLog::load_from(&bytes).unwrap();
+            check_totals(&oplog);
+        }
+    }
+}
@@ -55,22 +55,26 @@ impl ListOpLog {
             for time in span.iter() {
                 let name = name_of(time);
 
-                // This is horribly inefficient but I don't care.
-                let (KVPair(_, op), offset) = self.operations.find_packed_with_offset(time);
-                let mut op = op.to_operation(&self.operation_ctx);
-                op.truncate_keeping_right(offset);
-                op.truncate(1);
-
                 let txn = self.cg.graph.entries.find_packed(time);
 
-                // let label = if op.tag == Ins {
-                // let label = if op.content_known {
-                let label = if let Some(s) = &op.content {
-                    // <b>72</b><br align="left"/>  Del 7 <s>'n'</s>
-                    format!("<b>{}</b><br align=\"left\"/>{:?} {} '{}'", time, op.kind, op.start(), s)
-                    // format!("{}: {:?} {} '{}'", time, op.tag, op.pos, &op.content)
+                // This is horribly inefficient but I don't care.
+                let label = if let Some((KVPair(_, op), offset)) = self.operations.find_with_offset(time) {
+                    let mut op = op.to_operation(&self.operation_ctx);
+                    op.truncate_keeping_right(offset);
+                    op.truncate(1);
+
+                    // let label = if op.tag == Ins {
+                    // let label = if op.content_known {
+                    if let Some(s) = &op.content {
+                        // <b>72</b><br align="left"/>  Del 7 <s>'n'</s>
+                        format!("<b>{}</b><br align=\"left\"/>{:?} {} '{}'", time, op.kind, op.start(), s)
+                        // format!("{}: {:?} {} '{}'", time, op.tag, op.pos, &op.content)
+                    } else {
+                        format!("{}: {:?} {}", time, op.kind, op.start())
+                    }
                 } else {
-                    format!("{}: {:?} {}", time, op.kind, op.start())
+                    // Checkpoints don't have an operation.
+                    format!("{}: Checkpoint", time)
                 };
                 out.write_fmt(format_args!("\t{} [fillcolor={} label=<{}>]\n", name, color.to_string(), label)).unwrap();
 
@@ -375,7 +375,9 @@ impl M2Tracker {
         // dbg!(op);
         match op.kind {
             ListOpKind::Ins => {
-                if !op.loc.fwd { unimplemented!("Implement me!") }
+                // Reversed inserts are split into single character inserts when they're added to
+                // the oplog.
+                debug_assert!(op.loc.fwd);
 
                 // To implement this we need to:
                 // 1. Find the item directly before the requested position. This is our origin-left.
@@ -816,10 +818,11 @@ impl<'a> Iterator for TransformedOpsIter<'a> {
 
                 let mut iter = OpMetricsIter::new(self.ops, self.op_ctx, span);
 
-                // Pull the first item off the iterator and keep it for later.
-                // A fresh iterator should always return something!
-                let result = iter.next().unwrap();
-                // assert!(result.is_some());
+                // Pull the first item off the iterator and keep it for later. The iterator is only
+                // empty if the span is all checkpoints, which have no operations.
+                let Some(result) = iter.next() else {
+                    return self.next();
+                };
 
                 self.op_iter = Some(iter.into());
                 // println!("FF {:?}", result);
@@ -1011,14 +1014,14 @@ impl TextInfo {
                 match (origin_op.kind, xf) {
                     (ListOpKind::Ins, BaseMoved(pos)) => {
                         // println!("Insert '{}' at {} (len {})", op.content, ins_pos, op.len());
-                        debug_assert!(origin_op.content_pos.is_some()); // Ok if this is false - we'll just fill with junk.
-                        let content = origin_op.get_content(&self.ctx).unwrap();
+                        // Content can be missing (eg if it was redacted). Its filled with replacement characters.
+                        let content = origin_op.get_content_or_replacement(&self.ctx);
                         assert!(pos <= into.len_chars());
                         if origin_op.loc.fwd {
-                            into.insert(pos, content);
+                            into.insert(pos, &content);
                         } else {
                             // We need to insert the content in reverse order.
-                            let c = reverse_str(content);
+                            let c = reverse_str(&content);
                             into.insert(pos, &c);
                         }
                     }
@@ -22,6 +22,7 @@ mod markers;
 mod advance_retreat;
 pub(crate) mod txn_trace;
 mod metrics;
+pub mod char_lifetimes;
 #[cfg(test)]
 pub mod fuzzer;
 #[cfg(feature = "dot_export")]
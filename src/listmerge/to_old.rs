@@ -137,7 +137,7 @@ impl ListOpLog {
                         id,
                         origin_left,
                         origin_right,
-                        content: self.operation_ctx.get_str(ListOpKind::Ins, content_pos).into()
+                        content: self.operation_ctx.get_str(ListOpKind::Ins, content_pos).unwrap().into()
                     }
                 }
                 OldCRDTOpInternal::Del { start_time, target } => {
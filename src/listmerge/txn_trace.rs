@@ -26,7 +26,7 @@
 use std::mem::take;
 use smallvec::{SmallVec, smallvec};
 use rle::{HasLength, SplitableSpan};
-use crate::causalgraph::graph::Graph;
+use crate::causalgraph::graph::{Graph, GraphEntrySimple};
 use crate::dtrange::DTRange;
 use crate::{Frontier, LV};
 
@@ -40,6 +40,9 @@ struct VisitEntry {
     parent_idxs: SmallVec<[usize; 4]>,
     child_idxs: SmallVec<[usize; 4]>,
     visited: bool,
+    /// How many of parent_idxs haven't been visited yet. The entry can be processed when this is
+    /// 0. Counting (rather than checking every parent) keeps merges with lots of parents linear.
+    unvisited_parents: usize,
 }
 
 
@@ -130,11 +133,8 @@ impl<'a> SpanningTreeWalker<'a> {
                 let txn = &graph.entries[i];
                 debug_assert!(span_remaining.start >= txn.span.start && span_remaining.start < txn.span.end);
 
-                let offset = LV::min(span_remaining.len(), txn.span.end - span_remaining.start);
-                let span = span_remaining.truncate_keeping_right(offset);
-
-                // dbg!(span_remaining.start);
-                let parents: Frontier = txn.clone_parents_at_version(span.start);
+                let GraphEntrySimple { span, parents } = txn.clip(span_remaining);
+                span_remaining.truncate_keeping_right(span.len());
 
                 // We don't care about any parents outside of the input spans.
                 let parent_idxs: SmallVec<[usize; 4]> = parents.iter()
@@ -154,6 +154,7 @@ impl<'a> SpanningTreeWalker<'a> {
                     // entry: e,
                     visited: false,
                     parents,
+                    unvisited_parents: parent_idxs.len(),
                     parent_idxs,
                     child_idxs: smallvec![] // We can't process these yet.
                 });
@@ -281,28 +282,24 @@ impl<'a> Iterator for SpanningTreeWalker<'a> {
         // let parents = &input_entry.parents;
         let (only_branch, only_txn) = self.subgraph.diff_rev(self.frontier.as_ref(), parents.as_ref());
 
-        // Note that even if we're moving to one of our direct children we might see items only
-        // in only_branch if the child has a parent in the middle of our txn.
-        for range in &only_branch {
-            // println!("Retreat branch {:?} by {:?}", &self.branch, range);
-            self.frontier.retreat(self.subgraph, *range);
-            // println!(" -> {:?}", &self.branch);
-            // dbg!(&branch);
-        }
-
+        // Moving the frontier by the diff always lands on the txn's parents, so we can jump straight
+        // there. (Advancing through the ranges one at a time is quadratic when the txn merges lots
+        // of concurrent branches.) Debug builds still take the long way to check.
         if cfg!(debug_assertions) {
+            // Note that even if we're moving to one of our direct children we might see items only
+            // in only_branch if the child has a parent in the middle of our txn.
+            for range in &only_branch {
+                self.frontier.retreat(self.subgraph, *range);
+            }
             self.frontier.check(self.subgraph);
-        }
 
-        for range in only_txn.iter().rev() {
-            // println!("Advance branch by {:?}", range);
-            self.frontier.advance(self.subgraph, *range);
-            // dbg!(&branch);
-        }
-
-        if cfg!(debug_assertions) {
+            for range in only_txn.iter().rev() {
+                self.frontier.advance(self.subgraph, *range);
+            }
             self.frontier.check(self.subgraph);
+            debug_assert_eq!(self.frontier, parents);
         }
+        self.frontier = parents.clone();
 
         // println!("consume {} (order {:?})", next_idx, next_txn.as_span());
         let input_span = span;
@@ -310,14 +307,14 @@ impl<'a> Iterator for SpanningTreeWalker<'a> {
 
         self.num_consumed += 1;
 
-        'outer: for c in child_idxs {
-            if self.input[c].visited { continue; }
-            for p in &self.input[c].parent_idxs {
-                if !self.input[*p].visited {
-                    continue 'outer;
-                }
+        // child_idxs has an entry for each of the child's parent_idxs which point here.
+        for c in child_idxs {
+            let child = &mut self.input[c];
+            debug_assert!(!child.visited);
+            child.unvisited_parents -= 1;
+            if child.unvisited_parents == 0 {
+                self.to_process.push(c);
             }
-            self.to_process.push(c);
         }
 
         self.check();
@@ -1,7 +1,7 @@
 mod action_plan;
 mod test_conversion;
 
-// #[cfg(feature = "dot_export")]
+#[cfg(feature = "dot_export")]
 mod dot;
 mod index_gap_buffer;
 mod yjsspan;
@@ -393,6 +393,8 @@ impl OpLog {
         result
     }
 
+    /// Checkout the contents of the named map. Registers and collections aren't implemented yet,
+    /// and are left out of the result.
     pub fn checkout_map(&self, crdt: LVKey) -> BTreeMap<SmartString, Box<DTValue>> {
         let empty_str: SmartString = "".into();
         // dbg!((crdt, empty_str.clone())..(crdt, empty_str));
@@ -403,21 +405,18 @@ impl OpLog {
             self.map_keys.range((crdt, empty_str.clone())..(crdt + 1, empty_str))
         };
 
-        iter.map(|((_, key), info)| {
+        iter.filter_map(|((_, key), info)| {
             let inner = match self.resolve_mv(info) {
                 RegisterValue::Primitive(p) => DTValue::Primitive(p),
                 RegisterValue::OwnedCRDT(kind, child_crdt) => {
                     match kind {
                         CRDTKind::Map => DTValue::Map(self.checkout_map(child_crdt)),
                         CRDTKind::Text => DTValue::Text(self.checkout_text(child_crdt).to_string()),
-                        _ => unimplemented!(),
-                        // CRDTKind::Register => {}
-                        // CRDTKind::Collection => {}
-                        // CRDTKind::Text => {}
+                        CRDTKind::Register | CRDTKind::Collection => { return None; }
                     }
                 }
             };
-            (key.clone(), Box::new(inner))
+            Some((key.clone(), Box::new(inner)))
         }).collect()
     }
 
@@ -425,39 +424,34 @@ impl OpLog {
         self.checkout_map(ROOT_CRDT_ID)
     }
 
-    pub fn crdt_at_path(&self, path: &[&str]) -> (CRDTKind, LVKey) {
+    /// Find the CRDT at the specified path. Returns None if the path doesn't name a CRDT in the
+    /// document.
+    pub fn crdt_at_path(&self, path: &[&str]) -> Option<(CRDTKind, LVKey)> {
         let mut kind = CRDTKind::Map;
         let mut key = ROOT_CRDT_ID;
 
         for p in path {
-            match kind {
-                CRDTKind::Map => {
-                    let container = self.map_keys.get(&(key, (*p).into()))
-                        .unwrap();
-                    match self.resolve_mv(container) {
-                        RegisterValue::Primitive(_) => {
-                            panic!("Found primitive, not CRDT");
-                        }
-                        RegisterValue::OwnedCRDT(new_kind, new_key) => {
-                            kind = new_kind;
-                            key = new_key;
-                        }
-                    }
-                }
-                _ => {
-                    panic!("Invalid path in document");
+            if kind != CRDTKind::Map { return None; }
+
+            let container = self.map_keys.get(&(key, (*p).into()))?;
+            match self.resolve_mv(container) {
+                RegisterValue::Primitive(_) => { return None; } // Found primitive, not CRDT.
+                RegisterValue::OwnedCRDT(new_kind, new_key) => {
+                    kind = new_kind;
+                    key = new_key;
                 }
             }
         }
 
-        (kind, key)
+        Some((kind, key))
     }
 
-    pub fn text_at_path(&self, path: &[&str]) -> LVKey {
-        let (kind, key) = self.crdt_at_path(path);
-        if kind != CRDTKind::Text {
-            panic!("Unexpected CRDT kind {:?}", kind);
-        } else { key }
+    /// Find the text CRDT at the specified path, if there is one.
+    pub fn text_at_path(&self, path: &[&str]) -> Option<LVKey> {
+        match self.crdt_at_path(path)? {
+            (CRDTKind::Text, key) => Some(key),
+            _ => None,
+        }
     }
 
     pub fn text_changes_since(&self, text: LVKey, since_frontier: &[LV]) -> Vec<(DTRange, Option<TextOperation>)> {
@@ -545,10 +539,9 @@ impl OpLog {
                     let op_out = ListOpMetrics {
                         loc: op.loc,
                         kind: op.kind,
-                        content_pos: op.content_pos.map(|content_pos| {
-                            let content = info.ctx.get_str(op.kind, content_pos);
-                            text_context.push_str(op.kind, content)
-                        }),
+                        content_pos: op.content_pos
+                            .and_then(|content_pos| info.ctx.get_str(op.kind, content_pos))
+                            .map(|content| text_context.push_str(op.kind, content)),
                     };
                     let rv = self.cg.agent_assignment.local_to_remote_version(lv);
                     text_ops.push((crdt_name, rv, op_out));
@@ -158,6 +158,14 @@ impl<V: HasLength + MergableSpan + HasRleKey + Clone + Sized> RleVec<V> {
         item
     }
 
+    /// Find the entry containing key, if any. This works for both packed and sparse lists.
+    ///
+    /// Returns Some((entry, offset of key within the entry)), or None if key falls in a gap. This
+    /// is a binary search, so it takes O(log n) time.
+    pub fn find_containing(&self, key: usize) -> Option<(&V, usize)> {
+        self.find_with_offset(key)
+    }
+
     /// Find an entry in the list with the specified key using binary search.
     ///
     /// If found returns Some((found value, internal offset))
@@ -180,17 +188,17 @@ impl<V: HasLength + MergableSpan + HasRleKey + Clone + Sized> RleVec<V> {
     // }
 
     /// This method is similar to find, except instead of returning None when the value doesn't
-    /// exist in the RLE list, we return the position in the empty span.
+    /// exist in the RLE list, we return the empty span (gap) containing the needle.
     ///
-    /// This method assumes the "base" of the RLE is 0.
+    /// This method assumes the "base" of the RLE is 0. The gap after the last entry extends to
+    /// usize::MAX.
     ///
-    /// Returns (Ok(elem), offset) if item is found, otherwise (Err(void range), offset into void)
-    #[allow(unused)]
-    pub fn find_sparse(&self, needle: usize) -> (Result<&V, DTRange>, usize) {
+    /// Returns Ok((elem, offset)) if item is found, otherwise Err(gap). This takes O(log n) time.
+    pub fn find_sparse(&self, needle: usize) -> Result<(&V, usize), DTRange> {
         match self.find_index(needle) {
             Ok(idx) => {
                 let entry = &self.0[idx];
-                (Ok(entry), needle - entry.rle_key())
+                Ok((entry, needle - entry.rle_key()))
             }
             Err(idx) => {
                 let next_key = if let Some(entry) = self.0.get(idx) {
@@ -199,16 +207,38 @@ impl<V: HasLength + MergableSpan + HasRleKey + Clone + Sized> RleVec<V> {
                     usize::MAX
                 };
 
-                if idx == 0 {
-                    (Err((0..next_key).into()), needle)
-                } else {
-                    let end = self.0[idx - 1].end();
-                    (Err((end..next_key).into()), needle - end)
-                }
+                let start = if idx == 0 { 0 } else { self.0[idx - 1].end() };
+                Err((start..next_key).into())
             }
         }
     }
 
+    /// Iterate through the gaps (ranges not covered by any entry) inside range, in order. This
+    /// takes O(log n + k) time, where k is the number of entries overlapping the range.
+    pub fn iter_gaps(&self, range: DTRange) -> impl Iterator<Item = DTRange> + '_ {
+        let mut pos = range.start;
+        let mut entries = self.iter_from_idx(self.find_next_index(range.start));
+
+        std::iter::from_fn(move || {
+            while pos < range.end {
+                let (gap_end, next_pos) = match entries.next() {
+                    Some(e) => (e.rle_key().max(pos).min(range.end), e.end()),
+                    None => (range.end, range.end),
+                };
+                let gap = DTRange { start: pos, end: gap_end };
+                pos = next_pos;
+                if !gap.is_empty() { return Some(gap); }
+            }
+            None
+        })
+    }
+
+    /// The number of keys inside range which are covered by entries in the list. This takes
+    /// O(log n + k) time, where k is the number of entries overlapping the range.
+    pub fn covered_len(&self, range: DTRange) -> usize {
+        range.len() - self.iter_gaps(range).map(|gap| gap.len()).sum::<usize>()
+    }
+
     /// Find an entry in the list with the specified key using binary search.
     ///
     /// If found, item is returned by mutable reference as Some((&mut item, offset)).
@@ -579,6 +609,8 @@ impl<'a, V: HasRleKey + HasLength, I: HasLength + SplitableSpanCtx, F: Fn(&V) ->
 
 #[cfg(test)]
 mod tests {
+    use rand::prelude::*;
+    use rle::MergeableIterator;
     use super::*;
 
     #[test]
@@ -618,6 +650,73 @@ mod tests {
         ])
     }
 
+    #[test]
+    fn find_sparse_gaps() {
+        let mut rle: RleVec<DTRange> = RleVec::new();
+        assert_eq!(rle.find_sparse(10), Err((0..usize::MAX).into()));
+
+        rle.push((15..17).into());
+        assert_eq!(rle.find_sparse(10), Err((0..15).into()));
+        assert_eq!(rle.find_sparse(16), Ok((&rle.0[0], 1)));
+        assert_eq!(rle.find_sparse(17), Err((17..usize::MAX).into()));
+        assert_eq!(rle.find_containing(15), Some((&rle.0[0], 0)));
+        assert_eq!(rle.find_containing(17), None);
+    }
+
+    /// Make a random sparse RLE list, along with the set of keys it covers.
+    fn random_rle(rng: &mut SmallRng) -> (RleVec<DTRange>, Vec<bool>) {
+        let mut rle: RleVec<DTRange> = RleVec::new();
+        let mut covered = vec![false; 100];
+        let mut pos = 0;
+        for _ in 0..rng.gen_range(0..6) {
+            pos += rng.gen_range(0..10);
+            let len = rng.gen_range(1..10);
+            if pos + len > covered.len() { break; }
+            rle.push((pos..pos + len).into());
+            covered[pos..pos + len].fill(true);
+            pos += len;
+        }
+        (rle, covered)
+    }
+
+    #[test]
+    fn sparse_queries_match_model() {
+        let mut rng = SmallRng::seed_from_u64(123);
+
+        for _i in 0..500 {
+            let (rle, covered) = random_rle(&mut rng);
+
+            for key in 0..covered.len() {
+                match rle.find_sparse(key) {
+                    Ok((e, offset)) => {
+                        assert!(covered[key]);
+                        assert_eq!(e.start + offset, key);
+                        assert_eq!(rle.find_containing(key), Some((e, offset)));
+                    }
+                    Err(gap) => {
+                        assert!(!covered[key]);
+                        assert!(gap.start <= key && key < gap.end);
+                        assert!(gap.start == 0 || covered[gap.start - 1]);
+                        assert!(covered[gap.start..gap.end.min(covered.len())].iter().all(|c| !c));
+                        assert!(gap.end >= covered.len() || covered[gap.end]);
+                        assert_eq!(rle.find_containing(key), None);
+                    }
+                }
+            }
+
+            let start = rng.gen_range(0..covered.len());
+            let end = rng.gen_range(start..=covered.len());
+            let range: DTRange = (start..end).into();
+
+            let expect_gaps: Vec<DTRange> = (start..end)
+                .filter(|&k| !covered[k])
+                .map(|k| DTRange::from(k..k + 1))
+                .merge_spans()
+                .collect();
+            assert_eq!(rle.iter_gaps(range).collect::<Vec<_>>(), expect_gaps);
+            assert_eq!(rle.covered_len(range), covered[start..end].iter().filter(|c| **c).count());
+        }
+    }
 
     // use crate::order::OrderSpan;
     // use crate::rle::KVPair;
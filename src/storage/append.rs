@@ -0,0 +1,373 @@
+//! Saving changes to a .dt file without rewriting the whole file. See [`AppendableFile`].
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use crate::{Frontier, LV};
+use crate::frontier::local_frontier_eq;
+use crate::list::encoding::{ENCODE_PATCH, EncodeOptions, SEGMENT_MAGIC_BYTES};
+use crate::list::encoding::encode_tools::push_leb_usize;
+use crate::list::ListOpLog;
+use crate::storage::file::DTFile;
+
+/// The options appended segments are encoded with.
+const APPEND_OPTS: EncodeOptions = EncodeOptions {
+    store_deleted_content: true,
+    ..ENCODE_PATCH
+};
+
+/// A .dt file which new operations can be saved to by appending them to the end of the file. This
+/// is much faster than re-encoding the whole oplog each time it changes.
+///
+/// An appendable file starts with a normal encoded oplog. Each time it's saved, the operations
+/// added since the last save are encoded as a patch and appended as a new segment:
+///
+/// - The magic bytes `DMNDSGMT`
+/// - The length of the patch (LEB128)
+/// - The patch itself. This is a normal encoded file (with its own magic bytes, header and CRC).
+///
+/// [`ListOpLog::load_from`] reads these files, merging each segment in order. If a save was
+/// interrupted (eg by a crash or power loss), the last segment will be cut short or fail its CRC
+/// check. That segment is ignored when the file is loaded, and removed when the file is opened
+/// with [`AppendableFile::open`].
+///
+/// The file remembers the version of the oplog it contains. This is a local version, so the file
+/// should only be used with the oplog it was opened or created with (or its descendants).
+///
+/// Files with appended segments can't be read by older versions of diamond types. Use
+/// [`ListOpLog::encode`] to write the oplog as a single segment again.
+#[derive(Debug)]
+pub struct AppendableFile<F: DTFile = File> {
+    file: F,
+    /// The length of the valid data in the file. The next segment is written here. If an append
+    /// fails, there might be part of a segment after this.
+    len: u64,
+    version: Frontier,
+}
+
+impl AppendableFile<File> {
+    /// Open an existing file, and load the oplog it contains.
+    ///
+    /// If the last save to the file was interrupted, the partially written segment is removed
+    /// from the file. Any other damage is returned as an [`io::ErrorKind::InvalidData`] error
+    /// wrapping the [`ParseError`](crate::encoding::parseerror::ParseError).
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, ListOpLog)> {
+        Self::from_file(File::options().read(true).write(true).open(path)?)
+    }
+
+    /// Create a new file containing the whole oplog. If the file already exists, it's replaced.
+    pub fn create<P: AsRef<Path>>(path: P, oplog: &ListOpLog, opts: EncodeOptions) -> io::Result<Self> {
+        Self::create_in(File::create(path)?, oplog, opts)
+    }
+}
+
+impl<F: DTFile> AppendableFile<F> {
+    /// Load the oplog from an open file. See [`open`](AppendableFile::open).
+    pub(crate) fn from_file(mut file: F) -> io::Result<(Self, ListOpLog)> {
+        let mut data = vec![0; file.stream_len()? as usize];
+        file.read_all_at(&mut data, 0)?;
+        let (oplog, len) = ListOpLog::load_segments(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let len = len as u64;
+        if len < data.len() as u64 {
+            file.set_len(len)?;
+            file.sync_data()?;
+        }
+
+        let version = oplog.cg.version.clone();
+        Ok((Self { file, len, version }, oplog))
+    }
+
+    /// Replace the contents of an open file with the whole oplog. See
+    /// [`create`](AppendableFile::create).
+    pub(crate) fn create_in(mut file: F, oplog: &ListOpLog, opts: EncodeOptions) -> io::Result<Self> {
+        let data = oplog.encode(opts);
+        file.set_len(0)?;
+        file.write_all_at(&data, 0)?;
+        file.sync_data()?;
+
+        Ok(Self {
+            file,
+            len: data.len() as u64,
+            version: oplog.cg.version.clone(),
+        })
+    }
+
+    /// The version of the oplog stored in the file.
+    pub fn version(&self) -> &[LV] {
+        self.version.as_ref()
+    }
+
+    /// Save any operations in the oplog which haven't been saved yet.
+    pub fn append(&mut self, oplog: &ListOpLog) -> io::Result<()> {
+        let version = self.version.clone();
+        self.append_from(oplog, version.as_ref())
+    }
+
+    /// Append the operations in the oplog which aren't in `last_saved_frontier` to the file, as a
+    /// new segment. The data is synced to disk before this returns. Nothing is written if there's
+    /// nothing new.
+    ///
+    /// The file must already contain `last_saved_frontier`, or it won't load. Usually this is the
+    /// file's own [`version`](AppendableFile::version) (which is what
+    /// [`append`](AppendableFile::append) uses).
+    ///
+    /// If the write fails, the file's version isn't changed, and the next append overwrites
+    /// whatever was partially written. (And if the program exits first, the partial segment is
+    /// ignored when the file is loaded.)
+    pub fn append_from(&mut self, oplog: &ListOpLog, last_saved_frontier: &[LV]) -> io::Result<()> {
+        if local_frontier_eq(oplog.cg.version.as_ref(), last_saved_frontier) { return Ok(()); }
+
+        let patch = oplog.encode_from(APPEND_OPTS, last_saved_frontier);
+        let mut segment = SEGMENT_MAGIC_BYTES.to_vec();
+        push_leb_usize(&mut segment, patch.len());
+        segment.extend_from_slice(&patch);
+
+        self.file.write_all_at(&segment, self.len)?;
+        self.file.sync_data()?;
+
+        self.len += segment.len() as u64;
+        self.version = oplog.cg.version.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use crate::encoding::parseerror::ParseErrorKind;
+    use crate::list::encoding::ENCODE_FULL;
+    use crate::storage::file::test::TestFile;
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("dt-append-test-{}-{name}.dt", std::process::id()))
+    }
+
+    /// Write an oplog to path with 3 appended segments. Returns the oplog, and the length of the
+    /// file and the oplog's version after each save.
+    fn make_file(path: &Path) -> (ListOpLog, Vec<(u64, Frontier)>) {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        let mike = oplog.get_or_create_agent_id("mike");
+        let mut branch = oplog.checkout_tip();
+        branch.insert(&mut oplog, seph, 0, "hi there");
+
+        let mut file = AppendableFile::create(path, &oplog, ENCODE_FULL).unwrap();
+        let mut saves = vec![(file.len, oplog.cg.version.clone())];
+
+        for i in 0..3 {
+            branch.insert(&mut oplog, seph, branch.len(), &format!(" edit {i}"));
+            branch.delete(&mut oplog, mike, 0..1);
+            // And a concurrent edit.
+            let base = saves.last().unwrap().1.clone();
+            oplog.add_insert_at(mike, base.as_ref(), 0, "x");
+            branch.merge(&oplog, oplog.cg.version.as_ref());
+
+            file.append(&oplog).unwrap();
+            assert_eq!(file.version(), oplog.cg.version.as_ref());
+            assert_eq!(file.len, fs::metadata(path).unwrap().len());
+            saves.push((file.len, oplog.cg.version.clone()));
+        }
+
+        (oplog, saves)
+    }
+
+    #[test]
+    fn appended_segments_load() {
+        let path = temp_path("load");
+        let (oplog, _) = make_file(&path);
+
+        let data = fs::read(&path).unwrap();
+        assert_eq!(ListOpLog::load_from(&data).unwrap(), oplog);
+        assert_eq!(ListOpLog::load_from_shared(data.into()).unwrap(), oplog);
+
+        let (mut file, loaded) = AppendableFile::open(&path).unwrap();
+        assert_eq!(loaded, oplog);
+        assert_eq!(file.version(), oplog.cg.version.as_ref());
+
+        // Appending with nothing new doesn't change the file.
+        let len = file.len;
+        file.append(&loaded).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // Merging the file into a copy of itself is a no-op.
+        let mut merged = oplog.clone();
+        merged.decode_and_add(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(merged, oplog);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_final_segment_is_ignored() {
+        let path = temp_path("torn");
+        let (oplog, saves) = make_file(&path);
+        let data = fs::read(&path).unwrap();
+        let (prev_len, prev_version) = saves[saves.len() - 2].clone();
+        let prev_len = prev_len as usize;
+
+        // Cut the file off everywhere inside the last segment.
+        for len in prev_len..data.len() {
+            let loaded = ListOpLog::load_from(&data[..len]).unwrap();
+            assert_eq!(loaded.cg.version, prev_version);
+        }
+
+        // A damaged last segment is treated the same way.
+        let mut damaged = data.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert_eq!(ListOpLog::load_from(&damaged).unwrap().cg.version, prev_version);
+
+        // But damage in an earlier segment is an error.
+        let mut damaged = data.clone();
+        damaged[prev_len - 1] ^= 1;
+        assert!(matches!(ListOpLog::load_from(&damaged).unwrap_err().kind, ParseErrorKind::ChecksumFailed(_)));
+
+        // Opening the file removes the torn segment, so it can be appended to again.
+        fs::write(&path, &data[..data.len() - 3]).unwrap();
+        let (mut file, mut loaded) = AppendableFile::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len() as usize, prev_len);
+        assert_eq!(file.version(), prev_version.as_ref());
+
+        loaded.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+        file.append(&loaded).unwrap();
+        assert_eq!(ListOpLog::load_from(&fs::read(&path).unwrap()).unwrap(), loaded);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn damage_before_the_last_segment_is_an_error() {
+        let path = temp_path("damaged");
+        let (_, saves) = make_file(&path);
+        let data = fs::read(&path).unwrap();
+        let middle = saves[1].0 as usize;
+
+        let check_rejected = |damaged: &[u8]| {
+            assert!(ListOpLog::load_from(damaged).is_err());
+            fs::write(&path, damaged).unwrap();
+            assert_eq!(AppendableFile::open(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            // The file is left alone, so nothing after the damage is lost.
+            assert_eq!(fs::read(&path).unwrap(), damaged);
+        };
+
+        // A bit flip in the magic bytes or length of a segment in the middle of the file.
+        for pos in [middle, middle + 7, middle + 8] {
+            let mut damaged = data.clone();
+            damaged[pos] ^= 1;
+            check_rejected(&damaged);
+        }
+
+        // Data after the file which isn't a segment.
+        let mut damaged = data[..saves[0].0 as usize].to_vec();
+        damaged.extend_from_slice(b"hello");
+        check_rejected(&damaged);
+        let mut damaged = data.clone();
+        damaged.extend_from_slice(b"hello");
+        check_rejected(&damaged);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn appends_in_memory() {
+        let mut oplog = ListOpLog::new();
+        let seph = oplog.get_or_create_agent_id("seph");
+        oplog.add_insert(seph, 0, "hi there");
+
+        let handle = TestFile::new();
+        let mut file = AppendableFile::create_in(handle.open_again(), &oplog, ENCODE_FULL).unwrap();
+        for i in 0..10 {
+            oplog.add_insert(seph, 0, &format!("{i} "));
+            file.append(&oplog).unwrap();
+        }
+
+        let (file, loaded) = AppendableFile::from_file(handle.open_again()).unwrap();
+        assert_eq!(loaded, oplog);
+        assert_eq!(file.version(), oplog.cg.version.as_ref());
+    }
+
+    #[test]
+    fn reopen_replays_agents_and_parents() {
+        let mut oplog = ListOpLog::new();
+        let agents = ["seph", "mike", "kaarina"].map(|name| oplog.get_or_create_agent_id(name));
+        oplog.add_insert(agents[0], 0, "hi there");
+
+        let handle = TestFile::new();
+        let mut file = AppendableFile::create_in(handle.open_again(), &oplog, ENCODE_FULL).unwrap();
+        for i in 0..20 {
+            // Each agent edits concurrently from the last saved version, so segments contain
+            // interleaved agents and operations with more than one parent.
+            let base = file.version().to_vec();
+            for (a, &agent) in agents.iter().enumerate() {
+                if (i + a) % 3 == 0 { continue; }
+                oplog.add_insert_at(agent, &base, 0, &format!("{i}{a}"));
+            }
+            file.append(&oplog).unwrap();
+        }
+        drop(file);
+
+        let (mut file, mut loaded) = AppendableFile::from_file(handle.open_again()).unwrap();
+        loaded.dbg_check(true);
+        assert_eq!(loaded, oplog);
+        assert_eq!(loaded.len(), oplog.len());
+        assert_eq!(file.version(), oplog.cg.version.as_ref());
+        assert!(loaded.iter_remote_mappings().eq(oplog.iter_remote_mappings()));
+
+        // The recovered oplog can keep being edited and saved.
+        let mike = loaded.get_agent_id("mike").unwrap();
+        loaded.add_insert(mike, 0, "more");
+        file.append(&loaded).unwrap();
+        let (_, reloaded) = AppendableFile::from_file(handle.open_again()).unwrap();
+        reloaded.dbg_check(true);
+        assert_eq!(reloaded, loaded);
+    }
+
+    #[test]
+    fn failed_appends_recover() {
+        let mut failures = 0;
+        for seed in 0..50 {
+            let mut oplog = ListOpLog::new();
+            let seph = oplog.get_or_create_agent_id("seph");
+            oplog.add_insert(seph, 0, "hi there");
+
+            // Some syncs to this file fail partway through, leaving part of the segment written.
+            let mut handle = TestFile::new_faulty(seed, 0.3);
+            handle.set_contents(oplog.encode(ENCODE_FULL));
+            let (mut file, _) = AppendableFile::from_file(handle.open_again()).unwrap();
+
+            for i in 0..10 {
+                oplog.add_insert(seph, 0, &format!("{i} "));
+                let before = file.version().to_vec();
+                if file.append(&oplog).is_ok() { continue; }
+
+                failures += 1;
+                assert_eq!(file.version(), before);
+                if seed % 2 == 0 {
+                    // Keep going. The next append overwrites the partial segment.
+                    continue;
+                }
+
+                // Or crash, and reopen the file with whatever made it to disk.
+                let (mut reopened, mut loaded) = AppendableFile::from_file(TestFile::with_contents(handle.contents())).unwrap();
+                let saved = loaded.cg.version.clone();
+                assert!(saved.as_ref() == &before[..] || saved == oplog.cg.version, "{saved:?}");
+
+                loaded.decode_and_add(&oplog.encode(ENCODE_FULL)).unwrap();
+                reopened.append(&loaded).unwrap();
+                let (_, reloaded) = AppendableFile::from_file(reopened.file).unwrap();
+                assert_eq!(reloaded, loaded);
+                break;
+            }
+
+            if seed % 2 == 0 && local_frontier_eq(file.version(), oplog.cg.version.as_ref()) {
+                let (_, loaded) = AppendableFile::from_file(TestFile::with_contents(handle.contents())).unwrap();
+                assert_eq!(loaded, oplog);
+            }
+        }
+
+        assert!(failures > 0);
+    }
+}
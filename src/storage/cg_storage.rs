@@ -14,6 +14,11 @@
 //! - Entry index (goes up every time we flush to the end of the file)
 //! - Counter (goes up every time we blit back and forth)
 //! - Actual data
+//!
+//! Files can also be opened with a [`StorageCodec`] (eg to encrypt them). The header is stored as
+//! plaintext. Each blit is encoded as a whole, with the codec's overhead taken from the blit's
+//! space. Each chunk of data is stored as its (plaintext) length followed by the encoded chunk.
+//! The codec is passed the file offset of each blit and chunk as its block ID.
 
 
 // TODO: Open question: Currently this tracks 2 kinds of data (agent assignment and parents). I
@@ -31,7 +36,7 @@ use std::path::Path;
 use bumpalo::Bump;
 use rle::{HasLength, MergableSpan};
 use crate::encoding::bufparser::BufParser;
-use crate::encoding::parseerror::ParseError;
+use crate::encoding::parseerror::{ParseError, ParseErrorKind};
 use crate::encoding::tools::calc_checksum;
 use crate::{CausalGraph, DTRange, LV};
 use bumpalo::collections::vec::Vec as BumpVec;
@@ -41,6 +46,7 @@ use crate::encoding::cg_entry::{read_cg_entry_into_cg_nonoverlapping, write_cg_e
 use crate::encoding::map::{ReadMap, WriteMap};
 use crate::encoding::varint::{push_u64, push_usize, try_push_u64, try_push_usize};
 use crate::list::encoding::leb::{decode_leb_usize, encode_leb_usize};
+use crate::storage::StorageCodec;
 
 
 const CG_MAGIC_BYTES: [u8; 8] = *b"DMNDT_CG";
@@ -67,17 +73,37 @@ pub enum CGError {
 
     BlitTooLarge,
 
+    /// Some data in the file couldn't be decoded by the file's [`StorageCodec`].
+    DecodeFailed,
+
     ParseError(ParseError),
     IO(io::Error),
 }
 
 impl Display for CGError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ParseError {:?}", self)
+        match self {
+            CGError::InvalidHeader => write!(f, "Invalid causal graph file header"),
+            CGError::UnexpectedEOF => write!(f, "Unexpected end of causal graph file"),
+            CGError::ChecksumMismatch => write!(f, "Causal graph file checksum mismatch"),
+            CGError::InvalidBlit => write!(f, "Invalid blit in causal graph file"),
+            CGError::BlitTooLarge => write!(f, "Blit in causal graph file is too large"),
+            CGError::DecodeFailed => write!(f, "Could not decode causal graph file data"),
+            CGError::ParseError(_) => write!(f, "Could not parse causal graph file"),
+            CGError::IO(_) => write!(f, "IO error accessing causal graph file"),
+        }
     }
 }
 
-impl Error for CGError {}
+impl Error for CGError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CGError::ParseError(e) => Some(e),
+            CGError::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for CGError {
     fn from(io_err: io::Error) -> Self {
@@ -145,22 +171,39 @@ pub(crate) struct CGStorage {
     write_map: WriteMap,
 
     next_flush_time: LV,
+
+    /// Applied to everything after the header, if the file is encoded.
+    codec: Option<Box<dyn StorageCodec>>,
 }
 
+/// Encoded blits need room for at least a checksum, length, file size and counter.
+const MIN_BLIT_PAYLOAD: u64 = 16;
+
 impl CGStorage {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<(CausalGraph, CGStorage), CGError> {
+        Self::open_internal(path.as_ref(), None)
+    }
+
+    /// Open a causal graph file whose blits and data are encoded with codec. The file must always
+    /// be opened with the same codec.
+    pub fn open_with_codec<P: AsRef<Path>>(path: P, codec: Box<dyn StorageCodec>) -> Result<(CausalGraph, CGStorage), CGError> {
+        Self::open_internal(path.as_ref(), Some(codec))
+    }
+
+    fn open_internal(path: &Path, codec: Option<Box<dyn StorageCodec>>) -> Result<(CausalGraph, CGStorage), CGError> {
         let mut cg = CausalGraph::new();
+        let overhead = codec.as_ref().map_or(0, |c| c.overhead() as u64);
 
         let mut file = File::options()
             .read(true)
             .create(true)
             .write(true)
             .append(false)
-            .open(path.as_ref())?;
+            .open(path)?;
 
         let mut total_len = file.seek(SeekFrom::End(0))?;
         file.seek(SeekFrom::Start(0))?;
-        let blit_size = Self::read_header(&mut file, total_len)?;
+        let blit_size = Self::read_header(&mut file, total_len, overhead)?;
         debug_assert_eq!(file.stream_position()?, CG_HEADER_LENGTH_U64);
         total_len = total_len.max(CG_HEADER_LENGTH_U64);
 
@@ -174,6 +217,7 @@ impl CGStorage {
             entry: Default::default(),
             write_map: WriteMap::with_capacity_from(&cg.agent_assignment.client_data),
             next_flush_time: 0,
+            codec,
         };
 
         // If the file doesn't have room for the blit data, its probably new. Just set_len().
@@ -205,6 +249,9 @@ impl CGStorage {
         // TODO: This is suuuper duper dirty!
         let mut buf = vec![0u8; active_blit.filesize as usize];
         cgs.file.read_exact(&mut buf)?;
+        if let Some(codec) = &cgs.codec {
+            buf = Self::decode_data(codec.as_ref(), &buf, cgs.data_start())?;
+        }
         // dbg!(&buf);
 
         let mut r = BufParser(&buf);
@@ -239,8 +286,25 @@ impl CGStorage {
         let buf = &mut raw_buf[..bs_u * 2];
         self.file.read_exact(buf)?;
 
-        let b1 = Self::read_blit(&buf[0..bs_u]);
-        let b2 = Self::read_blit(&buf[bs_u..bs_u * 2]);
+        // Blits are decoded in place. A blit which can't be decoded is ignored, like a blit with a
+        // bad checksum.
+        let payload_size = bs_u - self.overhead();
+        let mut decoded = [true; 2];
+        if let Some(codec) = &self.codec {
+            for (i, slot) in buf.chunks_exact_mut(bs_u).enumerate() {
+                let location = CG_HEADER_LENGTH_U64 + blit_size * i as u64;
+                match codec.decode_block(slot, location) {
+                    Ok(data) if data.len() == payload_size => slot[..payload_size].copy_from_slice(&data),
+                    _ => decoded[i] = false,
+                }
+            }
+        }
+
+        let read = |i: usize| if decoded[i] {
+            Self::read_blit(&buf[bs_u * i..bs_u * i + payload_size])
+        } else { Err(CGError::DecodeFailed) };
+        let b1 = read(0);
+        let b2 = read(1);
         let (active_blit, next_blit) = match (b1, b2) {
             (Ok(b1), Ok(b2)) => {
                 // dbg!(&b1, &b2);
@@ -270,13 +334,14 @@ impl CGStorage {
     fn read_blit(buf: &[u8]) -> Result<Blit, CGError> {
         // Blits always start with a checksum,
         // dbg!(buf);
+        if buf.len() < 4 { return Err(CGError::InvalidBlit); }
         let mut pos = 0;
         let expected_checksum = u32::from_le_bytes(buf[0..4].try_into().unwrap());
         pos += 4;
 
         // Length
         let (len, len_size) = decode_leb_usize(&buf[pos..]).map_err(|e| {
-            assert_eq!(e, ParseError::InvalidVarInt);
+            assert_eq!(e, ParseErrorKind::InvalidVarInt);
             CGError::InvalidBlit
         })?;
         pos += len_size;
@@ -320,10 +385,18 @@ impl CGStorage {
 
     fn write_blit(&mut self, blit: Blit) -> Result<(), CGError> {
         debug_assert_eq!(self.file.seek(SeekFrom::Current(0)).unwrap(), self.next_write_location + self.data_start());
-        self.file.seek(SeekFrom::Start(self.next_blit_location()))?;
+        let location = self.next_blit_location();
+        let payload_size = self.blit_size - self.overhead() as u64;
+
+        let mut bytes = Vec::new();
+        Self::write_blit_to(&mut bytes, payload_size, blit)?;
+        if let Some(codec) = &self.codec {
+            bytes.resize(payload_size as usize, 0);
+            bytes = Self::encode(codec.as_ref(), &bytes, location)?;
+        }
 
-        Self::write_blit_to(BufWriter::new(&mut self.file), self.blit_size, blit)?;
-        self.file.flush()?;
+        self.file.seek(SeekFrom::Start(location))?;
+        self.file.write_all(&bytes)?;
         self.file.sync_data()?;
 
         self.next_blit = !self.next_blit;
@@ -332,33 +405,71 @@ impl CGStorage {
         Ok(())
     }
 
-    fn write_blit_to<W: Write>(mut w: BufWriter<W>, max_size: u64, blit: Blit) -> Result<(), CGError> {
+    fn write_blit_to(w: &mut Vec<u8>, max_size: u64, blit: Blit) -> Result<(), CGError> {
         let mut body = Vec::new(); // Bleh. TODO: Better to allocate on the stack here.
         push_u64(&mut body, blit.filesize);
         push_usize(&mut body, blit.counter);
         body.extend_from_slice(blit.data); // TODO: Less copying!
 
         let checksum = calc_checksum(&body);
-        w.write_all(&checksum.to_le_bytes())?;
+        w.extend_from_slice(&checksum.to_le_bytes());
 
         let mut buf = [0u8; 10];
         let len_len = encode_leb_usize(body.len(), &mut buf);
-        w.write_all(&buf[..len_len])?;
+        w.extend_from_slice(&buf[..len_len]);
 
         // TODO: DO THIS BETTER!!
         if 4 + len_len + body.len() > max_size as usize {
             return Err(CGError::BlitTooLarge)
         }
 
-        w.write_all(&body)?;
+        w.extend_from_slice(&body);
 
         Ok(())
     }
 
+    fn overhead(&self) -> usize {
+        self.codec.as_ref().map_or(0, |c| c.overhead())
+    }
+
+    fn encode(codec: &dyn StorageCodec, data: &[u8], location: u64) -> Result<Vec<u8>, io::Error> {
+        let encoded = codec.encode_block(data, location);
+        if encoded.len() != data.len() + codec.overhead() {
+            // The data is read back assuming this length, so we can't write it. Its a bug in the
+            // codec.
+            return Err(io::Error::new(ErrorKind::InvalidData, "Storage codec encoded a block with the wrong length"));
+        }
+        Ok(encoded)
+    }
+
+    /// Decode the data chunks written by write_data. start is the file offset of the data.
+    fn decode_data(codec: &dyn StorageCodec, data: &[u8], start: u64) -> Result<Vec<u8>, CGError> {
+        let mut r = BufParser(data);
+        let mut result = Vec::with_capacity(data.len());
+        while !r.is_empty() {
+            let location = start + (data.len() - r.len()) as u64;
+            let len = r.next_usize()?;
+            let encoded = r.next_n_bytes(len.checked_add(codec.overhead()).ok_or(CGError::DecodeFailed)?)?;
+            let decoded = codec.decode_block(encoded, location).map_err(|_| CGError::DecodeFailed)?;
+            if decoded.len() != len { return Err(CGError::DecodeFailed); }
+            result.extend_from_slice(&decoded);
+        }
+        Ok(result)
+    }
+
     fn write_data(&mut self, data: &[u8]) -> Result<(), io::Error> {
         // First we write the data to the end of the file.
         debug_assert_eq!(self.file.seek(SeekFrom::Current(0)).unwrap(), self.next_write_location + self.data_start());
 
+        let record;
+        let data = if let Some(codec) = &self.codec {
+            let mut r = Vec::new();
+            push_usize(&mut r, data.len());
+            r.extend_from_slice(&Self::encode(codec.as_ref(), data, self.next_write_location + self.data_start())?);
+            record = r;
+            &record[..]
+        } else { data };
+
         self.file.write_all(data)?;
         self.next_write_location += data.len() as u64;
         self.next_counter = 0;
@@ -372,19 +483,25 @@ impl CGStorage {
         CG_HEADER_LENGTH_U64 + self.blit_size * 2
     }
 
-    /// Returns blit size.
-    fn read_header(mut file: &mut File, total_len: u64) -> Result<u64, CGError> {
+    /// Returns blit size. overhead is the number of bytes the file's codec adds to each blit.
+    fn read_header(mut file: &mut File, total_len: u64, overhead: u64) -> Result<u64, CGError> {
         let blitsize = if total_len < CG_HEADER_LENGTH_U64 {
-            // Presumably we're creating a new file.
+            // Presumably we're creating a new file. Blits are made bigger to make room for the
+            // codec's overhead.
+            let blit_size = CG_DEFAULT_BLIT_SIZE + overhead;
+            if blit_size > MAX_BLIT_SIZE as u64 {
+                return Err(CGError::BlitTooLarge);
+            }
+
             let mut bw = BufWriter::new(file);
             bw.write_all(&CG_MAGIC_BYTES)?;
             bw.write_all(&CG_VERSION)?;
-            bw.write_all(&(CG_DEFAULT_BLIT_SIZE as u32).to_le_bytes())?;
+            bw.write_all(&(blit_size as u32).to_le_bytes())?;
 
             file = bw.into_inner().map_err(|e| e.into_error())?;
             file.sync_all()?;
 
-            CG_DEFAULT_BLIT_SIZE
+            blit_size
         } else {
             // Check the WAL header.
             let mut header = [0u8; CG_HEADER_LENGTH];
@@ -409,6 +526,10 @@ impl CGStorage {
                 eprintln!("Causality graph has invalid blit size ({blit_size} > {MAX_BLIT_SIZE})");
                 return Err(CGError::InvalidHeader);
             }
+            if blit_size < overhead + MIN_BLIT_PAYLOAD {
+                eprintln!("Causality graph blit size ({blit_size}) is too small for its codec");
+                return Err(CGError::InvalidHeader);
+            }
             // pos += 4;
 
             blit_size
@@ -515,7 +636,8 @@ mod test {
     use std::fs::{File, remove_file};
     use std::io::Read;
     use std::path::Path;
-    use crate::causalgraph::storage::CGStorage;
+    use crate::storage::cg_storage::CGStorage;
+    use crate::storage::codec::test::XorCodec;
 
     #[test]
     fn foo() {
@@ -541,6 +663,36 @@ mod test {
         cg2.dbg_check(true);
     }
 
+    #[test]
+    fn codec_round_trips() {
+        let path = std::env::temp_dir().join(format!("dt-cg-codec-test-{}.cg", std::process::id()));
+        drop(remove_file(&path));
+
+        let (mut cg, mut cgs) = CGStorage::open_with_codec(&path, Box::new(XorCodec(1))).unwrap();
+        let seph = cg.get_or_create_agent_id("seph");
+        let mike = cg.get_or_create_agent_id("mike");
+        cg.assign_local_op_with_parents(&[], seph, 10);
+        cg.assign_local_op_with_parents(&[5], mike, 15);
+        cg.assign_local_op_with_parents(&[9, 24], seph, 20);
+        cgs.save_missing(&cg).unwrap();
+        drop(cgs);
+
+        // Neither the agent names or the data is stored as plaintext.
+        let mut bytes = vec![];
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        assert!(!bytes.windows(4).any(|w| w == b"seph" || w == b"mike"));
+
+        let (cg2, _) = CGStorage::open_with_codec(&path, Box::new(XorCodec(1))).unwrap();
+        assert_eq!(cg, cg2);
+        cg2.dbg_check(true);
+
+        // With the wrong codec, neither blit decodes. So none of the data is visible.
+        let (cg3, _) = CGStorage::open_with_codec(&path, Box::new(XorCodec(2))).unwrap();
+        assert_eq!(cg3.len(), 0);
+
+        remove_file(&path).unwrap();
+    }
+
     #[test]
     fn write_node_nodecc() {
         use crate::list::ListOpLog;
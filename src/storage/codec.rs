@@ -0,0 +1,242 @@
+//! Block codecs transform pages on their way to and from disk. This is used to encrypt documents at
+//! rest, while the storage engine still owns the file (and all the crash safety logic).
+//!
+//! The codec only ever sees whole pages, after they've been baked. So page checksums are
+//! calculated over the plaintext before encoding, and checked after decoding. A page which doesn't
+//! decode to its original bytes - because of a torn write, a bit flip in the ciphertext or a page
+//! moved to a different block - fails its checksum just like a corrupt page in an unencoded file.
+//!
+//! Pages are rewritten in place, so a codec can't derive its keystream (or IV) from the block ID
+//! alone. Codecs can reserve some bytes in every block for a nonce or authentication tag (see
+//! [`StorageCodec::overhead`]), and the storage engine shrinks its pages to make room.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use crate::storage::DEFAULT_PAGE_SIZE;
+use crate::storage::file::DTFile;
+
+/// Returned by [`StorageCodec::decode_block`] when a block can't be decoded. The storage engine
+/// treats blocks which fail to decode as corrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecError;
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not decode storage block")
+    }
+}
+
+impl Error for CodecError {}
+
+pub trait StorageCodec {
+    /// Encode a block before it's written to disk. block_id is the block's page number. It should
+    /// be bound into the output, so a block can't be decoded as any other block.
+    ///
+    /// The same block is encoded many times as its page is rewritten. Codecs must not reuse a
+    /// keystream between calls - generate a fresh (eg random) nonce for each call and store it in
+    /// the overhead bytes.
+    ///
+    /// Blocks are stored at fixed offsets, so the result must be exactly `overhead()` bytes longer
+    /// than the input.
+    fn encode_block(&self, data: &[u8], block_id: u64) -> Vec<u8>;
+
+    /// Decode a block read from disk. This is passed the same block_id as encode_block, and
+    /// should return data `overhead()` bytes shorter than its input.
+    fn decode_block(&self, data: &[u8], block_id: u64) -> Result<Vec<u8>, CodecError>;
+
+    /// The number of bytes encode_block adds to every block, for nonces, authentication tags and
+    /// so on.
+    fn overhead(&self) -> usize { 0 }
+}
+
+impl std::fmt::Debug for dyn StorageCodec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageCodec")
+            .field("overhead", &self.overhead())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A file which passes every page through a codec as its written and read.
+///
+/// Pages in a codec file hold `overhead()` fewer bytes than a regular page, so each page still
+/// takes up exactly one block on disk once it's encoded.
+pub struct CodecFile<F: DTFile> {
+    file: F,
+    codec: Box<dyn StorageCodec>,
+}
+
+impl<F: DTFile + std::fmt::Debug> std::fmt::Debug for CodecFile<F> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CodecFile")
+            .field("file", &self.file)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<F: DTFile> CodecFile<F> {
+    pub fn new(file: F, codec: Box<dyn StorageCodec>) -> Self {
+        Self { file, codec }
+    }
+
+    fn block_id(&self, data: &[u8], offset: u64) -> io::Result<u64> {
+        // The storage engine only reads and writes whole pages.
+        if data.len() != DEFAULT_PAGE_SIZE - self.codec.overhead() || !offset.is_multiple_of(DEFAULT_PAGE_SIZE as u64) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Codec files can only read and write whole, aligned pages"));
+        }
+        Ok(offset / DEFAULT_PAGE_SIZE as u64)
+    }
+}
+
+impl<F: DTFile> DTFile for CodecFile<F> {
+    fn stream_len(&mut self) -> io::Result<u64> {
+        self.file.stream_len()
+    }
+
+    fn block_overhead(&self) -> usize {
+        self.codec.overhead()
+    }
+
+    fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        let encoded = self.codec.encode_block(data, self.block_id(data, offset)?);
+        if encoded.len() != DEFAULT_PAGE_SIZE {
+            // Writing this would clobber the next page (or leave a gap). Its a bug in the codec.
+            return Err(io::Error::new(ErrorKind::InvalidData, "Storage codec encoded a block with the wrong length"));
+        }
+        self.file.write_all_at(&encoded, offset)
+    }
+
+    fn read_all_at(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+        let block_id = self.block_id(buffer, offset)?;
+        let mut block = [0u8; DEFAULT_PAGE_SIZE];
+        self.file.read_all_at(&mut block, offset)?;
+        let decoded = self.codec.decode_block(&block, block_id)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if decoded.len() != buffer.len() {
+            return Err(io::Error::new(ErrorKind::InvalidData, CodecError));
+        }
+        buffer.copy_from_slice(&decoded);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn write_barrier(&mut self) -> io::Result<()> {
+        self.file.write_barrier()
+    }
+
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn try_lock(&mut self) -> io::Result<bool> {
+        self.file.try_lock()
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        self.file.unlock()
+    }
+}
+
+/// Returns true if the IO error came from a block which the codec couldn't decode.
+pub(super) fn is_codec_error(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<CodecError>())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    /// A (very insecure) codec which XORs each block with a keystream generated from the key, the
+    /// block's ID and a random nonce. The nonce is stored at the start of each block.
+    pub struct XorCodec(pub u64);
+
+    const NONCE_LEN: usize = 8;
+
+    impl XorCodec {
+        fn apply(&self, data: &[u8], block_id: u64, nonce: u64) -> Vec<u8> {
+            let mut state = self.0 ^ block_id.wrapping_mul(0x9E3779B97F4A7C15) ^ nonce.rotate_left(32);
+            data.iter().map(|b| {
+                // Splitmix64.
+                state = state.wrapping_add(0x9E3779B97F4A7C15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+                b ^ (z ^ (z >> 31)) as u8
+            }).collect()
+        }
+    }
+
+    impl StorageCodec for XorCodec {
+        fn encode_block(&self, data: &[u8], block_id: u64) -> Vec<u8> {
+            let nonce: u64 = rand::random();
+            let mut result = nonce.to_le_bytes().to_vec();
+            result.extend_from_slice(&self.apply(data, block_id, nonce));
+            result
+        }
+
+        fn decode_block(&self, data: &[u8], block_id: u64) -> Result<Vec<u8>, CodecError> {
+            if data.len() < NONCE_LEN { return Err(CodecError); }
+            let (nonce, data) = data.split_at(NONCE_LEN);
+            Ok(self.apply(data, block_id, u64::from_le_bytes(nonce.try_into().unwrap())))
+        }
+
+        fn overhead(&self) -> usize { NONCE_LEN }
+    }
+
+    /// A broken codec which doesn't add the overhead it promises.
+    struct ShortCodec;
+
+    impl StorageCodec for ShortCodec {
+        fn encode_block(&self, data: &[u8], _block_id: u64) -> Vec<u8> { data.to_vec() }
+
+        fn decode_block(&self, data: &[u8], _block_id: u64) -> Result<Vec<u8>, CodecError> { Ok(data.to_vec()) }
+
+        fn overhead(&self) -> usize { 16 }
+    }
+
+    #[test]
+    fn xor_codec_round_trips() {
+        let codec = XorCodec(123);
+        let data = [5u8; 100];
+        let encoded = codec.encode_block(&data, 3);
+        assert_eq!(encoded.len(), data.len() + codec.overhead());
+        assert_ne!(&encoded[NONCE_LEN..], &data[..]);
+        assert_eq!(codec.decode_block(&encoded, 3).unwrap(), &data[..]);
+        assert_ne!(codec.decode_block(&encoded, 4).unwrap(), &data[..]);
+
+        // Rewriting the same block doesn't reuse the keystream.
+        let again = codec.encode_block(&data, 3);
+        assert_ne!(again, encoded);
+        assert_eq!(codec.decode_block(&again, 3).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn codec_file_rejects_bad_blocks() {
+        use crate::storage::file::test::TestFile;
+
+        let mut file = CodecFile::new(TestFile::new(), Box::new(XorCodec(1)));
+        let page = vec![1u8; DEFAULT_PAGE_SIZE - NONCE_LEN];
+        file.write_all_at(&page, DEFAULT_PAGE_SIZE as u64).unwrap();
+        let mut buf = vec![0u8; page.len()];
+        file.read_all_at(&mut buf, DEFAULT_PAGE_SIZE as u64).unwrap();
+        assert_eq!(buf, page);
+
+        // Full size or misaligned pages are errors, not panics.
+        let err = file.write_all_at(&[1u8; DEFAULT_PAGE_SIZE], 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = file.write_all_at(&page, 10).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = file.read_all_at(&mut buf, 10).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // So is a codec which encodes blocks to the wrong length.
+        let mut file = CodecFile::new(TestFile::new(), Box::new(ShortCodec));
+        let err = file.write_all_at(&[1u8; DEFAULT_PAGE_SIZE - 16], 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
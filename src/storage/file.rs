@@ -1,7 +1,7 @@
 //! Ordinarily I'd just make direct calls to the std::File API, but I'm wrapping the file API here
 //! so I can swap out the implementation with something we can test.
 
-use std::fs::File;
+use std::fs::{File, TryLockError};
 use std::path::Path;
 use std::io;
 #[cfg(not(unix))]
@@ -16,14 +16,27 @@ use std::ffi::c_int;
 pub trait DTFile {
     fn stream_len(&mut self) -> io::Result<u64>;
 
+    /// The number of bytes in each page which the file reserves for itself. Pages read from and
+    /// written to the file are this much shorter than a full page.
+    fn block_overhead(&self) -> usize { 0 }
+
     fn write_all_at(&mut self, data: &[u8], offset: u64) -> io::Result<()>;
     fn read_all_at(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<()>;
 
+    /// Truncate or extend the file to len bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
     // fn sync_all(&self) -> io::Result<()>;
 
     // Might be cleaner to make both of these methods take a &self and use RefCell when necessary.
     fn write_barrier(&mut self) -> io::Result<()>;
     fn sync_data(&mut self) -> io::Result<()>;
+
+    /// Try to take an exclusive advisory lock on the file. Returns false if the file is already
+    /// locked by someone else. Locks are held until they're explicitly released, or the file is
+    /// closed (which also happens if the process holding the lock crashes).
+    fn try_lock(&mut self) -> io::Result<bool>;
+    fn unlock(&mut self) -> io::Result<()>;
 }
 
 impl DTFile for File {
@@ -53,6 +66,10 @@ impl DTFile for File {
         Ok(())
     }
 
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
     fn write_barrier(&mut self) -> io::Result<()> {
         // I have this as a separate function because fsync is very slow on apple hardware (probably
         // because its not cheating). When we finalize a block with blitted data or write a new
@@ -81,6 +98,21 @@ impl DTFile for File {
     fn sync_data(&mut self) -> io::Result<()> {
         File::sync_data(self)
     }
+
+    fn try_lock(&mut self) -> io::Result<bool> {
+        // This uses flock on unix and LockFileEx on windows. Either way, the OS releases the lock
+        // when the file is closed - so we don't need to worry about stale locks left behind by
+        // processes which crashed.
+        match File::try_lock(self) {
+            Ok(()) => Ok(true),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    fn unlock(&mut self) -> io::Result<()> {
+        File::unlock(self)
+    }
 }
 
 // *** Testing filesystem. This is used to make writing tests easier, and enable filesystem error
@@ -92,7 +124,7 @@ pub mod test {
     use std::collections::BTreeMap;
     use std::mem::replace;
     use std::path::PathBuf;
-    use std::rc::Rc;
+    use std::rc::{Rc, Weak};
     use rand::prelude::*;
     use super::*;
 
@@ -109,8 +141,8 @@ pub mod test {
     ///    to remember to delete the created files.
     /// 2. The testing filesystem can simulate power failures or hardware failure during writing.
     ///    The FS code should just deal with that, and not lose any uncommitted data.
-    #[derive(Debug, Clone, Default)]
-    pub struct TestFile {
+    #[derive(Debug, Default)]
+    struct TestFileData {
         /// Writes that have been committed to disk.
         committed: Vec<u8>,
 
@@ -122,6 +154,18 @@ pub mod test {
 
         // rng, per_write_crash_chance.
         failure_rng: Option<(SmallRng, f64)>,
+
+        /// The handle holding the file's lock (if any). If the handle is dropped without unlocking
+        /// the file, the lock is stale and can be taken by anyone.
+        lock_holder: Option<Weak<()>>,
+    }
+
+    /// Each TestFile is a handle to some shared file data. Use [`TestFile::open_again`] to get
+    /// another handle to the same file, as if it was opened by another process.
+    #[derive(Debug, Default)]
+    pub struct TestFile {
+        data: Rc<RefCell<TestFileData>>,
+        handle: Rc<()>,
     }
 
     impl TestFile {
@@ -130,18 +174,50 @@ pub mod test {
         }
 
         pub fn new_faulty(seed: u64, failure_rate: f64) -> Self {
-            TestFile {
-                committed: vec![],
-                uncommitted: vec![],
+            let data = TestFileData {
                 failure_rng: Some((SmallRng::seed_from_u64(seed), failure_rate)),
+                ..Default::default()
+            };
+            TestFile {
+                data: Rc::new(RefCell::new(data)),
+                handle: Rc::new(()),
             }
         }
 
-        fn contents(&mut self) -> &[u8] {
-            self.sync_safe();
-            &self.committed
+        pub fn with_contents(contents: Vec<u8>) -> Self {
+            let mut file = Self::new();
+            file.set_contents(contents);
+            file
+        }
+
+        /// Open another handle to the same file.
+        pub fn open_again(&self) -> Self {
+            TestFile {
+                data: self.data.clone(),
+                handle: Rc::new(()),
+            }
         }
 
+        pub fn contents(&mut self) -> Vec<u8> {
+            let mut data = self.data.borrow_mut();
+            data.sync_safe();
+            data.committed.clone()
+        }
+
+        /// Replace the file's contents, as if it was damaged on disk.
+        pub fn set_contents(&mut self, contents: Vec<u8>) {
+            let mut data = self.data.borrow_mut();
+            data.uncommitted.clear();
+            data.committed = contents;
+        }
+
+        fn sync_and_maybe_crash(&mut self) -> io::Result<()> {
+            self.data.borrow_mut().sync_and_maybe_crash()
+        }
+    }
+
+    impl TestFileData {
+
         fn sync_safe(&mut self) {
             // I'm pulling the uncommitted writes out like this because it works around a borrow
             // checker problem. Its also fine because we'll need to clear the uncommitted writes
@@ -219,18 +295,21 @@ pub mod test {
 
     impl DTFile for TestFile {
         fn stream_len(&mut self) -> io::Result<u64> {
-            Ok(self.committed.len() as u64)
+            Ok(self.data.borrow().committed.len() as u64)
         }
 
         fn write_all_at(&mut self, write_data: &[u8], offset: u64) -> io::Result<()> {
             // Just add the uncommitted data to the queue.
-            self.uncommitted
+            self.data.borrow_mut().uncommitted
                 .push(UncommittedEntry::Write(offset as usize, write_data.into()));
 
             Ok(())
         }
 
         fn read_all_at(&mut self, buffer: &mut [u8], offset: u64) -> io::Result<()> {
+            let data = self.data.borrow();
+            let committed = &data.committed;
+
             // Linux guarantees that if you write then immediately read, you'll see your written
             // data. So read_all_at() here will return data from uncommitted blocks too.
             buffer.fill(0);
@@ -241,13 +320,13 @@ pub mod test {
 
             // First read from committed data and overwrite with anything we find in uncommitted
             // data.
-            if start_req < self.committed.len() {
-                let end_committed = usize::min(self.committed.len(), end_req);
-                buffer[..end_committed - start_req].copy_from_slice(&self.committed[start_req..end_committed]);
+            if start_req < committed.len() {
+                let end_committed = usize::min(committed.len(), end_req);
+                buffer[..end_committed - start_req].copy_from_slice(&committed[start_req..end_committed]);
                 last_read_pos = end_committed;
             }
 
-            for e in self.uncommitted.iter() {
+            for e in data.uncommitted.iter() {
                 let UncommittedEntry::Write(offset, data) = e else { continue };
                 // We don't care about barriers.
 
@@ -270,14 +349,40 @@ pub mod test {
             }
         }
 
+        fn set_len(&mut self, len: u64) -> io::Result<()> {
+            // This is applied straight away, as if it was durable as soon as it returns. Any
+            // uncommitted writes past the end will still extend the file if they're synced.
+            self.data.borrow_mut().committed.resize(len as usize, 0);
+            Ok(())
+        }
+
         fn write_barrier(&mut self) -> io::Result<()> {
-            self.uncommitted.push(UncommittedEntry::Barrier);
+            self.data.borrow_mut().uncommitted.push(UncommittedEntry::Barrier);
             Ok(())
         }
 
         fn sync_data(&mut self) -> io::Result<()> {
             self.sync_and_maybe_crash()
         }
+
+        fn try_lock(&mut self) -> io::Result<bool> {
+            let mut data = self.data.borrow_mut();
+            match data.lock_holder.as_ref().and_then(Weak::upgrade) {
+                Some(holder) if !Rc::ptr_eq(&holder, &self.handle) => Ok(false),
+                _ => {
+                    data.lock_holder = Some(Rc::downgrade(&self.handle));
+                    Ok(true)
+                }
+            }
+        }
+
+        fn unlock(&mut self) -> io::Result<()> {
+            let mut data = self.data.borrow_mut();
+            if data.lock_holder.as_ref().and_then(Weak::upgrade).is_some_and(|h| Rc::ptr_eq(&h, &self.handle)) {
+                data.lock_holder = None;
+            }
+            Ok(())
+        }
     }
 
     #[test]
@@ -314,7 +419,7 @@ pub mod test {
             // dbg!(succeeded);
 
             let resulting_data = file.contents();
-            for (pos, i) in resulting_data.into_iter().enumerate() {
+            for (pos, i) in resulting_data.iter().enumerate() {
                 if succeeded {
                     assert_eq!(*i, (pos / 2) as u8);
                 } else {
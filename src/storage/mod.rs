@@ -1,9 +1,13 @@
 //! Storage engine. See [`README.md`] for more details.
 //!
-//!
+//! The other code which works with files lives in here too: the write-ahead log ([`wal`]), the
+//! causal graph file ([`cg_storage`]) and [`AppendableFile`]. This module is only built with the
+//! storage feature.
 
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io;
 use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
@@ -12,16 +16,24 @@ use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::FileExt;
 
 use std::path::Path;
+use std::time::{Duration, Instant};
 use num_enum::{TryFromPrimitive, TryFromPrimitiveError};
 use smallvec::{smallvec, SmallVec};
 use crate::encoding::parseerror::ParseError;
 use crate::encoding::tools::{DTSerializable, ExtendFromSlice, StackWriteBuf, try_push_str, TryExtendFromSlice};
 use crate::encoding::varint::{try_push_u32, try_push_u64, try_push_usize};
+pub use crate::storage::codec::{CodecError, CodecFile, StorageCodec};
 use crate::storage::file::DTFile;
-use crate::storage::page::{BlitStatus, DataPage, DataPageImmutableFields, HeaderPage, Page};
+use crate::storage::page::{BlitStatus, DataPage, DataPageImmutableFields, HeaderPage, Page, page_capacity};
 
 mod page;
-mod file;
+pub(crate) mod file;
+pub(crate) mod codec;
+pub(crate) mod wal;
+pub(crate) mod cg_storage;
+mod append;
+
+pub use append::AppendableFile;
 
 const SE_MAGIC_BYTES: [u8; 8] = *b"DT_STOR1";
 const SE_VERSION: u32 = 1; // 2 bytes would probably be fine for this but eh.
@@ -41,6 +53,8 @@ pub enum CorruptPageError {
     VersionTooNew(u16),
     InvalidHeaderPageSize(usize),
     PageLengthInvalid(u16),
+    /// The page couldn't be decoded by the file's [`StorageCodec`].
+    DecodeFailed,
 }
 
 #[derive(Debug)]
@@ -61,11 +75,64 @@ pub enum SEError {
 
     GenericInvalidData,
 
+    /// The file is already open (and locked) by another storage engine, probably in another
+    /// process.
+    AlreadyLocked,
+
+    /// The storage engine was opened in read only mode.
+    ReadOnly,
+
+    /// The file uses something this version of the storage engine doesn't implement yet (like
+    /// overflow pages).
+    NotSupported,
+
     PageIsCorrupt(CorruptPageError),
     ParseError(ParseError),
     IO(io::Error),
 }
 
+impl Display for CorruptPageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CorruptPageError::InvalidHeaderMagicBytes => write!(f, "Invalid magic bytes in header page"),
+            CorruptPageError::InvalidChecksum => write!(f, "Page checksum mismatch"),
+            CorruptPageError::VersionTooNew(v) => write!(f, "Storage version {v} is too new"),
+            CorruptPageError::InvalidHeaderPageSize(size) => write!(f, "Invalid page size {size} in header page"),
+            CorruptPageError::PageLengthInvalid(len) => write!(f, "Invalid page length {len}"),
+            CorruptPageError::DecodeFailed => write!(f, "Page could not be decoded"),
+        }
+    }
+}
+
+impl Error for CorruptPageError {}
+
+impl Display for SEError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SEError::DataTooLarge => write!(f, "Data is too large to store"),
+            SEError::PageFull => write!(f, "Page is full"),
+            SEError::UnexpectedPageType => write!(f, "Unexpected page type"),
+            SEError::GenericInvalidData => write!(f, "Invalid data in storage file"),
+            SEError::AlreadyLocked => write!(f, "Storage file is locked by another process"),
+            SEError::ReadOnly => write!(f, "Storage was opened in read only mode"),
+            SEError::NotSupported => write!(f, "Not supported by this version of the storage engine"),
+            SEError::PageIsCorrupt(_) => write!(f, "Storage file contains a corrupt page"),
+            SEError::ParseError(_) => write!(f, "Could not parse storage file"),
+            SEError::IO(_) => write!(f, "IO error accessing storage file"),
+        }
+    }
+}
+
+impl Error for SEError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SEError::PageIsCorrupt(e) => Some(e),
+            SEError::ParseError(e) => Some(e),
+            SEError::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for SEError {
     fn from(io_err: io::Error) -> Self {
@@ -99,10 +166,15 @@ impl<E: TryFromPrimitive> From<TryFromPrimitiveError<E>> for SEError {
 const NUM_DATA_CHUNK_TYPES: usize = 3;
 type PageNum = u32;
 
+type DataChunks = [Option<Box<DataPageState>>; NUM_DATA_CHUNK_TYPES];
+
 #[derive(Debug)]
 struct StorageEngine<F: DTFile = File> {
     file: F,
 
+    /// Read only storage engines don't hold the file lock, and never write to the file.
+    read_only: bool,
+
     header_dirty: bool,
     header_fields: StorageHeaderFields,
     next_free_page: PageNum,
@@ -360,21 +432,106 @@ fn scan_blocks<F: DTFile>(file: &mut F, header_fields: &StorageHeaderFields) ->
     Ok((next_page, data_chunks))
 }
 
+/// How often we check if the file has been unlocked in [`StorageEngine::from_file_with_timeout`].
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
 impl StorageEngine<File> {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SEError> {
-        let file = File::options()
+    fn open_file(path: &Path) -> io::Result<File> {
+        File::options()
             .read(true)
             .create(true)
             .write(true)
             .append(false)
-            .open(path.as_ref())?;
+            .open(path)
+    }
+
+    /// Open the storage engine at the named path, creating it if it doesn't exist. This fails with
+    /// [`SEError::AlreadyLocked`] if the file is already open somewhere else.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SEError> {
+        Self::from_file(Self::open_file(path.as_ref())?)
+    }
+
+    /// Open the storage engine, waiting up to timeout for any other storage engine with the file
+    /// open to close it.
+    pub fn try_open_with_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self, SEError> {
+        Self::from_file_with_timeout(Self::open_file(path.as_ref())?, timeout)
+    }
+
+    /// Open the storage engine in read only mode. This works even if the file is open somewhere
+    /// else. See [`StorageEngine::from_file_read_only`].
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self, SEError> {
+        Self::from_file_read_only(File::open(path.as_ref())?)
+    }
+}
+
+impl StorageEngine<CodecFile<File>> {
+    /// Open the storage engine at the named path, passing every page through codec as it's written
+    /// to and read from disk. See [`StorageEngine::from_file_with_codec`].
+    pub fn open_with_codec<P: AsRef<Path>>(path: P, codec: Box<dyn StorageCodec>) -> Result<Self, SEError> {
+        Self::from_file_with_codec(StorageEngine::open_file(path.as_ref())?, codec)
+    }
+}
 
-        Self::from_file(file)
+impl<F: DTFile> StorageEngine<CodecFile<F>> {
+    /// Open a file whose pages are encoded with codec (eg to encrypt them). The codec is only
+    /// applied to data on disk. The file must always be opened with the same codec.
+    ///
+    /// Codecs which need more than half of each page for their own overhead aren't supported.
+    pub fn from_file_with_codec(file: F, codec: Box<dyn StorageCodec>) -> Result<Self, SEError> {
+        if codec.overhead() > DEFAULT_PAGE_SIZE / 2 { return Err(SEError::NotSupported); }
+        StorageEngine::from_file(CodecFile::new(file, codec))
     }
 }
 
 impl<F: DTFile> StorageEngine<F> {
-    pub fn from_file(mut file: F) -> Result<Self, SEError> {
+    pub fn from_file(file: F) -> Result<Self, SEError> {
+        Self::from_file_with_timeout(file, Duration::ZERO)
+    }
+
+    pub fn from_file_with_timeout(mut file: F, timeout: Duration) -> Result<Self, SEError> {
+        let start = Instant::now();
+        while !file.try_lock()? {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(SEError::AlreadyLocked);
+            }
+            std::thread::sleep(LOCK_RETRY_INTERVAL.min(timeout - elapsed));
+        }
+
+        Self::load(file, false).map_err(|(mut file, e)| {
+            // We're not going to use the file. Don't leave it locked.
+            let _ = file.unlock();
+            e
+        })
+    }
+
+    /// Open a file without taking the file lock. The returned storage engine can read the data
+    /// which was in the file when it was opened, but it can't be modified.
+    ///
+    /// If another process is writing to the file at the same time, pages it's in the middle of
+    /// writing are ignored (the same way they would be after a crash).
+    pub fn from_file_read_only(file: F) -> Result<Self, SEError> {
+        Self::load(file, true).map_err(|(_, e)| e)
+    }
+
+    /// Read the header & data page information from the file. On error, the file is handed back
+    /// so the caller can clean up.
+    fn load(file: F, read_only: bool) -> Result<Self, (F, SEError)> {
+        let mut file = file;
+        match Self::load_fields(&mut file, read_only) {
+            Ok((header_fields, next_free_page, data_chunks)) => Ok(Self {
+                file,
+                read_only,
+                header_dirty: false,
+                header_fields,
+                next_free_page,
+                data_chunks,
+            }),
+            Err(e) => Err((file, e)),
+        }
+    }
+
+    fn load_fields(file: &mut F, read_only: bool) -> Result<(StorageHeaderFields, PageNum, DataChunks), SEError> {
         let total_len = file.stream_len()?;
 
         // let (header_fields, next_free_page, data_chunks) = Self::read_or_initialize_header(&mut file, total_len)?;
@@ -388,20 +545,16 @@ impl<F: DTFile> StorageEngine<F> {
             let header_fields = StorageHeaderFields::default();
 
             // TODO: Consider just leaving header_dirty=true here and not writing the inital header.
-            HeaderPage::encode_and_bake(&header_fields)
-                .write(&mut file, 0)?;
+            if !read_only {
+                HeaderPage::encode_and_bake(&header_fields, page_capacity(file))
+                    .write(file, 0)?;
+            }
 
-            Ok(Self {
-                file,
-                header_dirty: false,
-                header_fields,
-                next_free_page: 1,
-                data_chunks: [HACK_NONE; NUM_DATA_CHUNK_TYPES],
-            })
+            Ok((header_fields, 1, [HACK_NONE; NUM_DATA_CHUNK_TYPES]))
         } else {
             println!("Parsing fields");
             // Parse the header page.
-            let header_fields = HeaderPage::read(&mut file, 0)?;
+            let header_fields = HeaderPage::read(file, 0)?;
             // TODO: If the header page has an invalid checksum, we should now search the file for
             // the backup header page and load that instead.
 
@@ -409,15 +562,9 @@ impl<F: DTFile> StorageEngine<F> {
             // let last_page_for_type
             // let data_chunks = [HACK_NONE; NUM_DATA_CHUNK_TYPES];
 
-            let (next_free_page, data_chunks) = scan_blocks(&mut file, &header_fields)?;
+            let (next_free_page, data_chunks) = scan_blocks(file, &header_fields)?;
 
-            Ok(Self {
-                file,
-                header_dirty: false,
-                header_fields,
-                next_free_page,
-                data_chunks,
-            })
+            Ok((header_fields, next_free_page, data_chunks))
         }
     }
 
@@ -435,6 +582,7 @@ impl<F: DTFile> StorageEngine<F> {
         let kind_usize = kind as usize;
 
         assert!(kind_usize < self.data_chunks.len());
+        let capacity = page_capacity(&self.file);
         let state = self.data_chunks[kind_usize].get_or_insert_with(|| {
             // Assign new pages for it.
             println!("Assigning new pages {}", self.next_free_page);
@@ -474,7 +622,7 @@ impl<F: DTFile> StorageEngine<F> {
                 page: DataPage::new(DataPageImmutableFields {
                     kind,
                     prev_page: 0,
-                }, cursor_buf.data_slice()),
+                }, cursor_buf.data_slice(), capacity),
                 dirty: false,
             })
         });
@@ -506,7 +654,7 @@ impl<F: DTFile> StorageEngine<F> {
         let mut sync_needed = false;
 
         if self.header_dirty {
-            let new_head = HeaderPage::encode_and_bake(&self.header_fields);
+            let new_head = HeaderPage::encode_and_bake(&self.header_fields, page_capacity(&self.file));
 
             println!("Writing new header {:?} to page {}", &self.header_fields, self.next_free_page);
             new_head.write(&mut self.file, self.next_free_page)?;
@@ -610,7 +758,7 @@ impl<F: DTFile> StorageEngine<F> {
         state.page = DataPage::new(DataPageImmutableFields {
             kind,
             prev_page: state.current_page_no,
-        }, cursor_data);
+        }, cursor_data, page_capacity(file));
         // Not reassigning the dirty bit here or the assigned blit page. Should we mark the new page
         // as dirty?
 
@@ -621,12 +769,14 @@ impl<F: DTFile> StorageEngine<F> {
         where C: DTSerializable + ?Sized,
               I: DTSerializable + ?Sized
     {
+        if self.read_only { return Err(SEError::ReadOnly); }
+
         let mut item_buf: StackWriteBuf = Default::default();
         item.try_serialize(&mut item_buf)
             .map_err(|_| SEError::DataTooLarge)?;
 
         let bytes = item_buf.data_slice();
-        if bytes.len() > DataPage::capacity() / 2 {
+        if bytes.len() > page_capacity(&self.file) / 2 {
             // TODO: Add support for larger blocks.
             return Err(SEError::DataTooLarge);
         }
@@ -661,7 +811,15 @@ impl<F: DTFile> StorageEngine<F> {
 
 impl<F: DTFile> Drop for StorageEngine<F> {
     fn drop(&mut self) {
-        self.fsync().unwrap();
+        if self.read_only { return; }
+
+        let result = self.fsync();
+        // Release the lock even if the final fsync failed, so the file can be opened again.
+        let _ = self.file.unlock();
+
+        if !std::thread::panicking() {
+            result.unwrap();
+        }
     }
 }
 
@@ -754,10 +912,19 @@ impl<'a, F: DTFile> Iterator for DataChunkIterator<'a, F> {
 
 #[cfg(test)]
 mod test {
+    use std::time::{Duration, Instant};
     use crate::encoding::varint::try_push_usize;
-    use crate::storage::{DataPageType, StorageEngine};
+    use crate::storage::{CorruptPageError, DataPageType, DEFAULT_PAGE_SIZE, SEError, StorageEngine};
+    use crate::storage::codec::test::XorCodec;
+    use crate::storage::file::DTFile;
     use crate::storage::file::test::TestFile;
 
+    fn content_len<F: DTFile>(se: &mut StorageEngine<F>) -> usize {
+        se.iter_data_pages(DataPageType::AgentNames)
+            .map(|page| page.unwrap().get_content().len())
+            .sum()
+    }
+
     #[test]
     fn one() {
         // let mut se = StorageEngine::from_file(TestFile::new()).unwrap();
@@ -789,7 +956,8 @@ mod test {
     #[test]
     fn two() {
         // let mut se = StorageEngine::from_file(TestFile::new()).unwrap();
-        let mut se = StorageEngine::open("foo.dts").unwrap();
+        // Test one might have the file open at the same time.
+        let mut se = StorageEngine::try_open_with_timeout("foo.dts", Duration::from_secs(10)).unwrap();
 
 
         for page in se.iter_data_pages(DataPageType::AgentNames) {
@@ -801,6 +969,183 @@ mod test {
         dbg!(&se.data_chunks, &se.header_fields, &se.next_free_page);
     }
 
+    #[test]
+    fn second_opener_is_locked_out() {
+        let file = TestFile::new();
+        let other = file.open_again();
+
+        let mut se = StorageEngine::from_file(file).unwrap();
+        se.append_chunk(DataPageType::AgentNames, "", &123usize).unwrap();
+
+        assert!(matches!(StorageEngine::from_file(other.open_again()), Err(SEError::AlreadyLocked)));
+
+        let start = Instant::now();
+        let timeout = Duration::from_millis(30);
+        assert!(matches!(StorageEngine::from_file_with_timeout(other.open_again(), timeout), Err(SEError::AlreadyLocked)));
+        assert!(start.elapsed() >= timeout);
+
+        // Once the first storage engine is closed, the file can be opened again.
+        drop(se);
+        let mut se = StorageEngine::from_file(other).unwrap();
+        assert!(content_len(&mut se) > 0);
+    }
+
+    #[test]
+    fn stale_lock_is_ignored() {
+        let file = TestFile::new();
+
+        // A process which locked the file then crashed, without unlocking it.
+        let mut crashed = file.open_again();
+        assert!(crashed.try_lock().unwrap());
+        drop(crashed);
+
+        StorageEngine::from_file(file).unwrap();
+    }
+
+    #[test]
+    fn read_only_while_writing() {
+        let file = TestFile::new();
+        let reader = file.open_again();
+
+        let mut se = StorageEngine::from_file(file).unwrap();
+        for i in 0..1000usize {
+            se.append_chunk(DataPageType::AgentNames, "", &i).unwrap();
+        }
+        se.fsync().unwrap();
+
+        let mut ro = StorageEngine::from_file_read_only(reader.open_again()).unwrap();
+        let len = content_len(&mut ro);
+        assert_eq!(len, content_len(&mut se));
+        assert!(matches!(ro.append_chunk(DataPageType::AgentNames, "", &1usize), Err(SEError::ReadOnly)));
+
+        // The writer can keep appending while the reader has the file open.
+        for i in 0..1000usize {
+            se.append_chunk(DataPageType::AgentNames, "", &i).unwrap();
+        }
+        se.fsync().unwrap();
+
+        // Closing the reader doesn't release the writer's lock.
+        drop(ro);
+        assert!(matches!(StorageEngine::from_file(reader.open_again()), Err(SEError::AlreadyLocked)));
+
+        drop(se);
+        let mut ro = StorageEngine::from_file_read_only(reader).unwrap();
+        assert!(content_len(&mut ro) > len);
+    }
+
+    #[test]
+    fn real_files_are_locked() {
+        let path = std::env::temp_dir().join(format!("dt-storage-lock-test-{}.dts", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let se = StorageEngine::open(&path).unwrap();
+        assert!(matches!(StorageEngine::open(&path), Err(SEError::AlreadyLocked)));
+        StorageEngine::open_read_only(&path).unwrap();
+
+        // Wait for the lock to be released by another thread.
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(se);
+        });
+        StorageEngine::try_open_with_timeout(&path, Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Write n items, syncing half way through.
+    fn write_items<F: DTFile>(mut se: StorageEngine<F>, n: usize) {
+        for i in 0..n {
+            se.append_chunk(DataPageType::AgentNames, "", &i).unwrap();
+            if i == n / 2 { se.fsync().unwrap(); }
+        }
+        se.fsync().unwrap();
+    }
+
+    /// The items stored in all the pages, without the page fields. Encoded pages are smaller, so
+    /// the items are split between pages differently.
+    fn items<F: DTFile>(se: &mut StorageEngine<F>) -> Vec<u8> {
+        se.iter_data_pages(DataPageType::AgentNames)
+            .flat_map(|page| {
+                let mut page = page.unwrap();
+                page.read_fields().unwrap();
+                let cursor_len = page.next_usize().unwrap();
+                page.get_content()[cursor_len..].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn codec_encodes_every_page() {
+        let mut plain = TestFile::new();
+        write_items(StorageEngine::from_file(plain.open_again()).unwrap(), 2000);
+        let mut encoded = TestFile::new();
+        write_items(StorageEngine::from_file_with_codec(encoded.open_again(), Box::new(XorCodec(1))).unwrap(), 2000);
+
+        // No page is stored as plaintext. (Pages which were allocated but never written are left
+        // as holes.)
+        let (plain_bytes, encoded_bytes) = (plain.contents(), encoded.contents());
+        assert_eq!(encoded_bytes.len() % DEFAULT_PAGE_SIZE, 0);
+        for (a, b) in plain_bytes.chunks(DEFAULT_PAGE_SIZE).zip(encoded_bytes.chunks(DEFAULT_PAGE_SIZE)) {
+            assert!(a.iter().all(|&x| x == 0) || a != b);
+        }
+        assert!(!encoded_bytes.windows(8).any(|w| w == b"DT_STOR1"));
+
+        let mut se = StorageEngine::from_file_with_codec(encoded.open_again(), Box::new(XorCodec(1))).unwrap();
+        assert_eq!(items(&mut se), items(&mut StorageEngine::from_file(plain).unwrap()));
+        drop(se);
+
+        // The file can't be read without the right codec.
+        assert!(matches!(StorageEngine::from_file(encoded.open_again()),
+            Err(SEError::PageIsCorrupt(CorruptPageError::InvalidHeaderMagicBytes))));
+        assert!(matches!(StorageEngine::from_file_with_codec(encoded, Box::new(XorCodec(2))),
+            Err(SEError::PageIsCorrupt(_))));
+    }
+
+    #[test]
+    fn codec_detects_damaged_pages() {
+        let mut plain = TestFile::new();
+        write_items(StorageEngine::from_file(plain.open_again()).unwrap(), 2000);
+        let mut encoded = TestFile::new();
+        write_items(StorageEngine::from_file_with_codec(encoded.open_again(), Box::new(XorCodec(1))).unwrap(), 2000);
+        let (plain_bytes, encoded_bytes) = (plain.contents(), encoded.contents());
+
+        // A bit flip in the header is caught by the header's checksum.
+        let mut damaged = encoded_bytes.clone();
+        damaged[21] ^= 0x10;
+        encoded.set_contents(damaged);
+        assert!(matches!(StorageEngine::from_file_with_codec(encoded.open_again(), Box::new(XorCodec(1))),
+            Err(SEError::PageIsCorrupt(CorruptPageError::InvalidChecksum))));
+
+        // The second sync wrote the data to the blit page (page 1). If its damaged, the data from
+        // the first sync is recovered from the page itself.
+        encoded.set_contents(encoded_bytes.clone());
+        let undamaged = items(&mut StorageEngine::from_file_with_codec(encoded.open_again(), Box::new(XorCodec(1))).unwrap());
+        let blit_page = DEFAULT_PAGE_SIZE;
+        let mut damaged = encoded_bytes.clone();
+        damaged[blit_page + 20] ^= 0x10;
+        encoded.set_contents(damaged);
+
+        let mut se = StorageEngine::from_file_with_codec(encoded.open_again(), Box::new(XorCodec(1))).unwrap();
+        let recovered = items(&mut se);
+        assert!(recovered.len() < undamaged.len());
+        assert!(undamaged.starts_with(&recovered));
+        drop(se);
+
+        // Block IDs are bound into the encoded pages, so moving a page somewhere else is caught too.
+        // The header was also written to page 3 while it was updated. That copy can be moved to
+        // page 0 in a plain file, but not in an encoded one.
+        let header_copy = 3 * DEFAULT_PAGE_SIZE;
+        let mut moved = plain_bytes.clone();
+        moved.copy_within(header_copy..header_copy + DEFAULT_PAGE_SIZE, 0);
+        StorageEngine::from_file(TestFile::with_contents(moved)).unwrap();
+        let mut moved = encoded_bytes.clone();
+        moved.copy_within(header_copy..header_copy + DEFAULT_PAGE_SIZE, 0);
+        encoded.set_contents(moved);
+        assert!(matches!(StorageEngine::from_file_with_codec(encoded, Box::new(XorCodec(1))),
+            Err(SEError::PageIsCorrupt(_))));
+    }
+
     // #[test]
     // fn bar() {
     //     let file = std::fs::File::options()
@@ -12,6 +12,7 @@ use crate::encoding::bufparser::BufParser;
 use crate::encoding::tools::{calc_checksum, ExtendFromSlice, TryExtendFromSlice};
 use crate::encoding::varint::*;
 use crate::storage::*;
+use crate::storage::codec::is_codec_error;
 
 
 /// Pages have 3 kinds of data:
@@ -63,6 +64,18 @@ pub(super) struct Page<const T: usize> {
     // cursor_start_pos: usize,
     read_pos: usize,
     write_pos: usize,
+    /// How many bytes of data are usable. This is less than the page size if the file reserves
+    /// some bytes in each block (eg for a codec's nonce). See [`page_capacity`].
+    capacity: usize,
+    layout: PageLayout,
+}
+
+/// Where the fixed position fields are in a page. This depends on the page type.
+#[derive(Clone, Debug)]
+struct PageLayout {
+    checksum: Range<usize>,
+    len: Range<usize>,
+    immutable_data_start: usize,
 }
 
 pub(super) const PAGE_TYPE_HEADER: usize = PageType::Header as usize;
@@ -94,6 +107,12 @@ const PO_DATA_BLIT_STATUS: Range<usize> = 10..11; // 1 byte (u8)
 
 const PO_DATA_IMMUTABLE_FIELD_START: usize = 12;
 
+const DATA_LAYOUT: PageLayout = PageLayout {
+    checksum: PO_DATA_CHECKSUM,
+    len: PO_DATA_LEN,
+    immutable_data_start: PO_DATA_IMMUTABLE_FIELD_START,
+};
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub(super) struct DataPageImmutableFields {
     pub(super) kind: DataPageType,
@@ -127,11 +146,17 @@ const PO_HEADER_LEN: Range<usize> = 14..16;
 
 const PO_HEADER_START: usize = 20;
 
+const HEADER_LAYOUT: PageLayout = PageLayout {
+    checksum: PO_HEADER_CHECKSUM,
+    len: PO_HEADER_LEN,
+    immutable_data_start: PO_HEADER_START,
+};
+
 
 
 impl<const T: usize> TryExtendFromSlice for Page<T> {
     fn try_extend_from_slice(&mut self, slice: &[u8]) -> Result<(), ()> {
-        if self.write_pos + slice.len() > self.data.len() {
+        if self.write_pos + slice.len() > self.capacity {
             return Err(());
         }
         self.data[self.write_pos..self.write_pos + slice.len()].copy_from_slice(slice);
@@ -143,44 +168,37 @@ impl<const T: usize> TryExtendFromSlice for Page<T> {
 struct InfallibleWritePage<'a, const T: usize>(&'a mut Page<T>);
 impl<'a, const T: usize> ExtendFromSlice for InfallibleWritePage<'a, T> {
     fn extend_from_slice(&mut self, slice: &[u8]) {
-        assert!(self.0.write_pos + slice.len() <= self.0.data.len(), "Data too large for page");
+        assert!(self.0.write_pos + slice.len() <= self.0.capacity, "Data too large for page");
         self.0.data[self.0.write_pos..self.0.write_pos + slice.len()].copy_from_slice(slice);
         self.0.write_pos += slice.len();
     }
 }
 
 impl<const T: usize> Page<T> {
-    pub fn capacity() -> usize {
-        DEFAULT_PAGE_SIZE
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
-    fn checksum_offset() -> Range<usize> {
+    /// Overflow pages aren't implemented yet, so reading one returns [`SEError::NotSupported`].
+    fn layout() -> Result<PageLayout, SEError> {
         match T {
-            PAGE_TYPE_HEADER => PO_HEADER_CHECKSUM.clone(),
-            PAGE_TYPE_DATA => PO_DATA_CHECKSUM.clone(),
-            _ => unimplemented!(),
+            PAGE_TYPE_HEADER => Ok(HEADER_LAYOUT),
+            PAGE_TYPE_DATA => Ok(DATA_LAYOUT),
+            _ => Err(SEError::NotSupported),
         }
     }
 
-    fn len_offset() -> Range<usize> {
-        match T {
-            PAGE_TYPE_HEADER => PO_HEADER_LEN.clone(),
-            PAGE_TYPE_DATA => PO_DATA_LEN.clone(),
-            _ => unimplemented!(),
-        }
+    fn checksum_offset(&self) -> Range<usize> {
+        self.layout.checksum.clone()
     }
 
-    fn immutable_data_start_offset() -> usize {
-        match T {
-            PAGE_TYPE_HEADER => PO_HEADER_START,
-            PAGE_TYPE_DATA => PO_DATA_IMMUTABLE_FIELD_START,
-            _ => unimplemented!(),
-        }
+    fn len_offset(&self) -> Range<usize> {
+        self.layout.len.clone()
     }
 
     fn read_checksum(&self) -> u32 {
         let mut buf = [0u8; 4];
-        buf.copy_from_slice(&self.data[Self::checksum_offset()]);
+        buf.copy_from_slice(&self.data[self.checksum_offset()]);
         u32::from_le_bytes(buf)
     }
 
@@ -188,32 +206,34 @@ impl<const T: usize> Page<T> {
         let len = self.get_len();
         assert!(len <= self.data.len());
 
-        calc_checksum(&self.data[Self::checksum_offset().end..len])
+        calc_checksum(&self.data[self.checksum_offset().end..len])
     }
 
     fn set_checksum(&mut self, checksum: u32) {
-        self.data[Self::checksum_offset()].copy_from_slice(&checksum.to_le_bytes());
+        let offset = self.checksum_offset();
+        self.data[offset].copy_from_slice(&checksum.to_le_bytes());
     }
 
     fn get_len(&self) -> usize {
         let mut buf = [0u8; 2];
-        buf.copy_from_slice(&self.data[Self::len_offset()]);
+        buf.copy_from_slice(&self.data[self.len_offset()]);
         u16::from_le_bytes(buf) as usize
     }
 
     fn set_len(&mut self, len: usize) {
         let len_u16 = len as u16;
-        self.data[Self::len_offset()].copy_from_slice(&len_u16.to_le_bytes());
+        let offset = self.len_offset();
+        self.data[offset].copy_from_slice(&len_u16.to_le_bytes());
     }
 
     fn bake_len_and_checksum(&mut self) {
-        assert!(self.write_pos <= DEFAULT_PAGE_SIZE);
+        assert!(self.write_pos <= self.capacity);
 
         // Fill in the page length and checksum.
         self.set_len(self.write_pos);
 
         // Calculate and fill in the checksum. The checksum includes the length to the end of the page.
-        let checksum = calc_checksum(&self.data[Self::checksum_offset().end..self.write_pos]);
+        let checksum = calc_checksum(&self.data[self.checksum_offset().end..self.write_pos]);
         // println!("Shake and bake {} checksum {:x}", self.content_end_pos, checksum);
         self.set_checksum(checksum);
     }
@@ -260,7 +280,7 @@ impl<const T: usize> Page<T> {
     }
 
     pub(super) fn write<F: DTFile>(&self, file: &mut F, page_no: PageNum) -> Result<(), SEError> {
-        file.write_all_at(&self.data, page_no as u64 * DEFAULT_PAGE_SIZE as u64)?;
+        file.write_all_at(&self.data[..self.capacity], page_no as u64 * DEFAULT_PAGE_SIZE as u64)?;
         Ok(())
     }
 
@@ -273,14 +293,22 @@ impl<const T: usize> Page<T> {
     /// parse any of the content beyond the length. Further explicit parsing is needed to use the
     /// result.
     pub(super) fn read_raw<F: DTFile>(file: &mut F, page_no: PageNum) -> Result<Self, SEError> {
+        let layout = Self::layout()?;
+        let capacity = page_capacity(file);
         let mut page = Self {
             data: [0; DEFAULT_PAGE_SIZE],
-            // cursor_start_pos: Self::immutable_data_start_offset(),
-            read_pos: Self::immutable_data_start_offset(),
+            // cursor_start_pos: layout.immutable_data_start,
+            read_pos: layout.immutable_data_start,
             write_pos: usize::MAX,
+            capacity,
+            layout,
         };
 
-        file.read_all_at(&mut page.data, page_no as u64 * DEFAULT_PAGE_SIZE as u64)?;
+        file.read_all_at(&mut page.data[..capacity], page_no as u64 * DEFAULT_PAGE_SIZE as u64)
+            .map_err(|e| {
+                // Pages which fail to decode are corrupt, just like pages with a bad checksum.
+                if is_codec_error(&e) { CorruptPageError::DecodeFailed.into() } else { SEError::from(e) }
+            })?;
 
         // I hate doing this here, but its the right place - since checking magic is cheaper than
         // reading the checksum.
@@ -289,7 +317,7 @@ impl<const T: usize> Page<T> {
         }
 
         let len = page.get_len();
-        if len <= Self::len_offset().end || len > page.data.len() {
+        if len <= page.len_offset().end || len > page.capacity {
             return Err(CorruptPageError::PageLengthInvalid(len as u16).into());
         }
 
@@ -366,7 +394,7 @@ impl<const T: usize> Page<T> {
     }
 
     pub(crate) fn reset_read_pos(&mut self) {
-        self.read_pos = Self::immutable_data_start_offset();
+        self.read_pos = self.layout.immutable_data_start;
     }
 }
 
@@ -374,7 +402,7 @@ impl<const T: usize> Page<T> {
 impl HeaderPage {
     // We'll write and encode header pages in a "1-shot" way, because they get rewritten so
     // infrequently.
-    pub(super) fn encode_and_bake(header_fields: &StorageHeaderFields) -> Self {
+    pub(super) fn encode_and_bake(header_fields: &StorageHeaderFields, capacity: usize) -> Self {
         assert_eq!(header_fields.page_size, DEFAULT_PAGE_SIZE, "Other block sizes are not yet implemented");
 
         let mut page = Self {
@@ -382,6 +410,8 @@ impl HeaderPage {
             // cursor_start_pos: usize::MAX,
             read_pos: PO_HEADER_START,
             write_pos: PO_HEADER_START,
+            capacity,
+            layout: HEADER_LAYOUT,
         };
 
         page.data[PO_HEADER_MAGIC].copy_from_slice(&MAGIC_BYTES);
@@ -452,12 +482,14 @@ impl HeaderPage {
 }
 
 impl DataPage {
-    pub(super) fn new(fields: DataPageImmutableFields, cursor_data: &[u8]) -> Self {
+    pub(super) fn new(fields: DataPageImmutableFields, cursor_data: &[u8], capacity: usize) -> Self {
         let mut page = Self {
             data: [0; DEFAULT_PAGE_SIZE],
             // cursor_start_pos: usize::MAX,
             read_pos: PO_DATA_IMMUTABLE_FIELD_START,
             write_pos: PO_DATA_IMMUTABLE_FIELD_START,
+            capacity,
+            layout: DATA_LAYOUT,
         };
 
         // Write the immutable bytes. This will write at self.content_start_pos.
@@ -511,6 +543,11 @@ impl DataPage {
     }
 }
 
+/// The number of bytes usable in each page of the file.
+pub(super) fn page_capacity<F: DTFile>(file: &F) -> usize {
+    DEFAULT_PAGE_SIZE - file.block_overhead()
+}
+
 #[inline]
 pub(in crate::storage) fn page_checksum_offset(is_header: bool) -> usize {
     if is_header {
@@ -532,8 +569,9 @@ pub fn page_first_byte_offset(is_header: bool) -> usize {
 #[cfg(test)]
 mod test {
     use crate::encoding::tools::{ExtendFromSlice, TryExtendFromSlice};
-    use crate::storage::page::{BlitStatus, Page, DataPageImmutableFields, DataPage};
-    use crate::storage::{DataPageType, PageType};
+    use crate::storage::page::{BlitStatus, Page, DataPageImmutableFields, DataPage, OverflowPage};
+    use crate::storage::{DataPageType, DEFAULT_PAGE_SIZE, PageType, SEError};
+    use crate::storage::file::test::TestFile;
 
     #[test]
     fn blah() {
@@ -541,7 +579,7 @@ mod test {
                     kind: DataPageType::AgentNames,
                     prev_page: 0,
                 },
-        &[1,2,3]
+        &[1,2,3], DEFAULT_PAGE_SIZE
         );
 
         assert_eq!(0, page.get_next_or_associated_page());
@@ -557,6 +595,20 @@ mod test {
         dbg!(&bytes[0..len]);
     }
 
+    #[test]
+    fn overflow_pages_are_not_supported() {
+        let mut file = TestFile::new();
+        let mut page = DataPage::new(DataPageImmutableFields {
+            kind: DataPageType::AgentNames,
+            prev_page: 0,
+        }, &[], DEFAULT_PAGE_SIZE);
+        page.bake_and_write(&mut file, 1).unwrap();
+
+        assert!(DataPage::read_raw(&mut file, 1).is_ok());
+        assert!(matches!(OverflowPage::read_raw(&mut file, 1), Err(SEError::NotSupported)));
+        assert!(matches!(OverflowPage::try_read_raw(&mut file, 1), Err(SEError::NotSupported)));
+    }
+
     #[test]
     fn blit_cmp() {
         assert_eq!(BlitStatus(0), BlitStatus(0));
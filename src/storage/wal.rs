@@ -60,11 +60,25 @@ pub(crate) struct WriteAheadLog {
 
 impl Display for WALError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ParseError {:?}", self)
+        match self {
+            WALError::InvalidHeader => write!(f, "Invalid write ahead log header"),
+            WALError::UnexpectedEOF => write!(f, "Unexpected end of write ahead log"),
+            WALError::ChecksumMismatch => write!(f, "Write ahead log checksum mismatch"),
+            WALError::ParseError(_) => write!(f, "Could not parse write ahead log"),
+            WALError::IO(_) => write!(f, "IO error accessing write ahead log"),
+        }
     }
 }
 
-impl Error for WALError {}
+impl Error for WALError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WALError::ParseError(e) => Some(e),
+            WALError::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for WALError {
     fn from(io_err: io::Error) -> Self {
@@ -85,6 +99,11 @@ const WAL_VERSION: [u8; 4] = 1u32.to_le_bytes();
 const WAL_HEADER_LENGTH: usize = WAL_MAGIC_BYTES.len() + WAL_VERSION.len();
 const WAL_HEADER_LENGTH_U64: u64 = WAL_HEADER_LENGTH as u64;
 
+// After the header, each chunk is a 4 byte LE CRC32 of the chunk body (from calc_checksum), a 4
+// byte LE body length, then the body. When the log is opened, chunks are read in order until one
+// is cut short or fails its checksum. That's a torn write - the chunk and everything after it are
+// backed up and truncated away, and the next chunk is written in their place.
+
 
 // impl WriteAheadLog {
 //     pub fn open<P: AsRef<Path>>(path: P, cg: &mut CausalGraph) -> Result<(Self, Ops), WALError> {
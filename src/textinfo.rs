@@ -39,11 +39,14 @@ impl TextInfo {
             self.ctx.push_str(op.kind, content)
         });
 
-        self.ops.push(KVPair(v_range.start, ListOpMetrics {
+        let metrics = ListOpMetrics {
             loc: op.loc,
             kind: op.kind,
             content_pos
-        }));
+        };
+        for (i, piece) in metrics.split_reversed_insert(&self.ctx).into_iter().enumerate() {
+            self.ops.push(KVPair(v_range.start + i, piece));
+        }
     }
 
     pub fn remote_push_op(&mut self, op: TextOperation, v_range: DTRange, parents: &[LV], graph: &Graph) {